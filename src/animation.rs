@@ -0,0 +1,260 @@
+use crate::draw::drawer::{DrawOptions, Drawer};
+use crate::draw::png_writer::rgb_triples_to_png;
+use crate::draw::raw_writer::rgb_triples_to_ppm;
+use crate::draw::view::{View, ViewRenderer};
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
+use crate::mapcss::styler::{StyleType, Styler};
+use crate::progress::{Progress, ProgressFormat};
+use crate::tile::{coords_to_xy, xy_to_coords, MAX_ZOOM};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+/// A point of the camera path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub lat: f64,
+    pub lon: f64,
+    pub zoom: f64,
+    /// How long it takes to get here from the previous keyframe (or, for the first keyframe,
+    /// how long the animation stays here before moving on).
+    pub seconds: f64,
+    pub easing: Easing,
+}
+
+/// How the camera accelerates between two keyframes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    /// Starts and stops smoothly.
+    EaseInOut,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameFormat {
+    Png,
+    /// Faster to write and to read for ffmpeg, but much larger.
+    Ppm,
+}
+
+pub struct AnimationOptions {
+    pub keyframes: Vec<Keyframe>,
+    pub width: usize,
+    pub height: usize,
+    pub fps: f64,
+    /// The frames are written to this directory as `frame_00000.png`, `frame_00001.png` and so on.
+    pub output: PathBuf,
+    pub format: FrameFormat,
+    pub progress_format: ProgressFormat,
+}
+
+/// Reads the keyframes, one per line: `LAT LON ZOOM [SECONDS [linear|ease-in-out]]`. The duration defaults
+/// to zero and the easing to `ease-in-out`. Empty lines and the lines starting with `#` are skipped.
+pub fn parse_keyframes(content: &str) -> Result<Vec<Keyframe>> {
+    let mut keyframes = Vec::new();
+    for (line_idx, line) in content.lines().enumerate().map(|(idx, line)| (idx, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let keyframe = parse_keyframe(line).with_context(|| format!("Invalid keyframe at line {}", line_idx + 1))?;
+        keyframes.push(keyframe);
+    }
+    if keyframes.is_empty() {
+        bail!("No keyframes are listed");
+    }
+    Ok(keyframes)
+}
+
+fn parse_keyframe(line: &str) -> Result<Keyframe> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    if !(3..=5).contains(&tokens.len()) {
+        bail!("Expected LAT LON ZOOM [SECONDS [EASING]], got <{}>", line);
+    }
+    let parse_num = |idx: usize, name| {
+        tokens[idx]
+            .parse::<f64>()
+            .ok()
+            .filter(|num| num.is_finite())
+            .with_context(|| format!("Invalid {}: {}", name, tokens[idx]))
+    };
+    let keyframe = Keyframe {
+        lat: parse_num(0, "latitude")?,
+        lon: parse_num(1, "longitude")?,
+        zoom: parse_num(2, "zoom level")?,
+        seconds: if tokens.len() > 3 {
+            parse_num(3, "duration")?
+        } else {
+            0.0
+        },
+        easing: match tokens.get(4) {
+            None | Some(&"ease-in-out") => Easing::EaseInOut,
+            Some(&"linear") => Easing::Linear,
+            Some(unknown) => bail!("Unknown easing: {}", unknown),
+        },
+    };
+    if keyframe.lat.abs() > 90.0 || keyframe.lon.abs() > 180.0 {
+        bail!("The coordinates are out of range");
+    }
+    if keyframe.zoom < 0.0 || keyframe.zoom > f64::from(MAX_ZOOM) {
+        bail!("The zoom level must be between 0 and {}", MAX_ZOOM);
+    }
+    if keyframe.seconds < 0.0 {
+        bail!("The duration can't be negative");
+    }
+    Ok(keyframe)
+}
+
+/// Returns (lat, lon, zoom) for every frame. The first and the last frames are exactly at the first
+/// and the last keyframes.
+pub fn interpolate_keyframes(keyframes: &[Keyframe], fps: f64) -> Vec<(f64, f64, f64)> {
+    let total_seconds = keyframes.iter().map(|k| k.seconds).sum::<f64>();
+    let frame_count = (total_seconds * fps).round() as usize + 1;
+
+    (0..frame_count)
+        .map(|frame_idx| {
+            // Use the exact end time for the last frame, which the rounding of the frame count might miss.
+            let mut time = if frame_idx + 1 == frame_count {
+                total_seconds
+            } else {
+                frame_idx as f64 / fps
+            };
+
+            // The first keyframe is a hold, so the movement only starts after it.
+            let (first, rest) = keyframes.split_first().unwrap();
+            time -= first.seconds;
+            let mut from = first;
+            for to in rest {
+                if time <= to.seconds {
+                    let progress = if to.seconds > 0.0 {
+                        (time / to.seconds).max(0.0)
+                    } else {
+                        1.0
+                    };
+                    return interpolate(from, to, ease(progress, to.easing));
+                }
+                time -= to.seconds;
+                from = to;
+            }
+            (from.lat, from.lon, from.zoom)
+        })
+        .collect()
+}
+
+fn ease(progress: f64, easing: Easing) -> f64 {
+    match easing {
+        Easing::Linear => progress,
+        Easing::EaseInOut => progress * progress * (3.0 - 2.0 * progress),
+    }
+}
+
+// The center moves along a straight line on the map (i.e. in Web Mercator), not along a great circle.
+fn interpolate(from: &Keyframe, to: &Keyframe, progress: f64) -> (f64, f64, f64) {
+    let (from_x, from_y) = coords_to_xy(&(from.lat, from.lon), 0);
+    let (to_x, to_y) = coords_to_xy(&(to.lat, to.lon), 0);
+    let mix = |a: f64, b: f64| a + (b - a) * progress;
+    let (lat, lon) = xy_to_coords(mix(from_x, to_x), mix(from_y, to_y), 0);
+    (lat, lon, mix(from.zoom, to.zoom))
+}
+
+pub fn run_animation(
+    geodata_file: &str,
+    stylesheet_file: &str,
+    stylesheet_type: &StyleType,
+    font_size_multiplier: Option<f64>,
+    draw_options: DrawOptions,
+    options: &AnimationOptions,
+) -> Result<()> {
+    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
+    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
+    let styler = Styler::new(rules, stylesheet_type, font_size_multiplier);
+    let reader = GeodataReader::load(geodata_file).context("Failed to load the geodata file")?;
+    let drawer = Drawer::new(&base_path, draw_options);
+
+    fs::create_dir_all(&options.output).context(format!("Failed to create {}", options.output.display()))?;
+
+    let frames = interpolate_keyframes(&options.keyframes, options.fps);
+    let mut progress = Progress::new(options.progress_format);
+    progress.start_stage("Rendering", "frames", Some(frames.len() as u64), 0);
+
+    let mut view_renderer = ViewRenderer::new(&reader, &styler, &drawer);
+    for (frame_idx, &(lat, lon, zoom)) in frames.iter().enumerate() {
+        let view = View {
+            lat,
+            lon,
+            zoom,
+            width: options.width,
+            height: options.height,
+        };
+        let pixels = view_renderer.render(&view);
+        let (bytes, extension) = match options.format {
+            FrameFormat::Png => (rgb_triples_to_png(&pixels, options.width, options.height)?, "png"),
+            FrameFormat::Ppm => (rgb_triples_to_ppm(&pixels, options.width, options.height), "ppm"),
+        };
+        let frame_path = options.output.join(format!("frame_{:05}.{}", frame_idx, extension));
+        fs::write(&frame_path, bytes).context(format!("Failed to write {}", frame_path.display()))?;
+
+        progress.update(frame_idx as u64 + 1, frame_idx as u64 + 1);
+    }
+
+    progress.finish_stage(frames.len() as u64, frames.len() as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keyframes() {
+        let keyframes = parse_keyframes("# Start\n55.75 37.61 12 1\n\n55.76 37.62 15.5 4 linear\n").unwrap();
+        assert_eq!(
+            keyframes,
+            vec![
+                Keyframe {
+                    lat: 55.75,
+                    lon: 37.61,
+                    zoom: 12.0,
+                    seconds: 1.0,
+                    easing: Easing::EaseInOut,
+                },
+                Keyframe {
+                    lat: 55.76,
+                    lon: 37.62,
+                    zoom: 15.5,
+                    seconds: 4.0,
+                    easing: Easing::Linear,
+                },
+            ]
+        );
+
+        assert!(parse_keyframes("55.75 37.61").is_err());
+        assert!(parse_keyframes("55.75 37.61 12 1 bouncy").is_err());
+        assert!(parse_keyframes("55.75 37.61 25").is_err());
+        assert!(parse_keyframes("# Nothing").is_err());
+    }
+
+    #[test]
+    fn test_interpolate_keyframes() {
+        let keyframe = |lon, zoom, seconds, easing| Keyframe {
+            lat: 0.0,
+            lon,
+            zoom,
+            seconds,
+            easing,
+        };
+        let keyframes = [
+            keyframe(0.0, 10.0, 0.5, Easing::EaseInOut),
+            keyframe(10.0, 12.0, 1.0, Easing::Linear),
+            keyframe(10.0, 14.0, 1.0, Easing::EaseInOut),
+        ];
+        let frames = interpolate_keyframes(&keyframes, 4.0);
+        let zooms = frames.iter().map(|f| f.2).collect::<Vec<_>>();
+        assert_eq!(
+            zooms,
+            vec![10.0, 10.0, 10.0, 10.5, 11.0, 11.5, 12.0, 12.3125, 13.0, 13.6875, 14.0]
+        );
+        assert!((frames[4].1 - 5.0).abs() < 1e-9);
+        assert!(frames.iter().all(|f| f.0.abs() < 1e-9));
+    }
+}
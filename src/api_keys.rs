@@ -0,0 +1,401 @@
+use crate::geodata::timestamp::{format_date, parse_timestamp};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// How many tiles a key may get per day and per month (UTC), given in the config as e.g.
+/// `1000/day, 20000/month`. Either limit can be left out, and an empty value means no limits at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quota {
+    pub daily_tiles: Option<u64>,
+    pub monthly_tiles: Option<u64>,
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Quota> {
+        let mut quota = Quota::default();
+        for limit in s.split(',').map(str::trim).filter(|limit| !limit.is_empty()) {
+            let (tiles, period) = limit
+                .split_once('/')
+                .with_context(|| format!("Invalid quota: {} (expected e.g. 1000/day, 20000/month)", s))?;
+            let tiles = tiles
+                .trim()
+                .parse::<u64>()
+                .with_context(|| format!("Invalid number of tiles: {}", tiles))?;
+            let field = match period.trim() {
+                "day" => &mut quota.daily_tiles,
+                "month" => &mut quota.monthly_tiles,
+                _ => bail!("Invalid quota period: {} (expected day or month)", period),
+            };
+            if field.replace(tiles).is_some() {
+                bail!("The quota per {} is given twice", period.trim());
+            }
+        }
+        Ok(quota)
+    }
+}
+
+/// Why a tile isn't served to the client.
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    // No key, or a key that isn't in the config.
+    Unknown,
+    QuotaExceeded {
+        period: &'static str,
+        limit: u64,
+        // The UNIX timestamp of the next day or month, when the quota is available again.
+        resets_at: i64,
+    },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Unknown => write!(f, "A valid API key is required (e.g. ?key=YOUR_KEY)"),
+            KeyError::QuotaExceeded {
+                period,
+                limit,
+                resets_at,
+            } => write!(
+                f,
+                "The quota of {} tiles per {} is used up until {} 00:00 UTC",
+                limit,
+                period,
+                format_date(*resets_at)
+            ),
+        }
+    }
+}
+
+// The tiles that a key has got on the given day (`YYYY-MM-DD`) and in the month of that day.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Usage {
+    day: String,
+    daily_tiles: u64,
+    monthly_tiles: u64,
+}
+
+impl Usage {
+    // The counters are reset when a new day or month begins.
+    fn at(&self, day: &str) -> Usage {
+        Usage {
+            day: day.to_string(),
+            daily_tiles: if self.day == day { self.daily_tiles } else { 0 },
+            monthly_tiles: if self.day.get(..7) == day.get(..7) {
+                self.monthly_tiles
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// The keys that the clients of the server have to add to the tile URLs (e.g. `/15/19805/10244.png?key=KEY`),
+/// with their quotas and how much of them is used. The usage is kept in a file (if any) so that it survives
+/// the restarts of the server. The admin key (if any) isn't for the tiles, but can see the usage of all the keys.
+pub struct ApiKeys {
+    quotas: HashMap<String, Quota>,
+    admin_key: Option<String>,
+    usage: Mutex<HashMap<String, Usage>>,
+    usage_file: Option<String>,
+    is_modified: AtomicBool,
+}
+
+impl ApiKeys {
+    pub fn new(
+        quotas: HashMap<String, Quota>,
+        admin_key: Option<String>,
+        usage_file: Option<String>,
+    ) -> Result<ApiKeys> {
+        for key in quotas.keys().chain(&admin_key) {
+            validate_key(key)?;
+        }
+        if admin_key.as_ref().is_some_and(|key| quotas.contains_key(key)) {
+            bail!("The admin key can't be used for the tiles");
+        }
+        let usage = match &usage_file {
+            Some(file) if fs::metadata(file).is_ok() => {
+                let contents = fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+                parse_usage(&contents).with_context(|| format!("Failed to parse {}", file))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(ApiKeys {
+            quotas,
+            admin_key,
+            usage: Mutex::new(usage),
+            usage_file,
+            is_modified: AtomicBool::new(false),
+        })
+    }
+
+    /// Counts a tile for the key, unless the key is unknown or its quota is already used up.
+    /// `now` is a UNIX timestamp.
+    pub fn use_tile(&self, key: Option<&str>, now: i64) -> Result<(), KeyError> {
+        self.use_tiles(key, 1, now)
+    }
+
+    /// Counts several tiles at once (e.g. for a static map), unless the quota doesn't have room for all of them.
+    pub fn use_tiles(&self, key: Option<&str>, count: u64, now: i64) -> Result<(), KeyError> {
+        let (key, quota) = key
+            .and_then(|key| self.quotas.get_key_value(key))
+            .ok_or(KeyError::Unknown)?;
+        let day = format_date(now);
+        let mut all_usage = self.usage.lock().unwrap();
+        let usage = all_usage.get(key).map(|usage| usage.at(&day)).unwrap_or(Usage {
+            day,
+            ..Default::default()
+        });
+
+        if let Some(limit) = quota.daily_tiles.filter(|limit| usage.daily_tiles + count > *limit) {
+            return Err(KeyError::QuotaExceeded {
+                period: "day",
+                limit,
+                resets_at: (now.div_euclid(86400) + 1) * 86400,
+            });
+        }
+        if let Some(limit) = quota.monthly_tiles.filter(|limit| usage.monthly_tiles + count > *limit) {
+            return Err(KeyError::QuotaExceeded {
+                period: "month",
+                limit,
+                resets_at: start_of_next_month(&usage.day),
+            });
+        }
+
+        all_usage.insert(
+            key.clone(),
+            Usage {
+                daily_tiles: usage.daily_tiles + count,
+                monthly_tiles: usage.monthly_tiles + count,
+                ..usage
+            },
+        );
+        self.is_modified.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Lets only the admin through, e.g. to the requests that change what the server serves.
+    pub fn check_admin(&self, key: Option<&str>) -> Result<(), KeyError> {
+        match key {
+            Some(key) if self.admin_key.as_deref() == Some(key) => Ok(()),
+            _ => Err(KeyError::Unknown),
+        }
+    }
+
+    /// The usage and the quotas as JSON, e.g.
+    /// `{"KEY":{"day":"2020-01-01","daily_tiles":10,"daily_limit":1000,"monthly_tiles":10,"monthly_limit":null}}`.
+    /// The admin gets all the keys, and everybody else only the key they have asked with.
+    pub fn usage_json(&self, key: Option<&str>, now: i64) -> Result<String, KeyError> {
+        let format_limit = |limit: Option<u64>| limit.map_or_else(|| "null".to_string(), |limit| limit.to_string());
+        let mut keys = match key {
+            Some(key) if self.admin_key.as_deref() == Some(key) => {
+                self.quotas.keys().map(String::as_str).collect::<Vec<_>>()
+            }
+            Some(key) if self.quotas.contains_key(key) => vec![key],
+            _ => return Err(KeyError::Unknown),
+        };
+        keys.sort_unstable();
+        let day = format_date(now);
+        let all_usage = self.usage.lock().unwrap();
+        let entries = keys
+            .iter()
+            .map(|key| {
+                let quota = &self.quotas[*key];
+                let usage = all_usage.get(*key).map(|usage| usage.at(&day)).unwrap_or_default();
+                format!(
+                    "\"{}\":{{\"day\":\"{}\",\"daily_tiles\":{},\"daily_limit\":{},\"monthly_tiles\":{},\"monthly_limit\":{}}}",
+                    key,
+                    day,
+                    usage.daily_tiles,
+                    format_limit(quota.daily_tiles),
+                    usage.monthly_tiles,
+                    format_limit(quota.monthly_tiles),
+                )
+            })
+            .collect::<Vec<_>>();
+        Ok(format!("{{{}}}", entries.join(",")))
+    }
+
+    /// Writes the usage to the file if it has changed since the last time. The file is replaced at once,
+    /// so that it isn't left half-written if the server is stopped in the middle.
+    pub fn save(&self) -> Result<()> {
+        let file = match &self.usage_file {
+            Some(file) if self.is_modified.swap(false, Ordering::Relaxed) => file,
+            _ => return Ok(()),
+        };
+        let contents = format_usage(&self.usage.lock().unwrap());
+        let temp_file = format!("{}.tmp", file);
+        let result = fs::write(&temp_file, contents)
+            .and_then(|_| fs::rename(&temp_file, file))
+            .with_context(|| format!("Failed to save the usage to {}", file));
+        if result.is_err() {
+            // Try again the next time.
+            self.is_modified.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+// The keys go into the URLs and the usage file as they are.
+fn validate_key(key: &str) -> Result<()> {
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("<{}> can't be used as an API key", key);
+    }
+    Ok(())
+}
+
+fn start_of_next_month(day: &str) -> i64 {
+    let year = day[..4].parse::<i64>().unwrap_or_default();
+    let month = day[5..7].parse::<i64>().unwrap_or_default();
+    let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    parse_timestamp(&format!("{:04}-{:02}-01", year, month)).unwrap_or_default()
+}
+
+// One line per key: `KEY DAY DAILY_TILES MONTHLY_TILES`.
+fn format_usage(all_usage: &HashMap<String, Usage>) -> String {
+    let mut lines = all_usage
+        .iter()
+        .map(|(key, usage)| format!("{} {} {} {}\n", key, usage.day, usage.daily_tiles, usage.monthly_tiles))
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+    lines.concat()
+}
+
+fn parse_usage(contents: &str) -> Result<HashMap<String, Usage>> {
+    let mut all_usage = HashMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let parse = || -> Result<(String, Usage)> {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [key, day, daily_tiles, monthly_tiles] => {
+                    parse_timestamp(day)?;
+                    Ok((
+                        key.to_string(),
+                        Usage {
+                            day: day.to_string(),
+                            daily_tiles: daily_tiles.parse()?,
+                            monthly_tiles: monthly_tiles.parse()?,
+                        },
+                    ))
+                }
+                _ => bail!("Expected KEY DAY DAILY_TILES MONTHLY_TILES"),
+            }
+        };
+        let (key, usage) = parse().with_context(|| format!("Invalid line: {}", line))?;
+        all_usage.insert(key, usage);
+    }
+    Ok(all_usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(
+            "1000/day, 20000/month".parse::<Quota>().unwrap(),
+            Quota {
+                daily_tiles: Some(1000),
+                monthly_tiles: Some(20000),
+            }
+        );
+        assert_eq!(
+            "5/month".parse::<Quota>().unwrap(),
+            Quota {
+                daily_tiles: None,
+                monthly_tiles: Some(5),
+            }
+        );
+        assert_eq!("".parse::<Quota>().unwrap(), Quota::default());
+
+        assert!("1000".parse::<Quota>().is_err());
+        assert!("1000/week".parse::<Quota>().is_err());
+        assert!("-1/day".parse::<Quota>().is_err());
+        assert!("1/day, 2/day".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn test_quotas() {
+        let quotas = HashMap::from([
+            ("small".to_string(), "2/day, 3/month".parse().unwrap()),
+            ("free".to_string(), Quota::default()),
+        ]);
+        let keys = ApiKeys::new(quotas, Some("admin".to_string()), None).unwrap();
+        let jan_30 = parse_timestamp("2020-01-30T12:00:00Z").unwrap();
+        let jan_31 = parse_timestamp("2020-01-31T12:00:00Z").unwrap();
+        let feb_1 = parse_timestamp("2020-02-01").unwrap();
+
+        assert_eq!(keys.use_tile(None, jan_30), Err(KeyError::Unknown));
+        assert_eq!(keys.use_tile(Some("unknown"), jan_30), Err(KeyError::Unknown));
+
+        assert!(keys.use_tile(Some("small"), jan_30).is_ok());
+        assert!(keys.use_tile(Some("small"), jan_30).is_ok());
+        let daily_error = keys.use_tile(Some("small"), jan_30).unwrap_err();
+        assert_eq!(
+            daily_error.to_string(),
+            "The quota of 2 tiles per day is used up until 2020-01-31 00:00 UTC"
+        );
+
+        assert!(keys.use_tile(Some("small"), jan_31).is_ok());
+        assert_eq!(
+            keys.use_tile(Some("small"), jan_31),
+            Err(KeyError::QuotaExceeded {
+                period: "month",
+                limit: 3,
+                resets_at: feb_1,
+            })
+        );
+        assert!(keys.use_tile(Some("small"), feb_1).is_ok());
+        // Nothing is counted when not all the tiles fit.
+        assert!(keys.use_tiles(Some("small"), 2, feb_1).is_err());
+
+        for _ in 0..10 {
+            assert!(keys.use_tile(Some("free"), feb_1).is_ok());
+        }
+
+        assert_eq!(
+            keys.usage_json(Some("small"), feb_1).unwrap(),
+            r#"{"small":{"day":"2020-02-01","daily_tiles":1,"daily_limit":2,"monthly_tiles":1,"monthly_limit":3}}"#
+        );
+        assert_eq!(keys.usage_json(None, feb_1), Err(KeyError::Unknown));
+        assert_eq!(keys.usage_json(Some("unknown"), feb_1), Err(KeyError::Unknown));
+        assert_eq!(
+            keys.usage_json(Some("admin"), feb_1).unwrap(),
+            [
+                r#"{"free":{"day":"2020-02-01","daily_tiles":10,"daily_limit":null,"monthly_tiles":10,"monthly_limit":null}"#,
+                r#""small":{"day":"2020-02-01","daily_tiles":1,"daily_limit":2,"monthly_tiles":1,"monthly_limit":3}}"#,
+            ]
+            .join(",")
+        );
+        // The admin key doesn't get the tiles.
+        assert_eq!(keys.use_tile(Some("admin"), feb_1), Err(KeyError::Unknown));
+
+        assert!(keys.check_admin(Some("admin")).is_ok());
+        assert_eq!(keys.check_admin(Some("small")), Err(KeyError::Unknown));
+        assert_eq!(keys.check_admin(None), Err(KeyError::Unknown));
+    }
+
+    #[test]
+    fn test_usage_file_format() {
+        let all_usage = parse_usage("b 2020-01-31 1 5\n\na 2020-02-01 2 2\n").unwrap();
+        assert_eq!(
+            all_usage["b"],
+            Usage {
+                day: "2020-01-31".to_string(),
+                daily_tiles: 1,
+                monthly_tiles: 5,
+            }
+        );
+        assert_eq!(format_usage(&all_usage), "a 2020-02-01 2 2\nb 2020-01-31 1 5\n");
+
+        assert!(parse_usage("a 2020-01-31 1").is_err());
+        assert!(parse_usage("a yesterday 1 1").is_err());
+        assert!(parse_usage("a 2020-01-31 1 x").is_err());
+    }
+}
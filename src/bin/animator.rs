@@ -0,0 +1,127 @@
+mod common;
+
+use crate::common::{fail, get_style_config, get_value_from_config, read_config};
+use renderer::animation::{parse_keyframes, run_animation, AnimationOptions, FrameFormat};
+use renderer::progress::ProgressFormat;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn usage(bin_name: &str) -> ! {
+    eprintln!(
+        "Usage: {} CONFIG KEYFRAMES OUTPUT_DIR [--size WIDTHxHEIGHT] [--fps N] [--format png|ppm] [--json-progress]",
+        bin_name
+    );
+    fail();
+}
+
+fn parse_size(size: &str) -> Option<(usize, usize)> {
+    let (width, height) = size.split_once('x')?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
+    }
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("animator");
+
+    if args.len() < 4 {
+        usage(bin_name);
+    }
+
+    let config = read_config(&args[1]);
+    let geodata_file = get_value_from_config(&config, "geodata", "file");
+    let style_config = get_style_config(&config);
+
+    let keyframes = match fs::read_to_string(&args[2])
+        .map_err(anyhow::Error::from)
+        .and_then(|content| parse_keyframes(&content))
+    {
+        Ok(keyframes) => keyframes,
+        Err(err) => {
+            eprintln!("Failed to read the keyframes from {}: {:#}", args[2], err);
+            fail();
+        }
+    };
+
+    let (mut width, mut height) = (1280, 720);
+    let mut fps = 30.0;
+    let mut format = FrameFormat::Png;
+    let mut progress_format = ProgressFormat::Text;
+
+    let mut flags = args[4..].iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || match flags.next() {
+            Some(value) => value.clone(),
+            None => {
+                eprintln!("Missing value for {}", flag);
+                usage(bin_name);
+            }
+        };
+        match flag.as_str() {
+            "--size" => {
+                let size = value();
+                (width, height) = match parse_size(&size) {
+                    Some(size) => size,
+                    None => {
+                        eprintln!("Invalid frame size: {}", size);
+                        fail();
+                    }
+                };
+            }
+            "--fps" => {
+                let fps_str = value();
+                fps = match fps_str.parse::<f64>() {
+                    Ok(fps) if fps > 0.0 => fps,
+                    _ => {
+                        eprintln!("Invalid frame rate: {}", fps_str);
+                        fail();
+                    }
+                };
+            }
+            "--format" => {
+                format = match value().as_str() {
+                    "png" => FrameFormat::Png,
+                    "ppm" => FrameFormat::Ppm,
+                    unknown_format => {
+                        eprintln!("Unknown frame format: {}", unknown_format);
+                        usage(bin_name);
+                    }
+                };
+            }
+            "--json-progress" => progress_format = ProgressFormat::JsonLines,
+            unknown_flag => {
+                eprintln!("Unknown option: {}", unknown_flag);
+                usage(bin_name);
+            }
+        }
+    }
+
+    let options = AnimationOptions {
+        keyframes,
+        width,
+        height,
+        fps,
+        output: PathBuf::from(&args[3]),
+        format,
+        progress_format,
+    };
+
+    let res = run_animation(
+        &geodata_file,
+        &style_config.file,
+        &style_config.style_type,
+        style_config.font_size_multiplier,
+        style_config.draw_options,
+        &options,
+    );
+
+    if let Err(e) = res {
+        for cause in e.chain() {
+            eprintln!("{}", cause);
+        }
+        fail();
+    }
+}
@@ -0,0 +1,178 @@
+use renderer::draw::drawer::DrawOptions;
+use renderer::draw::filters::parse_filters;
+use renderer::draw::font::abbreviations::Abbreviations;
+use renderer::draw::render_order::RenderOrder;
+use renderer::draw::sprite::Sprite;
+use renderer::mapcss::priority::AreaPriorities;
+use renderer::mapcss::styler::StyleType;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tini::Ini;
+
+pub fn fail() -> ! {
+    std::process::exit(1);
+}
+
+pub fn read_config(config_path: &str) -> Ini {
+    match Ini::from_file(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to parse config from {}: {}", config_path, err);
+            fail();
+        }
+    }
+}
+
+pub fn get_value_from_config(config: &Ini, section: &str, name: &str) -> String {
+    match config.get(section, name) {
+        Some(value) => value,
+        _ => {
+            eprintln!("Property {} is missing in section [{}]", name, section);
+            fail();
+        }
+    }
+}
+
+pub fn get_flag_from_config(config: &Ini, section: &str, name: &str) -> bool {
+    match config.get::<String>(section, name).as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(unknown_value) => {
+            eprintln!("Invalid value for {}: {}", name, unknown_value);
+            fail();
+        }
+    }
+}
+
+pub struct StyleConfig {
+    pub file: String,
+    pub style_type: StyleType,
+    pub font_size_multiplier: Option<f64>,
+    // Not every binary draws the tiles (e.g. style_audit only styles the objects).
+    #[allow(dead_code)]
+    pub draw_options: DrawOptions,
+}
+
+pub fn get_style_config(config: &Ini) -> StyleConfig {
+    let style_section = "style";
+    let file = get_value_from_config(config, style_section, "file");
+    let style_type = match get_value_from_config(config, style_section, "type").as_str() {
+        "josm" => StyleType::Josm,
+        "mapsme" => StyleType::MapsMe,
+        unknown_style => {
+            eprintln!("Unknown stylesheet type: {}", unknown_style);
+            fail();
+        }
+    };
+    let font_size_multiplier =
+        config
+            .get::<String>(style_section, "font-mul")
+            .map(|multiplier_str| match multiplier_str.parse() {
+                Ok(multiplier) => multiplier,
+                Err(_) => {
+                    eprintln!("Invalid font size multiplier: {}", multiplier_str);
+                    fail();
+                }
+            });
+
+    let abbreviations = config
+        .get::<String>(style_section, "abbreviations")
+        .map(|locales| match Abbreviations::for_locales(&locales) {
+            Ok(abbreviations) => abbreviations,
+            Err(err) => {
+                eprintln!("Invalid abbreviations: {}", err);
+                fail();
+            }
+        })
+        .unwrap_or_default();
+
+    let sprite = config
+        .get::<String>(style_section, "sprite")
+        .map(|prefix| match Sprite::load(Path::new(&prefix)) {
+            Ok(sprite) => Arc::new(sprite),
+            Err(err) => {
+                eprintln!("Failed to load the sprite from {}: {:#}", prefix, err);
+                fail();
+            }
+        });
+
+    let render_order =
+        config
+            .get::<String>(style_section, "render-order")
+            .map(|path| match RenderOrder::load(Path::new(&path)) {
+                Ok(render_order) => Arc::new(render_order),
+                Err(err) => {
+                    eprintln!("Failed to load the render order from {}: {:#}", path, err);
+                    fail();
+                }
+            });
+
+    let tile_threads = config
+        .get::<String>(style_section, "tile-threads")
+        .map(|threads_str| match threads_str.parse() {
+            Ok(threads) => threads,
+            Err(_) => {
+                eprintln!("Invalid number of tile threads: {}", threads_str);
+                fail();
+            }
+        })
+        .unwrap_or(1);
+
+    let render_budget =
+        config
+            .get::<String>(style_section, "render-budget")
+            .map(|millis_str| match millis_str.parse() {
+                Ok(millis) => Duration::from_millis(millis),
+                Err(_) => {
+                    eprintln!("Invalid render budget: {}", millis_str);
+                    fail();
+                }
+            });
+
+    // E.g. `filters = invert(0.9), contrast(0.8)` for a dark version of the style.
+    let filters = config
+        .get::<String>(style_section, "filters")
+        .map(|filters| match parse_filters(&filters) {
+            Ok(filters) => filters,
+            Err(err) => {
+                eprintln!("Invalid filters: {:#}", err);
+                fail();
+            }
+        })
+        .unwrap_or_default();
+
+    // E.g. `area-priority = landuse=farmland, landuse=residential, natural=water`, from the bottom to the top.
+    let area_priorities = config
+        .get::<String>(style_section, "area-priority")
+        .map(|priorities| match priorities.parse::<AreaPriorities>() {
+            Ok(priorities) => priorities,
+            Err(err) => {
+                eprintln!("Invalid area priority: {:#}", err);
+                fail();
+            }
+        })
+        .unwrap_or_default();
+
+    let draw_options = DrawOptions {
+        draw_vertices: get_flag_from_config(config, style_section, "draw-vertices"),
+        qa_overlay: get_flag_from_config(config, style_section, "qa-overlay"),
+        cluster_pois: get_flag_from_config(config, style_section, "cluster-pois"),
+        cluster_counts: get_flag_from_config(config, style_section, "cluster-counts"),
+        abbreviations,
+        sprite,
+        render_order,
+        tile_threads,
+        parallel_layers: get_flag_from_config(config, style_section, "parallel-layers"),
+        render_budget,
+        filters,
+        area_priorities,
+    };
+
+    StyleConfig {
+        file,
+        style_type,
+        font_size_multiplier,
+        draw_options,
+    }
+}
@@ -1,33 +1,129 @@
+mod common;
+
+use crate::common::{get_style_config, read_config};
 use anyhow::Result;
+use renderer::geodata::baked::{BakeOptions, DEFAULT_BAKED_MAX_ZOOM};
+use renderer::geodata::importer::{CoordinatePrecision, ImportOptions};
+use renderer::progress::{Progress, ProgressFormat};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-fn import(input: &Path, tmp_output: &Path, output: &Path) -> Result<()> {
-    println!("Importing OSM data from {}", input.to_string_lossy());
-    renderer::geodata::importer::import(input, tmp_output)?;
+fn import(
+    input: &Path,
+    tmp_output: &Path,
+    output: &Path,
+    options: &ImportOptions,
+    progress: &mut Progress,
+) -> Result<()> {
+    progress.message(&format!("Importing OSM data from {}", input.to_string_lossy()));
+    renderer::geodata::importer::import_with_progress(input, tmp_output, options, progress)?;
     fs::rename(tmp_output, output)?;
 
     Ok(())
 }
 
+// The updated geodata replaces the old file only when it's complete, so a server can keep using the old one
+// until then (and until `/reload`).
+fn update(
+    geodata: &Path,
+    changes: &[PathBuf],
+    tmp_output: &Path,
+    bake: Option<BakeOptions>,
+    progress: &mut Progress,
+) -> Result<()> {
+    renderer::geodata::importer::update_with_progress(geodata, changes, tmp_output, bake, progress)?;
+    fs::rename(tmp_output, geodata)?;
+
+    Ok(())
+}
+
 fn main() {
-    let args: Vec<_> = env::args().collect();
+    let mut args: Vec<_> = env::args().collect();
+
+    let progress_format = match args.iter().position(|arg| arg == "--json-progress") {
+        Some(idx) => {
+            args.remove(idx);
+            ProgressFormat::JsonLines
+        }
+        None => ProgressFormat::Text,
+    };
+
+    let mut options = ImportOptions::default();
+    if let Some(idx) = args.iter().position(|arg| arg == "--keep-untagged-nodes") {
+        args.remove(idx);
+        options.keep_untagged_nodes = true;
+    }
+    if let Some(idx) = args.iter().position(|arg| arg == "--keep-timestamps") {
+        args.remove(idx);
+        options.keep_timestamps = true;
+    }
+    if let Some(idx) = args.iter().position(|arg| arg == "--landcover") {
+        args.remove(idx);
+        options.landcover = true;
+    }
+    if let Some(idx) = args.iter().position(|arg| arg == "--lossless-coordinates") {
+        args.remove(idx);
+        options.coordinates = CoordinatePrecision::Lossless;
+    }
+    if let Some(idx) = args.iter().position(|arg| arg == "--coordinate-digits") {
+        if idx + 1 < args.len() {
+            args.remove(idx);
+            let digits = args.remove(idx);
+            match digits.parse() {
+                Ok(digits) => options.coordinates = CoordinatePrecision::Digits(digits),
+                Err(_) => {
+                    eprintln!("Invalid number of coordinate digits: {}", digits);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    // The low zoom tiles are drawn with the style from the config of the server.
+    if let Some(idx) = args.iter().position(|arg| arg == "--bake") {
+        if idx + 1 < args.len() {
+            args.remove(idx);
+            let style_config = get_style_config(&read_config(&args.remove(idx)));
+            options.bake = Some(BakeOptions {
+                stylesheet_file: style_config.file,
+                stylesheet_type: style_config.style_type,
+                font_size_multiplier: style_config.font_size_multiplier,
+                draw_options: style_config.draw_options,
+                max_zoom: DEFAULT_BAKED_MAX_ZOOM,
+            });
+        }
+    }
 
-    if args.len() != 3 {
+    let is_update = args.get(1).is_some_and(|arg| arg == "update");
+    if (is_update && args.len() < 4) || (!is_update && args.len() != 3) {
         let bin_name = args.first().map(String::as_str).unwrap_or("importer");
-        eprintln!("Usage: {} INPUT OUTPUT", bin_name);
+        eprintln!(
+            "Usage: {} [--json-progress] [--keep-untagged-nodes] [--keep-timestamps] [--landcover] [--bake CONFIG] [--coordinate-digits N|--lossless-coordinates] INPUT|-|URL OUTPUT",
+            bin_name
+        );
+        eprintln!(
+            "       {} update [--json-progress] [--bake CONFIG] GEODATA CHANGES...",
+            bin_name
+        );
         std::process::exit(1);
     }
 
-    let input = PathBuf::from(&args[1]);
+    // The output of the import, or the geodata that's updated.
     let output = PathBuf::from(&args[2]);
-
     let mut tmp_output = output.clone();
     tmp_output.set_extension("tmp");
 
-    match import(&input, &tmp_output, &output) {
-        Ok(_) => println!("Successfully imported OSM data to {}", output.to_string_lossy()),
+    let mut progress = Progress::new(progress_format);
+    let result = if is_update {
+        let changes = args[3..].iter().map(PathBuf::from).collect::<Vec<_>>();
+        update(&output, &changes, &tmp_output, options.bake.take(), &mut progress)
+            .map(|_| format!("Successfully updated {}", output.to_string_lossy()))
+    } else {
+        import(&PathBuf::from(&args[1]), &tmp_output, &output, &options, &mut progress)
+            .map(|_| format!("Successfully imported OSM data to {}", output.to_string_lossy()))
+    };
+    match result {
+        Ok(message) => progress.message(&message),
         Err(err) => {
             // Make a best-effort attempt to remove the unfinished mess
             // we may have potentially left behind, deliberately ignoring
@@ -1,22 +1,27 @@
-use renderer::http_server::run_server;
-use renderer::mapcss::styler::StyleType;
+mod common;
+
+use crate::common::{fail, get_flag_from_config, get_style_config, get_value_from_config, read_config};
+use renderer::api_keys::{ApiKeys, Quota};
+use renderer::cache_control::{CacheControl, CachePolicy};
+use renderer::coords::GeoBounds;
+use renderer::cors::Cors;
+use renderer::http_server::{run_server, MapConfig, ServerOptions};
+use renderer::mapcss::dark::{parse_exceptions, DarkMode};
+use renderer::mapcss::palette::parse_palettes;
+use renderer::memory_budget::MemoryBudget;
+use renderer::tile::TILE_SIZE;
+use renderer::tile_cache::disk::{self, DiskCache, DiskConfig};
+use renderer::tile_cache::memory::MemoryCache;
+use renderer::tile_cache::redis::{RedisCache, RedisConfig, DEFAULT_LOCK_TIMEOUT};
+use renderer::tile_cache::s3::{S3Cache, S3Config};
+use renderer::tile_cache::{KeyLayout, TileCache};
+use renderer::upstream::Upstream;
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 use tini::Ini;
 
-fn fail() -> ! {
-    std::process::exit(1);
-}
-
-fn get_value_from_config(config: &Ini, section: &str, name: &str) -> String {
-    match config.get(section, name) {
-        Some(value) => value,
-        _ => {
-            eprintln!("Property {} is missing in section [{}]", name, section);
-            fail();
-        }
-    }
-}
-
 fn main() {
     let args: Vec<_> = env::args().collect();
 
@@ -26,39 +31,66 @@ fn main() {
         fail();
     }
 
-    let config_path = &args[1];
-    let config = match Ini::from_file(config_path) {
-        Ok(config) => config,
-        Err(err) => {
-            eprintln!("Failed to parse config from {}: {}", config_path, err);
-            fail();
-        }
-    };
+    let config = read_config(&args[1]);
 
     let server_address = get_value_from_config(&config, "http", "address");
-    let geodata_file = get_value_from_config(&config, "geodata", "file");
-
-    let style_section = "style";
-    let stylesheet_file = get_value_from_config(&config, style_section, "file");
-    let stylesheet_type = match get_value_from_config(&config, style_section, "type").as_str() {
-        "josm" => StyleType::Josm,
-        "mapsme" => StyleType::MapsMe,
-        unknown_style => {
-            eprintln!("Unknown stylesheet type: {}", unknown_style);
-            fail();
-        }
-    };
-    let font_size_multiplier =
+
+    // The config itself describes the map at the root (if it has the geodata), and `[maps]` lists the configs
+    // of the other maps by their names.
+    let mut maps = Vec::new();
+    if config.get::<String>("geodata", "file").is_some() {
+        maps.extend(get_map_configs(None, &config));
+    }
+    for (name, map_config_path) in config.section_iter("maps") {
+        maps.extend(get_map_configs(Some(name.clone()), &read_config(map_config_path)));
+    }
+    let tile_size = config
+        .get::<String>("http", "tile-size")
+        .map_or(TILE_SIZE as usize, |size_str| match size_str.parse() {
+            Ok(size) => size,
+            Err(_) => {
+                eprintln!("Invalid tile size: {}", size_str);
+                fail();
+            }
+        });
+    let memory_budget =
         config
-            .get::<String>(style_section, "font-mul")
-            .map(|multiplier_str| match multiplier_str.parse() {
-                Ok(multiplier) => multiplier,
-                Err(_) => {
-                    eprintln!("Invalid font size multiplier: {}", multiplier_str);
+            .get::<String>("http", "max-memory")
+            .map(|max_memory| match max_memory.parse::<MemoryBudget>() {
+                Ok(budget) => budget,
+                Err(err) => {
+                    eprintln!("{}", err);
                     fail();
                 }
             });
 
+    // `[api-keys]` lists the keys with their quotas, e.g. `KEY = 1000/day, 20000/month`.
+    let quotas = config
+        .section_iter("api-keys")
+        .map(|(key, quota)| match quota.parse::<Quota>() {
+            Ok(quota) => (key.clone(), quota),
+            Err(err) => {
+                eprintln!("Invalid quota of {}: {:#}", key, err);
+                fail();
+            }
+        })
+        .collect::<HashMap<_, _>>();
+    let api_keys = if quotas.is_empty() {
+        None
+    } else {
+        match ApiKeys::new(
+            quotas,
+            config.get("http", "admin-key"),
+            config.get("http", "usage-file"),
+        ) {
+            Ok(api_keys) => Some(api_keys),
+            Err(err) => {
+                eprintln!("{:#}", err);
+                fail();
+            }
+        }
+    };
+
     let osm_ids = if args.len() >= 3 {
         Some(
             args[2..]
@@ -70,14 +102,27 @@ fn main() {
         None
     };
 
-    let res = run_server(
-        &server_address,
-        &geodata_file,
-        &stylesheet_file,
-        &stylesheet_type,
-        font_size_multiplier,
+    let (tile_cache, tile_cache_layout) = get_tile_cache(&config);
+    let options = ServerOptions {
+        tile_size,
         osm_ids,
-    );
+        memory_budget,
+        api_keys,
+        tile_cache,
+        tile_cache_layout,
+        memory_cache: get_memory_cache(&config),
+        cache_control: get_cache_control(&config),
+        cors: config
+            .get::<String>("http", "cors-origins")
+            .map_or_else(Cors::default, |origins| match origins.parse() {
+                Ok(cors) => cors,
+                Err(err) => {
+                    eprintln!("Invalid CORS origins: {:#}", err);
+                    fail();
+                }
+            }),
+    };
+    let res = run_server(&server_address, maps, options);
 
     if let Err(e) = res {
         for cause in e.chain() {
@@ -86,3 +131,258 @@ fn main() {
         fail();
     }
 }
+
+// The map itself, followed by its dark variant (`<name>-dark`, or `dark` for the map at the root) if the style
+// asks for one. The dark variant draws all the tiles itself, as the upstream tiles are light.
+fn get_map_configs(name: Option<String>, config: &Ini) -> Vec<MapConfig> {
+    let style_config = get_style_config(config);
+    let geodata_file = get_value_from_config(config, "geodata", "file");
+    let attribution = config.get::<String>("geodata", "attribution");
+    let palettes = config
+        .get::<String>("style", "palettes")
+        .map(|palettes| match parse_palettes(&palettes) {
+            Ok(palettes) => palettes,
+            Err(err) => {
+                eprintln!("Invalid palettes: {:#}", err);
+                fail();
+            }
+        })
+        .unwrap_or_default();
+    // E.g. `settings = pistes` turns on the rules of the stylesheet that test for `[setting("pistes")]`.
+    let settings: Vec<String> = config
+        .get::<String>("style", "settings")
+        .map(|settings| {
+            settings
+                .split(',')
+                .map(str::trim)
+                .filter(|setting| !setting.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let map_config = |name, draw_options, upstream, dark_mode| MapConfig {
+        name,
+        geodata_file: geodata_file.clone(),
+        stylesheet_file: style_config.file.clone(),
+        stylesheet_type: style_config.style_type.clone(),
+        font_size_multiplier: style_config.font_size_multiplier,
+        draw_options,
+        upstream,
+        dark_mode,
+        palettes: palettes.clone(),
+        settings: settings.clone(),
+        attribution: attribution.clone(),
+    };
+
+    let mut maps = vec![map_config(
+        name.clone(),
+        style_config.draw_options.clone(),
+        get_upstream(config),
+        None,
+    )];
+    if get_flag_from_config(config, "style", "dark-variant") {
+        let exceptions = config
+            .get::<String>("style", "dark-exceptions")
+            .map(|exceptions| match parse_exceptions(&exceptions) {
+                Ok(exceptions) => exceptions,
+                Err(err) => {
+                    eprintln!("Invalid dark mode exceptions: {:#}", err);
+                    fail();
+                }
+            })
+            .unwrap_or_default();
+        let dark_name = name
+            .as_ref()
+            .map_or_else(|| "dark".to_string(), |name| format!("{}-dark", name));
+        maps.push(map_config(
+            Some(dark_name),
+            style_config.draw_options.clone(),
+            None,
+            Some(DarkMode { exceptions }),
+        ));
+    }
+    maps
+}
+
+// `[upstream]` describes the tile server that serves the tiles that the geodata doesn't cover.
+fn get_upstream(config: &Ini) -> Option<Upstream> {
+    let url = config.get::<String>("upstream", "url")?;
+    let local_bounds =
+        config
+            .get::<String>("upstream", "local-bounds")
+            .map(|bounds| match bounds.parse::<GeoBounds>() {
+                Ok(bounds) => bounds,
+                Err(err) => {
+                    eprintln!("Invalid local bounds: {:#}", err);
+                    fail();
+                }
+            });
+    match Upstream::new(&url, local_bounds) {
+        Ok(upstream) => Some(upstream),
+        Err(err) => {
+            eprintln!("{:#}", err);
+            fail();
+        }
+    }
+}
+
+// `[cache]` describes where the drawn tiles are kept (if anywhere) and how they are named there.
+fn get_tile_cache(config: &Ini) -> (Option<Box<dyn TileCache>>, KeyLayout) {
+    let cache_section = "cache";
+    let layout = config.get::<String>(cache_section, "layout").map(|layout| {
+        layout.parse::<KeyLayout>().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            fail();
+        })
+    });
+    let get_duration = |name: &str| {
+        config
+            .get::<String>(cache_section, name)
+            .map(|duration| match parse_duration(&duration) {
+                Some(duration) => duration,
+                None => {
+                    eprintln!("Invalid {}: {} (expected e.g. 3600, 30m, 12h or 7d)", name, duration);
+                    fail();
+                }
+            })
+    };
+    let ttl = get_duration("ttl");
+
+    let cache_type = config.get::<String>(cache_section, "type");
+    let cache: Box<dyn TileCache> = match cache_type.as_deref() {
+        None => return (None, layout.unwrap_or_default()),
+        Some("disk") => {
+            if ttl.is_some() {
+                eprintln!("The tiles in a disk cache don't expire, they are deleted when the cache is full");
+                fail();
+            }
+            let max_size = get_value_from_config(config, cache_section, "max-size");
+            let disk_config = DiskConfig {
+                directory: PathBuf::from(get_value_from_config(config, cache_section, "directory")),
+                max_bytes: match max_size.parse::<MemoryBudget>() {
+                    Ok(size) => size.max_bytes as u64,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        fail();
+                    }
+                },
+            };
+            match DiskCache::new(disk_config) {
+                Ok(cache) => Box::new(cache),
+                Err(err) => {
+                    eprintln!("{:#}", err);
+                    fail();
+                }
+            }
+        }
+        Some("s3") => {
+            // The credentials can also come from the environment, like for the other S3 clients.
+            let get_secret = |name: &str, env_var: &str| {
+                config
+                    .get::<String>(cache_section, name)
+                    .or_else(|| env::var(env_var).ok())
+                    .unwrap_or_else(|| get_value_from_config(config, cache_section, name))
+            };
+            let s3_config = S3Config {
+                endpoint: get_value_from_config(config, cache_section, "endpoint"),
+                region: get_value_from_config(config, cache_section, "region"),
+                bucket: get_value_from_config(config, cache_section, "bucket"),
+                access_key_id: get_secret("access-key-id", "AWS_ACCESS_KEY_ID"),
+                secret_access_key: get_secret("secret-access-key", "AWS_SECRET_ACCESS_KEY"),
+                ttl,
+            };
+            match S3Cache::new(s3_config) {
+                Ok(cache) => Box::new(cache),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    fail();
+                }
+            }
+        }
+        Some("redis") => {
+            let redis_config = RedisConfig {
+                address: get_value_from_config(config, cache_section, "address"),
+                password: config
+                    .get::<String>(cache_section, "password")
+                    .or_else(|| env::var("REDIS_PASSWORD").ok()),
+                database: config.get::<String>(cache_section, "database").map(|database| {
+                    database.parse().unwrap_or_else(|_| {
+                        eprintln!("Invalid Redis database: {}", database);
+                        fail();
+                    })
+                }),
+                ttl,
+                lock_timeout: get_duration("lock-timeout").unwrap_or(DEFAULT_LOCK_TIMEOUT),
+            };
+            match RedisCache::new(redis_config) {
+                Ok(cache) => Box::new(cache),
+                Err(err) => {
+                    eprintln!("{:#}", err);
+                    fail();
+                }
+            }
+        }
+        Some(unknown_type) => {
+            eprintln!("Unknown cache type: {}", unknown_type);
+            fail();
+        }
+    };
+    let layout = layout.unwrap_or_else(|| match cache_type.as_deref() {
+        Some("disk") => disk::default_layout(),
+        _ => KeyLayout::default(),
+    });
+    (Some(cache), layout)
+}
+
+// `memory` in `[cache]` keeps the most recently requested tiles in memory too, with or without a `type`.
+fn get_memory_cache(config: &Ini) -> Option<MemoryCache> {
+    config
+        .get::<String>("cache", "memory")
+        .map(|size| match size.parse::<MemoryBudget>() {
+            Ok(size) => MemoryCache::new(size.max_bytes as u64),
+            Err(err) => {
+                eprintln!("{}", err);
+                fail();
+            }
+        })
+}
+
+// `[cache-control]` sets the caching headers of the tiles per zoom range, e.g. `0-10 = 7d` (a duration becomes
+// `max-age` and `Expires`) or `17- = no-cache` (anything else is used as `Cache-Control` as it is).
+fn get_cache_control(config: &Ini) -> CacheControl {
+    let mut cache_control = CacheControl::default();
+    for (zooms, policy) in config.section_iter("cache-control") {
+        let policy = match parse_duration(policy) {
+            Some(max_age) => CachePolicy::MaxAge(max_age),
+            None if policy.trim().starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                CachePolicy::Directives(policy.clone())
+            }
+            None => {
+                eprintln!(
+                    "Invalid cache policy of {}: {} (expected e.g. 3600, 12h, 7d or no-cache)",
+                    zooms, policy
+                );
+                fail();
+            }
+        };
+        if let Err(err) = cache_control.add(zooms, policy) {
+            eprintln!("{:#}", err);
+            fail();
+        }
+    }
+    cache_control
+}
+
+// A number of seconds, optionally with an `m`, `h` or `d` suffix.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let (number, multiplier) = match duration.char_indices().last()? {
+        (idx, 's') => (&duration[..idx], 1),
+        (idx, 'm') => (&duration[..idx], 60),
+        (idx, 'h') => (&duration[..idx], 3600),
+        (idx, 'd') => (&duration[..idx], 86400),
+        _ => (duration, 1),
+    };
+    let seconds = number.trim().parse::<u64>().ok()?.checked_mul(multiplier)?;
+    Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,213 @@
+mod common;
+
+use crate::common::{fail, get_style_config, get_value_from_config, read_config};
+use renderer::coords::GeoBounds;
+use renderer::geodata::reader::GeodataReader;
+use renderer::progress::ProgressFormat;
+use renderer::seeder::{run_seeder, DirectoryLayout, SeedArea, SeedOptions, SeedPolygon};
+use renderer::tile::MAX_ZOOM;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn usage(bin_name: &str) -> ! {
+    eprintln!(
+        "Usage: {} CONFIG OUTPUT_DIR|OUTPUT.pmtiles|OUTPUT.mbtiles (--bbox MIN_LON,MIN_LAT,MAX_LON,MAX_LAT | --place NAME | --polygon FILE.geojson | --expired-list FILE) --zoom MIN[-MAX] [--layout xyz|metatile] [--metatiles] [--threads N] [--resume] [--json-progress] [--skip-if-newer-than FILE|UNIX_TIMESTAMP]",
+        bin_name
+    );
+    fail();
+}
+
+fn parse_zoom_range(zoom_range: &str) -> Option<(u8, u8)> {
+    let (min_zoom, max_zoom) = match zoom_range.split_once('-') {
+        Some((min_zoom, max_zoom)) => (min_zoom.parse().ok()?, max_zoom.parse().ok()?),
+        None => {
+            let zoom = zoom_range.parse().ok()?;
+            (zoom, zoom)
+        }
+    };
+    if min_zoom <= max_zoom && max_zoom <= MAX_ZOOM {
+        Some((min_zoom, max_zoom))
+    } else {
+        None
+    }
+}
+
+// Either a UNIX timestamp or a file whose modification time should be used (e.g. the geodata file
+// itself, so that only the tiles rendered before the last import are re-rendered).
+fn parse_freshness_threshold(threshold: &str) -> Option<SystemTime> {
+    if let Ok(timestamp) = threshold.parse::<u64>() {
+        return Some(UNIX_EPOCH + Duration::from_secs(timestamp));
+    }
+    fs::metadata(threshold).and_then(|m| m.modified()).ok()
+}
+
+// If several places have the same name (e.g. a city and the administrative boundary around it),
+// the largest one is used.
+fn find_place(geodata_file: &str, name: &str) -> GeoBounds {
+    let reader = match GeodataReader::load(geodata_file) {
+        Ok(reader) => reader,
+        Err(err) => {
+            eprintln!("Failed to load the geodata file: {}", err);
+            fail();
+        }
+    };
+    let area = |b: &GeoBounds| (b.max_lon - b.min_lon) * (b.max_lat - b.min_lat);
+    match reader
+        .find_places(name)
+        .into_iter()
+        .max_by(|a, b| area(a).total_cmp(&area(b)))
+    {
+        Some(bounds) => {
+            eprintln!("Found {} at {}", name, bounds);
+            bounds
+        }
+        None => {
+            eprintln!("No place named {} found in the geodata", name);
+            fail();
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("seeder");
+
+    if args.len() < 3 {
+        usage(bin_name);
+    }
+
+    let config = read_config(&args[1]);
+    let geodata_file = get_value_from_config(&config, "geodata", "file");
+    let style_config = get_style_config(&config);
+
+    let mut area = None;
+    let mut zoom_range = None;
+    let mut layout = DirectoryLayout::Xyz;
+    let mut metatiles = false;
+    let mut threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut resume = false;
+    let mut progress_format = ProgressFormat::Text;
+    let mut skip_if_newer_than = None;
+
+    let mut flags = args[3..].iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || match flags.next() {
+            Some(value) => value.clone(),
+            None => {
+                eprintln!("Missing value for {}", flag);
+                usage(bin_name);
+            }
+        };
+        if ["--bbox", "--place", "--polygon", "--expired-list"].contains(&flag.as_str()) && area.is_some() {
+            eprintln!("Only one of --bbox, --place, --polygon and --expired-list can be used");
+            usage(bin_name);
+        }
+        match flag.as_str() {
+            "--bbox" => {
+                let bbox = value();
+                area = match bbox.parse::<GeoBounds>() {
+                    Ok(bounds) => Some(SeedArea::Bounds(bounds)),
+                    Err(err) => {
+                        eprintln!("Invalid bounding box: {}", err);
+                        fail();
+                    }
+                };
+            }
+            "--place" => area = Some(SeedArea::Bounds(find_place(&geodata_file, &value()))),
+            "--polygon" => {
+                area = match SeedPolygon::load(Path::new(&value())) {
+                    Ok(polygon) => Some(SeedArea::Polygon(polygon)),
+                    Err(err) => {
+                        eprintln!("{:#}", err);
+                        fail();
+                    }
+                };
+            }
+            "--zoom" => {
+                let zoom = value();
+                zoom_range = match parse_zoom_range(&zoom) {
+                    Some(zoom_range) => Some(zoom_range),
+                    None => {
+                        eprintln!("Invalid zoom range: {}", zoom);
+                        fail();
+                    }
+                };
+            }
+            "--expired-list" => area = Some(SeedArea::ExpiredList(PathBuf::from(value()))),
+            "--layout" => {
+                layout = match value().as_str() {
+                    "xyz" => DirectoryLayout::Xyz,
+                    "metatile" => DirectoryLayout::Metatile,
+                    unknown_layout => {
+                        eprintln!("Unknown directory layout: {}", unknown_layout);
+                        usage(bin_name);
+                    }
+                };
+            }
+            "--threads" => {
+                let thread_count = value();
+                threads = match thread_count.parse::<usize>() {
+                    Ok(threads) if threads > 0 => threads,
+                    _ => {
+                        eprintln!("Invalid number of threads: {}", thread_count);
+                        fail();
+                    }
+                };
+            }
+            "--metatiles" => metatiles = true,
+            "--resume" => resume = true,
+            "--json-progress" => progress_format = ProgressFormat::JsonLines,
+            "--skip-if-newer-than" => {
+                let threshold = value();
+                skip_if_newer_than = match parse_freshness_threshold(&threshold) {
+                    Some(threshold) => Some(threshold),
+                    None => {
+                        eprintln!("{} is neither a timestamp nor an existing file", threshold);
+                        fail();
+                    }
+                };
+            }
+            unknown_flag => {
+                eprintln!("Unknown option: {}", unknown_flag);
+                usage(bin_name);
+            }
+        }
+    }
+
+    let (area, (min_zoom, max_zoom)) = match (area, zoom_range) {
+        (Some(area), Some(zoom_range)) => (area, zoom_range),
+        _ => usage(bin_name),
+    };
+
+    let options = SeedOptions {
+        area,
+        min_zoom,
+        max_zoom,
+        output: PathBuf::from(&args[2]),
+        layout,
+        metatiles,
+        resume,
+        skip_if_newer_than,
+        threads,
+        progress_format,
+    };
+
+    let res = run_seeder(
+        &geodata_file,
+        &style_config.file,
+        &style_config.style_type,
+        style_config.font_size_multiplier,
+        style_config.draw_options,
+        &options,
+    );
+
+    if let Err(e) = res {
+        for cause in e.chain() {
+            eprintln!("{}", cause);
+        }
+        fail();
+    }
+}
@@ -0,0 +1,31 @@
+use renderer::draw::sprite::Sprite;
+use std::env;
+use std::path::Path;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    if args.len() != 3 {
+        let bin_name = args.first().map(String::as_str).unwrap_or("sprite_packer");
+        eprintln!("Usage: {} ICON_DIR OUTPUT_PREFIX", bin_name);
+        std::process::exit(1);
+    }
+
+    let icon_dir = Path::new(&args[1]);
+    let output_prefix = Path::new(&args[2]);
+
+    match Sprite::pack(icon_dir).and_then(|sprite| sprite.save(output_prefix)) {
+        Ok(_) => println!(
+            "Packed the icons from {} into {}.png and {}.json",
+            icon_dir.display(),
+            output_prefix.display(),
+            output_prefix.display()
+        ),
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use crate::common::{fail, get_style_config, get_value_from_config, read_config};
+use renderer::mapcss::audit::{audit_stylesheet, ContrastThresholds};
+use std::env;
+
+fn usage(bin_name: &str) -> ! {
+    eprintln!(
+        "Usage: {} CONFIG [--min-text-contrast RATIO] [--min-line-contrast RATIO]",
+        bin_name
+    );
+    fail();
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("style_audit");
+    if args.len() < 2 {
+        usage(bin_name);
+    }
+
+    let config = read_config(&args[1]);
+    let geodata_file = get_value_from_config(&config, "geodata", "file");
+    let style_config = get_style_config(&config);
+
+    let mut thresholds = ContrastThresholds::default();
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        let threshold = match flag.as_str() {
+            "--min-text-contrast" => &mut thresholds.text,
+            "--min-line-contrast" => &mut thresholds.lines,
+            unknown_flag => {
+                eprintln!("Unknown option: {}", unknown_flag);
+                usage(bin_name);
+            }
+        };
+        *threshold = match flags.next().map(|value| value.parse::<f64>()) {
+            Some(Ok(ratio)) if (1.0..=21.0).contains(&ratio) => ratio,
+            _ => {
+                eprintln!("{} needs a contrast ratio from 1 to 21", flag);
+                usage(bin_name);
+            }
+        };
+    }
+
+    match audit_stylesheet(
+        &style_config.file,
+        &style_config.style_type,
+        style_config.font_size_multiplier,
+        &geodata_file,
+        &thresholds,
+    ) {
+        Ok(issues) => {
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            if !issues.is_empty() {
+                eprintln!("Found {} issue(s)", issues.len());
+                fail();
+            }
+        }
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            fail();
+        }
+    }
+}
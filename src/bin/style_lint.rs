@@ -0,0 +1,30 @@
+use renderer::mapcss::lint::lint_stylesheet;
+use std::env;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    if args.len() != 3 {
+        let bin_name = args.first().map(String::as_str).unwrap_or("style_lint");
+        eprintln!("Usage: {} STYLESHEET GEODATA_FILE", bin_name);
+        std::process::exit(1);
+    }
+
+    match lint_stylesheet(&args[1], &args[2]) {
+        Ok(issues) => {
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            if !issues.is_empty() {
+                eprintln!("Found {} issue(s)", issues.len());
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::geodata::timestamp::format_http_date;
+use crate::tile::MAX_ZOOM;
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+
+/// How long the browsers and the CDNs may keep the tiles, given in the config per zoom range, e.g.
+/// `0-10 = 7d` for the low zoom levels that rarely change and `11-18 = 1h` for the rest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheControl {
+    rules: Vec<CacheControlRule>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct CacheControlRule {
+    min_zoom: u8,
+    max_zoom: u8,
+    policy: CachePolicy,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// `Cache-Control: public, max-age=...` together with the matching `Expires`.
+    MaxAge(Duration),
+    /// The value of `Cache-Control` as it is, e.g. `no-store`.
+    Directives(String),
+}
+
+impl CacheControl {
+    /// Adds the policy for a single zoom level (`15`), a range of them (`0-10`) or everything starting
+    /// from a zoom level (`15-`). If the ranges overlap, the one that was added first wins.
+    pub fn add(&mut self, zooms: &str, policy: CachePolicy) -> Result<()> {
+        let parse_zoom = |zoom: &str| {
+            zoom.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|zoom| *zoom <= MAX_ZOOM)
+                .with_context(|| format!("Invalid zoom level: {}", zoom))
+        };
+        let (min_zoom, max_zoom) = match zooms.split_once('-') {
+            Some((min_zoom, max_zoom)) if max_zoom.trim().is_empty() => (parse_zoom(min_zoom)?, MAX_ZOOM),
+            Some((min_zoom, max_zoom)) => (parse_zoom(min_zoom)?, parse_zoom(max_zoom)?),
+            None => {
+                let zoom = parse_zoom(zooms)?;
+                (zoom, zoom)
+            }
+        };
+        if min_zoom > max_zoom {
+            bail!("Invalid zoom range: {}", zooms);
+        }
+        if let CachePolicy::Directives(directives) = &policy {
+            if directives.trim().is_empty() || directives.contains(['\r', '\n']) {
+                bail!("Invalid Cache-Control value: {:?}", directives);
+            }
+        }
+        self.rules.push(CacheControlRule {
+            min_zoom,
+            max_zoom,
+            policy,
+        });
+        Ok(())
+    }
+
+    /// The headers of a tile at the zoom level, served at `now` (a UNIX timestamp). There are none
+    /// for the zoom levels that aren't in the config.
+    pub fn headers(&self, zoom: u8, now: i64) -> Vec<String> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| (rule.min_zoom..=rule.max_zoom).contains(&zoom));
+        match rule.map(|rule| &rule.policy) {
+            Some(CachePolicy::MaxAge(max_age)) => vec![
+                format!("Cache-Control: public, max-age={}", max_age.as_secs()),
+                format!("Expires: {}", format_http_date(now + max_age.as_secs() as i64)),
+            ],
+            Some(CachePolicy::Directives(directives)) => vec![format!("Cache-Control: {}", directives.trim())],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let mut cache_control = CacheControl::default();
+        cache_control
+            .add("0-10", CachePolicy::MaxAge(Duration::from_secs(86400)))
+            .unwrap();
+        cache_control
+            .add("10", CachePolicy::Directives("no-store".to_string()))
+            .unwrap();
+        cache_control
+            .add("15-", CachePolicy::Directives("no-cache".to_string()))
+            .unwrap();
+
+        assert_eq!(
+            cache_control.headers(10, 0),
+            vec![
+                "Cache-Control: public, max-age=86400",
+                "Expires: Fri, 02 Jan 1970 00:00:00 GMT"
+            ]
+        );
+        assert!(cache_control.headers(12, 0).is_empty());
+        assert_eq!(cache_control.headers(MAX_ZOOM, 0), vec!["Cache-Control: no-cache"]);
+
+        let no_store = || CachePolicy::Directives("no-store".to_string());
+        assert!(cache_control.add("10-5", no_store()).is_err());
+        assert!(cache_control.add("0-100", no_store()).is_err());
+        assert!(cache_control.add("low", no_store()).is_err());
+        assert!(cache_control
+            .add("1", CachePolicy::Directives("no-store\r\nX-Evil: 1".to_string()))
+            .is_err());
+    }
+}
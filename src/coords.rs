@@ -1,3 +1,7 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::str::FromStr;
+
 pub trait Coords {
     fn lat(&self) -> f64;
     fn lon(&self) -> f64;
@@ -12,3 +16,43 @@ impl Coords for (f64, f64) {
         self.1
     }
 }
+
+/// A geographic bounding box, usually written as `min_lon,min_lat,max_lon,max_lat`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeoBounds {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl FromStr for GeoBounds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split(',')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .context(format!("<{}> contains an invalid coordinate", s))?;
+        if parts.len() != 4 {
+            bail!("Expected min_lon,min_lat,max_lon,max_lat, got <{}>", s);
+        }
+        let bounds = GeoBounds {
+            min_lon: parts[0],
+            min_lat: parts[1],
+            max_lon: parts[2],
+            max_lat: parts[3],
+        };
+        if bounds.min_lon > bounds.max_lon || bounds.min_lat > bounds.max_lat {
+            bail!("<{}> has its minimum and maximum coordinates swapped", s);
+        }
+        Ok(bounds)
+    }
+}
+
+impl fmt::Display for GeoBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.min_lon, self.min_lat, self.max_lon, self.max_lat)
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// The origins of the pages that may use the responses of the server, e.g. the maps on other sites that
+/// load the tiles with `fetch` (like MapLibre does).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Cors {
+    /// Any page, with `Access-Control-Allow-Origin: *`.
+    #[default]
+    AnyOrigin,
+    /// Only the pages from these origins (e.g. `https://example.com`). An empty list turns CORS off.
+    Origins(Vec<String>),
+}
+
+// How long the browsers may remember the answer to a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+impl FromStr for Cors {
+    type Err = anyhow::Error;
+
+    /// Parses `*` or a comma-separated list of origins.
+    /// # Examples
+    /// ```
+    /// use renderer::cors::Cors;
+    /// assert_eq!("*".parse::<Cors>().unwrap(), Cors::AnyOrigin);
+    /// let cors = "https://example.com, http://localhost:3000".parse::<Cors>().unwrap();
+    /// assert_eq!(cors, Cors::Origins(vec!["https://example.com".to_string(), "http://localhost:3000".to_string()]));
+    /// assert!("example.com".parse::<Cors>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Cors> {
+        if s.trim() == "*" {
+            return Ok(Cors::AnyOrigin);
+        }
+        let origins = s
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/'))
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                let is_valid = origin
+                    .split_once("://")
+                    .is_some_and(|(scheme, host)| !scheme.is_empty() && !host.is_empty() && !host.contains('/'))
+                    && origin.chars().all(|c| c.is_ascii_graphic());
+                if !is_valid {
+                    bail!("Expected * or origins like https://example.com, got <{}>", origin);
+                }
+                Ok(origin.to_string())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Cors::Origins(origins))
+    }
+}
+
+impl Cors {
+    /// The headers of a response to a request with the given `Origin` header.
+    pub fn headers(&self, origin: Option<&str>) -> Vec<String> {
+        match self {
+            Cors::AnyOrigin => vec!["Access-Control-Allow-Origin: *".to_string()],
+            Cors::Origins(origins) if origins.is_empty() => Vec::new(),
+            // The response depends on the origin, so the caches between the server and the clients
+            // have to know about it.
+            Cors::Origins(origins) => match origin.filter(|origin| origins.iter().any(|o| o == origin)) {
+                Some(origin) => vec![
+                    format!("Access-Control-Allow-Origin: {}", origin),
+                    "Vary: Origin".to_string(),
+                ],
+                None => vec!["Vary: Origin".to_string()],
+            },
+        }
+    }
+
+    /// The headers of a response to a preflight request, which the browsers send before the requests with
+    /// the headers that aren't safelisted (e.g. a custom `Authorization`). The browser sends the headers it
+    /// would like to use in `Access-Control-Request-Headers`, and they're all allowed.
+    pub fn preflight_headers(&self, origin: Option<&str>, request_headers: Option<&str>) -> Vec<String> {
+        let mut headers = self.headers(origin);
+        if headers
+            .iter()
+            .any(|header| header.starts_with("Access-Control-Allow-Origin"))
+        {
+            headers.push("Access-Control-Allow-Methods: GET, OPTIONS".to_string());
+            if let Some(request_headers) = request_headers.filter(|h| !h.trim().is_empty()) {
+                headers.push(format!("Access-Control-Allow-Headers: {}", request_headers.trim()));
+            }
+            headers.push(format!("Access-Control-Max-Age: {}", PREFLIGHT_MAX_AGE));
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors() {
+        let any = Cors::AnyOrigin;
+        assert_eq!(any.headers(None), vec!["Access-Control-Allow-Origin: *"]);
+        assert_eq!(
+            any.preflight_headers(Some("https://example.com"), Some("authorization")),
+            vec![
+                "Access-Control-Allow-Origin: *",
+                "Access-Control-Allow-Methods: GET, OPTIONS",
+                "Access-Control-Allow-Headers: authorization",
+                "Access-Control-Max-Age: 86400",
+            ]
+        );
+
+        let listed = "https://example.com/".parse::<Cors>().unwrap();
+        assert_eq!(
+            listed.headers(Some("https://example.com")),
+            vec!["Access-Control-Allow-Origin: https://example.com", "Vary: Origin"]
+        );
+        assert_eq!(listed.headers(Some("https://example.org")), vec!["Vary: Origin"]);
+        assert_eq!(listed.headers(None), vec!["Vary: Origin"]);
+        assert_eq!(
+            listed.preflight_headers(Some("https://example.org"), None),
+            vec!["Vary: Origin"]
+        );
+
+        let none = "".parse::<Cors>().unwrap();
+        assert!(none.headers(Some("https://example.com")).is_empty());
+        assert!(none.preflight_headers(Some("https://example.com"), None).is_empty());
+
+        assert!("https://example.com/tiles".parse::<Cors>().is_err());
+        assert!("https://example.com\r\nX-Evil: 1".parse::<Cors>().is_err());
+    }
+}
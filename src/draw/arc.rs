@@ -0,0 +1,158 @@
+use crate::draw::tile_pixels::{RgbaColor, TilePixels};
+use crate::mapcss::color::Color;
+
+// Pixel coordinates relative to the top left corner of the tile.
+pub type ArcPoint = (f64, f64);
+
+/// Draws the part of the circle around `center` that goes clockwise from the `start` bearing to the `end` one
+/// (in degrees, clockwise from the north, i.e. from the top of the tile), or the whole circle without the bearings.
+/// The ends of the arc are cut straight across, along the radius.
+pub fn draw_arc(
+    center: ArcPoint,
+    radius: f64,
+    bearings: Option<(f64, f64)>,
+    width: f64,
+    color: &Color,
+    opacity: f64,
+    pixels: &mut TilePixels,
+) {
+    let half_width = width / 2.0;
+    draw_around(center, radius + half_width, color, opacity, pixels, |offset| {
+        arc_coverage(offset, radius, half_width, bearings)
+    });
+}
+
+/// Fills the sector of the disc around `center` between the bearings (see `draw_arc`), or the whole disc
+/// without them.
+pub fn fill_sector(
+    center: ArcPoint,
+    radius: f64,
+    bearings: Option<(f64, f64)>,
+    color: &Color,
+    opacity: f64,
+    pixels: &mut TilePixels,
+) {
+    draw_around(center, radius, color, opacity, pixels, |offset| {
+        sector_coverage(offset, radius, bearings)
+    });
+}
+
+// Sets the pixels of the tile that are at most `reach` away from the center, with the opacity multiplied by
+// how much of every pixel the shape covers.
+fn draw_around(
+    center: ArcPoint,
+    reach: f64,
+    color: &Color,
+    opacity: f64,
+    pixels: &mut TilePixels,
+    coverage: impl Fn(ArcPoint) -> f64,
+) {
+    let reach = reach + 1.0;
+    let bb = pixels.bb();
+    let (min_x, max_x) = (
+        ((center.0 - reach).floor() as i32).max(bb.min_x),
+        ((center.0 + reach).ceil() as i32).min(bb.max_x),
+    );
+    let (min_y, max_y) = (
+        ((center.1 - reach).floor() as i32).max(bb.min_y),
+        ((center.1 + reach).ceil() as i32).min(bb.max_y),
+    );
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let coverage = coverage((f64::from(x) - center.0, f64::from(y) - center.1));
+            if coverage > 0.0 {
+                pixels.set_pixel(x, y, &RgbaColor::from_color(color, opacity * coverage));
+            }
+        }
+    }
+}
+
+// How much of the pixel that is `offset` away from the center the arc covers, from 0 to 1.
+pub(super) fn arc_coverage(offset: ArcPoint, radius: f64, half_width: f64, bearings: Option<(f64, f64)>) -> f64 {
+    let distance = offset.0.hypot(offset.1);
+    let across = (half_width + 0.5 - (distance - radius).abs()).clamp(0.0, 1.0);
+    if across == 0.0 {
+        return 0.0;
+    }
+    across * bearings_coverage(offset, bearings)
+}
+
+// The same for the sector.
+pub(super) fn sector_coverage(offset: ArcPoint, radius: f64, bearings: Option<(f64, f64)>) -> f64 {
+    let inside = (radius + 0.5 - offset.0.hypot(offset.1)).clamp(0.0, 1.0);
+    if inside == 0.0 {
+        return 0.0;
+    }
+    inside * bearings_coverage(offset, bearings)
+}
+
+// How much of the pixel is between the radii that go in the directions of the bearings.
+fn bearings_coverage(offset: ArcPoint, bearings: Option<(f64, f64)>) -> f64 {
+    let (start, end) = match bearings {
+        Some((start, end)) if (end - start).abs() < 360.0 => (start, end),
+        _ => return 1.0,
+    };
+    // The y axis goes down, so the north is -y.
+    let bearing = offset.0.atan2(-offset.1).to_degrees();
+    let span = (end - start).rem_euclid(360.0);
+    let from_start = (bearing - start).rem_euclid(360.0);
+    // How far the direction is from the nearest of the bearings: positive between them and negative outside.
+    let degrees = if from_start <= span {
+        from_start.min(span - from_start)
+    } else {
+        -(from_start - span).min(360.0 - from_start)
+    };
+    let distance = offset.0.hypot(offset.1) * degrees.clamp(-90.0, 90.0).to_radians().sin();
+    (distance + 0.5).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arc_coverage() {
+        // The north-east quarter of a circle with a radius of 10 pixels and a width of 2 pixels.
+        let quarter = Some((0.0, 90.0));
+        let coverage = |x, y| arc_coverage((x, y), 10.0, 1.0, quarter);
+        assert_eq!(coverage(7.0, -7.0), 1.0);
+        assert_eq!(coverage(-7.0, -7.0), 0.0);
+        assert_eq!(coverage(7.0, 7.0), 0.0);
+        // Off the ring.
+        assert_eq!(coverage(3.0, -3.0), 0.0);
+        assert_eq!(coverage(0.0, -12.0), 0.0);
+        assert_eq!(arc_coverage((0.0, -11.0), 10.0, 1.0, None), 0.5);
+        // The ends are cut along the radius.
+        assert_eq!(coverage(0.0, -10.0), 0.5);
+        assert_eq!(coverage(10.0, 0.0), 0.5);
+        assert_eq!(coverage(-1.0, -10.0), 0.0);
+
+        // The bearings go clockwise, even across the north.
+        let across_north = |x, y| arc_coverage((x, y), 10.0, 1.0, Some((300.0, 60.0)));
+        assert_eq!(across_north(0.0, -10.0), 1.0);
+        assert_eq!(across_north(0.0, 10.0), 0.0);
+        assert_eq!(arc_coverage((0.0, 10.0), 10.0, 1.0, Some((-90.0, 270.0))), 1.0);
+        assert_eq!(arc_coverage((0.0, 10.0), 10.0, 1.0, None), 1.0);
+    }
+
+    #[test]
+    fn test_sector_coverage() {
+        // The southern half of a disc with a radius of 10 pixels.
+        let half = Some((90.0, 270.0));
+        let coverage = |x, y| sector_coverage((x, y), 10.0, half);
+        assert_eq!(coverage(0.0, 5.0), 1.0);
+        assert_eq!(coverage(3.0, 9.0), 1.0);
+        assert_eq!(coverage(0.0, -5.0), 0.0);
+        assert_eq!(coverage(0.0, 12.0), 0.0);
+        assert_eq!(coverage(0.0, 10.5), 0.0);
+        assert_eq!(coverage(0.0, 10.0), 0.5);
+        // Along the straight edge, and right at the center.
+        assert!((coverage(5.0, 0.0) - 0.5).abs() < 1e-9);
+        assert!((coverage(0.0, 0.0) - 0.5).abs() < 1e-9);
+
+        // The whole disc, center included.
+        assert_eq!(sector_coverage((0.0, 0.0), 10.0, None), 1.0);
+        assert_eq!(sector_coverage((-6.0, -6.0), 10.0, Some((0.0, 360.0))), 1.0);
+        assert_eq!(sector_coverage((-8.0, -8.0), 10.0, None), 0.0);
+    }
+}
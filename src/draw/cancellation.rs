@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Lets the renders that nobody waits for anymore stop early. The clones of a token share its state,
+/// so one of them can be handed to the code that decides to cancel, and another one to the drawer.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    is_cancelled: Arc<AtomicBool>,
+}
+
+/// The error that the drawer returns for the cancelled renders.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The rendering was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.is_cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.is_cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,20 +1,166 @@
+use crate::draw::arc::{draw_arc, fill_sector};
+use crate::draw::cancellation::CancellationToken;
 use crate::draw::fill::{fill_contour, Filler};
-use crate::draw::icon_cache::IconCache;
-use crate::draw::labeler::Labeler;
-use crate::draw::line::draw_lines;
-use crate::draw::png_writer::rgb_triples_to_png;
-use crate::draw::point_pairs::PointPairCollection;
-use crate::draw::tile_pixels::{RgbTriples, TilePixels};
-use crate::geodata::reader::{Node, OsmEntities, OsmEntity};
+use crate::draw::filters::{apply_filters, Filter};
+use crate::draw::font::abbreviations::Abbreviations;
+use crate::draw::icon_cache::{IconCache, IconKey};
+use crate::draw::icon_rotation::{get_icon_angle, get_way_bearings};
+use crate::draw::labeler::{LabelContext, Labeler};
+use crate::draw::line::{draw_lines, DrawnSegments, LineCaps, Stroke};
+use crate::draw::png_writer::{deep_rgba_quadruples_to_png, rgb_triples_to_png, rgba_quadruples_to_png};
+use crate::draw::poi_cluster::{cluster_pois, PoiCluster};
+use crate::draw::point::Point;
+use crate::draw::point_pairs::{simplify, PointPairCollection, PointPairIter};
+use crate::draw::qa::draw_qa_overlay;
+use crate::draw::raw_writer::{rgb_triples_to_ppm, rgba_quadruples_to_pam, rgba_quadruples_to_raw};
+use crate::draw::render_cost::RenderCosts;
+use crate::draw::render_order::RenderOrder;
+use crate::draw::sprite::Sprite;
+use crate::draw::svg_writer::{SvgPoint, SvgWriter};
+use crate::draw::ticks::{place_ticks, TickPoint};
+use crate::draw::tile_pixels::{to_8_bit, DeepRgbaQuadruples, RgbTriples, RgbaColor, TilePixels};
+use crate::draw::webp_writer::{rgb_triples_to_webp, rgba_quadruples_to_webp};
+use crate::geodata::landcover::{is_aggregated, landcover_class, LandcoverGrid, GRID_SIZE, LANDCOVER_ZOOM};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntities, OsmEntity, Way};
+use crate::mapcss::color::Color;
+use crate::mapcss::priority::AreaPriorities;
 use crate::mapcss::styler::{Style, StyledArea, Styler, TextPosition};
-use crate::tile::Tile;
+use crate::tile::{coords_to_xy_tile_relative, pixels_per_meter, zoom_offset_for_tile_size, Tile};
 use anyhow::Result;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct Drawer {
     icon_cache: IconCache,
     labeler: Labeler,
+    render_costs: Option<RenderCosts>,
+    // Draws the bands and the layers of the dense tiles, with `tile_threads` threads.
+    thread_pool: Option<ThreadPool>,
+    options: DrawOptions,
+}
+
+// What differs between the renders of the same tile.
+struct RenderControl<'c> {
+    cancellation: &'c CancellationToken,
+    // Zero means full detail, see `RenderCosts`.
+    simplification_level: u8,
+}
+
+/// Rendering modes that are not controlled by the stylesheet.
+#[derive(Clone, Default)]
+pub struct DrawOptions {
+    /// Draw the nodes without tags as small squares, like JOSM does it. This only makes sense for
+    /// the geodata imported with the untagged nodes kept.
+    pub draw_vertices: bool,
+    /// Highlight the places where the data is likely to be broken: unclosed ways that are styled as areas,
+    /// ways with duplicate consecutive nodes and self-intersecting ways.
+    pub qa_overlay: bool,
+    /// At low zooms, draw a single icon for the POIs of the same kind that are too close to each other.
+    pub cluster_pois: bool,
+    /// Draw the number of POIs that a clustered icon stands for next to it.
+    pub cluster_counts: bool,
+    /// Used to shorten the street names that don't fit along their ways.
+    pub abbreviations: Abbreviations,
+    /// The packed icons of the style, used instead of the individual icon files.
+    pub sprite: Option<Arc<Sprite>>,
+    /// The passes that the areas are drawn in. Without it, all the fills are drawn first, then the casings
+    /// and then the strokes.
+    pub render_order: Option<Arc<RenderOrder>>,
+    /// Draw the dense tiles with up to this many threads: the styles of the areas are matched in parallel,
+    /// and then every thread draws a horizontal band of the tile. Zero and one mean a single thread.
+    pub tile_threads: usize,
+    /// With `tile_threads`, also draw the fills, the casings and the strokes of every band into layers
+    /// of their own at the same time, and then blend them together in the usual order. The antialiased
+    /// edges can end up a color step away from the ones drawn in a single layer.
+    pub parallel_layers: bool,
+    /// How long a tile may take to draw. The neighbors of the tiles that take longer are drawn with less detail:
+    /// the smallest areas are skipped and the vertices that are too close to each other are merged.
+    pub render_budget: Option<Duration>,
+    /// Applied to the pixels of every tile before it's encoded (except for the SVG tiles).
+    pub filters: Vec<Filter>,
+    /// Which of the overlapping areas drawn at the same level (e.g. landuse polygons of different kinds)
+    /// ends up on top.
+    pub area_priorities: AreaPriorities,
+}
+
+/// What a raster tile is drawn and encoded as, apart from the tile itself.
+#[derive(Clone, Copy)]
+pub struct TileRequest<'c> {
+    pub scale: usize,
+    pub layers: TileLayers,
+    pub format: ImageFormat,
+    /// Stops the render once nobody waits for the tile anymore.
+    pub cancellation: &'c CancellationToken,
+}
+
+/// The parts of the map that end up in a tile.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TileLayers {
+    /// Everything, as usual.
+    #[default]
+    All,
+    /// Everything except for the labels and icons.
+    Base,
+    /// Only the labels and icons, on a transparent background, to be drawn on top of the base layer.
+    Labels,
+}
+
+/// The encodings that a tile can be returned in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// Uncompressed RGBA bytes without any header.
+    Rgba,
+    /// Uncompressed RGBA with a PAM header.
+    Pam,
+    /// Uncompressed RGB with a PPM header, which drops the transparency of the labels layer.
+    Ppm,
+    /// PNG with 16 bits per channel, for the exports that are edited further (e.g. composited with
+    /// other translucent layers) and would show banding with 8 bits.
+    Png16,
+    /// Lossless WebP, which is usually quite a bit smaller than PNG.
+    WebP,
+}
+
+impl ImageFormat {
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match extension {
+            "png" => Some(ImageFormat::Png),
+            "rgba" => Some(ImageFormat::Rgba),
+            "pam" => Some(ImageFormat::Pam),
+            "ppm" => Some(ImageFormat::Ppm),
+            "png16" => Some(ImageFormat::Png16),
+            "webp" => Some(ImageFormat::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Rgba => "rgba",
+            ImageFormat::Pam => "pam",
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png16 => "png16",
+            ImageFormat::WebP => "webp",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png | ImageFormat::Png16 => "image/png",
+            ImageFormat::Rgba => "application/octet-stream",
+            ImageFormat::Pam => "image/x-portable-arbitrarymap",
+            ImageFormat::Ppm => "image/x-portable-pixmap",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Hash)]
@@ -30,31 +176,190 @@ pub struct TileRenderedPixels {
 }
 
 impl Drawer {
-    pub fn new(base_path: &Path) -> Drawer {
+    pub fn new(base_path: &Path, options: DrawOptions) -> Drawer {
         Drawer {
-            icon_cache: IconCache::new(base_path),
-            labeler: Labeler::default(),
+            icon_cache: IconCache::new(base_path, options.sprite.clone()),
+            labeler: Labeler::new(options.abbreviations.clone()),
+            render_costs: options.render_budget.map(RenderCosts::new),
+            // Without the threads, the tiles are just drawn by the calling one.
+            thread_pool: (options.tile_threads > 1)
+                .then(|| create_thread_pool(options.tile_threads))
+                .flatten(),
+            options,
         }
     }
 
+    /// The memory taken by the icons that have been loaded so far.
+    pub fn icon_cache_usage(&self) -> usize {
+        self.icon_cache.used_bytes()
+    }
+
     pub fn draw_tile(
         &self,
         entities: &OsmEntities<'_>,
         tile: &Tile,
         pixels: &mut TilePixels,
-        scale: usize,
         styler: &Styler,
+        request: &TileRequest<'_>,
     ) -> Result<Vec<u8>> {
-        let rendered_pixels = self.draw_to_pixels(entities, tile, pixels, scale, styler);
+        let start_time = Instant::now();
+        self.draw_layers(entities, tile, tile.zoom, pixels, styler, request)?;
+        let encoded = encode_tile(
+            &self.filtered_pixels(pixels),
+            pixels.dimension(),
+            request.layers,
+            request.format,
+        );
+        self.record_render_cost(tile, pixels, start_time);
+        encoded
+    }
 
-        {
-            let _m = crate::perf_stats::measure("RGB triples to PNG");
-            rgb_triples_to_png(
-                &rendered_pixels.triples,
-                rendered_pixels.dimension,
-                rendered_pixels.dimension,
-            )
+    /// Draws a tile with the styles of its zoom level and of the next one mixed together, with `fade` going
+    /// from 0 (only the current styles) to 1 (only the next ones). This lets the clients that zoom continuously
+    /// change the styles smoothly instead of all at once at every zoom level.
+    pub fn draw_cross_faded_tile(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        pixels: &mut TilePixels,
+        styler: &Styler,
+        fade: f64,
+        request: &TileRequest<'_>,
+    ) -> Result<Vec<u8>> {
+        let start_time = Instant::now();
+        self.draw_layers(entities, tile, tile.zoom, pixels, styler, request)?;
+        let current = pixels.to_deep_rgba_quadruples();
+        self.draw_layers(entities, tile, tile.zoom + 1, pixels, styler, request)?;
+        let next = pixels.to_deep_rgba_quadruples();
+
+        let _m = crate::perf_stats::measure("Cross-fade");
+        let mut faded = cross_fade(&current, &next, fade.clamp(0.0, 1.0));
+        apply_filters(&self.options.filters, &mut faded, pixels.dimension());
+        let encoded = encode_tile(&faded, pixels.dimension(), request.layers, request.format);
+        self.record_render_cost(tile, pixels, start_time);
+        encoded
+    }
+
+    /// Draws the fills, the casings and the lines of the tile as SVG, in the same order as they are drawn
+    /// on the raster tiles. The labels, the icons and the `fill-image` patterns are left out.
+    /// Panics if the tile size isn't supported (see `zoom_offset_for_tile_size`).
+    pub fn draw_svg_tile(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        tile_size: usize,
+        scale: usize,
+        styler: &Styler,
+    ) -> String {
+        let zoom_offset = zoom_offset_for_tile_size(tile_size as u32).expect("Unsupported tile size");
+        let tile = &tile.top_left_descendant(zoom_offset);
+        let float_scale = scale as f64;
+        let mut styled_areas =
+            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, false);
+        self.options.area_priorities.sort(&mut styled_areas);
+        let to_svg_points = |nodes: &mut dyn Iterator<Item = Node<'_>>| {
+            nodes
+                .map(|node| {
+                    let (x, y) = coords_to_xy_tile_relative(&node, tile);
+                    (x * float_scale, y * float_scale)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut svg = SvgWriter::new(tile_size * scale, &styler.canvas_fill_color);
+        let pass_count = self.options.render_order.as_ref().map_or(1, |order| order.pass_count());
+        for pass in 0..pass_count {
+            for draw_type in [DrawType::Fill, DrawType::Casing, DrawType::Stroke] {
+                let mut drawn_boundary_segments = HashMap::<StrokeKey, HashSet<SvgSegment>>::new();
+                for (area, style) in styled_areas.iter().filter(|(area, style)| {
+                    self.get_render_pass(style, &draw_type) == pass
+                        && !is_too_small(area, style, tile, float_scale, 0.0)
+                }) {
+                    // Of the multipolygons, only the boundaries have their outlines drawn as well.
+                    let has_rings = |rel: &Multipolygon<'_>| draw_type == DrawType::Fill || is_boundary_relation(rel);
+                    let rings = match area {
+                        StyledArea::Way(way) => {
+                            vec![to_svg_points(&mut (0..way.node_count()).map(|idx| way.get_node(idx)))]
+                        }
+                        StyledArea::Multipolygon(rel) if has_rings(rel) => (0..rel.polygon_count())
+                            .map(|idx| {
+                                let polygon = rel.get_polygon(idx);
+                                to_svg_points(&mut (0..polygon.node_count()).map(|node_idx| polygon.get_node(node_idx)))
+                            })
+                            .collect(),
+                        StyledArea::Multipolygon(_) => continue,
+                    };
+                    let line_scale = line_scale(style, &draw_type, tile, float_scale);
+                    let scale_dashes = |dashes: &Option<Vec<f64>>| {
+                        dashes
+                            .as_ref()
+                            .map(|nums| nums.iter().map(|x| x * line_scale).collect())
+                    };
+                    // The lines to stroke, each with how far along the whole line it starts.
+                    let lines = match draw_type {
+                        DrawType::Fill => Vec::new(),
+                        _ if is_boundary(area) => {
+                            let drawn = drawn_boundary_segments
+                                .entry(StrokeKey::new(style, &draw_type))
+                                .or_default();
+                            rings.iter().flat_map(|ring| undrawn_parts(ring, drawn)).collect()
+                        }
+                        _ => vec![(0.0, rings[0].as_slice())],
+                    };
+                    match draw_type {
+                        DrawType::Fill => {
+                            if let Some(color) = &style.fill_color {
+                                svg.fill(&rings, color, style.fill_opacity.unwrap_or(1.0));
+                            }
+                        }
+                        DrawType::Casing => {
+                            if let (Some(color), Some(width)) = (&style.casing_color, style.casing_width) {
+                                let dashes = scale_dashes(&style.casing_dashes);
+                                let dashes_offset = style.casing_dashes_offset.unwrap_or_default() * line_scale;
+                                for (distance, points) in &lines {
+                                    svg.stroke(
+                                        points,
+                                        width * line_scale,
+                                        color,
+                                        1.0,
+                                        &dashes,
+                                        dashes_offset + distance,
+                                        &style.casing_line_cap,
+                                    );
+                                }
+                            }
+                        }
+                        DrawType::Stroke => {
+                            if let Some(color) = &style.color {
+                                let width = style.width.unwrap_or(1.0) * line_scale;
+                                let opacity = style.opacity.unwrap_or(1.0);
+                                let dashes = scale_dashes(&style.dashes);
+                                let dashes_offset = style.dashes_offset.unwrap_or_default() * line_scale;
+                                for (distance, points) in &lines {
+                                    svg.stroke(
+                                        points,
+                                        width,
+                                        color,
+                                        opacity,
+                                        &dashes,
+                                        dashes_offset + distance,
+                                        &style.line_cap,
+                                    );
+                                }
+                                if let Some(ticks) = &style.ticks {
+                                    let segments = rings.iter().flat_map(|ring| ring.windows(2).map(|w| (w[0], w[1])));
+                                    let tick_width = ticks.width.map_or(width, |width| width * line_scale);
+                                    for (start, end) in place_ticks(segments, ticks, line_scale) {
+                                        svg.stroke(&[start, end], tick_width, color, opacity, &None, 0.0, &None);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
         }
+        svg.finish()
     }
 
     pub fn draw_to_pixels(
@@ -65,69 +370,360 @@ impl Drawer {
         scale: usize,
         styler: &Styler,
     ) -> TileRenderedPixels {
-        {
-            let _m = crate::perf_stats::measure("Resetting TilePixels");
-            pixels.reset(&styler.canvas_fill_color);
-        }
+        // Nothing can cancel this render, so it always succeeds.
+        let never_cancelled = CancellationToken::new();
+        let request = pixels_request(scale, &never_cancelled);
+        let start_time = Instant::now();
+        self.draw_layers(entities, tile, tile.zoom, pixels, styler, &request)
+            .unwrap();
 
-        let styled_areas = {
-            let _m = crate::perf_stats::measure("Style areas");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, false)
+        let rendered = TileRenderedPixels {
+            triples: to_rgb_triples(&self.filtered_pixels(pixels)),
+            dimension: pixels.dimension(),
         };
+        self.record_render_cost(tile, pixels, start_time);
+        rendered
+    }
 
-        let float_scale = scale as f64;
+    /// Draws the block of tiles that `metatile` covers (see `Tile::metatile`) at once and cuts it into the tiles,
+    /// which are listed column by column. This saves styling the same objects for every tile again, and keeps
+    /// the labels and the dashes continuous from tile to tile. The entities have to be the ones of `metatile`,
+    /// and `pixels` have to come from `TilePixels::for_metatile` with the size of the block.
+    pub fn draw_metatile_to_pixels(
+        &self,
+        entities: &OsmEntities<'_>,
+        metatile: &Tile,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+    ) -> Vec<(Tile, TileRenderedPixels)> {
+        let never_cancelled = CancellationToken::new();
+        let request = pixels_request(scale, &never_cancelled);
+        let start_time = Instant::now();
+        self.draw_layers(entities, metatile, metatile.zoom, pixels, styler, &request)
+            .unwrap();
+        self.record_render_cost(metatile, pixels, start_time);
 
-        let draw_areas_with_type = |pixels: &mut TilePixels, draw_type, use_multipolygons| {
-            self.draw_areas(
-                pixels,
-                &styled_areas,
-                tile,
-                float_scale,
-                draw_type,
-                use_multipolygons,
-                styler.use_caps_for_dashes,
-            );
+        let _m = crate::perf_stats::measure("Slice metatile");
+        let top_left = metatile.top_left_descendant(pixels.zoom_offset());
+        let size = 1 << pixels.zoom_offset();
+        let dimension = pixels.dimension() / size;
+        // The filters that look at the neighboring pixels work across the tiles too.
+        let triples = to_rgb_triples(&self.filtered_pixels(pixels));
+        let mut tiles = Vec::with_capacity(size * size);
+        for dx in 0..size {
+            for dy in 0..size {
+                let tile = Tile {
+                    zoom: top_left.zoom,
+                    x: top_left.x + dx as u32,
+                    y: top_left.y + dy as u32,
+                };
+                let tile_triples = (0..dimension)
+                    .flat_map(|row| {
+                        let start = (dy * dimension + row) * pixels.dimension() + dx * dimension;
+                        triples[start..start + dimension].iter().copied()
+                    })
+                    .collect();
+                tiles.push((
+                    tile,
+                    TileRenderedPixels {
+                        triples: tile_triples,
+                        dimension,
+                    },
+                ));
+            }
+        }
+        tiles
+    }
+
+    // Called once per finished tile, so that all the passes over it (e.g. both zoom levels of a cross-faded tile)
+    // count towards its budget together.
+    fn record_render_cost(&self, tile: &Tile, pixels: &TilePixels, start_time: Instant) {
+        if let Some(render_costs) = &self.render_costs {
+            render_costs.record(&tile.top_left_descendant(pixels.zoom_offset()), start_time.elapsed());
+        }
+    }
+
+    fn filtered_pixels(&self, pixels: &TilePixels) -> DeepRgbaQuadruples {
+        let mut quadruples = pixels.to_deep_rgba_quadruples();
+        apply_filters(&self.options.filters, &mut quadruples, pixels.dimension());
+        quadruples
+    }
+
+    // The styles are usually the ones of the tile's own zoom level, but can be taken from another one.
+    // Fails only if the render gets cancelled.
+    fn draw_layers(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        style_zoom: u8,
+        pixels: &mut TilePixels,
+        styler: &Styler,
+        request: &TileRequest<'_>,
+    ) -> Result<()> {
+        let (scale, layers, cancellation) = (request.scale, request.layers, request.cancellation);
+        // The bigger tiles are drawn just like the blocks of the usual tiles of the further zoom levels.
+        let zoom_offset = pixels.zoom_offset();
+        let tile = &tile.top_left_descendant(zoom_offset);
+        let style_zoom = style_zoom + zoom_offset;
+
+        let control = RenderControl {
+            cancellation,
+            simplification_level: self
+                .render_costs
+                .as_ref()
+                .map_or(0, |costs| costs.simplification_level(tile)),
         };
 
         {
-            let _m = crate::perf_stats::measure("Fill areas");
-            draw_areas_with_type(pixels, &DrawType::Fill, true);
+            let _m = crate::perf_stats::measure("Resetting TilePixels");
+            if layers == TileLayers::Labels {
+                pixels.reset_transparent();
+            } else {
+                pixels.reset(&styler.canvas_fill_color);
+            }
         }
-        {
-            let _m = crate::perf_stats::measure("Draw areas");
-            draw_areas_with_type(pixels, &DrawType::Casing, false);
-            draw_areas_with_type(pixels, &DrawType::Stroke, false);
+
+        let float_scale = scale as f64;
+
+        let styled_areas = if layers != TileLayers::Labels {
+            self.draw_base(entities, tile, style_zoom, pixels, scale, styler, &control)?
+        } else {
+            Vec::new()
+        };
+
+        if layers != TileLayers::Base {
+            let styled_areas_for_labels = {
+                let _m = crate::perf_stats::measure("Style area for labels");
+                styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), style_zoom, true)
+            };
+
+            let styled_nodes = {
+                let _m = crate::perf_stats::measure("Style nodes");
+                styler.style_entities(entities.nodes.iter(), style_zoom, true)
+            };
+            cancellation.check()?;
+
+            {
+                let _m = crate::perf_stats::measure("Draw labels");
+                self.draw_labels(
+                    pixels,
+                    tile,
+                    float_scale,
+                    &styled_areas_for_labels,
+                    &styled_nodes,
+                    &entities.ways,
+                    cancellation,
+                )?;
+            }
+
+            {
+                let _m = crate::perf_stats::measure("Blend after labels");
+                pixels.blend_unfinished_pixels(true);
+            }
         }
 
-        {
-            let _m = crate::perf_stats::measure("Blend after areas");
+        if self.options.qa_overlay && layers != TileLayers::Labels {
+            let _m = crate::perf_stats::measure("Draw QA overlay");
+            draw_qa_overlay(pixels, &entities.ways, &styled_areas, tile, float_scale);
             pixels.blend_unfinished_pixels(false);
         }
 
-        let styled_areas_for_labels = {
-            let _m = crate::perf_stats::measure("Style area for labels");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, true)
+        Ok(())
+    }
+
+    // Draws everything except for the labels and returns the styled areas, which the QA overlay needs as well.
+    fn draw_base<'e, 'wr>(
+        &self,
+        entities: &'wr OsmEntities<'e>,
+        tile: &Tile,
+        style_zoom: u8,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+        control: &RenderControl<'_>,
+    ) -> Result<Vec<(StyledArea<'e, 'wr>, Arc<Style>)>> {
+        let mut styled_areas = {
+            let _m = crate::perf_stats::measure("Style areas");
+            let threads = self.get_tile_threads(entities.ways.len() + entities.multipolygons.len());
+            let mut styled_areas =
+                styler.style_areas_in_parallel(&entities.ways, &entities.multipolygons, style_zoom, false, threads);
+            self.options.area_priorities.sort(&mut styled_areas);
+            styled_areas
         };
+        control.cancellation.check()?;
 
-        let styled_nodes = {
-            let _m = crate::perf_stats::measure("Style nodes");
-            styler.style_entities(entities.nodes.iter(), tile.zoom, true)
+        // The small landcover areas of the low zoom levels are drawn from the grids of the tiles instead,
+        // with the fills that the style gives them.
+        let landcover = match &entities.landcover {
+            Some(landcover) if style_zoom <= LANDCOVER_ZOOM => {
+                let _m = crate::perf_stats::measure("Get landcover grids");
+                let fills = take_landcover_fills(&mut styled_areas);
+                let size = 1 << pixels.zoom_offset();
+                let grids = (0..size)
+                    .flat_map(|dx| (0..size).map(move |dy| (dx, dy)))
+                    .filter_map(|(dx, dy)| {
+                        let grid_tile = Tile {
+                            zoom: tile.zoom,
+                            x: tile.x + dx,
+                            y: tile.y + dy,
+                        };
+                        landcover.grid(&grid_tile).map(|grid| ((dx, dy), grid))
+                    })
+                    .collect();
+                Some(LandcoverLayer { grids, fills })
+            }
+            _ => None,
         };
 
-        {
-            let _m = crate::perf_stats::measure("Draw labels");
-            self.draw_labels(pixels, tile, float_scale, &styled_areas_for_labels, &styled_nodes);
+        let base = TileBase {
+            entities,
+            styled_areas: &styled_areas,
+            landcover: landcover.as_ref(),
+        };
+        let threads = self.get_tile_threads(styled_areas.len());
+        if let (true, Some(thread_pool)) = (threads > 1, &self.thread_pool) {
+            // Every band gets all the areas and only keeps the pixels that fall into it, so the bands
+            // end up exactly like the corresponding rows of the tile drawn at once.
+            let tile_size = pixels.dimension();
+            let band_height = tile_size.div_ceil(threads);
+            let mut bands = (0..tile_size)
+                .step_by(band_height)
+                .map(|min_y| {
+                    let max_y = (min_y + band_height).min(tile_size) - 1;
+                    pixels.new_band(min_y as i32, max_y as i32)
+                })
+                .collect::<Vec<_>>();
+            {
+                let _m = crate::perf_stats::measure("Draw bands");
+                thread_pool.install(|| {
+                    bands.par_iter_mut().try_for_each(|band| {
+                        band.reset(&styler.canvas_fill_color);
+                        self.draw_areas_and_vertices(
+                            band,
+                            &base,
+                            tile,
+                            scale as f64,
+                            styler,
+                            control,
+                            self.options.parallel_layers,
+                        )
+                    })
+                })?;
+            }
+            let _m = crate::perf_stats::measure("Composite bands");
+            for band in &bands {
+                pixels.copy_band(band);
+            }
+        } else {
+            self.draw_areas_and_vertices(pixels, &base, tile, scale as f64, styler, control, false)?;
         }
 
-        {
-            let _m = crate::perf_stats::measure("Blend after labels");
-            pixels.blend_unfinished_pixels(true);
+        // The arcs are few and small, so they're drawn over the whole tile at once.
+        let styled_arcs = {
+            let _m = crate::perf_stats::measure("Style arcs");
+            let mut styled_nodes = styler.style_entities(entities.nodes.iter(), style_zoom, false);
+            styled_nodes.retain(|(_, style)| style.arc.is_some());
+            styled_nodes
+        };
+        if !styled_arcs.is_empty() {
+            let _m = crate::perf_stats::measure("Draw arcs");
+            self.draw_arcs(pixels, &styled_arcs, tile, scale as f64);
+            pixels.blend_unfinished_pixels(false);
         }
 
-        TileRenderedPixels {
-            triples: pixels.to_rgb_triples(),
-            dimension: pixels.dimension(),
+        Ok(styled_areas)
+    }
+
+    // Only the dense tiles are worth the overhead of the threads.
+    fn get_tile_threads(&self, area_count: usize) -> usize {
+        self.options
+            .tile_threads
+            .min(area_count / MIN_AREAS_PER_TILE_THREAD)
+            .max(1)
+    }
+
+    fn draw_areas_and_vertices(
+        &self,
+        pixels: &mut TilePixels,
+        base: &TileBase<'_, '_, '_>,
+        tile: &Tile,
+        scale: f64,
+        styler: &Styler,
+        control: &RenderControl<'_>,
+        parallel_layers: bool,
+    ) -> Result<()> {
+        if let Some(landcover) = base.landcover {
+            let _m = crate::perf_stats::measure("Draw landcover");
+            draw_landcover(pixels, landcover, scale);
         }
+
+        let draw_areas_with_type = |pixels: &mut TilePixels, draw_type: &DrawType, pass| {
+            let layer = AreaLayer {
+                pass,
+                draw_type: draw_type.clone(),
+            };
+            self.draw_areas(
+                pixels,
+                base.styled_areas,
+                tile,
+                scale,
+                &layer,
+                styler.use_caps_for_dashes,
+                control,
+            )
+        };
+
+        let pass_count = self.options.render_order.as_ref().map_or(1, |order| order.pass_count());
+        if parallel_layers {
+            // Every pass of every draw type is a layer, and they're blended together from the bottom up.
+            let layer_types = (0..pass_count)
+                .flat_map(|pass| {
+                    [DrawType::Fill, DrawType::Casing, DrawType::Stroke].map(|draw_type| (pass, draw_type))
+                })
+                .collect::<Vec<_>>();
+            let layers = {
+                let _m = crate::perf_stats::measure("Draw layers");
+                layer_types
+                    .par_iter()
+                    .map(|(pass, draw_type)| {
+                        let mut layer = pixels.new_layer();
+                        draw_areas_with_type(&mut layer, draw_type, *pass)?;
+                        layer.blend_unfinished_pixels(false);
+                        Ok(layer)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+            let _m = crate::perf_stats::measure("Blend layers");
+            pixels.blend_unfinished_pixels(false);
+            for layer in &layers {
+                pixels.blend_layer(layer);
+            }
+        } else {
+            for pass in 0..pass_count {
+                {
+                    let _m = crate::perf_stats::measure("Fill areas");
+                    draw_areas_with_type(pixels, &DrawType::Fill, pass)?;
+                }
+                {
+                    let _m = crate::perf_stats::measure("Draw areas");
+                    draw_areas_with_type(pixels, &DrawType::Casing, pass)?;
+                    draw_areas_with_type(pixels, &DrawType::Stroke, pass)?;
+                }
+            }
+        }
+
+        if self.options.draw_vertices && tile.zoom >= MIN_VERTEX_ZOOM {
+            let _m = crate::perf_stats::measure("Draw vertices");
+            self.draw_vertices(pixels, &base.entities.nodes, tile, scale);
+        }
+
+        {
+            let _m = crate::perf_stats::measure("Blend after areas");
+            pixels.blend_unfinished_pixels(false);
+        }
+
+        Ok(())
     }
 
     fn draw_areas(
@@ -136,36 +732,92 @@ impl Drawer {
         areas: &[(StyledArea<'_, '_>, Arc<Style>)],
         tile: &Tile,
         scale: f64,
-        draw_type: &DrawType,
-        use_multipolygons: bool,
+        layer: &AreaLayer,
         use_caps_for_dashes: bool,
-    ) {
-        for (area, style) in areas {
+        control: &RenderControl<'_>,
+    ) -> Result<()> {
+        let (draw_type, pass) = (&layer.draw_type, layer.pass);
+        // Of the multipolygons, only the boundaries have their outlines drawn as well.
+        let use_multipolygons = *draw_type == DrawType::Fill;
+        let level = f64::from(control.simplification_level);
+        let min_size = level * CULLED_SIZE_PER_LEVEL * scale;
+        let tolerance = level * SIMPLIFICATION_PER_LEVEL * scale;
+        let simplify_points = |points| {
+            if tolerance > 0.0 {
+                simplify(points, tolerance)
+            } else {
+                points
+            }
+        };
+        let mut drawn_boundary_segments = HashMap::<StrokeKey, DrawnSegments>::new();
+        for (area, style) in areas.iter().filter(|(area, style)| {
+            self.get_render_pass(style, draw_type) == pass && !is_too_small(area, style, tile, scale, min_size)
+        }) {
+            control.cancellation.check()?;
+            let drawn_segments = match draw_type {
+                DrawType::Casing | DrawType::Stroke if is_boundary(area) => Some(
+                    drawn_boundary_segments
+                        .entry(StrokeKey::new(style, draw_type))
+                        .or_default(),
+                ),
+                _ => None,
+            };
             match area {
                 StyledArea::Way(way) => {
-                    self.draw_one_area(pixels, tile, scale, *way, style, draw_type, use_caps_for_dashes);
+                    let points = simplify_points(match draw_type {
+                        DrawType::Fill => way.to_ring_point_pairs(tile, scale),
+                        _ => way.to_point_pairs(tile, scale),
+                    });
+                    self.draw_one_area(
+                        pixels,
+                        line_scale(style, draw_type, tile, scale),
+                        points,
+                        style,
+                        draw_type,
+                        use_caps_for_dashes,
+                        drawn_segments,
+                    );
                 }
-                StyledArea::Multipolygon(rel) if use_multipolygons => {
-                    self.draw_one_area(pixels, tile, scale, *rel, style, draw_type, use_caps_for_dashes);
+                StyledArea::Multipolygon(rel) if use_multipolygons || is_boundary_relation(rel) => {
+                    let points = simplify_points(rel.to_point_pairs(tile, scale));
+                    self.draw_one_area(
+                        pixels,
+                        line_scale(style, draw_type, tile, scale),
+                        points,
+                        style,
+                        draw_type,
+                        use_caps_for_dashes,
+                        drawn_segments,
+                    );
                 }
                 _ => {}
             }
         }
+        Ok(())
+    }
+
+    fn get_render_pass(&self, style: &Style, draw_type: &DrawType) -> usize {
+        let render_order = match self.options.render_order {
+            Some(ref render_order) => render_order,
+            None => return 0,
+        };
+        let pass = match draw_type {
+            DrawType::Casing => style.casing_render_pass.as_ref().or(style.render_pass.as_ref()),
+            DrawType::Fill | DrawType::Stroke => style.render_pass.as_ref(),
+        };
+        render_order.get_pass_index(pass)
     }
 
-    fn draw_one_area<'e, A>(
+    fn draw_one_area(
         &self,
         pixels: &mut TilePixels,
-        tile: &'e Tile,
         scale: f64,
-        area: &'e A,
+        points: PointPairIter<'_>,
         style: &Style,
         draw_type: &DrawType,
         use_caps_for_dashes: bool,
-    ) where
-        A: OsmEntity<'e> + PointPairCollection<'e>,
-    {
-        let points = area.to_point_pairs(tile, scale);
+        drawn_segments: Option<&mut DrawnSegments>,
+    ) {
         let float_or_one = |num: &Option<f64>| num.unwrap_or(1.0);
 
         let scale_dashes =
@@ -177,8 +829,9 @@ impl Drawer {
                 if let Some(ref color) = style.fill_color {
                     fill_contour(points, &Filler::Color(color), opacity, pixels);
                 } else if let Some(ref icon_name) = style.fill_image {
-                    let read_icon_cache = self.icon_cache.open_read_session(icon_name);
-                    if let Some(Some(icon)) = read_icon_cache.get(icon_name) {
+                    let icon_key = IconKey::new(icon_name, None, None, scale);
+                    let read_icon_cache = self.icon_cache.open_read_session(&icon_key);
+                    if let Some(Some(icon)) = read_icon_cache.get(&icon_key) {
                         fill_contour(points, &Filler::Image(icon), opacity, pixels);
                     }
                 }
@@ -186,36 +839,109 @@ impl Drawer {
             DrawType::Casing => {
                 if let Some(color) = style.casing_color.as_ref() {
                     if let Some(casing_width) = style.casing_width {
-                        draw_lines(
-                            points,
-                            casing_width * scale,
-                            color,
-                            1.0,
-                            &scale_dashes(&style.casing_dashes),
-                            &style.casing_line_cap,
+                        let stroke = Stroke {
+                            dashes: &scale_dashes(&style.casing_dashes),
+                            dashes_offset: style.casing_dashes_offset.unwrap_or_default() * scale,
+                            line_caps: LineCaps::uniform(&style.casing_line_cap),
                             use_caps_for_dashes,
-                            pixels,
-                        );
+                            ..Stroke::plain(casing_width * scale, color)
+                        };
+                        draw_lines(points, &stroke, drawn_segments, pixels);
                     }
                 }
             }
             DrawType::Stroke => {
                 if let Some(color) = style.color.as_ref() {
-                    draw_lines(
-                        points,
-                        scale * float_or_one(&style.width),
+                    // The ticks need the points of the line once again.
+                    let (points, tick_points) = match style.ticks {
+                        Some(_) => {
+                            let pairs = points.collect::<Vec<_>>();
+                            (Box::new(pairs.clone().into_iter()) as PointPairIter<'_>, pairs)
+                        }
+                        None => (points, Vec::new()),
+                    };
+                    let stroke = Stroke {
+                        width: scale * float_or_one(&style.width),
                         color,
-                        float_or_one(&style.opacity),
-                        &scale_dashes(&style.dashes),
-                        &style.line_cap,
+                        opacity: float_or_one(&style.opacity),
+                        dashes: &scale_dashes(&style.dashes),
+                        dashes_offset: style.dashes_offset.unwrap_or_default() * scale,
+                        line_caps: LineCaps {
+                            line: &style.line_cap,
+                            start: &style.line_cap_start,
+                            end: &style.line_cap_end,
+                        },
                         use_caps_for_dashes,
+                    };
+                    draw_lines(points, &stroke, drawn_segments, pixels);
+                    if let Some(ticks) = &style.ticks {
+                        let to_xy = |p: &Point| (f64::from(p.x), f64::from(p.y));
+                        let to_point = |(x, y): TickPoint| Point {
+                            x: x.round() as i32,
+                            y: y.round() as i32,
+                        };
+                        let tick_segments =
+                            place_ticks(tick_points.iter().map(|(p1, p2)| (to_xy(p1), to_xy(p2))), ticks, scale)
+                                .into_iter()
+                                .map(|(start, end)| (to_point(start), to_point(end)));
+                        let tick_stroke = Stroke {
+                            opacity: float_or_one(&style.opacity),
+                            ..Stroke::plain(scale * ticks.width.unwrap_or_else(|| float_or_one(&style.width)), color)
+                        };
+                        draw_lines(Box::new(tick_segments), &tick_stroke, None, pixels);
+                    }
+                }
+            }
+        }
+
+        pixels.bump_generation();
+    }
+
+    fn draw_arcs(&self, pixels: &mut TilePixels, nodes: &[(&Node<'_>, Arc<Style>)], tile: &Tile, scale: f64) {
+        for (node, style) in nodes {
+            if let Some(arc) = &style.arc {
+                let (x, y) = coords_to_xy_tile_relative(*node, tile);
+                let center = (x * scale, y * scale);
+                if let Some(fill_color) = &arc.fill_color {
+                    fill_sector(
+                        center,
+                        arc.radius * scale,
+                        arc.bearings,
+                        fill_color,
+                        arc.fill_opacity,
                         pixels,
                     );
+                    pixels.bump_generation();
+                }
+                if let Some(color) = &arc.color {
+                    draw_arc(
+                        center,
+                        arc.radius * scale,
+                        arc.bearings,
+                        arc.width * scale,
+                        color,
+                        arc.opacity,
+                        pixels,
+                    );
+                    pixels.bump_generation();
                 }
             }
         }
+    }
+
+    fn draw_vertices(&self, pixels: &mut TilePixels, nodes: &[Node<'_>], tile: &Tile, scale: f64) {
+        let color = RgbaColor::from_components(VERTEX_COLOR.0, VERTEX_COLOR.1, VERTEX_COLOR.2, u8::MAX);
+        let half_size = (VERTEX_HALF_SIZE * scale).round() as i32;
 
         pixels.bump_generation();
+        for node in nodes.iter().filter(|n| n.tags().is_empty()) {
+            let center = Point::from_node(node, tile, scale);
+            for x in center.x - half_size..=center.x + half_size {
+                for y in center.y - half_size..=center.y + half_size {
+                    pixels.set_pixel(x, y, &color);
+                }
+            }
+        }
     }
 
     fn draw_labels(
@@ -225,39 +951,404 @@ impl Drawer {
         scale: f64,
         areas: &[(StyledArea<'_, '_>, Arc<Style>)],
         nodes: &[(&Node<'_>, Arc<Style>)],
-    ) {
-        {
-            let _m = crate::perf_stats::measure("Label areas");
-            for (area, style) in areas {
-                match area {
-                    StyledArea::Way(way) => self.labeler.label_entity(
+        ways: &[Way<'_>],
+        cancellation: &CancellationToken,
+    ) -> Result<()> {
+        let clusters = if self.options.cluster_pois && tile.zoom <= MAX_CLUSTER_ZOOM {
+            cluster_pois(nodes, tile, scale)
+        } else {
+            (0..nodes.len()).map(|idx| PoiCluster { idx, count: 1 }).collect()
+        };
+
+        // The labels that are placed first win the collisions. By default, these are the area labels,
+        // but `text-priority` can move any label ahead. The sort is stable, so the order of the labels
+        // with the same priority (and therefore the result) doesn't change between renders and tiles.
+        let mut labels = areas
+            .iter()
+            .enumerate()
+            .filter(|(_, (area, style))| !is_too_small(area, style, tile, scale, 0.0))
+            .map(|(idx, _)| Label::Area(idx))
+            .chain(clusters.iter().map(Label::Node))
+            .collect::<Vec<_>>();
+        let priority = |label: &Label<'_>| {
+            let style = match label {
+                Label::Area(idx) => &areas[*idx].1,
+                Label::Node(cluster) => &nodes[cluster.idx].1,
+            };
+            style.text_priority.unwrap_or_default()
+        };
+        labels.sort_by(|a, b| priority(b).total_cmp(&priority(a)));
+
+        let way_bearings = OnceCell::new();
+        let context = LabelContext {
+            tile,
+            scale,
+            icon_cache: &self.icon_cache,
+        };
+
+        let _m = crate::perf_stats::measure("Place labels");
+        for label in labels {
+            cancellation.check()?;
+            match label {
+                Label::Area(idx) => match &areas[idx] {
+                    (StyledArea::Way(way), style) => self.labeler.label_entity(
                         *way,
                         style,
-                        tile,
-                        scale,
-                        &self.icon_cache,
+                        get_icon_angle(*way, style, || None),
                         TextPosition::Line,
+                        &context,
                         pixels,
                     ),
-                    StyledArea::Multipolygon(rel) => self.labeler.label_entity(
+                    (StyledArea::Multipolygon(rel), style) => self.labeler.label_entity(
                         *rel,
                         style,
-                        tile,
-                        scale,
-                        &self.icon_cache,
+                        get_icon_angle(*rel, style, || None),
                         TextPosition::Center,
+                        &context,
                         pixels,
                     ),
+                },
+                Label::Node(cluster) => {
+                    let (node, ref style) = nodes[cluster.idx];
+                    let count = if self.options.cluster_counts { cluster.count } else { 1 };
+                    let icon_angle = get_icon_angle(node, style, || {
+                        let way_bearings = way_bearings.get_or_init(|| get_way_bearings(ways, tile));
+                        way_bearings.get(&node.global_id()).copied()
+                    });
+                    self.labeler
+                        .label_cluster(node, style, count, icon_angle, &context, pixels);
                 }
             }
         }
+        Ok(())
+    }
+}
 
-        {
-            let _m = crate::perf_stats::measure("Label nodes");
-            for &(node, ref style) in nodes {
-                self.labeler
-                    .label_entity(node, style, tile, scale, &self.icon_cache, TextPosition::Center, pixels);
+// If the threads can't be started, the tiles are drawn by the calling thread instead.
+fn create_thread_pool(threads: usize) -> Option<ThreadPool> {
+    match ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => Some(pool),
+        Err(e) => {
+            eprintln!("Failed to start {} threads for drawing the tiles: {}", threads, e);
+            None
+        }
+    }
+}
+
+// The renders that only need the pixels, which aren't encoded.
+fn pixels_request(scale: usize, cancellation: &CancellationToken) -> TileRequest<'_> {
+    TileRequest {
+        scale,
+        layers: TileLayers::All,
+        format: ImageFormat::default(),
+        cancellation,
+    }
+}
+
+fn to_rgb_triples(deep_quadruples: &DeepRgbaQuadruples) -> RgbTriples {
+    to_8_bit(deep_quadruples)
+        .into_iter()
+        .map(|(r, g, b, _)| (r, g, b))
+        .collect()
+}
+
+fn encode_tile(
+    deep_quadruples: &DeepRgbaQuadruples,
+    dimension: usize,
+    layers: TileLayers,
+    format: ImageFormat,
+) -> Result<Vec<u8>> {
+    // Only the labels layer is transparent.
+    let is_transparent = layers == TileLayers::Labels;
+    if format == ImageFormat::Png16 {
+        let _m = crate::perf_stats::measure("Deep RGBA quadruples to PNG");
+        return deep_rgba_quadruples_to_png(deep_quadruples, dimension, dimension, is_transparent);
+    }
+
+    let quadruples = to_8_bit(deep_quadruples);
+    let to_triples = || quadruples.iter().map(|&(r, g, b, _)| (r, g, b)).collect::<Vec<_>>();
+    match format {
+        ImageFormat::Png if is_transparent => {
+            let _m = crate::perf_stats::measure("RGBA quadruples to PNG");
+            rgba_quadruples_to_png(&quadruples, dimension, dimension)
+        }
+        ImageFormat::Png => {
+            let _m = crate::perf_stats::measure("RGB triples to PNG");
+            rgb_triples_to_png(&to_triples(), dimension, dimension)
+        }
+        ImageFormat::WebP if is_transparent => {
+            let _m = crate::perf_stats::measure("RGBA quadruples to WebP");
+            rgba_quadruples_to_webp(&quadruples, dimension, dimension)
+        }
+        ImageFormat::WebP => {
+            let _m = crate::perf_stats::measure("RGB triples to WebP");
+            rgb_triples_to_webp(&to_triples(), dimension, dimension)
+        }
+        ImageFormat::Rgba => Ok(rgba_quadruples_to_raw(&quadruples)),
+        ImageFormat::Pam => Ok(rgba_quadruples_to_pam(&quadruples, dimension, dimension)),
+        ImageFormat::Ppm => Ok(rgb_triples_to_ppm(&to_triples(), dimension, dimension)),
+        ImageFormat::Png16 => unreachable!(),
+    }
+}
+
+// The colors are mixed with their alphas taken into account, so that the transparent pixels
+// of the labels layer don't darken the labels that fade in or out.
+fn cross_fade(from: &DeepRgbaQuadruples, to: &DeepRgbaQuadruples, fade: f64) -> DeepRgbaQuadruples {
+    from.iter()
+        .zip(to)
+        .map(|(&(r1, g1, b1, a1), &(r2, g2, b2, a2))| {
+            let (a1, a2) = (f64::from(a1), f64::from(a2));
+            let alpha = a1 * (1.0 - fade) + a2 * fade;
+            let mix = |c1: u16, c2: u16| {
+                if alpha == 0.0 {
+                    return 0;
+                }
+                let premultiplied = f64::from(c1) * a1 * (1.0 - fade) + f64::from(c2) * a2 * fade;
+                (premultiplied / alpha).round() as u16
+            };
+            (mix(r1, r2), mix(g1, g2), mix(b1, b2), alpha.round() as u16)
+        })
+        .collect()
+}
+
+enum Label<'c> {
+    Area(usize),
+    Node(&'c PoiCluster),
+}
+
+// The borders of the neighboring regions belong to the boundaries of both of them (and usually to the ways
+// that make them up as well), so their segments are only drawn once for every stroke.
+fn is_boundary(area: &StyledArea<'_, '_>) -> bool {
+    match area {
+        StyledArea::Way(way) => way.tags().get_by_key("boundary").is_some(),
+        StyledArea::Multipolygon(rel) => rel.tags().get_by_key("boundary").is_some() || is_boundary_relation(rel),
+    }
+}
+
+// Unlike the other multipolygons, the boundaries are drawn as lines, since nothing else draws their outlines.
+fn is_boundary_relation(rel: &Multipolygon<'_>) -> bool {
+    rel.tags().get_by_key("type") == Some("boundary")
+}
+
+// The scale of the lengths of the lines, which are given in meters with `width-unit: meters` (see `Style`).
+fn line_scale(style: &Style, draw_type: &DrawType, tile: &Tile, scale: f64) -> f64 {
+    match draw_type {
+        DrawType::Casing | DrawType::Stroke if style.width_in_meters => scale * pixels_per_meter(tile),
+        _ => scale,
+    }
+}
+
+// Everything that makes two lines look the same. The numbers are compared bit by bit.
+#[derive(Eq, Hash, PartialEq)]
+struct StrokeKey {
+    color: Option<Color>,
+    width: Option<u64>,
+    opacity: Option<u64>,
+    dashes: Option<Vec<u64>>,
+    dashes_offset: Option<u64>,
+    in_meters: bool,
+}
+
+impl StrokeKey {
+    fn new(style: &Style, draw_type: &DrawType) -> StrokeKey {
+        let bits = |num: &Option<f64>| num.map(f64::to_bits);
+        let dash_bits = |dashes: &Option<Vec<f64>>| {
+            dashes
+                .as_ref()
+                .map(|dashes| dashes.iter().map(|d| d.to_bits()).collect())
+        };
+        match draw_type {
+            DrawType::Casing => StrokeKey {
+                color: style.casing_color.clone(),
+                width: bits(&style.casing_width),
+                opacity: None,
+                dashes: dash_bits(&style.casing_dashes),
+                dashes_offset: bits(&style.casing_dashes_offset),
+                in_meters: style.width_in_meters,
+            },
+            DrawType::Fill | DrawType::Stroke => StrokeKey {
+                color: style.color.clone(),
+                width: bits(&style.width),
+                opacity: bits(&style.opacity),
+                dashes: dash_bits(&style.dashes),
+                dashes_offset: bits(&style.dashes_offset),
+                in_meters: style.width_in_meters,
+            },
+        }
+    }
+}
+
+// A segment of an SVG line, with the coordinates of its ends compared bit by bit.
+type SvgSegment = ((u64, u64), (u64, u64));
+
+// The parts of the line whose segments weren't drawn with the same stroke yet, each with how far along the line
+// it starts (so that the dashes stay where they are on the whole line). The new segments become drawn ones.
+fn undrawn_parts<'p>(points: &'p [SvgPoint], drawn: &mut HashSet<SvgSegment>) -> Vec<(f64, &'p [SvgPoint])> {
+    let to_bits = |p: &SvgPoint| (p.0.to_bits(), p.1.to_bits());
+    let mut parts = Vec::new();
+    let mut part_start = None;
+    let mut distance = 0.0;
+    for idx in 1..points.len() {
+        let (p1, p2) = (&points[idx - 1], &points[idx]);
+        let (b1, b2) = (to_bits(p1), to_bits(p2));
+        if drawn.insert(if b1 <= b2 { (b1, b2) } else { (b2, b1) }) {
+            part_start.get_or_insert((idx - 1, distance));
+        } else if let Some((start, start_distance)) = part_start.take() {
+            parts.push((start_distance, &points[start..idx]));
+        }
+        distance += (p2.0 - p1.0).hypot(p2.1 - p1.1);
+    }
+    if let Some((start, start_distance)) = part_start {
+        parts.push((start_distance, &points[start..]));
+    }
+    parts
+}
+
+// What the base layer of a tile is drawn from.
+struct TileBase<'a, 'e, 'wr> {
+    entities: &'a OsmEntities<'e>,
+    styled_areas: &'a [(StyledArea<'e, 'wr>, Arc<Style>)],
+    landcover: Option<&'a LandcoverLayer>,
+}
+
+// The areas are drawn in layers: the fills, then the casings and then the strokes of every pass in turn.
+struct AreaLayer {
+    pass: usize,
+    draw_type: DrawType,
+}
+
+// The landcover grids of the tiles that are drawn (by their position among them), together with the fills
+// of the landcover classes.
+struct LandcoverLayer {
+    grids: Vec<((u32, u32), LandcoverGrid)>,
+    fills: HashMap<u8, RgbaColor>,
+}
+
+// Removes the areas that are in the landcover grids and returns the fills of their classes. Every class gets
+// the fill of the lowest of its areas (which usually all look the same anyway).
+fn take_landcover_fills(styled_areas: &mut Vec<(StyledArea<'_, '_>, Arc<Style>)>) -> HashMap<u8, RgbaColor> {
+    let mut fills = HashMap::new();
+    let mut aggregated = HashMap::new();
+    styled_areas.retain(|(area, style)| {
+        let (global_id, tags) = match area {
+            StyledArea::Way(way) => (way.global_id(), way.tags()),
+            StyledArea::Multipolygon(rel) => (rel.global_id(), rel.tags()),
+        };
+        let class = landcover_class(|key| tags.get_by_key(key));
+        if class == 0 {
+            return true;
+        }
+        let is_in_grids = *aggregated.entry(global_id).or_insert_with(|| match area {
+            StyledArea::Way(way) => {
+                way.is_closed() && is_aggregated((0..way.node_count()).map(|idx| way.get_node(idx)))
+            }
+            StyledArea::Multipolygon(rel) => is_aggregated((0..rel.polygon_count()).flat_map(|idx| {
+                let polygon = rel.get_polygon(idx);
+                (0..polygon.node_count()).map(move |node_idx| polygon.get_node(node_idx))
+            })),
+        });
+        if is_in_grids {
+            if let Some(color) = &style.fill_color {
+                fills
+                    .entry(class)
+                    .or_insert_with(|| RgbaColor::from_color(color, style.fill_opacity.unwrap_or(1.0)));
             }
         }
+        !is_in_grids
+    });
+    fills
+}
+
+// Every cell of the grids is a square of pixels.
+fn draw_landcover(pixels: &mut TilePixels, landcover: &LandcoverLayer, scale: f64) {
+    let cell_size = f64::from(crate::tile::TILE_SIZE) / GRID_SIZE as f64 * scale;
+    let tile_size = f64::from(crate::tile::TILE_SIZE) * scale;
+    let to_pixel = |c: f64| c.round() as i32;
+    pixels.bump_generation();
+    for ((dx, dy), grid) in &landcover.grids {
+        let (left, top) = (f64::from(*dx) * tile_size, f64::from(*dy) * tile_size);
+        for (idx, class) in grid.iter().enumerate() {
+            let fill = match landcover.fills.get(class) {
+                Some(fill) => fill,
+                None => continue,
+            };
+            let (column, row) = ((idx % GRID_SIZE) as f64, (idx / GRID_SIZE) as f64);
+            let (x0, y0) = (to_pixel(left + column * cell_size), to_pixel(top + row * cell_size));
+            let (x1, y1) = (
+                to_pixel(left + (column + 1.0) * cell_size),
+                to_pixel(top + (row + 1.0) * cell_size),
+            );
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    pixels.set_pixel(x, y, fill);
+                }
+            }
+        }
+    }
+    pixels.bump_generation();
+}
+
+// Tiny areas (e.g. buildings at low zooms) are mostly noise, and skipping them saves time as well.
+// Besides the `min-size` of the style, there can be a minimal size for all areas (in pixels).
+fn is_too_small(area: &StyledArea<'_, '_>, style: &Style, tile: &Tile, scale: f64, min_size_for_all: f64) -> bool {
+    let min_size = style
+        .min_size
+        .map_or(0.0, |min_size| min_size * scale)
+        .max(min_size_for_all);
+    if min_size <= 0.0 {
+        return false;
+    }
+    let points = match area {
+        StyledArea::Way(way) => way.to_point_pairs(tile, scale),
+        StyledArea::Multipolygon(rel) => rel.to_point_pairs(tile, scale),
+    };
+    is_smaller_than(points, min_size)
+}
+
+// Whether both sides of the bounding box of the points are shorter than `min_size` pixels.
+fn is_smaller_than(points: PointPairIter<'_>, min_size: f64) -> bool {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+    for (p1, p2) in points {
+        for p in [p1, p2] {
+            min_x = min_x.min(p.x);
+            min_y = min_y.min(p.y);
+            max_x = max_x.max(p.x);
+            max_y = max_y.max(p.y);
+        }
+    }
+
+    // An area without any points is going to be skipped anyway.
+    min_x <= max_x && f64::from((max_x - min_x).max(max_y - min_y)) < min_size
+}
+
+const MIN_VERTEX_ZOOM: u8 = 16;
+const MAX_CLUSTER_ZOOM: u8 = 15;
+const VERTEX_HALF_SIZE: f64 = 1.0;
+const VERTEX_COLOR: (u8, u8, u8) = (0x40, 0x40, 0x40);
+const MIN_AREAS_PER_TILE_THREAD: usize = 500;
+// With every level of simplification, the areas that are smaller than this are skipped, and the vertices
+// that are closer than this to each other are merged (in pixels).
+const CULLED_SIZE_PER_LEVEL: f64 = 2.0;
+const SIMPLIFICATION_PER_LEVEL: f64 = 1.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(width: i32, height: i32) -> PointPairIter<'static> {
+        let corners = [(0, 0), (width, 0), (width, height), (0, height), (0, 0)].map(|(x, y)| Point { x, y });
+        Box::new((0..4).map(move |idx| (corners[idx].clone(), corners[idx + 1].clone())))
+    }
+
+    #[test]
+    fn test_is_smaller_than() {
+        assert!(is_smaller_than(rectangle(10, 10), 10.5));
+        assert!(!is_smaller_than(rectangle(10, 10), 10.0));
+        assert!(!is_smaller_than(rectangle(10, 10), 9.5));
+        // The longer side is what counts.
+        assert!(!is_smaller_than(rectangle(30, 2), 20.0));
+        assert!(!is_smaller_than(rectangle(2, 30), 20.0));
+        assert!(is_smaller_than(rectangle(19, 2), 20.0));
+        assert!(!is_smaller_than(Box::new(std::iter::empty()), 20.0));
     }
 }
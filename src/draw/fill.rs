@@ -5,7 +5,6 @@ use crate::draw::tile_pixels::RgbaColor;
 use crate::mapcss::color::Color;
 
 use crate::draw::tile_pixels::TilePixels;
-use indexmap::IndexMap;
 use std::cmp::{max, min};
 
 pub enum Filler<'a> {
@@ -14,20 +13,20 @@ pub enum Filler<'a> {
 }
 
 pub fn fill_contour(points: PointPairIter<'_>, filler: &Filler<'_>, opacity: f64, pixels: &mut TilePixels) {
-    let mut y_to_edges = EdgesByY::default();
+    // The buffer is reused for all areas of all tiles drawn with these pixels, so filling an area
+    // doesn't allocate anything once the buffer has grown large enough.
+    let mut spans = pixels.take_edge_spans();
 
     for (idx, (p1, p2)) in points.enumerate() {
-        draw_line(idx, &p1, &p2, &mut y_to_edges, pixels.bb().min_y, pixels.bb().max_y);
+        draw_line(idx, &p1, &p2, &mut spans, pixels.bb().min_y, pixels.bb().max_y);
     }
 
-    for (y, edges) in y_to_edges.iter() {
-        let mut good_edges = edges.values().filter(|e| !e.is_poisoned).collect::<Vec<_>>();
-        good_edges.sort_by_key(|e| e.x_min);
+    spans.sort_unstable_by_key(|s| (s.y, s.x_min, s.edge_idx));
 
-        let mut idx = 0;
-        while idx + 1 < good_edges.len() {
-            let e1 = good_edges[idx];
-            let e2 = good_edges[idx + 1];
+    for row in spans.chunk_by(|s1, s2| s1.y == s2.y) {
+        let mut good_edges = row.iter().filter(|e| !e.is_poisoned);
+        while let (Some(e1), Some(e2)) = (good_edges.next(), good_edges.next()) {
+            let y = e1.y;
             let from_x = e1.x_min.max(pixels.bb().min_x);
             let to_x = e2.x_max.min(pixels.bb().max_x) + 1;
             for x in from_x..to_x {
@@ -35,20 +34,21 @@ pub fn fill_contour(points: PointPairIter<'_>, filler: &Filler<'_>, opacity: f64
                     Filler::Color(color) => RgbaColor::from_color(color, opacity),
                     Filler::Image(icon) => {
                         let icon_x = (x as usize) % icon.width;
-                        let icon_y = (*y as usize) % icon.height;
+                        let icon_y = (y as usize) % icon.height;
                         icon.get(icon_x, icon_y)
                     }
                 };
-                pixels.set_pixel(x, *y, &fill_color);
+                pixels.set_pixel(x, y, &fill_color);
             }
-            idx += 2;
         }
     }
+
+    pixels.return_edge_spans(spans);
 }
 
 // Stripped-down version of Bresenham which is extremely easy to implement.
 // See http://members.chello.at/~easyfilter/bresenham.html
-fn draw_line(edge_idx: usize, p1: &Point, p2: &Point, y_to_edges: &mut EdgesByY, min_y: i32, max_y: i32) {
+fn draw_line(edge_idx: usize, p1: &Point, p2: &Point, spans: &mut Vec<EdgeSpan>, min_y: i32, max_y: i32) {
     let dx = (p2.x - p1.x).abs();
     let dy = -(p2.y - p1.y).abs();
 
@@ -72,19 +72,21 @@ fn draw_line(edge_idx: usize, p1: &Point, p2: &Point, y_to_edges: &mut EdgesByY,
         };
 
         if cur_point.y >= min_y && cur_point.y <= max_y {
-            let edge = y_to_edges
-                .entry(cur_point.y)
-                .or_insert_with(Default::default)
-                .entry(edge_idx)
-                .or_insert_with(|| Edge {
+            // The rows of an edge are visited one after another, so only the last span can be on the same row.
+            match spans.last_mut() {
+                Some(span) if span.y == cur_point.y && span.edge_idx == edge_idx => {
+                    span.x_min = min(span.x_min, cur_point.x);
+                    span.x_max = max(span.x_max, cur_point.x);
+                    span.is_poisoned |= is_poisoned;
+                }
+                _ => spans.push(EdgeSpan {
+                    y: cur_point.y,
+                    edge_idx,
                     x_min: cur_point.x,
                     x_max: cur_point.x,
                     is_poisoned,
-                });
-
-            edge.x_min = min(edge.x_min, cur_point.x);
-            edge.x_max = max(edge.x_max, cur_point.x);
-            edge.is_poisoned |= is_poisoned;
+                }),
+            }
         }
 
         if is_end {
@@ -103,10 +105,59 @@ fn draw_line(edge_idx: usize, p1: &Point, p2: &Point, y_to_edges: &mut EdgesByY,
     }
 }
 
-type EdgesByY = IndexMap<i32, IndexMap<usize, Edge>>;
-
-struct Edge {
+// The pixels of a single edge of the contour on a single row.
+pub(super) struct EdgeSpan {
+    y: i32,
+    edge_idx: usize,
     x_min: i32,
     x_max: i32,
     is_poisoned: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::TILE_SIZE;
+
+    fn fill(contour: &[(i32, i32)], pixels: &mut TilePixels) -> Vec<(u8, u8, u8, u8)> {
+        let points = contour.iter().map(|&(x, y)| Point { x, y }).collect::<Vec<_>>();
+        let pairs = points
+            .windows(2)
+            .map(|w| (w[0].clone(), w[1].clone()))
+            .collect::<Vec<_>>();
+        pixels.reset_transparent();
+        let color = Color { r: 0, g: 0, b: 0 };
+        fill_contour(Box::new(pairs.into_iter()), &Filler::Color(&color), 1.0, pixels);
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgba_quadruples()
+    }
+
+    #[test]
+    fn test_fill_with_reused_buffer() {
+        // A U shape, which has two separate spans on its upper rows.
+        let u_shape = [
+            (10, 10),
+            (40, 10),
+            (40, 40),
+            (30, 40),
+            (30, 20),
+            (20, 20),
+            (20, 40),
+            (10, 40),
+            (10, 10),
+        ];
+        let is_filled = |pixels: &[(u8, u8, u8, u8)], x: usize, y: usize| pixels[y * TILE_SIZE + x].3 > 0;
+
+        let mut pixels = TilePixels::new(1);
+        let filled = fill(&u_shape, &mut pixels);
+        assert!(is_filled(&filled, 15, 30));
+        assert!(is_filled(&filled, 35, 30));
+        assert!(is_filled(&filled, 25, 15));
+        assert!(!is_filled(&filled, 25, 30));
+        assert!(!is_filled(&filled, 50, 30));
+
+        // Another area in between doesn't leave anything behind in the buffer.
+        fill(&[(100, 100), (200, 100), (150, 200), (100, 100)], &mut pixels);
+        assert_eq!(fill(&u_shape, &mut pixels), filled);
+    }
+}
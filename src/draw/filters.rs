@@ -0,0 +1,199 @@
+use crate::draw::tile_pixels::DeepRgbaQuadruples;
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+
+/// A pass over the finished pixels of a tile, before it is encoded. The filters change the look of a whole style
+/// without touching the stylesheet, e.g. `invert(0.9), contrast(0.8)` makes a dark version of a light style.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Makes the edges crisper by exaggerating the difference between every pixel and its neighbors,
+    /// by the given factor.
+    Sharpen(f64),
+    /// Spreads the colors away from the middle gray (above 1) or draws them towards it (below 1).
+    Contrast(f64),
+    /// Tints the colors brown like an old photo, completely at 1.
+    Sepia(f64),
+    /// Turns the colors into their opposites, completely at 1 (0.5 makes everything gray).
+    Invert(f64),
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    /// E.g. `contrast(1.2)`. The amount can be left out for everything except for the contrast, and is 1 then.
+    fn from_str(s: &str) -> Result<Filter> {
+        let s = s.trim();
+        let (name, amount) = match s.split_once('(') {
+            Some((name, rest)) => {
+                let amount = rest
+                    .strip_suffix(')')
+                    .with_context(|| format!("Expected ) at the end of {}", s))?;
+                let amount = amount
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|amount| amount.is_finite() && *amount >= 0.0)
+                    .with_context(|| format!("Invalid amount in {}", s))?;
+                (name.trim(), Some(amount))
+            }
+            None => (s, None),
+        };
+        let filter = match name {
+            "sharpen" => Filter::Sharpen(amount.unwrap_or(1.0)),
+            "contrast" => Filter::Contrast(amount.with_context(|| format!("{} needs an amount", s))?),
+            "sepia" => Filter::Sepia(amount.unwrap_or(1.0)),
+            "invert" => Filter::Invert(amount.unwrap_or(1.0)),
+            _ => bail!("Unknown filter: {}", name),
+        };
+        match filter {
+            Filter::Sepia(amount) | Filter::Invert(amount) if amount > 1.0 => {
+                bail!("The amount in {} can't be more than 1", s)
+            }
+            filter => Ok(filter),
+        }
+    }
+}
+
+/// Parses a comma-separated list of filters, which are applied in the same order.
+/// # Examples
+/// ```
+/// use renderer::draw::filters::{parse_filters, Filter};
+/// assert_eq!(
+///     parse_filters("invert(0.9), contrast(0.8), sharpen").unwrap(),
+///     vec![Filter::Invert(0.9), Filter::Contrast(0.8), Filter::Sharpen(1.0)]
+/// );
+/// assert_eq!(parse_filters("").unwrap(), vec![]);
+/// assert!(parse_filters("blur(2)").is_err());
+/// ```
+pub fn parse_filters(s: &str) -> Result<Vec<Filter>> {
+    s.split(',')
+        .filter(|filter| !filter.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Applies the filters one after another to the `dimension`×`dimension` pixels. The transparency stays the same.
+pub fn apply_filters(filters: &[Filter], quadruples: &mut DeepRgbaQuadruples, dimension: usize) {
+    for filter in filters {
+        let _m = crate::perf_stats::measure("Apply filter");
+        match *filter {
+            Filter::Sharpen(amount) => sharpen(quadruples, dimension, amount),
+            Filter::Contrast(amount) => map_colors(quadruples, |c| c.map(|c| (c - 0.5) * amount + 0.5)),
+            Filter::Sepia(amount) => map_colors(quadruples, |[r, g, b]| {
+                let sepia = [
+                    0.393 * r + 0.769 * g + 0.189 * b,
+                    0.349 * r + 0.686 * g + 0.168 * b,
+                    0.272 * r + 0.534 * g + 0.131 * b,
+                ];
+                [0, 1, 2].map(|i| [r, g, b][i] + (sepia[i] - [r, g, b][i]) * amount)
+            }),
+            Filter::Invert(amount) => map_colors(quadruples, |c| c.map(|c| c + (1.0 - 2.0 * c) * amount)),
+        }
+    }
+}
+
+// The colors are passed as RGB from 0 to 1, and the results are clamped to the same range.
+fn map_colors(quadruples: &mut DeepRgbaQuadruples, f: impl Fn([f64; 3]) -> [f64; 3]) {
+    for (r, g, b, _) in quadruples.iter_mut() {
+        let [new_r, new_g, new_b] = f([*r, *g, *b].map(from_u16));
+        (*r, *g, *b) = (to_u16(new_r), to_u16(new_g), to_u16(new_b));
+    }
+}
+
+// An unsharp mask with the average of the 3×3 pixels around every pixel as the blurred image.
+// The pixels beyond the edges are taken to be the same as the edge pixels.
+fn sharpen(quadruples: &mut DeepRgbaQuadruples, dimension: usize, amount: f64) {
+    let original = quadruples.clone();
+    let channels = |(r, g, b, _): (u16, u16, u16, u16)| [r, g, b].map(from_u16);
+    for y in 0..dimension {
+        for x in 0..dimension {
+            let mut sum = [0.0; 3];
+            for ny in y.saturating_sub(1)..=(y + 1).min(dimension - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(dimension - 1) {
+                    let neighbor = channels(original[ny * dimension + nx]);
+                    (0..3).for_each(|i| sum[i] += neighbor[i]);
+                }
+            }
+            let count = ((y + 1).min(dimension - 1) - y.saturating_sub(1) + 1)
+                * ((x + 1).min(dimension - 1) - x.saturating_sub(1) + 1);
+            let pixel = &mut quadruples[y * dimension + x];
+            let color = channels(*pixel);
+            let [r, g, b] = [0, 1, 2].map(|i| to_u16(color[i] + (color[i] - sum[i] / count as f64) * amount));
+            (pixel.0, pixel.1, pixel.2) = (r, g, b);
+        }
+    }
+}
+
+fn from_u16(c: u16) -> f64 {
+    f64::from(c) / f64::from(u16::MAX)
+}
+
+fn to_u16(c: f64) -> u16 {
+    (c.clamp(0.0, 1.0) * f64::from(u16::MAX)).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: (u16, u16, u16, u16) = (u16::MAX, u16::MAX, u16::MAX, u16::MAX);
+    const BLACK: (u16, u16, u16, u16) = (0, 0, 0, u16::MAX);
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!("sepia".parse::<Filter>().unwrap(), Filter::Sepia(1.0));
+        assert_eq!(" invert( 0.5 ) ".parse::<Filter>().unwrap(), Filter::Invert(0.5));
+        assert!("contrast".parse::<Filter>().is_err());
+        assert!("contrast(-1)".parse::<Filter>().is_err());
+        assert!("sepia(2)".parse::<Filter>().is_err());
+        assert!("sharpen(1".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_color_filters() {
+        let gray = (0x4000, 0x8000, 0xc000, 0x1234);
+        let apply = |filters: &[Filter]| {
+            let mut pixels = vec![WHITE, BLACK, gray];
+            apply_filters(filters, &mut pixels, 0);
+            pixels
+        };
+
+        assert_eq!(apply(&[]), vec![WHITE, BLACK, gray]);
+        assert_eq!(
+            apply(&[Filter::Invert(1.0)]),
+            vec![BLACK, WHITE, (0xbfff, 0x7fff, 0x3fff, 0x1234)]
+        );
+        assert_eq!(
+            apply(&[Filter::Invert(1.0), Filter::Invert(1.0)]),
+            vec![WHITE, BLACK, gray]
+        );
+        assert_eq!(
+            apply(&[Filter::Contrast(1.0), Filter::Sepia(0.0)]),
+            vec![WHITE, BLACK, gray]
+        );
+        assert_eq!(apply(&[Filter::Contrast(0.0)])[0], (0x8000, 0x8000, 0x8000, u16::MAX));
+        assert_eq!(apply(&[Filter::Contrast(2.0)])[2], (0, 0x8001, u16::MAX, 0x1234));
+
+        // Sepia makes white a bit yellowish, as the CSS filter does.
+        let (r, g, b, a) = apply(&[Filter::Sepia(1.0)])[0];
+        assert!(r == u16::MAX && g == u16::MAX && b < g && a == u16::MAX);
+    }
+
+    #[test]
+    fn test_sharpen() {
+        // A vertical edge between the black left half and the white right half of a 4×4 image.
+        let mut pixels = (0..16)
+            .map(|i| if i % 4 < 2 { BLACK } else { WHITE })
+            .collect::<Vec<_>>();
+        let gray = (0x8000, 0x8000, 0x8000, u16::MAX);
+        pixels[0] = gray;
+        let original = pixels.clone();
+        apply_filters(&[Filter::Sharpen(0.0)], &mut pixels, 4);
+        assert_eq!(pixels, original);
+
+        apply_filters(&[Filter::Sharpen(1.0)], &mut pixels, 4);
+        // The gray pixel next to the black ones gets lighter, and everything else stays as it was.
+        assert!(pixels[0].0 > gray.0);
+        assert_eq!(pixels[1..], original[1..]);
+    }
+}
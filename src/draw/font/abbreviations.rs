@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// Shorter versions of the words that are common in street names (e.g. "Street" -> "St"), used for
+/// the line labels that don't fit along their ways otherwise.
+#[derive(Clone, Default)]
+pub struct Abbreviations {
+    words: HashMap<&'static str, &'static str>,
+}
+
+impl Abbreviations {
+    /// Combines the built-in dictionaries for a comma-separated list of locales (e.g. `en,ru`).
+    pub fn for_locales(locales: &str) -> Result<Abbreviations> {
+        let mut words = HashMap::new();
+        for locale in locales.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+            let dictionary = match locale {
+                "de" => DE,
+                "en" => EN,
+                "fr" => FR,
+                "ru" => RU,
+                _ => bail!("No abbreviations are known for locale {}", locale),
+            };
+            words.extend(dictionary.iter().copied());
+        }
+        Ok(Abbreviations { words })
+    }
+
+    /// Returns the abbreviated text, or `None` if there's nothing to abbreviate.
+    pub fn abbreviate(&self, text: &str) -> Option<String> {
+        let mut is_abbreviated = false;
+        let words = text
+            .split(' ')
+            .map(|word| match self.words.get(word) {
+                Some(abbreviation) => {
+                    is_abbreviated = true;
+                    *abbreviation
+                }
+                None => word,
+            })
+            .collect::<Vec<_>>();
+        if is_abbreviated {
+            Some(words.join(" "))
+        } else {
+            None
+        }
+    }
+}
+
+const DE: &[(&str, &str)] = &[
+    ("Allee", "Al."),
+    ("Platz", "Pl."),
+    ("Straße", "Str."),
+    ("Strasse", "Str."),
+];
+
+const EN: &[(&str, &str)] = &[
+    ("Avenue", "Ave"),
+    ("Boulevard", "Blvd"),
+    ("Court", "Ct"),
+    ("Drive", "Dr"),
+    ("Highway", "Hwy"),
+    ("Lane", "Ln"),
+    ("North", "N"),
+    ("Parkway", "Pkwy"),
+    ("Place", "Pl"),
+    ("Road", "Rd"),
+    ("South", "S"),
+    ("Square", "Sq"),
+    ("Street", "St"),
+    ("Terrace", "Ter"),
+];
+
+const FR: &[(&str, &str)] = &[
+    ("Avenue", "Av."),
+    ("Boulevard", "Bd"),
+    ("Chemin", "Ch."),
+    ("Place", "Pl."),
+    ("Route", "Rte"),
+    ("Saint", "St"),
+    ("Sainte", "Ste"),
+];
+
+const RU: &[(&str, &str)] = &[
+    ("бульвар", "бул."),
+    ("набережная", "наб."),
+    ("переулок", "пер."),
+    ("площадь", "пл."),
+    ("проезд", "пр."),
+    ("проспект", "просп."),
+    ("тупик", "туп."),
+    ("улица", "ул."),
+    ("шоссе", "ш."),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abbreviate() {
+        let abbreviations = Abbreviations::for_locales("en, ru").unwrap();
+        assert_eq!(
+            abbreviations.abbreviate("North Main Street"),
+            Some("N Main St".to_string())
+        );
+        assert_eq!(
+            abbreviations.abbreviate("Большая Никитская улица"),
+            Some("Большая Никитская ул.".to_string())
+        );
+        assert_eq!(abbreviations.abbreviate("Streeterville"), None);
+        assert!(Abbreviations::default().abbreviate("Main Street").is_none());
+        assert!(Abbreviations::for_locales("xx").is_err());
+    }
+}
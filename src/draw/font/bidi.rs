@@ -0,0 +1,66 @@
+use std::borrow::Cow;
+use unicode_bidi::BidiInfo;
+
+/// Reorders the characters of a (possibly mixed-direction) text in the order they should be drawn
+/// from left to right, as defined by the Unicode Bidirectional Algorithm. The paired punctuation in
+/// the right-to-left runs is mirrored, so that e.g. the parentheses still enclose what they should.
+pub fn to_visual_order(text: &str) -> Cow<'_, str> {
+    let bidi_info = BidiInfo::new(text, None);
+    if !bidi_info.has_rtl() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    for para in &bidi_info.paragraphs {
+        let (levels, runs) = bidi_info.visual_runs(para, para.range.clone());
+        for run in runs {
+            let run_text = &text[run.clone()];
+            if levels[run.start].is_rtl() {
+                result.extend(run_text.chars().rev().map(mirror));
+            } else {
+                result.push_str(run_text);
+            }
+        }
+    }
+    Cow::Owned(result)
+}
+
+fn mirror(ch: char) -> char {
+    match ch {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        '‹' => '›',
+        '›' => '‹',
+        _ => ch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_left_to_right_text_is_untouched() {
+        assert!(matches!(to_visual_order("Красная площадь"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_right_to_left_text() {
+        assert_eq!(to_visual_order("שלום"), "םולש");
+        assert_eq!(to_visual_order("(שלום)"), "(םולש)");
+    }
+
+    #[test]
+    fn test_mixed_direction_text() {
+        assert_eq!(to_visual_order("Tel Aviv תל אביב"), "Tel Aviv ביבא לת");
+        assert_eq!(to_visual_order("רחוב Main 12"), "Main 12 בוחר");
+    }
+}
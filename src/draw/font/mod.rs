@@ -1,2 +1,4 @@
+pub mod abbreviations;
+pub mod bidi;
 pub mod rasterizer;
 pub mod text_placer;
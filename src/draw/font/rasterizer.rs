@@ -14,13 +14,16 @@ type Stripes = BTreeMap<i32, Stripe>;
 pub struct Rasterizer {
     stripes: Stripes,
     color: Color,
+    halo: Option<(Color, f64)>,
 }
 
 impl Rasterizer {
-    pub fn new(color: &Color) -> Rasterizer {
+    /// The halo is given as a color and a radius in pixels.
+    pub fn new(color: &Color, halo: Option<(&Color, f64)>) -> Rasterizer {
         Rasterizer {
             stripes: Stripes::default(),
             color: color.clone(),
+            halo: halo.map(|(color, radius)| (color.clone(), radius)),
         }
     }
 
@@ -41,7 +44,7 @@ impl Rasterizer {
         let y_max = y0.max(y1);
 
         for y in (y_min.floor() as i32)..=(y_max.floor() as i32) {
-            let current_stripes = self.stripes.entry(y).or_insert_with(Default::default);
+            let current_stripes = self.stripes.entry(y).or_default();
 
             let y_bottom = f64::from(y).max(y_min);
             let y_top = f64::from(y + 1).min(y_max);
@@ -107,6 +110,81 @@ impl Rasterizer {
     }
 
     pub fn save_to_figure(&self, pixels: &mut TilePixels) -> bool {
+        let coverage = self.compute_coverage();
+        match self.halo {
+            None => {
+                for (&(y, x), &total) in &coverage {
+                    if !pixels.set_label_pixel(x, y, &RgbaColor::from_color(&self.color, total)) {
+                        return false;
+                    }
+                }
+            }
+            Some((ref halo_color, radius)) => {
+                let halo_coverage = dilate(&coverage, radius);
+                for (&(y, x), &halo_total) in &halo_coverage {
+                    let total = coverage.get(&(y, x)).copied().unwrap_or_default();
+                    // The text is drawn over its halo.
+                    let text = RgbaColor::from_color(&self.color, total);
+                    let halo = RgbaColor::from_color(halo_color, (1.0 - total) * halo_total);
+                    let color = RgbaColor {
+                        r: text.r + halo.r,
+                        g: text.g + halo.g,
+                        b: text.b + halo.b,
+                        a: text.a + halo.a,
+                    };
+                    if !pixels.set_label_pixel(x, y, &color) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Fills the box (given as its top left and bottom right pixels) with a color and draws the figure
+    /// over it. The casing, if any, is given as a color and a width in pixels. The halo isn't used here,
+    /// since the box is there to make the text stand out anyway.
+    pub fn save_to_box(
+        &self,
+        (min_x, min_y): (i32, i32),
+        (max_x, max_y): (i32, i32),
+        fill_color: &Color,
+        casing: Option<(&Color, i32)>,
+        pixels: &mut TilePixels,
+    ) -> bool {
+        let coverage = self.compute_coverage();
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let box_color = match casing {
+                    Some((casing_color, width))
+                        if x < min_x + width || x > max_x - width || y < min_y + width || y > max_y - width =>
+                    {
+                        casing_color
+                    }
+                    _ => fill_color,
+                };
+                let total = coverage.get(&(y, x)).copied().unwrap_or_default();
+                let text = RgbaColor::from_color(&self.color, total);
+                let background = RgbaColor::from_color(box_color, 1.0 - total);
+                let color = RgbaColor {
+                    r: text.r + background.r,
+                    g: text.g + background.g,
+                    b: text.b + background.b,
+                    a: 1.0,
+                };
+                if !pixels.set_label_pixel(x, y, &color) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Returns the share of every pixel (keyed by (y, x)) that is covered by the figure.
+    fn compute_coverage(&self) -> BTreeMap<(i32, i32), f64> {
+        let mut coverage = BTreeMap::new();
         for (y, stripe) in &self.stripes {
             let cur_a = stripe.a.iter().collect();
             let cur_s = stripe.s.iter().collect();
@@ -124,8 +202,8 @@ impl Rasterizer {
                 }
             };
 
-            let mut x_min = i32::max_value();
-            let mut x_max = i32::min_value();
+            let mut x_min = i32::MAX;
+            let mut x_max = i32::MIN;
             if !stripe.a.is_empty() {
                 x_min = x_min.min(*stripe.a.keys().min().unwrap());
                 x_max = x_max.max(*stripe.a.keys().max().unwrap());
@@ -138,12 +216,34 @@ impl Rasterizer {
             for x in x_min..=x_max {
                 s_acc += extract_val(&cur_s, &mut s_idx, x);
                 let total = (extract_val(&cur_a, &mut a_idx, x) + s_acc).min(1.0);
-                if total > 0.0 && !pixels.set_label_pixel(x, *y, &RgbaColor::from_color(&self.color, total)) {
-                    return false;
+                if total > 0.0 {
+                    coverage.insert((*y, x), total);
                 }
             }
         }
+        coverage
+    }
+}
 
-        true
+// Spreads the coverage of every pixel over a disc with the given radius, with antialiased edges.
+fn dilate(coverage: &BTreeMap<(i32, i32), f64>, radius: f64) -> BTreeMap<(i32, i32), f64> {
+    let int_radius = radius.ceil() as i32;
+    let mut kernel = Vec::new();
+    for dy in -int_radius..=int_radius {
+        for dx in -int_radius..=int_radius {
+            let weight = (radius + 0.5 - f64::from(dx).hypot(f64::from(dy))).clamp(0.0, 1.0);
+            if weight > 0.0 {
+                kernel.push((dy, dx, weight));
+            }
+        }
+    }
+
+    let mut result = BTreeMap::new();
+    for (&(y, x), &total) in coverage {
+        for &(dy, dx, weight) in &kernel {
+            let value = result.entry((y + dy, x + dx)).or_insert(0.0);
+            *value = f64::max(*value, total * weight);
+        }
     }
+    result
 }
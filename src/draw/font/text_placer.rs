@@ -1,26 +1,35 @@
+use crate::draw::font::abbreviations::Abbreviations;
+use crate::draw::font::bidi::to_visual_order;
 use crate::draw::font::rasterizer::Rasterizer;
 use crate::draw::labelable::Labelable;
 use crate::draw::point::Point;
-use crate::draw::tile_pixels::TilePixels;
+use crate::draw::tile_pixels::{BoundingBox, RgbaColor, TilePixels};
 use crate::geodata::reader::OsmEntity;
 use crate::mapcss::color::Color;
-use crate::mapcss::styler::{TextPosition, TextStyle};
-use crate::tile::{Tile, TILE_SIZE};
+use crate::mapcss::styler::{TextPosition, TextShield, TextStyle, DEFAULT_TEXT_COLOR};
+use crate::tile::Tile;
 use stb_truetype::{FontInfo, Vertex, VertexType};
+use std::borrow::Cow;
 
 pub struct TextPlacer {
     font: FontInfo<&'static [u8]>,
+    abbreviations: Abbreviations,
 }
 
 impl Default for TextPlacer {
     fn default() -> Self {
+        TextPlacer::new(Abbreviations::default())
+    }
+}
+
+impl TextPlacer {
+    pub fn new(abbreviations: Abbreviations) -> TextPlacer {
         TextPlacer {
             font: FontInfo::new(FONT_DATA, 0).unwrap(),
+            abbreviations,
         }
     }
-}
 
-impl TextPlacer {
     pub fn place<'e, E>(
         &self,
         on: &E,
@@ -51,11 +60,31 @@ impl TextPlacer {
 
         let text_color = match text_style.text_color {
             Some(ref color) => color,
-            _ => &Color { r: 0, g: 0, b: 0 },
+            _ => &DEFAULT_TEXT_COLOR,
         };
-        let mut rasterizer = Rasterizer::new(text_color);
+        let halo = text_style
+            .halo
+            .as_ref()
+            .map(|halo| (&halo.color, halo.radius * global_scale));
+        let mut rasterizer = Rasterizer::new(text_color, halo);
         let vm = self.get_v_metrics(scale);
 
+        if text_style.is_vertical && is_cjk(text_to_draw) {
+            let row_height = vm.ascent - vm.descent;
+            let column_height = row_height * glyphs.glyphs.len() as f64;
+            if let Some((center_x, center_y)) =
+                get_vertical_label_center(on, text_pos, tile, global_scale, column_height)
+            {
+                let mut cur_y = center_y - column_height / 2.0;
+                for glyph in &glyphs.glyphs {
+                    let (x_offset, baseline) = (center_x - glyph.width / 2.0, cur_y + vm.ascent);
+                    glyph.rasterize(&mut rasterizer, scale, |&(x, y)| (x_offset + x, baseline - y));
+                    cur_y += row_height;
+                }
+                return rasterizer.save_to_figure(pixels);
+            }
+        }
+
         match text_pos {
             TextPosition::Line => {
                 if let Some(mut points) = on.get_waypoints(tile, global_scale) {
@@ -73,6 +102,20 @@ impl TextPlacer {
                         })
                         .sum();
 
+                    // Try to squeeze the label in by abbreviating it, but only if the full text doesn't fit.
+                    let abbreviated_glyphs;
+                    let glyphs = if glyphs.total_width > total_way_length {
+                        match self.abbreviations.abbreviate(text_to_draw) {
+                            Some(abbreviated_text) => {
+                                abbreviated_glyphs = self.text_to_glyphs(&abbreviated_text, scale);
+                                &abbreviated_glyphs
+                            }
+                            None => return true,
+                        }
+                    } else {
+                        &glyphs
+                    };
+
                     if glyphs.total_width > total_way_length {
                         return true;
                     }
@@ -111,26 +154,8 @@ impl TextPlacer {
             }
             TextPosition::Center => {
                 if let Some((center_x, center_y)) = on.get_label_position(tile, global_scale) {
-                    let mut glyph_rows = Vec::new();
-                    let mut current_row = Vec::new();
-                    let mut current_row_width = 0.0;
-                    let mut max_row_width = 0.0;
-
-                    for (idx, glyph) in glyphs.glyphs.iter().enumerate() {
-                        current_row.push(glyph);
-                        current_row_width += glyph.width;
-                        let is_last_glyph = idx + 1 == glyphs.glyphs.len();
-                        let should_break =
-                            glyph.ch.is_whitespace() && (current_row_width + glyph.width > MAX_TEXT_WIDTH);
-                        if !current_row.is_empty() && (should_break || is_last_glyph) {
-                            glyph_rows.push((current_row.clone(), current_row_width));
-                            if current_row_width > max_row_width {
-                                max_row_width = current_row_width;
-                            }
-                            current_row.clear();
-                            current_row_width = 0.0;
-                        }
-                    }
+                    let max_width = text_style.max_width.unwrap_or(DEFAULT_MAX_TEXT_WIDTH) * font_size;
+                    let glyph_rows = wrap_into_rows(&glyphs.glyphs, max_width);
 
                     let row_height = vm.ascent - vm.descent + vm.line_gap;
                     let total_height = row_height * glyph_rows.len() as f64;
@@ -167,13 +192,138 @@ impl TextPlacer {
         rasterizer.save_to_figure(pixels)
     }
 
+    /// Draws the text (as a single row) in a box beside the entity, trying the sides in turn until the box
+    /// and its leader line don't collide with the labels placed before. `distance` is the space that's
+    /// kept free around the label position (e.g. for the icon) on top of the shield offset.
+    pub fn place_in_shield<'e, E>(
+        &self,
+        on: &E,
+        text_style: &TextStyle,
+        shield: &TextShield,
+        tile: &Tile,
+        global_scale: f64,
+        distance: usize,
+        pixels: &mut TilePixels,
+    ) -> bool
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let (font_size, text_to_draw) = match (text_style.font_size, on.tags().get_by_key(&text_style.text)) {
+            (Some(font_size), Some(text_to_draw)) => (font_size * global_scale, text_to_draw),
+            _ => return true,
+        };
+        let (center_x, center_y) = match on.get_label_position(tile, global_scale) {
+            Some(center) => center,
+            _ => return true,
+        };
+
+        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
+        let glyphs = self.text_to_glyphs(text_to_draw, scale);
+        let vm = self.get_v_metrics(scale);
+        let text_color = text_style.text_color.as_ref().unwrap_or(&DEFAULT_TEXT_COLOR);
+
+        let casing_width = (shield.casing_width * global_scale).round() as i32;
+        let inset = f64::from(casing_width) + shield.padding * global_scale;
+        let box_width = (glyphs.total_width + 2.0 * inset).ceil() as i32;
+        let box_height = (vm.ascent - vm.descent + 2.0 * inset).ceil() as i32;
+        let (point_x, point_y) = (center_x.floor() as i32, center_y.floor() as i32);
+        let gap = (distance as f64 + shield.offset * global_scale).round() as i32;
+        let leader_width = global_scale.round().max(1.0) as i32;
+
+        // Right, left, below and above the point, given as the top left corner of the box and the middle
+        // of its side that faces the point (where the leader line ends).
+        let candidates = [
+            ((point_x + gap, point_y - box_height / 2), (point_x + gap, point_y)),
+            (
+                (point_x - gap - box_width + 1, point_y - box_height / 2),
+                (point_x - gap, point_y),
+            ),
+            ((point_x - box_width / 2, point_y + gap), (point_x, point_y + gap)),
+            (
+                (point_x - box_width / 2, point_y - gap - box_height + 1),
+                (point_x, point_y - gap),
+            ),
+        ];
+
+        let mut placements = candidates.iter().map(|&((min_x, min_y), leader_end)| {
+            let text_box = BoundingBox {
+                min_x,
+                max_x: min_x + box_width - 1,
+                min_y,
+                max_y: min_y + box_height - 1,
+            };
+            (text_box, get_leader_line((point_x, point_y), leader_end, leader_width))
+        });
+        let (text_box, leader_line) = match placements.find(|(text_box, leader_line)| {
+            pixels.is_label_area_free(text_box)
+                && leader_line.as_ref().is_none_or(|line| pixels.is_label_area_free(line))
+        }) {
+            Some(placement) => placement,
+            None => return false,
+        };
+
+        if let Some(leader_line) = leader_line {
+            let leader_color = RgbaColor::from_color(shield.casing_color.as_ref().unwrap_or(text_color), 1.0);
+            for y in leader_line.min_y..=leader_line.max_y {
+                for x in leader_line.min_x..=leader_line.max_x {
+                    if !pixels.set_label_pixel(x, y, &leader_color) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        let mut rasterizer = Rasterizer::new(text_color, None);
+        let baseline = f64::from(text_box.min_y) + inset + vm.ascent;
+        let mut cur_x = f64::from(text_box.min_x) + inset;
+        for glyph in &glyphs.glyphs {
+            let x_offset = cur_x;
+            glyph.rasterize(&mut rasterizer, scale, |&(x, y)| (x_offset + x, baseline - y));
+            cur_x += glyph.width;
+        }
+        let casing = shield.casing_color.as_ref().map(|color| (color, casing_width));
+        rasterizer.save_to_box(
+            (text_box.min_x, text_box.min_y),
+            (text_box.max_x, text_box.max_y),
+            &shield.color,
+            casing,
+            pixels,
+        )
+    }
+
+    /// Draws a single row of text centered at the given point.
+    pub fn place_at(
+        &self,
+        text: &str,
+        text_color: &Color,
+        font_size: f64,
+        (center_x, center_y): (f64, f64),
+        pixels: &mut TilePixels,
+    ) -> bool {
+        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
+        let glyphs = self.text_to_glyphs(text, scale);
+        let vm = self.get_v_metrics(scale);
+        let mut rasterizer = Rasterizer::new(text_color, None);
+
+        let baseline = center_y + (vm.ascent + vm.descent) / 2.0;
+        let mut cur_x = center_x - glyphs.total_width / 2.0;
+        for glyph in &glyphs.glyphs {
+            let x_offset = cur_x;
+            glyph.rasterize(&mut rasterizer, scale, |&(x, y)| (x_offset + x, baseline - y));
+            cur_x += glyph.width;
+        }
+
+        rasterizer.save_to_figure(pixels)
+    }
+
     fn text_to_glyphs(&self, text: &str, scale: f64) -> Glyphs {
         let mut result = Glyphs {
             glyphs: Vec::<Glyph>::default(),
             total_width: 0.0,
         };
         let mut prev_glyph_id: Option<u32> = None;
-        for ch in text.chars() {
+        let text = self.drop_missing_emoji(text);
+        for ch in to_visual_order(&text).chars() {
             let glyph_id = self.font.find_glyph_index(ch as u32);
             let advance_width = f64::from(self.font.get_glyph_h_metrics(glyph_id).advance_width);
 
@@ -196,6 +346,25 @@ impl TextPlacer {
         result
     }
 
+    // The font can't draw color glyphs, and most emoji aren't in it anyway. Emoji that the font does have
+    // are drawn like any other character, and the rest are dropped instead of being drawn as empty boxes.
+    fn drop_missing_emoji<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let is_missing_emoji = |ch: char| is_emoji(ch) && self.font.find_glyph_index(ch as u32) == 0;
+        if !text.chars().any(is_missing_emoji) {
+            return Cow::Borrowed(text);
+        }
+        // The joiners and the selectors of the dropped emoji go with them, even if the font has them.
+        let mut is_previous_dropped = false;
+        let filtered = text
+            .chars()
+            .filter(|&ch| {
+                is_previous_dropped = is_missing_emoji(ch) || (is_previous_dropped && is_emoji_component(ch));
+                !is_previous_dropped
+            })
+            .collect::<String>();
+        Cow::Owned(filtered.trim().to_string())
+    }
+
     fn get_v_metrics(&self, scale: f64) -> VMetrics {
         let convert = |x| f64::from(x) * scale;
         let vm = self.font.get_v_metrics();
@@ -253,6 +422,71 @@ struct Glyphs {
     total_width: f64,
 }
 
+// Breaks the text into rows at whitespace, putting as many words into each row as fits into `max_width`.
+// A word that is wider than `max_width` on its own gets a row of its own. Returns the glyphs of each row
+// along with the row width.
+fn wrap_into_rows(glyphs: &[Glyph], max_width: f64) -> Vec<(Vec<&Glyph>, f64)> {
+    let width = |glyphs: &[Glyph]| glyphs.iter().map(|g| g.width).sum::<f64>();
+
+    let mut rows = Vec::new();
+    let mut current_row = Vec::<&Glyph>::new();
+    let mut current_row_width = 0.0;
+
+    let mut idx = 0;
+    while idx < glyphs.len() {
+        let separator_start = idx;
+        while idx < glyphs.len() && glyphs[idx].ch.is_whitespace() {
+            idx += 1;
+        }
+        let word_start = idx;
+        while idx < glyphs.len() && !glyphs[idx].ch.is_whitespace() {
+            idx += 1;
+        }
+
+        let (separator, word) = (&glyphs[separator_start..word_start], &glyphs[word_start..idx]);
+        if word.is_empty() {
+            break;
+        }
+        if !current_row.is_empty() {
+            if current_row_width + width(separator) + width(word) > max_width {
+                rows.push((std::mem::take(&mut current_row), current_row_width));
+                current_row_width = 0.0;
+            } else {
+                current_row.extend(separator);
+                current_row_width += width(separator);
+            }
+        }
+        current_row.extend(word);
+        current_row_width += width(word);
+    }
+
+    if !current_row.is_empty() {
+        rows.push((current_row, current_row_width));
+    }
+    rows
+}
+
+// The leader lines are either horizontal or vertical, so they're drawn as thin rectangles. The end pixel
+// (that belongs to the box) isn't included. Returns `None` if the box touches the point.
+fn get_leader_line((from_x, from_y): (i32, i32), (to_x, to_y): (i32, i32), width: i32) -> Option<BoundingBox> {
+    if from_x == to_x && from_y == to_y {
+        return None;
+    }
+    let across = |coord: i32| (coord - (width - 1) / 2, coord + width / 2);
+    let along = |from: i32, to: i32| if from < to { (from, to - 1) } else { (to + 1, from) };
+    let ((min_x, max_x), (min_y, max_y)) = if from_x == to_x {
+        (across(from_x), along(from_y, to_y))
+    } else {
+        (along(from_x, to_x), across(from_y))
+    };
+    Some(BoundingBox {
+        min_x,
+        max_x,
+        min_y,
+        max_y,
+    })
+}
+
 fn get_angle(points: &[Point], start_idx: usize) -> f64 {
     let from = &points[start_idx];
     let to = &points[start_idx + 1];
@@ -276,7 +510,7 @@ fn compute_way_position(points: &[Point], advance_by: f64) -> WayPosition {
             let from = &points[point_idx];
             let to = &points[point_idx + 1];
             let ratio = to_travel / from.dist(to);
-            let coord_dist = |from_c, to_c| (f64::from(from_c) + (f64::from(to_c - from_c) * ratio));
+            let coord_dist = |from_c, to_c| f64::from(from_c) + (f64::from(to_c - from_c) * ratio);
             return WayPosition {
                 x: coord_dist(from.x, to.x),
                 y: coord_dist(from.y, to.y),
@@ -295,5 +529,136 @@ fn compute_way_position(points: &[Point], advance_by: f64) -> WayPosition {
     }
 }
 
-const MAX_TEXT_WIDTH: f64 = TILE_SIZE as f64 / 8.0;
+// CJK text can be written top to bottom, with the characters kept upright. Ways get such labels only if
+// they go more up and down than sideways and are long enough for the whole column.
+fn get_vertical_label_center(
+    on: &impl Labelable,
+    text_pos: &TextPosition,
+    tile: &Tile,
+    scale: f64,
+    column_height: f64,
+) -> Option<(f64, f64)> {
+    match text_pos {
+        TextPosition::Center => on.get_label_position(tile, scale),
+        TextPosition::Line => {
+            let points = on.get_waypoints(tile, scale)?;
+            if points.len() < 2 {
+                return None;
+            }
+            let (min_x, max_x) = (points.iter().map(|p| p.x).min()?, points.iter().map(|p| p.x).max()?);
+            let (min_y, max_y) = (points.iter().map(|p| p.y).min()?, points.iter().map(|p| p.y).max()?);
+            let total_way_length = (1..points.len())
+                .map(|idx| points[idx - 1].dist(&points[idx]))
+                .sum::<f64>();
+            if max_y - min_y <= max_x - min_x || column_height > total_way_length {
+                return None;
+            }
+            let middle = compute_way_position(&points, total_way_length / 2.0);
+            Some((middle.x, middle.y))
+        }
+    }
+}
+
+fn is_emoji(ch: char) -> bool {
+    matches!(ch as u32,
+        0x200D // Zero width joiner
+        | 0x20E3 // Combining enclosing keycap
+        | 0x2600..=0x27BF // Miscellaneous symbols and dingbats
+        | 0x2B00..=0x2BFF // Miscellaneous symbols and arrows
+        | 0xFE0E..=0xFE0F // Text and emoji variation selectors
+        | 0x1F000..=0x1FAFF // Emoji, flags, skin tone modifiers, etc.
+        | 0xE0020..=0xE007F // Tags used in subdivision flags
+    )
+}
+
+// The parts of the emoji sequences that don't stand for anything on their own.
+fn is_emoji_component(ch: char) -> bool {
+    matches!(ch as u32, 0x200D | 0x20E3 | 0xFE0E..=0xFE0F | 0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F)
+}
+
+fn is_cjk(text: &str) -> bool {
+    text.chars().filter(|ch| !ch.is_whitespace()).all(|ch| {
+        matches!(ch as u32,
+            0x3000..=0x303F // CJK symbols and punctuation
+            | 0x3040..=0x30FF // Hiragana and Katakana
+            | 0x3400..=0x4DBF // CJK Unified Ideographs Extension A
+            | 0x4E00..=0x9FFF // CJK Unified Ideographs
+            | 0xAC00..=0xD7AF // Hangul syllables
+            | 0xFF00..=0xFFEF // Halfwidth and fullwidth forms
+        )
+    })
+}
+
+// In ems.
+const DEFAULT_MAX_TEXT_WIDTH: f64 = 6.0;
 const FONT_DATA: &[u8] = include_bytes!("NotoSans-Regular.ttf");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_into_rows() {
+        let glyphs = "ab cd  ef ghijk"
+            .chars()
+            .map(|ch| Glyph {
+                ch,
+                width: 1.0,
+                shape: None,
+            })
+            .collect::<Vec<_>>();
+        let rows = |max_width| {
+            wrap_into_rows(&glyphs, max_width)
+                .iter()
+                .map(|(row, width)| (row.iter().map(|g| g.ch).collect::<String>(), *width))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            rows(6.0),
+            vec![
+                ("ab cd".to_string(), 5.0),
+                ("ef".to_string(), 2.0),
+                ("ghijk".to_string(), 5.0)
+            ]
+        );
+        assert_eq!(
+            rows(3.0),
+            vec![
+                ("ab".to_string(), 2.0),
+                ("cd".to_string(), 2.0),
+                ("ef".to_string(), 2.0),
+                ("ghijk".to_string(), 5.0)
+            ]
+        );
+        assert_eq!(rows(100.0), vec![("ab cd  ef ghijk".to_string(), 15.0)]);
+    }
+
+    #[test]
+    fn test_is_emoji() {
+        assert!(is_emoji('☕'));
+        assert!(is_emoji('🍕'));
+        assert!(is_emoji('\u{FE0F}'));
+        assert!(is_emoji('\u{200D}'));
+        assert!(!is_emoji('a'));
+        assert!(!is_emoji('ü'));
+        assert!(!is_emoji('Ж'));
+        assert!(!is_emoji('中'));
+        assert!(is_emoji_component('\u{200D}'));
+        assert!(!is_emoji_component('🍳'));
+    }
+
+    #[test]
+    fn test_drop_missing_emoji() {
+        // The bundled font has none of the emoji.
+        let text_placer = TextPlacer::default();
+        assert_eq!(text_placer.drop_missing_emoji("☕ Café"), "Café");
+        assert_eq!(text_placer.drop_missing_emoji("Pizza 🍕🍕"), "Pizza");
+        assert_eq!(text_placer.drop_missing_emoji("👩\u{200D}🍳 Chef"), "Chef");
+        assert_eq!(text_placer.drop_missing_emoji("🍕"), "");
+        assert!(matches!(
+            text_placer.drop_missing_emoji("Zürich Hbf"),
+            Cow::Borrowed("Zürich Hbf")
+        ));
+    }
+}
@@ -2,6 +2,7 @@ use crate::draw::tile_pixels::RgbaColor;
 use anyhow::{bail, Context, Result};
 use png::{ColorType, Decoder, Transformations};
 use std::fs::File;
+use std::io::{BufRead, BufReader, Seek};
 use std::path::Path;
 
 pub struct Icon {
@@ -16,7 +17,11 @@ impl Icon {
         P: AsRef<Path>,
     {
         let icon_file = File::open(&icon_path).context("Failed to open icon file")?;
-        let mut decoder = Decoder::new(icon_file);
+        Icon::decode(BufReader::new(icon_file))
+    }
+
+    pub fn decode<R: BufRead + Seek>(png_reader: R) -> Result<Icon> {
+        let mut decoder = Decoder::new(png_reader);
         decoder.set_transformations(Transformations::normalize_to_color8());
         let mut reader = decoder.read_info().context("Icon is not a valid PNG file")?;
 
@@ -29,13 +34,7 @@ impl Icon {
         let mut idx = 0;
         while idx < info.buffer_size() {
             let (r, g, b, a, idx_delta) = match info.color_type {
-                ColorType::Rgb => (
-                    raw_pixels[idx],
-                    raw_pixels[idx + 1],
-                    raw_pixels[idx + 2],
-                    u8::max_value(),
-                    3,
-                ),
+                ColorType::Rgb => (raw_pixels[idx], raw_pixels[idx + 1], raw_pixels[idx + 2], u8::MAX, 3),
                 ColorType::Rgba => (
                     raw_pixels[idx],
                     raw_pixels[idx + 1],
@@ -63,7 +62,117 @@ impl Icon {
         })
     }
 
+    /// Rasterizes an SVG icon. Without the width and the height, the icon keeps its own size, and with only one
+    /// of them, its proportions. All sizes are multiplied by the scale (e.g. 2 for high-resolution tiles).
+    #[cfg(feature = "svg")]
+    pub fn load_svg<P>(icon_path: P, width: Option<u32>, height: Option<u32>, scale: u32) -> Result<Icon>
+    where
+        P: AsRef<Path>,
+    {
+        use resvg::{tiny_skia, usvg};
+
+        let svg_data = std::fs::read(&icon_path).context("Failed to open icon file")?;
+        let tree =
+            usvg::Tree::from_data(&svg_data, &usvg::Options::default()).context("Icon is not a valid SVG file")?;
+
+        let (own_width, own_height) = (f64::from(tree.size().width()), f64::from(tree.size().height()));
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (f64::from(w), f64::from(h)),
+            (Some(w), None) => (f64::from(w), own_height * f64::from(w) / own_width),
+            (None, Some(h)) => (own_width * f64::from(h) / own_height, f64::from(h)),
+            (None, None) => (own_width * f64::from(scale), own_height * f64::from(scale)),
+        };
+        let (width, height) = (width.round().max(1.0) as u32, height.round().max(1.0) as u32);
+
+        let mut pixmap = tiny_skia::Pixmap::new(width, height).context("Invalid icon size")?;
+        let transform = tiny_skia::Transform::from_scale(
+            (f64::from(width) / own_width) as f32,
+            (f64::from(height) / own_height) as f32,
+        );
+        resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .map(|p| {
+                let c = p.demultiply();
+                RgbaColor::from_components(c.red(), c.green(), c.blue(), c.alpha())
+            })
+            .collect();
+
+        Ok(Icon {
+            pixels,
+            width: width as usize,
+            height: height as usize,
+        })
+    }
+
+    // The pixels go row by row, and their colors are premultiplied.
+    pub(super) fn from_pixels(pixels: Vec<RgbaColor>, width: usize, height: usize) -> Icon {
+        assert_eq!(pixels.len(), width * height);
+        Icon { pixels, width, height }
+    }
+
     pub fn get(&self, x: usize, y: usize) -> RgbaColor {
         self.pixels[y * self.width + x].clone()
     }
+
+    /// Returns the icon rotated clockwise around its center, enlarged to fit the rotated corners.
+    pub fn rotate(&self, degrees: f64) -> Icon {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (w, h) = (self.width as f64, self.height as f64);
+        let width = (w * cos.abs() + h * sin.abs()).round().max(1.0) as usize;
+        let height = (w * sin.abs() + h * cos.abs()).round().max(1.0) as usize;
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                // Rotate the center of the pixel back to find where it comes from in the original icon.
+                let dx = x as f64 + 0.5 - width as f64 / 2.0;
+                let dy = y as f64 + 0.5 - height as f64 / 2.0;
+                let src_x = dx * cos + dy * sin + w / 2.0 - 0.5;
+                let src_y = -dx * sin + dy * cos + h / 2.0 - 0.5;
+                pixels.push(self.interpolate(src_x, src_y));
+            }
+        }
+
+        Icon { pixels, width, height }
+    }
+
+    // Bilinear interpolation, with transparent pixels around the icon. The colors are premultiplied,
+    // so they can be mixed directly.
+    fn interpolate(&self, x: f64, y: f64) -> RgbaColor {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let mut result = RgbaColor {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 0.0,
+        };
+        for (px, py, weight) in [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x0 + 1.0, y0, fx * (1.0 - fy)),
+            (x0, y0 + 1.0, (1.0 - fx) * fy),
+            (x0 + 1.0, y0 + 1.0, fx * fy),
+        ] {
+            if px < 0.0 || py < 0.0 || px >= self.width as f64 || py >= self.height as f64 || weight == 0.0 {
+                continue;
+            }
+            let p = &self.pixels[py as usize * self.width + px as usize];
+            result.r += p.r * weight;
+            result.g += p.g * weight;
+            result.b += p.b * weight;
+            result.a += p.a * weight;
+        }
+        result
+    }
+
+    /// Returns the given rectangle of the icon as a separate icon.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Icon {
+        let pixels = (y..y + height)
+            .flat_map(|row| (x..x + width).map(move |col| self.get(col, row)))
+            .collect();
+        Icon { pixels, width, height }
+    }
 }
@@ -1,46 +1,122 @@
 use crate::draw::icon::Icon;
+use crate::draw::sprite::Sprite;
+use crate::draw::tile_pixels::RgbaColor;
 use std::collections::HashMap;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::{RwLock, RwLockReadGuard};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 
 pub struct IconCache {
-    cache: RwLock<NameToIcon>,
+    cache: RwLock<KeyToIcon>,
     base_path: PathBuf,
+    sprite: Option<Arc<Sprite>>,
 }
 
-pub type NameToIcon = HashMap<String, Option<Icon>>;
+pub type KeyToIcon = HashMap<IconKey, Option<Icon>>;
+
+/// Identifies an icon at the size it's drawn at. SVG icons are rasterized (and cached) separately
+/// for every size, while the other icons are always drawn as they are.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct IconKey {
+    name: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    scale: u32,
+}
+
+impl IconKey {
+    pub fn new(name: &str, width: Option<f64>, height: Option<f64>, scale: f64) -> IconKey {
+        if !is_svg(name) {
+            return IconKey::unscaled(name);
+        }
+        let to_pixels = |size: Option<f64>| size.map(|s| (s * scale).round().max(1.0) as u32);
+        IconKey {
+            name: name.to_string(),
+            width: to_pixels(width),
+            height: to_pixels(height),
+            scale: scale.round().max(1.0) as u32,
+        }
+    }
+
+    pub fn unscaled(name: &str) -> IconKey {
+        IconKey {
+            name: name.to_string(),
+            width: None,
+            height: None,
+            scale: 1,
+        }
+    }
+}
 
 impl IconCache {
-    pub fn new(base_path: &Path) -> IconCache {
+    pub fn new(base_path: &Path, sprite: Option<Arc<Sprite>>) -> IconCache {
         IconCache {
-            cache: RwLock::<NameToIcon>::default(),
+            cache: RwLock::<KeyToIcon>::default(),
             base_path: base_path.to_owned(),
+            sprite,
         }
     }
 
-    pub fn open_read_session(&self, icon_name: &str) -> RwLockReadGuard<'_, NameToIcon> {
+    pub fn open_read_session(&self, key: &IconKey) -> RwLockReadGuard<'_, KeyToIcon> {
         {
             let read_cache = self.cache.read().unwrap();
-            if read_cache.get(icon_name).is_some() {
+            if read_cache.get(key).is_some() {
                 return read_cache;
             }
         }
 
         {
-            let full_icon_path = self.base_path.join(icon_name);
+            let full_icon_path = self.base_path.join(&key.name);
             let mut write_icon_cache = self.cache.write().unwrap();
-            write_icon_cache
-                .entry(icon_name.to_string())
-                .or_insert(match Icon::load(&full_icon_path) {
+            write_icon_cache.entry(key.clone()).or_insert_with(|| {
+                // The icons that are missing from the sprite (if any) are still loaded from their own files.
+                if let Some(icon) = self.sprite.as_ref().and_then(|sprite| sprite.get_icon(&key.name)) {
+                    return Some(icon);
+                }
+                match load_icon(&full_icon_path, key) {
                     Ok(icon) => Some(icon),
                     Err(error) => {
                         let full_icon_path_str = full_icon_path.to_str().unwrap_or("N/A");
                         eprintln!("Failed to load icon from {}: {}", full_icon_path_str, error);
                         None
                     }
-                });
+                }
+            });
         }
 
         self.cache.read().unwrap()
     }
+
+    /// The memory taken by the pixels of the loaded icons. The icons are never evicted, since there are only as
+    /// many of them as the stylesheet mentions (times the sizes that they're drawn at).
+    pub fn used_bytes(&self) -> usize {
+        self.cache
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|icon| icon.width * icon.height * mem::size_of::<RgbaColor>())
+            .sum()
+    }
+}
+
+fn is_svg(name: &str) -> bool {
+    name.ends_with(".svg")
+}
+
+#[cfg(feature = "svg")]
+fn load_icon(path: &Path, key: &IconKey) -> anyhow::Result<Icon> {
+    if is_svg(&key.name) {
+        Icon::load_svg(path, key.width, key.height, key.scale)
+    } else {
+        Icon::load(path)
+    }
+}
+
+#[cfg(not(feature = "svg"))]
+fn load_icon(path: &Path, key: &IconKey) -> anyhow::Result<Icon> {
+    if is_svg(&key.name) {
+        anyhow::bail!("SVG icons are only supported when built with the svg feature");
+    }
+    Icon::load(path)
 }
@@ -0,0 +1,120 @@
+use crate::geodata::reader::{OsmEntity, Way};
+use crate::mapcss::styler::{IconRotation, Style};
+use crate::tile::{coords_to_xy_tile_relative, Tile};
+use std::collections::HashMap;
+
+/// Returns the clockwise angle (in degrees) that the icon of the entity should be rotated by, if any.
+/// `way_bearing` (the direction of the way that the entity belongs to) is only called for `icon-rotation: way`.
+pub(super) fn get_icon_angle<'e>(
+    entity: &impl OsmEntity<'e>,
+    style: &Style,
+    way_bearing: impl FnOnce() -> Option<f64>,
+) -> Option<f64> {
+    match style.icon_rotation.as_ref()? {
+        IconRotation::Angle(angle) => Some(*angle),
+        IconRotation::Tag(tag_name) => entity.tags().get_by_key(tag_name).and_then(parse_direction),
+        IconRotation::Way => {
+            // The way only defines the angle up to its direction; e.g. a stop sign can face either way.
+            let flip = entity.tags().get_by_key("direction") == Some("backward");
+            way_bearing().map(|bearing| if flip { bearing + 180.0 } else { bearing })
+        }
+    }
+}
+
+/// For every node of the ways, the direction (clockwise from the north, in degrees) in which the way
+/// goes through it. If a node belongs to several ways, the first one wins.
+pub(super) fn get_way_bearings(ways: &[Way<'_>], tile: &Tile) -> HashMap<u64, f64> {
+    let mut bearings = HashMap::new();
+    for way in ways {
+        let node_count = way.node_count();
+        for idx in 0..node_count {
+            let node_id = way.get_node(idx).global_id();
+            if bearings.contains_key(&node_id) {
+                continue;
+            }
+            let prev = coords_to_xy_tile_relative(&way.get_node(idx.saturating_sub(1)), tile);
+            let next = coords_to_xy_tile_relative(&way.get_node((idx + 1).min(node_count - 1)), tile);
+            if let Some(bearing) = get_bearing(prev, next) {
+                bearings.insert(node_id, bearing);
+            }
+        }
+    }
+    bearings
+}
+
+// The screen Y axis points to the south.
+fn get_bearing(from: (f64, f64), to: (f64, f64)) -> Option<f64> {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    if dx == 0.0 && dy == 0.0 {
+        return None;
+    }
+    Some(dx.atan2(-dy).to_degrees().rem_euclid(360.0))
+}
+
+// Parses the values of the `direction=*` tag: either degrees or one of the 16 compass points.
+fn parse_direction(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if let Ok(degrees) = value.parse::<f64>() {
+        return Some(degrees);
+    }
+    COMPASS_POINTS
+        .iter()
+        .position(|p| p.eq_ignore_ascii_case(value))
+        .map(|idx| idx as f64 * 22.5)
+}
+
+const COMPASS_POINTS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW", "NNW",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_direction() {
+        assert_eq!(parse_direction("45"), Some(45.0));
+        assert_eq!(parse_direction("N"), Some(0.0));
+        assert_eq!(parse_direction("ese"), Some(112.5));
+        assert_eq!(parse_direction("NNW"), Some(337.5));
+        assert_eq!(parse_direction("forward"), None);
+    }
+
+    #[test]
+    fn test_rotate_icon() {
+        use crate::draw::icon::Icon;
+        use crate::draw::png_writer::rgba_quadruples_to_png;
+        use std::io::Cursor;
+
+        let png = rgba_quadruples_to_png(&[(255, 0, 0, 255), (0, 0, 255, 255)], 2, 1).unwrap();
+        let icon = Icon::decode(Cursor::new(png)).unwrap();
+        let colors = |icon: &Icon| {
+            (0..icon.height)
+                .flat_map(|y| (0..icon.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let p = icon.get(x, y);
+                    let to_u8 = |c: f64| (c * 255.0).round() as u8;
+                    (to_u8(p.r), to_u8(p.g), to_u8(p.b), to_u8(p.a))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Clockwise, so the left pixel ends up on top.
+        let rotated = icon.rotate(90.0);
+        assert_eq!((rotated.width, rotated.height), (1, 2));
+        assert_eq!(colors(&rotated), vec![(255, 0, 0, 255), (0, 0, 255, 255)]);
+
+        let rotated = icon.rotate(180.0);
+        assert_eq!((rotated.width, rotated.height), (2, 1));
+        assert_eq!(colors(&rotated), vec![(0, 0, 255, 255), (255, 0, 0, 255)]);
+    }
+
+    #[test]
+    fn test_bearing() {
+        assert_eq!(get_bearing((0.0, 0.0), (0.0, -1.0)), Some(0.0));
+        assert_eq!(get_bearing((0.0, 0.0), (1.0, 0.0)), Some(90.0));
+        assert_eq!(get_bearing((0.0, 0.0), (0.0, 1.0)), Some(180.0));
+        assert_eq!(get_bearing((0.0, 0.0), (-1.0, 0.0)), Some(270.0));
+        assert_eq!(get_bearing((1.0, 1.0), (1.0, 1.0)), None);
+    }
+}
@@ -106,13 +106,13 @@ impl Eq for Cell {}
 
 impl Ord for Cell {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.max_fitness.partial_cmp(&other.max_fitness).unwrap()
     }
 }
 
 impl PartialOrd for Cell {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.max_fitness.partial_cmp(&other.max_fitness)
+        Some(self.cmp(other))
     }
 }
 
@@ -247,10 +247,10 @@ impl BoundingBox {
 }
 
 fn get_bounding_box(polygon: &[PointF]) -> BoundingBox {
-    let mut min_x = std::f64::INFINITY;
-    let mut max_x = std::f64::NEG_INFINITY;
-    let mut min_y = std::f64::INFINITY;
-    let mut max_y = std::f64::NEG_INFINITY;
+    let mut min_x = f64::INFINITY;
+    let mut max_x = f64::NEG_INFINITY;
+    let mut min_y = f64::INFINITY;
+    let mut max_y = f64::NEG_INFINITY;
 
     for point in polygon {
         min_x = min_x.min(point.0);
@@ -293,7 +293,7 @@ fn segment_dist_sq(point: &PointF, seg_start: &PointF, seg_end: &PointF) -> f64
 
 fn point_to_polygon_dist(point: &PointF, polygons: &[Vec<PointF>]) -> f64 {
     let mut inside = false;
-    let mut min_dist_sq = std::f64::INFINITY;
+    let mut min_dist_sq = f64::INFINITY;
 
     for poly in polygons {
         for (a, b) in iterate_polygon(poly) {
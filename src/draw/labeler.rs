@@ -1,9 +1,12 @@
+use crate::draw::font::abbreviations::Abbreviations;
 use crate::draw::font::text_placer::TextPlacer;
 use crate::draw::icon::Icon;
-use crate::draw::icon_cache::IconCache;
+use crate::draw::icon_cache::{IconCache, IconKey};
 use crate::draw::labelable::Labelable;
+use crate::draw::symbol::symbol_to_icon;
 use crate::draw::tile_pixels::TilePixels;
 use crate::geodata::reader::OsmEntity;
+use crate::mapcss::color::Color;
 use crate::mapcss::styler::{Style, TextPosition};
 use crate::tile::Tile;
 
@@ -12,22 +15,35 @@ pub struct Labeler {
     text_placer: TextPlacer,
 }
 
+/// What all the labels of a tile are placed with.
+pub struct LabelContext<'a> {
+    pub tile: &'a Tile,
+    pub scale: f64,
+    pub icon_cache: &'a IconCache,
+}
+
 impl Labeler {
+    pub fn new(abbreviations: Abbreviations) -> Labeler {
+        Labeler {
+            text_placer: TextPlacer::new(abbreviations),
+        }
+    }
+
     pub fn label_entity<'e, E>(
         &self,
         entity: &E,
         style: &Style,
-        tile: &Tile,
-        scale: f64,
-        icon_cache: &IconCache,
+        icon_angle: Option<f64>,
         default_text_position: TextPosition,
+        context: &LabelContext<'_>,
         pixels: &mut TilePixels,
     ) where
         E: Labelable + OsmEntity<'e>,
     {
         let succeeded = {
-            if let Some(y_offset) = self.label_with_icon(entity, style, tile, scale, icon_cache, pixels) {
-                self.label_with_text(entity, style, tile, scale, y_offset, default_text_position, pixels)
+            if let Some((_, half_height)) = self.label_with_icon(entity, style, icon_angle, context, pixels) {
+                let (tile, scale) = (context.tile, context.scale);
+                self.label_with_text(entity, style, tile, scale, half_height, default_text_position, pixels)
             } else {
                 false
             }
@@ -36,34 +52,94 @@ impl Labeler {
         pixels.bump_label_generation(succeeded);
     }
 
+    /// Same as `label_entity`, but if the entity represents several POIs, their number is drawn
+    /// at the top right corner of its icon.
+    pub fn label_cluster<'e, E>(
+        &self,
+        entity: &E,
+        style: &Style,
+        count: usize,
+        icon_angle: Option<f64>,
+        context: &LabelContext<'_>,
+        pixels: &mut TilePixels,
+    ) where
+        E: Labelable + OsmEntity<'e>,
+    {
+        if count <= 1 {
+            self.label_entity(entity, style, icon_angle, TextPosition::Center, context, pixels);
+            return;
+        }
+
+        let (tile, scale) = (context.tile, context.scale);
+        let succeeded = match self.label_with_icon(entity, style, icon_angle, context, pixels) {
+            Some((half_width, half_height)) => {
+                let has_badge = match entity.get_label_position(tile, scale) {
+                    Some((center_x, center_y)) if half_width > 0 => self.text_placer.place_at(
+                        &count.to_string(),
+                        &BADGE_COLOR,
+                        BADGE_FONT_SIZE * scale,
+                        (center_x + half_width as f64, center_y - half_height as f64),
+                        pixels,
+                    ),
+                    _ => true,
+                };
+                has_badge && self.label_with_text(entity, style, tile, scale, half_height, TextPosition::Center, pixels)
+            }
+            None => false,
+        };
+
+        pixels.bump_label_generation(succeeded);
+    }
+
     fn label_with_icon(
         &self,
         entity: &impl Labelable,
         style: &Style,
-        tile: &Tile,
-        scale: f64,
-        icon_cache: &IconCache,
+        icon_angle: Option<f64>,
+        context: &LabelContext<'_>,
         pixels: &mut TilePixels,
-    ) -> Option<usize> {
-        let icon_name = match style.icon_image {
-            Some(ref icon_name) => icon_name,
-            _ => return Some(0),
+    ) -> Option<(usize, usize)> {
+        let (tile, scale) = (context.tile, context.scale);
+        let icon_name = match (&style.icon_image, &style.symbol) {
+            (Some(icon_name), _) => icon_name,
+            (None, Some(symbol)) => {
+                let icon = symbol_to_icon(symbol, scale);
+                return self.place_icon(entity, &icon, tile, scale, icon_angle, pixels);
+            }
+            (None, None) => return Some((0, 0)),
         };
 
-        let read_icon_cache = icon_cache.open_read_session(icon_name);
+        let icon_key = IconKey::new(icon_name, style.icon_width, style.icon_height, scale);
+        let read_icon_cache = context.icon_cache.open_read_session(&icon_key);
 
-        if let Some(Some(icon)) = read_icon_cache.get(icon_name) {
-            let (center_x, center_y) = match entity.get_label_position(tile, scale) {
-                Some(center) => center,
-                _ => return Some(0),
-            };
-            if self.draw_icon(icon, center_x, center_y, pixels) {
-                Some(icon.height / 2)
-            } else {
-                None
-            }
+        if let Some(Some(icon)) = read_icon_cache.get(&icon_key) {
+            self.place_icon(entity, icon, tile, scale, icon_angle, pixels)
         } else {
-            Some(0)
+            Some((0, 0))
+        }
+    }
+
+    fn place_icon(
+        &self,
+        entity: &impl Labelable,
+        icon: &Icon,
+        tile: &Tile,
+        scale: f64,
+        icon_angle: Option<f64>,
+        pixels: &mut TilePixels,
+    ) -> Option<(usize, usize)> {
+        let (center_x, center_y) = match entity.get_label_position(tile, scale) {
+            Some(center) => center,
+            _ => return Some((0, 0)),
+        };
+        let rotated_icon = icon_angle
+            .filter(|angle| angle.rem_euclid(360.0) != 0.0)
+            .map(|angle| icon.rotate(angle));
+        let icon = rotated_icon.as_ref().unwrap_or(icon);
+        if self.draw_icon(icon, center_x, center_y, pixels) {
+            Some((icon.width / 2, icon.height / 2))
+        } else {
+            None
         }
     }
 
@@ -80,11 +156,19 @@ impl Labeler {
     where
         E: Labelable + OsmEntity<'e>,
     {
-        if let Some(ref text_style) = style.text_style {
-            self.text_placer
-                .place(entity, text_style, tile, scale, y_offset, default_text_position, pixels)
-        } else {
-            true
+        match style.text_style {
+            Some(ref text_style) => match (
+                &text_style.shield,
+                text_style.text_position.as_ref().unwrap_or(&default_text_position),
+            ) {
+                (Some(shield), TextPosition::Center) => self
+                    .text_placer
+                    .place_in_shield(entity, text_style, shield, tile, scale, y_offset, pixels),
+                _ => self
+                    .text_placer
+                    .place(entity, text_style, tile, scale, y_offset, default_text_position, pixels),
+            },
+            None => true,
         }
     }
 
@@ -105,3 +189,6 @@ impl Labeler {
         true
     }
 }
+
+const BADGE_FONT_SIZE: f64 = 10.0;
+const BADGE_COLOR: Color = Color { r: 0xc0, g: 0, b: 0 };
@@ -2,57 +2,123 @@ use crate::draw::opacity_calculator::OpacityCalculator;
 use crate::draw::point::Point;
 use crate::draw::point_pairs::PointPairIter;
 use crate::draw::tile_pixels::RgbaColor;
-use crate::draw::tile_pixels::TilePixels;
+use crate::draw::tile_pixels::{BoundingBox, TilePixels};
 use crate::mapcss::color::Color;
 use crate::mapcss::styler::{is_non_trivial_cap, LineCap};
+use std::collections::HashSet;
 
+/// The caps at the ends of the whole line and at the ends of its dashes. The ends fall back to the caps of
+/// the dashes (i.e. `linecap`) when they don't have their own ones.
+#[derive(Clone, Copy)]
+pub struct LineCaps<'a> {
+    pub line: &'a Option<LineCap>,
+    pub start: &'a Option<LineCap>,
+    pub end: &'a Option<LineCap>,
+}
+
+impl<'a> LineCaps<'a> {
+    pub fn uniform(line_cap: &'a Option<LineCap>) -> LineCaps<'a> {
+        LineCaps {
+            line: line_cap,
+            start: &None,
+            end: &None,
+        }
+    }
+
+    fn own_or_line_cap(&self, cap: &'a Option<LineCap>) -> &'a Option<LineCap> {
+        if cap.is_some() {
+            cap
+        } else {
+            self.line
+        }
+    }
+}
+
+/// How a line is drawn.
+pub struct Stroke<'a> {
+    pub width: f64,
+    pub color: &'a Color,
+    pub opacity: f64,
+    pub dashes: &'a Option<Vec<f64>>,
+    /// How far into the pattern of the dashes the line starts.
+    pub dashes_offset: f64,
+    pub line_caps: LineCaps<'a>,
+    /// Whether the dashes get the caps of the line as well, or are cut straight across.
+    pub use_caps_for_dashes: bool,
+}
+
+impl<'a> Stroke<'a> {
+    /// An opaque solid line without caps.
+    pub fn plain(width: f64, color: &'a Color) -> Stroke<'a> {
+        Stroke {
+            width,
+            color,
+            opacity: 1.0,
+            dashes: &None,
+            dashes_offset: 0.0,
+            line_caps: LineCaps::uniform(&None),
+            use_caps_for_dashes: false,
+        }
+    }
+}
+
+/// The segments of the tile that were already drawn with a particular stroke, in both directions.
+pub type DrawnSegments = HashSet<(Point, Point)>;
+
+/// With `drawn_segments`, the segments that are already there aren't drawn again (and the new ones are
+/// added), so that a line shared by several objects doesn't get darker or show two sets of dashes.
 pub fn draw_lines(
     points: PointPairIter<'_>,
-    width: f64,
-    color: &Color,
-    opacity: f64,
-    dashes: &Option<Vec<f64>>,
-    line_cap: &Option<LineCap>,
-    use_caps_for_dashes: bool,
+    stroke: &Stroke<'_>,
+    mut drawn_segments: Option<&mut DrawnSegments>,
     pixels: &mut TilePixels,
 ) {
-    let half_width = width / 2.0;
-    let line_cap_for_dashes = if use_caps_for_dashes { line_cap } else { &None };
+    let (color, opacity, dashes, line_caps) = (stroke.color, stroke.opacity, stroke.dashes, &stroke.line_caps);
+    let half_width = stroke.width / 2.0;
+    let line_cap_for_dashes = if stroke.use_caps_for_dashes {
+        line_caps.line
+    } else {
+        &None
+    };
     let mut opacity_calculator = OpacityCalculator::new(half_width, dashes, line_cap_for_dashes);
-    let opacity_calculator_for_outer_caps = OpacityCalculator::new(half_width, &Some(vec![0.0]), line_cap);
+    let dash_pattern_len = dashes.as_ref().map_or(0.0, |dashes| dashes.iter().sum::<f64>());
+    if dash_pattern_len > 0.0 {
+        opacity_calculator.add_traveled_distance(stroke.dashes_offset.rem_euclid(dash_pattern_len));
+    }
 
-    let has_caps = is_non_trivial_cap(line_cap);
+    let start_cap = OuterCap::new(line_caps.own_or_line_cap(line_caps.start), half_width, pixels.scale());
+    let end_cap = OuterCap::new(line_caps.own_or_line_cap(line_caps.end), half_width, pixels.scale());
+    let max_cap_length = [&start_cap, &end_cap]
+        .iter()
+        .filter_map(|cap| cap.as_ref().map(|cap| cap.length))
+        .fold(half_width, f64::max);
+    let visible_bb = get_visible_bb(pixels.bb(), max_cap_length);
 
     let mut peekable_points = points.peekable();
     let mut first = true;
 
     while let Some((p1, p2)) = peekable_points.next() {
-        draw_line(&p1, &p2, color, opacity, &opacity_calculator, pixels);
+        // The segments that can't leave any pixels on the tile are skipped, but their length still counts
+        // towards the dash phase: it's always measured from the real start of the way, so the dashes line up
+        // with the ones drawn on the neighboring tiles. The same goes for the segments that were drawn before.
+        let is_visible = is_segment_visible(&p1, &p2, &visible_bb)
+            && drawn_segments
+                .as_mut()
+                .is_none_or(|drawn| drawn.insert(ordered_segment(&p1, &p2)));
+        if is_visible {
+            draw_line(&p1, &p2, color, opacity, &opacity_calculator, pixels);
+        }
         opacity_calculator.add_traveled_distance(p1.dist(&p2));
 
-        if p1 != p2 && has_caps {
-            if first {
-                let cap_end = p1.push_away_from(&p2, half_width);
-                draw_line(
-                    &p1,
-                    &cap_end,
-                    color,
-                    opacity,
-                    &opacity_calculator_for_outer_caps,
-                    pixels,
-                );
+        if p1 != p2 && is_visible {
+            if let (true, Some(cap)) = (first, &start_cap) {
+                let cap_end = p1.push_away_from(&p2, cap.length);
+                draw_line(&p1, &cap_end, color, opacity, &cap.opacity_calculator, pixels);
             }
 
-            if peekable_points.peek().is_none() {
-                let cap_end = p2.push_away_from(&p1, half_width);
-                draw_line(
-                    &p2,
-                    &cap_end,
-                    color,
-                    opacity,
-                    &opacity_calculator_for_outer_caps,
-                    pixels,
-                );
+            if let (None, Some(cap)) = (peekable_points.peek(), &end_cap) {
+                let cap_end = p2.push_away_from(&p1, cap.length);
+                draw_line(&p2, &cap_end, color, opacity, &cap.opacity_calculator, pixels);
             }
         }
 
@@ -60,6 +126,68 @@ pub fn draw_lines(
     }
 }
 
+fn ordered_segment(p1: &Point, p2: &Point) -> (Point, Point) {
+    if (p1.x, p1.y) <= (p2.x, p2.y) {
+        (p1.clone(), p2.clone())
+    } else {
+        (p2.clone(), p1.clone())
+    }
+}
+
+// A cap at one of the ends of the whole line, drawn as a short line that starts at the end and goes on
+// in the same direction.
+struct OuterCap {
+    length: f64,
+    opacity_calculator: OpacityCalculator,
+}
+
+impl OuterCap {
+    fn new(line_cap: &Option<LineCap>, half_width: f64, scale: f64) -> Option<OuterCap> {
+        // A zero-length dash right at the end of the line, so that only its cap is drawn.
+        let cap_dash = Some(vec![0.0]);
+        match *line_cap {
+            Some(LineCap::Arrow) => {
+                // An arrowhead is a long triangular cap of a line that is wider than the real one.
+                let arrow_half_width = (ARROW_WIDTH * half_width).max(MIN_ARROW_HALF_WIDTH * scale);
+                let arrow_length = ARROW_LENGTH * arrow_half_width;
+                Some(OuterCap {
+                    length: arrow_length,
+                    opacity_calculator: OpacityCalculator::new_arrowhead(arrow_half_width, arrow_length),
+                })
+            }
+            _ if is_non_trivial_cap(line_cap) => Some(OuterCap {
+                length: half_width,
+                opacity_calculator: OpacityCalculator::new(half_width, &cap_dash, line_cap),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The half width of an arrowhead relative to the half width of its line, and its length relative to its own
+// half width.
+const ARROW_WIDTH: f64 = 2.5;
+const ARROW_LENGTH: f64 = 2.0;
+const MIN_ARROW_HALF_WIDTH: f64 = 3.0;
+
+// The tile extended by the parts of the line (the caps included) that stick out of its center.
+fn get_visible_bb(tile_bb: &BoundingBox, max_distance_from_center: f64) -> BoundingBox {
+    let margin = max_distance_from_center.ceil() as i32 + 1;
+    BoundingBox {
+        min_x: tile_bb.min_x - margin,
+        max_x: tile_bb.max_x + margin,
+        min_y: tile_bb.min_y - margin,
+        max_y: tile_bb.max_y + margin,
+    }
+}
+
+fn is_segment_visible(p1: &Point, p2: &Point, visible_bb: &BoundingBox) -> bool {
+    p1.x.max(p2.x) >= visible_bb.min_x
+        && p1.x.min(p2.x) <= visible_bb.max_x
+        && p1.y.max(p2.y) >= visible_bb.min_y
+        && p1.y.min(p2.y) <= visible_bb.max_y
+}
+
 // Full-blown Bresenham with anti-aliasing and thick line support.
 // Mostly inspired by http://kt8216.unixcab.org/murphy/index.html
 fn draw_line(
@@ -164,3 +292,133 @@ fn swap_x_y_if_needed<T>(a: T, b: T, should_swap: bool) -> (T, T) {
         (a, b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::TILE_SIZE;
+
+    fn draw_way(
+        points: &[(i32, i32)],
+        width: f64,
+        dashes: &Option<Vec<f64>>,
+        line_caps: &LineCaps<'_>,
+    ) -> Vec<(u8, u8, u8, u8)> {
+        let points = points.iter().map(|&(x, y)| Point { x, y }).collect::<Vec<_>>();
+        let pairs = points
+            .windows(2)
+            .map(|w| (w[0].clone(), w[1].clone()))
+            .collect::<Vec<_>>();
+        let mut pixels = TilePixels::new(1);
+        pixels.reset_transparent();
+        let stroke = Stroke {
+            dashes,
+            line_caps: *line_caps,
+            use_caps_for_dashes: true,
+            ..Stroke::plain(width, &Color { r: 0, g: 0, b: 0 })
+        };
+        draw_lines(Box::new(pairs.into_iter()), &stroke, None, &mut pixels);
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgba_quadruples()
+    }
+
+    #[test]
+    fn test_dash_phase_across_tiles() {
+        // The same way as seen from two tiles 200 pixels apart. The first segment is skipped on the second
+        // tile, and the dashes have to stay where they are anyway.
+        let shift = 200;
+        let way = [(-300, 37), (100, 100), (1000, 130)];
+        let shifted_way = way.iter().map(|&(x, y)| (x - shift, y)).collect::<Vec<_>>();
+
+        let dashes = Some(vec![7.0, 5.0]);
+        let line_caps = LineCaps::uniform(&Some(LineCap::Round));
+        let first = draw_way(&way, 3.0, &dashes, &line_caps);
+        let second = draw_way(&shifted_way, 3.0, &dashes, &line_caps);
+
+        let size = TILE_SIZE as i32;
+        for y in 0..size {
+            for x in shift..size {
+                let idx = |x| (y * size + x) as usize;
+                assert_eq!(first[idx(x)], second[idx(x - shift)], "at ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_drawn_segments() {
+        let draw = |ways: &[&[(i32, i32)]], dashes_offset| {
+            let mut pixels = TilePixels::new(1);
+            pixels.reset_transparent();
+            let mut drawn_segments = DrawnSegments::new();
+            for way in ways {
+                let points = way.iter().map(|&(x, y)| Point { x, y }).collect::<Vec<_>>();
+                let pairs = points
+                    .windows(2)
+                    .map(|w| (w[0].clone(), w[1].clone()))
+                    .collect::<Vec<_>>();
+                let stroke = Stroke {
+                    opacity: 0.5,
+                    dashes: &Some(vec![10.0, 10.0]),
+                    dashes_offset,
+                    ..Stroke::plain(2.0, &Color { r: 0, g: 0, b: 0 })
+                };
+                draw_lines(
+                    Box::new(pairs.into_iter()),
+                    &stroke,
+                    Some(&mut drawn_segments),
+                    &mut pixels,
+                );
+                pixels.bump_generation();
+            }
+            pixels.blend_unfinished_pixels(false);
+            pixels.to_rgba_quadruples()
+        };
+        let alpha = |drawn: &[(u8, u8, u8, u8)], x: i32, y: i32| drawn[(y * TILE_SIZE as i32 + x) as usize].3;
+
+        // The shared segment (drawn in the opposite direction the second time) isn't drawn again, and the dashes
+        // of the rest of the second line stay in place.
+        let shared = draw(&[&[(20, 100), (120, 100)], &[(120, 50), (120, 100), (20, 100)]], 0.0);
+        let single = draw(&[&[(20, 100), (120, 100)]], 0.0);
+        assert_eq!(alpha(&shared, 25, 100), alpha(&single, 25, 100));
+        assert!(alpha(&shared, 120, 55) > 0);
+        assert_eq!(alpha(&shared, 120, 65), 0);
+
+        // The offset moves the dashes into the gaps.
+        let shifted = draw(&[&[(20, 100), (120, 100)]], 30.0);
+        assert!(alpha(&single, 25, 100) > 0);
+        assert_eq!(alpha(&shifted, 25, 100), 0);
+        assert!(alpha(&shifted, 35, 100) > 0);
+    }
+
+    #[test]
+    fn test_arrow_cap() {
+        let line_caps = LineCaps {
+            line: &Some(LineCap::Butt),
+            start: &None,
+            end: &Some(LineCap::Arrow),
+        };
+        let drawn = draw_way(&[(50, 100), (150, 100)], 2.0, &None, &line_caps);
+        let alpha = |x: i32, y: i32| drawn[(y * TILE_SIZE as i32 + x) as usize].3;
+
+        // The arrowhead is wider than the line and sticks out of its end, while the start is cut off.
+        assert_eq!(alpha(100, 102), 0);
+        assert!(alpha(150, 102) > 0);
+        assert!(alpha(155, 100) > 0);
+        assert_eq!(alpha(155, 102), 0);
+        assert_eq!(alpha(158, 100), 0);
+        assert_eq!(alpha(48, 100), 0);
+    }
+
+    #[test]
+    fn test_triangle_dash_caps() {
+        let line_caps = LineCaps::uniform(&Some(LineCap::Triangle));
+        let drawn = draw_way(&[(50, 100), (150, 100)], 8.0, &Some(vec![20.0, 20.0]), &line_caps);
+        let alpha = |x: i32, y: i32| drawn[(y * TILE_SIZE as i32 + x) as usize].3;
+
+        // The first dash ends at x = 70, and its tip at x = 74.
+        assert_eq!(alpha(69, 103), 255);
+        assert!(alpha(72, 100) > 0);
+        assert_eq!(alpha(72, 103), 0);
+        assert_eq!(alpha(76, 100), 0);
+    }
+}
@@ -1,15 +1,30 @@
 const TILE_SIZE: usize = crate::tile::TILE_SIZE as usize;
 
+mod arc;
+pub mod cancellation;
 pub mod drawer;
 pub mod fill;
+pub mod filters;
 pub mod font;
 pub mod icon;
 pub mod icon_cache;
+mod icon_rotation;
 pub mod labelable;
 pub mod labeler;
 pub mod line;
 pub mod opacity_calculator;
 pub mod png_writer;
+mod poi_cluster;
 pub mod point;
 pub mod point_pairs;
+mod qa;
+pub mod raw_writer;
+pub mod render_cost;
+pub mod render_order;
+pub mod sprite;
+pub mod svg_writer;
+mod symbol;
+pub mod ticks;
 pub mod tile_pixels;
+pub mod view;
+pub mod webp_writer;
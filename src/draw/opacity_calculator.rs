@@ -2,6 +2,8 @@ use crate::mapcss::styler::{is_non_trivial_cap, LineCap};
 
 pub struct OpacityCalculator {
     half_line_width: f64,
+    // The distance from the end of a triangular cap to its tip.
+    pointy_cap_length: Option<f64>,
     dashes: Vec<DashSegment>,
     total_dash_len: f64,
     traveled_distance: f64,
@@ -14,15 +16,33 @@ pub struct OpacityData {
 
 impl OpacityCalculator {
     pub fn new(half_line_width: f64, dashes: &Option<Vec<f64>>, line_cap: &Option<LineCap>) -> Self {
+        Self::with_cap_length(half_line_width, half_line_width, dashes, line_cap)
+    }
+
+    // A zero-length dash with a long triangular cap, which is how the arrowheads are drawn.
+    pub fn new_arrowhead(half_width: f64, length: f64) -> Self {
+        Self::with_cap_length(half_width, length, &Some(vec![0.0]), &Some(LineCap::Triangle))
+    }
+
+    fn with_cap_length(
+        half_line_width: f64,
+        cap_length: f64,
+        dashes: &Option<Vec<f64>>,
+        line_cap: &Option<LineCap>,
+    ) -> Self {
         let mut dash_segments = Vec::new();
         let mut len_before = 0.0;
 
         if let Some(ref dashes) = *dashes {
-            compute_segments(half_line_width, dashes, line_cap, &mut dash_segments, &mut len_before);
+            compute_segments(cap_length, dashes, line_cap, &mut dash_segments, &mut len_before);
         }
 
         Self {
             half_line_width,
+            pointy_cap_length: match *line_cap {
+                Some(LineCap::Triangle) => Some(cap_length),
+                _ => None,
+            },
             dashes: dash_segments,
             total_dash_len: len_before,
             traveled_distance: 0.0,
@@ -33,7 +53,10 @@ impl OpacityCalculator {
         let sd = self.get_opacity_by_start_distance(start_distance);
 
         let cap_dist = sd.distance_in_cap.unwrap_or_default();
-        let half_line_width = (self.half_line_width.powi(2) - cap_dist.powi(2)).sqrt();
+        let half_line_width = match self.pointy_cap_length {
+            Some(cap_length) => (self.half_line_width * (1.0 - cap_dist / cap_length)).max(0.0),
+            None => (self.half_line_width.powi(2) - cap_dist.powi(2)).sqrt(),
+        };
 
         let cd = get_opacity_by_center_distance(center_distance, half_line_width);
         OpacityData {
@@ -96,7 +119,7 @@ struct DashSegment {
 }
 
 fn compute_segments(
-    half_line_width: f64,
+    cap_length: f64,
     dashes: &[f64],
     line_cap: &Option<LineCap>,
     segments: &mut Vec<DashSegment>,
@@ -120,13 +143,13 @@ fn compute_segments(
         let mut end = start + dash;
 
         let original_endpoints = match *line_cap {
-            Some(LineCap::Round) => Some((start, end)),
+            Some(LineCap::Round) | Some(LineCap::Triangle) => Some((start, end)),
             _ => None,
         };
 
         if is_non_trivial_cap(line_cap) {
-            start -= half_line_width;
-            end += half_line_width;
+            start -= cap_length;
+            end += cap_length;
         }
 
         let midpoint = (start + end) / 2.0;
@@ -1,20 +1,60 @@
 use anyhow::{Context, Result};
-use png::{ColorType, Encoder};
+use png::{BitDepth, ColorType, Encoder};
 
 pub fn rgb_triples_to_png(triples: &[(u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b) in triples {
+        image_bytes.extend([r, g, b].iter());
+    }
+    write_png(&image_bytes, width, height, ColorType::Rgb)
+}
+
+pub fn rgba_quadruples_to_png(quadruples: &[(u8, u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b, a) in quadruples {
+        image_bytes.extend([r, g, b, a].iter());
+    }
+    write_png(&image_bytes, width, height, ColorType::Rgba)
+}
+
+/// Writes 16 bits per channel, with the transparency kept only if `with_alpha` is set.
+pub fn deep_rgba_quadruples_to_png(
+    quadruples: &[(u16, u16, u16, u16)],
+    width: usize,
+    height: usize,
+    with_alpha: bool,
+) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b, a) in quadruples {
+        let components = if with_alpha { &[r, g, b, a][..] } else { &[r, g, b][..] };
+        for c in components {
+            image_bytes.extend(c.to_be_bytes());
+        }
+    }
+    let color_type = if with_alpha { ColorType::Rgba } else { ColorType::Rgb };
+    write_png_with_depth(&image_bytes, width, height, color_type, BitDepth::Sixteen)
+}
+
+fn write_png(image_bytes: &[u8], width: usize, height: usize, color_type: ColorType) -> Result<Vec<u8>> {
+    write_png_with_depth(image_bytes, width, height, color_type, BitDepth::Eight)
+}
+
+fn write_png_with_depth(
+    image_bytes: &[u8],
+    width: usize,
+    height: usize,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
     {
         let mut png_encoder = Encoder::new(&mut buf, width as u32, height as u32);
-        png_encoder.set_color(ColorType::Rgb);
+        png_encoder.set_color(color_type);
+        png_encoder.set_depth(bit_depth);
         let mut png_writer = png_encoder.write_header().context("Failed to write PNG header")?;
 
-        let mut image_bytes = Vec::new();
-        for &(r, g, b) in triples {
-            image_bytes.extend([r, g, b].iter());
-        }
-
         png_writer
-            .write_image_data(image_bytes.as_slice())
+            .write_image_data(image_bytes)
             .context("Failed to write PNG data")?;
     }
     Ok(buf)
@@ -0,0 +1,76 @@
+use crate::draw::point::Point;
+use crate::geodata::reader::Node;
+use crate::mapcss::styler::Style;
+use crate::tile::Tile;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A POI that is drawn on behalf of `count` POIs with the same icon (including itself).
+pub(super) struct PoiCluster {
+    pub(super) idx: usize,
+    pub(super) count: usize,
+}
+
+/// Groups the POIs that have the same icon and fall into the same cell of a grid laid over the tile.
+/// The first POI of each group (i.e. the one that would've been drawn first anyway) represents the group.
+/// POIs without icons are never grouped.
+pub(super) fn cluster_pois(nodes: &[(&Node<'_>, Arc<Style>)], tile: &Tile, scale: f64) -> Vec<PoiCluster> {
+    let keys = nodes
+        .iter()
+        .map(|(node, style)| {
+            style.icon_image.as_deref().map(|icon| {
+                let p = Point::from_node(node, tile, scale);
+                (icon, p.x, p.y)
+            })
+        })
+        .collect::<Vec<_>>();
+    group_by_cell(&keys, CLUSTER_CELL_SIZE * scale)
+}
+
+fn group_by_cell(keys: &[Option<(&str, i32, i32)>], cell_size: f64) -> Vec<PoiCluster> {
+    let cell_size = cell_size.round().max(1.0) as i32;
+    let mut clusters = Vec::<PoiCluster>::new();
+    let mut cell_to_cluster = HashMap::<_, usize>::new();
+
+    for (idx, key) in keys.iter().enumerate() {
+        let cell = key.map(|(icon, x, y)| (icon, x.div_euclid(cell_size), y.div_euclid(cell_size)));
+        match cell.and_then(|cell| cell_to_cluster.get(&cell)) {
+            Some(&cluster_idx) => clusters[cluster_idx].count += 1,
+            None => {
+                if let Some(cell) = cell {
+                    cell_to_cluster.insert(cell, clusters.len());
+                }
+                clusters.push(PoiCluster { idx, count: 1 });
+            }
+        }
+    }
+
+    clusters
+}
+
+// Divides the tile size, so that the grid cells are the same for all tiles of the same zoom level
+// and a cluster near the tile border looks the same on both sides.
+const CLUSTER_CELL_SIZE: f64 = 32.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_by_cell() {
+        let keys = [
+            Some(("cafe", 1, 1)),
+            Some(("cafe", 30, 30)),
+            Some(("bank", 2, 2)),
+            None,
+            Some(("cafe", 33, 1)),
+            None,
+            Some(("cafe", -1, 1)),
+        ];
+        let clusters = group_by_cell(&keys, 32.0)
+            .iter()
+            .map(|c| (c.idx, c.count))
+            .collect::<Vec<_>>();
+        assert_eq!(clusters, vec![(0, 2), (2, 1), (3, 1), (4, 1), (5, 1), (6, 1)]);
+    }
+}
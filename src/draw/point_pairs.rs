@@ -1,5 +1,5 @@
 use crate::draw::point::Point;
-use crate::geodata::reader::{Multipolygon, Polygon, Way};
+use crate::geodata::reader::{Multipolygon, OsmArea, Polygon, Way};
 use crate::tile::Tile;
 
 pub type PointPairIter<'a> = Box<dyn Iterator<Item = (Point, Point)> + 'a>;
@@ -27,6 +27,24 @@ impl<'w> PointPairCollection<'w> for Way<'w> {
     }
 }
 
+impl<'w> Way<'w> {
+    /// The same as `to_point_pairs`, with one more pair from the last node back to the first one if the way is
+    /// unclosed, so that it can be filled.
+    pub fn to_ring_point_pairs(&'w self, tile: &'w Tile, scale: f64) -> PointPairIter<'w> {
+        let pairs = self.to_point_pairs(tile, scale);
+        if self.node_count() <= 2 || self.is_closed() {
+            return pairs;
+        }
+        let first_node = self.get_node(0);
+        let last_node = self.get_node(self.node_count() - 1);
+        let closing_pair = (
+            Point::from_node(&last_node, tile, scale),
+            Point::from_node(&first_node, tile, scale),
+        );
+        Box::new(pairs.chain(std::iter::once(closing_pair)))
+    }
+}
+
 impl<'p> Polygon<'p> {
     fn into_point_pairs(self, tile: &'p Tile, scale: f64) -> PointPairIter<'p> {
         implement_to_point_pairs!(self, tile, scale)
@@ -39,3 +57,52 @@ impl<'r> PointPairCollection<'r> for Multipolygon<'r> {
         Box::new((0..polygon_count).flat_map(move |idx| self.get_polygon(idx).into_point_pairs(tile, scale)))
     }
 }
+
+/// Drops the points that are closer than `tolerance` pixels to the previous kept point. The first and the last
+/// points of every chain of connected pairs (e.g. of every ring of a multipolygon) are always kept, so the rings
+/// stay closed.
+pub fn simplify(points: PointPairIter<'_>, tolerance: f64) -> PointPairIter<'_> {
+    let mut points = points.peekable();
+    let mut chain_start: Option<Point> = None;
+    Box::new(std::iter::from_fn(move || loop {
+        let (p1, p2) = points.next()?;
+        let from = chain_start.take().unwrap_or(p1);
+        let is_chain_end = points.peek().is_none_or(|(next_p1, _)| *next_p1 != p2);
+        if is_chain_end || from.dist(&p2) >= tolerance {
+            if !is_chain_end {
+                chain_start = Some(p2.clone());
+            }
+            return Some((from, p2));
+        }
+        chain_start = Some(from);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_pairs(chains: &[&[(i32, i32)]]) -> Vec<(Point, Point)> {
+        chains
+            .iter()
+            .flat_map(|chain| {
+                chain
+                    .windows(2)
+                    .map(|w| (Point { x: w[0].0, y: w[0].1 }, Point { x: w[1].0, y: w[1].1 }))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_simplify() {
+        let line: &[(i32, i32)] = &[(0, 0), (1, 0), (2, 1), (5, 0), (6, 0), (6, 1)];
+        let ring: &[(i32, i32)] = &[(10, 10), (20, 10), (20, 11), (10, 20), (10, 10)];
+        let simplified = simplify(Box::new(to_pairs(&[line, ring]).into_iter()), 3.0).collect::<Vec<_>>();
+        let line: &[(i32, i32)] = &[(0, 0), (5, 0), (6, 1)];
+        let ring: &[(i32, i32)] = &[(10, 10), (20, 10), (10, 20), (10, 10)];
+        assert_eq!(simplified, to_pairs(&[line, ring]));
+
+        let unchanged = simplify(Box::new(to_pairs(&[ring]).into_iter()), 1.0).collect::<Vec<_>>();
+        assert_eq!(unchanged, to_pairs(&[ring]));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::draw::line::{draw_lines, Stroke};
+use crate::draw::point::Point;
+use crate::draw::point_pairs::{PointPairCollection, PointPairIter};
+use crate::draw::tile_pixels::TilePixels;
+use crate::geodata::reader::{OsmArea, OsmEntity, Way};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{Style, StyledArea};
+use crate::tile::{coords_to_xy_tile_relative, Tile};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+type XY = (f64, f64);
+
+/// Draws the QA overlay on top of an already rendered tile:
+///
+/// * unclosed ways that the stylesheet wants to fill are outlined, since the fill closes them with a straight line;
+/// * duplicate consecutive nodes of a way are marked;
+/// * the points where a way intersects itself are marked.
+pub(super) fn draw_qa_overlay(
+    pixels: &mut TilePixels,
+    ways: &[Way<'_>],
+    styled_areas: &[(StyledArea<'_, '_>, Arc<Style>)],
+    tile: &Tile,
+    scale: f64,
+) {
+    let mut outlined_ways = HashSet::new();
+    for (area, style) in styled_areas {
+        if let StyledArea::Way(way) = area {
+            let is_filled = style.fill_color.is_some() || style.fill_image.is_some();
+            if is_filled && !way.is_closed() && outlined_ways.insert(way.global_id()) {
+                draw_lines(
+                    way.to_point_pairs(tile, scale),
+                    &Stroke::plain(UNCLOSED_AREA_WIDTH * scale, &UNCLOSED_AREA_COLOR),
+                    None,
+                    pixels,
+                );
+                pixels.bump_generation();
+            }
+        }
+    }
+
+    for way in ways {
+        let points = (0..way.node_count())
+            .map(|idx| coords_to_xy_tile_relative(&way.get_node(idx), tile))
+            .collect::<Vec<_>>();
+        for p in find_duplicate_points(&points) {
+            draw_marker(pixels, p, scale, &DUPLICATE_NODE_COLOR);
+        }
+        for p in find_self_intersections(&points) {
+            draw_marker(pixels, p, scale, &SELF_INTERSECTION_COLOR);
+        }
+    }
+}
+
+// Returns the points that are the same as the previous one.
+fn find_duplicate_points(points: &[XY]) -> Vec<XY> {
+    points.windows(2).filter(|w| w[0] == w[1]).map(|w| w[1]).collect()
+}
+
+// Returns the points where two non-adjacent segments of the polyline cross each other. Segments that merely
+// touch (e.g. when a way returns to one of its nodes) or overlap are not considered to be intersecting.
+fn find_self_intersections(points: &[XY]) -> Vec<XY> {
+    let segments = points
+        .windows(2)
+        .filter(|w| w[0] != w[1])
+        .map(|w| (w[0], w[1]))
+        .collect::<Vec<_>>();
+    let is_closed = points.len() > 2 && points.first() == points.last();
+
+    let mut intersections = Vec::new();
+    for i in 0..segments.len() {
+        for j in i + 2..segments.len() {
+            if is_closed && i == 0 && j == segments.len() - 1 {
+                continue;
+            }
+            if let Some(p) = intersect_segments(segments[i], segments[j]) {
+                intersections.push(p);
+            }
+        }
+    }
+    intersections
+}
+
+fn intersect_segments((a, b): (XY, XY), (c, d): (XY, XY)) -> Option<XY> {
+    let cross = |o: XY, p: XY, q: XY| (p.0 - o.0) * (q.1 - o.1) - (p.1 - o.1) * (q.0 - o.0);
+
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    if d1 * d2 >= 0.0 || d3 * d4 >= 0.0 {
+        return None;
+    }
+
+    let t = d1 / (d1 - d2);
+    Some((a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)))
+}
+
+// A small square frame around the point.
+fn draw_marker(pixels: &mut TilePixels, (x, y): XY, scale: f64, color: &Color) {
+    let (x, y) = ((x * scale).round() as i32, (y * scale).round() as i32);
+    let half_size = (MARKER_HALF_SIZE * scale).round() as i32;
+    let corners = [
+        Point {
+            x: x - half_size,
+            y: y - half_size,
+        },
+        Point {
+            x: x + half_size,
+            y: y - half_size,
+        },
+        Point {
+            x: x + half_size,
+            y: y + half_size,
+        },
+        Point {
+            x: x - half_size,
+            y: y + half_size,
+        },
+    ];
+    let sides: PointPairIter<'_> =
+        Box::new((0..corners.len()).map(|i| (corners[i].clone(), corners[(i + 1) % 4].clone())));
+    draw_lines(sides, &Stroke::plain(MARKER_WIDTH * scale, color), None, pixels);
+    pixels.bump_generation();
+}
+
+const UNCLOSED_AREA_WIDTH: f64 = 3.0;
+const UNCLOSED_AREA_COLOR: Color = Color { r: 255, g: 0, b: 255 };
+const MARKER_HALF_SIZE: f64 = 5.0;
+const MARKER_WIDTH: f64 = 2.0;
+const DUPLICATE_NODE_COLOR: Color = Color { r: 255, g: 140, b: 0 };
+const SELF_INTERSECTION_COLOR: Color = Color { r: 255, g: 0, b: 0 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_points() {
+        let points = [(0.0, 0.0), (1.0, 1.0), (1.0, 1.0), (2.0, 0.0)];
+        assert_eq!(find_duplicate_points(&points), vec![(1.0, 1.0)]);
+    }
+
+    #[test]
+    fn test_self_intersections() {
+        // A bow tie: the second and the fourth segments cross at (1, 1).
+        let bow_tie = [(0.0, 0.0), (2.0, 0.0), (0.0, 2.0), (2.0, 2.0), (0.0, 0.0)];
+        assert_eq!(find_self_intersections(&bow_tie), vec![(1.0, 1.0)]);
+
+        let square = [(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (0.0, 2.0), (0.0, 0.0)];
+        assert!(find_self_intersections(&square).is_empty());
+
+        // Touching itself at a node is fine.
+        let figure_eight = [(0.0, 0.0), (1.0, 1.0), (2.0, 0.0), (2.0, 2.0), (1.0, 1.0), (0.0, 2.0)];
+        assert!(find_self_intersections(&figure_eight).is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+use crate::draw::tile_pixels::{RgbTriples, RgbaQuadruples};
+
+// The uncompressed formats are meant for the consumers that do the encoding on their own (e.g. when
+// assembling video frames), so that they don't have to decode a PNG first.
+
+/// Just the bytes of the pixels, row by row: red, green, blue and alpha.
+pub fn rgba_quadruples_to_raw(quadruples: &RgbaQuadruples) -> Vec<u8> {
+    let mut image_bytes = Vec::with_capacity(4 * quadruples.len());
+    for &(r, g, b, a) in quadruples {
+        image_bytes.extend([r, g, b, a].iter());
+    }
+    image_bytes
+}
+
+/// A binary PPM (`P6`) image, which most image tools (e.g. ffmpeg) can read. It has no alpha channel.
+pub fn rgb_triples_to_ppm(triples: &RgbTriples, width: usize, height: usize) -> Vec<u8> {
+    let mut image_bytes = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    image_bytes.reserve(3 * triples.len());
+    for &(r, g, b) in triples {
+        image_bytes.extend([r, g, b].iter());
+    }
+    image_bytes
+}
+
+/// A PAM (`P7`) image with the alpha channel.
+pub fn rgba_quadruples_to_pam(quadruples: &RgbaQuadruples, width: usize, height: usize) -> Vec<u8> {
+    let mut image_bytes = format!(
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+        width, height
+    )
+    .into_bytes();
+    image_bytes.extend(rgba_quadruples_to_raw(quadruples));
+    image_bytes
+}
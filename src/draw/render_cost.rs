@@ -0,0 +1,96 @@
+use crate::tile::Tile;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Remembers which parts of the map take too long to draw, so that the next tiles there can be drawn
+/// with less detail. The tiles are grouped into square blocks of neighbors at the same zoom level, and
+/// every block has its own level of simplification: it goes up after every tile of the block that exceeds
+/// the time budget, and back down after every tile that takes less than half of it.
+pub struct RenderCosts {
+    budget: Duration,
+    levels: Mutex<HashMap<(u8, u32, u32), u8>>,
+}
+
+/// The blocks are `2^BLOCK_SHIFT` tiles wide.
+const BLOCK_SHIFT: u32 = 2;
+pub const MAX_SIMPLIFICATION_LEVEL: u8 = 4;
+
+impl RenderCosts {
+    pub fn new(budget: Duration) -> RenderCosts {
+        RenderCosts {
+            budget,
+            levels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Zero means full detail.
+    pub fn simplification_level(&self, tile: &Tile) -> u8 {
+        let levels = self.levels.lock().unwrap();
+        levels.get(&get_block(tile)).copied().unwrap_or_default()
+    }
+
+    pub fn record(&self, tile: &Tile, duration: Duration) {
+        let block = get_block(tile);
+        let mut levels = self.levels.lock().unwrap();
+        let level = levels.get(&block).copied().unwrap_or_default();
+        let new_level = if duration > self.budget {
+            (level + 1).min(MAX_SIMPLIFICATION_LEVEL)
+        } else if duration < self.budget / 2 {
+            level.saturating_sub(1)
+        } else {
+            level
+        };
+        // Only the slow blocks are stored, so the map doesn't grow with the number of tiles drawn.
+        if new_level == 0 {
+            levels.remove(&block);
+        } else {
+            levels.insert(block, new_level);
+        }
+    }
+}
+
+fn get_block(tile: &Tile) -> (u8, u32, u32) {
+    (tile.zoom, tile.x >> BLOCK_SHIFT, tile.y >> BLOCK_SHIFT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let costs = RenderCosts::new(Duration::from_millis(100));
+        let tile = |zoom, x, y| Tile { zoom, x, y };
+        let slow = Duration::from_millis(150);
+        let fast = Duration::from_millis(10);
+
+        costs.record(&tile(15, 100, 100), slow);
+        costs.record(&tile(15, 101, 102), slow);
+        // The neighbors in the same block get simplified, the others don't.
+        assert_eq!(costs.simplification_level(&tile(15, 103, 103)), 2);
+        assert_eq!(costs.simplification_level(&tile(15, 104, 100)), 0);
+        assert_eq!(costs.simplification_level(&tile(16, 100, 100)), 0);
+
+        for _ in 0..10 {
+            costs.record(&tile(15, 100, 100), slow);
+        }
+        assert_eq!(
+            costs.simplification_level(&tile(15, 100, 100)),
+            MAX_SIMPLIFICATION_LEVEL
+        );
+
+        // Neither slow nor fast.
+        costs.record(&tile(15, 100, 100), Duration::from_millis(70));
+        assert_eq!(
+            costs.simplification_level(&tile(15, 100, 100)),
+            MAX_SIMPLIFICATION_LEVEL
+        );
+
+        for _ in 0..MAX_SIMPLIFICATION_LEVEL {
+            costs.record(&tile(15, 100, 100), fast);
+        }
+        assert_eq!(costs.simplification_level(&tile(15, 100, 100)), 0);
+        assert!(costs.levels.lock().unwrap().is_empty());
+    }
+}
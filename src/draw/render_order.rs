@@ -0,0 +1,69 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// An explicit order of named drawing passes (e.g. `landuse`, `water`, `roads-casing`, `roads-fill`).
+/// The stylesheet assigns its rules to the passes with `render-pass` (and `casing-render-pass` for the
+/// casings), and every pass is drawn completely before the next one, whatever the z-indices are.
+///
+/// The file lists one pass per line, from the bottom to the top. Empty lines and the lines
+/// starting with `#` are skipped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenderOrder {
+    passes: Vec<String>,
+}
+
+impl RenderOrder {
+    pub fn load(path: &Path) -> Result<RenderOrder> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        RenderOrder::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<RenderOrder> {
+        let mut passes = Vec::<String>::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.contains(char::is_whitespace) {
+                bail!("Invalid pass name: {}", line);
+            }
+            if passes.iter().any(|pass| pass == line) {
+                bail!("Pass {} is listed more than once", line);
+            }
+            passes.push(line.to_string());
+        }
+        if passes.is_empty() {
+            bail!("No passes are listed");
+        }
+        Ok(RenderOrder { passes })
+    }
+
+    pub fn pass_count(&self) -> usize {
+        self.passes.len()
+    }
+
+    /// The styles that aren't assigned to any of the listed passes are drawn in the first one.
+    pub fn get_pass_index(&self, pass: Option<&String>) -> usize {
+        pass.and_then(|pass| self.passes.iter().position(|p| p == pass))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let order = RenderOrder::parse("# Bottom first\nlanduse\n\n  water \nroads-casing\nroads-fill\n").unwrap();
+        assert_eq!(order.pass_count(), 4);
+        assert_eq!(order.get_pass_index(Some(&"water".to_string())), 1);
+        assert_eq!(order.get_pass_index(Some(&"roads-fill".to_string())), 3);
+        assert_eq!(order.get_pass_index(Some(&"unknown".to_string())), 0);
+        assert_eq!(order.get_pass_index(None), 0);
+
+        assert!(RenderOrder::parse("water\nwater").is_err());
+        assert!(RenderOrder::parse("roads fill").is_err());
+        assert!(RenderOrder::parse("# Nothing\n").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::draw::icon::Icon;
+use crate::draw::png_writer::rgba_quadruples_to_png;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Cursor;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+/// The position of an icon in the sprite atlas.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpriteEntry {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// All icons of a style packed into a single image, with a JSON index in the format that the web map
+/// libraries understand (`{"symbols/cafe.png": {"x": 0, "y": 0, "width": 16, "height": 16, "pixelRatio": 1}, ...}`).
+/// The icons are named by their paths relative to the packed directory, so packing the directory
+/// of the stylesheet gives the same names that the stylesheet uses in `icon-image`.
+pub struct Sprite {
+    atlas: Icon,
+    png: Vec<u8>,
+    index: BTreeMap<String, SpriteEntry>,
+}
+
+impl Sprite {
+    /// Packs all PNG icons found in the directory (and its subdirectories).
+    pub fn pack(icon_dir: &Path) -> Result<Sprite> {
+        let mut icons = Vec::new();
+        collect_icons(icon_dir, icon_dir, &mut icons)?;
+        icons.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let sizes = icons
+            .iter()
+            .map(|(_, icon)| (icon.width, icon.height))
+            .collect::<Vec<_>>();
+        let (positions, width, height) = pack_rectangles(&sizes);
+
+        let mut quadruples = vec![(0, 0, 0, 0); width * height];
+        let mut index = BTreeMap::new();
+        for ((name, icon), &(x, y)) in icons.iter().zip(&positions) {
+            for icon_y in 0..icon.height {
+                for icon_x in 0..icon.width {
+                    let p = icon.get(icon_x, icon_y);
+                    let postdivide = |val: f64| {
+                        let mul = if p.a == 0.0 { 0.0 } else { val / p.a };
+                        (f64::from(u8::MAX) * mul).round() as u8
+                    };
+                    let alpha = (f64::from(u8::MAX) * p.a).round() as u8;
+                    quadruples[(y + icon_y) * width + x + icon_x] =
+                        (postdivide(p.r), postdivide(p.g), postdivide(p.b), alpha);
+                }
+            }
+            index.insert(
+                name.clone(),
+                SpriteEntry {
+                    x,
+                    y,
+                    width: icon.width,
+                    height: icon.height,
+                },
+            );
+        }
+
+        let png = rgba_quadruples_to_png(&quadruples, width, height)?;
+        Sprite::from_parts(png, index)
+    }
+
+    /// Loads the `PREFIX.png` and `PREFIX.json` files written by `save()`.
+    pub fn load(prefix: &Path) -> Result<Sprite> {
+        let png_path = with_suffix(prefix, ".png");
+        let json_path = with_suffix(prefix, ".json");
+        let png = fs::read(&png_path).context(format!("Failed to read {}", png_path.display()))?;
+        let json = fs::read_to_string(&json_path).context(format!("Failed to read {}", json_path.display()))?;
+        Sprite::from_bytes(png, &json)
+    }
+
+    pub fn from_bytes(png: Vec<u8>, json: &str) -> Result<Sprite> {
+        let index = parse_index(json).context("Failed to parse the sprite index")?;
+        Sprite::from_parts(png, index)
+    }
+
+    fn from_parts(png: Vec<u8>, index: BTreeMap<String, SpriteEntry>) -> Result<Sprite> {
+        let atlas = Icon::decode(Cursor::new(&png)).context("Failed to decode the sprite image")?;
+        for (name, entry) in &index {
+            if entry.x + entry.width > atlas.width || entry.y + entry.height > atlas.height {
+                bail!("Icon {} doesn't fit into the sprite image", name);
+            }
+        }
+        Ok(Sprite { atlas, png, index })
+    }
+
+    pub fn save(&self, prefix: &Path) -> Result<()> {
+        fs::write(with_suffix(prefix, ".png"), &self.png).context("Failed to write the sprite image")?;
+        fs::write(with_suffix(prefix, ".json"), self.to_json()).context("Failed to write the sprite index")?;
+        Ok(())
+    }
+
+    pub fn png(&self) -> &[u8] {
+        &self.png
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries = self
+            .index
+            .iter()
+            .map(|(name, e)| {
+                format!(
+                    "  \"{}\": {{\"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}, \"pixelRatio\": 1}}",
+                    escape_json(name),
+                    e.x,
+                    e.y,
+                    e.width,
+                    e.height
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{\n{}\n}}\n", entries.join(",\n"))
+    }
+
+    pub fn get_icon(&self, name: &str) -> Option<Icon> {
+        self.index
+            .get(name)
+            .map(|e| self.atlas.crop(e.x, e.y, e.width, e.height))
+    }
+}
+
+fn collect_icons(base_dir: &Path, dir: &Path, icons: &mut Vec<(String, Icon)>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_icons(base_dir, &path, icons)?;
+        } else if path.extension().is_some_and(|ext| ext == "png") {
+            let icon = Icon::load(&path).context(format!("Failed to load icon from {}", path.display()))?;
+            let name = path
+                .strip_prefix(base_dir)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            icons.push((name, icon));
+        }
+    }
+    Ok(())
+}
+
+// Places the rectangles onto shelves (the tallest rectangles go first), trying to keep the result
+// roughly square. Returns the positions of the rectangles and the size of the atlas.
+fn pack_rectangles(sizes: &[(usize, usize)]) -> (Vec<(usize, usize)>, usize, usize) {
+    let padded_area = sizes.iter().map(|(w, h)| (w + PADDING) * (h + PADDING)).sum::<usize>();
+    let max_width = sizes.iter().map(|(w, _)| *w).max().unwrap_or(1);
+    let atlas_width = max_width.max((padded_area as f64).sqrt().ceil() as usize);
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&idx| std::cmp::Reverse(sizes[idx].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    for idx in order {
+        let (w, h) = sizes[idx];
+        if x > 0 && x + w > atlas_width {
+            x = 0;
+            y += shelf_height + PADDING;
+            shelf_height = 0;
+        }
+        positions[idx] = (x, y);
+        x += w + PADDING;
+        shelf_height = shelf_height.max(h);
+    }
+
+    (positions, atlas_width, (y + shelf_height).max(1))
+}
+
+fn with_suffix(prefix: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(prefix);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            ch if ch.is_control() => result.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+// Only the subset of JSON that describes a sprite is supported: an object with the icon names
+// as keys and objects with numeric properties as values.
+fn parse_index(json: &str) -> Result<BTreeMap<String, SpriteEntry>> {
+    let mut chars = json.chars().peekable();
+    let mut index = BTreeMap::new();
+    read_object(&mut chars, |chars, name| {
+        let mut props = BTreeMap::new();
+        read_object(chars, |chars, prop| {
+            props.insert(prop, read_number(chars)?);
+            Ok(())
+        })?;
+        let get = |prop: &str| match props.get(prop) {
+            Some(&value) if value >= 0.0 && value.fract() == 0.0 => Ok(value as usize),
+            _ => bail!("Icon {} has no valid {}", name, prop),
+        };
+        let entry = SpriteEntry {
+            x: get("x")?,
+            y: get("y")?,
+            width: get("width")?,
+            height: get("height")?,
+        };
+        index.insert(name, entry);
+        Ok(())
+    })?;
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        bail!("Unexpected data after the end of the index");
+    }
+    Ok(index)
+}
+
+fn read_object<F>(chars: &mut Peekable<Chars<'_>>, mut read_value: F) -> Result<()>
+where
+    F: FnMut(&mut Peekable<Chars<'_>>, String) -> Result<()>,
+{
+    expect_char(chars, '{')?;
+    skip_whitespace(chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return Ok(());
+    }
+    loop {
+        skip_whitespace(chars);
+        let key = read_string(chars)?;
+        expect_char(chars, ':')?;
+        skip_whitespace(chars);
+        read_value(chars, key)?;
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => {}
+            Some('}') => return Ok(()),
+            _ => bail!("Expected ',' or '}}'"),
+        }
+    }
+}
+
+fn read_string(chars: &mut Peekable<Chars<'_>>) -> Result<String> {
+    expect_char(chars, '"')?;
+    let mut result = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(result),
+            Some('\\') => match chars.next() {
+                Some('u') => {
+                    let code = chars.by_ref().take(4).collect::<String>();
+                    let ch = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32);
+                    result.push(ch.context("Invalid \\u escape")?);
+                }
+                Some(ch @ ('"' | '\\' | '/')) => result.push(ch),
+                _ => bail!("Unsupported escape sequence"),
+            },
+            Some(ch) => result.push(ch),
+            None => bail!("Unterminated string"),
+        }
+    }
+}
+
+fn read_number(chars: &mut Peekable<Chars<'_>>) -> Result<f64> {
+    let mut number = String::new();
+    while let Some(&ch) = chars.peek() {
+        if !(ch.is_ascii_digit() || "+-.eE".contains(ch)) {
+            break;
+        }
+        number.push(ch);
+        chars.next();
+    }
+    number.parse().context(format!("Invalid number: {:?}", number))
+}
+
+fn expect_char(chars: &mut Peekable<Chars<'_>>, expected: char) -> Result<()> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(ch) if ch == expected => Ok(()),
+        _ => bail!("Expected '{}'", expected),
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars<'_>>) {
+    while chars.peek().is_some_and(|ch| ch.is_whitespace()) {
+        chars.next();
+    }
+}
+
+// Keeps the icons from bleeding into each other when a client scales the atlas.
+const PADDING: usize = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_rectangles() {
+        let sizes = [(16, 16), (8, 24), (30, 4), (16, 16), (1, 1)];
+        let (positions, width, height) = pack_rectangles(&sizes);
+
+        let rects = sizes
+            .iter()
+            .zip(&positions)
+            .map(|(&(w, h), &(x, y))| (x, y, x + w, y + h))
+            .collect::<Vec<_>>();
+        for (i, a) in rects.iter().enumerate() {
+            assert!(a.2 <= width && a.3 <= height);
+            for b in &rects[i + 1..] {
+                let overlap = a.0 < b.2 + PADDING && b.0 < a.2 + PADDING && a.1 < b.3 + PADDING && b.1 < a.3 + PADDING;
+                assert!(!overlap, "{:?} and {:?} are too close", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let json = r#"{
+            "symbols/cafe.png": {"x": 0, "y": 2, "width": 16, "height": 14, "pixelRatio": 1},
+            "quote\"d A": {"height": 1, "width": 1, "y": 0, "x": 17}
+        }"#;
+        let index = parse_index(json).unwrap();
+        assert_eq!(
+            index["symbols/cafe.png"],
+            SpriteEntry {
+                x: 0,
+                y: 2,
+                width: 16,
+                height: 14
+            }
+        );
+        assert_eq!(index["quote\"d A"].x, 17);
+
+        assert!(parse_index("{}").unwrap().is_empty());
+        assert!(parse_index(r#"{"a": {"x": 1}}"#).is_err());
+        assert!(parse_index(r#"{"a": {"x": -1, "y": 0, "width": 1, "height": 1}}"#).is_err());
+        assert!(parse_index(r#"{} trailing"#).is_err());
+    }
+}
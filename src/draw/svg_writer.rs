@@ -0,0 +1,177 @@
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::LineCap;
+use std::fmt::Write;
+
+// Pixel coordinates relative to the top left corner of the tile.
+pub type SvgPoint = (f64, f64);
+
+/// Collects the shapes of a tile into an SVG document, in the order they are added (i.e. the later shapes
+/// are drawn on top). The coordinates aren't rounded to the pixels, so the result can be scaled up for print.
+pub struct SvgWriter {
+    dimension: usize,
+    body: String,
+}
+
+impl SvgWriter {
+    pub fn new(dimension: usize, background: &Option<Color>) -> SvgWriter {
+        let mut writer = SvgWriter {
+            dimension,
+            body: String::new(),
+        };
+        if let Some(color) = background {
+            let _ = writeln!(
+                writer.body,
+                r#"<rect width="{0}" height="{0}" fill="{1}"/>"#,
+                dimension,
+                to_hex(color)
+            );
+        }
+        writer
+    }
+
+    /// The rings are closed automatically. The overlapping parts of the rings are holes.
+    pub fn fill(&mut self, rings: &[Vec<SvgPoint>], color: &Color, opacity: f64) {
+        let path_data = rings
+            .iter()
+            .filter(|ring| ring.len() > 2 && self.is_visible(ring, 0.0))
+            .map(|ring| format!("{}Z", format_path(ring)))
+            .collect::<String>();
+        if path_data.is_empty() {
+            return;
+        }
+        let _ = writeln!(
+            self.body,
+            r#"<path d="{}" fill="{}"{} fill-rule="evenodd"/>"#,
+            path_data,
+            to_hex(color),
+            format_opacity("fill-opacity", opacity)
+        );
+    }
+
+    pub fn stroke(
+        &mut self,
+        points: &[SvgPoint],
+        width: f64,
+        color: &Color,
+        opacity: f64,
+        dashes: &Option<Vec<f64>>,
+        dashes_offset: f64,
+        line_cap: &Option<LineCap>,
+    ) {
+        if points.len() < 2 || width <= 0.0 || !self.is_visible(points, width) {
+            return;
+        }
+        let mut dash_array = match dashes {
+            Some(dashes) if !dashes.is_empty() => format!(
+                r#" stroke-dasharray="{}""#,
+                dashes.iter().map(|d| format_number(*d)).collect::<Vec<_>>().join(",")
+            ),
+            _ => String::new(),
+        };
+        if !dash_array.is_empty() && dashes_offset != 0.0 {
+            let _ = write!(dash_array, r#" stroke-dashoffset="{}""#, format_number(dashes_offset));
+        }
+        // SVG doesn't have the triangular caps and the arrowheads, so such lines end without caps.
+        let cap = match line_cap {
+            Some(LineCap::Round) => "round",
+            Some(LineCap::Square) => "square",
+            _ => "butt",
+        };
+        let _ = writeln!(
+            self.body,
+            r#"<path d="{}" fill="none" stroke="{}" stroke-width="{}"{}{} stroke-linecap="{}" stroke-linejoin="round"/>"#,
+            format_path(points),
+            to_hex(color),
+            format_number(width),
+            format_opacity("stroke-opacity", opacity),
+            dash_array,
+            cap
+        );
+    }
+
+    // The entities of the tile include the ones of its neighbors, whose shapes would only make the file larger.
+    fn is_visible(&self, points: &[SvgPoint], margin: f64) -> bool {
+        let (min, max) = (-margin, self.dimension as f64 + margin);
+        let is_outside = |coord: fn(&SvgPoint) -> f64| {
+            points.iter().all(|p| coord(p) < min) || points.iter().all(|p| coord(p) > max)
+        };
+        !is_outside(|p| p.0) && !is_outside(|p| p.1)
+    }
+
+    pub fn finish(self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" viewBox=\"0 0 {0} {0}\">\n{1}</svg>\n",
+            self.dimension, self.body
+        )
+    }
+}
+
+fn to_hex(color: &Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+fn format_opacity(attribute: &str, opacity: f64) -> String {
+    if opacity >= 1.0 {
+        String::new()
+    } else {
+        format!(r#" {}="{}""#, attribute, format_number(opacity))
+    }
+}
+
+fn format_path(points: &[SvgPoint]) -> String {
+    let mut result = String::new();
+    for (idx, (x, y)) in points.iter().enumerate() {
+        let command = if idx == 0 { 'M' } else { 'L' };
+        let _ = write!(result, "{}{} {}", command, format_number(*x), format_number(*y));
+    }
+    result
+}
+
+// Two decimal places are a hundredth of a pixel, which is more than enough even for print.
+fn format_number(num: f64) -> String {
+    let formatted = format!("{:.2}", num);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "-0" {
+        "0".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_svg_writer() {
+        let red = Color { r: 255, g: 0, b: 0 };
+        let mut writer = SvgWriter::new(256, &Some(Color { r: 1, g: 2, b: 255 }));
+        writer.fill(&[vec![(0.0, 0.0), (10.5, 0.0), (10.5, 10.0)]], &red, 0.5);
+        writer.stroke(
+            &[(1.0, 2.0), (3.333, -0.001)],
+            2.0,
+            &red,
+            1.0,
+            &Some(vec![4.0, 2.5]),
+            1.5,
+            &Some(LineCap::Round),
+        );
+        // Nothing to draw, or nothing that is visible.
+        writer.fill(&[vec![(0.0, 0.0), (1.0, 1.0)]], &red, 1.0);
+        writer.stroke(&[(0.0, 0.0)], 1.0, &red, 1.0, &None, 0.0, &None);
+        writer.fill(&[vec![(-5.0, 0.0), (-1.0, 0.0), (-1.0, 300.0)]], &red, 1.0);
+        writer.stroke(&[(300.0, 0.0), (300.0, 10.0)], 2.0, &red, 1.0, &None, 0.0, &None);
+
+        assert_eq!(
+            writer.finish(),
+            [
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="256" height="256" viewBox="0 0 256 256">"#,
+                r##"<rect width="256" height="256" fill="#0102ff"/>"##,
+                r##"<path d="M0 0L10.5 0L10.5 10Z" fill="#ff0000" fill-opacity="0.5" fill-rule="evenodd"/>"##,
+                r##"<path d="M1 2L3.33 0" fill="none" stroke="#ff0000" stroke-width="2" stroke-dasharray="4,2.5" stroke-dashoffset="1.5" stroke-linecap="round" stroke-linejoin="round"/>"##,
+                "</svg>\n",
+            ]
+            .join("\n")
+        );
+    }
+}
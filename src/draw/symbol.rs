@@ -0,0 +1,108 @@
+use crate::draw::arc::{arc_coverage, sector_coverage, ArcPoint};
+use crate::draw::icon::Icon;
+use crate::draw::tile_pixels::RgbaColor;
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{Symbol, SymbolShape};
+
+/// Draws the symbol into an icon of its own, so that it's placed (and collides with the labels) just like
+/// the icons are. The stroke is centered on the edge of the shape, over the fill.
+pub(super) fn symbol_to_icon(symbol: &Symbol, scale: f64) -> Icon {
+    let half_size = symbol.size * scale / 2.0;
+    let half_stroke = match symbol.stroke_color {
+        Some(_) => (symbol.stroke_width * scale / 2.0).max(0.0),
+        None => 0.0,
+    };
+    let dimension = (2.0 * (half_size + half_stroke)).ceil().max(1.0) as usize;
+    let layer = |color: &Option<Color>, opacity: f64, coverage: f64| match color {
+        Some(color) if coverage > 0.0 => RgbaColor::from_color(color, opacity * coverage),
+        _ => RgbaColor::from_components(0, 0, 0, 0),
+    };
+
+    let mut pixels = Vec::with_capacity(dimension * dimension);
+    for y in 0..dimension {
+        for x in 0..dimension {
+            // From the center of the icon to the center of the pixel.
+            let to_offset = |c: usize| c as f64 + 0.5 - dimension as f64 / 2.0;
+            let offset = (to_offset(x), to_offset(y));
+            let (fill_coverage, stroke_coverage) = match symbol.shape {
+                SymbolShape::Circle => (
+                    sector_coverage(offset, half_size, None),
+                    if half_stroke > 0.0 {
+                        arc_coverage(offset, half_size, half_stroke, None)
+                    } else {
+                        0.0
+                    },
+                ),
+                SymbolShape::Square => (
+                    square_coverage(offset, half_size),
+                    square_coverage(offset, half_size + half_stroke) - square_coverage(offset, half_size - half_stroke),
+                ),
+            };
+            let fill = layer(&symbol.fill_color, symbol.fill_opacity, fill_coverage);
+            let stroke = layer(&symbol.stroke_color, symbol.stroke_opacity, stroke_coverage);
+            // The colors are premultiplied.
+            let blend = |top, bottom| top + (1.0 - stroke.a) * bottom;
+            pixels.push(RgbaColor {
+                r: blend(stroke.r, fill.r),
+                g: blend(stroke.g, fill.g),
+                b: blend(stroke.b, fill.b),
+                a: blend(stroke.a, fill.a),
+            });
+        }
+    }
+    Icon::from_pixels(pixels, dimension, dimension)
+}
+
+// How much of the pixel that is `offset` away from the center the square covers, from 0 to 1.
+fn square_coverage(offset: ArcPoint, half_size: f64) -> f64 {
+    if half_size <= 0.0 {
+        return 0.0;
+    }
+    let along = |c: f64| (half_size + 0.5 - c.abs()).clamp(0.0, 1.0);
+    along(offset.0) * along(offset.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(shape: SymbolShape, stroke_color: Option<Color>) -> Symbol {
+        Symbol {
+            shape,
+            size: 6.0,
+            fill_color: Some(Color { r: 255, g: 0, b: 0 }),
+            fill_opacity: 1.0,
+            stroke_color,
+            stroke_width: 2.0,
+            stroke_opacity: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_symbol_to_icon() {
+        let opacity = |icon: &Icon, x, y| icon.get(x, y).a;
+        let is_red = |icon: &Icon, x, y| {
+            let pixel = icon.get(x, y);
+            pixel.r == 1.0 && pixel.g == 0.0 && pixel.a == 1.0
+        };
+
+        let dot = symbol_to_icon(&symbol(SymbolShape::Circle, None), 1.0);
+        assert_eq!((dot.width, dot.height), (6, 6));
+        assert!(is_red(&dot, 2, 3));
+        assert!(is_red(&dot, 1, 3));
+        assert_eq!(opacity(&dot, 0, 0), 0.0);
+        assert!(opacity(&dot, 1, 0) > 0.0 && opacity(&dot, 1, 0) < 1.0);
+
+        // The stroke goes around the fill and makes the icon bigger.
+        let circle = symbol_to_icon(&symbol(SymbolShape::Circle, Some(Color { r: 0, g: 0, b: 255 })), 2.0);
+        assert_eq!((circle.width, circle.height), (16, 16));
+        assert!(is_red(&circle, 8, 8));
+        assert_eq!(circle.get(1, 8).b, 1.0);
+        assert_eq!(opacity(&circle, 0, 0), 0.0);
+
+        let square = symbol_to_icon(&symbol(SymbolShape::Square, None), 1.0);
+        assert_eq!((square.width, square.height), (6, 6));
+        assert!(is_red(&square, 0, 0));
+        assert!(is_red(&square, 5, 5));
+    }
+}
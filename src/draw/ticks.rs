@@ -0,0 +1,89 @@
+use crate::mapcss::styler::{TickSide, Ticks};
+
+// Pixel coordinates relative to the top left corner of the tile.
+pub type TickPoint = (f64, f64);
+
+/// The ticks along a line that is given by its segments, each from the point on the line to its other end.
+/// The ticks are `spacing` apart, starting half of it from the start of the line. The whole line is always
+/// walked (not only the part on the tile), so the ticks line up with the ones drawn on the neighboring tiles.
+pub fn place_ticks(
+    segments: impl Iterator<Item = (TickPoint, TickPoint)>,
+    ticks: &Ticks,
+    scale: f64,
+) -> Vec<(TickPoint, TickPoint)> {
+    let (spacing, length) = (ticks.spacing * scale, ticks.length * scale);
+    let sides: &[f64] = match ticks.side {
+        TickSide::Right => &[1.0],
+        TickSide::Left => &[-1.0],
+        TickSide::Both => &[1.0, -1.0],
+    };
+
+    let mut result = Vec::new();
+    // How far the next tick is from the start of the current segment.
+    let mut next_tick = spacing / 2.0;
+    for ((x1, y1), (x2, y2)) in segments {
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let segment_length = dx.hypot(dy);
+        if segment_length == 0.0 {
+            continue;
+        }
+        // The y axis goes down, so this is the normal on the right of the direction of the line.
+        let (nx, ny) = (-dy / segment_length, dx / segment_length);
+        while next_tick <= segment_length {
+            let t = next_tick / segment_length;
+            let start = (x1 + dx * t, y1 + dy * t);
+            for side in sides {
+                result.push((start, (start.0 + side * nx * length, start.1 + side * ny * length)));
+            }
+            next_tick += spacing;
+        }
+        next_tick -= segment_length;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticks(side: TickSide) -> Ticks {
+        Ticks {
+            spacing: 10.0,
+            length: 2.0,
+            width: None,
+            side,
+        }
+    }
+
+    #[test]
+    fn test_place_ticks() {
+        // Going east and then south, the right side is the south and then the west.
+        let line = [((0.0, 0.0), (12.0, 0.0)), ((12.0, 0.0), (12.0, 20.0))];
+        assert_eq!(
+            place_ticks(line.into_iter(), &ticks(TickSide::Right), 1.0),
+            vec![
+                ((5.0, 0.0), (5.0, 2.0)),
+                ((12.0, 3.0), (10.0, 3.0)),
+                ((12.0, 13.0), (10.0, 13.0)),
+            ]
+        );
+        assert_eq!(
+            place_ticks(line.into_iter(), &ticks(TickSide::Left), 2.0),
+            vec![((10.0, 0.0), (10.0, -4.0)), ((12.0, 18.0), (16.0, 18.0))]
+        );
+        assert_eq!(
+            place_ticks(line[..1].iter().cloned(), &ticks(TickSide::Both), 1.0),
+            vec![((5.0, 0.0), (5.0, 2.0)), ((5.0, 0.0), (5.0, -2.0))]
+        );
+        // The ticks don't depend on where the line is cut into segments.
+        let split_line = [
+            ((0.0, 0.0), (3.0, 0.0)),
+            ((3.0, 0.0), (3.0, 0.0)),
+            ((3.0, 0.0), (30.0, 0.0)),
+        ];
+        assert_eq!(
+            place_ticks(split_line.into_iter(), &ticks(TickSide::Right), 1.0),
+            place_ticks(std::iter::once(((0.0, 0.0), (30.0, 0.0))), &ticks(TickSide::Right), 1.0)
+        );
+    }
+}
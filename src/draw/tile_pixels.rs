@@ -1,5 +1,7 @@
+use crate::draw::fill::EdgeSpan;
 use crate::draw::TILE_SIZE;
 use crate::mapcss::color::Color;
+use crate::tile::zoom_offset_for_tile_size;
 
 #[derive(Clone)]
 pub struct RgbaColor {
@@ -29,12 +31,14 @@ impl RgbaColor {
 pub struct TilePixels {
     bb: BoundingBox,
     labels_bb: BoundingBox,
+    scale: usize,
     scaled_tile_size: usize,
-    scaled_extended_tile_size: usize,
+    row_length: usize,
     pixels: Vec<RgbaColor>,
     next_pixels: Vec<Option<NextPixel>>,
     generation: usize,
     label_generation_statuses: Vec<bool>,
+    edge_spans: Vec<EdgeSpan>,
 }
 
 #[derive(Clone)]
@@ -44,6 +48,18 @@ struct NextPixel {
 }
 
 pub type RgbTriples = Vec<(u8, u8, u8)>;
+pub type RgbaQuadruples = Vec<(u8, u8, u8, u8)>;
+/// 16 bits per channel, for the formats that can keep them and for the compositing done after the drawing.
+pub type DeepRgbaQuadruples = Vec<(u16, u16, u16, u16)>;
+
+pub fn to_8_bit(quadruples: &DeepRgbaQuadruples) -> RgbaQuadruples {
+    // 257 = u16::MAX / u8::MAX, and the division rounds down just like the conversion from the floating point.
+    let convert = |c: u16| (c / 257) as u8;
+    quadruples
+        .iter()
+        .map(|&(r, g, b, a)| (convert(r), convert(g), convert(b), convert(a)))
+        .collect()
+}
 
 #[derive(Clone)]
 pub struct BoundingBox {
@@ -55,8 +71,32 @@ pub struct BoundingBox {
 
 impl TilePixels {
     pub fn new(scale: usize) -> TilePixels {
-        let scaled_tile_size = TILE_SIZE * scale;
+        TilePixels::with_tile_size(TILE_SIZE, scale)
+    }
+
+    /// Pixels for the tiles that are bigger than usual (see `zoom_offset_for_tile_size`), which the drawer
+    /// draws with the details of the further zoom levels. Panics if the tile size isn't supported.
+    pub fn with_tile_size(tile_size: usize, scale: usize) -> TilePixels {
+        assert!(
+            zoom_offset_for_tile_size(tile_size as u32).is_some(),
+            "Unsupported tile size: {}",
+            tile_size
+        );
+        TilePixels::with_label_margin(tile_size, tile_size, scale)
+    }
+
+    /// Pixels for a `size`×`size` block of the usual tiles that is drawn at once (see `Drawer::draw_metatile_to_pixels`).
+    /// The labels can stick out of the block by a usual tile, just like they can stick out of a single tile.
+    /// Panics if the size isn't a power of two.
+    pub fn for_metatile(size: usize, scale: usize) -> TilePixels {
+        assert!(size.is_power_of_two(), "Unsupported metatile size: {}", size);
+        TilePixels::with_label_margin(size * TILE_SIZE, TILE_SIZE, scale)
+    }
+
+    fn with_label_margin(tile_size: usize, label_margin: usize, scale: usize) -> TilePixels {
+        let scaled_tile_size = tile_size * scale;
         let scaled_tile_size_i32 = scaled_tile_size as i32;
+        let scaled_label_margin = (label_margin * scale) as i32;
 
         let bounding_box = BoundingBox {
             min_x: 0,
@@ -65,24 +105,90 @@ impl TilePixels {
             max_y: scaled_tile_size_i32 - 1,
         };
         let bounding_box_for_labels = BoundingBox {
-            min_x: bounding_box.min_x - scaled_tile_size_i32,
-            max_x: bounding_box.max_x + scaled_tile_size_i32,
-            min_y: bounding_box.min_y - scaled_tile_size_i32,
-            max_y: bounding_box.max_y + scaled_tile_size_i32,
+            min_x: bounding_box.min_x - scaled_label_margin,
+            max_x: bounding_box.max_x + scaled_label_margin,
+            min_y: bounding_box.min_y - scaled_label_margin,
+            max_y: bounding_box.max_y + scaled_label_margin,
         };
 
-        let scaled_extended_tile_size = EXTENDED_TILE_SIZE * scale;
-        let pixel_count = scaled_extended_tile_size * scaled_extended_tile_size;
+        let row_length = scaled_tile_size + 2 * scaled_label_margin as usize;
+        let pixel_count = row_length * row_length;
 
         TilePixels {
             bb: bounding_box,
             labels_bb: bounding_box_for_labels,
+            scale,
+            scaled_tile_size,
+            row_length,
+            pixels: vec![DEFAULT_PIXEL_COLOR; pixel_count],
+            next_pixels: vec![None; pixel_count],
+            generation: 0,
+            label_generation_statuses: Vec::new(),
+            edge_spans: Vec::new(),
+        }
+    }
+
+    // The rows from `min_y` to `max_y` (inclusive) of the tile, without the surrounding space for the labels.
+    // The bands of a tile can be drawn independently and then copied back with `copy_band`.
+    pub(super) fn new_band(&self, min_y: i32, max_y: i32) -> TilePixels {
+        let scaled_tile_size = self.scaled_tile_size;
+        let bounding_box = BoundingBox {
+            min_x: 0,
+            max_x: scaled_tile_size as i32 - 1,
+            min_y,
+            max_y,
+        };
+        let pixel_count = scaled_tile_size * (max_y - min_y + 1) as usize;
+
+        TilePixels {
+            bb: bounding_box.clone(),
+            labels_bb: bounding_box,
+            scale: self.scale,
             scaled_tile_size,
-            scaled_extended_tile_size,
+            row_length: scaled_tile_size,
             pixels: vec![DEFAULT_PIXEL_COLOR; pixel_count],
             next_pixels: vec![None; pixel_count],
             generation: 0,
             label_generation_statuses: Vec::new(),
+            edge_spans: Vec::new(),
+        }
+    }
+
+    // Expects the band to be fully blended.
+    pub(super) fn copy_band(&mut self, band: &TilePixels) {
+        let width = (band.bb.max_x - band.bb.min_x + 1) as usize;
+        for y in band.bb.min_y..=band.bb.max_y {
+            let to = self.global_coords_to_idx(band.bb.min_x, y, false).unwrap();
+            let from = band.global_coords_to_idx(band.bb.min_x, y, false).unwrap();
+            self.pixels[to..to + width].clone_from_slice(&band.pixels[from..from + width]);
+        }
+    }
+
+    // A transparent layer of the same rows, to be drawn on its own and then blended back with `blend_layer`.
+    pub(super) fn new_layer(&self) -> TilePixels {
+        let mut layer = self.new_band(self.bb.min_y, self.bb.max_y);
+        layer.reset_transparent();
+        layer
+    }
+
+    // Expects both the pixels and the layer to be fully blended.
+    pub(super) fn blend_layer(&mut self, layer: &TilePixels) {
+        let width = (layer.bb.max_x - layer.bb.min_x + 1) as usize;
+        for y in layer.bb.min_y..=layer.bb.max_y {
+            let to = self.global_coords_to_idx(layer.bb.min_x, y, false).unwrap();
+            let from = layer.global_coords_to_idx(layer.bb.min_x, y, false).unwrap();
+            for (pixel, top) in self.pixels[to..to + width]
+                .iter_mut()
+                .zip(&layer.pixels[from..from + width])
+            {
+                let blend = |new_value, old_value| new_value + (1.0 - top.a) * old_value;
+                *pixel = RgbaColor {
+                    r: blend(top.r, pixel.r),
+                    g: blend(top.g, pixel.g),
+                    b: blend(top.b, pixel.b),
+                    a: blend(top.a, pixel.a),
+                };
+            }
         }
     }
 
@@ -91,7 +197,15 @@ impl TilePixels {
             .as_ref()
             .map(|c| RgbaColor::from_color(c, 1.0))
             .unwrap_or(DEFAULT_PIXEL_COLOR);
+        self.reset_to(&initial_pixel_color);
+    }
+
+    // Used for the layers that are meant to be drawn on top of the other ones (e.g. the labels).
+    pub fn reset_transparent(&mut self) {
+        self.reset_to(&TRANSPARENT_PIXEL_COLOR);
+    }
 
+    fn reset_to(&mut self, initial_pixel_color: &RgbaColor) {
         for pixel in self.pixels.iter_mut() {
             *pixel = initial_pixel_color.clone();
         }
@@ -104,6 +218,17 @@ impl TilePixels {
         self.label_generation_statuses.clear();
     }
 
+    // The scratch buffer for filling the areas, which is handed out empty and taken back after every area.
+    pub(super) fn take_edge_spans(&mut self) -> Vec<EdgeSpan> {
+        let mut spans = std::mem::take(&mut self.edge_spans);
+        spans.clear();
+        spans
+    }
+
+    pub(super) fn return_edge_spans(&mut self, spans: Vec<EdgeSpan>) {
+        self.edge_spans = spans;
+    }
+
     pub fn set_pixel(&mut self, x: i32, y: i32, color: &RgbaColor) {
         let idx = match self.global_coords_to_idx(x, y, false) {
             Some(idx) => idx,
@@ -147,6 +272,26 @@ impl TilePixels {
         true
     }
 
+    /// Checks that a label can be drawn over the whole box (inclusive) without colliding with the labels placed before.
+    pub fn is_label_area_free(&self, area: &BoundingBox) -> bool {
+        let label_generation = self.label_generation_statuses.len();
+        for y in area.min_y..=area.max_y {
+            for x in area.min_x..=area.max_x {
+                let idx = match self.global_coords_to_idx(x, y, true) {
+                    Some(idx) => idx,
+                    _ => continue,
+                };
+                if let Some(next_pixel) = &self.next_pixels[idx] {
+                    if next_pixel.generation < label_generation && self.label_generation_statuses[next_pixel.generation]
+                    {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
     pub fn bump_generation(&mut self) {
         self.generation += 1;
     }
@@ -162,28 +307,57 @@ impl TilePixels {
     }
 
     pub fn to_rgb_triples(&self) -> RgbTriples {
-        let mut triples = Vec::new();
+        self.to_rgba_quadruples()
+            .into_iter()
+            .map(|(r, g, b, _)| (r, g, b))
+            .collect()
+    }
+
+    pub fn to_rgba_quadruples(&self) -> RgbaQuadruples {
+        to_8_bit(&self.to_deep_rgba_quadruples())
+    }
 
-        let non_label_pixel_range = || self.scaled_tile_size..2 * self.scaled_tile_size;
+    // The pixels are blended with the full floating point precision, so this is as exact as it gets.
+    pub fn to_deep_rgba_quadruples(&self) -> DeepRgbaQuadruples {
+        let mut quadruples = Vec::new();
+
+        let label_margin = (self.bb.min_x - self.labels_bb.min_x) as usize;
+        let non_label_pixel_range = || label_margin..label_margin + self.scaled_tile_size;
 
         for y in non_label_pixel_range() {
             for x in non_label_pixel_range() {
                 let p = &self.pixels[self.local_coords_to_idx(x, y)];
                 let postdivide = |val| {
                     let mul = if p.a == 0.0 { 0.0 } else { val / p.a };
-                    (f64::from(u8::max_value()) * mul) as u8
+                    (f64::from(u16::MAX) * mul) as u16
                 };
-                triples.push((postdivide(p.r), postdivide(p.g), postdivide(p.b)));
+                let alpha = (f64::from(u16::MAX) * p.a) as u16;
+                quadruples.push((postdivide(p.r), postdivide(p.g), postdivide(p.b), alpha));
             }
         }
 
-        triples
+        quadruples
     }
 
     pub fn dimension(&self) -> usize {
         self.scaled_tile_size
     }
 
+    // 2 for the `@2x` tiles, etc.
+    pub fn scale(&self) -> f64 {
+        self.scale as f64
+    }
+
+    // The size of the tile without the scale (e.g. 512 for the 512×512 tiles, including the `@2x` ones).
+    pub fn tile_size(&self) -> usize {
+        self.scaled_tile_size / self.scale
+    }
+
+    /// How many zoom levels further the usual tiles with the same detail are (see `zoom_offset_for_tile_size`).
+    pub fn zoom_offset(&self) -> u8 {
+        (self.tile_size() / TILE_SIZE).trailing_zeros() as u8
+    }
+
     pub fn bb(&self) -> &BoundingBox {
         &self.bb
     }
@@ -199,7 +373,7 @@ impl TilePixels {
     }
 
     fn local_coords_to_idx(&self, x: usize, y: usize) -> usize {
-        y * self.scaled_extended_tile_size + x
+        y * self.row_length + x
     }
 
     fn blend_pixel(&mut self, idx: usize, for_labels: bool) {
@@ -224,13 +398,18 @@ impl TilePixels {
 }
 
 fn component_to_opacity(comp: u8) -> f64 {
-    f64::from(comp) / f64::from(u8::max_value())
+    f64::from(comp) / f64::from(u8::MAX)
 }
 
-const EXTENDED_TILE_SIZE: usize = 3 * TILE_SIZE;
 const DEFAULT_PIXEL_COLOR: RgbaColor = RgbaColor {
     r: 0.0,
     g: 0.0,
     b: 0.0,
     a: 1.0,
 };
+const TRANSPARENT_PIXEL_COLOR: RgbaColor = RgbaColor {
+    r: 0.0,
+    g: 0.0,
+    b: 0.0,
+    a: 0.0,
+};
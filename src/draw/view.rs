@@ -0,0 +1,178 @@
+use crate::coords::{Coords, GeoBounds};
+use crate::draw::drawer::Drawer;
+use crate::draw::png_writer::rgb_triples_to_png;
+use crate::draw::tile_pixels::{RgbTriples, TilePixels};
+use crate::draw::TILE_SIZE;
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::styler::Styler;
+use crate::tile::{coords_to_xy, xy_to_coords, Tile, MAX_ZOOM};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A rectangular part of the map that doesn't have to match the tile grid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    pub lat: f64,
+    pub lon: f64,
+    /// Can be fractional, in which case the tiles of the next zoom level are scaled down.
+    pub zoom: f64,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl View {
+    /// The view of the given size that is centered on the bounds and shows all of them as closely as possible.
+    /// # Examples
+    /// ```
+    /// use renderer::coords::GeoBounds;
+    /// use renderer::draw::view::View;
+    /// let bounds = "0,0,1.40625,1.40625".parse::<GeoBounds>().unwrap();
+    /// let view = View::fitting(&bounds, 1024, 512);
+    /// assert!((view.zoom - 9.0).abs() < 0.01);
+    /// assert!((view.lat - 0.703).abs() < 0.001 && (view.lon - 0.703).abs() < 0.001);
+    /// ```
+    pub fn fitting(bounds: &GeoBounds, width: usize, height: usize) -> View {
+        let (min_x, min_y) = coords_to_xy(&(bounds.max_lat, bounds.min_lon), 0);
+        let (max_x, max_y) = coords_to_xy(&(bounds.min_lat, bounds.max_lon), 0);
+        let zoom_to_fit = |pixels: usize, extent: f64| (pixels as f64 / extent).log2();
+        let zoom = zoom_to_fit(width, max_x - min_x)
+            .min(zoom_to_fit(height, max_y - min_y))
+            .clamp(0.0, f64::from(MAX_ZOOM));
+        let (lat, lon) = xy_to_coords((min_x + max_x) / 2.0, (min_y + max_y) / 2.0, 0);
+        View {
+            lat,
+            lon,
+            zoom,
+            width,
+            height,
+        }
+    }
+}
+
+/// Draws the bounds into a PNG image of the given size (e.g. for a preview of an area), with the same
+/// margins on both sides along the dimension that the bounds don't fill.
+pub fn render_static_map(
+    reader: &GeodataReader<'_>,
+    styler: &Styler,
+    drawer: &Drawer,
+    bounds: &GeoBounds,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>> {
+    let view = View::fitting(bounds, width, height);
+    let triples = ViewRenderer::new(reader, styler, drawer).render(&view);
+    rgb_triples_to_png(&triples, width, height)
+}
+
+/// Renders views by drawing the tiles they cover and resampling them. The tiles are kept until
+/// a view no longer needs them, so rendering a sequence of close views (e.g. the frames of an animation)
+/// only draws the tiles that come into sight.
+pub struct ViewRenderer<'a> {
+    reader: &'a GeodataReader<'a>,
+    styler: &'a Styler,
+    drawer: &'a Drawer,
+    pixels: TilePixels,
+    tiles: HashMap<Tile, RgbTriples>,
+}
+
+impl<'a> ViewRenderer<'a> {
+    pub fn new(reader: &'a GeodataReader<'a>, styler: &'a Styler, drawer: &'a Drawer) -> ViewRenderer<'a> {
+        ViewRenderer {
+            reader,
+            styler,
+            drawer,
+            pixels: TilePixels::new(1),
+            tiles: HashMap::new(),
+        }
+    }
+
+    pub fn render(&mut self, view: &View) -> RgbTriples {
+        let zoom = (view.zoom.ceil().max(0.0) as u8).min(MAX_ZOOM);
+        // The number of pixels of the tiles per pixel of the view.
+        let step = 2f64.powf(f64::from(zoom) - view.zoom);
+        let (center_x, center_y) = coords_to_xy(view, zoom);
+        let left = center_x - step * view.width as f64 / 2.0;
+        let top = center_y - step * view.height as f64 / 2.0;
+
+        // Every pixel of the view is interpolated from the four closest pixels of the tiles.
+        let tile_size = TILE_SIZE as i64;
+        let min_x = left.floor() as i64 - 1;
+        let min_y = top.floor() as i64 - 1;
+        let max_x = (left + step * view.width as f64).ceil() as i64 + 1;
+        let max_y = (top + step * view.height as f64).ceil() as i64 + 1;
+        let (min_tile_x, max_tile_x) = (min_x.div_euclid(tile_size), max_x.div_euclid(tile_size));
+        let (min_tile_y, max_tile_y) = (min_y.div_euclid(tile_size), max_y.div_euclid(tile_size));
+
+        let tile_count = 1i64 << zoom;
+        let get_tile = |tile_x: i64, tile_y: i64| Tile {
+            zoom,
+            x: tile_x.rem_euclid(tile_count) as u32,
+            y: tile_y.clamp(0, tile_count - 1) as u32,
+        };
+        let needed_tiles = (min_tile_y..=max_tile_y)
+            .flat_map(|tile_y| (min_tile_x..=max_tile_x).map(move |tile_x| get_tile(tile_x, tile_y)))
+            .collect::<Vec<_>>();
+        self.tiles.retain(|tile, _| needed_tiles.contains(tile));
+        for tile in &needed_tiles {
+            if !self.tiles.contains_key(tile) {
+                let entities = self.reader.get_entities_in_tile_with_neighbors(tile, &None);
+                let rendered = self
+                    .drawer
+                    .draw_to_pixels(&entities, tile, &mut self.pixels, 1, self.styler);
+                self.tiles.insert(tile.clone(), rendered.triples);
+            }
+        }
+
+        let grid_width = (max_tile_x - min_tile_x + 1) as usize;
+        let grid = needed_tiles.iter().map(|tile| &self.tiles[tile]).collect::<Vec<_>>();
+        let get_pixel = |x: i64, y: i64| {
+            let grid_idx = (y.div_euclid(tile_size) - min_tile_y) as usize * grid_width
+                + (x.div_euclid(tile_size) - min_tile_x) as usize;
+            // Above and below the map, the edge rows of the tiles are repeated.
+            let tile_row = if y < 0 {
+                0
+            } else if y >= tile_count * tile_size {
+                tile_size - 1
+            } else {
+                y.rem_euclid(tile_size)
+            };
+            grid[grid_idx][(tile_row * tile_size + x.rem_euclid(tile_size)) as usize]
+        };
+
+        let mut result = Vec::with_capacity(view.width * view.height);
+        for row in 0..view.height {
+            for col in 0..view.width {
+                // The pixel centers of the tiles are at half-integer coordinates.
+                let x = left + step * (col as f64 + 0.5) - 0.5;
+                let y = top + step * (row as f64 + 0.5) - 0.5;
+                let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+                let (dx, dy) = (x - x0 as f64, y - y0 as f64);
+                let corners = [
+                    (get_pixel(x0, y0), (1.0 - dx) * (1.0 - dy)),
+                    (get_pixel(x0 + 1, y0), dx * (1.0 - dy)),
+                    (get_pixel(x0, y0 + 1), (1.0 - dx) * dy),
+                    (get_pixel(x0 + 1, y0 + 1), dx * dy),
+                ];
+                let blend = |component: fn(&(u8, u8, u8)) -> u8| {
+                    let value = corners
+                        .iter()
+                        .map(|(pixel, weight)| f64::from(component(pixel)) * weight)
+                        .sum::<f64>();
+                    value.round().clamp(0.0, 255.0) as u8
+                };
+                result.push((blend(|p| p.0), blend(|p| p.1), blend(|p| p.2)));
+            }
+        }
+        result
+    }
+}
+
+impl Coords for View {
+    fn lat(&self) -> f64 {
+        self.lat
+    }
+
+    fn lon(&self) -> f64 {
+        self.lon
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::{Context, Result};
+use image_webp::{ColorType, WebPEncoder};
+
+// The tiles are always encoded losslessly, so they have exactly the same pixels as the PNG ones.
+pub fn rgb_triples_to_webp(triples: &[(u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b) in triples {
+        image_bytes.extend([r, g, b].iter());
+    }
+    write_webp(&image_bytes, width, height, ColorType::Rgb8)
+}
+
+pub fn rgba_quadruples_to_webp(quadruples: &[(u8, u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b, a) in quadruples {
+        image_bytes.extend([r, g, b, a].iter());
+    }
+    write_webp(&image_bytes, width, height, ColorType::Rgba8)
+}
+
+fn write_webp(image_bytes: &[u8], width: usize, height: usize, color_type: ColorType) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    WebPEncoder::new(&mut buf)
+        .encode(image_bytes, width as u32, height as u32, color_type)
+        .context("Failed to write WebP data")?;
+    Ok(buf)
+}
@@ -0,0 +1,93 @@
+use crate::draw::cancellation::CancellationToken;
+use crate::draw::drawer::{DrawOptions, Drawer, ImageFormat, TileLayers, TileRequest};
+use crate::draw::tile_pixels::TilePixels;
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
+use crate::mapcss::styler::{get_style_version, StyleType, Styler};
+use crate::progress::Progress;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// The low zoom levels change rarely and take the longest to draw, so the tiles up to this one are baked by default.
+pub const DEFAULT_BAKED_MAX_ZOOM: u8 = 8;
+
+/// Draws the tiles of the low zoom levels at import time and stores them in the geodata file (as 256×256 PNG
+/// images), so that the server gives them out as they are (see `GeodataReader::baked_tile`). The style is
+/// the same as the one of the server, e.g. taken from its config.
+pub struct BakeOptions {
+    pub stylesheet_file: String,
+    pub stylesheet_type: StyleType,
+    pub font_size_multiplier: Option<f64>,
+    pub draw_options: DrawOptions,
+    /// The tiles from zoom level 0 up to this one are baked, as long as there's anything in them.
+    pub max_zoom: u8,
+}
+
+pub(super) struct BakedTiles {
+    // The tiles are only used with the style of this version.
+    pub(super) style_version: String,
+    // The PNG images by the zoom levels and the coordinates of the tiles.
+    pub(super) tiles: BTreeMap<(u8, u32, u32), Vec<u8>>,
+}
+
+// Draws the tiles from the geodata that has already been saved without them.
+pub(super) fn bake_tiles(geodata_file: &Path, options: &BakeOptions, progress: &mut Progress) -> Result<BakedTiles> {
+    let (base_path, file_name) = split_stylesheet_path(&options.stylesheet_file)?;
+    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
+    let style_version = get_style_version(&rules, &options.stylesheet_type, options.font_size_multiplier);
+    let styler = Styler::new(rules, &options.stylesheet_type, options.font_size_multiplier);
+    let drawer = Drawer::new(&base_path, options.draw_options.clone());
+    let reader = GeodataReader::load(&geodata_file.to_string_lossy())?;
+
+    let tiles = (0..=options.max_zoom)
+        .flat_map(|zoom| reader.covered_tiles(zoom))
+        .collect::<Vec<_>>();
+    progress.start_stage("Baking the tiles", "tiles", Some(tiles.len() as u64), 0);
+
+    let thread_count = thread::available_parallelism().map_or(1, usize::from);
+    let next_tile = AtomicUsize::new(0);
+    let baked = thread::scope(|s| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..thread_count {
+            let sender = sender.clone();
+            let (tiles, next_tile, reader, drawer, styler) = (&tiles, &next_tile, &reader, &drawer, &styler);
+            s.spawn(move || {
+                let mut pixels = TilePixels::new(1);
+                let cancellation = CancellationToken::new();
+                while let Some(tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                    let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+                    let request = TileRequest {
+                        scale: 1,
+                        layers: TileLayers::All,
+                        format: ImageFormat::Png,
+                        cancellation: &cancellation,
+                    };
+                    let png = drawer.draw_tile(&entities, tile, &mut pixels, styler, &request);
+                    // Nobody needs the rest of the tiles after an error.
+                    if sender.send((tile, png)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut baked = BTreeMap::new();
+        for (tile, png) in receiver {
+            let png = png.with_context(|| format!("Failed to draw the tile {}/{}/{}", tile.zoom, tile.x, tile.y))?;
+            baked.insert((tile.zoom, tile.x, tile.y), png);
+            progress.update(baked.len() as u64, baked.len() as u64);
+        }
+        Ok::<_, anyhow::Error>(baked)
+    })?;
+    progress.finish_stage(baked.len() as u64, baked.len() as u64);
+
+    Ok(BakedTiles {
+        style_version,
+        tiles: baked,
+    })
+}
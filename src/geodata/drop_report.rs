@@ -0,0 +1,58 @@
+use crate::progress::Progress;
+use std::collections::BTreeMap;
+
+/// Keeps track of the input data that the importer had to skip, so that a single broken entity
+/// doesn't abort a long import, but also doesn't go unnoticed.
+#[derive(Default)]
+pub(super) struct DropReport {
+    dropped: BTreeMap<(&'static str, &'static str), Dropped>,
+    missing_refs: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+#[derive(Default)]
+struct Dropped {
+    count: u64,
+    examples: Vec<String>,
+}
+
+impl DropReport {
+    /// Records that an entity of a given kind ("node", "way", etc.) was skipped.
+    pub(super) fn drop_entity(&mut self, kind: &'static str, reason: &'static str, details: String) {
+        let dropped = self.dropped.entry((kind, reason)).or_default();
+        dropped.count += 1;
+        if dropped.examples.len() < MAX_EXAMPLES {
+            dropped.examples.push(details);
+        }
+    }
+
+    /// Records that an entity of kind `from` referenced an entity of kind `to` that is not in the input.
+    /// The reference itself is skipped. This is expected for extracts, where the entities on the border
+    /// reference the data outside of the extract.
+    pub(super) fn skip_missing_ref(&mut self, from: &'static str, to: &'static str) {
+        *self.missing_refs.entry((from, to)).or_default() += 1;
+    }
+
+    pub(super) fn print(&self, progress: &Progress) {
+        for ((from, to), count) in &self.missing_refs {
+            progress.message(&format!(
+                "Skipped {} references from {}s to missing {}s",
+                count, from, to
+            ));
+        }
+
+        if self.dropped.is_empty() {
+            return;
+        }
+
+        let total: u64 = self.dropped.values().map(|d| d.count).sum();
+        progress.message(&format!("Dropped {} malformed entities:", total));
+        for ((kind, reason), dropped) in &self.dropped {
+            progress.message(&format!("  {} {}s with {}, e.g.:", dropped.count, kind, reason));
+            for example in &dropped.examples {
+                progress.message(&format!("    {}", example));
+            }
+        }
+    }
+}
+
+const MAX_EXAMPLES: usize = 5;
@@ -0,0 +1,329 @@
+use crate::draw::sprite::escape_json;
+use crate::geodata::reader::{OsmEntities, OsmEntity, Tags};
+use crate::tile::{coords_to_xy_tile_relative, xy_to_coords, Tile, TILE_SIZE};
+
+// Web Mercator pixel coordinates relative to the top left corner of the tile, so that clipping
+// the geometries to the tile follows the same straight lines that the renderer draws.
+type Xy = (f64, f64);
+
+/// Converts the objects of a tile to a GeoJSON `FeatureCollection`, with the geometries clipped to the tile.
+/// The nodes become points, the ways become line strings and the multipolygons become multipolygons.
+/// Only the tags with the given keys (or all of them, if no keys are given) end up in the properties.
+pub fn tile_to_geojson(entities: &OsmEntities<'_>, tile: &Tile, tag_keys: Option<&[String]>) -> String {
+    let mut features = Vec::new();
+    let mut add_feature = |entity_type, id, tags: Tags<'_>, geometry| {
+        features.push(format!(
+            "{{\"type\":\"Feature\",\"id\":\"{}/{}\",\"properties\":{{{}}},\"geometry\":{}}}",
+            entity_type,
+            id,
+            format_properties(&tags, tag_keys),
+            geometry
+        ));
+    };
+
+    for node in &entities.nodes {
+        let xy = coords_to_xy_tile_relative(node, tile);
+        if is_inside(xy) {
+            let geometry = format!("{{\"type\":\"Point\",\"coordinates\":{}}}", format_position(xy, tile));
+            add_feature("node", node.global_id(), node.tags(), geometry);
+        }
+    }
+
+    for way in &entities.ways {
+        let points = (0..way.node_count())
+            .map(|idx| coords_to_xy_tile_relative(&way.get_node(idx), tile))
+            .collect::<Vec<_>>();
+        let mut parts = clip_line(&points);
+        let geometry = match parts.len() {
+            0 => continue,
+            1 => format!(
+                "{{\"type\":\"LineString\",\"coordinates\":{}}}",
+                format_positions(&parts.remove(0), tile)
+            ),
+            _ => format!(
+                "{{\"type\":\"MultiLineString\",\"coordinates\":[{}]}}",
+                join(parts.iter().map(|part| format_positions(part, tile)))
+            ),
+        };
+        add_feature("way", way.global_id(), way.tags(), geometry);
+    }
+
+    for multipolygon in &entities.multipolygons {
+        let rings = (0..multipolygon.polygon_count())
+            .map(|idx| {
+                let polygon = multipolygon.get_polygon(idx);
+                (0..polygon.node_count())
+                    .map(|node_idx| coords_to_xy_tile_relative(&polygon.get_node(node_idx), tile))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let polygons = group_rings(&rings)
+            .into_iter()
+            .filter_map(|(outer, holes)| {
+                let clipped_outer = clip_ring(&rings[outer]);
+                if clipped_outer.is_empty() {
+                    return None;
+                }
+                let clipped_holes = holes
+                    .into_iter()
+                    .map(|hole| clip_ring(&rings[hole]))
+                    .filter(|hole| !hole.is_empty());
+                let all_rings = Some(clipped_outer).into_iter().chain(clipped_holes);
+                Some(format!(
+                    "[{}]",
+                    join(all_rings.map(|ring| format_positions(&ring, tile)))
+                ))
+            })
+            .collect::<Vec<_>>();
+        if !polygons.is_empty() {
+            let geometry = format!(
+                "{{\"type\":\"MultiPolygon\",\"coordinates\":[{}]}}",
+                join(polygons.into_iter())
+            );
+            add_feature("relation", multipolygon.global_id(), multipolygon.tags(), geometry);
+        }
+    }
+
+    format!(
+        "{{\"type\":\"FeatureCollection\",\"features\":[{}]}}",
+        features.join(",")
+    )
+}
+
+fn format_properties(tags: &Tags<'_>, tag_keys: Option<&[String]>) -> String {
+    join(
+        tags.iter()
+            .filter(|(k, _)| tag_keys.is_none_or(|keys| keys.iter().any(|key| key == k)))
+            .map(|(k, v)| format!("\"{}\":\"{}\"", escape_json(k), escape_json(v))),
+    )
+}
+
+fn format_position((x, y): Xy, tile: &Tile) -> String {
+    let tile_size = f64::from(TILE_SIZE);
+    let (lat, lon) = xy_to_coords(
+        x + f64::from(tile.x) * tile_size,
+        y + f64::from(tile.y) * tile_size,
+        tile.zoom,
+    );
+    format!("[{:.7},{:.7}]", lon, lat)
+}
+
+fn format_positions(points: &[Xy], tile: &Tile) -> String {
+    format!("[{}]", join(points.iter().map(|p| format_position(*p, tile))))
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(",")
+}
+
+fn is_inside((x, y): Xy) -> bool {
+    let tile_size = f64::from(TILE_SIZE);
+    (0.0..=tile_size).contains(&x) && (0.0..=tile_size).contains(&y)
+}
+
+// Splits a line into the parts that are inside the tile.
+fn clip_line(points: &[Xy]) -> Vec<Vec<Xy>> {
+    let mut parts = Vec::new();
+    let mut current_part = Vec::<Xy>::new();
+    for segment in points.windows(2) {
+        if let Some((from, to)) = clip_segment(segment[0], segment[1]) {
+            // The segment doesn't continue the current part if the line has left the tile in between.
+            if current_part.last() != Some(&from) {
+                if current_part.len() > 1 {
+                    parts.push(current_part);
+                }
+                current_part = vec![from];
+            }
+            current_part.push(to);
+        }
+    }
+    if current_part.len() > 1 {
+        parts.push(current_part);
+    }
+    parts
+}
+
+// Liang-Barsky. The endpoints that are inside the tile are returned as they are, so that the consecutive
+// segments of a line can be joined back together.
+fn clip_segment(from: Xy, to: Xy) -> Option<(Xy, Xy)> {
+    let tile_size = f64::from(TILE_SIZE);
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let (mut t_from, mut t_to) = (0.0f64, 1.0f64);
+    let edges = [
+        (-dx, from.0),
+        (dx, tile_size - from.0),
+        (-dy, from.1),
+        (dy, tile_size - from.1),
+    ];
+    for (p, q) in edges {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t_from = t_from.max(t);
+            } else {
+                t_to = t_to.min(t);
+            }
+        }
+    }
+    if t_from > t_to {
+        return None;
+    }
+    let point_at = |t: f64| (from.0 + t * dx, from.1 + t * dy);
+    let clipped_from = if t_from == 0.0 { from } else { point_at(t_from) };
+    let clipped_to = if t_to == 1.0 { to } else { point_at(t_to) };
+    Some((clipped_from, clipped_to))
+}
+
+// Sutherland-Hodgman. Returns a closed ring, or nothing if the ring is completely outside the tile.
+fn clip_ring(ring: &[Xy]) -> Vec<Xy> {
+    let tile_size = f64::from(TILE_SIZE);
+    let mut points = ring.to_vec();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+
+    // The edges of the tile as (whether it's a vertical edge, its coordinate, whether the tile is below it).
+    let edges = [
+        (true, 0.0, false),
+        (true, tile_size, true),
+        (false, 0.0, false),
+        (false, tile_size, true),
+    ];
+    for (is_vertical, bound, is_max) in edges {
+        let coord = |p: Xy| if is_vertical { p.0 } else { p.1 };
+        let is_inside_edge = |p: Xy| if is_max { coord(p) <= bound } else { coord(p) >= bound };
+        let intersect = |a: Xy, b: Xy| {
+            if is_vertical {
+                intersect_x(a, b, bound)
+            } else {
+                intersect_y(a, b, bound)
+            }
+        };
+
+        let input = std::mem::take(&mut points);
+        for (idx, &current) in input.iter().enumerate() {
+            let previous = input[(idx + input.len() - 1) % input.len()];
+            match (is_inside_edge(previous), is_inside_edge(current)) {
+                (true, true) => points.push(current),
+                (true, false) => points.push(intersect(previous, current)),
+                (false, true) => {
+                    points.push(intersect(previous, current));
+                    points.push(current);
+                }
+                (false, false) => {}
+            }
+        }
+    }
+
+    if points.len() < 3 {
+        return Vec::new();
+    }
+    points.push(points[0]);
+    points
+}
+
+fn intersect_x(a: Xy, b: Xy, x: f64) -> Xy {
+    (x, a.1 + (b.1 - a.1) * (x - a.0) / (b.0 - a.0))
+}
+
+fn intersect_y(a: Xy, b: Xy, y: f64) -> Xy {
+    (a.0 + (b.0 - a.0) * (y - a.1) / (b.1 - a.1), y)
+}
+
+// The multipolygons only store their rings, so the rings are sorted out into the outer ones and the holes
+// by how many other rings they are nested in. Returns the outer rings with their holes, as indices.
+fn group_rings(rings: &[Vec<Xy>]) -> Vec<(usize, Vec<usize>)> {
+    let contains = |outer: usize, inner: usize| {
+        outer != inner && !rings[inner].is_empty() && is_point_in_ring(rings[inner][0], &rings[outer])
+    };
+    let depths = (0..rings.len())
+        .map(|inner| (0..rings.len()).filter(|&outer| contains(outer, inner)).count())
+        .collect::<Vec<_>>();
+
+    let mut groups = (0..rings.len())
+        .filter(|&idx| depths[idx] % 2 == 0)
+        .map(|idx| (idx, Vec::new()))
+        .collect::<Vec<_>>();
+    for hole in (0..rings.len()).filter(|&idx| depths[idx] % 2 == 1) {
+        if let Some(group) = groups
+            .iter_mut()
+            .find(|(outer, _)| depths[*outer] + 1 == depths[hole] && contains(*outer, hole))
+        {
+            group.1.push(hole);
+        }
+    }
+    groups
+}
+
+fn is_point_in_ring(point: Xy, ring: &[Xy]) -> bool {
+    let mut is_inside = false;
+    for edge in ring.windows(2) {
+        let (a, b) = (edge[0], edge[1]);
+        if (a.1 > point.1) != (b.1 > point.1) && point.0 < a.0 + (b.0 - a.0) * (point.1 - a.1) / (b.1 - a.1) {
+            is_inside = !is_inside;
+        }
+    }
+    is_inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_line() {
+        // Leaves the tile on the right and comes back from the top.
+        let line = [
+            (10.0, 10.0),
+            (300.0, 10.0),
+            (300.0, -10.0),
+            (100.0, -10.0),
+            (100.0, 20.0),
+        ];
+        assert_eq!(
+            clip_line(&line),
+            vec![vec![(10.0, 10.0), (256.0, 10.0)], vec![(100.0, 0.0), (100.0, 20.0)]]
+        );
+        assert!(clip_line(&[(-10.0, -10.0), (-5.0, 300.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_clip_ring() {
+        let square = [
+            (-10.0, -10.0),
+            (10.0, -10.0),
+            (10.0, 10.0),
+            (-10.0, 10.0),
+            (-10.0, -10.0),
+        ];
+        let clipped = clip_ring(&square);
+        assert_eq!(clipped.first(), clipped.last());
+        let mut corners = clipped[1..].to_vec();
+        corners.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(corners, vec![(0.0, 0.0), (0.0, 10.0), (10.0, 0.0), (10.0, 10.0)]);
+
+        let outside = [(300.0, 300.0), (310.0, 300.0), (310.0, 310.0), (300.0, 300.0)];
+        assert!(clip_ring(&outside).is_empty());
+    }
+
+    #[test]
+    fn test_group_rings() {
+        let square = |from: f64, to: f64| vec![(from, from), (to, from), (to, to), (from, to), (from, from)];
+        // An outer ring with a hole that has an island in it, and a separate outer ring.
+        let rings = vec![
+            square(1.0, 2.0),
+            square(0.0, 10.0),
+            square(3.0, 7.0),
+            square(4.0, 6.0),
+            square(20.0, 30.0),
+        ];
+        assert_eq!(
+            group_rings(&rings),
+            vec![(1, vec![0, 2]), (3, Vec::new()), (4, Vec::new())]
+        );
+    }
+}
@@ -1,7 +1,15 @@
 use crate::coords;
+use crate::coords::Coords;
+use crate::geodata::baked::{bake_tiles, BakeOptions};
+use crate::geodata::drop_report::DropReport;
 use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
-use crate::geodata::saver::save_to_internal_format;
+use crate::geodata::reader::{GeodataReader, OsmEntity, Tags};
+use crate::geodata::saver::{is_poi, save_to_internal_format};
+use crate::geodata::timestamp::parse_timestamp;
+use crate::progress::{Progress, ProgressFormat};
 use anyhow::{anyhow, bail, Context, Result};
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
 #[cfg(feature = "pbf")]
 use osmpbf::{Element, ElementReader, RelMemberType};
 use quick_xml::events::attributes::Attributes;
@@ -11,58 +19,426 @@ use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
+use std::mem;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-pub fn import<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
-    let output_file = File::create(output.as_ref()).context(format!(
-        "Failed to open {} for writing",
-        output.as_ref().to_string_lossy()
-    ))?;
-    let mut writer = BufWriter::new(output_file);
+#[derive(Default)]
+pub struct ImportOptions {
+    /// Store the nodes without tags (usually the vertices of ways) as separate entities, so that they can be
+    /// rendered on their own, e.g. for editing or QA purposes. Makes the output file noticeably larger.
+    pub keep_untagged_nodes: bool,
+    /// Store when every version of an entity appeared and disappeared, so that the map can be rendered
+    /// as of a given date. This only makes sense for the files with the full history of the entities.
+    pub keep_timestamps: bool,
+    /// Store the grids of the small landcover areas (see `landcover`) for the low zoom levels, which then
+    /// draw them instead of the areas themselves.
+    pub landcover: bool,
+    /// Draw the tiles of the low zoom levels right away and store them too.
+    pub bake: Option<BakeOptions>,
+    /// How the coordinates of the nodes are stored.
+    pub coordinates: CoordinatePrecision,
+}
+
+/// The nodes take most of the geodata, and most of a node is its coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoordinatePrecision {
+    /// As 32-bit integers, in the units of 10^-N degrees (N is at most `MAX_COORDINATE_DIGITS`). The OSM data
+    /// itself has 7 digits (about a centimeter), so the default loses nothing while taking half the space of
+    /// the floats. 5 digits (about a meter) are still finer than a pixel of the tiles at `MAX_ZOOM`.
+    Digits(u8),
+    /// As 64-bit floats, exactly as they were parsed.
+    Lossless,
+}
+
+pub const MAX_COORDINATE_DIGITS: u8 = 7;
+
+// The value that marks the floats in the geodata file, instead of the number of digits.
+const LOSSLESS_COORDINATES_HEADER: u32 = u32::MAX;
+
+impl Default for CoordinatePrecision {
+    fn default() -> CoordinatePrecision {
+        CoordinatePrecision::Digits(MAX_COORDINATE_DIGITS)
+    }
+}
+
+impl CoordinatePrecision {
+    /// The number of the units in a degree, if the coordinates are integers.
+    pub(super) fn units_per_degree(self) -> Option<f64> {
+        match self {
+            CoordinatePrecision::Digits(digits) => Some(10f64.powi(i32::from(digits))),
+            CoordinatePrecision::Lossless => None,
+        }
+    }
+
+    // Dividing the integers (instead of multiplying by a fraction) gives the exact floats for the coordinates
+    // that didn't have more digits to begin with.
+    fn quantize(self, value: f64) -> f64 {
+        match self.units_per_degree() {
+            Some(units) => (value * units).round() / units,
+            None => value,
+        }
+    }
+
+    pub(super) fn to_header(self) -> u32 {
+        match self {
+            CoordinatePrecision::Digits(digits) => u32::from(digits),
+            CoordinatePrecision::Lossless => LOSSLESS_COORDINATES_HEADER,
+        }
+    }
 
-    let parsed = match input.as_ref().extension().and_then(OsStr::to_str) {
-        Some("osm") | Some("xml") => {
-            let input_file = File::open(input.as_ref()).context(format!(
-                "Failed to open {} for reading",
-                input.as_ref().to_string_lossy()
-            ))?;
-            let parser = Reader::from_reader(BufReader::new(input_file));
-            parse_osm_xml(parser)?
+    pub(super) fn from_header(header: u32) -> CoordinatePrecision {
+        match header {
+            LOSSLESS_COORDINATES_HEADER => CoordinatePrecision::Lossless,
+            digits => CoordinatePrecision::Digits(digits as u8),
         }
+    }
+}
+
+pub fn import<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
+    import_with_progress(
+        input,
+        output,
+        &ImportOptions::default(),
+        &mut Progress::new(ProgressFormat::Text),
+    )
+}
+
+enum InputFormat {
+    Xml,
+    #[cfg(feature = "pbf")]
+    Pbf,
+}
+
+enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+}
+
+// Guesses the format from the file name, e.g. `city.osm.bz2` is bzip2-compressed XML.
+fn detect_input_format(input: &Path) -> Result<(InputFormat, Compression)> {
+    let extension = |path: &Path| path.extension().and_then(OsStr::to_str).map(str::to_lowercase);
+    let (compression, uncompressed_name) = match extension(input).as_deref() {
+        Some("gz") => (Compression::Gzip, input.with_extension("")),
+        Some("bz2") => (Compression::Bzip2, input.with_extension("")),
+        _ => (Compression::None, input.to_path_buf()),
+    };
+
+    match (extension(&uncompressed_name).as_deref(), compression) {
+        (Some("osm") | Some("osc") | Some("xml"), compression) => Ok((InputFormat::Xml, compression)),
         #[cfg(feature = "pbf")]
-        Some("pbf") => parse_pbf(input)?,
+        (Some("pbf"), Compression::None) => Ok((InputFormat::Pbf, Compression::None)),
         _ => bail!("Extension not supported"),
+    }
+}
+
+// Data coming from stdin has no name, so we have to look at the first bytes to tell what it is.
+fn sniff_input_format(header: &[u8]) -> Result<(InputFormat, Compression)> {
+    if header.starts_with(&[0x1f, 0x8b]) {
+        return Ok((InputFormat::Xml, Compression::Gzip));
+    }
+    if header.starts_with(b"BZh") {
+        return Ok((InputFormat::Xml, Compression::Bzip2));
+    }
+    match header.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'<') => Ok((InputFormat::Xml, Compression::None)),
+        // A PBF file starts with the big-endian length of the first blob header, which is always small.
+        #[cfg(feature = "pbf")]
+        Some(0) => Ok((InputFormat::Pbf, Compression::None)),
+        _ => bail!("Failed to recognize the format of the input data"),
+    }
+}
+
+// `-` stands for stdin, and URLs are downloaded while being imported. Everything else is a file name.
+fn open_input(input: &Path) -> Result<(Box<dyn Read + Send>, Option<u64>)> {
+    let input_name = input.to_string_lossy();
+    if input_name == "-" {
+        return Ok((Box::new(std::io::stdin()), None));
+    }
+
+    if input_name.starts_with("http://") || input_name.starts_with("https://") {
+        let response = ureq::get(input_name.as_ref())
+            .call()
+            .context(format!("Failed to download {}", input_name))?;
+        let input_size = response.header("Content-Length").and_then(|len| len.parse().ok());
+        return Ok((Box::new(response.into_reader()), input_size));
+    }
+
+    let input_file = File::open(input).context(format!("Failed to open {} for reading", input_name))?;
+    let input_size = input_file.metadata().ok().map(|m| m.len());
+    Ok((Box::new(input_file), input_size))
+}
+
+// Counts the bytes that were read from the inner reader so far.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+pub fn import_with_progress<P: AsRef<Path>>(
+    input: P,
+    output: P,
+    options: &ImportOptions,
+    progress: &mut Progress,
+) -> Result<()> {
+    if let CoordinatePrecision::Digits(digits) = options.coordinates {
+        if digits > MAX_COORDINATE_DIGITS {
+            bail!(
+                "The coordinates can't have more than {} digits after the decimal point",
+                MAX_COORDINATE_DIGITS
+            );
+        }
+    }
+    let input = input.as_ref();
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let (format, decompressed, input_size) = open_input_data(input, &bytes_read)?;
+    let mut writer = create_output(output.as_ref())?;
+
+    let parsed = match format {
+        InputFormat::Xml => {
+            let parser = Reader::from_reader(BufReader::new(decompressed));
+            parse_osm_xml(parser, input_size, &bytes_read, options, progress)?
+        }
+        #[cfg(feature = "pbf")]
+        InputFormat::Pbf => parse_pbf(decompressed, input_size, &bytes_read, options, progress)?,
+    };
+
+    save(&mut writer, output.as_ref(), &parsed, options, progress)
+}
+
+/// Applies the changes to the geodata that was imported before, and writes the updated geodata to `output` (which
+/// can then be moved over the old file, like a new import). The changes are osmChange files (`.osc`, compressed or
+/// not), e.g. the minutely or the daily diffs of the OSM replication, and they're applied in the given order.
+/// The geodata keeps the options it was imported with, apart from `bake`.
+///
+/// The geodata doesn't know which ways the multipolygons were made of, so a multipolygon only changes its shape
+/// when the relation itself is changed (the moved nodes are followed, though). The geodata imported with
+/// the timestamps can't be updated.
+pub fn update_with_progress<P: AsRef<Path>, C: AsRef<Path>>(
+    geodata: P,
+    changes: &[C],
+    output: P,
+    bake: Option<BakeOptions>,
+    progress: &mut Progress,
+) -> Result<()> {
+    let geodata = geodata.as_ref().to_string_lossy();
+    let reader = GeodataReader::load(&geodata)?;
+    if reader.has_lifetimes() {
+        bail!("The geodata imported with the timestamps can't be updated, it has to be imported again");
+    }
+    let options = ImportOptions {
+        keep_untagged_nodes: reader.all_pois().any(|poi| poi.tags().is_empty()),
+        keep_timestamps: false,
+        landcover: reader.has_landcover(),
+        bake,
+        coordinates: reader.coordinates(),
+    };
+    let mut writer = create_output(output.as_ref())?;
+
+    progress.message(&format!("Reading the geodata from {}", geodata));
+    let mut entity_storages = EntityStorages::from_geodata(&reader, &options);
+    for change in changes {
+        let change = change.as_ref();
+        progress.message(&format!("Applying the changes from {}", change.to_string_lossy()));
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let (format, decompressed, input_size) = open_input_data(change, &bytes_read)?;
+        if !matches!(format, InputFormat::Xml) {
+            bail!("The changes have to be in the osmChange XML format");
+        }
+        let parser = Reader::from_reader(BufReader::new(decompressed));
+        read_osm_xml(parser, &mut entity_storages, input_size, &bytes_read, progress)?;
+    }
+    entity_storages.remove_outdated();
+    entity_storages.remove_unused_polygons();
+    entity_storages.replace_outdated_nodes();
+    print_storage_stats(&entity_storages, progress);
+
+    save(&mut writer, output.as_ref(), &entity_storages, &options, progress)
+}
+
+// Opens the input (see `open_input`) and tells what's in it. The progress is measured in the bytes of the input
+// as it is stored or transferred, not in the decompressed bytes, since we only know the size of the former
+// in advance.
+fn open_input_data(
+    input: &Path,
+    bytes_read: &Arc<AtomicU64>,
+) -> Result<(InputFormat, Box<dyn Read + Send>, Option<u64>)> {
+    let (input_reader, input_size) = open_input(input)?;
+    let mut input_reader = BufReader::new(CountingReader {
+        inner: input_reader,
+        count: bytes_read.clone(),
+    });
+
+    let (format, compression) = if input == Path::new("-") {
+        sniff_input_format(input_reader.fill_buf().context("Failed to read the input data")?)?
+    } else {
+        // Ignore the query string and the fragment if the input is a URL.
+        let name = input.to_string_lossy();
+        let name = name.split(['?', '#']).next().unwrap_or_default();
+        detect_input_format(Path::new(name))?
+    };
+
+    let decompressed: Box<dyn Read + Send> = match compression {
+        Compression::None => Box::new(input_reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(input_reader)),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(input_reader)),
     };
+    Ok((format, decompressed, input_size))
+}
+
+// The output is created before the input is parsed, so that a wrong path doesn't waste the whole import.
+fn create_output(output: &Path) -> Result<BufWriter<File>> {
+    let output_file =
+        File::create(output).context(format!("Failed to open {} for writing", output.to_string_lossy()))?;
+    Ok(BufWriter::new(output_file))
+}
 
-    println!("Converting geodata to internal format");
-    save_to_internal_format(&mut writer, &parsed).context("Failed to write the imported data to the output file")?;
+fn save(
+    writer: &mut BufWriter<File>,
+    output: &Path,
+    parsed: &EntityStorages,
+    options: &ImportOptions,
+    progress: &mut Progress,
+) -> Result<()> {
+    progress.message("Converting geodata to internal format");
+    let baked_tiles = match &options.bake {
+        // The tiles are drawn from the geodata exactly as it's going to be, so it's saved without them first.
+        Some(bake) => {
+            let unbaked_output = output.with_extension("unbaked");
+            let mut unbaked_writer = BufWriter::new(File::create(&unbaked_output).context(format!(
+                "Failed to open {} for writing",
+                unbaked_output.to_string_lossy()
+            ))?);
+            save_to_internal_format(
+                &mut unbaked_writer,
+                parsed,
+                options.keep_untagged_nodes,
+                options.landcover,
+                None,
+            )
+            .and_then(|_| Ok(unbaked_writer.flush()?))
+            .context("Failed to write the imported data to the output file")?;
+            drop(unbaked_writer);
+            let baked_tiles = bake_tiles(&unbaked_output, bake, progress);
+            let _ = fs::remove_file(&unbaked_output);
+            Some(baked_tiles?)
+        }
+        None => None,
+    };
+    save_to_internal_format(
+        writer,
+        parsed,
+        options.keep_untagged_nodes,
+        options.landcover,
+        baked_tiles.as_ref(),
+    )
+    .and_then(|_| Ok(writer.flush()?))
+    .context("Failed to write the imported data to the output file")?;
     Ok(())
 }
 
 pub(super) struct OsmEntityStorage<E: Default> {
     global_id_to_local_id: HashMap<u64, usize>,
     entities: Vec<E>,
+    // Only present if we keep the timestamps.
+    lifetimes: Option<Vec<Lifetime>>,
+    // The versions that were replaced by the next one or deleted.
+    outdated: Vec<bool>,
+}
+
+/// The UNIX timestamps of the moments when a version of an entity appeared and when it was
+/// replaced by the next version or deleted.
+#[derive(Clone, Copy)]
+pub(super) struct Lifetime {
+    pub(super) from: i64,
+    pub(super) until: i64,
 }
 
 impl<E: Default> OsmEntityStorage<E> {
-    fn new() -> OsmEntityStorage<E> {
+    fn new(keep_lifetimes: bool) -> OsmEntityStorage<E> {
         OsmEntityStorage {
             global_id_to_local_id: HashMap::new(),
             entities: Vec::new(),
+            lifetimes: if keep_lifetimes { Some(Vec::new()) } else { None },
+            outdated: Vec::new(),
         }
     }
 
-    fn add(&mut self, global_id: u64, entity: E) {
+    // The entities without a timestamp are considered to always have existed. Adding a new version of
+    // an entity ends the lifetime of the previous one, but both versions are stored.
+    fn add(&mut self, global_id: u64, entity: E, timestamp: Option<i64>) {
+        self.end_lifetime(global_id, timestamp);
+        if let Some(lifetimes) = self.lifetimes.as_mut() {
+            lifetimes.push(Lifetime {
+                from: timestamp.unwrap_or(i64::MIN),
+                until: i64::MAX,
+            });
+        }
+
         let old_size = self.entities.len();
         self.global_id_to_local_id.insert(global_id, old_size);
         self.entities.push(entity);
+        self.outdated.push(false);
     }
 
+    // Called for the deleted entities (`visible="false"`) as well. An entity can be deleted and then
+    // recreated later, in which case it's added again.
+    fn end_lifetime(&mut self, global_id: u64, timestamp: Option<i64>) {
+        let Some(&local_id) = self.global_id_to_local_id.get(&global_id) else {
+            return;
+        };
+        self.outdated[local_id] = true;
+        if let (Some(lifetimes), Some(timestamp)) = (self.lifetimes.as_mut(), timestamp) {
+            let lifetime = &mut lifetimes[local_id];
+            lifetime.until = lifetime.until.min(timestamp);
+        }
+    }
+
+    /// Whether the version is drawn: with the timestamps, every version is (at its own dates), and without
+    /// them only the current versions are.
+    pub(super) fn is_drawn(&self, local_id: usize) -> bool {
+        self.lifetimes.is_some() || !self.outdated[local_id]
+    }
+
+    // Without the timestamps, the versions that aren't drawn are of no use, unless something refers to them
+    // by their local IDs (like the ways do to the nodes).
+    fn remove_outdated(&mut self) {
+        if self.lifetimes.is_some() {
+            return;
+        }
+        let mut local_id = 0;
+        self.entities.retain(|_| {
+            local_id += 1;
+            !self.outdated[local_id - 1]
+        });
+        self.outdated = vec![false; self.entities.len()];
+        self.global_id_to_local_id.clear();
+    }
+
+    pub(super) fn get_lifetimes(&self) -> Option<&Vec<Lifetime>> {
+        self.lifetimes.as_ref()
+    }
+
+    // Without the timestamps, the references to the deleted entities are dropped (so a way doesn't keep a node
+    // that's gone). With them, the last known version is used, since the history files list all the versions
+    // of the nodes before the ways, and the ways that were drawn before the deletion need their nodes.
     fn translate_id(&self, global_id: u64) -> Option<usize> {
-        self.global_id_to_local_id.get(&global_id).cloned()
+        let local_id = *self.global_id_to_local_id.get(&global_id)?;
+        Some(local_id).filter(|&id| self.is_drawn(id))
     }
 
     pub(super) fn get_entities(&self) -> &Vec<E> {
@@ -75,33 +451,225 @@ pub(super) struct EntityStorages {
     pub(super) way_storage: OsmEntityStorage<RawWay>,
     pub(super) polygon_storage: Vec<Polygon>,
     pub(super) multipolygon_storage: OsmEntityStorage<Multipolygon>,
+    pub(super) coordinates: CoordinatePrecision,
+}
+
+impl EntityStorages {
+    fn new(options: &ImportOptions) -> EntityStorages {
+        EntityStorages {
+            node_storage: OsmEntityStorage::new(options.keep_timestamps),
+            way_storage: OsmEntityStorage::new(options.keep_timestamps),
+            polygon_storage: Vec::new(),
+            multipolygon_storage: OsmEntityStorage::new(options.keep_timestamps),
+            coordinates: options.coordinates,
+        }
+    }
+
+    // The coordinates are rounded right away, so that everything computed during the import (like the tiles
+    // that the entities are in) agrees with what's saved.
+    fn add_node(&mut self, mut node: RawNode, timestamp: Option<i64>) {
+        node.lat = self.coordinates.quantize(node.lat);
+        node.lon = self.coordinates.quantize(node.lon);
+        self.node_storage.add(node.global_id, node, timestamp);
+    }
+
+    fn keep_timestamps(&self) -> bool {
+        self.node_storage.lifetimes.is_some()
+    }
+
+    // Only the relations that make valid polygons are kept. Returns whether the relation was added.
+    fn add_multipolygon(&mut self, relation: RawRelation, timestamp: Option<i64>) -> bool {
+        if !is_polygon_relation(&relation.tags) {
+            return false;
+        }
+        let segments = relation.to_segments(self);
+        let polygons = match find_polygons_in_multipolygon(relation.global_id, &segments) {
+            Some(polygons) => polygons,
+            None => return false,
+        };
+        if relation.duplicates_member_way(&polygons, self) {
+            return false;
+        }
+        let mut multipolygon = Multipolygon {
+            global_id: relation.global_id,
+            polygon_ids: Vec::new(),
+            tags: relation.tags,
+        };
+        for poly in polygons {
+            multipolygon.polygon_ids.push(self.polygon_storage.len());
+            self.polygon_storage.push(poly);
+        }
+        self.multipolygon_storage
+            .add(relation.global_id, multipolygon, timestamp);
+        true
+    }
+
+    // Nothing refers to the ways and the multipolygons by their local IDs once everything is parsed (the polygons
+    // of the multipolygons refer to the nodes directly).
+    fn remove_outdated(&mut self) {
+        self.way_storage.remove_outdated();
+        self.multipolygon_storage.remove_outdated();
+    }
+
+    // The entities are added in the order they were saved in, so that the references between them stay the same.
+    fn from_geodata(reader: &GeodataReader<'_>, options: &ImportOptions) -> EntityStorages {
+        let mut entity_storages = EntityStorages::new(options);
+        let raw_tags = |tags: Tags<'_>| {
+            tags.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<RawTags>()
+        };
+
+        let poi_ids = reader.all_pois().map(|poi| poi.global_id()).collect::<HashSet<_>>();
+        for node in reader.all_nodes() {
+            let node = RawNode {
+                global_id: node.global_id(),
+                lat: node.lat(),
+                lon: node.lon(),
+                tags: raw_tags(node.tags()),
+            };
+            // The nodes that would be POIs but aren't were deleted, and are only there for the ways that still
+            // refer to them.
+            let is_deleted = (options.keep_untagged_nodes || is_poi(&node)) && !poi_ids.contains(&node.global_id);
+            entity_storages.add_node(node, None);
+            if is_deleted {
+                *entity_storages.node_storage.outdated.last_mut().unwrap() = true;
+            }
+        }
+        for way in reader.all_ways() {
+            let way = RawWay {
+                global_id: way.global_id(),
+                node_ids: way.node_ids().iter().map(|&id| id as usize).collect(),
+                tags: raw_tags(way.tags()),
+            };
+            entity_storages.way_storage.add(way.global_id, way, None);
+        }
+        for polygon in reader.all_polygons() {
+            let polygon = polygon.node_ids().iter().map(|&id| id as usize).collect();
+            entity_storages.polygon_storage.push(polygon);
+        }
+        for multipolygon in reader.all_multipolygons() {
+            let multipolygon = Multipolygon {
+                global_id: multipolygon.global_id(),
+                polygon_ids: multipolygon.polygon_ids().iter().map(|&id| id as usize).collect(),
+                tags: raw_tags(multipolygon.tags()),
+            };
+            entity_storages
+                .multipolygon_storage
+                .add(multipolygon.global_id, multipolygon, None);
+        }
+        entity_storages
+    }
+
+    // The polygons of the changed and the deleted multipolygons.
+    fn remove_unused_polygons(&mut self) {
+        let mut new_ids = vec![None; self.polygon_storage.len()];
+        let mut polygons = Vec::new();
+        for multipolygon in &mut self.multipolygon_storage.entities {
+            for polygon_id in &mut multipolygon.polygon_ids {
+                let new_id = new_ids[*polygon_id].get_or_insert_with(|| {
+                    polygons.push(mem::take(&mut self.polygon_storage[*polygon_id]));
+                    polygons.len() - 1
+                });
+                *polygon_id = *new_id;
+            }
+        }
+        self.polygon_storage = polygons;
+    }
+
+    // The ways and the polygons that weren't changed still refer to the previous versions of the nodes that were,
+    // so they're pointed to the current versions instead. The versions that nothing refers to anymore are dropped.
+    fn replace_outdated_nodes(&mut self) {
+        let node_storage = &mut self.node_storage;
+        let node_count = node_storage.entities.len();
+        let current_ids = node_storage
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(local_id, node)| {
+                node_storage
+                    .global_id_to_local_id
+                    .get(&node.global_id)
+                    .copied()
+                    .unwrap_or(local_id)
+            })
+            .collect::<Vec<_>>();
+        let mut is_used = node_storage
+            .outdated
+            .iter()
+            .map(|outdated| !outdated)
+            .collect::<Vec<_>>();
+        for node_id in node_refs_mut(&mut self.way_storage.entities, &mut self.polygon_storage) {
+            *node_id = current_ids[*node_id];
+            is_used[*node_id] = true;
+        }
+
+        let mut new_ids = vec![0; node_count];
+        let mut used_count = 0;
+        for (local_id, new_id) in new_ids.iter_mut().enumerate() {
+            *new_id = used_count;
+            used_count += usize::from(is_used[local_id]);
+        }
+        for node_id in node_refs_mut(&mut self.way_storage.entities, &mut self.polygon_storage) {
+            *node_id = new_ids[*node_id];
+        }
+        let mut local_id = 0;
+        node_storage.entities.retain(|_| {
+            local_id += 1;
+            is_used[local_id - 1]
+        });
+        let mut local_id = 0;
+        node_storage.outdated.retain(|_| {
+            local_id += 1;
+            is_used[local_id - 1]
+        });
+        node_storage.global_id_to_local_id.clear();
+    }
 }
 
-fn print_storage_stats(entity_storages: &EntityStorages) {
-    println!(
-        "Got {} nodes, {} ways and {} multipolygon relations so far",
+fn node_refs_mut<'a>(ways: &'a mut [RawWay], polygons: &'a mut [Polygon]) -> impl Iterator<Item = &'a mut usize> {
+    ways.iter_mut()
+        .map(|way| &mut way.node_ids)
+        .chain(polygons.iter_mut())
+        .flat_map(|refs| refs.iter_mut())
+}
+
+fn print_storage_stats(entity_storages: &EntityStorages, progress: &Progress) {
+    progress.message(&format!(
+        "Got {} nodes, {} ways and {} multipolygon relations",
         entity_storages.node_storage.entities.len(),
         entity_storages.way_storage.entities.len(),
         entity_storages.multipolygon_storage.entities.len()
-    );
+    ));
 }
 
 #[cfg(feature = "pbf")]
-fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+fn parse_pbf<R: Read + Send>(
+    input: R,
+    input_size: Option<u64>,
+    bytes_read: &AtomicU64,
+    options: &ImportOptions,
+    progress: &mut Progress,
+) -> Result<EntityStorages> {
+    let mut entity_storages = EntityStorages::new(options);
 
     let mut elem_count = 0;
-    println!("Parsing PBF");
+    let mut report = DropReport::default();
+    progress.start_stage("Parsing PBF", "elements", input_size, 0);
 
-    let reader = ElementReader::from_path(input)?;
+    let reader = ElementReader::new(input);
     reader.for_each(|element| {
         match element {
             Element::DenseNode(el_node) => {
+                let info = el_node.info();
+                let timestamp = info.map(|i| i.milli_timestamp() / 1000);
+                elem_count += 1;
+                if !info.is_none_or(|i| i.visible()) {
+                    entity_storages
+                        .node_storage
+                        .end_lifetime(el_node.id() as u64, timestamp);
+                    return;
+                }
                 let mut node = RawNode {
                     global_id: el_node.id() as u64,
                     lat: el_node.lat(),
@@ -111,10 +679,17 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                 for (key, value) in el_node.tags() {
                     node.tags.insert(key.to_string(), value.to_string());
                 }
-                elem_count += 1;
-                entity_storages.node_storage.add(node.global_id, node);
+                if has_valid_coords(&node, &mut report) {
+                    entity_storages.add_node(node, timestamp);
+                }
             }
             Element::Way(el_way) => {
+                let timestamp = el_way.info().milli_timestamp().map(|t| t / 1000);
+                elem_count += 1;
+                if !el_way.info().visible() {
+                    entity_storages.way_storage.end_lifetime(el_way.id() as u64, timestamp);
+                    return;
+                }
                 let mut way = RawWay {
                     global_id: el_way.id() as u64,
                     node_ids: RawRefs::default(),
@@ -124,15 +699,22 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                     way.tags.insert(key.to_string(), value.to_string());
                 }
                 for r in el_way.refs() {
-                    if let Some(local_id) = entity_storages.node_storage.translate_id(r as u64) {
-                        way.node_ids.push(local_id);
+                    match entity_storages.node_storage.translate_id(r as u64) {
+                        Some(local_id) => way.node_ids.push(local_id),
+                        None => report.skip_missing_ref("way", "node"),
                     }
                 }
                 postprocess_node_refs(&mut way.node_ids);
-                elem_count += 1;
-                entity_storages.way_storage.add(way.global_id, way);
+                entity_storages.way_storage.add(way.global_id, way, timestamp);
             }
             Element::Relation(el_rel) => {
+                let timestamp = el_rel.info().milli_timestamp().map(|t| t / 1000);
+                entity_storages
+                    .multipolygon_storage
+                    .end_lifetime(el_rel.id() as u64, timestamp);
+                if !el_rel.info().visible() {
+                    return;
+                }
                 let mut relation = RawRelation {
                     global_id: el_rel.id() as u64,
                     way_refs: Vec::<RelationWayRef>::default(),
@@ -143,57 +725,63 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                 }
                 for way in el_rel.members() {
                     if way.member_type == RelMemberType::Way {
-                        if let Some(local_id) = entity_storages.way_storage.translate_id(way.member_id as u64) {
-                            let is_inner = way.role().unwrap() == "inner";
-                            relation.way_refs.push(RelationWayRef {
-                                way_id: local_id,
-                                is_inner,
-                            });
+                        match entity_storages.way_storage.translate_id(way.member_id as u64) {
+                            Some(local_id) => {
+                                let is_inner = way.role().is_ok_and(|role| role == "inner");
+                                relation.way_refs.push(RelationWayRef {
+                                    way_id: local_id,
+                                    is_inner,
+                                });
+                            }
+                            None => report.skip_missing_ref("relation", "way"),
                         }
                     }
                 }
-                if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                    let segments = relation.to_segments(&entity_storages);
-                    if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                        let mut multipolygon = Multipolygon {
-                            global_id: relation.global_id,
-                            polygon_ids: Vec::new(),
-                            tags: relation.tags,
-                        };
-                        for poly in polygons {
-                            multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                            entity_storages.polygon_storage.push(poly);
-                        }
-                        elem_count += 1;
-                        entity_storages
-                            .multipolygon_storage
-                            .add(relation.global_id, multipolygon);
-                    }
+                if entity_storages.add_multipolygon(relation, timestamp) {
+                    elem_count += 1;
                 }
             }
             Element::Node(_) => panic!(),
         }
-        if elem_count % 100_000 == 0 {
-            print_storage_stats(&entity_storages);
-        }
+        progress.update(bytes_read.load(Ordering::Relaxed), elem_count);
     })?;
 
-    print_storage_stats(&entity_storages);
+    progress.finish_stage(bytes_read.load(Ordering::Relaxed), elem_count);
+    entity_storages.remove_outdated();
+    print_storage_stats(&entity_storages, progress);
+    report.print(progress);
 
     Ok(entity_storages)
 }
 
-fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+fn parse_osm_xml<R: BufRead>(
+    parser: Reader<R>,
+    input_size: Option<u64>,
+    bytes_read: &AtomicU64,
+    options: &ImportOptions,
+    progress: &mut Progress,
+) -> Result<EntityStorages> {
+    let mut entity_storages = EntityStorages::new(options);
+    read_osm_xml(parser, &mut entity_storages, input_size, bytes_read, progress)?;
+    entity_storages.remove_outdated();
+    print_storage_stats(&entity_storages, progress);
+    Ok(entity_storages)
+}
 
+// Adds the entities to the storages. The osmChange files (see `update_with_progress`) have the same entities,
+// wrapped in `<create>`, `<modify>` and `<delete>`.
+fn read_osm_xml<R: BufRead>(
+    mut parser: Reader<R>,
+    entity_storages: &mut EntityStorages,
+    input_size: Option<u64>,
+    bytes_read: &AtomicU64,
+    progress: &mut Progress,
+) -> Result<()> {
     let mut elem_count = 0;
+    let mut report = DropReport::default();
+    let mut is_deleting = false;
 
-    println!("Parsing XML");
+    progress.start_stage("Parsing XML", "elements", input_size, 0);
     let mut buf = Vec::new();
     loop {
         let e = parser
@@ -204,28 +792,30 @@ fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
                 &mut parser,
                 start.local_name().as_ref(),
                 &mut start.attributes(),
-                &mut entity_storages,
+                entity_storages,
+                &mut report,
                 have_subelements,
+                is_deleting,
             )?;
             elem_count += 1;
-            if elem_count % 100_000 == 0 {
-                print_storage_stats(&entity_storages);
-            }
             Ok(())
         };
         match e {
             Event::Eof => break,
+            Event::Start(start) if start.local_name().as_ref() == b"delete" => is_deleting = true,
+            Event::End(end) if end.local_name().as_ref() == b"delete" => is_deleting = false,
             Event::Start(start) => on_elem(start, true)?,
             Event::Empty(start) => on_elem(start, false)?,
             _ => {}
         }
+        progress.update(bytes_read.load(Ordering::Relaxed), elem_count);
         // The official `quick-xml` examples suggests we do this to save memory.
         buf.clear();
     }
 
-    print_storage_stats(&entity_storages);
-
-    Ok(entity_storages)
+    progress.finish_stage(bytes_read.load(Ordering::Relaxed), elem_count);
+    report.print(progress);
+    Ok(())
 }
 
 fn process_element<R: BufRead>(
@@ -233,80 +823,174 @@ fn process_element<R: BufRead>(
     name: &[u8],
     attrs: &mut Attributes,
     entity_storages: &mut EntityStorages,
+    report: &mut DropReport,
     have_subelements: bool,
+    is_deleted: bool,
 ) -> Result<()> {
-    match name {
-        b"node" => {
-            let mut node = RawNode {
-                global_id: get_id(parser, name, attrs)?,
-                lat: parse_required_attr(parser, name, attrs, b"lat")?,
-                lon: parse_required_attr(parser, name, attrs, b"lon")?,
-                tags: RawTags::default(),
-            };
-            if have_subelements {
-                process_subelements(name, &mut node, entity_storages, process_node_subelement, parser)?;
-            }
-            entity_storages.node_storage.add(node.global_id, node);
-        }
-        b"way" => {
-            let mut way = RawWay {
-                global_id: get_id(parser, name, attrs)?,
-                node_ids: RawRefs::default(),
-                tags: RawTags::default(),
-            };
-            if have_subelements {
-                process_subelements(name, &mut way, entity_storages, process_way_subelement, parser)?;
-            }
-            postprocess_node_refs(&mut way.node_ids);
-            entity_storages.way_storage.add(way.global_id, way);
-        }
-        b"relation" => {
-            let mut relation = RawRelation {
-                global_id: get_id(parser, name, attrs)?,
-                way_refs: Vec::<RelationWayRef>::default(),
-                tags: RawTags::default(),
-            };
-            if have_subelements {
-                process_subelements(
-                    name,
-                    &mut relation,
-                    entity_storages,
-                    process_relation_subelement,
-                    parser,
-                )?;
-            }
-            if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                let segments = relation.to_segments(entity_storages);
-                if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                    let mut multipolygon = Multipolygon {
-                        global_id: relation.global_id,
-                        polygon_ids: Vec::new(),
-                        tags: relation.tags,
-                    };
-                    for poly in polygons {
-                        multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                        entity_storages.polygon_storage.push(poly);
-                    }
-                    entity_storages
-                        .multipolygon_storage
-                        .add(relation.global_id, multipolygon);
-                }
-            }
+    let (kind, res) = match name {
+        b"node" => (
+            "node",
+            process_node(parser, attrs, entity_storages, report, have_subelements, is_deleted),
+        ),
+        b"way" => (
+            "way",
+            process_way(parser, attrs, entity_storages, report, have_subelements, is_deleted),
+        ),
+        b"relation" => (
+            "relation",
+            process_relation(parser, attrs, entity_storages, report, have_subelements, is_deleted),
+        ),
+        _ => return Ok(()),
+    };
+
+    // If we can't make sense of a single entity, we skip it and keep going. Its remaining subelements
+    // (if any) are going to be ignored by the caller, since they aren't nodes, ways or relations.
+    // Errors in the XML structure itself are still fatal, though.
+    match res {
+        Err(e) if !is_broken_xml(&e) => {
+            let details = e.chain().map(|c| c.to_string()).collect::<Vec<_>>().join(": ");
+            report.drop_entity(kind, "malformed data", details);
+            Ok(())
         }
-        _ => {}
+        res => res,
+    }
+}
+
+fn process_node<R: BufRead>(
+    parser: &mut Reader<R>,
+    attrs: &mut Attributes,
+    entity_storages: &mut EntityStorages,
+    report: &mut DropReport,
+    have_subelements: bool,
+    is_deleted: bool,
+) -> Result<()> {
+    let name = b"node";
+    let version = get_version_info(parser, name, attrs, entity_storages.keep_timestamps(), is_deleted)?;
+    if !version.visible {
+        let global_id = get_id(parser, name, attrs)?;
+        entity_storages.node_storage.end_lifetime(global_id, version.timestamp);
+        return Ok(());
+    }
+    let mut node = RawNode {
+        global_id: get_id(parser, name, attrs)?,
+        lat: parse_required_attr(parser, name, attrs, b"lat")?,
+        lon: parse_required_attr(parser, name, attrs, b"lon")?,
+        tags: RawTags::default(),
+    };
+    if have_subelements {
+        process_subelements(
+            name,
+            &mut node,
+            entity_storages,
+            report,
+            process_node_subelement,
+            parser,
+        )
+        .context(format!("Failed to process node {}", node.global_id))?;
+    }
+    if has_valid_coords(&node, report) {
+        entity_storages.add_node(node, version.timestamp);
+    }
+    Ok(())
+}
+
+fn process_way<R: BufRead>(
+    parser: &mut Reader<R>,
+    attrs: &mut Attributes,
+    entity_storages: &mut EntityStorages,
+    report: &mut DropReport,
+    have_subelements: bool,
+    is_deleted: bool,
+) -> Result<()> {
+    let name = b"way";
+    let version = get_version_info(parser, name, attrs, entity_storages.keep_timestamps(), is_deleted)?;
+    if !version.visible {
+        let global_id = get_id(parser, name, attrs)?;
+        entity_storages.way_storage.end_lifetime(global_id, version.timestamp);
+        return Ok(());
+    }
+    let mut way = RawWay {
+        global_id: get_id(parser, name, attrs)?,
+        node_ids: RawRefs::default(),
+        tags: RawTags::default(),
+    };
+    if have_subelements {
+        process_subelements(name, &mut way, entity_storages, report, process_way_subelement, parser)
+            .context(format!("Failed to process way {}", way.global_id))?;
     }
+    postprocess_node_refs(&mut way.node_ids);
+    entity_storages.way_storage.add(way.global_id, way, version.timestamp);
     Ok(())
 }
 
+fn process_relation<R: BufRead>(
+    parser: &mut Reader<R>,
+    attrs: &mut Attributes,
+    entity_storages: &mut EntityStorages,
+    report: &mut DropReport,
+    have_subelements: bool,
+    is_deleted: bool,
+) -> Result<()> {
+    let name = b"relation";
+    let version = get_version_info(parser, name, attrs, entity_storages.keep_timestamps(), is_deleted)?;
+    let mut relation = RawRelation {
+        global_id: get_id(parser, name, attrs)?,
+        way_refs: Vec::<RelationWayRef>::default(),
+        tags: RawTags::default(),
+    };
+    // Not every version of a relation is a valid multipolygon, so the previous one ends here regardless.
+    entity_storages
+        .multipolygon_storage
+        .end_lifetime(relation.global_id, version.timestamp);
+    if !version.visible {
+        return Ok(());
+    }
+    if have_subelements {
+        process_subelements(
+            name,
+            &mut relation,
+            entity_storages,
+            report,
+            process_relation_subelement,
+            parser,
+        )
+        .context(format!("Failed to process relation {}", relation.global_id))?;
+    }
+    entity_storages.add_multipolygon(relation, version.timestamp);
+    Ok(())
+}
+
+fn has_valid_coords(node: &RawNode, report: &mut DropReport) -> bool {
+    let is_valid = (-90.0..=90.0).contains(&node.lat) && (-180.0..=180.0).contains(&node.lon);
+    if !is_valid {
+        report.drop_entity(
+            "node",
+            "invalid coordinates",
+            format!("node {} at ({}, {})", node.global_id, node.lat, node.lon),
+        );
+    }
+    is_valid
+}
+
+fn is_broken_xml(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<quick_xml::Error>(),
+            Some(quick_xml::Error::Io(_)) | Some(quick_xml::Error::Syntax(_)) | Some(quick_xml::Error::IllFormed(_))
+        )
+    })
+}
+
 fn process_subelements<E: Default, R: BufRead, F>(
     entity_name: &[u8],
     entity: &mut E,
     entity_storages: &EntityStorages,
+    report: &mut DropReport,
     subelement_processor: F,
     parser: &mut Reader<R>,
 ) -> Result<()>
 where
-    F: Fn(&mut Reader<R>, &mut E, &EntityStorages, &[u8], &mut Attributes) -> Result<()>,
+    F: Fn(&mut Reader<R>, &mut E, &EntityStorages, &mut DropReport, &[u8], &mut Attributes) -> Result<()>,
 {
     let mut buf = Vec::new();
     loop {
@@ -321,6 +1005,7 @@ where
                 parser,
                 entity,
                 entity_storages,
+                report,
                 start.local_name().as_ref(),
                 &mut start.attributes(),
             )?,
@@ -356,6 +1041,7 @@ fn process_node_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     node: &mut RawNode,
     _: &EntityStorages,
+    _: &mut DropReport,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
@@ -366,6 +1052,7 @@ fn process_way_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     way: &mut RawWay,
     entity_storages: &EntityStorages,
+    report: &mut DropReport,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
@@ -373,8 +1060,9 @@ fn process_way_subelement<R: BufRead>(
         return Ok(());
     }
     if sub_name == b"nd" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)? {
-            way.node_ids.push(r);
+        match get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)? {
+            Some(r) => way.node_ids.push(r),
+            None => report.skip_missing_ref("way", "node"),
         }
     }
     Ok(())
@@ -384,6 +1072,7 @@ fn process_relation_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     relation: &mut RawRelation,
     entity_storages: &EntityStorages,
+    report: &mut DropReport,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
@@ -391,9 +1080,12 @@ fn process_relation_subelement<R: BufRead>(
         return Ok(());
     }
     if sub_name == b"member" && get_required_attr(parser, sub_name, sub_attrs, b"type")? == "way" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.way_storage)? {
-            let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
-            relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
+        match get_ref(parser, sub_name, sub_attrs, &entity_storages.way_storage)? {
+            Some(r) => {
+                let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
+                relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
+            }
+            None => report.skip_missing_ref("relation", "way"),
         }
     }
     Ok(())
@@ -469,6 +1161,44 @@ fn try_add_tag<R: BufRead>(
     Ok(true)
 }
 
+struct VersionInfo {
+    timestamp: Option<i64>,
+    visible: bool,
+}
+
+// Reads the optional attributes that describe an entity version in the history files. The attributes
+// are read from a copy, so the required ones can still be read in their usual order afterwards.
+// The entities in the `<delete>` sections of the osmChange files are deleted whatever their attributes say.
+fn get_version_info<R: BufRead>(
+    parser: &mut Reader<R>,
+    elem_name: &[u8],
+    attrs: &Attributes,
+    parse_timestamps: bool,
+    is_deleted: bool,
+) -> Result<VersionInfo> {
+    let mut version = VersionInfo {
+        timestamp: None,
+        visible: true,
+    };
+    for attr in attrs.clone() {
+        let attr = attr?;
+        match attr.key.local_name().as_ref() {
+            b"timestamp" if parse_timestamps => {
+                let value = attr.decode_and_unescape_value(parser)?;
+                let timestamp = parse_timestamp(&value).context(format!(
+                    "Failed to parse the timestamp of {}",
+                    ascii_name_as_str(elem_name)
+                ))?;
+                version.timestamp = Some(timestamp);
+            }
+            b"visible" => version.visible = attr.decode_and_unescape_value(parser)? != "false",
+            _ => {}
+        }
+    }
+    version.visible &= !is_deleted;
+    Ok(version)
+}
+
 fn get_id<R: BufRead>(parser: &mut Reader<R>, elem_name: &[u8], attrs: &mut Attributes) -> Result<u64> {
     parse_required_attr(parser, elem_name, attrs, b"id")
 }
@@ -476,6 +1206,15 @@ fn get_id<R: BufRead>(parser: &mut Reader<R>, elem_name: &[u8], attrs: &mut Attr
 pub(super) type RawRefs = Vec<usize>;
 pub(super) type RawTags = BTreeMap<String, String>;
 
+// The boundaries are stored the same way as the multipolygons: their outlines are drawn, and the areas inside
+// them can be styled as well.
+fn is_polygon_relation(tags: &RawTags) -> bool {
+    matches!(
+        tags.get("type").map(String::as_str),
+        Some("multipolygon") | Some("boundary")
+    )
+}
+
 #[derive(Default)]
 pub(super) struct RawNode {
     pub(super) global_id: u64,
@@ -514,6 +1253,21 @@ struct RawRelation {
 }
 
 impl RawRelation {
+    // A relation with the same tags as its only (closed) way would be drawn twice over the way, e.g. with
+    // the transparent fills getting darker. The way is drawn on its own anyway, so the relation isn't needed.
+    fn duplicates_member_way(&self, polygons: &[Polygon], entity_storages: &EntityStorages) -> bool {
+        let [polygon] = polygons else {
+            return false;
+        };
+        let [way_ref] = &self.way_refs[..] else {
+            return false;
+        };
+        let way = &entity_storages.way_storage.entities[way_ref.way_id];
+        let is_same_ring = way.node_ids == *polygon || way.node_ids.iter().rev().eq(polygon.iter());
+        let area_tags = self.tags.iter().filter(|(k, _)| k.as_str() != "type");
+        is_same_ring && way.tags.len() == area_tags.clone().count() && area_tags.eq(way.tags.iter())
+    }
+
     fn to_segments(&self, entity_storages: &EntityStorages) -> Vec<NodeDescPair> {
         let create_node_desc = |way: &RawWay, node_idx_in_way| {
             let node_id = way.node_ids[node_idx_in_way];
@@ -0,0 +1,52 @@
+use crate::geodata::reader::{OsmEntities, OsmEntity};
+
+/// Keeps only the entities that are on the given level of the buildings (as in the Simple Indoor Tagging schema),
+/// along with the ones that aren't on any level (e.g. the streets and the outlines of the buildings).
+pub fn retain_level(entities: &mut OsmEntities<'_>, level: f64) {
+    entities.nodes.retain(|node| is_on_level(node, level));
+    entities.ways.retain(|way| is_on_level(way, level));
+    entities.multipolygons.retain(|mp| is_on_level(mp, level));
+    // The grids are made from the areas on all the levels.
+    entities.landcover = None;
+}
+
+fn is_on_level<'a>(entity: &impl OsmEntity<'a>, level: f64) -> bool {
+    let tags = entity.tags();
+    let mut levels = ["level", "repeat_on"]
+        .into_iter()
+        .filter_map(|key| tags.get_by_key(key))
+        .peekable();
+    levels.peek().is_none() || levels.any(|levels| levels_contain(levels, level))
+}
+
+/// Whether the value of `level=*` (or `repeat_on=*`) includes the level: it's a list of the levels and the ranges
+/// of the levels, separated with semicolons.
+/// # Examples
+/// ```
+/// use renderer::geodata::indoor::levels_contain;
+/// assert!(levels_contain("1", 1.0));
+/// assert!(levels_contain("0;1", 1.0));
+/// assert!(levels_contain("-2--1", -1.0));
+/// assert!(levels_contain("0-3", 2.0));
+/// assert!(levels_contain("0.5", 0.5));
+/// assert!(!levels_contain("0-3", 4.0));
+/// assert!(!levels_contain("-1", 1.0));
+/// assert!(!levels_contain("ground", 0.0));
+/// ```
+pub fn levels_contain(levels: &str, level: f64) -> bool {
+    levels.split(';').any(|part| {
+        let part = part.trim();
+        // The minus of a negative level at the start isn't the dash of a range.
+        let range = part
+            .get(1..)
+            .and_then(|rest| rest.find('-'))
+            .map(|dash| (&part[..dash + 1], &part[dash + 2..]));
+        match range {
+            Some((from, to)) => match (from.trim().parse::<f64>(), to.trim().parse::<f64>()) {
+                (Ok(from), Ok(to)) => from.min(to) <= level && level <= from.max(to),
+                _ => false,
+            },
+            None => part.parse::<f64>() == Ok(level),
+        }
+    })
+}
@@ -0,0 +1,331 @@
+use crate::coords::Coords;
+use crate::geodata::importer::{EntityStorages, RawNode, RawTags};
+use crate::tile::{coords_to_xy, TILE_SIZE};
+use std::collections::{BTreeMap, HashMap};
+
+/// The highest zoom level that has the landcover grids. The grids of the lower zoom levels are made from
+/// the ones of this level.
+pub const LANDCOVER_ZOOM: u8 = 10;
+/// The number of cells on every side of a grid, i.e. every cell is 2×2 pixels of a usual tile.
+pub const GRID_SIZE: usize = 128;
+
+// Every cell of the grids of `LANDCOVER_ZOOM` is split into this many samples on every side, and it gets
+// the class that covers at least half of them.
+const SAMPLES_PER_CELL: usize = 4;
+const SAMPLES_PER_TILE: usize = SAMPLES_PER_CELL * GRID_SIZE;
+const SAMPLES_PER_CELL_AREA: u8 = (SAMPLES_PER_CELL * SAMPLES_PER_CELL) as u8;
+
+// The areas that are at least this large (in pixels at `LANDCOVER_ZOOM`) are drawn as they are.
+const MAX_AGGREGATED_SIZE: f64 = 16.0;
+
+/// The kinds of areas that are put into the grids: the class of an area is the position of its tag
+/// in the list plus one. Zero means that there is no landcover in the cell.
+const LANDCOVER_TAGS: [(&str, &str); 12] = [
+    ("landuse", "forest"),
+    ("natural", "wood"),
+    ("landuse", "residential"),
+    ("landuse", "farmland"),
+    ("landuse", "meadow"),
+    ("landuse", "grass"),
+    ("natural", "grassland"),
+    ("natural", "scrub"),
+    ("landuse", "orchard"),
+    ("landuse", "allotments"),
+    ("landuse", "industrial"),
+    ("landuse", "commercial"),
+];
+const CLASS_COUNT: usize = LANDCOVER_TAGS.len();
+
+/// Returns the landcover class of an area with the tags, or zero if it's not a landcover area.
+pub fn landcover_class<'t>(get_tag: impl Fn(&str) -> Option<&'t str>) -> u8 {
+    LANDCOVER_TAGS
+        .iter()
+        .position(|(key, value)| get_tag(key) == Some(*value))
+        .map_or(0, |idx| idx as u8 + 1)
+}
+
+/// Whether a landcover area with these nodes (of all its rings) is small enough to be drawn from the grids
+/// instead of on its own.
+pub fn is_aggregated<C: Coords>(nodes: impl Iterator<Item = C>) -> bool {
+    let mut bounds: Option<(f64, f64, f64, f64)> = None;
+    for node in nodes {
+        let (x, y) = coords_to_xy(&node, LANDCOVER_ZOOM);
+        let (min_x, min_y, max_x, max_y) = bounds.unwrap_or((x, y, x, y));
+        bounds = Some((min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)));
+        if max_x.max(x) - min_x.min(x) >= MAX_AGGREGATED_SIZE || max_y.max(y) - min_y.min(y) >= MAX_AGGREGATED_SIZE {
+            return false;
+        }
+    }
+    bounds.is_some()
+}
+
+/// A grid of a tile: the `GRID_SIZE` rows of its cells, with the landcover class of every cell.
+pub type LandcoverGrid = Vec<u8>;
+
+/// The grids of all the tiles from zoom level 0 to `LANDCOVER_ZOOM` that have some landcover, by (zoom, x, y).
+pub(super) type LandcoverGrids = BTreeMap<(u8, u32, u32), LandcoverGrid>;
+
+pub(super) fn get_landcover_grids(entity_storages: &EntityStorages) -> LandcoverGrids {
+    let nodes = entity_storages.node_storage.get_entities();
+    let polygons = &entity_storages.polygon_storage;
+    let mut samples = LandcoverSamples::default();
+
+    for way in entity_storages.way_storage.get_entities() {
+        let class = raw_landcover_class(&way.tags);
+        let way_nodes = way.node_ids.iter().map(|idx| &nodes[*idx]).collect::<Vec<_>>();
+        if class == 0 || !is_closed(&way.node_ids, nodes) || !is_aggregated(way_nodes.iter().map(|n| (n.lat, n.lon))) {
+            continue;
+        }
+        samples.add_polygon(class, &[way_nodes]);
+    }
+    for multipolygon in entity_storages.multipolygon_storage.get_entities() {
+        let class = raw_landcover_class(&multipolygon.tags);
+        let rings = multipolygon
+            .polygon_ids
+            .iter()
+            .map(|poly_id| polygons[*poly_id].iter().map(|idx| &nodes[*idx]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        if class == 0 || rings.is_empty() || !is_aggregated(rings.iter().flatten().map(|n| (n.lat, n.lon))) {
+            continue;
+        }
+        samples.add_polygon(class, &rings);
+    }
+
+    let mut grids = samples.into_grids();
+    for zoom in (0..LANDCOVER_ZOOM).rev() {
+        let parents = downsample(&grids, zoom);
+        grids.extend(parents);
+    }
+    grids
+}
+
+fn raw_landcover_class(tags: &RawTags) -> u8 {
+    landcover_class(|key| tags.get(key).map(String::as_str))
+}
+
+// The same as `OsmArea::is_closed` for the ways of the reader.
+fn is_closed(node_ids: &[usize], nodes: &[RawNode]) -> bool {
+    match (node_ids.first(), node_ids.last()) {
+        (Some(first), Some(last)) if node_ids.len() > 2 => {
+            (nodes[*first].lat, nodes[*first].lon) == (nodes[*last].lat, nodes[*last].lon)
+        }
+        _ => false,
+    }
+}
+
+// How many samples of every cell of the tiles of `LANDCOVER_ZOOM` are covered by every class.
+#[derive(Default)]
+struct LandcoverSamples {
+    tiles: HashMap<(u32, u32), Vec<[u8; CLASS_COUNT]>>,
+}
+
+impl LandcoverSamples {
+    // Marks the samples whose centers are inside the polygon (the rings overlap like in `fill-rule: evenodd`).
+    fn add_polygon(&mut self, class: u8, rings: &[Vec<&RawNode>]) {
+        let to_samples = SAMPLES_PER_TILE as f64 / f64::from(TILE_SIZE);
+        let rings = rings
+            .iter()
+            .map(|ring| {
+                ring.iter()
+                    .map(|node| {
+                        let (x, y) = coords_to_xy(*node, LANDCOVER_ZOOM);
+                        (x * to_samples, y * to_samples)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let (min_y, max_y) = rings
+            .iter()
+            .flatten()
+            .fold((f64::MAX, f64::MIN), |(min_y, max_y), (_, y)| {
+                (min_y.min(*y), max_y.max(*y))
+            });
+
+        let mut crossings = Vec::new();
+        for row in (min_y.floor() as i64).max(0)..=(max_y.ceil() as i64) {
+            let center_y = row as f64 + 0.5;
+            crossings.clear();
+            for ring in &rings {
+                for (from, to) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                    if (from.1 <= center_y) != (to.1 <= center_y) {
+                        crossings.push(from.0 + (center_y - from.1) * (to.0 - from.0) / (to.1 - from.1));
+                    }
+                }
+            }
+            crossings.sort_by(f64::total_cmp);
+            for pair in crossings.chunks_exact(2) {
+                let first_column = (pair[0] - 0.5).ceil().max(0.0) as i64;
+                let last_column = (pair[1] - 0.5).ceil() as i64 - 1;
+                for column in first_column..=last_column {
+                    self.add_sample(class, column as usize, row as usize);
+                }
+            }
+        }
+    }
+
+    fn add_sample(&mut self, class: u8, column: usize, row: usize) {
+        let tile = ((column / SAMPLES_PER_TILE) as u32, (row / SAMPLES_PER_TILE) as u32);
+        let cell_x = column % SAMPLES_PER_TILE / SAMPLES_PER_CELL;
+        let cell_y = row % SAMPLES_PER_TILE / SAMPLES_PER_CELL;
+        let cells = self
+            .tiles
+            .entry(tile)
+            .or_insert_with(|| vec![[0; CLASS_COUNT]; GRID_SIZE * GRID_SIZE]);
+        let count = &mut cells[cell_y * GRID_SIZE + cell_x][class as usize - 1];
+        *count = count.saturating_add(1);
+    }
+
+    // Every cell gets the class that covers the most of it, if the landcover covers at least half of it.
+    fn into_grids(self) -> LandcoverGrids {
+        let mut grids = LandcoverGrids::new();
+        for ((x, y), cells) in self.tiles {
+            let grid = cells
+                .iter()
+                .map(|counts| {
+                    let covered = counts.iter().map(|count| u32::from(*count)).sum::<u32>();
+                    if 2 * covered < u32::from(SAMPLES_PER_CELL_AREA) {
+                        return 0;
+                    }
+                    let best = (0..CLASS_COUNT)
+                        .max_by_key(|idx| (counts[*idx], CLASS_COUNT - idx))
+                        .unwrap();
+                    best as u8 + 1
+                })
+                .collect::<Vec<_>>();
+            if grid.iter().any(|class| *class != 0) {
+                grids.insert((LANDCOVER_ZOOM, x, y), grid);
+            }
+        }
+        grids
+    }
+}
+
+// Makes the grids of the zoom level from the ones of the next zoom level: every cell gets the class that
+// the most of the four cells under it have (the landcover wins the ties with the empty cells, and the lower
+// classes win the ties between themselves).
+fn downsample(grids: &LandcoverGrids, zoom: u8) -> LandcoverGrids {
+    let mut parents = LandcoverGrids::new();
+    for (_, x, y) in grids.keys().filter(|(child_zoom, _, _)| *child_zoom == zoom + 1) {
+        let parent = (zoom, x / 2, y / 2);
+        if parents.contains_key(&parent) {
+            continue;
+        }
+        let child_cell = |column: usize, row: usize| {
+            let child = (
+                zoom + 1,
+                parent.1 * 2 + (column / GRID_SIZE) as u32,
+                parent.2 * 2 + (row / GRID_SIZE) as u32,
+            );
+            grids
+                .get(&child)
+                .map_or(0, |grid| grid[row % GRID_SIZE * GRID_SIZE + column % GRID_SIZE])
+        };
+        let mut grid = vec![0; GRID_SIZE * GRID_SIZE];
+        for row in 0..GRID_SIZE {
+            for column in 0..GRID_SIZE {
+                let mut counts = [0; CLASS_COUNT + 1];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    counts[child_cell(2 * column + dx, 2 * row + dy) as usize] += 1;
+                }
+                let best = (1..=CLASS_COUNT)
+                    .max_by_key(|class| (counts[*class], CLASS_COUNT - class))
+                    .filter(|class| counts[*class] >= counts[0])
+                    .unwrap_or(0);
+                grid[row * GRID_SIZE + column] = best as u8;
+            }
+        }
+        if grid.iter().any(|class| *class != 0) {
+            parents.insert(parent, grid);
+        }
+    }
+    parents
+}
+
+/// Packs a grid into runs of the same class (the class in the upper 16 bits and the length in the lower ones).
+pub(super) fn encode_grid(grid: &[u8]) -> Vec<u32> {
+    let mut runs = Vec::<u32>::new();
+    for class in grid {
+        let class = u32::from(*class);
+        match runs.last_mut() {
+            Some(run) if *run >> 16 == class => *run += 1,
+            _ => runs.push((class << 16) | 1),
+        }
+    }
+    runs
+}
+
+pub(super) fn decode_grid(runs: &[u32]) -> LandcoverGrid {
+    let mut grid = Vec::with_capacity(GRID_SIZE * GRID_SIZE);
+    for run in runs {
+        grid.extend(std::iter::repeat_n((*run >> 16) as u8, (*run & 0xffff) as usize));
+    }
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_landcover_class() {
+        let tags = |key: &str| match key {
+            "landuse" => Some("residential"),
+            "natural" => Some("wood"),
+            _ => None,
+        };
+        assert_eq!(landcover_class(tags), 2);
+        assert_eq!(landcover_class(|_| Some("water")), 0);
+    }
+
+    #[test]
+    fn test_samples() {
+        let node = |x: f64, y: f64| {
+            let to_pixels = SAMPLES_PER_TILE as f64 / f64::from(TILE_SIZE);
+            let (lat, lon) = crate::tile::xy_to_coords(x / to_pixels, y / to_pixels, LANDCOVER_ZOOM);
+            RawNode {
+                global_id: 0,
+                lat,
+                lon,
+                tags: RawTags::new(),
+            }
+        };
+        // A square of 2×2 cells in the top left corner of the tile (0, 0), with a hole of 1×1 cells.
+        let outer = [node(0.0, 0.0), node(8.0, 0.0), node(8.0, 8.0), node(0.0, 8.0)];
+        let inner = [node(2.0, 2.0), node(6.0, 2.0), node(6.0, 6.0), node(2.0, 6.0)];
+        let mut samples = LandcoverSamples::default();
+        samples.add_polygon(3, &[outer.iter().collect(), inner.iter().collect()]);
+        let grids = samples.into_grids();
+        let grid = &grids[&(LANDCOVER_ZOOM, 0, 0)];
+        // Three quarters of every cell are covered.
+        assert_eq!(&grid[..3], &[3, 3, 0]);
+        assert_eq!(&grid[GRID_SIZE..GRID_SIZE + 3], &[3, 3, 0]);
+        assert_eq!(grid.iter().filter(|class| **class != 0).count(), 4);
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut grids = LandcoverGrids::new();
+        let mut grid = vec![0; GRID_SIZE * GRID_SIZE];
+        grid[..4].copy_from_slice(&[1, 2, 2, 2]);
+        grid[GRID_SIZE..GRID_SIZE + 4].copy_from_slice(&[2, 1, 0, 0]);
+        grids.insert((LANDCOVER_ZOOM, 3, 1), grid);
+
+        let parents = downsample(&grids, LANDCOVER_ZOOM - 1);
+        assert_eq!(parents.len(), 1);
+        let parent = &parents[&(LANDCOVER_ZOOM - 1, 1, 0)];
+        // The tile (3, 1) is the bottom right quarter of its parent.
+        let first_cell = GRID_SIZE / 2 * GRID_SIZE + GRID_SIZE / 2;
+        assert_eq!(&parent[first_cell..first_cell + 3], &[1, 2, 0]);
+        // The tie between the landcover and the empty cells goes to the landcover.
+        assert_eq!(parent.iter().filter(|class| **class != 0).count(), 2);
+    }
+
+    #[test]
+    fn test_grid_encoding() {
+        let mut grid = vec![0; GRID_SIZE * GRID_SIZE];
+        grid[5..10].fill(4);
+        let runs = encode_grid(&grid);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(decode_grid(&runs), grid);
+    }
+}
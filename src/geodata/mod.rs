@@ -1,4 +1,10 @@
+pub mod baked;
+mod drop_report;
 mod find_polygons;
+pub mod geojson;
 pub mod importer;
+pub mod indoor;
+pub mod landcover;
 pub mod reader;
 mod saver;
+pub mod timestamp;
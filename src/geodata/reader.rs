@@ -1,4 +1,6 @@
-use crate::coords::Coords;
+use crate::coords::{Coords, GeoBounds};
+use crate::geodata::importer::CoordinatePrecision;
+use crate::geodata::landcover::{decode_grid, LandcoverGrid, LANDCOVER_ZOOM};
 use crate::tile;
 use anyhow::{Context, Result};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
@@ -12,6 +14,7 @@ use std::mem;
 use std::ops::Deref;
 use std::slice;
 use std::str;
+use std::time::SystemTime;
 
 pub trait OsmEntity<'a> {
     fn global_id(&self) -> u64;
@@ -22,21 +25,46 @@ pub struct OsmEntities<'a> {
     pub nodes: Vec<Node<'a>>,
     pub ways: Vec<Way<'a>>,
     pub multipolygons: Vec<Multipolygon<'a>>,
+    /// The grids of the small landcover areas, if the geodata has them and they match the entities
+    /// (i.e. the entities are the current ones, and all of them).
+    pub landcover: Option<Landcover<'a>>,
+}
+
+/// Gives the landcover grids (see `landcover`) of the tiles up to `LANDCOVER_ZOOM`.
+#[derive(Clone, Copy)]
+pub struct Landcover<'a> {
+    reader: &'a GeodataReader<'a>,
+}
+
+impl<'a> Landcover<'a> {
+    /// Returns `None` if there's no landcover in the tile (or it's beyond `LANDCOVER_ZOOM`).
+    pub fn grid(&self, t: &tile::Tile) -> Option<LandcoverGrid> {
+        let storage = &self.reader.storages().landcover_storage;
+        let idx = storage.find_zoom_tile(t)?;
+        let runs = self
+            .reader
+            .get_ints_by_ref(&storage.get_object(idx)[3 * mem::size_of::<u32>()..]);
+        Some(decode_grid(runs))
+    }
 }
 
 #[derive(Default)]
 pub(super) struct OsmEntityIds {
-    pub(super) nodes: Vec<u32>,
+    pub(super) pois: Vec<u32>,
     pub(super) ways: Vec<u32>,
     pub(super) multipolygons: Vec<u32>,
 }
 
 pub trait OsmArea {
     fn is_closed(&self) -> bool;
+    /// Whether the entity is an area (and is filled) rather than a line. A closed way is an area unless it's tagged
+    /// with `area=no`, and an unclosed way tagged with `area=yes` is an area as well.
+    fn is_area(&self) -> bool;
 }
 
 pub struct GeodataReader<'a> {
     storages: ObjectStorages<'a>,
+    modified: Option<SystemTime>,
     _mmap: Mmap,
 }
 
@@ -54,38 +82,112 @@ impl<'a> GeodataReader<'a> {
         // The bytes are only ever accessed from `storages`, which is bundled together with `mmap`
         // in `GeodataReader`. Therefore, `mmap` is still not dropped whenever we access the bytes.
         let storages = ObjectStorages::from_bytes(unsafe { &*raw_mmap_bytes });
-        Ok(GeodataReader { storages, _mmap: mmap })
+        // Taken from the mapped file itself, so that it can't belong to a newer file moved in its place.
+        let modified = input_file.metadata().and_then(|m| m.modified()).ok();
+        Ok(GeodataReader {
+            storages,
+            modified,
+            _mmap: mmap,
+        })
+    }
+
+    /// When the geodata file was written, which tells the imports apart.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// The PNG image of the tile that was baked at import time (see `BakeOptions`), if it was drawn
+    /// with the style of the given version (see `get_style_version`).
+    pub fn baked_tile(&self, t: &tile::Tile, style_version: &str) -> Option<&'a [u8]> {
+        let storages = self.storages();
+        if storages.baked_style_storage.object_count == 0
+            || self.get_bytes_by_ref(storages.baked_style_storage.get_object(0)) != style_version.as_bytes()
+        {
+            return None;
+        }
+        let idx = storages.baked_tile_storage.find_zoom_tile(t)?;
+        Some(self.get_bytes_by_ref(&storages.baked_tile_storage.get_object(idx)[3 * mem::size_of::<u32>()..]))
+    }
+
+    /// The area covered by the `MAX_ZOOM` tiles that have any entities in them, or `None` if there are no entities.
+    pub fn bounds(&self) -> Option<GeoBounds> {
+        let storages = self.storages();
+        let (min_x, min_y, max_x, max_y) = [&storages.tile_storage, &storages.poi_tile_storage]
+            .into_iter()
+            .flat_map(|storage| (0..storage.object_count).map(|idx| storage.tile_xy(idx)))
+            .fold(None, |bounds, (x, y)| match bounds {
+                None => Some((x, y, x, y)),
+                Some((min_x, min_y, max_x, max_y)) => Some((min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))),
+            })?;
+        let to_coords = |x: u32, y: u32| {
+            let to_pixels = |tile_number: u32| f64::from(tile_number) * f64::from(tile::TILE_SIZE);
+            tile::xy_to_coords(to_pixels(x), to_pixels(y), tile::MAX_ZOOM)
+        };
+        let (max_lat, min_lon) = to_coords(min_x, min_y);
+        let (min_lat, max_lon) = to_coords(max_x + 1, max_y + 1);
+        Some(GeoBounds {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+
+    /// The tiles of the zoom level that have any entities in them.
+    pub fn covered_tiles(&self, zoom: u8) -> Vec<tile::Tile> {
+        let storages = self.storages();
+        let shift = tile::MAX_ZOOM - zoom;
+        let mut tiles = [&storages.tile_storage, &storages.poi_tile_storage]
+            .into_iter()
+            .flat_map(|storage| (0..storage.object_count).map(|idx| storage.tile_xy(idx)))
+            .map(|(x, y)| (x >> shift, y >> shift))
+            .collect::<Vec<_>>();
+        tiles.sort_unstable();
+        tiles.dedup();
+        tiles.into_iter().map(|(x, y)| tile::Tile { zoom, x, y }).collect()
     }
 
     pub fn get_entities_in_tile_with_neighbors(
         &'a self,
         t: &tile::Tile,
         osm_ids: &Option<HashSet<u64>>,
-    ) -> OsmEntities {
+    ) -> OsmEntities<'a> {
+        self.get_entities_in_tile_with_neighbors_at(t, osm_ids, None)
+    }
+
+    /// Same as `get_entities_in_tile_with_neighbors`, but if `date` (a UNIX timestamp) is given, returns
+    /// only the versions of the entities that existed at that moment instead of the current ones. This
+    /// requires the geodata to be imported with the timestamps, otherwise the date is ignored.
+    pub fn get_entities_in_tile_with_neighbors_at(
+        &'a self,
+        t: &tile::Tile,
+        osm_ids: &Option<HashSet<u64>>,
+        date: Option<i64>,
+    ) -> OsmEntities<'a> {
         let mut entity_ids = OsmEntityIds::default();
 
-        let deltas = [-1, 0, 1];
-        for dx in &deltas {
-            for dy in &deltas {
-                let adjacent_tile = tile::Tile {
-                    x: (t.x as i32 + dx) as u32,
-                    y: (t.y as i32 + dy) as u32,
-                    zoom: t.zoom,
-                };
-                self.get_entities_in_tile(&adjacent_tile, &mut entity_ids);
-            }
-        }
+        for_each_neighbor(t, |neighbor| self.get_entities_in_tile(neighbor, &mut entity_ids));
 
         let uniq = |ids: &mut Vec<u32>| {
             ids.sort_unstable();
             ids.dedup();
         };
 
-        uniq(&mut entity_ids.nodes);
+        uniq(&mut entity_ids.pois);
         uniq(&mut entity_ids.ways);
         uniq(&mut entity_ids.multipolygons);
 
-        let nodes = entity_ids.nodes.iter().map(|id| self.get_node(*id as usize));
+        let storages = self.storages();
+        let retain_existing = |ids: &mut Vec<u32>, lifetimes: &ObjectStorage<'_>| {
+            if lifetimes.object_count > 0 {
+                ids.retain(|id| existed_at(lifetimes, *id as usize, date));
+            }
+        };
+        retain_existing(&mut entity_ids.pois, &storages.poi_lifetime_storage);
+        retain_existing(&mut entity_ids.ways, &storages.way_lifetime_storage);
+        retain_existing(&mut entity_ids.multipolygons, &storages.multipolygon_lifetime_storage);
+
+        let nodes = entity_ids.pois.iter().map(|id| self.get_poi(*id as usize));
         let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize));
         let multipolygons = entity_ids.multipolygons.iter().filter_map(|id| {
             let mp = self.get_multipolygon(*id as usize);
@@ -96,91 +198,111 @@ impl<'a> GeodataReader<'a> {
             }
         });
 
+        // The grids are made from the current versions of all the areas.
+        let has_landcover = storages.landcover_storage.object_count > 0
+            && t.zoom <= LANDCOVER_ZOOM
+            && date.is_none()
+            && osm_ids.is_none();
+
         OsmEntities {
             nodes: filter_entities_by_ids(nodes, osm_ids),
             ways: filter_entities_by_ids(ways, osm_ids),
             multipolygons: filter_entities_by_ids(multipolygons, osm_ids),
+            landcover: has_landcover.then_some(Landcover { reader: self }),
         }
     }
 
-    pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
-        let mut bounds = tile::tile_to_max_zoom_tile_range(t);
-        let mut start_from_index = 0;
-
-        let tile_count = self.tile_count();
-        while start_from_index < tile_count {
-            match self.next_good_tile(&mut bounds, start_from_index) {
-                None => break,
-                Some(mut current_index) => {
-                    let (mut tile_x, mut tile_y) = self.tile_xy(current_index);
-                    let current_x = tile_x;
-
-                    while (tile_x == current_x) && (tile_y <= bounds.max_y) {
-                        entity_ids.nodes.extend(self.tile_local_ids(current_index, 0));
-                        entity_ids.ways.extend(self.tile_local_ids(current_index, 1));
-                        entity_ids.multipolygons.extend(self.tile_local_ids(current_index, 2));
-
-                        current_index += 1;
-                        if current_index >= tile_count {
-                            break;
-                        }
-                        let (next_tile_x, next_tile_y) = self.tile_xy(current_index);
-                        tile_x = next_tile_x;
-                        tile_y = next_tile_y;
-                    }
-
-                    start_from_index = current_index;
-                    bounds.min_x = current_x + 1;
-                }
-            }
+    /// Returns only the POIs (i.e. the nodes with tags) for a tile, without looking at ways and multipolygons.
+    pub fn get_pois_in_tile_with_neighbors(&'a self, t: &tile::Tile, osm_ids: &Option<HashSet<u64>>) -> Vec<Node<'a>> {
+        let mut poi_ids = Vec::new();
+        for_each_neighbor(t, |neighbor| self.get_pois_in_tile(neighbor, &mut poi_ids));
+        poi_ids.sort_unstable();
+        poi_ids.dedup();
+        let poi_lifetimes = &self.storages().poi_lifetime_storage;
+        if poi_lifetimes.object_count > 0 {
+            poi_ids.retain(|id| existed_at(poi_lifetimes, *id as usize, None));
         }
+        filter_entities_by_ids(poi_ids.iter().map(|id| self.get_poi(*id as usize)), osm_ids)
     }
 
-    fn next_good_tile(&self, bounds: &mut tile::TileRange, start_index: usize) -> Option<usize> {
-        let tile_count = self.tile_count();
-        if start_index >= tile_count {
-            return None;
-        }
-
-        let find_smallest_feasible_index = |from, min_x, min_y| {
-            let large_enough = |idx| self.tile_xy(idx) >= (min_x, min_y);
-
-            let mut lo = from;
-            let mut hi = tile_count - 1;
-
-            while lo < hi {
-                let mid = (lo + hi) / 2;
+    pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
+        let tile_storage = &self.storages().tile_storage;
+        tile_storage.for_each_tile(t, |idx| {
+            entity_ids.ways.extend(self.tile_local_ids(tile_storage, idx, 0));
+            entity_ids
+                .multipolygons
+                .extend(self.tile_local_ids(tile_storage, idx, 1));
+        });
+        self.get_pois_in_tile(t, &mut entity_ids.pois);
+    }
+
+    fn get_pois_in_tile(&'a self, t: &tile::Tile, poi_ids: &mut Vec<u32>) {
+        let poi_tile_storage = &self.storages().poi_tile_storage;
+        poi_tile_storage.for_each_tile(t, |idx| poi_ids.extend(self.tile_local_ids(poi_tile_storage, idx, 0)));
+    }
+
+    /// Returns a rough estimate of how much work it takes to render a tile: the number of entity references
+    /// in the index for the tile and its neighbors. Entities spanning several index cells are counted
+    /// multiple times, but that's fine, since they're usually more expensive to draw anyway.
+    pub fn estimate_tile_density(&self, t: &tile::Tile) -> usize {
+        let storages = self.storages();
+        let mut density = 0;
+        for_each_neighbor(t, |neighbor| {
+            storages.tile_storage.for_each_tile(neighbor, |idx| {
+                density += (0..2)
+                    .map(|local_ids_idx| self.tile_local_ids(&storages.tile_storage, idx, local_ids_idx).len())
+                    .sum::<usize>();
+            });
+            storages.poi_tile_storage.for_each_tile(neighbor, |idx| {
+                density += self.tile_local_ids(&storages.poi_tile_storage, idx, 0).len();
+            });
+        });
+        density
+    }
 
-                if large_enough(mid) {
-                    hi = mid;
-                } else {
-                    lo = mid + 1;
-                }
-            }
+    /// Returns the bounding boxes of the places (see `place=*`) and the relations with the given name,
+    /// ignoring the case. There can be several of them, e.g. a city and the administrative boundary around it.
+    pub fn find_places(&self, name: &str) -> Vec<GeoBounds> {
+        let storage = &self.storages().place_name_storage;
+        let name = name.to_lowercase();
+        let place_name = |idx| self.place_name(storage.get_object(idx)).to_lowercase();
 
-            if large_enough(lo) {
-                Some(lo)
+        // The names are sorted case-insensitively, so the matching ones are next to each other.
+        let (mut lo, mut hi) = (0, storage.object_count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if place_name(mid) < name {
+                lo = mid + 1;
             } else {
-                None
-            }
-        };
-
-        let mut idx = start_index;
-        while let Some(next_idx) = find_smallest_feasible_index(idx, bounds.min_x, bounds.min_y) {
-            let (tile_x, tile_y) = self.tile_xy(next_idx);
-            if (tile_x, tile_y) > (bounds.max_x, bounds.max_y) {
-                return None;
+                hi = mid;
             }
+        }
 
-            if tile_x == bounds.min_x {
-                return Some(next_idx);
-            }
+        (lo..storage.object_count)
+            .take_while(|idx| place_name(*idx) == name)
+            .map(|idx| {
+                let bytes = storage.get_object(idx);
+                let coord = |coord_idx: usize| {
+                    LittleEndian::read_f64(&bytes[INT_REF_SIZE + coord_idx * mem::size_of::<f64>()..])
+                };
+                GeoBounds {
+                    min_lon: coord(0),
+                    min_lat: coord(1),
+                    max_lon: coord(2),
+                    max_lat: coord(3),
+                }
+            })
+            .collect()
+    }
 
-            idx = next_idx;
-            bounds.min_x = tile_x;
-        }
+    fn place_name(&self, bytes: &'a [u8]) -> &'a str {
+        str::from_utf8(self.get_bytes_by_ref(bytes)).unwrap()
+    }
 
-        None
+    fn get_bytes_by_ref(&self, ref_bytes: &[u8]) -> &'a [u8] {
+        let offset = LittleEndian::read_u32(ref_bytes) as usize;
+        let length = LittleEndian::read_u32(&ref_bytes[mem::size_of::<u32>()..]) as usize;
+        &self.storages().strings[offset..offset + length]
     }
 
     fn get_node(&'a self, idx: usize) -> Node<'a> {
@@ -192,6 +314,15 @@ impl<'a> GeodataReader<'a> {
         }
     }
 
+    fn get_poi(&'a self, idx: usize) -> Node<'a> {
+        Node {
+            entity: BaseOsmEntity {
+                bytes: self.storages().poi_storage.get_object(idx),
+                reader: self,
+            },
+        }
+    }
+
     fn get_way(&'a self, idx: usize) -> Way<'a> {
         let bytes = self.storages().way_storage.get_object(idx);
         let node_ids_start_pos = mem::size_of::<u64>();
@@ -218,24 +349,12 @@ impl<'a> GeodataReader<'a> {
         }
     }
 
-    fn tile_xy(&self, idx: usize) -> (u32, u32) {
-        let tile = self.storages().tile_storage.get_object(idx);
-        let mut cursor = Cursor::new(tile);
-        let x = cursor.read_u32::<LittleEndian>().unwrap();
-        let y = cursor.read_u32::<LittleEndian>().unwrap();
-        (x, y)
-    }
-
-    fn tile_local_ids(&self, idx: usize, local_ids_idx: usize) -> &'a [u32] {
-        let tile = self.storages().tile_storage.get_object(idx);
+    fn tile_local_ids(&self, tile_storage: &ObjectStorage<'a>, idx: usize, local_ids_idx: usize) -> &'a [u32] {
+        let tile = tile_storage.get_object(idx);
         let offset = 2 * mem::size_of::<u32>() * (local_ids_idx + 1);
         self.get_ints_by_ref(&tile[offset..])
     }
 
-    fn tile_count(&self) -> usize {
-        self.storages().tile_storage.object_count
-    }
-
     fn tags(&self, ref_bytes: &'a [u8]) -> Tags<'a> {
         Tags {
             kv_refs: self.get_ints_by_ref(ref_bytes),
@@ -255,6 +374,79 @@ impl<'a> GeodataReader<'a> {
     }
 }
 
+// All the entities as they were saved, for the importer to apply the changes to them (see `importer::update`).
+impl<'a> GeodataReader<'a> {
+    pub(super) fn coordinates(&self) -> CoordinatePrecision {
+        self.storages().coordinates
+    }
+
+    pub(super) fn has_lifetimes(&self) -> bool {
+        let storages = self.storages();
+        [
+            &storages.poi_lifetime_storage,
+            &storages.way_lifetime_storage,
+            &storages.multipolygon_lifetime_storage,
+        ]
+        .iter()
+        .any(|storage| storage.object_count > 0)
+    }
+
+    pub(super) fn has_landcover(&self) -> bool {
+        self.storages().landcover_storage.object_count > 0
+    }
+
+    pub(super) fn all_nodes(&'a self) -> impl Iterator<Item = Node<'a>> {
+        (0..self.storages().node_storage.object_count).map(|idx| self.get_node(idx))
+    }
+
+    pub(super) fn all_pois(&'a self) -> impl Iterator<Item = Node<'a>> {
+        (0..self.storages().poi_storage.object_count).map(|idx| self.get_poi(idx))
+    }
+
+    pub(super) fn all_ways(&'a self) -> impl Iterator<Item = Way<'a>> {
+        (0..self.storages().way_storage.object_count).map(|idx| self.get_way(idx))
+    }
+
+    pub(super) fn all_polygons(&'a self) -> impl Iterator<Item = Polygon<'a>> {
+        (0..self.storages().polygon_storage.object_count).map(|idx| self.get_polygon(idx))
+    }
+
+    pub(super) fn all_multipolygons(&'a self) -> impl Iterator<Item = Multipolygon<'a>> {
+        (0..self.storages().multipolygon_storage.object_count).map(|idx| self.get_multipolygon(idx))
+    }
+}
+
+// Calls `f` for the tile itself and all of its adjacent tiles.
+fn for_each_neighbor<F: FnMut(&tile::Tile)>(t: &tile::Tile, mut f: F) {
+    let deltas = [-1, 0, 1];
+    let zoom_size = 1i64 << t.zoom;
+    for dx in &deltas {
+        for dy in &deltas {
+            let (x, y) = (i64::from(t.x) + dx, i64::from(t.y) + dy);
+            // Tiles on the edges of the map don't have some of the neighbors.
+            if x < 0 || y < 0 || x >= zoom_size || y >= zoom_size {
+                continue;
+            }
+            f(&tile::Tile {
+                x: x as u32,
+                y: y as u32,
+                zoom: t.zoom,
+            });
+        }
+    }
+}
+
+// Without a date, only the current versions (the ones that were neither replaced nor deleted) exist.
+fn existed_at(lifetimes: &ObjectStorage<'_>, idx: usize, date: Option<i64>) -> bool {
+    let bytes = lifetimes.get_object(idx);
+    let from = LittleEndian::read_i64(&bytes[..8]);
+    let until = LittleEndian::read_i64(&bytes[8..]);
+    match date {
+        Some(date) => from <= date && date < until,
+        None => until == i64::MAX,
+    }
+}
+
 fn filter_entities_by_ids<'a, E>(entities: impl Iterator<Item = E>, osm_ids: &Option<HashSet<u64>>) -> Vec<E>
 where
     E: OsmEntity<'a> + Hash + Eq,
@@ -290,34 +482,179 @@ impl<'a> ObjectStorage<'a> {
         let end_pos = start_pos + self.object_size;
         &self.objects[start_pos..end_pos]
     }
+
+    // For the storages where each object starts with the zoom level and the coordinates of a tile, sorted by them.
+    fn find_zoom_tile(&self, t: &tile::Tile) -> Option<usize> {
+        let key = |idx| {
+            let bytes = self.get_object(idx);
+            let read = |pos: usize| LittleEndian::read_u32(&bytes[pos * mem::size_of::<u32>()..]);
+            (read(0), read(1), read(2))
+        };
+        let wanted = (u32::from(t.zoom), t.x, t.y);
+        let (mut lo, mut hi) = (0, self.object_count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if key(mid) < wanted {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo < self.object_count && key(lo) == wanted).then_some(lo)
+    }
+}
+
+// The tile indexes are object storages where each object starts with the (x, y) of a max zoom tile,
+// and the objects are sorted by these coordinates.
+impl<'a> ObjectStorage<'a> {
+    fn for_each_tile<F: FnMut(usize)>(&self, t: &tile::Tile, mut f: F) {
+        let mut bounds = tile::tile_to_max_zoom_tile_range(t);
+        let mut start_from_index = 0;
+
+        let tile_count = self.object_count;
+        while start_from_index < tile_count {
+            match self.next_good_tile(&mut bounds, start_from_index) {
+                None => break,
+                Some(mut current_index) => {
+                    let (mut tile_x, mut tile_y) = self.tile_xy(current_index);
+                    let current_x = tile_x;
+
+                    while (tile_x == current_x) && (tile_y <= bounds.max_y) {
+                        f(current_index);
+
+                        current_index += 1;
+                        if current_index >= tile_count {
+                            break;
+                        }
+                        let (next_tile_x, next_tile_y) = self.tile_xy(current_index);
+                        tile_x = next_tile_x;
+                        tile_y = next_tile_y;
+                    }
+
+                    start_from_index = current_index;
+                    bounds.min_x = current_x + 1;
+                }
+            }
+        }
+    }
+
+    fn next_good_tile(&self, bounds: &mut tile::TileRange, start_index: usize) -> Option<usize> {
+        let tile_count = self.object_count;
+        if start_index >= tile_count {
+            return None;
+        }
+
+        let find_smallest_feasible_index = |from, min_x, min_y| {
+            let large_enough = |idx| self.tile_xy(idx) >= (min_x, min_y);
+
+            let mut lo = from;
+            let mut hi = tile_count - 1;
+
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+
+                if large_enough(mid) {
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+
+            if large_enough(lo) {
+                Some(lo)
+            } else {
+                None
+            }
+        };
+
+        let mut idx = start_index;
+        while let Some(next_idx) = find_smallest_feasible_index(idx, bounds.min_x, bounds.min_y) {
+            let (tile_x, tile_y) = self.tile_xy(next_idx);
+            if (tile_x, tile_y) > (bounds.max_x, bounds.max_y) {
+                return None;
+            }
+
+            if tile_x == bounds.min_x {
+                return Some(next_idx);
+            }
+
+            idx = next_idx;
+            bounds.min_x = tile_x;
+        }
+
+        None
+    }
+
+    fn tile_xy(&self, idx: usize) -> (u32, u32) {
+        let tile = self.get_object(idx);
+        let mut cursor = Cursor::new(tile);
+        let x = cursor.read_u32::<LittleEndian>().unwrap();
+        let y = cursor.read_u32::<LittleEndian>().unwrap();
+        (x, y)
+    }
 }
 
 struct ObjectStorages<'a> {
+    coordinates: CoordinatePrecision,
+    // How many units make a degree, if the coordinates of the nodes are integers (see `CoordinatePrecision`).
+    coordinate_units: Option<f64>,
     node_storage: ObjectStorage<'a>,
+    poi_storage: ObjectStorage<'a>,
     way_storage: ObjectStorage<'a>,
     polygon_storage: ObjectStorage<'a>,
     multipolygon_storage: ObjectStorage<'a>,
+    poi_lifetime_storage: ObjectStorage<'a>,
+    way_lifetime_storage: ObjectStorage<'a>,
+    multipolygon_lifetime_storage: ObjectStorage<'a>,
     tile_storage: ObjectStorage<'a>,
+    poi_tile_storage: ObjectStorage<'a>,
+    place_name_storage: ObjectStorage<'a>,
+    landcover_storage: ObjectStorage<'a>,
+    baked_style_storage: ObjectStorage<'a>,
+    baked_tile_storage: ObjectStorage<'a>,
     ints: &'a [u32],
     strings: &'a [u8],
 }
 
 const INT_REF_SIZE: usize = 2 * mem::size_of::<u32>();
-const NODE_SIZE: usize = mem::size_of::<u64>() + 2 * mem::size_of::<f64>() + INT_REF_SIZE;
+const NODE_HEADER_SIZE: usize = mem::size_of::<u64>();
 const POLYGON_SIZE: usize = INT_REF_SIZE;
 const WAY_OR_MULTIPOLYGON_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
-const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 3 * INT_REF_SIZE;
+const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 2 * INT_REF_SIZE;
+const POI_TILE_SIZE: usize = 2 * mem::size_of::<u32>() + INT_REF_SIZE;
+const LIFETIME_SIZE: usize = 2 * mem::size_of::<i64>();
+const PLACE_NAME_SIZE: usize = INT_REF_SIZE + 4 * mem::size_of::<f64>();
+const LANDCOVER_GRID_SIZE: usize = 3 * mem::size_of::<u32>() + INT_REF_SIZE;
+const STRING_REF_SIZE: usize = 2 * mem::size_of::<u32>();
+const BAKED_TILE_SIZE: usize = 3 * mem::size_of::<u32>() + STRING_REF_SIZE;
 
 impl<'a> ObjectStorages<'a> {
     // All geodata members have sizes divisible by 4, so the u8* -> u32* cast should be safe,
     // provided that `bytes` is aligned to 4 bytes (if it's not, we're in trouble anyway).
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_ptr_alignment))]
+    #[allow(clippy::cast_ptr_alignment)]
     fn from_bytes(bytes: &[u8]) -> ObjectStorages<'_> {
-        let (node_storage, rest) = ObjectStorage::from_bytes(bytes, NODE_SIZE);
+        let coordinates = CoordinatePrecision::from_header(LittleEndian::read_u32(bytes));
+        let coordinate_units = coordinates.units_per_degree();
+        let coordinate_size = match coordinate_units {
+            Some(_) => mem::size_of::<i32>(),
+            None => mem::size_of::<f64>(),
+        };
+        let node_size = NODE_HEADER_SIZE + 2 * coordinate_size + INT_REF_SIZE;
+        let rest = &bytes[mem::size_of::<u32>()..];
+        let (node_storage, rest) = ObjectStorage::from_bytes(rest, node_size);
+        let (poi_storage, rest) = ObjectStorage::from_bytes(rest, node_size);
         let (way_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
         let (polygon_storage, rest) = ObjectStorage::from_bytes(rest, POLYGON_SIZE);
         let (multipolygon_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
+        let (poi_lifetime_storage, rest) = ObjectStorage::from_bytes(rest, LIFETIME_SIZE);
+        let (way_lifetime_storage, rest) = ObjectStorage::from_bytes(rest, LIFETIME_SIZE);
+        let (multipolygon_lifetime_storage, rest) = ObjectStorage::from_bytes(rest, LIFETIME_SIZE);
         let (tile_storage, rest) = ObjectStorage::from_bytes(rest, TILE_SIZE);
+        let (poi_tile_storage, rest) = ObjectStorage::from_bytes(rest, POI_TILE_SIZE);
+        let (place_name_storage, rest) = ObjectStorage::from_bytes(rest, PLACE_NAME_SIZE);
+        let (landcover_storage, rest) = ObjectStorage::from_bytes(rest, LANDCOVER_GRID_SIZE);
+        let (baked_style_storage, rest) = ObjectStorage::from_bytes(rest, STRING_REF_SIZE);
+        let (baked_tile_storage, rest) = ObjectStorage::from_bytes(rest, BAKED_TILE_SIZE);
 
         let int_count = LittleEndian::read_u32(rest) as usize;
         let start_pos = mem::size_of::<u32>();
@@ -328,11 +665,22 @@ impl<'a> ObjectStorages<'a> {
         let strings = &rest[end_pos..];
 
         ObjectStorages {
+            coordinates,
+            coordinate_units,
             node_storage,
+            poi_storage,
             way_storage,
             polygon_storage,
             multipolygon_storage,
+            poi_lifetime_storage,
+            way_lifetime_storage,
+            multipolygon_lifetime_storage,
             tile_storage,
+            poi_tile_storage,
+            place_name_storage,
+            landcover_storage,
+            baked_style_storage,
+            baked_tile_storage,
             ints,
             strings,
         }
@@ -376,7 +724,18 @@ impl<'a> Tags<'a> {
         }
     }
 
-    pub fn iter(&'a self) -> impl Iterator<Item = (StringWithOffset<'a>, StringWithOffset<'a>)> {
+    pub fn is_empty(&self) -> bool {
+        self.get_kv_count() == 0
+    }
+
+    /// Iterates over the tags sorted by key. The strings point into the geodata file, so nothing is allocated.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.iter_with_offsets().map(|(k, v)| (k.str, v.str))
+    }
+
+    /// Same as `iter`, but also returns the offsets of the strings, which are the same for equal strings
+    /// and therefore can be compared instead of the strings themselves.
+    pub fn iter_with_offsets(&self) -> impl Iterator<Item = (StringWithOffset<'a>, StringWithOffset<'a>)> + '_ {
         (0..self.get_kv_count()).map(move |idx| self.get_kv(idx))
     }
 
@@ -445,15 +804,24 @@ pub struct Node<'a> {
 
 implement_osm_entity!(Node<'a>);
 
+impl<'a> Node<'a> {
+    // The latitude is the first coordinate, and the longitude is the second one.
+    fn coordinate(&self, idx: usize) -> f64 {
+        let bytes = &self.entity.bytes[NODE_HEADER_SIZE..];
+        match self.entity.reader.storages().coordinate_units {
+            Some(units) => f64::from(LittleEndian::read_i32(&bytes[idx * mem::size_of::<i32>()..])) / units,
+            None => LittleEndian::read_f64(&bytes[idx * mem::size_of::<f64>()..]),
+        }
+    }
+}
+
 impl<'a> Coords for Node<'a> {
     fn lat(&self) -> f64 {
-        let start_pos = mem::size_of::<u64>();
-        LittleEndian::read_f64(&self.entity.bytes[start_pos..])
+        self.coordinate(0)
     }
 
     fn lon(&self) -> f64 {
-        let start_pos = mem::size_of::<u64>() + mem::size_of::<f64>();
-        LittleEndian::read_f64(&self.entity.bytes[start_pos..])
+        self.coordinate(1)
     }
 }
 
@@ -473,6 +841,10 @@ impl<'a> Way<'a> {
         let node_id = self.node_ids[idx];
         self.entity.reader.get_node(node_id as usize)
     }
+
+    pub(super) fn node_ids(&self) -> &'a [u32] {
+        self.node_ids
+    }
 }
 
 impl<'a> OsmArea for Way<'a> {
@@ -484,6 +856,14 @@ impl<'a> OsmArea for Way<'a> {
         let last_node = self.get_node(self.node_count() - 1);
         (first_node.lat(), first_node.lon()) == (last_node.lat(), last_node.lon())
     }
+
+    fn is_area(&self) -> bool {
+        match self.tags().get_by_key("area") {
+            Some("yes") => true,
+            Some("no") => false,
+            _ => self.is_closed(),
+        }
+    }
 }
 
 pub struct Polygon<'a> {
@@ -500,6 +880,10 @@ impl<'a> Polygon<'a> {
         let node_id = self.node_ids[idx];
         self.reader.get_node(node_id as usize)
     }
+
+    pub(super) fn node_ids(&self) -> &'a [u32] {
+        self.node_ids
+    }
 }
 
 pub struct Multipolygon<'a> {
@@ -518,10 +902,18 @@ impl<'a> Multipolygon<'a> {
         let polygon_id = self.polygon_ids[idx];
         self.entity.reader.get_polygon(polygon_id as usize)
     }
+
+    pub(super) fn polygon_ids(&self) -> &'a [u32] {
+        self.polygon_ids
+    }
 }
 
 impl<'a> OsmArea for Multipolygon<'a> {
     fn is_closed(&self) -> bool {
         true
     }
+
+    fn is_area(&self) -> bool {
+        true
+    }
 }
@@ -1,71 +1,158 @@
-use crate::geodata::importer::{EntityStorages, Multipolygon, Polygon, RawNode, RawRefs, RawWay};
+use crate::coords::GeoBounds;
+use crate::geodata::baked::BakedTiles;
+use crate::geodata::importer::{
+    CoordinatePrecision, EntityStorages, Lifetime, Multipolygon, Polygon, RawNode, RawRefs, RawTags, RawWay,
+};
+use crate::geodata::landcover::{encode_grid, get_landcover_grids, LandcoverGrids};
 use crate::tile;
 use anyhow::{bail, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::cmp::{max, min};
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::Write;
 
 #[derive(Default)]
 struct TileReferences {
-    local_node_ids: BTreeSet<usize>,
     local_way_ids: BTreeSet<usize>,
     local_multipolygon_ids: BTreeSet<usize>,
 }
 
+// POIs have an index of their own, so that getting the POIs in a tile doesn't involve
+// looking at the (usually much larger) way and multipolygon references.
 #[derive(Default)]
 struct TileIdToReferences {
     refs: BTreeMap<(u32, u32), TileReferences>,
+    poi_refs: BTreeMap<(u32, u32), Vec<usize>>,
 }
 
-pub(super) fn save_to_internal_format(writer: &mut dyn Write, entity_storages: &EntityStorages) -> Result<()> {
+pub(super) fn save_to_internal_format(
+    writer: &mut dyn Write,
+    entity_storages: &EntityStorages,
+    keep_untagged_nodes: bool,
+    landcover: bool,
+    baked_tiles: Option<&BakedTiles>,
+) -> Result<()> {
     let mut buffered_data = BufferedData::default();
+    let coordinates = entity_storages.coordinates;
+    writer.write_u32::<LittleEndian>(coordinates.to_header())?;
     let nodes = &entity_storages.node_storage.get_entities();
-    save_nodes(writer, nodes, &mut buffered_data)?;
+    save_nodes(writer, nodes, coordinates, &mut buffered_data)?;
 
-    let ways = &entity_storages.way_storage.get_entities();
-    save_ways(writer, ways, &mut buffered_data)?;
+    let node_storage = &entity_storages.node_storage;
+    let poi_ids = (0..nodes.len())
+        .filter(|&idx| (keep_untagged_nodes || is_poi(&nodes[idx])) && node_storage.is_drawn(idx))
+        .collect::<Vec<_>>();
+    let pois = poi_ids.iter().map(|&idx| &nodes[idx]).collect::<Vec<_>>();
+    save_pois(writer, &pois, coordinates, &mut buffered_data)?;
 
     let polygons = &entity_storages.polygon_storage;
+    let ways = &entity_storages.way_storage.get_entities();
+    save_ways(writer, ways, polygons, &mut buffered_data)?;
     save_polygons(writer, polygons, &mut buffered_data)?;
 
     let multipolygons = &entity_storages.multipolygon_storage.get_entities();
     save_multipolygons(writer, multipolygons, &mut buffered_data)?;
 
-    let tile_references = get_tile_references(entity_storages);
+    let node_lifetimes = entity_storages.node_storage.get_lifetimes();
+    let poi_lifetimes = node_lifetimes.map(|l| poi_ids.iter().map(|&idx| l[idx]).collect::<Vec<_>>());
+    save_lifetimes(writer, poi_lifetimes.as_deref())?;
+    save_lifetimes(writer, entity_storages.way_storage.get_lifetimes().map(|l| &l[..]))?;
+    save_lifetimes(
+        writer,
+        entity_storages.multipolygon_storage.get_lifetimes().map(|l| &l[..]),
+    )?;
+
+    let tile_references = get_tile_references(entity_storages, &pois);
     save_tile_references(writer, &tile_references, &mut buffered_data)?;
 
+    let place_names = get_place_names(entity_storages, &pois);
+    save_place_names(writer, &place_names, &mut buffered_data)?;
+
+    let landcover_grids = if landcover {
+        get_landcover_grids(entity_storages)
+    } else {
+        LandcoverGrids::new()
+    };
+    save_landcover_grids(writer, &landcover_grids, &mut buffered_data)?;
+
+    save_baked_tiles(writer, baked_tiles, &mut buffered_data)?;
+
     buffered_data.save(writer)?;
 
     Ok(())
 }
 
-impl TileIdToReferences {
-    fn tile_ref_by_node(&mut self, node: &RawNode) -> &mut TileReferences {
-        let node_tile = tile::coords_to_max_zoom_tile(node);
-        self.tile_ref_by_xy(node_tile.x, node_tile.y)
-    }
+// Normally, only the nodes that have tags are rendered on their own. The rest are just the points of ways and polygons.
+pub(super) fn is_poi(node: &RawNode) -> bool {
+    !node.tags.is_empty()
+}
 
+impl TileIdToReferences {
     fn tile_ref_by_xy(&mut self, tile_x: u32, tile_y: u32) -> &mut TileReferences {
-        self.refs.entry((tile_x, tile_y)).or_insert_with(Default::default)
+        self.refs.entry((tile_x, tile_y)).or_default()
     }
 }
 
-fn save_nodes(writer: &mut dyn Write, nodes: &[RawNode], data: &mut BufferedData) -> Result<()> {
+fn save_nodes(
+    writer: &mut dyn Write,
+    nodes: &[RawNode],
+    coordinates: CoordinatePrecision,
+    data: &mut BufferedData,
+) -> Result<()> {
     writer.write_u32::<LittleEndian>(to_u32_safe(nodes.len())?)?;
     for node in nodes {
-        writer.write_u64::<LittleEndian>(node.global_id)?;
-        writer.write_f64::<LittleEndian>(node.lat)?;
-        writer.write_f64::<LittleEndian>(node.lon)?;
-        save_tags(writer, &node.tags, data)?;
+        save_node(writer, node, coordinates, data)?;
+    }
+    Ok(())
+}
+
+// POIs are stored exactly like the nodes (which they're a copy of), but packed together, so that
+// reading them doesn't touch the memory occupied by the untagged nodes.
+fn save_pois(
+    writer: &mut dyn Write,
+    pois: &[&RawNode],
+    coordinates: CoordinatePrecision,
+    data: &mut BufferedData,
+) -> Result<()> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(pois.len())?)?;
+    for poi in pois {
+        save_node(writer, poi, coordinates, data)?;
+    }
+    Ok(())
+}
+
+fn save_node(
+    writer: &mut dyn Write,
+    node: &RawNode,
+    coordinates: CoordinatePrecision,
+    data: &mut BufferedData,
+) -> Result<()> {
+    writer.write_u64::<LittleEndian>(node.global_id)?;
+    match coordinates.units_per_degree() {
+        // The coordinates are already rounded to the units, and even 180 degrees of them fit into an i32.
+        Some(units) => {
+            writer.write_i32::<LittleEndian>((node.lat * units).round() as i32)?;
+            writer.write_i32::<LittleEndian>((node.lon * units).round() as i32)?;
+        }
+        None => {
+            writer.write_f64::<LittleEndian>(node.lat)?;
+            writer.write_f64::<LittleEndian>(node.lon)?;
+        }
     }
+    save_tags(writer, &node.tags, data)?;
     Ok(())
 }
 
-fn save_ways(writer: &mut dyn Write, ways: &[RawWay], data: &mut BufferedData) -> Result<()> {
+// The closed ways that are also the rings of the multipolygons (e.g. the outline of a building with a courtyard)
+// have their nodes stored once, for both of them.
+fn save_ways(writer: &mut dyn Write, ways: &[RawWay], polygons: &[Polygon], data: &mut BufferedData) -> Result<()> {
+    let rings = polygons.iter().map(Vec::as_slice).collect::<HashSet<_>>();
     writer.write_u32::<LittleEndian>(to_u32_safe(ways.len())?)?;
     for way in ways {
         writer.write_u64::<LittleEndian>(way.global_id)?;
+        if rings.contains(way.node_ids.as_slice()) {
+            data.ring_to_offset.insert(way.node_ids.clone(), data.all_ints.len());
+        }
         save_refs(writer, way.node_ids.iter(), data)?;
         save_tags(writer, &way.tags, data)?;
     }
@@ -75,7 +162,13 @@ fn save_ways(writer: &mut dyn Write, ways: &[RawWay], data: &mut BufferedData) -
 fn save_polygons(writer: &mut dyn Write, polygons: &[Polygon], data: &mut BufferedData) -> Result<()> {
     writer.write_u32::<LittleEndian>(to_u32_safe(polygons.len())?)?;
     for polygon in polygons {
-        save_refs(writer, polygon.iter(), data)?;
+        match data.ring_to_offset.get(polygon) {
+            Some(&offset) => {
+                writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+                writer.write_u32::<LittleEndian>(to_u32_safe(polygon.len())?)?;
+            }
+            None => save_refs(writer, polygon.iter(), data)?,
+        }
     }
     Ok(())
 }
@@ -90,6 +183,17 @@ fn save_multipolygons(writer: &mut dyn Write, multipolygons: &[Multipolygon], da
     Ok(())
 }
 
+// The section is empty if the timestamps weren't kept during the import.
+fn save_lifetimes(writer: &mut dyn Write, lifetimes: Option<&[Lifetime]>) -> Result<()> {
+    let lifetimes = lifetimes.unwrap_or_default();
+    writer.write_u32::<LittleEndian>(to_u32_safe(lifetimes.len())?)?;
+    for lifetime in lifetimes {
+        writer.write_i64::<LittleEndian>(lifetime.from)?;
+        writer.write_i64::<LittleEndian>(lifetime.until)?;
+    }
+    Ok(())
+}
+
 fn save_tile_references(
     writer: &mut dyn Write,
     tile_references: &TileIdToReferences,
@@ -100,11 +204,81 @@ fn save_tile_references(
         writer.write_u32::<LittleEndian>(k.0)?;
         writer.write_u32::<LittleEndian>(k.1)?;
 
-        save_refs(writer, v.local_node_ids.iter(), data)?;
         save_refs(writer, v.local_way_ids.iter(), data)?;
         save_refs(writer, v.local_multipolygon_ids.iter(), data)?;
     }
 
+    writer.write_u32::<LittleEndian>(to_u32_safe(tile_references.poi_refs.len())?)?;
+    for (k, v) in &tile_references.poi_refs {
+        writer.write_u32::<LittleEndian>(k.0)?;
+        writer.write_u32::<LittleEndian>(k.1)?;
+
+        save_refs(writer, v.iter(), data)?;
+    }
+
+    Ok(())
+}
+
+// The names are sorted case-insensitively, so that a place can be looked up with a binary search.
+fn save_place_names(
+    writer: &mut dyn Write,
+    place_names: &[(String, GeoBounds)],
+    data: &mut BufferedData,
+) -> Result<()> {
+    let mut sorted = place_names.iter().collect::<Vec<_>>();
+    sorted.sort_by_cached_key(|(name, _)| name.to_lowercase());
+    writer.write_u32::<LittleEndian>(to_u32_safe(sorted.len())?)?;
+    for (name, bounds) in sorted {
+        let (offset, length) = data.add_string(name);
+        writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+        writer.write_u32::<LittleEndian>(to_u32_safe(length)?)?;
+        for coord in [bounds.min_lon, bounds.min_lat, bounds.max_lon, bounds.max_lat] {
+            writer.write_f64::<LittleEndian>(coord)?;
+        }
+    }
+    Ok(())
+}
+
+// Sorted by (zoom, x, y), so that the grid of a tile can be found with a binary search.
+fn save_landcover_grids(writer: &mut dyn Write, grids: &LandcoverGrids, data: &mut BufferedData) -> Result<()> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(grids.len())?)?;
+    for ((zoom, x, y), grid) in grids {
+        writer.write_u32::<LittleEndian>(u32::from(*zoom))?;
+        writer.write_u32::<LittleEndian>(*x)?;
+        writer.write_u32::<LittleEndian>(*y)?;
+        let offset = data.all_ints.len();
+        data.all_ints.extend(encode_grid(grid));
+        writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+        writer.write_u32::<LittleEndian>(to_u32_safe(data.all_ints.len() - offset)?)?;
+    }
+    Ok(())
+}
+
+// The version of the style (if there are any tiles) and then the tiles, sorted by (zoom, x, y) like the landcover grids.
+// The images are stored with the strings, and the identical ones (e.g. the empty tiles) only once.
+fn save_baked_tiles(writer: &mut dyn Write, baked_tiles: Option<&BakedTiles>, data: &mut BufferedData) -> Result<()> {
+    let write_ref = |writer: &mut dyn Write, (offset, length): (usize, usize)| -> Result<()> {
+        writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+        writer.write_u32::<LittleEndian>(to_u32_safe(length)?)?;
+        Ok(())
+    };
+    match baked_tiles {
+        Some(baked_tiles) => {
+            writer.write_u32::<LittleEndian>(1)?;
+            write_ref(writer, data.add_string(&baked_tiles.style_version))?;
+            writer.write_u32::<LittleEndian>(to_u32_safe(baked_tiles.tiles.len())?)?;
+            for ((zoom, x, y), png) in &baked_tiles.tiles {
+                writer.write_u32::<LittleEndian>(u32::from(*zoom))?;
+                writer.write_u32::<LittleEndian>(*x)?;
+                writer.write_u32::<LittleEndian>(*y)?;
+                write_ref(writer, data.add_bytes(png))?;
+            }
+        }
+        None => {
+            writer.write_u32::<LittleEndian>(0)?;
+            writer.write_u32::<LittleEndian>(0)?;
+        }
+    }
     Ok(())
 }
 
@@ -130,7 +304,20 @@ fn save_tags(writer: &mut dyn Write, tags: &BTreeMap<String, String>, data: &mut
         kv_refs.extend([k_offset, k_length, v_offset, v_length].iter());
     }
 
-    save_refs(writer, kv_refs.iter(), data)?;
+    // Lots of entities have exactly the same tags (think `amenity=bench`), so we store every set of tags only once.
+    let offset = match data.kv_refs_to_offset.get(&kv_refs) {
+        Some(offset) => *offset,
+        None => {
+            let offset = data.all_ints.len();
+            for r in &kv_refs {
+                data.all_ints.push(to_u32_safe(*r)?);
+            }
+            data.kv_refs_to_offset.insert(kv_refs.clone(), offset);
+            offset
+        }
+    };
+    writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+    writer.write_u32::<LittleEndian>(to_u32_safe(kv_refs.len())?)?;
 
     Ok(())
 }
@@ -139,6 +326,10 @@ fn save_tags(writer: &mut dyn Write, tags: &BTreeMap<String, String>, data: &mut
 struct BufferedData {
     all_ints: Vec<u32>,
     string_to_offset: HashMap<String, usize>,
+    kv_refs_to_offset: HashMap<RawRefs, usize>,
+    // The nodes of the ways that are the same as some rings of the multipolygons.
+    ring_to_offset: HashMap<RawRefs, usize>,
+    bytes_to_offset: HashMap<Vec<u8>, usize>,
     all_strings: Vec<u8>,
 }
 
@@ -154,6 +345,16 @@ impl BufferedData {
         (*offset, bytes.len())
     }
 
+    fn add_bytes(&mut self, bytes: &[u8]) -> (usize, usize) {
+        let all_strings = &mut self.all_strings;
+        let offset = self.bytes_to_offset.entry(bytes.to_vec()).or_insert_with(|| {
+            let offset = all_strings.len();
+            all_strings.extend_from_slice(bytes);
+            offset
+        });
+        (*offset, bytes.len())
+    }
+
     fn save(&self, writer: &mut dyn Write) -> Result<()> {
         writer.write_u32::<LittleEndian>(to_u32_safe(self.all_ints.len())?)?;
         for i in &self.all_ints {
@@ -164,14 +365,16 @@ impl BufferedData {
     }
 }
 
-fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
+fn get_tile_references(entity_storages: &EntityStorages, pois: &[&RawNode]) -> TileIdToReferences {
     let mut result = TileIdToReferences::default();
 
-    let nodes = &entity_storages.node_storage.get_entities();
-    for (i, node) in nodes.iter().enumerate() {
-        result.tile_ref_by_node(node).local_node_ids.insert(i);
+    for (i, poi) in pois.iter().enumerate() {
+        let poi_tile = tile::coords_to_max_zoom_tile(*poi);
+        result.poi_refs.entry((poi_tile.x, poi_tile.y)).or_default().push(i);
     }
 
+    let nodes = &entity_storages.node_storage.get_entities();
+
     for (i, way) in entity_storages.way_storage.get_entities().iter().enumerate() {
         let node_ids = way.node_ids.iter().map(|idx| &nodes[*idx]);
 
@@ -191,6 +394,65 @@ fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
     result
 }
 
+// The named places (`place=*` nodes and ways) and the named relations (cities, districts, parks and so on)
+// with their bounding boxes, so that an area can be found by its name without an external geocoder.
+fn get_place_names(entity_storages: &EntityStorages, pois: &[&RawNode]) -> Vec<(String, GeoBounds)> {
+    let nodes = &entity_storages.node_storage.get_entities();
+    let polygons = &entity_storages.polygon_storage;
+    let get_name = |tags: &RawTags, needs_place: bool| {
+        if needs_place && !tags.contains_key("place") {
+            return None;
+        }
+        tags.get("name").cloned()
+    };
+
+    let mut result = Vec::new();
+    for poi in pois {
+        if let Some(name) = get_name(&poi.tags, true) {
+            result.push((name, get_bounds(std::iter::once(*poi)).unwrap()));
+        }
+    }
+    for way in entity_storages.way_storage.get_entities() {
+        if let Some(name) = get_name(&way.tags, true) {
+            if let Some(bounds) = get_bounds(way.node_ids.iter().map(|idx| &nodes[*idx])) {
+                result.push((name, bounds));
+            }
+        }
+    }
+    for multipolygon in entity_storages.multipolygon_storage.get_entities() {
+        if let Some(name) = get_name(&multipolygon.tags, false) {
+            let multipolygon_nodes = multipolygon
+                .polygon_ids
+                .iter()
+                .flat_map(|poly_id| polygons[*poly_id].iter())
+                .map(|idx| &nodes[*idx]);
+            if let Some(bounds) = get_bounds(multipolygon_nodes) {
+                result.push((name, bounds));
+            }
+        }
+    }
+    result
+}
+
+fn get_bounds<'a>(nodes: impl Iterator<Item = &'a RawNode>) -> Option<GeoBounds> {
+    nodes.fold(None, |bounds, node| {
+        Some(match bounds {
+            None => GeoBounds {
+                min_lon: node.lon,
+                min_lat: node.lat,
+                max_lon: node.lon,
+                max_lat: node.lat,
+            },
+            Some(b) => GeoBounds {
+                min_lon: b.min_lon.min(node.lon),
+                min_lat: b.min_lat.min(node.lat),
+                max_lon: b.max_lon.max(node.lon),
+                max_lat: b.max_lat.max(node.lat),
+            },
+        })
+    })
+}
+
 fn insert_entity_id_to_tiles<'a, I>(
     result: &mut TileIdToReferences,
     mut nodes: I,
@@ -226,7 +488,7 @@ fn insert_entity_id_to_tiles<'a, I>(
 }
 
 fn to_u32_safe(num: usize) -> Result<u32> {
-    if num > (u32::max_value() as usize) {
+    if num > (u32::MAX as usize) {
         bail!("{} doesn't fit into u32", num);
     }
     Ok(num as u32)
@@ -239,6 +501,26 @@ mod tests {
     use std::fs::File;
     use std::io::BufWriter;
 
+    #[test]
+    fn test_shared_rings() {
+        let way = |global_id: u64, node_ids: &[usize]| RawWay {
+            global_id,
+            node_ids: node_ids.to_vec(),
+            tags: RawTags::default(),
+        };
+        let ways = [way(1, &[0, 1, 2, 0]), way(2, &[3, 4])];
+        let polygons = [vec![5, 6, 7, 5], vec![0, 1, 2, 0]];
+
+        let mut data = BufferedData::default();
+        let mut bytes = Vec::new();
+        save_ways(&mut bytes, &ways, &polygons, &mut data).unwrap();
+        save_polygons(&mut bytes, &polygons, &mut data).unwrap();
+        assert_eq!(data.all_ints, vec![0, 1, 2, 0, 3, 4, 5, 6, 7, 5]);
+        // The second polygon refers to the nodes of the first way.
+        let polygon_refs = &bytes[bytes.len() - 16..];
+        assert_eq!(polygon_refs[8..], [0, 0, 0, 0, 4, 0, 0, 0]);
+    }
+
     #[test]
     fn test_synthetic_data() {
         let mut good_node_ids = Vec::new();
@@ -290,13 +572,11 @@ mod tests {
             });
         }
 
+        let pois = nodes.iter().collect::<Vec<_>>();
+
         let mut tile_refs = TileIdToReferences::default();
         for (idx, &(x, y)) in tile_ids.iter().enumerate() {
-            tile_refs.refs.entry((x, y)).or_insert(TileReferences {
-                local_node_ids: [idx].iter().cloned().collect(),
-                local_way_ids: BTreeSet::default(),
-                local_multipolygon_ids: BTreeSet::default(),
-            });
+            tile_refs.poi_refs.entry((x, y)).or_insert(vec![idx]);
         }
 
         let mut tmp_path = env::temp_dir();
@@ -307,11 +587,20 @@ mod tests {
             let mut writer = BufWriter::new(tmp_file);
 
             let mut data = BufferedData::default();
-            save_nodes(&mut writer, &nodes, &mut data).unwrap();
-            save_ways(&mut writer, &[], &mut data).unwrap();
+            let coordinates = CoordinatePrecision::default();
+            writer.write_u32::<LittleEndian>(coordinates.to_header()).unwrap();
+            save_nodes(&mut writer, &nodes, coordinates, &mut data).unwrap();
+            save_pois(&mut writer, &pois, coordinates, &mut data).unwrap();
+            save_ways(&mut writer, &[], &[], &mut data).unwrap();
             save_polygons(&mut writer, &[], &mut data).unwrap();
             save_multipolygons(&mut writer, &[], &mut data).unwrap();
+            for _ in 0..3 {
+                save_lifetimes(&mut writer, None).unwrap();
+            }
             save_tile_references(&mut writer, &tile_refs, &mut data).unwrap();
+            save_place_names(&mut writer, &[], &mut data).unwrap();
+            save_landcover_grids(&mut writer, &LandcoverGrids::new(), &mut data).unwrap();
+            save_baked_tiles(&mut writer, None, &mut data).unwrap();
             data.save(&mut writer).unwrap();
         }
 
@@ -319,6 +608,6 @@ mod tests {
         let tile = crate::tile::Tile { zoom: 15, x: 0, y: 1 };
         let mut local_ids = crate::geodata::reader::OsmEntityIds::default();
         reader.get_entities_in_tile(&tile, &mut local_ids);
-        assert_eq!(good_node_ids, local_ids.nodes);
+        assert_eq!(good_node_ids, local_ids.pois);
     }
 }
@@ -0,0 +1,111 @@
+use anyhow::{bail, Context, Result};
+
+/// Parses a UTC date (`YYYY-MM-DD`) or date and time (`YYYY-MM-DDTHH:MM:SSZ`, as in OSM files) into a UNIX timestamp.
+/// # Examples
+/// ```
+/// use renderer::geodata::timestamp::parse_timestamp;
+/// assert_eq!(parse_timestamp("1970-01-01").unwrap(), 0);
+/// assert_eq!(parse_timestamp("2020-01-01").unwrap(), 1577836800);
+/// assert_eq!(parse_timestamp("2012-08-17T13:07:26Z").unwrap(), 1345208846);
+/// assert_eq!(parse_timestamp("1969-12-31T23:59:59Z").unwrap(), -1);
+/// assert!(parse_timestamp("2020-13-01").is_err());
+/// assert!(parse_timestamp("2020-01-01T25:00:00Z").is_err());
+/// assert!(parse_timestamp("yesterday").is_err());
+/// ```
+pub fn parse_timestamp(s: &str) -> Result<i64> {
+    parse_timestamp_impl(s).context(format!("Invalid timestamp: {}", s))
+}
+
+/// Formats a UNIX timestamp as a UTC date (`YYYY-MM-DD`), dropping the time of day.
+/// # Examples
+/// ```
+/// use renderer::geodata::timestamp::format_date;
+/// assert_eq!(format_date(0), "1970-01-01");
+/// assert_eq!(format_date(1345208846), "2012-08-17");
+/// assert_eq!(format_date(951782400), "2000-02-29");
+/// assert_eq!(format_date(-1), "1969-12-31");
+/// ```
+pub fn format_date(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats a UNIX timestamp as an HTTP date, e.g. for the `Expires` header.
+/// # Examples
+/// ```
+/// use renderer::geodata::timestamp::format_http_date;
+/// assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+/// assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn format_http_date(timestamp: i64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = timestamp.div_euclid(86400);
+    let seconds_of_day = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+fn parse_timestamp_impl(s: &str) -> Result<i64> {
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z').unwrap_or(time))),
+        None => (s, None),
+    };
+
+    let date_parts = date.split('-').map(str::parse::<i64>).collect::<Result<Vec<_>, _>>()?;
+    let (year, month, day) = match date_parts[..] {
+        [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => (year, month, day),
+        _ => bail!("Expected a date in the YYYY-MM-DD format"),
+    };
+
+    let seconds_of_day = match time {
+        Some(time) => {
+            let time_parts = time.split(':').map(str::parse::<i64>).collect::<Result<Vec<_>, _>>()?;
+            match time_parts[..] {
+                [h, m, s] if (0..24).contains(&h) && (0..60).contains(&m) && (0..=60).contains(&s) => {
+                    h * 3600 + m * 60 + s
+                }
+                _ => bail!("Expected a time in the HH:MM:SS format"),
+            }
+        }
+        None => 0,
+    };
+
+    Ok(days_from_civil(year, month, day) * 86400 + seconds_of_day)
+}
+
+// The number of days since 1970-01-01 in the proleptic Gregorian calendar
+// (see http://howardhinnant.github.io/date_algorithms.html#days_from_civil).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The reverse of `days_from_civil` (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -1,25 +1,376 @@
-use crate::draw::drawer::Drawer;
+use crate::api_keys::{ApiKeys, KeyError};
+use crate::cache_control::CacheControl;
+use crate::coords::GeoBounds;
+use crate::cors::Cors;
+use crate::draw::cancellation::{CancellationToken, Cancelled};
+use crate::draw::drawer::{DrawOptions, Drawer, ImageFormat, TileLayers, TileRequest};
+use crate::draw::sprite::{escape_json, Sprite};
 use crate::draw::tile_pixels::TilePixels;
-use crate::geodata::reader::GeodataReader;
-use crate::mapcss::parser::parse_file;
-use crate::mapcss::styler::{StyleType, Styler};
+use crate::draw::view::render_static_map;
+use crate::geodata::geojson::tile_to_geojson;
+use crate::geodata::indoor::retain_level;
+use crate::geodata::reader::{GeodataReader, OsmEntities};
+use crate::geodata::timestamp::parse_timestamp;
+use crate::map_matching::MapMatcher;
+use crate::mapcss::dark::DarkMode;
+use crate::mapcss::palette::Palette;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
+use crate::mapcss::settings::apply_settings;
+use crate::mapcss::styler::{get_style_version, StyleType, Styler};
+use crate::memory_budget::MemoryBudget;
+use crate::metrics::{CacheKind, Metrics};
 use crate::perf_stats::PerfStats;
-use crate::tile::{Tile, MAX_ZOOM};
-use anyhow::{anyhow, bail, Context, Result};
+use crate::tile::{zoom_offset_for_tile_size, Tile, MAX_OVERZOOM, MAX_ZOOM, TILE_SIZE};
+use crate::tile_cache::memory::MemoryCache;
+use crate::tile_cache::pending::{Coalesced, PendingRenders};
+use crate::tile_cache::{CachedTile, KeyLayout, TileCache};
+use crate::upstream::{Upstream, FORWARDED_REQUEST_HEADERS};
+use crate::wmts::{self, WmtsLayer, WmtsRequest};
+use anyhow::{bail, Context, Result};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use sha2::{Digest, Sha256};
 use std::collections::HashSet;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::RwLock;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::{task, time};
+
+const DEFAULT_MATCHING_ZOOM: u8 = 16;
+const DEFAULT_MATCHING_DISTANCE: f64 = 20.0;
+
+const MAX_HEADER_COUNT: usize = 100;
+
+// How many requests may wait for the render threads, per thread, before the server stops reading the new ones.
+const QUEUED_REQUESTS_PER_THREAD: usize = 16;
+
+// How often the cache is checked for the tiles that another server is drawing.
+const LOCKED_TILE_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+// How often the usage of the API keys is saved, i.e. how many tiles can go uncounted if the server crashes.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The largest `@Nx` tiles, which already take 16 times as much memory and time as the usual ones.
+// The bigger tiles can't be scaled as much, as long as they don't get any larger than that.
+const MAX_TILE_SCALE: usize = 4;
+const MAX_SCALED_TILE_SIZE: usize = MAX_TILE_SCALE * TILE_SIZE as usize;
+
+// The largest width and height of a static map, which then takes as much memory as 256 usual tiles.
+const MAX_STATIC_MAP_SIZE: usize = 4096;
+
+// The version of the text format of Prometheus.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub const DEFAULT_ATTRIBUTION: &str = "© OpenStreetMap contributors";
+// The TileJSON and WMTS bounds of the maps without any data: the whole world that the Web Mercator tiles cover.
+const WORLD_BOUNDS: [f64; 4] = [-180.0, -85.051129, 180.0, 85.051129];
+// The WMTS layer of the map that is served without a name.
+const DEFAULT_WMTS_LAYER: &str = "map";
 
 enum HandlerMessage {
     Terminate,
-    ServeTile { path: String, stream: TcpStream },
+    ServeTile {
+        // Boxed, as the queue holds many of the requests.
+        request: Box<HttpRequest>,
+        responder: oneshot::Sender<Handled<'static>>,
+    },
+    RenderTile {
+        job: Box<TileJob<'static>>,
+        responder: oneshot::Sender<RenderedTile>,
+    },
+}
+
+// What the render threads make of a request.
+enum Handled<'a> {
+    Response(HttpResponse),
+    // The tile isn't anywhere yet, see `serve_tile`.
+    Render(Box<TileJob<'a>>),
+}
+
+// A tile that has to be drawn (or fetched, or found in the tile cache), once its request has got past everything
+// that doesn't need the tile itself: the API keys, `If-None-Match`, and the tiles kept in memory or baked into
+// the geodata. The requests for the same tile that come in at the same time share a single job.
+struct TileJob<'a> {
+    map_name: Option<String>,
+    geodata: Arc<GeodataSnapshot<'a>>,
+    tile: RequestTile,
+    date: Option<i64>,
+    fade: Option<f64>,
+    palette: Option<Palette>,
+    level: Option<f64>,
+    image_format: ImageFormat,
+    // The headers for the client that has asked for the tile (i.e. CORS), and the headers of the tile itself.
+    client_headers: Vec<String>,
+    tile_headers: Vec<String>,
+    // Names everything that makes the tile different (see `get_tile_etag`), unless the tile isn't for sharing.
+    etag: Option<String>,
+    // The keys of the tile in the caches, unless the tile is drawn differently on request.
+    cache_key: Option<String>,
+    memory_cache_key: Option<String>,
+    // Claims the drawing of the tile from the other servers that share the tile cache.
+    lock_token: Option<String>,
+    client: IpAddr,
+    forwarded_headers: Vec<(String, String)>,
+    cancellation: CancellationToken,
+}
+
+impl TileJob<'_> {
+    // The response to a request that has got the tile drawn for another one.
+    fn tile_response(&self, tile_bytes: &[u8]) -> HttpResponse {
+        let mut client = Client {
+            headers: self.client_headers.clone(),
+            response: None,
+        };
+        serve_data_with_headers(
+            &mut client,
+            tile_bytes,
+            self.image_format.content_type(),
+            &as_strs(&self.tile_headers),
+        );
+        client.response.unwrap_or_else(HttpResponse::internal_error)
+    }
+}
+
+struct RenderedTile {
+    response: HttpResponse,
+    // Whether the body of the response is the tile, which the other requests for it can get too.
+    is_shared: bool,
+}
+
+struct HttpRequest {
+    // `GET` or `OPTIONS` (a preflight request).
+    method: Method,
+    path: String,
+    client: IpAddr,
+    // Stops the render when the client goes away, or when a newer request for its viewport comes in.
+    cancellation: CancellationToken,
+    // The value of the `X-Viewport` header, if it's valid.
+    viewport: Option<Viewport>,
+    // The value of the `Origin` header, if any.
+    origin: Option<String>,
+    // The value of the `Access-Control-Request-Headers` header of a preflight request, if any.
+    requested_headers: Option<String>,
+    // The value of the `Accept` header, if any.
+    accept: Option<String>,
+    // The values of the `Host` and the `X-Forwarded-Proto` headers, if any, for the links back to the server.
+    host: Option<String>,
+    forwarded_proto: Option<String>,
+    // The headers that are sent on to the upstream server (see `FORWARDED_REQUEST_HEADERS`).
+    forwarded_headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    // One of the forwarded headers, by its name as it's spelled in `FORWARDED_REQUEST_HEADERS`.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.forwarded_headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// The view of the map that a client asks the tiles for, as `X-Viewport: <session>:<generation>`. Whenever the map
+// is panned or zoomed, the client moves on to the next generation, and the tiles of the older ones that aren't drawn
+// yet are given up. The clients that don't send the header only have their renders cancelled by disconnecting.
+#[derive(Clone, Debug, PartialEq)]
+struct Viewport {
+    session: String,
+    generation: u64,
+}
+
+impl Viewport {
+    fn parse(header: &str) -> Option<Viewport> {
+        let (session, generation) = header.rsplit_once(':')?;
+        if session.is_empty() {
+            return None;
+        }
+        Some(Viewport {
+            session: session.to_string(),
+            generation: generation.parse().ok()?,
+        })
+    }
+
+    fn supersedes(&self, other: &Viewport) -> bool {
+        self.session == other.session && self.generation > other.generation
+    }
+}
+
+// A request that is being handled for a client, which can be cancelled if the client disconnects or moves on
+// to a newer viewport.
+struct InFlightRender {
+    id: u64,
+    viewport: Option<Viewport>,
+    cancellation: CancellationToken,
+}
+
+// Cancels the request and forgets about it when dropped, i.e. when the response is sent or the client has gone away.
+struct InFlightGuard<'s, 'a> {
+    server: &'s HttpServer<'a>,
+    id: u64,
+    cancellation: CancellationToken,
+}
+
+impl Drop for InFlightGuard<'_, '_> {
+    fn drop(&mut self) {
+        self.cancellation.cancel();
+        self.server.finish_render(self.id);
+    }
+}
+
+// The errors of the clients, who get the reason with a 4xx status instead of a 500.
+#[derive(Debug)]
+enum RequestError {
+    // The request can't mean anything, e.g. `/15/-1/abc.png` or `?fade=2`.
+    BadRequest(String),
+    // The request makes sense, but there's nothing like that on the server, e.g. `/3/8/0.png` or `/nonsense`.
+    NotFound(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::BadRequest(reason) | RequestError::NotFound(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl RequestError {
+    fn status(&self) -> &'static str {
+        match self {
+            RequestError::BadRequest(_) => "400 Bad Request",
+            RequestError::NotFound(_) => "404 Not Found",
+        }
+    }
+}
+
+// For the parsers of the query parameters, whose errors are all the client's.
+fn bad_request(error: anyhow::Error) -> RequestError {
+    RequestError::BadRequest(format!("{:#}", error))
+}
+
+struct HttpResponse {
+    // E.g. `200 OK`.
+    status: String,
+    // As `Name: value`.
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn empty(status: &str) -> HttpResponse {
+        HttpResponse {
+            status: status.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn internal_error() -> HttpResponse {
+        HttpResponse::empty("500 Internal Server Error")
+    }
+
+    // The client still waits for the response, but has moved on to a newer viewport (see `Viewport`).
+    fn superseded() -> HttpResponse {
+        HttpResponse::empty("409 Conflict")
+    }
+
+    fn status_code(&self) -> StatusCode {
+        self.status
+            .split(' ')
+            .next()
+            .and_then(|code| StatusCode::from_bytes(code.as_bytes()).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn into_hyper(self) -> Response<Full<Bytes>> {
+        let status = self.status_code();
+        let mut response = Response::new(Full::new(Bytes::from(self.body)));
+        *response.status_mut() = status;
+        for header in &self.headers {
+            let parsed = header.split_once(':').and_then(|(name, value)| {
+                let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+                let value = HeaderValue::from_str(value.trim()).ok()?;
+                Some((name, value))
+            });
+            match parsed {
+                Some((name, value)) => {
+                    response.headers_mut().append(name, value);
+                }
+                None => eprintln!("Invalid response header: {:?}", header),
+            }
+        }
+        response
+    }
+}
+
+// The geodata together with the styler that has cached the styles of its objects. Every request works with
+// the snapshot that was current when it came in, even if the geodata is reloaded in the meantime.
+struct GeodataSnapshot<'a> {
+    reader: GeodataReader<'a>,
+    // Names the cached tiles drawn from this geodata (see `CachedTile`).
+    data_version: String,
+    styler: Styler,
+    // The stylers that remap the colors for the palettes of the map.
+    palette_stylers: Vec<(Palette, Styler)>,
+    // The extent of the geodata, which takes a pass over all of its tiles, so it's only found when it's asked for.
+    bounds: OnceLock<Option<GeoBounds>>,
+}
+
+impl<'a> GeodataSnapshot<'a> {
+    fn new(reader: GeodataReader<'a>, styler: Styler, palette_stylers: Vec<(Palette, Styler)>) -> GeodataSnapshot<'a> {
+        // The files of two imports have different modification times.
+        let data_version = reader
+            .modified()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default()
+            .to_string();
+        GeodataSnapshot {
+            reader,
+            data_version,
+            styler,
+            palette_stylers,
+            bounds: OnceLock::new(),
+        }
+    }
+
+    fn bounds(&self) -> Option<&GeoBounds> {
+        self.bounds.get_or_init(|| self.reader.bounds()).as_ref()
+    }
+
+    fn styler(&self, palette: Option<Palette>) -> Result<&Styler> {
+        match palette {
+            Some(palette) => self
+                .palette_stylers
+                .iter()
+                .find(|(p, _)| *p == palette)
+                .map(|(_, styler)| styler)
+                .ok_or_else(|| RequestError::NotFound(format!("The map doesn't have the {} palette", palette.name())))
+                .map_err(Into::into),
+            None => Ok(&self.styler),
+        }
+    }
+
+    fn stylers(&self) -> impl Iterator<Item = &Styler> {
+        std::iter::once(&self.styler).chain(self.palette_stylers.iter().map(|(_, styler)| styler))
+    }
 }
 
 struct HandlerState {
@@ -27,258 +378,1890 @@ struct HandlerState {
     current_pixels: Box<TilePixels>,
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::implicit_hasher))]
-pub fn run_server(
-    address: &str,
-    geodata_file: &str,
-    stylesheet_file: &str,
-    stylesheet_type: &StyleType,
-    font_size_multiplier: Option<f64>,
-    osm_ids: Option<HashSet<u64>>,
-) -> Result<()> {
-    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
-    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
+/// One of the maps that the server draws, with its own geodata and style.
+pub struct MapConfig {
+    /// The map is served under `/<name>/` (e.g. `/paris/{z}/{x}/{y}.png`), or at the root if there's no name.
+    pub name: Option<String>,
+    pub geodata_file: String,
+    pub stylesheet_file: String,
+    pub stylesheet_type: StyleType,
+    pub font_size_multiplier: Option<f64>,
+    pub draw_options: DrawOptions,
+    /// Serves the tiles that the geodata doesn't cover.
+    pub upstream: Option<Upstream>,
+    /// Draws the map with the dark variant of the stylesheet.
+    pub dark_mode: Option<DarkMode>,
+    /// The palettes that the clients can ask for with `?palette=`.
+    pub palettes: Vec<Palette>,
+    /// The settings of the stylesheet that are turned on (see `apply_settings`).
+    pub settings: Vec<String>,
+    /// Credits the data in the TileJSON document of the map (`DEFAULT_ATTRIBUTION` if not given).
+    pub attribution: Option<String>,
+}
+
+/// The settings of the server that are shared by all the maps.
+pub struct ServerOptions {
+    /// 256, 512 or 1024 (see `zoom_offset_for_tile_size`).
+    pub tile_size: usize,
+    /// Only these objects are drawn, if given.
+    pub osm_ids: Option<HashSet<u64>>,
+    pub memory_budget: Option<MemoryBudget>,
+    pub api_keys: Option<ApiKeys>,
+    pub tile_cache: Option<Box<dyn TileCache>>,
+    pub tile_cache_layout: KeyLayout,
+    /// Looked into before `tile_cache`.
+    pub memory_cache: Option<MemoryCache>,
+    /// The caching headers of the tiles, by their zoom levels.
+    pub cache_control: CacheControl,
+    /// The pages that may use the responses from other origins.
+    pub cors: Cors,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            tile_size: TILE_SIZE as usize,
+            osm_ids: None,
+            memory_budget: None,
+            api_keys: None,
+            tile_cache: None,
+            tile_cache_layout: KeyLayout::default(),
+            memory_cache: None,
+            cache_control: CacheControl::default(),
+            cors: Cors::default(),
+        }
+    }
+}
+
+pub fn run_server(address: &str, maps: Vec<MapConfig>, options: ServerOptions) -> Result<()> {
+    let tcp_listener = std::net::TcpListener::bind(address).context(format!("Failed to bind to {}", address))?;
+    run_server_on(tcp_listener, maps, options)
+}
+
+/// Same as `run_server`, but listens on a socket that is already bound, e.g. to port 0 to get any free port.
+pub fn run_server_on(tcp_listener: std::net::TcpListener, maps: Vec<MapConfig>, options: ServerOptions) -> Result<()> {
+    let tile_size = options.tile_size;
+    if maps.is_empty() {
+        bail!("No maps to serve");
+    }
+    if zoom_offset_for_tile_size(tile_size as u32).is_none() {
+        bail!("Unsupported tile size: {} (expected 256, 512 or 1024)", tile_size);
+    }
+    let mut names = HashSet::new();
+    for name in maps.iter().map(|map| map.name.as_deref()) {
+        if let Some(name) = name {
+            validate_map_name(name)?;
+        }
+        if !names.insert(name) {
+            bail!("The map {} is configured twice", name.unwrap_or("at the root"));
+        }
+    }
+
+    // The stylers of all the maps (one per palette, and the usual one) share the budget equally.
+    let styler_count = maps.iter().map(|map| map.palettes.len() + 1).sum::<usize>();
+    let style_cache_bytes = options
+        .memory_budget
+        .map(|budget| budget.style_cache_bytes() / styler_count);
 
     let server = Arc::new(HttpServer {
-        styler: Styler::new(rules, stylesheet_type, font_size_multiplier),
-        reader: GeodataReader::load(geodata_file).context("Failed to load the geodata file")?,
-        drawer: Drawer::new(&base_path),
-        osm_ids,
+        maps: OnceLock::new(),
+        tile_size,
+        osm_ids: options.osm_ids,
+        memory_budget: options.memory_budget,
+        api_keys: options.api_keys,
+        tile_cache: options.tile_cache,
+        tile_cache_layout: options.tile_cache_layout,
+        memory_cache: options.memory_cache,
+        cache_control: options.cache_control,
+        cors: options.cors,
         perf_stats: Mutex::new(PerfStats::default()),
+        in_flight: Mutex::new(Vec::new()),
+        next_render_id: AtomicU64::new(0),
+        pending_renders: PendingRenders::new(),
+        metrics: Metrics::new(),
     });
 
-    let thread_count =
-        thread::available_parallelism().context("Failed to determine the number of threads to use for rendering")?;
+    let saved_server = Arc::downgrade(&server);
+    thread::spawn(move || loop {
+        thread::sleep(USAGE_SAVE_INTERVAL);
+        match saved_server.upgrade() {
+            Some(server) => server.save_usage(),
+            None => break,
+        }
+    });
 
-    let mut senders: Vec<Sender<HandlerMessage>> = Vec::new();
-    let mut receivers: Vec<Receiver<HandlerMessage>> = Vec::new();
-
-    for _ in 0..thread_count.into() {
-        let (tx, rx) = mpsc::channel();
-        senders.push(tx);
-        receivers.push(rx);
-    }
+    let thread_count: usize = thread::available_parallelism()
+        .context("Failed to determine the number of threads to use for rendering")?
+        .into();
 
+    // The connections are handled asynchronously, and the requests wait in the queue for one of the render
+    // threads (or, if the queue is full, for a place in the queue). This way, the slow clients don't keep
+    // the render threads busy, and there are never more renders at once than there are threads.
+    let (sender, receiver) = mpsc::channel(thread_count * QUEUED_REQUESTS_PER_THREAD);
+    let receiver = Arc::new(Mutex::new(receiver));
     let mut handlers = Vec::new();
 
-    for receiver in receivers {
+    for _ in 0..thread_count {
         let server_ref = Arc::clone(&server);
+        let receiver = Arc::clone(&receiver);
         handlers.push(thread::spawn(move || {
             let initial_scale = 1;
 
             let mut handler_state = HandlerState {
                 current_scale: initial_scale,
-                current_pixels: Box::new(TilePixels::new(initial_scale)),
+                current_pixels: Box::new(TilePixels::with_tile_size(server_ref.tile_size, initial_scale)),
             };
 
-            while let Ok(msg) = receiver.recv() {
+            loop {
+                let msg = receiver.lock().unwrap().blocking_recv();
                 match msg {
-                    HandlerMessage::Terminate => break,
-                    HandlerMessage::ServeTile { path, stream } => {
-                        server_ref.handle_connection(&path, stream, &mut handler_state)
+                    Some(HandlerMessage::ServeTile { request, responder }) => {
+                        // The client has gone away while the request was waiting in the queue.
+                        if request.cancellation.is_cancelled() {
+                            continue;
+                        }
+                        let _ = responder.send(server_ref.handle_connection(&request));
+                    }
+                    Some(HandlerMessage::RenderTile { job, responder }) => {
+                        if job.cancellation.is_cancelled() {
+                            server_ref.unlock_tile(&job);
+                            continue;
+                        }
+                        let _ = responder.send(server_ref.render_tile(&job, &mut handler_state));
                     }
+                    Some(HandlerMessage::Terminate) | None => break,
                 }
             }
         }));
     }
 
-    let tcp_listener = TcpListener::bind(address).context(format!("Failed to bind to {}", address))?;
-    let mut thread_id = 0;
+    tcp_listener
+        .set_nonblocking(true)
+        .context("Failed to set up the listening socket")?;
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .context("Failed to start the async runtime")?;
+    let shutdown = Arc::new(Notify::new());
+    let accept_loop = {
+        let _guard = runtime.enter();
+        let tcp_listener = TcpListener::from_std(tcp_listener).context("Failed to set up the listening socket")?;
+        runtime.spawn(accept_connections(
+            tcp_listener,
+            Arc::clone(&server),
+            sender.clone(),
+            Arc::clone(&shutdown),
+        ))
+    };
+
+    // The maps are loaded while the server is already answering `/healthz` and `/readyz`, so that the load
+    // balancers (or Kubernetes) don't send the clients to it until it's ready, and don't restart it meanwhile.
+    let loaded = maps
+        .into_iter()
+        .map(|config| TileMap::load(config, style_cache_bytes))
+        .collect::<Result<Vec<_>>>()
+        .map(|maps| {
+            let _ = server.maps.set(maps);
+        });
+    if loaded.is_err() {
+        shutdown.notify_one();
+    }
 
-    for mut stream in tcp_listener.incoming().flatten() {
-        let path = match extract_path_from_stream(&mut stream) {
-            Ok(path) => path,
+    runtime.block_on(async {
+        shutdown.notified().await;
+        accept_loop.abort();
+        // The requests that are already in the queue are handled before the threads exit.
+        for _ in 0..thread_count {
+            let _ = sender.send(HandlerMessage::Terminate).await;
+        }
+    });
+    for h in handlers {
+        h.join().unwrap();
+    }
+    // The open connections are closed without waiting for the clients.
+    runtime.shutdown_background();
+    server.save_usage();
+
+    loaded
+}
+
+async fn accept_connections(
+    tcp_listener: TcpListener,
+    server: Arc<HttpServer<'static>>,
+    sender: mpsc::Sender<HandlerMessage>,
+    shutdown: Arc<Notify>,
+) {
+    loop {
+        let (stream, addr) = match tcp_listener.accept().await {
+            Ok(accepted) => accepted,
             Err(e) => {
-                eprintln!("{} didn't send a valid HTTP request: {}", peer_addr(&stream), e);
+                eprintln!("Failed to accept a connection: {}", e);
                 continue;
             }
         };
+        let server = Arc::clone(&server);
+        let sender = sender.clone();
+        let shutdown = Arc::clone(&shutdown);
+        tokio::spawn(async move {
+            let service = service_fn(|request| {
+                handle_request(
+                    Arc::clone(&server),
+                    sender.clone(),
+                    Arc::clone(&shutdown),
+                    addr,
+                    request,
+                )
+            });
+            let connection = http1::Builder::new()
+                .max_headers(MAX_HEADER_COUNT)
+                .serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = connection.await {
+                if e.is_parse() {
+                    eprintln!("{} didn't send a valid HTTP request: {}", addr, e);
+                }
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    server: Arc<HttpServer<'static>>,
+    sender: mpsc::Sender<HandlerMessage>,
+    shutdown: Arc<Notify>,
+    addr: SocketAddr,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = get_response(&server, sender, shutdown, addr, request).await;
+    server
+        .metrics
+        .response_sent(response.status_code().as_u16(), response.body.len());
+    Ok(response.into_hyper())
+}
+
+async fn get_response(
+    server: &HttpServer<'static>,
+    sender: mpsc::Sender<HandlerMessage>,
+    shutdown: Arc<Notify>,
+    addr: SocketAddr,
+    request: Request<Incoming>,
+) -> HttpResponse {
+    let request = match extract_request(&request, addr.ip()) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("{} didn't send a valid HTTP request: {}", addr, e);
+            return HttpResponse::empty("400 Bad Request");
+        }
+    };
+
+    if request.path == "/shutdown" {
+        eprintln!("Shutting down due to a shutdown request");
+        shutdown.notify_one();
+        return HttpResponse::empty("200 OK");
+    }
+
+    let mut client = Client {
+        headers: Vec::new(),
+        response: None,
+    };
+    // The preflight requests are answered right away, as there's nothing to draw for them.
+    if request.method == Method::OPTIONS {
+        let headers = server
+            .cors
+            .preflight_headers(request.origin.as_deref(), request.requested_headers.as_deref());
+        serve_response(&mut client, "204 No Content", b"", "text/plain", &as_strs(&headers));
+        return client.response.unwrap_or_else(HttpResponse::internal_error);
+    }
+    // And so are the probes and the scrapes of the metrics, which mustn't wait behind the renders.
+    match request.path.as_str() {
+        "/metrics" => {
+            let metrics = server.metrics.to_prometheus();
+            serve_data(&mut client, metrics.as_bytes(), PROMETHEUS_CONTENT_TYPE);
+        }
+        "/healthz" => serve_data(&mut client, b"OK\n", "text/plain"),
+        "/readyz" if server.is_ready() => serve_data(&mut client, b"OK\n", "text/plain"),
+        _ if !server.is_ready() => serve_response(
+            &mut client,
+            "503 Service Unavailable",
+            b"The maps are still loading\n",
+            "text/plain",
+            &[],
+        ),
+        _ => {}
+    }
+    if let Some(response) = client.response {
+        return response;
+    }
+
+    // If the client goes away, the connection drops this future, and the render is cancelled along with it.
+    let _render = server.start_render(&request);
+    let cancellation = request.cancellation.clone();
+    let (responder, handled) = oneshot::channel();
+    if sender
+        .send(HandlerMessage::ServeTile {
+            request: Box::new(request),
+            responder,
+        })
+        .await
+        .is_err()
+    {
+        return HttpResponse::empty("503 Service Unavailable");
+    }
+    match handled.await {
+        Ok(Handled::Response(response)) => response,
+        Ok(Handled::Render(job)) => serve_tile(server, &sender, job).await,
+        Err(_) if cancellation.is_cancelled() => HttpResponse::superseded(),
+        Err(_) => HttpResponse::internal_error(),
+    }
+}
 
-        if path == "/shutdown" {
-            eprintln!("Shutting down due to a shutdown request");
-            for sender in senders {
-                sender.send(HandlerMessage::Terminate).unwrap();
+// Draws the tile on one of the render threads, unless another request is already drawing the same tile. Then this
+// request waits for that tile without taking a thread, so that the clients that ask for the same tiles at once
+// (e.g. the visitors of the same page) don't keep the threads from drawing the other tiles.
+async fn serve_tile(
+    server: &HttpServer<'static>,
+    sender: &mpsc::Sender<HandlerMessage>,
+    mut job: Box<TileJob<'static>>,
+) -> HttpResponse {
+    let leader = match &job.etag {
+        Some(etag) => match server.pending_renders.join(etag).await {
+            Coalesced::Done(tile_bytes) => return job.tile_response(&tile_bytes),
+            Coalesced::Leader(leader) => Some(leader),
+        },
+        None => None,
+    };
+
+    // Another server might be drawing the tile already, and it's waited for here too, so that the render threads
+    // only ever draw.
+    if let (Some(cache), Some(key)) = (&server.tile_cache, &job.cache_key) {
+        let lookup = match get_cached_tile(cache.as_ref(), key, &job.cancellation).await {
+            Ok(lookup) => lookup,
+            Err(_) => return HttpResponse::superseded(),
+        };
+        server
+            .metrics
+            .cache_lookup(CacheKind::Storage, matches!(lookup, CacheLookup::Hit(_)));
+        match lookup {
+            CacheLookup::Hit(tile_bytes) => {
+                server.keep_in_memory(&job, &tile_bytes);
+                if let Some(leader) = &leader {
+                    leader.share(&tile_bytes);
+                }
+                return job.tile_response(&tile_bytes);
             }
-            break;
+            CacheLookup::Locked(token) => job.lock_token = Some(token),
+            CacheLookup::Miss => {}
         }
+    }
 
-        senders[thread_id]
-            .send(HandlerMessage::ServeTile { path, stream })
-            .unwrap();
-        thread_id = (thread_id + 1) % senders.len();
+    let cancellation = job.cancellation.clone();
+    let (responder, rendered) = oneshot::channel();
+    if sender
+        .send(HandlerMessage::RenderTile { job, responder })
+        .await
+        .is_err()
+    {
+        return HttpResponse::empty("503 Service Unavailable");
+    }
+    match rendered.await {
+        Ok(rendered) => {
+            if let (Some(leader), true) = (&leader, rendered.is_shared) {
+                leader.share(&rendered.response.body);
+            }
+            rendered.response
+        }
+        Err(_) if cancellation.is_cancelled() => HttpResponse::superseded(),
+        Err(_) => HttpResponse::internal_error(),
     }
+}
 
-    for h in handlers {
-        h.join().unwrap();
+struct TileMap<'a> {
+    name: Option<String>,
+    // Names the cached tiles drawn with this style (see `CachedTile`).
+    style_version: String,
+    sprite: Option<Arc<Sprite>>,
+    geodata_file: String,
+    geodata: RwLock<Arc<GeodataSnapshot<'a>>>,
+    drawer: Drawer,
+    upstream: Option<Upstream>,
+    attribution: String,
+}
+
+impl<'a> TileMap<'a> {
+    fn load(config: MapConfig, style_cache_bytes: Option<usize>) -> Result<TileMap<'a>> {
+        let context = || match &config.name {
+            Some(name) => format!("Failed to load the map {}", name),
+            None => "Failed to load the map".to_string(),
+        };
+        let (base_path, file_name) = split_stylesheet_path(&config.stylesheet_file).with_context(context)?;
+        let mut rules = parse_file(&base_path, &file_name)
+            .context("Failed to parse the stylesheet file")
+            .with_context(context)?;
+        apply_settings(&mut rules, &config.settings).with_context(context)?;
+        if let Some(dark_mode) = &config.dark_mode {
+            dark_mode.apply(&mut rules);
+        }
+        let style_version = get_style_version(&rules, &config.stylesheet_type, config.font_size_multiplier);
+        let styler = Styler::new(rules, &config.stylesheet_type, config.font_size_multiplier);
+        styler.set_cache_limit(style_cache_bytes);
+        let palette_stylers = config
+            .palettes
+            .iter()
+            .map(|palette| (*palette, styler.with_palette(*palette)))
+            .collect();
+        let reader = GeodataReader::load(&config.geodata_file)
+            .context("Failed to load the geodata file")
+            .with_context(context)?;
+
+        Ok(TileMap {
+            sprite: config.draw_options.sprite.clone(),
+            geodata: RwLock::new(Arc::new(GeodataSnapshot::new(reader, styler, palette_stylers))),
+            drawer: Drawer::new(&base_path, config.draw_options),
+            name: config.name,
+            style_version,
+            geodata_file: config.geodata_file,
+            upstream: config.upstream,
+            attribution: config.attribution.unwrap_or_else(|| DEFAULT_ATTRIBUTION.to_string()),
+        })
     }
 
-    Ok(())
+    fn geodata(&self) -> Arc<GeodataSnapshot<'a>> {
+        Arc::clone(&self.geodata.read().unwrap())
+    }
+
+    // Maps the geodata file again (e.g. after a new import was moved in its place). The requests that are
+    // already being served keep using the old file, which is unmapped once the last of them is done.
+    fn reload_geodata(&self) -> Result<()> {
+        let reader = GeodataReader::load(&self.geodata_file).context("Failed to reload the geodata file")?;
+        let old_geodata = self.geodata();
+        let styler = old_geodata.styler.with_empty_cache();
+        let palette_stylers = old_geodata
+            .palette_stylers
+            .iter()
+            .map(|(palette, styler)| (*palette, styler.with_empty_cache()))
+            .collect();
+        *self.geodata.write().unwrap() = Arc::new(GeodataSnapshot::new(reader, styler, palette_stylers));
+        eprintln!("Reloaded {}", self.geodata_file);
+        Ok(())
+    }
 }
 
 struct HttpServer<'a> {
-    styler: Styler,
-    reader: GeodataReader<'a>,
-    drawer: Drawer,
+    // Empty until all of the maps are loaded.
+    maps: OnceLock<Vec<TileMap<'a>>>,
+    tile_size: usize,
     osm_ids: Option<HashSet<u64>>,
+    memory_budget: Option<MemoryBudget>,
+    api_keys: Option<ApiKeys>,
+    tile_cache: Option<Box<dyn TileCache>>,
+    tile_cache_layout: KeyLayout,
+    memory_cache: Option<MemoryCache>,
+    cache_control: CacheControl,
+    cors: Cors,
     perf_stats: Mutex<PerfStats>,
+    in_flight: Mutex<Vec<InFlightRender>>,
+    next_render_id: AtomicU64,
+    pending_renders: PendingRenders,
+    metrics: Metrics,
 }
 
 impl<'a> HttpServer<'a> {
-    fn handle_connection(&self, path: &str, mut stream: TcpStream, state: &mut HandlerState) {
-        match self.try_handle_connection(path, &mut stream, state) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error processing request from {}: {}", peer_addr(&stream), e),
+    fn handle_connection(&self, request: &HttpRequest) -> Handled<'a> {
+        let mut client = Client {
+            headers: self.cors.headers(request.origin.as_deref()),
+            response: None,
+        };
+        match self.try_handle_connection(request, &mut client) {
+            Ok(Some(job)) => Handled::Render(Box::new(job)),
+            result => Handled::Response(finish_response(client, result.map(|_| ()), request.client)),
         }
     }
 
-    fn try_handle_connection(&self, path: &str, stream: &mut TcpStream, state: &mut HandlerState) -> Result<()> {
+    // Answers everything but the tiles that have to be drawn, which are left to `render_tile`.
+    fn try_handle_connection(&self, request: &HttpRequest, client: &mut Client) -> Result<Option<TileJob<'a>>> {
+        let path = request.path.as_str();
+        validate_path(path)?;
         if cfg!(feature = "perf-stats") && path == "/perf_stats" {
             let perf_stats_html = self.perf_stats.lock().unwrap().to_html();
-            serve_data(stream, perf_stats_html.as_bytes(), "text/html");
-            return Ok(());
+            serve_data(client, perf_stats_html.as_bytes(), "text/html");
+            return Ok(None);
         }
 
-        let tile = match extract_tile_from_path(path) {
-            Some(tile) => tile,
-            _ => bail!("<{}> doesn't look like a valid tile ID", path),
+        if path == "/memory" {
+            serve_data(client, self.memory_usage_json().as_bytes(), "application/json");
+            return Ok(None);
+        }
+
+        if path == "/usage" || path.starts_with("/usage?") {
+            let api_keys = self
+                .api_keys
+                .as_ref()
+                .ok_or_else(|| RequestError::NotFound("The server doesn't have the API keys".to_string()))?;
+            match api_keys.usage_json(extract_key_from_path(path), unix_time()) {
+                Ok(json) => serve_data(client, json.as_bytes(), "application/json"),
+                Err(e) => serve_key_error(client, &e),
+            }
+            return Ok(None);
+        }
+
+        let (map, mut path) = match self.find_map(path) {
+            Some(found) => found,
+            _ => return Err(RequestError::NotFound(format!("<{}> doesn't belong to any of the maps", path)).into()),
+        };
+
+        if path == "/reload" || path.starts_with("/reload?") {
+            if let Err(e) = check_reload_allowed(self.api_keys.as_ref(), extract_key_from_path(path), request.client) {
+                serve_key_error(client, &e);
+                return Ok(None);
+            }
+            map.reload_geodata()?;
+            serve_data(client, b"OK\n", "text/plain");
+            return Ok(None);
+        }
+
+        // The clients that draw the icons on their own (e.g. on top of the base layer) can use the same sprite.
+        if let Some(sprite) = &map.sprite {
+            match path {
+                "/sprite.json" => {
+                    serve_data(client, sprite.to_json().as_bytes(), "application/json");
+                    return Ok(None);
+                }
+                "/sprite.png" => {
+                    serve_data(client, sprite.png(), "image/png");
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+
+        let geodata = map.geodata();
+
+        if path == "/tilejson.json" || path.starts_with("/tilejson.json?") {
+            let tilejson = self.tilejson(map, &geodata, request, extract_key_from_path(path));
+            serve_data(client, tilejson.as_bytes(), "application/json");
+            return Ok(None);
+        }
+
+        let wmts_tile_path;
+        if let Some(query) = path.strip_prefix("/wmts?") {
+            match wmts::parse_request(query).map_err(bad_request)? {
+                WmtsRequest::GetCapabilities => {
+                    let layer = WmtsLayer {
+                        identifier: map.name.as_deref().unwrap_or(DEFAULT_WMTS_LAYER),
+                        bounds: geodata.bounds().cloned().unwrap_or(GeoBounds {
+                            min_lon: WORLD_BOUNDS[0],
+                            min_lat: WORLD_BOUNDS[1],
+                            max_lon: WORLD_BOUNDS[2],
+                            max_lat: WORLD_BOUNDS[3],
+                        }),
+                        map_url: map_url(map, request),
+                        query: extract_key_from_path(path).map(|key| format!("key={}", key)),
+                    };
+                    let xml = wmts::capabilities(&layer, self.tile_size);
+                    serve_data(client, xml.as_bytes(), "application/xml");
+                    return Ok(None);
+                }
+                // The tiles are then served just like the ones requested by their XYZ paths.
+                WmtsRequest::GetTile { layer, tile_path } => {
+                    if layer != map.name.as_deref().unwrap_or(DEFAULT_WMTS_LAYER) {
+                        return Err(RequestError::NotFound(format!("Unknown layer: <{}>", layer)).into());
+                    }
+                    wmts_tile_path = tile_path;
+                    path = &wmts_tile_path;
+                }
+            }
+        }
+
+        if let Some(query) = path.strip_prefix("/match?") {
+            let geojson = self.match_track(&geodata, query)?;
+            serve_data(client, geojson.as_bytes(), "application/geo+json");
+            return Ok(None);
+        }
+
+        if let Some(query) = path.strip_prefix("/static?") {
+            let (bounds, width, height) = extract_static_map_from_query(query).map_err(bad_request)?;
+            let styler = geodata.styler(extract_palette_from_path(path).map_err(bad_request)?)?;
+            if let Some(api_keys) = &self.api_keys {
+                // A static map counts as the usual tiles that cover as many pixels.
+                let tiles = (width * height).div_ceil((TILE_SIZE * TILE_SIZE) as usize) as u64;
+                if let Err(e) = api_keys.use_tiles(extract_key_from_path(path), tiles, unix_time()) {
+                    serve_key_error(client, &e);
+                    return Ok(None);
+                }
+            }
+            let png = render_static_map(&geodata.reader, styler, &map.drawer, &bounds, width, height)?;
+            serve_data(client, &png, "image/png");
+            return Ok(None);
+        }
+
+        let tile = extract_tile_from_path(path)?;
+        if self.tile_size * tile.scale > MAX_SCALED_TILE_SIZE {
+            return Err(RequestError::BadRequest(format!(
+                "The {0}×{0} tiles can't be scaled {1} times",
+                self.tile_size, tile.scale
+            ))
+            .into());
+        }
+        let date = extract_date_from_path(path).map_err(bad_request)?;
+        let fade = extract_fade_from_path(path).map_err(bad_request)?;
+        let palette = extract_palette_from_path(path).map_err(bad_request)?;
+        let level = extract_level_from_path(path).map_err(bad_request)?;
+        let styler = geodata.styler(palette)?;
+
+        if let Some(api_keys) = &self.api_keys {
+            if let Err(e) = api_keys.use_tile(extract_key_from_path(path), unix_time()) {
+                serve_key_error(client, &e);
+                return Ok(None);
+            }
+        }
+
+        let get_entities = || self.get_entities(&geodata, &tile.tile, date, level);
+
+        let mut tile_headers = self.cache_control.headers(tile.tile.zoom, unix_time());
+
+        let image_format = match tile.format {
+            TileFormat::Image(image_format) => image_format,
+            TileFormat::Negotiated if accepts_media_type(request.accept.as_deref(), "image/webp") => ImageFormat::WebP,
+            TileFormat::Negotiated => ImageFormat::Png,
+            TileFormat::GeoJson => {
+                let tag_keys = extract_tag_keys_from_path(path);
+                let geojson = tile_to_geojson(&get_entities(), &tile.tile, tag_keys.as_deref());
+                serve_data_with_headers(
+                    client,
+                    geojson.as_bytes(),
+                    "application/geo+json",
+                    &as_strs(&tile_headers),
+                );
+                return Ok(None);
+            }
+            TileFormat::Svg => {
+                let svg = map
+                    .drawer
+                    .draw_svg_tile(&get_entities(), &tile.tile, self.tile_size, tile.scale, styler);
+                serve_data_with_headers(client, svg.as_bytes(), "image/svg+xml", &as_strs(&tile_headers));
+                return Ok(None);
+            }
+        };
+
+        // The tiles that are drawn differently on request aren't shared with anybody.
+        let is_shared =
+            date.is_none() && fade.is_none() && palette.is_none() && level.is_none() && self.osm_ids.is_none();
+        let cached_tile = CachedTile {
+            map: map.name.as_deref(),
+            layers: tile.layers,
+            tile: &tile.tile,
+            scale: tile.scale,
+            extension: image_format.extension(),
+            style_version: &map.style_version,
+            data_version: &geodata.data_version,
+        };
+        let cache = match &self.tile_cache {
+            Some(cache) if is_shared => Some((cache, self.tile_cache_layout.key(&cached_tile))),
+            _ => None,
+        };
+        let memory_cache = match &self.memory_cache {
+            Some(memory_cache) if is_shared => Some((memory_cache, memory_cache.key(&cached_tile))),
+            _ => None,
+        };
+        // The caches between the server and the clients mustn't give the WebP tiles to the clients without WebP support.
+        if let TileFormat::Negotiated = tile.format {
+            tile_headers.push("Vary: Accept".to_string());
+        }
+        // The tile only changes with the style and the geodata, so the clients can ask whether the one they have
+        // is still good instead of downloading it again.
+        let etag = self
+            .osm_ids
+            .is_none()
+            .then(|| get_tile_etag(&cached_tile, self.tile_size, date, fade, palette, level));
+        if let Some(etag) = &etag {
+            tile_headers.push(format!("ETag: {}", etag));
+        }
+        let tile_headers = as_strs(&tile_headers);
+        if let (Some(etag), Some(if_none_match)) = (&etag, request.header("If-None-Match")) {
+            if matches_etag(if_none_match, etag) {
+                serve_response(
+                    client,
+                    "304 Not Modified",
+                    b"",
+                    image_format.content_type(),
+                    &tile_headers,
+                );
+                return Ok(None);
+            }
+        }
+
+        if let Some((memory_cache, key)) = &memory_cache {
+            let tile_bytes = memory_cache.get(key);
+            self.metrics.cache_lookup(CacheKind::Memory, tile_bytes.is_some());
+            if let Some(tile_bytes) = tile_bytes {
+                serve_data_with_headers(client, &tile_bytes, image_format.content_type(), &tile_headers);
+                return Ok(None);
+            }
+        }
+        // The tiles baked into the geodata with the same style are served as they are.
+        if is_shared
+            && tile.layers == TileLayers::All
+            && tile.scale == 1
+            && self.tile_size == TILE_SIZE as usize
+            && image_format == ImageFormat::Png
+        {
+            if let Some(tile_bytes) = geodata.reader.baked_tile(&tile.tile, &map.style_version) {
+                serve_data_with_headers(client, tile_bytes, image_format.content_type(), &tile_headers);
+                return Ok(None);
+            }
+        }
+        Ok(Some(TileJob {
+            map_name: map.name.clone(),
+            geodata: Arc::clone(&geodata),
+            tile,
+            date,
+            fade,
+            palette,
+            level,
+            image_format,
+            client_headers: client.headers.clone(),
+            tile_headers: tile_headers.iter().map(|header| header.to_string()).collect(),
+            etag,
+            cache_key: cache.map(|(_, key)| key),
+            memory_cache_key: memory_cache.map(|(_, key)| key),
+            lock_token: None,
+            client: request.client,
+            forwarded_headers: request.forwarded_headers.clone(),
+            cancellation: request.cancellation.clone(),
+        }))
+    }
+
+    fn render_tile(&self, job: &TileJob<'a>, state: &mut HandlerState) -> RenderedTile {
+        let mut client = Client {
+            headers: job.client_headers.clone(),
+            response: None,
         };
+        let result = self.try_render_tile(job, &mut client, state);
+        let is_shared = matches!(result, Ok(true));
+        RenderedTile {
+            response: finish_response(client, result.map(|_| ()), job.client),
+            is_shared,
+        }
+    }
+
+    // Tells whether the tile has been served, and can be given to the other requests for it.
+    fn try_render_tile(&self, job: &TileJob<'a>, client: &mut Client, state: &mut HandlerState) -> Result<bool> {
+        let map = self
+            .maps()
+            .iter()
+            .find(|map| map.name == job.map_name)
+            .context("The map of the tile is gone")?;
+        let geodata = &job.geodata;
+        let styler = geodata.styler(job.palette)?;
+        let tile = &job.tile;
+        let content_type = job.image_format.content_type();
+        let tile_headers = as_strs(&job.tile_headers);
+        let cache = self.tile_cache.as_ref().zip(job.cache_key.as_ref());
+        let put_into_memory = |tile_bytes: &[u8]| self.keep_in_memory(job, tile_bytes);
+        let unlock_tile = || self.unlock_tile(job);
 
         if cfg!(feature = "perf-stats") {
             crate::perf_stats::start_tile(tile.tile.zoom);
         }
 
-        let entities = {
-            let _m = crate::perf_stats::measure("Get tile entities");
-            self.reader
-                .get_entities_in_tile_with_neighbors(&tile.tile, &self.osm_ids)
-        };
+        let entities = self.get_entities(geodata, &tile.tile, job.date, job.level);
+
+        if let Some(upstream) = &map.upstream {
+            let has_local_data =
+                || !(entities.nodes.is_empty() && entities.ways.is_empty() && entities.multipolygons.is_empty());
+            if tile.layers == TileLayers::All
+                && upstream.has_scale(tile.scale)
+                && !upstream.draws_locally(&tile.tile, has_local_data)
+            {
+                let _m = crate::perf_stats::measure("Get upstream tile");
+                let response = upstream.fetch(&tile.tile, tile.scale, &job.forwarded_headers);
+                let mut is_shared = false;
+                match &response {
+                    // The validators are passed both ways, so that the clients revalidate their tiles with
+                    // the upstream server.
+                    Ok(response) => {
+                        let status = format!("{} {}", response.status, response.status_text);
+                        let headers = as_strs(&response.headers);
+                        serve_response(client, &status, &response.data, &response.content_type, &headers);
+                        if response.status == 200 && response.content_type == content_type {
+                            put_into_memory(&response.data);
+                            is_shared = true;
+                            if let Some((cache, key)) = &cache {
+                                if let Err(e) = cache.put(key, &response.data, &response.content_type) {
+                                    eprintln!("{:#}", e);
+                                }
+                            }
+                        }
+                    }
+                    Err(_) => serve_response(client, "502 Bad Gateway", b"", "text/plain", &[]),
+                }
+                unlock_tile();
+                return response.map(|_| is_shared);
+            }
+        }
 
         if tile.scale != state.current_scale {
             let _m = crate::perf_stats::measure("Re-scaling TilePixels");
             state.current_scale = tile.scale;
-            state.current_pixels = Box::new(TilePixels::new(tile.scale));
+            *state.current_pixels = TilePixels::with_tile_size(self.tile_size, tile.scale);
         }
 
-        let tile_png_bytes = self
-            .drawer
-            .draw_tile(
+        let tile_request = TileRequest {
+            scale: state.current_scale,
+            layers: tile.layers,
+            format: job.image_format,
+            cancellation: &job.cancellation,
+        };
+        let render_started = Instant::now();
+        let tile_bytes = match job.fade {
+            Some(fade) => map.drawer.draw_cross_faded_tile(
                 &entities,
                 &tile.tile,
                 &mut state.current_pixels,
-                state.current_scale,
-                &self.styler,
-            )
-            .unwrap();
+                styler,
+                fade,
+                &tile_request,
+            ),
+            None => map
+                .drawer
+                .draw_tile(&entities, &tile.tile, &mut state.current_pixels, styler, &tile_request),
+        };
 
-        if cfg!(feature = "perf-stats") {
-            crate::perf_stats::finish_tile(&mut self.perf_stats.lock().unwrap());
+        if let Ok(tile_bytes) = &tile_bytes {
+            self.metrics.tile_rendered(tile.tile.zoom, render_started.elapsed());
+            if cfg!(feature = "perf-stats") {
+                crate::perf_stats::finish_tile(&mut self.perf_stats.lock().unwrap());
+            }
+
+            serve_data_with_headers(client, tile_bytes, content_type, &tile_headers);
+
+            put_into_memory(tile_bytes);
+            if let Some((cache, key)) = &cache {
+                if let Err(e) = cache.put(key, tile_bytes, content_type) {
+                    eprintln!("{:#}", e);
+                }
+            }
         }
 
-        serve_data(stream, &tile_png_bytes, "image/png");
+        unlock_tile();
 
-        Ok(())
+        tile_bytes.map(|_| true)
+    }
+
+    fn keep_in_memory(&self, job: &TileJob<'a>, tile_bytes: &[u8]) {
+        if let (Some(memory_cache), Some(key)) = (&self.memory_cache, &job.memory_cache_key) {
+            memory_cache.put(key, tile_bytes);
+        }
+    }
+
+    // The other servers that wait for the tile look for it in the cache again, and draw it themselves
+    // if it isn't there (e.g. because the client of this server has gone away).
+    fn unlock_tile(&self, job: &TileJob<'a>) {
+        if let (Some(cache), Some(key), Some(token)) = (&self.tile_cache, &job.cache_key, &job.lock_token) {
+            if let Err(e) = cache.unlock(key, token) {
+                eprintln!("{:#}", e);
+            }
+        }
+    }
+
+    fn get_entities<'g>(
+        &self,
+        geodata: &'g GeodataSnapshot<'a>,
+        tile: &Tile,
+        date: Option<i64>,
+        level: Option<f64>,
+    ) -> OsmEntities<'g> {
+        let _m = crate::perf_stats::measure("Get tile entities");
+        let started = Instant::now();
+        let mut entities = geodata
+            .reader
+            .get_entities_in_tile_with_neighbors_at(tile, &self.osm_ids, date);
+        if let Some(level) = level {
+            retain_level(&mut entities, level);
+        }
+        self.metrics.geodata_queried(started.elapsed());
+        entities
+    }
+}
+
+impl<'a> HttpServer<'a> {
+    fn start_render(&self, request: &HttpRequest) -> InFlightGuard<'_, 'a> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Only the client that has named the viewport can give it up: the requests from the same address (e.g. from
+        // the clients behind the same proxy) are never taken for each other.
+        if let Some(viewport) = &request.viewport {
+            for render in in_flight.iter() {
+                if render.viewport.as_ref().is_some_and(|old| viewport.supersedes(old)) {
+                    render.cancellation.cancel();
+                }
+            }
+        }
+        let render = InFlightRender {
+            id: self.next_render_id.fetch_add(1, Ordering::Relaxed),
+            viewport: request.viewport.clone(),
+            cancellation: request.cancellation.clone(),
+        };
+        let guard = InFlightGuard {
+            server: self,
+            id: render.id,
+            cancellation: render.cancellation.clone(),
+        };
+        in_flight.push(render);
+        guard
+    }
+
+    fn save_usage(&self) {
+        if let Some(Err(e)) = self.api_keys.as_ref().map(ApiKeys::save) {
+            eprintln!("{:#}", e);
+        }
+    }
+
+    fn finish_render(&self, id: u64) {
+        self.in_flight.lock().unwrap().retain(|render| render.id != id);
+    }
+
+    // The map that the path belongs to and the rest of the path (e.g. `/15/19805/10244.png` for
+    // `/paris/15/19805/10244.png`). The paths without a map name go to the map at the root, if there is one.
+    fn find_map<'p>(&self, path: &'p str) -> Option<(&TileMap<'a>, &'p str)> {
+        self.maps()
+            .iter()
+            .find_map(|map| {
+                let name = map.name.as_deref()?;
+                strip_map_name(path, name).map(|rest| (map, rest))
+            })
+            .or_else(|| self.maps().iter().find(|map| map.name.is_none()).map(|map| (map, path)))
+    }
+
+    fn maps(&self) -> &[TileMap<'a>] {
+        self.maps.get().map_or(&[], Vec::as_slice)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.maps.get().is_some()
+    }
+
+    // The budget (if any) and how much of it the caches of all the maps actually take, in bytes. The tiles kept
+    // in memory have a limit of their own.
+    fn memory_usage_json(&self) -> String {
+        let format_limit = |limit: Option<usize>| limit.map_or_else(|| "null".to_string(), |limit| limit.to_string());
+        format!(
+            "{{\"max_memory\":{},\"style_cache\":{{\"used\":{},\"limit\":{}}},\"icon_cache\":{{\"used\":{}}},\
+             \"tile_cache\":{{\"used\":{},\"limit\":{}}}}}",
+            format_limit(self.memory_budget.map(|budget| budget.max_bytes)),
+            self.maps()
+                .iter()
+                .map(|map| map.geodata().stylers().map(Styler::cache_usage).sum::<usize>())
+                .sum::<usize>(),
+            format_limit(self.memory_budget.map(|budget| budget.style_cache_bytes())),
+            self.maps()
+                .iter()
+                .map(|map| map.drawer.icon_cache_usage())
+                .sum::<usize>(),
+            self.memory_cache.as_ref().map_or(0, MemoryCache::used_bytes),
+            format_limit(self.memory_cache.as_ref().map(|cache| cache.max_bytes() as usize)),
+        )
+    }
+
+    // Describes the map for the clients that set themselves up from TileJSON (https://github.com/mapbox/tilejson-spec).
+    // The tile URLs point back to the host that the client reached, and carry the API key it was given, if any.
+    fn tilejson(
+        &self,
+        map: &TileMap<'_>,
+        geodata: &GeodataSnapshot<'_>,
+        request: &HttpRequest,
+        key: Option<&str>,
+    ) -> String {
+        let key_query = key.map_or_else(String::new, |key| format!("?key={}", key));
+        let tiles_url = format!("{}/{{z}}/{{x}}/{{y}}.png{}", map_url(map, request), key_query);
+        let bounds = geodata.bounds().map_or(WORLD_BOUNDS, |bounds| {
+            [bounds.min_lon, bounds.min_lat, bounds.max_lon, bounds.max_lat]
+        });
+        format!(
+            "{{\"tilejson\":\"3.0.0\",{}\"scheme\":\"xyz\",\"tiles\":[\"{}\"],\"minzoom\":0,\"maxzoom\":{},\
+             \"tileSize\":{},\"bounds\":[{}],\"attribution\":\"{}\"}}",
+            map.name
+                .as_ref()
+                .map_or_else(String::new, |name| format!("\"name\":\"{}\",", escape_json(name))),
+            escape_json(&tiles_url),
+            MAX_OVERZOOM,
+            self.tile_size,
+            bounds.map(|x| x.to_string()).join(","),
+            escape_json(&map.attribution),
+        )
+    }
+
+    // Snaps a track to the roads (e.g. `/match?track=55.75,37.61;55.76,37.62&zoom=16&max_distance=20`)
+    // and returns the result as a GeoJSON line string.
+    fn match_track(&self, geodata: &GeodataSnapshot<'_>, query: &str) -> Result<String> {
+        let get_param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|param| param.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+        };
+
+        let track = get_param("track")
+            .context("The track is missing")?
+            .split(';')
+            .map(|point| {
+                let (lat, lon) = point.split_once(',').context("Expected LAT,LON")?;
+                Ok((lat.parse::<f64>()?, lon.parse::<f64>()?))
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("Invalid track")?;
+        let zoom = match get_param("zoom") {
+            Some(zoom) => zoom.parse::<u8>().ok().filter(|zoom| *zoom <= MAX_ZOOM),
+            None => Some(DEFAULT_MATCHING_ZOOM),
+        }
+        .context("Invalid zoom level")?;
+        let max_distance = match get_param("max_distance") {
+            Some(distance) => distance.parse::<f64>().ok().filter(|distance| *distance >= 0.0),
+            None => Some(DEFAULT_MATCHING_DISTANCE),
+        }
+        .context("Invalid maximum distance")?;
+
+        let matched = MapMatcher::new(&geodata.reader, &geodata.styler, zoom, max_distance).match_track(&track);
+        let coordinates = matched
+            .iter()
+            .map(|(lat, lon)| format!("[{:.7},{:.7}]", lon, lat))
+            .collect::<Vec<_>>();
+        Ok(format!(
+            "{{\"type\":\"LineString\",\"coordinates\":[{}]}}",
+            coordinates.join(",")
+        ))
     }
 }
 
-fn serve_data(stream: &mut TcpStream, data: &[u8], content_type: &str) {
-    let header = [
-        "HTTP/1.1 200 OK",
-        &format!("Content-Type: {}", content_type),
-        &format!("Content-Length: {}", data.len()),
-        "Access-Control-Allow-Origin: *",
-        "Connection: close",
-        "",
-        "",
-    ]
-    .join("\r\n");
+enum CacheLookup {
+    Hit(Vec<u8>),
+    // The tile is missing, and this server has claimed its drawing with the token.
+    Locked(String),
+    // The tile is missing (or the cache isn't available), and is drawn without telling anybody.
+    Miss,
+}
 
-    // Errors at this stage usually happen when the outstanding requests get terminated for some
-    // reason (e.g. the user scrolls the map). We're not interested in reporting these errors,
-    // but there's no point in continuing after a write fails either.
-    if stream.write_all(header.as_bytes()).is_ok() {
-        let _ = stream.write_all(data);
+// If the tile is missing and another server is already drawing it, waits for the tile to appear in the cache
+// (but not longer than the lock timeout of the cache, and only as long as the client waits too).
+// The caches are asked on the async threads, which are handed over to the other connections meanwhile.
+async fn get_cached_tile(cache: &dyn TileCache, key: &str, cancellation: &CancellationToken) -> Result<CacheLookup> {
+    let wait_until = Instant::now() + cache.lock_timeout().unwrap_or_default();
+    loop {
+        if cancellation.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        match task::block_in_place(|| cache.get(key)) {
+            Ok(Some(tile_bytes)) => return Ok(CacheLookup::Hit(tile_bytes)),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("{:#}", e);
+                return Ok(CacheLookup::Miss);
+            }
+        }
+        match task::block_in_place(|| cache.try_lock(key)) {
+            Ok(Some(token)) => return Ok(CacheLookup::Locked(token)),
+            Ok(None) if Instant::now() < wait_until => time::sleep(LOCKED_TILE_CHECK_INTERVAL).await,
+            Ok(None) => return Ok(CacheLookup::Miss),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                return Ok(CacheLookup::Miss);
+            }
+        }
     }
 }
 
-fn extract_path_from_stream(stream: &mut TcpStream) -> Result<String> {
-    let mut rdr = BufReader::new(stream);
-    let first_line = match rdr.by_ref().lines().next() {
-        Some(Ok(line)) => line,
-        _ => bail!("Failed to read the first line from the TCP stream"),
-    };
-    let tokens: Vec<_> = first_line.split(' ').collect();
-    if tokens.len() != 3 {
-        bail!("<{}> doesn't look like a valid HTTP request", first_line);
+// Changes whenever the bytes of the tile can: with the tile itself, the style and the geodata, and with the way
+// the tile is drawn on request.
+fn get_tile_etag(
+    cached_tile: &CachedTile<'_>,
+    tile_size: usize,
+    date: Option<i64>,
+    fade: Option<f64>,
+    palette: Option<Palette>,
+    level: Option<f64>,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(KeyLayout::versioned().key(cached_tile));
+    hasher.update(format!("{} {:?} {:?} {:?} {:?}", tile_size, date, fade, palette, level));
+    let hash = hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("\"{}\"", hash)
+}
+
+// `If-None-Match` lists the tags the client has (or is `*` for any tag), and compares them weakly.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// The names that would be confused with the zoom levels or the separate layers of the map at the root.
+fn validate_map_name(name: &str) -> Result<()> {
+    let is_valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.chars().all(|c| c.is_ascii_digit())
+        && name != "base"
+        && name != "labels";
+    if !is_valid {
+        bail!("<{}> can't be used as a map name", name);
     }
-    let method = tokens[0];
-    if method != "GET" {
-        bail!("Invalid HTTP method: {}", method);
+    Ok(())
+}
+
+fn strip_map_name<'p>(path: &'p str, name: &str) -> Option<&'p str> {
+    path.strip_prefix('/')?
+        .strip_prefix(name)
+        .filter(|rest| rest.starts_with('/'))
+}
+
+// The response to a client, together with the headers that every response to it gets (see `Cors`).
+struct Client {
+    headers: Vec<String>,
+    response: Option<HttpResponse>,
+}
+
+fn serve_data(client: &mut Client, data: &[u8], content_type: &str) {
+    serve_data_with_headers(client, data, content_type, &[]);
+}
+
+fn serve_data_with_headers(client: &mut Client, data: &[u8], content_type: &str, extra_headers: &[&str]) {
+    serve_response(client, "200 OK", data, content_type, extra_headers);
+}
+
+// The clients get the reason as the body, and the ones over the quota also get `Retry-After`.
+fn serve_key_error(client: &mut Client, error: &KeyError) {
+    let body = format!("{}\n", error);
+    match error {
+        KeyError::Unknown => serve_response(client, "403 Forbidden", body.as_bytes(), "text/plain", &[]),
+        KeyError::QuotaExceeded { resets_at, .. } => {
+            let retry_after_header = format!("Retry-After: {}", (resets_at - unix_time()).max(0));
+            serve_response(
+                client,
+                "429 Too Many Requests",
+                body.as_bytes(),
+                "text/plain",
+                &[&retry_after_header],
+            );
+        }
     }
-    let http_version = tokens[2];
-    if http_version != "HTTP/1.1" && http_version != "HTTP/1.0" {
-        bail!("Invalid HTTP version: {}", http_version);
+}
+
+// The response that the request has got, or the one for its error.
+fn finish_response(mut client: Client, result: Result<()>, from: IpAddr) -> HttpResponse {
+    match result {
+        Ok(_) => {}
+        // If the client has gone away, nobody is going to read the response.
+        Err(e) if e.is::<Cancelled>() => client.response = Some(HttpResponse::superseded()),
+        Err(e) => match e.downcast_ref::<RequestError>() {
+            Some(error) => serve_request_error(&mut client, error),
+            None => eprintln!("Error processing request from {}: {}", from, e),
+        },
+    }
+    client.response.unwrap_or_else(HttpResponse::internal_error)
+}
+
+fn serve_request_error(client: &mut Client, error: &RequestError) {
+    let body = format!("{}\n", error);
+    serve_response(client, error.status(), body.as_bytes(), "text/plain", &[]);
+}
+
+fn as_strs(headers: &[String]) -> Vec<&str> {
+    headers.iter().map(String::as_str).collect()
+}
+
+fn serve_response(client: &mut Client, status: &str, data: &[u8], content_type: &str, extra_headers: &[&str]) {
+    let mut headers = Vec::new();
+    // The client already has the body of a 304, and a 204 has none, so the headers that describe the body
+    // are left out.
+    if !status.starts_with("304") && !status.starts_with("204") {
+        headers.push(format!("Content-Type: {}", content_type));
     }
-    Ok(tokens[1].to_string())
+    headers.extend(client.headers.iter().cloned());
+    headers.extend(extra_headers.iter().map(|header| header.to_string()));
+    client.response = Some(HttpResponse {
+        status: status.to_string(),
+        headers,
+        body: data.to_vec(),
+    });
+}
+
+fn extract_request(request: &Request<Incoming>, client: IpAddr) -> Result<HttpRequest> {
+    let method = request.method();
+    if method != Method::GET && method != Method::OPTIONS {
+        bail!("Invalid HTTP method: {}", method);
+    }
+    let path = match request.uri().path_and_query() {
+        Some(path) => path.as_str().to_string(),
+        None => bail!("<{}> doesn't look like a valid path", request.uri()),
+    };
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    };
+    let forwarded_headers = FORWARDED_REQUEST_HEADERS
+        .iter()
+        .filter_map(|name| header(name).map(|value| (name.to_string(), value)))
+        .collect();
+
+    Ok(HttpRequest {
+        method: method.clone(),
+        path,
+        client,
+        cancellation: CancellationToken::new(),
+        viewport: header("x-viewport").as_deref().and_then(Viewport::parse),
+        origin: header("origin"),
+        requested_headers: header("access-control-request-headers"),
+        accept: header("accept"),
+        host: header("host"),
+        forwarded_proto: header("x-forwarded-proto"),
+        forwarded_headers,
+    })
+}
+
+// Checks whether the `Accept` header lists the media type explicitly (the wildcards like `image/*`
+// don't count, as the clients send them even for the formats they can't decode) and doesn't set its
+// quality to zero.
+fn accepts_media_type(accept: Option<&str>, media_type: &str) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let mut params = range.split(';');
+            let range_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            range_type.eq_ignore_ascii_case(media_type) && quality > 0.0
+        })
+    })
 }
 
 struct RequestTile {
     tile: Tile,
     scale: usize,
+    layers: TileLayers,
+    format: TileFormat,
 }
 
-fn extract_tile_from_path(path: &str) -> Option<RequestTile> {
-    let expected_token_count = 3;
+enum TileFormat {
+    Image(ImageFormat),
+    // The objects of the tile instead of the rendered image (e.g. `/15/19805/10244.geojson`).
+    GeoJson,
+    // The styled areas and lines as vector graphics (e.g. `/15/19805/10244.svg`).
+    Svg,
+    // No extension (e.g. `/15/19805/10244`): WebP if the client accepts it, PNG otherwise.
+    Negotiated,
+}
 
-    let real_path = match path.rfind('?') {
-        Some(pos) => &path[..pos],
-        None => path,
-    };
+// The paths are only ever matched against the known ones, but the ones that try to climb out of a directory
+// (e.g. `/../../etc/passwd` or its percent-encoded forms) are turned away before anything else.
+fn validate_path(path: &str) -> Result<(), RequestError> {
+    let real_path = path.split_once('?').map_or(path, |(path, _)| path);
+    let lowercase = real_path.to_ascii_lowercase();
+    let is_valid = real_path.starts_with('/')
+        && !real_path.split('/').any(|segment| segment == "." || segment == "..")
+        && !["%2e", "%2f", "%5c", "%00", "\\"]
+            .iter()
+            .any(|sequence| lowercase.contains(sequence));
+    if !is_valid {
+        return Err(RequestError::BadRequest(format!("<{}> isn't a valid path", real_path)));
+    }
+    Ok(())
+}
 
-    let mut tokens = real_path
-        .trim_end_matches(".png")
-        .rsplit('/')
-        .take(expected_token_count)
-        .collect::<Vec<_>>();
+// E.g. `/15/19805/10244.png`, with the zoom level and the coordinates within the range of the zoom level.
+// The paths of other shapes aren't tiles at all (404), while the malformed parts of a tile path
+// (e.g. `/15/-1/abc.png` or `@5x`) are the client's mistakes (400).
+fn extract_tile_from_path(path: &str) -> Result<RequestTile, RequestError> {
+    let real_path = path.split_once('?').map_or(path, |(path, _)| path);
+    let not_found = || RequestError::NotFound(format!("<{}> doesn't look like a tile", real_path));
 
-    if tokens.len() != expected_token_count {
-        return None;
+    // The Bing-style clients address the tiles by their quadkeys (e.g. `/q/120310101011301.png`).
+    if let Some(rest) = real_path.strip_prefix("/q/") {
+        let (quadkey, suffix) = rest.split_at(rest.find(['.', '@']).unwrap_or(rest.len()));
+        let tile = Tile::from_quadkey(quadkey).map_err(|e| RequestError::BadRequest(format!("{:#}", e)))?;
+        return extract_tile_from_path(&format!("/{}/{}/{}{}", tile.zoom, tile.x, tile.y, suffix));
     }
 
-    tokens.reverse();
-    let (z_str, x_str, mut y_str) = (tokens[0], tokens[1], tokens[2]);
+    // The legacy GIS clients that only speak TMS number the rows from the bottom (e.g. `/tms/15/19805/22523.png`).
+    let (is_tms, real_path) = match real_path.strip_prefix("/tms") {
+        Some(rest) if rest.starts_with('/') => (true, rest),
+        _ => (false, real_path),
+    };
 
-    let mut scale = 1;
+    // The base map and the labels can be requested separately (e.g. `/labels/15/19805/10244.png`),
+    // so that the clients can draw the labels on top of the base map on their own.
+    let (layers, tile_path) = if let Some(rest) = real_path.strip_prefix("/base/") {
+        (TileLayers::Base, rest)
+    } else if let Some(rest) = real_path.strip_prefix("/labels/") {
+        (TileLayers::Labels, rest)
+    } else {
+        (TileLayers::All, real_path.strip_prefix('/').ok_or_else(not_found)?)
+    };
+    let [z_str, x_str, y_str] = tile_path.split('/').collect::<Vec<_>>()[..] else {
+        return Err(not_found());
+    };
 
-    let y_tokens = y_str.split('@').collect::<Vec<_>>();
-    if y_tokens.len() == 2 {
-        if let Ok(parsed_scale) = y_tokens[1].trim_end_matches('x').parse() {
-            y_str = y_tokens[0];
-            scale = parsed_scale;
+    // The tiles can also be requested without compression, e.g. `/15/19805/10244.rgba` (or `.pam` and `.ppm`).
+    let (y_str, format) = match y_str.split_once('.') {
+        Some((y_str, "geojson")) => (y_str, TileFormat::GeoJson),
+        Some((y_str, "svg")) => (y_str, TileFormat::Svg),
+        Some((y_str, extension)) => match ImageFormat::from_extension(extension) {
+            Some(image_format) => (y_str, TileFormat::Image(image_format)),
+            None => {
+                return Err(RequestError::BadRequest(format!(
+                    "Unknown tile format: <{}>",
+                    extension
+                )))
+            }
+        },
+        None => (y_str, TileFormat::Negotiated),
+    };
+
+    let (y_str, scale) = match y_str.split_once('@') {
+        Some((y_str, scale_str)) => {
+            let scale = scale_str
+                .strip_suffix('x')
+                .and_then(|scale| parse_tile_number(scale).ok())
+                .filter(|scale| (1..=MAX_TILE_SCALE as u32).contains(scale))
+                .ok_or_else(|| {
+                    RequestError::BadRequest(format!(
+                        "<@{}> isn't a tile scale between @1x and @{}x",
+                        scale_str, MAX_TILE_SCALE
+                    ))
+                })?;
+            (y_str, scale as usize)
         }
+        None => (y_str, 1),
+    };
+
+    let (zoom, x, y) = (
+        parse_tile_number(z_str)?,
+        parse_tile_number(x_str)?,
+        parse_tile_number(y_str)?,
+    );
+    if zoom > u32::from(MAX_OVERZOOM) {
+        return Err(RequestError::NotFound(format!(
+            "There are no tiles beyond zoom level {}",
+            MAX_OVERZOOM
+        )));
+    }
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return Err(RequestError::NotFound(format!("There's no tile {}/{}/{}", zoom, x, y)));
+    }
+    let y = if is_tms { (1 << zoom) - 1 - y } else { y };
+
+    Ok(RequestTile {
+        tile: Tile { zoom: zoom as u8, x, y },
+        scale,
+        layers,
+        format,
+    })
+}
+
+// Only the plain decimal numbers, without the signs that `parse` would let through. The numbers that are too large
+// for any zoom level are just out of range.
+fn parse_tile_number(s: &str) -> Result<u32, RequestError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(RequestError::BadRequest(format!("<{}> isn't a tile coordinate", s)));
+    }
+    s.parse()
+        .map_err(|_| RequestError::NotFound(format!("{} is out of the range of the tile coordinates", s)))
+}
+
+// The GeoJSON tiles can be limited to the given tags (e.g. `/15/19805/10244.geojson?tags=name,highway`).
+fn extract_tag_keys_from_path(path: &str) -> Option<Vec<String>> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("tags="))
+        .map(|keys| {
+            keys.split(',')
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+}
+
+// E.g. `bbox=37.61,55.75,37.62,55.76&width=800&height=600`, with the bounds as `MIN_LON,MIN_LAT,MAX_LON,MAX_LAT`.
+fn extract_static_map_from_query(query: &str) -> Result<(GeoBounds, usize, usize)> {
+    let get_param = |name: &str| {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+    };
+    let get_size = |name: &str| {
+        get_param(name)
+            .with_context(|| format!("The {} is missing", name))?
+            .parse::<usize>()
+            .ok()
+            .filter(|size| (1..=MAX_STATIC_MAP_SIZE).contains(size))
+            .with_context(|| format!("The {} must be between 1 and {}", name, MAX_STATIC_MAP_SIZE))
+    };
+
+    let bounds = get_param("bbox")
+        .context("The bounding box is missing")?
+        .parse::<GeoBounds>()
+        .context("Invalid bounding box")?;
+    Ok((bounds, get_size("width")?, get_size("height")?))
+}
+
+// Tiles can be requested as of a given date (e.g. `/15/19805/10244.png?date=2020-01-01`), which only
+// makes a difference if the geodata was imported with the timestamps.
+fn extract_date_from_path(path: &str) -> Result<Option<i64>> {
+    let query = match path.split_once('?') {
+        Some((_, query)) => query,
+        None => return Ok(None),
+    };
+    match query.split('&').find_map(|param| param.strip_prefix("date=")) {
+        Some(date) => Ok(Some(parse_timestamp(date)?)),
+        None => Ok(None),
     }
+}
 
-    match (z_str.parse(), x_str.parse(), y_str.parse()) {
-        (Ok(z), Ok(x), Ok(y)) if z <= MAX_ZOOM => Some(RequestTile {
-            tile: Tile { zoom: z, x, y },
-            scale,
-        }),
-        _ => None,
+// Tiles can be drawn with their styles partly faded into the styles of the next zoom level
+// (e.g. `/15/19805/10244.png?fade=0.25`), for the clients that zoom continuously.
+fn extract_fade_from_path(path: &str) -> Result<Option<f64>> {
+    let query = match path.split_once('?') {
+        Some((_, query)) => query,
+        None => return Ok(None),
+    };
+    match query.split('&').find_map(|param| param.strip_prefix("fade=")) {
+        Some(fade) => match fade.parse::<f64>() {
+            Ok(fade) if (0.0..=1.0).contains(&fade) => Ok(Some(fade)),
+            _ => bail!("Invalid fade factor: {} (expected a number between 0 and 1)", fade),
+        },
+        None => Ok(None),
     }
 }
 
-fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String)> {
-    let mut result = PathBuf::from(file_path);
-    let file_name = result
-        .file_name()
-        .and_then(|x| x.to_str().map(ToString::to_string))
-        .ok_or_else(|| anyhow!("Failed to extract the file name for {}", file_path))?;
-    result.pop();
-    Ok((result, file_name))
+// The colors can be remapped for the viewers with a color vision deficiency (e.g. `/15/19805/10244.png?palette=deuteranopia`),
+// if the map has the palette.
+fn extract_palette_from_path(path: &str) -> Result<Option<Palette>> {
+    let query = match path.split_once('?') {
+        Some((_, query)) => query,
+        None => return Ok(None),
+    };
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("palette="))
+        .map(str::parse)
+        .transpose()
 }
 
-fn peer_addr(stream: &TcpStream) -> String {
-    stream
-        .peer_addr()
-        .map(|x| format!("{}", x))
-        .unwrap_or_else(|_| "N/A".to_string())
+// The indoor maps are drawn one level of the buildings at a time (e.g. `/19/316880/163907.png?level=1`).
+fn extract_level_from_path(path: &str) -> Result<Option<f64>> {
+    let query = match path.split_once('?') {
+        Some((_, query)) => query,
+        None => return Ok(None),
+    };
+    match query.split('&').find_map(|param| param.strip_prefix("level=")) {
+        Some(level) => match level.parse::<f64>() {
+            Ok(level) if level.is_finite() => Ok(Some(level)),
+            _ => bail!("Invalid level: {} (expected a number, e.g. 1 or -0.5)", level),
+        },
+        None => Ok(None),
+    }
+}
+
+// The API key of the client (e.g. `/15/19805/10244.png?key=KEY`).
+fn extract_key_from_path(path: &str) -> Option<&str> {
+    let (_, query) = path.split_once('?')?;
+    query.split('&').find_map(|param| param.strip_prefix("key="))
+}
+
+// Anybody could keep the server busy remapping the geodata and refilling the style caches, so with the API keys
+// only the admin can reload the geodata. Without them, the requests from the server's own host still can
+// (e.g. `curl` from the script that has updated the geodata).
+fn check_reload_allowed(api_keys: Option<&ApiKeys>, key: Option<&str>, client: IpAddr) -> Result<(), KeyError> {
+    match api_keys {
+        Some(api_keys) => api_keys.check_admin(key),
+        None if client.is_loopback() => Ok(()),
+        None => Err(KeyError::Unknown),
+    }
+}
+
+// The URL of the map as the client reached it (e.g. `https://tiles.example.com/paris`), which the paths of the map
+// are appended to. Without the Host header, it's left relative to the server.
+fn map_url(map: &TileMap<'_>, request: &HttpRequest) -> String {
+    let base_url = request.host.as_ref().map_or_else(String::new, |host| {
+        format!("{}://{}", request.forwarded_proto.as_deref().unwrap_or("http"), host)
+    });
+    let map_prefix = map.name.as_ref().map_or_else(String::new, |name| format!("/{}", name));
+    format!("{}{}", base_url, map_prefix)
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_response_into_hyper() {
+        let response = HttpResponse {
+            status: "304 Not Modified".to_string(),
+            headers: vec![
+                "ETag: \"abc\"".to_string(),
+                "Vary: Origin".to_string(),
+                "Vary: Accept".to_string(),
+            ],
+            body: Vec::new(),
+        }
+        .into_hyper();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], "\"abc\"");
+        assert_eq!(response.headers().get_all("vary").iter().count(), 2);
+
+        let response = HttpResponse {
+            status: "nonsense".to_string(),
+            headers: vec!["Not a header".to_string()],
+            body: b"OK".to_vec(),
+        }
+        .into_hyper();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.headers().is_empty());
+    }
+
+    #[test]
+    fn test_map_names() {
+        assert!(validate_map_name("paris").is_ok());
+        assert!(validate_map_name("new-york_2").is_ok());
+        for name in ["", "15", "base", "labels", "a/b", "a.b", "a?b"] {
+            assert!(validate_map_name(name).is_err(), "{}", name);
+        }
+
+        assert_eq!(strip_map_name("/paris/15/1/2.png", "paris"), Some("/15/1/2.png"));
+        assert_eq!(
+            strip_map_name("/paris/labels/15/1/2.png", "paris"),
+            Some("/labels/15/1/2.png")
+        );
+        assert_eq!(strip_map_name("/paris/reload", "paris"), Some("/reload"));
+        assert_eq!(strip_map_name("/paris", "paris"), None);
+        assert_eq!(strip_map_name("/parisian/15/1/2.png", "paris"), None);
+        assert_eq!(strip_map_name("/15/1/2.png", "paris"), None);
+    }
+
+    #[test]
+    fn test_tile_scale() {
+        let get_scale = |path| extract_tile_from_path(path).ok().map(|tile| tile.scale);
+        assert_eq!(get_scale("/15/19805/10244.png"), Some(1));
+        assert_eq!(get_scale("/15/19805/10244@2x.png"), Some(2));
+        assert_eq!(get_scale("/labels/15/19805/10244@4x.png"), Some(4));
+        assert_eq!(get_scale("/15/19805/10244@0x.png"), None);
+        assert_eq!(get_scale("/15/19805/10244@100x.png"), None);
+    }
+
+    #[test]
+    fn test_tms_tile() {
+        let get_tile = |path| extract_tile_from_path(path).ok().map(|tile| tile.tile);
+        assert_eq!(get_tile("/tms/15/19805/22523.png"), get_tile("/15/19805/10244.png"));
+        assert_eq!(get_tile("/tms/0/0/0.png"), Some(Tile { zoom: 0, x: 0, y: 0 }));
+        assert_eq!(get_tile("/tms/1/0/1.png"), Some(Tile { zoom: 1, x: 0, y: 0 }));
+        assert_eq!(
+            get_tile("/tms/labels/2/1/0@2x.png?key=abc"),
+            Some(Tile { zoom: 2, x: 1, y: 3 })
+        );
+        assert_eq!(get_tile("/tms/1/0/2.png"), None);
+        assert_eq!(get_tile("/tmsx/1/0/0.png"), None);
+    }
+
+    #[test]
+    fn test_quadkey_tile() {
+        let get_tile = |path| extract_tile_from_path(path).ok().map(|tile| (tile.tile, tile.scale));
+        assert_eq!(get_tile("/q/120310101011301.png"), get_tile("/15/19805/10244.png"));
+        assert_eq!(get_tile("/q/.png"), Some((Tile { zoom: 0, x: 0, y: 0 }, 1)));
+        assert_eq!(get_tile("/q/3@2x.png?key=abc"), Some((Tile { zoom: 1, x: 1, y: 1 }, 2)));
+        assert_eq!(get_tile("/q/13"), Some((Tile { zoom: 2, x: 3, y: 1 }, 1)));
+        assert!(matches!(
+            extract_tile_from_path("/q/1204.png"),
+            Err(RequestError::BadRequest(_))
+        ));
+        assert!(matches!(
+            extract_tile_from_path("/q/00000000000000000000000.png"),
+            Err(RequestError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_tile_path_errors() {
+        let status = |path| match extract_tile_from_path(path) {
+            Ok(_) => "200",
+            Err(RequestError::BadRequest(_)) => "400",
+            Err(RequestError::NotFound(_)) => "404",
+        };
+        assert_eq!(status("/0/0/0.png"), "200");
+        assert_eq!(status("/22/4194303/4194303"), "200");
+        assert_eq!(status("/base/3/7/7.geojson?tags=name"), "200");
+        for path in [
+            "/1/-1/0.png",
+            "/1/+1/0.png",
+            "/1/0x1/0.png",
+            "/abc/0/0.png",
+            "/1/0/.png",
+            "/1/0/0.5.png",
+            "/1/0/0.jpg",
+            "/1/0/0@5x.png",
+            "/1/0/0@x.png",
+            "/1/0/0@2.png",
+        ] {
+            assert_eq!(status(path), "400", "{}", path);
+        }
+        for path in [
+            "/1/2/0.png",
+            "/1/0/2.png",
+            "/23/0/0.png",
+            "/4294967296/0/0.png",
+            "/99999999999999999999/0/0.png",
+            "/1/0.png",
+            "/1/0/0/0.png",
+            "/foo/1/0/0.png",
+            "/",
+            "",
+        ] {
+            assert_eq!(status(path), "404", "{}", path);
+        }
+
+        assert!(validate_path("/15/19805/10244.png?date=2020-01-01").is_ok());
+        for path in [
+            "/../15/1/2.png",
+            "/base/./15/1/2.png",
+            "/paris/..",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2e%2fetc",
+            "/a\\..\\b",
+            "/1/0/0.png%00",
+            "15/1/2.png",
+        ] {
+            assert!(validate_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_static_map_query() {
+        let (bounds, width, height) =
+            extract_static_map_from_query("bbox=37.61,55.75,37.62,55.76&width=800&height=600&key=KEY").unwrap();
+        assert_eq!(bounds.to_string(), "37.61,55.75,37.62,55.76");
+        assert_eq!((width, height), (800, 600));
+
+        assert!(extract_static_map_from_query("width=800&height=600").is_err());
+        assert!(extract_static_map_from_query("bbox=37.62,55.75,37.61,55.76&width=800&height=600").is_err());
+        assert!(extract_static_map_from_query("bbox=37.61,55.75,37.62,55.76&width=800").is_err());
+        assert!(extract_static_map_from_query("bbox=37.61,55.75,37.62,55.76&width=0&height=600").is_err());
+        assert!(extract_static_map_from_query("bbox=37.61,55.75,37.62,55.76&width=800&height=5000").is_err());
+    }
+
+    #[test]
+    fn test_api_key() {
+        assert_eq!(extract_key_from_path("/15/19805/10244.png?key=abc"), Some("abc"));
+        assert_eq!(
+            extract_key_from_path("/15/19805/10244.png?date=2020-01-01&key=abc"),
+            Some("abc")
+        );
+        assert_eq!(extract_key_from_path("/15/19805/10244.png?monkey=abc"), None);
+        assert_eq!(extract_key_from_path("/15/19805/10244.png"), None);
+        assert!(extract_tile_from_path("/15/19805/10244@2x.png?key=abc").is_ok());
+    }
+
+    #[test]
+    fn test_reload_allowed() {
+        let local = IpAddr::from([127, 0, 0, 1]);
+        let remote = IpAddr::from([192, 0, 2, 1]);
+        assert!(check_reload_allowed(None, None, local).is_ok());
+        assert!(check_reload_allowed(None, None, IpAddr::from(std::net::Ipv6Addr::LOCALHOST)).is_ok());
+        assert!(check_reload_allowed(None, None, remote).is_err());
+        assert!(check_reload_allowed(None, Some("guess"), remote).is_err());
+    }
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(
+            extract_palette_from_path("/15/19805/10244.png?key=abc&palette=protanopia").unwrap(),
+            Some(Palette::Protanopia)
+        );
+        assert_eq!(extract_palette_from_path("/15/19805/10244.png").unwrap(), None);
+        assert!(extract_palette_from_path("/15/19805/10244.png?palette=grayscale").is_err());
+    }
+
+    // A cache shared with another server that is drawing the tile it has locked.
+    struct SharedCache {
+        tiles: Mutex<Vec<(String, Vec<u8>)>>,
+        is_locked: Mutex<bool>,
+    }
+
+    impl TileCache for SharedCache {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            let tiles = self.tiles.lock().unwrap();
+            Ok(tiles.iter().find(|(k, _)| k == key).map(|(_, data)| data.clone()))
+        }
+
+        fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<()> {
+            self.tiles.lock().unwrap().push((key.to_string(), data.to_vec()));
+            Ok(())
+        }
+
+        fn try_lock(&self, _key: &str) -> Result<Option<String>> {
+            let mut is_locked = self.is_locked.lock().unwrap();
+            Ok(if *is_locked {
+                None
+            } else {
+                *is_locked = true;
+                Some("token".to_string())
+            })
+        }
+
+        fn lock_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(200))
+        }
+    }
+
+    #[test]
+    fn test_get_cached_tile() {
+        let cache = SharedCache {
+            tiles: Mutex::new(Vec::new()),
+            is_locked: Mutex::new(false),
+        };
+        let runtime = runtime::Builder::new_multi_thread().enable_time().build().unwrap();
+        let lookup = |cancellation| runtime.block_on(get_cached_tile(&cache, "1/0/0.png", cancellation));
+        let cancellation = CancellationToken::new();
+        assert!(matches!(lookup(&cancellation), Ok(CacheLookup::Locked(token)) if token == "token"));
+        // Nobody draws the tile, so the server stops waiting and draws it on its own.
+        assert!(matches!(lookup(&cancellation), Ok(CacheLookup::Miss)));
+
+        // The client that has gone away doesn't wait anymore.
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        assert!(lookup(&cancelled).is_err_and(|e| e.is::<Cancelled>()));
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                cache.put("1/0/0.png", b"tile", "image/png").unwrap();
+            });
+            assert!(matches!(lookup(&cancellation), Ok(CacheLookup::Hit(data)) if data == b"tile"));
+        });
+    }
+
+    #[test]
+    fn test_viewport() {
+        let viewport = |header| Viewport::parse(header).unwrap();
+        assert_eq!(
+            Viewport::parse("3f9c:17"),
+            Some(Viewport {
+                session: "3f9c".to_string(),
+                generation: 17,
+            })
+        );
+        for invalid in ["", "3f9c", ":17", "3f9c:", "3f9c:-1", "3f9c:next"] {
+            assert_eq!(Viewport::parse(invalid), None, "{}", invalid);
+        }
+
+        assert!(viewport("3f9c:17").supersedes(&viewport("3f9c:16")));
+        assert!(!viewport("3f9c:17").supersedes(&viewport("3f9c:17")));
+        assert!(!viewport("3f9c:16").supersedes(&viewport("3f9c:17")));
+        assert!(!viewport("a0b1:17").supersedes(&viewport("3f9c:16")));
+    }
+
+    #[test]
+    fn test_accepts_media_type() {
+        let accepts_webp = |accept| accepts_media_type(accept, "image/webp");
+        assert!(accepts_webp(Some("image/avif,image/webp,*/*")));
+        assert!(accepts_webp(Some("image/png, IMAGE/WEBP;q=0.8")));
+        assert!(!accepts_webp(Some("image/webp;q=0")));
+        assert!(!accepts_webp(Some("image/*,*/*;q=0.8")));
+        assert!(!accepts_webp(Some("")));
+        assert!(!accepts_webp(None));
+    }
+
+    #[test]
+    fn test_tile_etag() {
+        let tile = Tile {
+            zoom: 15,
+            x: 19805,
+            y: 10244,
+        };
+        let etag = |data_version, palette| {
+            let cached_tile = CachedTile {
+                map: None,
+                layers: TileLayers::All,
+                tile: &tile,
+                scale: 1,
+                extension: "png",
+                style_version: "3f2a9c1e",
+                data_version,
+            };
+            get_tile_etag(&cached_tile, 256, None, None, palette, None)
+        };
+        assert_eq!(etag("1700000000", None), etag("1700000000", None));
+        assert_ne!(etag("1700000000", None), etag("1700000001", None));
+        assert_ne!(
+            etag("1700000000", None),
+            etag("1700000000", Some(Palette::Deuteranopia))
+        );
+
+        let tag = etag("1700000000", None);
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert!(matches_etag(&tag, &tag));
+        assert!(matches_etag(&format!("\"0000\", W/{}", tag), &tag));
+        assert!(matches_etag("*", &tag));
+        assert!(!matches_etag("\"0000\"", &tag));
+    }
+
+    proptest! {
+        #[test]
+        fn test_tile_path_fuzz(path in "\\PC*") {
+            // Anything at all gives either a tile within the range of its zoom level or an error.
+            if let Ok(tile) = extract_tile_from_path(&path) {
+                prop_assert!(tile.tile.zoom <= MAX_OVERZOOM);
+                prop_assert!(tile.tile.x < 1 << tile.tile.zoom && tile.tile.y < 1 << tile.tile.zoom);
+                prop_assert!((1..=MAX_TILE_SCALE).contains(&tile.scale));
+            }
+            let _ = validate_path(&path);
+        }
+
+        #[test]
+        fn test_tile_path_parts(
+            layer in prop::sample::select(vec!["", "/base", "/labels", "/paris", "/.."]),
+            z in "-?[0-9]{1,3}|[a-z]{1,2}",
+            x in "-?[0-9]{1,8}",
+            y in "-?[0-9]{1,8}",
+            suffix in prop::sample::select(
+                vec!["", ".png", ".webp", "@2x.png", "@9x.png", ".geojson", ".gif", "."]
+            ),
+            query in prop::sample::select(vec!["", "?key=abc", "?date=2020-01-01&fade=0.5"]),
+        ) {
+            let path = format!("{}/{}/{}/{}{}{}", layer, z, x, y, suffix, query);
+            let is_number = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+            let (z_value, x_value, y_value) = (z.parse::<u64>().ok(), x.parse::<u64>().ok(), y.parse::<u64>().ok());
+            let expected = if !["", "/base", "/labels"].contains(&layer) {
+                "404"
+            } else if ["@9x.png", ".gif", "."].contains(&suffix) || !(is_number(&z) && is_number(&x) && is_number(&y)) {
+                "400"
+            } else {
+                let (z, x, y) = (z_value.unwrap(), x_value.unwrap(), y_value.unwrap());
+                if z > u64::from(MAX_OVERZOOM) || x >= 1 << z || y >= 1 << z {
+                    "404"
+                } else {
+                    "200"
+                }
+            };
+            match extract_tile_from_path(&path) {
+                Ok(tile) => {
+                    prop_assert_eq!(expected, "200", "{}", path);
+                    let parsed = (u64::from(tile.tile.zoom), u64::from(tile.tile.x), u64::from(tile.tile.y));
+                    prop_assert_eq!(parsed, (z_value.unwrap(), x_value.unwrap(), y_value.unwrap()));
+                }
+                Err(RequestError::BadRequest(_)) => prop_assert_eq!(expected, "400", "{}", path),
+                Err(RequestError::NotFound(_)) => prop_assert_eq!(expected, "404", "{}", path),
+            }
+        }
+    }
 }
@@ -1,7 +1,19 @@
+pub mod animation;
+pub mod api_keys;
+pub mod cache_control;
 pub mod coords;
+pub mod cors;
 pub mod draw;
 pub mod geodata;
 pub mod http_server;
+pub mod map_matching;
 pub mod mapcss;
+pub mod memory_budget;
+pub mod metrics;
 pub mod perf_stats;
+pub mod progress;
+pub mod seeder;
 pub mod tile;
+pub mod tile_cache;
+pub mod upstream;
+pub mod wmts;
@@ -0,0 +1,218 @@
+use crate::geodata::reader::{GeodataReader, OsmEntity};
+use crate::mapcss::styler::Styler;
+use crate::tile::{coords_to_tile, coords_to_xy, xy_to_coords, Tile};
+use std::collections::HashMap;
+
+// Web Mercator pixel coordinates at the zoom level of the matching.
+type Xy = (f64, f64);
+
+struct Road {
+    id: u64,
+    points: Vec<Xy>,
+}
+
+// Where a point of the track ends up on a road: the segment it's on and the snapped position.
+#[derive(Debug, PartialEq)]
+struct Snap {
+    road_id: u64,
+    segment_idx: usize,
+    xy: Xy,
+}
+
+/// Snaps coordinate tracks (e.g. from GPX files) to the roads, meaning the ways with a `highway` tag
+/// that the stylesheet draws as lines at the given zoom level. The resulting polylines follow the roads
+/// between the snapped points, so they can be drawn on top of the map without cutting the corners.
+pub struct MapMatcher<'a> {
+    reader: &'a GeodataReader<'a>,
+    styler: &'a Styler,
+    zoom: u8,
+    max_distance: f64,
+    roads: HashMap<Tile, Vec<Road>>,
+}
+
+impl<'a> MapMatcher<'a> {
+    /// The points that are farther than `max_distance` pixels (at `zoom`) from every road are left
+    /// where they are. The distance can't be larger than the size of a tile.
+    pub fn new(reader: &'a GeodataReader<'a>, styler: &'a Styler, zoom: u8, max_distance: f64) -> MapMatcher<'a> {
+        MapMatcher {
+            reader,
+            styler,
+            zoom,
+            max_distance,
+            roads: HashMap::new(),
+        }
+    }
+
+    /// Takes and returns (lat, lon) pairs.
+    pub fn match_track(&mut self, track: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        let mut snaps = Vec::new();
+        for point in track {
+            let xy = coords_to_xy(point, self.zoom);
+            let max_distance = self.max_distance;
+            let roads = self.get_roads(&coords_to_tile(point, self.zoom));
+            snaps.push(snap_to_roads(xy, roads, max_distance).ok_or(xy));
+        }
+        follow_roads(&snaps, &self.roads)
+            .into_iter()
+            .map(|(x, y)| xy_to_coords(x, y, self.zoom))
+            .collect()
+    }
+
+    fn get_roads(&mut self, tile: &Tile) -> &[Road] {
+        let (reader, styler, zoom) = (self.reader, self.styler, self.zoom);
+        self.roads.entry(tile.clone()).or_insert_with(|| {
+            let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+            let roads = entities
+                .ways
+                .iter()
+                .filter(|way| way.tags().get_by_key("highway").is_some());
+            let mut styled_roads = styler
+                .style_entities(roads, zoom, false)
+                .into_iter()
+                .filter(|(_, style)| style.width.is_some_and(|width| width > 0.0))
+                .map(|(way, _)| way)
+                .collect::<Vec<_>>();
+            // A road can have several styles, e.g. for its casing and for its fill.
+            styled_roads.sort_by_key(|way| way.global_id());
+            styled_roads.dedup_by_key(|way| way.global_id());
+            styled_roads
+                .into_iter()
+                .map(|way| Road {
+                    id: way.global_id(),
+                    points: (0..way.node_count())
+                        .map(|idx| coords_to_xy(&way.get_node(idx), zoom))
+                        .collect(),
+                })
+                .collect()
+        })
+    }
+}
+
+fn snap_to_roads(xy: Xy, roads: &[Road], max_distance: f64) -> Option<Snap> {
+    let mut best: Option<(f64, Snap)> = None;
+    for road in roads {
+        for (segment_idx, segment) in road.points.windows(2).enumerate() {
+            let projection = project_to_segment(xy, segment[0], segment[1]);
+            let distance = (projection.0 - xy.0).hypot(projection.1 - xy.1);
+            if distance <= max_distance && best.as_ref().is_none_or(|(best_distance, _)| distance < *best_distance) {
+                let snap = Snap {
+                    road_id: road.id,
+                    segment_idx,
+                    xy: projection,
+                };
+                best = Some((distance, snap));
+            }
+        }
+    }
+    best.map(|(_, snap)| snap)
+}
+
+fn project_to_segment(p: Xy, a: Xy, b: Xy) -> Xy {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return a;
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0);
+    (a.0 + t * dx, a.1 + t * dy)
+}
+
+// Builds the polyline through the snapped points (or the original ones, for the points that weren't snapped).
+// When two consecutive points are on the same road, the nodes of the road between them are added too.
+fn follow_roads(snaps: &[Result<Snap, Xy>], roads: &HashMap<Tile, Vec<Road>>) -> Vec<Xy> {
+    let find_road = |id: u64| roads.values().flatten().find(|road| road.id == id);
+
+    let mut result = Vec::<Xy>::new();
+    let mut push = |xy: Xy| {
+        if result.last() != Some(&xy) {
+            result.push(xy);
+        }
+    };
+    for (idx, snap) in snaps.iter().enumerate() {
+        match snap {
+            Ok(snap) => {
+                if let Some(Ok(previous)) = idx.checked_sub(1).map(|prev_idx| &snaps[prev_idx]) {
+                    if previous.road_id == snap.road_id {
+                        let points = &find_road(snap.road_id).unwrap().points;
+                        if previous.segment_idx < snap.segment_idx {
+                            points[previous.segment_idx + 1..=snap.segment_idx]
+                                .iter()
+                                .for_each(|p| push(*p));
+                        } else {
+                            points[snap.segment_idx + 1..=previous.segment_idx]
+                                .iter()
+                                .rev()
+                                .for_each(|p| push(*p));
+                        }
+                    }
+                }
+                push(snap.xy);
+            }
+            Err(xy) => push(*xy),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_roads() -> HashMap<Tile, Vec<Road>> {
+        let road = |id, points| Road { id, points };
+        let roads = vec![
+            road(1, vec![(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (200.0, 100.0)]),
+            road(2, vec![(0.0, 50.0), (50.0, 50.0)]),
+        ];
+        let mut result = HashMap::new();
+        result.insert(Tile { zoom: 0, x: 0, y: 0 }, roads);
+        result
+    }
+
+    #[test]
+    fn test_snap_to_roads() {
+        let roads = get_roads();
+        let roads = &roads[&Tile { zoom: 0, x: 0, y: 0 }];
+        assert_eq!(
+            snap_to_roads((20.0, 5.0), roads, 10.0),
+            Some(Snap {
+                road_id: 1,
+                segment_idx: 0,
+                xy: (20.0, 0.0),
+            })
+        );
+        assert_eq!(snap_to_roads((20.0, 46.0), roads, 10.0).unwrap().road_id, 2);
+        assert_eq!(snap_to_roads((20.0, 25.0), roads, 10.0), None);
+    }
+
+    #[test]
+    fn test_follow_roads() {
+        let roads = get_roads();
+        let snap_all = |track: &[Xy]| {
+            track
+                .iter()
+                .map(|xy| snap_to_roads(*xy, &roads[&Tile { zoom: 0, x: 0, y: 0 }], 10.0).ok_or(*xy))
+                .collect::<Vec<_>>()
+        };
+
+        // Goes around the corners of the first road, then leaves it.
+        let track = [(50.0, 3.0), (150.0, 97.0), (150.0, 130.0)];
+        assert_eq!(
+            follow_roads(&snap_all(&track), &roads),
+            vec![
+                (50.0, 0.0),
+                (100.0, 0.0),
+                (100.0, 100.0),
+                (150.0, 100.0),
+                (150.0, 130.0)
+            ]
+        );
+
+        // The same in the opposite direction.
+        let track = [(150.0, 97.0), (50.0, 3.0)];
+        assert_eq!(
+            follow_roads(&snap_all(&track), &roads),
+            vec![(150.0, 100.0), (100.0, 100.0), (100.0, 0.0), (50.0, 0.0)]
+        );
+    }
+}
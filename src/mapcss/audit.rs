@@ -0,0 +1,251 @@
+use crate::geodata::reader::{GeodataReader, OsmArea, OsmEntities, OsmEntity};
+use crate::mapcss::color::Color;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
+use crate::mapcss::styler::{
+    area_matches, CacheableEntity, Style, StyleType, StyleableEntity, Styler, DEFAULT_TEXT_COLOR,
+};
+use crate::tile::{Tile, MAX_ZOOM};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// Two colors of a style that are drawn one on top of the other and have to stand apart to be legible.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ColorPair {
+    /// The text and the halo around it.
+    TextOnHalo,
+    /// The text and the box of its shield.
+    TextOnShield,
+    /// A text without a halo and whatever is under it: the fill of the labeled area, or the canvas.
+    TextOnBackground,
+    /// The casing and the line inside it (e.g. a road and its border).
+    CasingAroundLine,
+}
+
+impl ColorPair {
+    // The properties that decide the colors of the pair. The rule that sets them last is the one to fix.
+    fn properties(&self) -> &'static [&'static str] {
+        match self {
+            ColorPair::TextOnHalo => &["text-color", "text-halo-color", "text-halo-radius", "text-halo-auto"],
+            ColorPair::TextOnShield => &["text-color", "shield-color"],
+            ColorPair::TextOnBackground => &["text-color", "text"],
+            ColorPair::CasingAroundLine => &["color", "casing-color"],
+        }
+    }
+}
+
+impl fmt::Display for ColorPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorPair::TextOnHalo => "Text on halo",
+            ColorPair::TextOnShield => "Text on shield",
+            ColorPair::TextOnBackground => "Text on background",
+            ColorPair::CasingAroundLine => "Casing around line",
+        })
+    }
+}
+
+/// The lowest contrast ratios (as defined by WCAG) that the pairs of colors are allowed to have.
+#[derive(Clone, Debug)]
+pub struct ContrastThresholds {
+    /// For the text, 4.5 by default (the WCAG AA level for normal text).
+    pub text: f64,
+    /// For the casings, 3 by default (the WCAG level for graphical objects).
+    pub lines: f64,
+}
+
+impl Default for ContrastThresholds {
+    fn default() -> ContrastThresholds {
+        ContrastThresholds { text: 4.5, lines: 3.0 }
+    }
+}
+
+/// A pair of colors with too little contrast, found in the styles of the objects of the geodata.
+#[derive(Debug, PartialEq)]
+pub struct ContrastIssue {
+    pub pair: ColorPair,
+    /// The selectors of the last rule that sets the colors of the pair for the objects.
+    pub selectors: String,
+    /// The colors with the lowest contrast among all the objects and zoom levels.
+    pub foreground: Color,
+    pub background: Color,
+    pub contrast_ratio: f64,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub object_count: usize,
+}
+
+impl fmt::Display for ContrastIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |c: &Color| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b);
+        write!(
+            f,
+            "{}: contrast {:.2} ({} on {}) at z{}-{} for {} object(s): {}",
+            self.pair,
+            self.contrast_ratio,
+            hex(&self.foreground),
+            hex(&self.background),
+            self.min_zoom,
+            self.max_zoom,
+            self.object_count,
+            self.selectors
+        )
+    }
+}
+
+pub fn audit_stylesheet(
+    stylesheet_file: &str,
+    stylesheet_type: &StyleType,
+    font_size_multiplier: Option<f64>,
+    geodata_file: &str,
+    thresholds: &ContrastThresholds,
+) -> Result<Vec<ContrastIssue>> {
+    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
+    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet")?;
+    let styler = Styler::new(rules, stylesheet_type, font_size_multiplier);
+    let reader = GeodataReader::load(geodata_file).context("Failed to load the geodata file")?;
+
+    let world = Tile { zoom: 0, x: 0, y: 0 };
+    let entities = reader.get_entities_in_tile_with_neighbors(&world, &None);
+    Ok(audit_styles(&styler, &entities, thresholds))
+}
+
+/// Styles every object at every zoom level the same way as for drawing (so that e.g. the automatic halo
+/// colors are taken into account) and checks the colors that end up next to each other. The issues are
+/// grouped by the rule to fix, in the order of the rules.
+pub fn audit_styles(
+    styler: &Styler,
+    entities: &OsmEntities<'_>,
+    thresholds: &ContrastThresholds,
+) -> Vec<ContrastIssue> {
+    let mut auditor = Auditor {
+        styler,
+        thresholds,
+        // Without a canvas color the tiles start black, as in the drawer.
+        canvas_color: styler.canvas_fill_color.clone().unwrap_or(Color { r: 0, g: 0, b: 0 }),
+        findings: BTreeMap::new(),
+    };
+    for zoom in 0..=MAX_ZOOM {
+        auditor.audit(entities.nodes.iter(), zoom, |_| false);
+        auditor.audit(entities.ways.iter(), zoom, |way| way.is_area());
+        auditor.audit(entities.multipolygons.iter(), zoom, |_| true);
+    }
+
+    auditor
+        .findings
+        .into_iter()
+        .map(|((rule_idx, pair), finding)| ContrastIssue {
+            pair,
+            selectors: styler.rules()[rule_idx]
+                .selectors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            foreground: finding.foreground,
+            background: finding.background,
+            contrast_ratio: finding.contrast_ratio,
+            min_zoom: finding.min_zoom,
+            max_zoom: finding.max_zoom,
+            object_count: finding.object_ids.len(),
+        })
+        .collect()
+}
+
+struct Finding {
+    foreground: Color,
+    background: Color,
+    contrast_ratio: f64,
+    min_zoom: u8,
+    max_zoom: u8,
+    object_ids: HashSet<u64>,
+}
+
+struct Auditor<'s> {
+    styler: &'s Styler,
+    thresholds: &'s ContrastThresholds,
+    canvas_color: Color,
+    // By the index of the rule to fix and the pair of colors.
+    findings: BTreeMap<(usize, ColorPair), Finding>,
+}
+
+impl Auditor<'_> {
+    fn audit<'e, 'wp, A, I>(&mut self, entities: I, zoom: u8, is_filled_area: impl Fn(&A) -> bool)
+    where
+        A: CacheableEntity + StyleableEntity + OsmEntity<'e> + 'wp,
+        I: Iterator<Item = &'wp A>,
+    {
+        for (entity, style) in self.styler.style_entities(entities, zoom, false) {
+            let background = match &style.fill_color {
+                Some(fill_color) if is_filled_area(entity) => fill_color,
+                _ => &self.canvas_color,
+            };
+            for (pair, foreground, background) in color_pairs(&style, background) {
+                let contrast_ratio = foreground.contrast_ratio(background);
+                let threshold = match pair {
+                    ColorPair::CasingAroundLine => self.thresholds.lines,
+                    _ => self.thresholds.text,
+                };
+                if contrast_ratio >= threshold {
+                    continue;
+                }
+                let rule_idx = match self.find_rule(entity, zoom, pair) {
+                    Some(rule_idx) => rule_idx,
+                    None => continue,
+                };
+
+                let finding = self.findings.entry((rule_idx, pair)).or_insert_with(|| Finding {
+                    foreground: foreground.clone(),
+                    background: background.clone(),
+                    contrast_ratio,
+                    min_zoom: zoom,
+                    max_zoom: zoom,
+                    object_ids: HashSet::new(),
+                });
+                if contrast_ratio < finding.contrast_ratio {
+                    finding.foreground = foreground.clone();
+                    finding.background = background.clone();
+                    finding.contrast_ratio = contrast_ratio;
+                }
+                finding.min_zoom = finding.min_zoom.min(zoom);
+                finding.max_zoom = finding.max_zoom.max(zoom);
+                finding.object_ids.insert(entity.global_id());
+            }
+        }
+    }
+
+    fn find_rule<'e, A>(&self, entity: &A, zoom: u8, pair: ColorPair) -> Option<usize>
+    where
+        A: StyleableEntity + OsmEntity<'e>,
+    {
+        self.styler.rules().iter().rposition(|rule| {
+            rule.properties
+                .iter()
+                .any(|p| pair.properties().contains(&p.name.as_str()))
+                && rule.selectors.iter().any(|sel| area_matches(entity, sel, zoom))
+        })
+    }
+}
+
+// The pairs of colors of the style that are drawn next to each other, as (pair, foreground, background).
+fn color_pairs<'s>(style: &'s Style, background: &'s Color) -> Vec<(ColorPair, &'s Color, &'s Color)> {
+    let mut pairs = Vec::new();
+    if let Some(text_style) = style
+        .text_style
+        .as_ref()
+        .filter(|text_style| !text_style.text.is_empty())
+    {
+        let text_color = text_style.text_color.as_ref().unwrap_or(&DEFAULT_TEXT_COLOR);
+        pairs.push(match (&text_style.shield, &text_style.halo) {
+            (Some(shield), _) => (ColorPair::TextOnShield, text_color, &shield.color),
+            (None, Some(halo)) => (ColorPair::TextOnHalo, text_color, &halo.color),
+            (None, None) => (ColorPair::TextOnBackground, text_color, background),
+        });
+    }
+    if let (Some(color), Some(casing_color)) = (&style.color, &style.casing_color) {
+        if style.casing_width.is_some_and(|width| width > 0.0) {
+            pairs.push((ColorPair::CasingAroundLine, casing_color, color));
+        }
+    }
+    pairs
+}
@@ -20,3 +20,31 @@ pub fn from_color_name(name: &str) -> Option<Color> {
         _ => None,
     }
 }
+
+impl Color {
+    /// The relative luminance as defined by WCAG: 0 for black and 1 for white.
+    pub fn luminance(&self) -> f64 {
+        let linearize = |c: u8| {
+            let c = f64::from(c) / 255.0;
+            if c <= 0.040_45 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        0.2126 * linearize(self.r) + 0.7152 * linearize(self.g) + 0.0722 * linearize(self.b)
+    }
+
+    /// The WCAG contrast ratio between two colors, ranging from 1 (same luminance) to 21 (black and white).
+    /// # Examples
+    /// ```
+    /// use renderer::mapcss::color::from_color_name;
+    /// let (black, white) = (from_color_name("black").unwrap(), from_color_name("white").unwrap());
+    /// assert_eq!(black.contrast_ratio(&white), 21.0);
+    /// assert_eq!(white.contrast_ratio(&white), 1.0);
+    /// ```
+    pub fn contrast_ratio(&self, other: &Color) -> f64 {
+        let (l1, l2) = (self.luminance(), other.luminance());
+        (l1.max(l2) + 0.05) / (l1.min(l2) + 0.05)
+    }
+}
@@ -0,0 +1,110 @@
+use crate::mapcss::color::{from_color_name, Color};
+use crate::mapcss::parser::{BinaryStringTestType, PropertyValue, Rule, Selector, Test, UnaryTestType};
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Derives a dark variant from a light stylesheet by changing the colors of its rules, so that the two
+/// variants can't get out of sync. Unlike the `invert` filter, this keeps the hues: light green parks
+/// become dark green rather than purple.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DarkMode {
+    /// The rules that test for these tags keep their colors from the light style.
+    pub exceptions: Vec<DarkModeException>,
+}
+
+/// A tag with a given value (`natural=water`) or with any value (`waterway`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DarkModeException {
+    pub tag_name: String,
+    pub value: Option<String>,
+}
+
+impl FromStr for DarkModeException {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DarkModeException> {
+        let (tag_name, value) = match s.split_once('=') {
+            Some((tag_name, value)) => (tag_name.trim(), Some(value.trim().to_string())),
+            None => (s.trim(), None),
+        };
+        if tag_name.is_empty() || value.as_deref() == Some("") {
+            bail!("Expected TAG or TAG=VALUE, got <{}>", s);
+        }
+        Ok(DarkModeException {
+            tag_name: tag_name.to_string(),
+            value,
+        })
+    }
+}
+
+/// Parses a comma-separated list of exceptions.
+/// # Examples
+/// ```
+/// use renderer::mapcss::dark::parse_exceptions;
+/// let exceptions = parse_exceptions("natural=water, waterway").unwrap();
+/// assert_eq!(exceptions[0].value.as_deref(), Some("water"));
+/// assert_eq!(exceptions[1].tag_name, "waterway");
+/// assert!(parse_exceptions("natural=").is_err());
+/// ```
+pub fn parse_exceptions(s: &str) -> Result<Vec<DarkModeException>> {
+    s.split(',')
+        .filter(|exception| !exception.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl DarkMode {
+    /// Replaces every color in the rules with `invert_lightness` of it, except in the rules with a selector
+    /// that tests for one of the exceptions. The named colors (e.g. `white`) are replaced too.
+    pub fn apply(&self, rules: &mut [Rule]) {
+        for rule in rules {
+            if rule.selectors.iter().any(|selector| self.is_exception(selector)) {
+                continue;
+            }
+            for property in rule.properties.iter_mut().filter(|p| p.name.ends_with("color")) {
+                let color = match &property.value {
+                    PropertyValue::Color(color) => Some(color.clone()),
+                    PropertyValue::Identifier(name) => from_color_name(name),
+                    _ => None,
+                };
+                if let Some(color) = color {
+                    property.value = PropertyValue::Color(invert_lightness(&color));
+                }
+            }
+        }
+    }
+
+    fn is_exception(&self, selector: &Selector) -> bool {
+        selector.tests.iter().any(|test| {
+            self.exceptions.iter().any(|exception| match test {
+                Test::Unary {
+                    tag_name,
+                    test_type: UnaryTestType::Exists | UnaryTestType::True,
+                } => *tag_name == exception.tag_name && exception.value.is_none(),
+                Test::BinaryStringCompare {
+                    tag_name,
+                    value,
+                    test_type: BinaryStringTestType::Equal,
+                } => *tag_name == exception.tag_name && exception.value.as_ref().is_none_or(|v| v == value),
+                _ => false,
+            })
+        })
+    }
+}
+
+/// Turns light colors into dark ones and vice versa, keeping the hue and the saturation (the lightness
+/// of HSL becomes 1 minus itself, which amounts to adding the same number to all three components).
+/// # Examples
+/// ```
+/// use renderer::mapcss::color::Color;
+/// use renderer::mapcss::dark::invert_lightness;
+/// assert_eq!(invert_lightness(&Color { r: 255, g: 255, b: 255 }), Color { r: 0, g: 0, b: 0 });
+/// assert_eq!(invert_lightness(&Color { r: 200, g: 230, b: 180 }), Color { r: 45, g: 75, b: 25 });
+/// assert_eq!(invert_lightness(&Color { r: 255, g: 0, b: 0 }), Color { r: 255, g: 0, b: 0 });
+/// ```
+pub fn invert_lightness(color: &Color) -> Color {
+    let components = [color.r, color.g, color.b].map(i32::from);
+    let (min, max) = (components.iter().min().unwrap(), components.iter().max().unwrap());
+    let [r, g, b] = components.map(|c| (c + 255 - max - min) as u8);
+    Color { r, g, b }
+}
@@ -0,0 +1,223 @@
+use crate::geodata::reader::{GeodataReader, OsmEntities};
+use crate::mapcss::parser::{
+    parse_file, split_stylesheet_path, BinaryStringTestType, ObjectType, Rule, Selector, Test, UnaryTestType,
+};
+use crate::mapcss::styler::{area_matches, get_layer_id, SUPPORTED_PROPERTIES};
+use crate::tile::Tile;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// A likely mistake in a stylesheet, found by checking it against the actual geodata.
+#[derive(Debug, PartialEq)]
+pub enum LintIssue {
+    /// None of the selectors of the rule match any object at the zoom levels they're meant for.
+    UnusedRule { selectors: String },
+    /// A selector of a rule that matches something with its other selectors.
+    UnusedSelector { selector: String },
+    /// Every object that the rule matches gets all of its properties overridden by the later rules.
+    ShadowedRule { selectors: String },
+    /// A property that the renderer doesn't know about and therefore ignores.
+    UnknownProperty {
+        property: String,
+        rule_count: usize,
+        example_selector: String,
+    },
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintIssue::UnusedRule { selectors } => write!(f, "Rule never matches any object: {}", selectors),
+            LintIssue::UnusedSelector { selector } => write!(f, "Selector never matches any object: {}", selector),
+            LintIssue::ShadowedRule { selectors } => {
+                write!(f, "Rule is fully overridden by the rules after it: {}", selectors)
+            }
+            LintIssue::UnknownProperty {
+                property,
+                rule_count,
+                example_selector,
+            } => write!(
+                f,
+                "Unknown property {} (in {} rule(s), e.g. {})",
+                property, rule_count, example_selector
+            ),
+        }
+    }
+}
+
+pub fn lint_stylesheet(stylesheet_file: &str, geodata_file: &str) -> Result<Vec<LintIssue>> {
+    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
+    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet")?;
+    let reader = GeodataReader::load(geodata_file).context("Failed to load the geodata file")?;
+
+    // The whole world, which means every object in the file.
+    let world = Tile { zoom: 0, x: 0, y: 0 };
+    let entities = reader.get_entities_in_tile_with_neighbors(&world, &None);
+    Ok(lint_rules(&rules, &entities))
+}
+
+pub fn lint_rules(rules: &[Rule], entities: &OsmEntities<'_>) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+
+    let unused = find_unused_selectors(rules, entities);
+    for (rule_idx, rule) in rules.iter().enumerate() {
+        let styled_selectors = || rule.selectors.iter().enumerate().filter(|(_, sel)| is_styled(sel));
+        let unused_count = styled_selectors()
+            .filter(|(sel_idx, _)| unused.contains(&(rule_idx, *sel_idx)))
+            .count();
+        if unused_count == 0 {
+            if is_shadowed(rules, rule_idx) {
+                issues.push(LintIssue::ShadowedRule {
+                    selectors: format_selectors(rule),
+                });
+            }
+        } else if unused_count == styled_selectors().count() {
+            issues.push(LintIssue::UnusedRule {
+                selectors: format_selectors(rule),
+            });
+        } else {
+            for (_, sel) in styled_selectors().filter(|(sel_idx, _)| unused.contains(&(rule_idx, *sel_idx))) {
+                issues.push(LintIssue::UnusedSelector {
+                    selector: sel.to_string(),
+                });
+            }
+        }
+    }
+
+    let mut unknown_properties = BTreeMap::<&str, (usize, &Selector)>::new();
+    for rule in rules.iter().filter(|rule| rule.selectors.iter().any(is_styled)) {
+        let property_names = rule.properties.iter().map(|p| p.name.as_str()).collect::<HashSet<_>>();
+        for name in property_names
+            .into_iter()
+            .filter(|name| !SUPPORTED_PROPERTIES.contains(name))
+        {
+            unknown_properties.entry(name).or_insert((0, &rule.selectors[0])).0 += 1;
+        }
+    }
+    for (property, (rule_count, example_selector)) in unknown_properties {
+        issues.push(LintIssue::UnknownProperty {
+            property: property.to_string(),
+            rule_count,
+            example_selector: example_selector.to_string(),
+        });
+    }
+
+    issues
+}
+
+// The canvas, meta and setting rules describe the whole map, not the objects.
+fn is_styled(selector: &Selector) -> bool {
+    !matches!(
+        selector.object_type,
+        ObjectType::Canvas | ObjectType::Meta | ObjectType::Setting
+    )
+}
+
+// Returns (rule index, selector index) of the selectors that don't match anything at any of their zoom levels.
+fn find_unused_selectors(rules: &[Rule], entities: &OsmEntities<'_>) -> HashSet<(usize, usize)> {
+    let mut unused = HashSet::new();
+    let mut unmatched = Vec::new();
+    for (rule_idx, rule) in rules.iter().enumerate() {
+        for (sel_idx, sel) in rule.selectors.iter().enumerate().filter(|(_, sel)| is_styled(sel)) {
+            let min_zoom = sel.min_zoom.unwrap_or_default();
+            if sel.max_zoom.is_some_and(|max_zoom| max_zoom < min_zoom) {
+                unused.insert((rule_idx, sel_idx));
+            } else {
+                unmatched.push((rule_idx, sel_idx, sel, min_zoom));
+            }
+        }
+    }
+
+    // Only the selectors that haven't matched anything yet are checked against the next objects, so that
+    // the used ones (which is hopefully most of them) are quickly out of the way.
+    for node in &entities.nodes {
+        unmatched.retain(|(_, _, sel, zoom)| !area_matches(node, sel, *zoom));
+    }
+    for way in &entities.ways {
+        unmatched.retain(|(_, _, sel, zoom)| !area_matches(way, sel, *zoom));
+    }
+    for multipolygon in &entities.multipolygons {
+        unmatched.retain(|(_, _, sel, zoom)| !area_matches(multipolygon, sel, *zoom));
+    }
+
+    unused.extend(
+        unmatched
+            .into_iter()
+            .map(|(rule_idx, sel_idx, _, _)| (rule_idx, sel_idx)),
+    );
+    unused
+}
+
+// A rule is shadowed if, for each of its selectors, the later rules that match at least the same objects
+// at the same zoom levels set all of its properties again.
+fn is_shadowed(rules: &[Rule], rule_idx: usize) -> bool {
+    let rule = &rules[rule_idx];
+    if rule.properties.is_empty() || !rule.selectors.iter().any(is_styled) {
+        return false;
+    }
+    rule.selectors.iter().filter(|sel| is_styled(sel)).all(|sel| {
+        let overridden = rules[rule_idx + 1..]
+            .iter()
+            .filter(|later| later.selectors.iter().any(|later_sel| covers(later_sel, sel)))
+            .flat_map(|later| later.properties.iter().map(|p| p.name.as_str()))
+            .collect::<HashSet<_>>();
+        rule.properties.iter().all(|p| overridden.contains(p.name.as_str()))
+    })
+}
+
+// Whether `wider` matches everything that `narrower` does.
+fn covers(wider: &Selector, narrower: &Selector) -> bool {
+    let same_layer = wider.layer_id.as_deref() == Some("*") || get_layer_id(wider) == get_layer_id(narrower);
+    let wider_object_type = matches!(
+        (&wider.object_type, &narrower.object_type),
+        (ObjectType::Node, ObjectType::Node)
+            | (ObjectType::Way, ObjectType::Way | ObjectType::Area)
+            | (ObjectType::Area, ObjectType::Area)
+    );
+    let wider_zoom_range = wider.min_zoom.unwrap_or_default() <= narrower.min_zoom.unwrap_or_default()
+        && wider.max_zoom.unwrap_or(u8::MAX) >= narrower.max_zoom.unwrap_or(u8::MAX);
+    let fewer_tests = wider
+        .tests
+        .iter()
+        .all(|wider_test| narrower.tests.iter().any(|test| implies(test, wider_test)));
+    // The selectors that depend on a setting don't match anything while it's off.
+    let fewer_settings = wider.settings.iter().all(|setting| narrower.settings.contains(setting));
+    same_layer && wider_object_type && wider_zoom_range && fewer_tests && fewer_settings
+}
+
+// Whether every object that passes `test` also passes `other`.
+fn implies(test: &Test, other: &Test) -> bool {
+    match other {
+        Test::Unary {
+            tag_name,
+            test_type: UnaryTestType::Exists,
+        } if get_required_tag(test) == Some(tag_name) => true,
+        _ => test.to_string() == other.to_string(),
+    }
+}
+
+// The tag that has to be present for an object to pass the test.
+fn get_required_tag(test: &Test) -> Option<&String> {
+    match test {
+        Test::Unary {
+            tag_name,
+            test_type: UnaryTestType::Exists | UnaryTestType::True,
+        }
+        | Test::BinaryStringCompare {
+            tag_name,
+            test_type: BinaryStringTestType::Equal,
+            ..
+        }
+        | Test::BinaryNumericCompare { tag_name, .. } => Some(tag_name),
+        _ => None,
+    }
+}
+
+fn format_selectors(rule: &Rule) -> String {
+    rule.selectors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
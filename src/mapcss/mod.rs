@@ -1,5 +1,11 @@
+pub mod audit;
 pub mod color;
+pub mod dark;
+pub mod lint;
+pub mod palette;
 pub mod parser;
+pub mod priority;
+pub mod settings;
 mod style_cache;
 pub mod styler;
 pub mod token;
@@ -0,0 +1,172 @@
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::Style;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// A remapping of the style colors for the viewers with a color vision deficiency. The colors are
+/// "daltonized": the part of every color that the viewer can't see is moved into the components that
+/// they can, so that e.g. the red and the green roads stay apart.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Palette {
+    /// For the viewers who don't see green well (the most common deficiency).
+    Deuteranopia,
+    /// For the viewers who don't see red well.
+    Protanopia,
+}
+
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Palette> {
+        match s.trim() {
+            "deuteranopia" => Ok(Palette::Deuteranopia),
+            "protanopia" => Ok(Palette::Protanopia),
+            unknown => bail!("Unknown palette: {} (expected deuteranopia or protanopia)", unknown),
+        }
+    }
+}
+
+/// Parses a comma-separated list of palettes.
+/// # Examples
+/// ```
+/// use renderer::mapcss::palette::{parse_palettes, Palette};
+/// assert_eq!(
+///     parse_palettes("deuteranopia, protanopia").unwrap(),
+///     vec![Palette::Deuteranopia, Palette::Protanopia]
+/// );
+/// assert!(parse_palettes("tritanopia").is_err());
+/// ```
+pub fn parse_palettes(s: &str) -> Result<Vec<Palette>> {
+    s.split(',')
+        .filter(|palette| !palette.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl Palette {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Deuteranopia => "deuteranopia",
+            Palette::Protanopia => "protanopia",
+        }
+    }
+
+    /// The grays stay as they are.
+    pub fn remap(&self, color: &Color) -> Color {
+        let linear = to_linear(color);
+        let simulated = self.simulate_linear(&linear);
+        let error = [0, 1, 2].map(|i| linear[i] - simulated[i]);
+        from_linear(&[
+            linear[0],
+            linear[1] + 0.7 * error[0] + error[1],
+            linear[2] + 0.7 * error[0] + error[2],
+        ])
+    }
+
+    /// Replaces all the colors of the style with their remapped versions.
+    pub fn remap_style(&self, style: &mut Style) {
+        let remap = |color: &mut Option<Color>| {
+            if let Some(color) = color {
+                *color = self.remap(color);
+            }
+        };
+        remap(&mut style.color);
+        remap(&mut style.fill_color);
+        remap(&mut style.background_color);
+        remap(&mut style.casing_color);
+        if let Some(symbol) = &mut style.symbol {
+            remap(&mut symbol.fill_color);
+            remap(&mut symbol.stroke_color);
+        }
+        if let Some(arc) = &mut style.arc {
+            remap(&mut arc.color);
+            remap(&mut arc.fill_color);
+        }
+        if let Some(text_style) = &mut style.text_style {
+            remap(&mut text_style.text_color);
+            if let Some(halo) = &mut text_style.halo {
+                halo.color = self.remap(&halo.color);
+            }
+            if let Some(shield) = &mut text_style.shield {
+                shield.color = self.remap(&shield.color);
+                remap(&mut shield.casing_color);
+            }
+        }
+    }
+
+    // How the viewer sees the color, from the model of Machado, Oliveira and Fernandes (2009) at full severity.
+    fn simulate_linear(&self, linear: &[f64; 3]) -> [f64; 3] {
+        let matrix = match self {
+            Palette::Deuteranopia => [
+                [0.367_322, 0.860_646, -0.227_968],
+                [0.280_085, 0.672_501, 0.047_413],
+                [-0.011_820, 0.042_940, 0.968_881],
+            ],
+            Palette::Protanopia => [
+                [0.152_286, 1.052_583, -0.204_868],
+                [0.114_503, 0.786_281, 0.099_216],
+                [-0.003_882, -0.048_116, 1.051_998],
+            ],
+        };
+        matrix.map(|row| row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2])
+    }
+}
+
+fn to_linear(color: &Color) -> [f64; 3] {
+    [color.r, color.g, color.b].map(|c| {
+        let c = f64::from(c) / 255.0;
+        if c <= 0.040_45 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+fn from_linear(linear: &[f64; 3]) -> Color {
+    let [r, g, b] = linear.map(|c| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.003_130_8 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    });
+    Color { r, g, b }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap() {
+        for palette in [Palette::Deuteranopia, Palette::Protanopia] {
+            for gray in [0, 128, 255] {
+                let color = Color {
+                    r: gray,
+                    g: gray,
+                    b: gray,
+                };
+                assert_eq!(palette.remap(&color), color);
+            }
+
+            // Red and green look much more alike to the viewer than their remapped versions do.
+            let seen_distance = |a: &Color, b: &Color| {
+                let (a, b) = (
+                    palette.simulate_linear(&to_linear(a)),
+                    palette.simulate_linear(&to_linear(b)),
+                );
+                (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f64>().sqrt()
+            };
+            let red = Color { r: 220, g: 50, b: 50 };
+            let green = Color { r: 50, g: 170, b: 50 };
+            assert!(
+                seen_distance(&palette.remap(&red), &palette.remap(&green)) > 1.5 * seen_distance(&red, &green),
+                "{:?}",
+                palette
+            );
+        }
+    }
+}
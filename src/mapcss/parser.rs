@@ -2,7 +2,7 @@ use crate::mapcss::color::Color;
 use crate::mapcss::token::{InputPosition, Token, TokenWithPosition, Tokenizer};
 use crate::mapcss::MapcssError;
 
-use anyhow::{Context, Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
@@ -17,6 +17,8 @@ pub enum ObjectType {
     Node,
     Way,
     Area,
+    // Declares a setting that can be turned on or off (see `mapcss::settings`).
+    Setting,
 }
 
 impl fmt::Display for ObjectType {
@@ -28,11 +30,31 @@ impl fmt::Display for ObjectType {
             ObjectType::Node => "node",
             ObjectType::Way => "way",
             ObjectType::Area => "area",
+            ObjectType::Setting => "setting",
         };
         write!(f, "{}", object_type)
     }
 }
 
+/// The pseudo-classes that the styler knows about. The other ones are parsed but never restrict a selector.
+#[derive(Debug)]
+pub enum PseudoClass {
+    /// A way that is drawn as a ring: either its ends meet or it's an area.
+    Closed,
+    /// A way or a multipolygon that is filled (see `OsmArea::is_area`).
+    Area,
+}
+
+impl fmt::Display for PseudoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pseudo_class = match *self {
+            PseudoClass::Closed => "closed",
+            PseudoClass::Area => "area",
+        };
+        write!(f, ":{}", pseudo_class)
+    }
+}
+
 #[derive(Debug)]
 pub enum UnaryTestType {
     Exists,
@@ -128,6 +150,45 @@ pub enum PropertyValue {
     Color(Color),
     Numbers(Vec<f64>),
     WidthDelta(f64),
+    Eval(Expression),
+}
+
+/// A function-style `eval(...)` expression, e.g. `eval(min(times(log(tag("population")), 1.5), 20))`.
+#[derive(Debug)]
+pub enum Expression {
+    Number(f64),
+    String(String),
+    Call { function: String, args: Vec<Expression> },
+}
+
+impl Expression {
+    /// Returns the keys of the tags that are read with `tag(...)` or `has_tag_key(...)`.
+    pub fn tag_names(&self) -> Vec<&str> {
+        match *self {
+            Expression::Call { ref function, ref args } => match (function.as_str(), &args[..]) {
+                ("tag", [Expression::String(tag_name)]) | ("has_tag_key", [Expression::String(tag_name)]) => {
+                    vec![tag_name.as_str()]
+                }
+                _ => args.iter().flat_map(Expression::tag_names).collect(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Expression::Number(ref num) => write!(f, "{}", num),
+            Expression::String(ref s) => write!(f, "\"{}\"", s),
+            Expression::Call { ref function, ref args } => write!(
+                f,
+                "{}({})",
+                function,
+                args.iter().map(fmt_item::<Expression>).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
 }
 
 impl fmt::Display for PropertyValue {
@@ -140,6 +201,7 @@ impl fmt::Display for PropertyValue {
                 write!(f, "{}", nums.iter().map(fmt_item::<f64>).collect::<Vec<_>>().join(","))
             }
             PropertyValue::WidthDelta(ref delta) => write!(f, "eval(prop(\"width\")) + {}", delta),
+            PropertyValue::Eval(ref expr) => write!(f, "eval({})", expr),
         }
     }
 }
@@ -162,6 +224,9 @@ pub struct Selector {
     pub min_zoom: Option<u8>,
     pub max_zoom: Option<u8>,
     pub tests: Vec<Test>,
+    pub pseudo_classes: Vec<PseudoClass>,
+    // The settings that have to be on for the selector to match anything (`[setting("pistes")]`).
+    pub settings: Vec<String>,
     pub layer_id: Option<String>,
 }
 
@@ -185,11 +250,21 @@ impl fmt::Display for Selector {
         };
         write!(
             f,
-            "{}{}{}{}{}",
+            "{}{}{}{}{}{}{}",
             self.object_type,
             if formatted_zoom_range.is_empty() { "" } else { "|z" },
             formatted_zoom_range,
             self.tests.iter().map(fmt_item::<Test>).collect::<Vec<_>>().join(""),
+            self.settings
+                .iter()
+                .map(|setting| format!("[setting(\"{}\")]", setting))
+                .collect::<Vec<_>>()
+                .join(""),
+            self.pseudo_classes
+                .iter()
+                .map(fmt_item::<PseudoClass>)
+                .collect::<Vec<_>>()
+                .join(""),
             formatted_layer_id
         )
     }
@@ -339,6 +414,8 @@ impl<'a> Parser<'a> {
                     min_zoom: None,
                     max_zoom: None,
                     tests: Vec::new(),
+                    pseudo_classes: Vec::new(),
+                    settings: Vec::new(),
                     layer_id: None,
                 }
             }
@@ -360,13 +437,15 @@ impl<'a> Parser<'a> {
                     selector.min_zoom = min_zoom;
                     selector.max_zoom = max_zoom;
                 }
-                Token::LeftBracket => {
-                    selector.tests.push(self.read_test()?);
-                }
+                Token::LeftBracket => match self.read_test()? {
+                    SelectorTest::Tag(test) => selector.tests.push(test),
+                    SelectorTest::Setting(name) => selector.settings.push(name),
+                },
                 Token::Colon => {
-                    // This is a pseudo-class. Even though we don't use them,
-                    // we still have to parse them correctly.
-                    self.read_identifier()?;
+                    // The pseudo-classes that we don't know about still have to be parsed correctly.
+                    if let Some(pseudo_class) = id_to_pseudo_class(&self.read_identifier()?) {
+                        selector.pseudo_classes.push(pseudo_class);
+                    }
                 }
                 Token::DoubleColon => {
                     selector.layer_id = Some(self.read_identifier()?);
@@ -383,7 +462,7 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn read_test(&mut self) -> Result<Test> {
+    fn read_test(&mut self) -> Result<SelectorTest> {
         let mut starts_with_bang = false;
 
         let mut current_token = self.read_mandatory_token()?;
@@ -400,6 +479,16 @@ impl<'a> Parser<'a> {
 
         current_token = self.read_mandatory_token()?;
 
+        if let (Token::LeftParen, "setting", false) = (&current_token.token, lhs.as_str(), starts_with_bang) {
+            current_token = self.read_mandatory_token()?;
+            let Token::String(name) = current_token.token else {
+                return self.unexpected_token(&current_token);
+            };
+            self.expect_simple_token(&Token::RightParen)?;
+            self.expect_simple_token(&Token::RightBracket)?;
+            return Ok(SelectorTest::Setting(String::from(name)));
+        }
+
         if let Token::Colon = current_token.token {
             lhs.push(':');
             lhs.push_str(&self.read_identifier()?);
@@ -418,11 +507,11 @@ impl<'a> Parser<'a> {
 
                 self.expect_simple_token(&Token::RightBracket)?;
 
-                return Ok(Test::BinaryStringCompare {
+                return Ok(SelectorTest::Tag(Test::BinaryStringCompare {
                     tag_name: lhs,
                     value: rhs,
                     test_type: binary_op,
-                });
+                }));
             }
 
             if let Some(binary_op) = to_binary_numeric_test_type(&current_token.token) {
@@ -435,11 +524,11 @@ impl<'a> Parser<'a> {
 
                 self.expect_simple_token(&Token::RightBracket)?;
 
-                return Ok(Test::BinaryNumericCompare {
+                return Ok(SelectorTest::Tag(Test::BinaryNumericCompare {
                     tag_name: lhs,
                     value: rhs,
                     test_type: binary_op,
-                });
+                }));
             }
         }
 
@@ -471,10 +560,10 @@ impl<'a> Parser<'a> {
             _ => return self.unexpected_token(&current_token),
         };
 
-        Ok(Test::Unary {
+        Ok(SelectorTest::Tag(Test::Unary {
             tag_name: lhs,
             test_type: unary_test_type,
-        })
+        }))
     }
 
     fn read_properties(&mut self) -> Result<Vec<Property>> {
@@ -543,7 +632,8 @@ impl<'a> Parser<'a> {
         Ok(result)
     }
 
-    // Support the only form of eval() used in Maps.ME: eval(prop("width") + X);
+    // Support the only form of eval() used in Maps.ME, eval(prop("width") + X), and the function-style
+    // expressions (e.g. eval(cond(boolean(tag("capital")), 16, 12))) that don't need any operators.
     fn read_simple_eval(&mut self, position: InputPosition) -> Result<PropertyValue> {
         let mut tokens = Vec::new();
         loop {
@@ -580,10 +670,26 @@ impl<'a> Parser<'a> {
             }
         };
 
-        match width_increment {
-            Some(num) => Ok(PropertyValue::WidthDelta(num)),
-            _ => Err(self.parse_error("Unknown eval(...) form", position)),
+        if let Some(num) = width_increment {
+            return Ok(PropertyValue::WidthDelta(num));
         }
+
+        let mut idx = 0;
+        let expr = match tokens.first() {
+            Some(Token::LeftParen) if tokens.last() == Some(&Token::RightParen) => {
+                let inner_tokens = &tokens[1..tokens.len() - 1];
+                read_expression(inner_tokens, &mut idx).and_then(|expr| {
+                    if idx == inner_tokens.len() {
+                        Ok(expr)
+                    } else {
+                        Err("Unexpected tokens after the expression".to_string())
+                    }
+                })
+            }
+            _ => Err("Unknown eval(...) form".to_string()),
+        };
+        expr.map(PropertyValue::Eval)
+            .map_err(|msg| self.parse_error(msg, position))
     }
 
     fn read_number_list(&mut self, first_num: f64) -> Result<Vec<f64>> {
@@ -622,10 +728,9 @@ impl<'a> Parser<'a> {
     }
 
     fn read_optional_token(&mut self) -> Option<Result<TokenWithPosition<'a>>> {
-        self.tokenizer.next().map(|x| {
-            x.context(format!("Failed to tokenize {}", self.file_name))
-                .map_err(Error::from)
-        })
+        self.tokenizer
+            .next()
+            .map(|x| x.context(format!("Failed to tokenize {}", self.file_name)))
     }
 
     fn expect_simple_token(&mut self, expected: &Token<'static>) -> Result<()> {
@@ -653,6 +758,16 @@ impl<'a> Parser<'a> {
     }
 }
 
+pub fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String)> {
+    let mut result = PathBuf::from(file_path);
+    let file_name = result
+        .file_name()
+        .and_then(|x| x.to_str().map(ToString::to_string))
+        .ok_or_else(|| anyhow!("Failed to extract the file name for {}", file_path))?;
+    result.pop();
+    Ok((result, file_name))
+}
+
 fn read_stylesheet(base_path: &Path, file_name: &str) -> Result<String> {
     let file_path = base_path.join(file_name);
     let mut stylesheet_reader = File::open(file_path).context("Failed to open the stylesheet file")?;
@@ -671,10 +786,25 @@ fn id_to_object_type(id: &str) -> Option<ObjectType> {
         "node" => Some(ObjectType::Node),
         "way" | "line" => Some(ObjectType::Way),
         "area" => Some(ObjectType::Area),
+        "setting" => Some(ObjectType::Setting),
         _ => None,
     }
 }
 
+fn id_to_pseudo_class(id: &str) -> Option<PseudoClass> {
+    match id {
+        "closed" => Some(PseudoClass::Closed),
+        "area" => Some(PseudoClass::Area),
+        _ => None,
+    }
+}
+
+// What's between the square brackets of a selector.
+enum SelectorTest {
+    Tag(Test),
+    Setting(String),
+}
+
 struct ConsumedSelector {
     selector: Selector,
     expect_more_selectors: bool,
@@ -698,6 +828,76 @@ fn to_binary_numeric_test_type(token: &Token<'_>) -> Option<BinaryNumericTestTyp
     }
 }
 
+fn read_expression(tokens: &[Token<'_>], idx: &mut usize) -> std::result::Result<Expression, String> {
+    let token = tokens.get(*idx).ok_or("Unexpected end of the expression")?;
+    *idx += 1;
+    match *token {
+        Token::Number(num) => Ok(Expression::Number(num)),
+        Token::String(s) => Ok(Expression::String(s.to_string())),
+        Token::Identifier(function) => {
+            if tokens.get(*idx) != Some(&Token::LeftParen) {
+                return Err(format!("Expected '(' after {}", function));
+            }
+            *idx += 1;
+
+            let mut args = Vec::new();
+            if tokens.get(*idx) == Some(&Token::RightParen) {
+                *idx += 1;
+            } else {
+                loop {
+                    args.push(read_expression(tokens, idx)?);
+                    let token = tokens.get(*idx);
+                    *idx += 1;
+                    match token {
+                        Some(Token::Comma) => {}
+                        Some(Token::RightParen) => break,
+                        _ => return Err(format!("Expected ',' or ')' in the arguments of {}", function)),
+                    }
+                }
+            }
+
+            let arity = EVAL_FUNCTIONS
+                .iter()
+                .find(|(name, _)| *name == function)
+                .map(|(_, arity)| *arity)
+                .ok_or_else(|| format!("Unknown eval(...) function: {}", function))?;
+            let arity_matches = match arity {
+                Some(arity) => args.len() == arity,
+                None => !args.is_empty(),
+            };
+            if !arity_matches {
+                return Err(format!("Wrong number of arguments for {}", function));
+            }
+            if (function == "tag" || function == "has_tag_key") && !matches!(args[0], Expression::String(_)) {
+                return Err(format!("Expected a tag name in quotes for {}", function));
+            }
+
+            Ok(Expression::Call {
+                function: function.to_string(),
+                args,
+            })
+        }
+        ref token => Err(format!("Unexpected token in the expression: '{}'", token)),
+    }
+}
+
+// The supported eval(...) functions with the number of their arguments (None if it's any positive number).
+const EVAL_FUNCTIONS: &[(&str, Option<usize>)] = &[
+    ("tag", Some(1)),
+    ("has_tag_key", Some(1)),
+    ("num", Some(1)),
+    ("boolean", Some(1)),
+    ("cond", Some(3)),
+    ("plus", None),
+    ("minus", Some(2)),
+    ("times", None),
+    ("divided_by", Some(2)),
+    ("min", None),
+    ("max", None),
+    ("log", Some(1)),
+    ("sqrt", Some(1)),
+];
+
 fn fmt_item<T: fmt::Display>(item: &T) -> String {
     format!("{}", item)
 }
@@ -0,0 +1,89 @@
+use crate::geodata::reader::OsmEntity;
+use crate::mapcss::styler::{compare_styles, Style, StyledArea};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Decides which of the overlapping areas with the same `layer` and `z-index` is drawn on top, e.g. so that
+/// the water is never hidden under a residential area that was imported as a larger polygon. Without it, the
+/// area with the larger ID always wins, which looks random on the map.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AreaPriorities {
+    /// From the bottom to the top. The areas that have none of these tags are drawn below all the listed ones.
+    pub tags: Vec<PriorityTag>,
+}
+
+/// A tag with a given value (`landuse=forest`) or with any value (`natural`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PriorityTag {
+    pub tag_name: String,
+    pub value: Option<String>,
+}
+
+impl FromStr for AreaPriorities {
+    type Err = anyhow::Error;
+
+    /// Parses a comma-separated list of tags.
+    /// # Examples
+    /// ```
+    /// use renderer::mapcss::priority::AreaPriorities;
+    /// let priorities = "landuse=farmland, landuse=residential, natural".parse::<AreaPriorities>().unwrap();
+    /// assert_eq!(priorities.tags[1].value.as_deref(), Some("residential"));
+    /// assert_eq!(priorities.tags[2].tag_name, "natural");
+    /// assert!("landuse=".parse::<AreaPriorities>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<AreaPriorities> {
+        let tags = s
+            .split(',')
+            .filter(|tag| !tag.trim().is_empty())
+            .map(|tag| {
+                let (tag_name, value) = match tag.split_once('=') {
+                    Some((tag_name, value)) => (tag_name.trim(), Some(value.trim().to_string())),
+                    None => (tag.trim(), None),
+                };
+                if tag_name.is_empty() || value.as_deref() == Some("") {
+                    bail!("Expected TAG or TAG=VALUE, got <{}>", tag);
+                }
+                Ok(PriorityTag {
+                    tag_name: tag_name.to_string(),
+                    value,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(AreaPriorities { tags })
+    }
+}
+
+impl AreaPriorities {
+    /// Reorders the areas that are drawn at the same level by their priority. The areas with the same priority
+    /// keep their order, so the result is still the same for every tile.
+    pub fn sort(&self, styled_areas: &mut [(StyledArea<'_, '_>, Arc<Style>)]) {
+        if self.tags.is_empty() {
+            return;
+        }
+        let same_level = |a: &(StyledArea<'_, '_>, Arc<Style>), b: &(StyledArea<'_, '_>, Arc<Style>)| {
+            compare_styles(&a.1, &b.1, false) == Ordering::Equal
+        };
+        for areas in styled_areas.chunk_by_mut(same_level) {
+            if areas.len() > 1 {
+                areas.sort_by_cached_key(|(area, _)| self.priority(area));
+            }
+        }
+    }
+
+    // The position of the last listed tag that the area has, counting from one.
+    fn priority(&self, area: &StyledArea<'_, '_>) -> usize {
+        let tags = match area {
+            StyledArea::Way(way) => way.tags(),
+            StyledArea::Multipolygon(rel) => rel.tags(),
+        };
+        self.tags
+            .iter()
+            .rposition(|tag| match tags.get_by_key(&tag.tag_name) {
+                Some(value) => tag.value.as_ref().is_none_or(|v| v == value),
+                None => false,
+            })
+            .map_or(0, |idx| idx + 1)
+    }
+}
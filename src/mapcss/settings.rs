@@ -0,0 +1,72 @@
+use crate::mapcss::parser::{ObjectType, PropertyValue, Rule};
+use anyhow::{bail, Result};
+
+/// Turns the settings of the stylesheet into fixed values, so that a group of rules (e.g. the ski pistes) can be
+/// switched on for a map. A setting is declared like in JOSM:
+///
+/// ```text
+/// setting::pistes {
+///     type: boolean;
+///     label: "Ski pistes and lifts";
+///     default: false;
+/// }
+///
+/// way|z12-[piste:type=downhill][setting("pistes")] { ... }
+/// ```
+///
+/// The settings in `enabled` are on, and the other ones are as `default` says. The selectors that test for a setting
+/// that is off are removed (along with the rules that are left without selectors), as are the declarations.
+pub fn apply_settings(rules: &mut Vec<Rule>, enabled: &[String]) -> Result<()> {
+    let declared = declared_settings(rules);
+    if let Some(unknown) = enabled
+        .iter()
+        .find(|name| !declared.iter().any(|(declared, _)| declared == *name))
+    {
+        bail!("The stylesheet doesn't declare the setting {}", unknown);
+    }
+    resolve_settings(rules, |name| {
+        enabled.iter().any(|enabled| enabled == name)
+            || declared.iter().any(|(declared, default)| declared == name && *default)
+    });
+    Ok(())
+}
+
+/// Same as `apply_settings` without turning anything on.
+pub(super) fn apply_default_settings(rules: &mut Vec<Rule>) {
+    let declared = declared_settings(rules);
+    resolve_settings(rules, |name| {
+        declared.iter().any(|(declared, default)| declared == name && *default)
+    });
+}
+
+/// The names of the declared settings with their defaults. The settings that aren't declared are off.
+fn declared_settings(rules: &[Rule]) -> Vec<(String, bool)> {
+    let mut result = Vec::new();
+    for rule in rules {
+        let default = rule
+            .properties
+            .iter()
+            .rev()
+            .find(|p| p.name == "default")
+            .is_some_and(|p| matches!(&p.value, PropertyValue::Identifier(id) if id == "true" || id == "yes"));
+        for selector in &rule.selectors {
+            if let (ObjectType::Setting, Some(name)) = (&selector.object_type, &selector.layer_id) {
+                result.push((name.clone(), default));
+            }
+        }
+    }
+    result
+}
+
+fn resolve_settings(rules: &mut Vec<Rule>, is_on: impl Fn(&str) -> bool) {
+    rules.retain_mut(|rule| {
+        rule.selectors.retain(|selector| {
+            !matches!(selector.object_type, ObjectType::Setting)
+                && selector.settings.iter().all(|setting| is_on(setting))
+        });
+        for selector in &mut rule.selectors {
+            selector.settings.clear();
+        }
+        !rule.selectors.is_empty()
+    });
+}
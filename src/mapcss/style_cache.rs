@@ -1,10 +1,12 @@
 use crate::geodata::reader::OsmEntity;
+use crate::mapcss::parser::PropertyValue;
 use crate::mapcss::parser::Rule;
 use crate::mapcss::parser::Test;
 use crate::mapcss::parser::UnaryTestType;
 use crate::mapcss::styler::CacheableEntity;
 use crate::mapcss::styler::Style;
 use std::collections::HashMap;
+use std::mem;
 use std::sync::Arc;
 
 #[derive(Hash, Eq, PartialEq)]
@@ -17,6 +19,9 @@ struct StyleCacheKey {
 pub struct StyleCache {
     cache: HashMap<StyleCacheKey, Vec<Arc<Style>>>,
     tag_value_matters: HashMap<String, bool>,
+    // An estimate of the memory taken by the entries, which are all dropped at once when it exceeds the limit.
+    used_bytes: usize,
+    max_bytes: Option<usize>,
 }
 
 impl StyleCache {
@@ -24,6 +29,8 @@ impl StyleCache {
         let mut tag_value_matters = HashMap::new();
 
         tag_value_matters.insert("layer".to_string(), true);
+        // Decides whether a way is an area, even if no selector tests it.
+        tag_value_matters.insert("area".to_string(), true);
 
         for r in rules.iter() {
             for sel in r.selectors.iter() {
@@ -43,11 +50,20 @@ impl StyleCache {
                     *tag_value_matters.entry(tag_name.clone()).or_default() |= value_matters;
                 }
             }
+            for prop in r.properties.iter() {
+                if let PropertyValue::Eval(ref expr) = prop.value {
+                    for tag_name in expr.tag_names() {
+                        tag_value_matters.insert(tag_name.to_string(), true);
+                    }
+                }
+            }
         }
 
         StyleCache {
             cache: HashMap::default(),
             tag_value_matters,
+            used_bytes: 0,
+            max_bytes: None,
         }
     }
 
@@ -62,7 +78,36 @@ impl StyleCache {
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
-        self.cache.insert(self.to_cache_key(entity, zoom), styles);
+        let key = self.to_cache_key(entity, zoom);
+        let tag_count = key.tags.len();
+        let new_bytes = entry_bytes(tag_count, styles.len());
+        if self
+            .max_bytes
+            .is_some_and(|max_bytes| self.used_bytes + new_bytes > max_bytes)
+        {
+            self.cache.clear();
+            self.used_bytes = 0;
+        }
+        if let Some(old_styles) = self.cache.insert(key, styles) {
+            self.used_bytes -= entry_bytes(tag_count, old_styles.len());
+        }
+        self.used_bytes += new_bytes;
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        if max_bytes.is_some_and(|max_bytes| self.used_bytes > max_bytes) {
+            self.cache.clear();
+            self.used_bytes = 0;
+        }
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
     }
 
     fn to_cache_key<'e, E>(&self, entity: &E, zoom: u8) -> StyleCacheKey
@@ -70,7 +115,7 @@ impl StyleCache {
         E: CacheableEntity + OsmEntity<'e>,
     {
         let mut tags = Vec::new();
-        for (k, v) in entity.tags().iter() {
+        for (k, v) in entity.tags().iter_with_offsets() {
             if let Some(value_matters) = self.tag_value_matters.get(k.str) {
                 tags.push(k.offset);
                 if *value_matters {
@@ -86,3 +131,10 @@ impl StyleCache {
         }
     }
 }
+
+fn entry_bytes(tag_count: usize, style_count: usize) -> usize {
+    mem::size_of::<StyleCacheKey>()
+        + tag_count * mem::size_of::<usize>()
+        + mem::size_of::<Vec<Arc<Style>>>()
+        + style_count * (mem::size_of::<Arc<Style>>() + mem::size_of::<Style>())
+}
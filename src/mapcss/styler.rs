@@ -1,18 +1,78 @@
 use crate::mapcss::color::{from_color_name, Color};
+use crate::mapcss::palette::Palette;
 use crate::mapcss::parser::*;
+use crate::mapcss::settings::apply_default_settings;
 use crate::mapcss::style_cache::StyleCache;
 
-use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Way};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Tags, Way};
 use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
 use std::cmp::Ordering;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::thread;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum LineCap {
     Butt,
     Round,
     Square,
+    // Narrows down to a point, half the line width away from the end.
+    Triangle,
+    // A triangle wider than the line, only drawn at the ends of the whole line (the dashes get butt caps).
+    Arrow,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TickSide {
+    Left,
+    Right,
+    Both,
+}
+
+/// Short lines across a stroke, on one side of it or on both, e.g. on the inner side of a wall or on the lower
+/// side of a cliff. The sides are as seen when looking along the way.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ticks {
+    // The distance between the ticks along the line.
+    pub spacing: f64,
+    pub length: f64,
+    // The width of the line itself if not given.
+    pub width: Option<f64>,
+    pub side: TickSide,
+}
+
+/// An arc of the circle around a node, e.g. a sector of a light, and the sector of the disc under it.
+/// The bearings are in degrees, clockwise from the north, and the arc goes clockwise from the first one
+/// to the second one. Without them, it's the whole circle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircleArc {
+    pub radius: f64,
+    pub bearings: Option<(f64, f64)>,
+    pub width: f64,
+    pub color: Option<Color>,
+    pub opacity: f64,
+    pub fill_color: Option<Color>,
+    pub fill_opacity: f64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SymbolShape {
+    Circle,
+    Square,
+}
+
+/// A shape that is drawn instead of an icon (like in JOSM), e.g. a dot for a small POI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub shape: SymbolShape,
+    // The diameter of the circle or the side of the square, without the stroke.
+    pub size: f64,
+    pub fill_color: Option<Color>,
+    pub fill_opacity: f64,
+    pub stroke_color: Option<Color>,
+    pub stroke_width: f64,
+    pub stroke_opacity: f64,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -21,10 +81,39 @@ pub enum TextPosition {
     Line,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum IconRotation {
+    // Clockwise, in degrees.
+    Angle(f64),
+    // Along the way that the node belongs to (`icon-rotation: way`).
+    Way,
+    // The value of a tag, either in degrees or as a compass direction like `NE` (e.g. `icon-rotation: direction`).
+    Tag(String),
+}
+
+/// A short hash of everything that the styles depend on. The rules are hashed in their canonical form,
+/// so that the imported files are included and the formatting of the stylesheet doesn't matter.
+pub fn get_style_version(rules: &[Rule], style_type: &StyleType, font_size_multiplier: Option<f64>) -> String {
+    let mut hasher = Sha256::new();
+    for rule in rules {
+        hasher.update(rule.to_string());
+    }
+    hasher.update(match style_type {
+        StyleType::Josm => "josm",
+        StyleType::MapsMe => "mapsme",
+    });
+    hasher.update(format!("{:?}", font_size_multiplier));
+    hasher.finalize()[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn is_non_trivial_cap(line_cap: &Option<LineCap>) -> bool {
-    matches!(*line_cap, Some(LineCap::Square) | Some(LineCap::Round))
+    matches!(
+        *line_cap,
+        Some(LineCap::Square) | Some(LineCap::Round) | Some(LineCap::Triangle)
+    )
 }
 
+#[derive(Clone)]
 pub enum StyleType {
     Josm,
     MapsMe,
@@ -33,6 +122,7 @@ pub enum StyleType {
 pub trait StyleableEntity {
     fn default_z_index(&self) -> f64;
     fn matches_object_type(&self, object_type: &ObjectType) -> bool;
+    fn matches_pseudo_class(&self, pseudo_class: &PseudoClass) -> bool;
 }
 
 pub trait CacheableEntity {
@@ -44,6 +134,29 @@ pub struct TextStyle {
     pub text_color: Option<Color>,
     pub text_position: Option<TextPosition>,
     pub font_size: Option<f64>,
+    pub halo: Option<TextHalo>,
+    // The width (in ems) after which the text is wrapped onto the next row (`text-max-width`).
+    pub max_width: Option<f64>,
+    // Draw CJK text top to bottom (`text-orientation: vertical`).
+    pub is_vertical: bool,
+    // Only used for the point labels, e.g. the exit numbers of motorway junctions.
+    pub shield: Option<TextShield>,
+}
+
+pub struct TextHalo {
+    pub color: Color,
+    pub radius: f64,
+}
+
+/// A box that the text is drawn in, placed beside the labeled point.
+pub struct TextShield {
+    pub color: Color,
+    pub casing_color: Option<Color>,
+    pub casing_width: f64,
+    pub padding: f64,
+    // The distance between the point and the box. If it's not zero, the box is connected to the point
+    // with a leader line.
+    pub offset: f64,
 }
 
 pub struct Style {
@@ -58,17 +171,47 @@ pub struct Style {
     pub fill_opacity: Option<f64>,
 
     pub width: Option<f64>,
+    // With `width-unit: meters`, all of the lengths of the line (the width, the dashes, the casing and the ticks)
+    // are in meters on the ground instead of pixels, so e.g. a runway is drawn as wide as it is.
+    pub width_in_meters: bool,
     pub dashes: Option<Vec<f64>>,
+    // How far into the dash pattern the line starts, e.g. to put the dots of a second layer between the dashes.
+    pub dashes_offset: Option<f64>,
     pub line_cap: Option<LineCap>,
+    // The caps at the start and at the end of the whole line, if they differ from `line_cap`.
+    pub line_cap_start: Option<LineCap>,
+    pub line_cap_end: Option<LineCap>,
+    // Drawn with the color and the opacity of the stroke.
+    pub ticks: Option<Ticks>,
 
     pub casing_color: Option<Color>,
     pub casing_width: Option<f64>,
     pub casing_dashes: Option<Vec<f64>>,
+    pub casing_dashes_offset: Option<f64>,
     pub casing_line_cap: Option<LineCap>,
 
     pub icon_image: Option<String>,
+    // Only the SVG icons are scaled to these, since the other ones are supposed to be drawn pixel by pixel.
+    pub icon_width: Option<f64>,
+    pub icon_height: Option<f64>,
+    pub icon_rotation: Option<IconRotation>,
+    // Drawn when there's no `icon_image`, and placed just like the icon.
+    pub symbol: Option<Symbol>,
+    // Only drawn for the nodes, below all the labels and icons.
+    pub arc: Option<CircleArc>,
     pub fill_image: Option<String>,
     pub text_style: Option<TextStyle>,
+
+    // The labels with higher priority are placed before (and therefore win the collisions with) the other ones.
+    pub text_priority: Option<f64>,
+
+    // Areas whose projected bounding box is smaller than this (in pixels) aren't drawn at all.
+    pub min_size: Option<f64>,
+
+    // The named passes (see `RenderOrder`) that the area is drawn in. The casing goes to the same pass
+    // as the rest of the area unless `casing-render-pass` is set.
+    pub render_pass: Option<String>,
+    pub casing_render_pass: Option<String>,
 }
 
 pub struct Styler {
@@ -77,7 +220,8 @@ pub struct Styler {
 
     casing_width_multiplier: f64,
     font_size_multiplier: Option<f64>,
-    rules: Vec<Rule>,
+    rules: Arc<Vec<Rule>>,
+    palette: Option<Palette>,
 
     style_cache: RwLock<StyleCache>,
 }
@@ -91,7 +235,9 @@ where
 }
 
 impl Styler {
-    pub fn new(rules: Vec<Rule>, style_type: &StyleType, font_size_multiplier: Option<f64>) -> Styler {
+    pub fn new(mut rules: Vec<Rule>, style_type: &StyleType, font_size_multiplier: Option<f64>) -> Styler {
+        // The settings that weren't applied (see `apply_settings`) are as the stylesheet declares them.
+        apply_default_settings(&mut rules);
         let use_caps_for_dashes = matches!(*style_type, StyleType::Josm);
         let canvas_fill_color = extract_canvas_fill_color(&rules, style_type);
 
@@ -107,11 +253,51 @@ impl Styler {
             canvas_fill_color,
             casing_width_multiplier,
             font_size_multiplier,
-            rules,
+            rules: Arc::new(rules),
+            palette: None,
             style_cache: RwLock::new(style_cache),
         }
     }
 
+    /// Limits the memory that the cached styles take (roughly, in bytes). The cache is emptied when it's full.
+    pub fn set_cache_limit(&self, max_bytes: Option<usize>) {
+        self.style_cache.write().unwrap().set_max_bytes(max_bytes);
+    }
+
+    pub fn cache_usage(&self) -> usize {
+        self.style_cache.read().unwrap().used_bytes()
+    }
+
+    /// Returns a styler with the same rules and cache limit, but an empty cache. The cached styles are only
+    /// valid for the geodata file they were computed for (the cache refers to its strings by their offsets),
+    /// so objects from a different file need a styler of their own.
+    pub fn with_empty_cache(&self) -> Styler {
+        let mut style_cache = StyleCache::new(&self.rules);
+        style_cache.set_max_bytes(self.style_cache.read().unwrap().max_bytes());
+        Styler {
+            canvas_fill_color: self.canvas_fill_color.clone(),
+            use_caps_for_dashes: self.use_caps_for_dashes,
+            casing_width_multiplier: self.casing_width_multiplier,
+            font_size_multiplier: self.font_size_multiplier,
+            rules: Arc::clone(&self.rules),
+            palette: self.palette,
+            style_cache: RwLock::new(style_cache),
+        }
+    }
+
+    /// Returns a styler with the same rules and cache limit (but an empty cache) that remaps all the colors
+    /// of the styles with the palette.
+    pub fn with_palette(&self, palette: Palette) -> Styler {
+        let mut styler = self.with_empty_cache();
+        styler.canvas_fill_color = styler.canvas_fill_color.map(|color| palette.remap(&color));
+        styler.palette = Some(palette);
+        styler
+    }
+
+    pub(super) fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
     pub fn style_entities<'e, 'wp, I, A>(&self, areas: I, zoom: u8, for_labels: bool) -> Vec<(&'wp A, Arc<Style>)>
     where
         A: CacheableEntity + StyleableEntity + OsmEntity<'e>,
@@ -145,14 +331,18 @@ impl Styler {
             let mut styles = Vec::new();
             for (layer, prop_map) in &all_property_maps {
                 if *layer != "*" {
-                    styles.push(Arc::new(property_map_to_style(
+                    let mut style = property_map_to_style(
                         prop_map,
                         base_layer,
                         default_z_index,
                         self.casing_width_multiplier,
                         &self.font_size_multiplier,
                         area,
-                    )))
+                    );
+                    if let Some(palette) = &self.palette {
+                        palette.remap_style(&mut style);
+                    }
+                    styles.push(Arc::new(style))
                 }
             }
 
@@ -174,32 +364,59 @@ impl Styler {
     ) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
         let styled_ways = self.style_entities(ways, zoom, for_labels);
         let styled_multipolygons = self.style_entities(multipolygons, zoom, for_labels);
+        merge_styled_areas(styled_ways, styled_multipolygons, for_labels)
+    }
 
-        let mut mp_iter = styled_multipolygons.into_iter();
-        let mut way_iter = styled_ways.into_iter();
-        let mut poly = mp_iter.next();
-        let mut way = way_iter.next();
-        let mut result = Vec::new();
-        loop {
-            let is_rel_better = {
-                match (&poly, &way) {
-                    (None, None) => break,
-                    (Some(_), None) => true,
-                    (None, Some(_)) => false,
-                    (Some(mp), Some(way)) => compare_styled_entities(mp, way, for_labels) != Ordering::Greater,
-                }
-            };
-            if is_rel_better {
-                let (mp, style) = poly.unwrap();
-                result.push((StyledArea::Multipolygon(mp), style));
-                poly = mp_iter.next();
-            } else {
-                let (w, style) = way.unwrap();
-                result.push((StyledArea::Way(w), style));
-                way = way_iter.next();
-            }
+    /// The same as `style_areas`, but the entities are split between up to `threads` threads.
+    /// The result doesn't depend on the number of threads.
+    pub fn style_areas_in_parallel<'a, 'wr>(
+        &self,
+        ways: &'wr [Way<'a>],
+        multipolygons: &'wr [Multipolygon<'a>],
+        zoom: u8,
+        for_labels: bool,
+        threads: usize,
+    ) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+        if threads <= 1 {
+            return self.style_areas(ways.iter(), multipolygons.iter(), zoom, for_labels);
         }
-        result
+        let (styled_ways, styled_multipolygons) = thread::scope(|s| {
+            let styled_multipolygons =
+                s.spawn(|| self.style_entities_in_parallel(multipolygons, zoom, for_labels, threads / 2));
+            let styled_ways = self.style_entities_in_parallel(ways, zoom, for_labels, threads - threads / 2);
+            (styled_ways, styled_multipolygons.join().unwrap())
+        });
+        merge_styled_areas(styled_ways, styled_multipolygons, for_labels)
+    }
+
+    fn style_entities_in_parallel<'e, 'wp, A>(
+        &self,
+        areas: &'wp [A],
+        zoom: u8,
+        for_labels: bool,
+        threads: usize,
+    ) -> Vec<(&'wp A, Arc<Style>)>
+    where
+        A: CacheableEntity + StyleableEntity + OsmEntity<'e> + Sync,
+    {
+        if threads <= 1 {
+            return self.style_entities(areas.iter(), zoom, for_labels);
+        }
+        let chunk_size = areas.len().div_ceil(threads).max(1);
+        let mut styled_areas = thread::scope(|s| {
+            let handles = areas
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(move || self.style_entities(chunk.iter(), zoom, for_labels)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // Every chunk is sorted already, and the sort is stable, so the areas that compare as equal
+        // stay in the same order as if they were styled all at once.
+        styled_areas.sort_by(|a, b| compare_styled_entities(a, b, for_labels));
+        styled_areas
     }
 
     fn style_area<'r, 'e, A>(&'r self, area: &A, zoom: u8) -> LayerToPropertyMap<'r>
@@ -208,13 +425,13 @@ impl Styler {
     {
         let mut result: LayerToPropertyMap<'r> = IndexMap::new();
 
-        for rule in &self.rules {
+        for rule in self.rules.iter() {
             for sel in rule.selectors.iter().filter(|x| area_matches(area, x, zoom)) {
                 let layer_id = get_layer_id(sel);
 
                 let update_layer = |layer: &mut PropertyMap<'r>| {
                     for prop in &rule.properties {
-                        layer.insert(prop.name.clone(), &prop.value);
+                        layer.insert(&prop.name, &prop.value);
                     }
                 };
 
@@ -242,7 +459,38 @@ impl Styler {
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::float_cmp))]
+fn merge_styled_areas<'a, 'wr>(
+    styled_ways: Vec<(&'wr Way<'a>, Arc<Style>)>,
+    styled_multipolygons: Vec<(&'wr Multipolygon<'a>, Arc<Style>)>,
+    for_labels: bool,
+) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+    let mut mp_iter = styled_multipolygons.into_iter();
+    let mut way_iter = styled_ways.into_iter();
+    let mut poly = mp_iter.next();
+    let mut way = way_iter.next();
+    let mut result = Vec::new();
+    loop {
+        let is_rel_better = {
+            match (&poly, &way) {
+                (None, None) => break,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(mp), Some(way)) => compare_styled_entities(mp, way, for_labels) != Ordering::Greater,
+            }
+        };
+        if is_rel_better {
+            let (mp, style) = poly.unwrap();
+            result.push((StyledArea::Multipolygon(mp), style));
+            poly = mp_iter.next();
+        } else {
+            let (w, style) = way.unwrap();
+            result.push((StyledArea::Way(w), style));
+            way = way_iter.next();
+        }
+    }
+    result
+}
+
 fn compare_styled_entities<'a, E1, E2>(
     (a, a_style): &(&E1, Arc<Style>),
     (b, b_style): &(&E2, Arc<Style>),
@@ -252,6 +500,12 @@ where
     E1: OsmEntity<'a>,
     E2: OsmEntity<'a>,
 {
+    compare_styles(a_style, b_style, for_labels).then_with(|| a.global_id().cmp(&b.global_id()))
+}
+
+// The order of the styles alone, without telling apart the entities that are drawn at the same level.
+#[allow(clippy::float_cmp)]
+pub(super) fn compare_styles(a_style: &Style, b_style: &Style, for_labels: bool) -> Ordering {
     let get_layer = |s: &Style| s.layer.unwrap_or(0);
 
     let (a_layer, b_layer) = (get_layer(a_style), get_layer(b_style));
@@ -268,11 +522,11 @@ where
         return a_style.z_index.partial_cmp(&b_style.z_index).unwrap();
     }
 
-    a.global_id().cmp(&b.global_id())
+    Ordering::Equal
 }
 
 type LayerToPropertyMap<'r> = IndexMap<&'r str, PropertyMap<'r>>;
-type PropertyMap<'r> = IndexMap<String, &'r PropertyValue>;
+type PropertyMap<'r> = IndexMap<&'r str, &'r PropertyValue>;
 
 fn property_map_to_style<'r, 'e, E>(
     current_layer_map: &'r PropertyMap<'r>,
@@ -314,6 +568,8 @@ where
 
     let get_num = |prop_map: &'r PropertyMap<'r>, prop_name| match prop_map.get(prop_name) {
         Some(&PropertyValue::Numbers(nums)) if nums.len() == 1 => Some(nums[0]),
+        // A missing tag (or e.g. a non-numeric population) simply leaves the property unset.
+        Some(&PropertyValue::Eval(expr)) => evaluate(expr, &osm_entity.tags()).filter(|x| x.is_finite()),
         _ => {
             warn(prop_map, prop_name, "expected a number");
             None
@@ -341,6 +597,8 @@ where
         Some("none") | Some("butt") => Some(LineCap::Butt),
         Some("round") => Some(LineCap::Round),
         Some("square") => Some(LineCap::Square),
+        Some("triangle") => Some(LineCap::Triangle),
+        Some("arrow") => Some(LineCap::Arrow),
         _ => {
             warn(current_layer_map, prop_name, "unknown line cap value");
             None
@@ -356,6 +614,40 @@ where
         }
     };
 
+    let get_icon_rotation = |prop_name| match current_layer_map.get(prop_name) {
+        Some(&PropertyValue::Identifier(id)) if id == "way" || id == "way-direction" => Some(IconRotation::Way),
+        Some(&PropertyValue::Identifier(id)) => Some(IconRotation::Tag(id.clone())),
+        _ => get_num(current_layer_map, prop_name).map(IconRotation::Angle),
+    };
+
+    let get_tick_side = |prop_name| match get_id(prop_name) {
+        Some("left") => Some(TickSide::Left),
+        Some("right") => Some(TickSide::Right),
+        Some("both") => Some(TickSide::Both),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown tick side");
+            None
+        }
+    };
+
+    let get_symbol_shape = |prop_name| match get_id(prop_name) {
+        Some("circle") => Some(SymbolShape::Circle),
+        Some("square") => Some(SymbolShape::Square),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown symbol shape");
+            None
+        }
+    };
+
+    let get_width_unit = |prop_name| match get_id(prop_name) {
+        Some("px") | Some("pixels") => Some(false),
+        Some("m") | Some("meters") => Some(true),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown width unit");
+            None
+        }
+    };
+
     let get_dashes = |prop_name| match current_layer_map.get(prop_name) {
         Some(&PropertyValue::Numbers(nums)) => Some(nums.clone()),
         _ => {
@@ -395,11 +687,42 @@ where
 
     let font_size = get_num(current_layer_map, "font-size").map(|x| x * font_size_multiplier.unwrap_or(1.0));
 
-    let text_style = text.map(|text| TextStyle {
-        text,
-        text_color: get_color("text-color"),
-        text_position: get_text_position("text-position"),
-        font_size,
+    let text_style = text.map(|text| {
+        let text_color = get_color("text-color");
+        let halo_is_auto = matches!(get_id("text-halo-auto"), Some("true") | Some("yes"));
+        let halo_radius = get_num(current_layer_map, "text-halo-radius").or(if halo_is_auto {
+            Some(DEFAULT_AUTO_HALO_RADIUS)
+        } else {
+            None
+        });
+        let halo = halo_radius.filter(|r| *r > 0.0).map(|radius| TextHalo {
+            color: pick_halo_color(
+                text_color.as_ref().unwrap_or(&DEFAULT_TEXT_COLOR),
+                get_color("text-halo-color"),
+                halo_is_auto,
+            ),
+            radius,
+        });
+        TextStyle {
+            text,
+            text_color,
+            text_position: get_text_position("text-position"),
+            font_size,
+            halo,
+            max_width: get_num(current_layer_map, "text-max-width"),
+            is_vertical: matches!(get_id("text-orientation"), Some("vertical")),
+            shield: get_color("shield-color").map(|color| {
+                let casing_color = get_color("shield-casing-color");
+                let default_casing_width = if casing_color.is_some() { 1.0 } else { 0.0 };
+                TextShield {
+                    color,
+                    casing_color,
+                    casing_width: get_num(current_layer_map, "shield-casing-width").unwrap_or(default_casing_width),
+                    padding: get_num(current_layer_map, "shield-padding").unwrap_or(DEFAULT_SHIELD_PADDING),
+                    offset: get_num(current_layer_map, "shield-offset").unwrap_or_default(),
+                }
+            }),
+        }
     });
 
     Style {
@@ -414,20 +737,162 @@ where
         fill_opacity: get_num(current_layer_map, "fill-opacity"),
 
         width,
+        width_in_meters: get_width_unit("width-unit").unwrap_or(false),
         dashes: get_dashes("dashes"),
+        dashes_offset: get_num(current_layer_map, "dashes-offset"),
         line_cap: get_line_cap("linecap"),
+        line_cap_start: get_line_cap("linecap-start"),
+        line_cap_end: get_line_cap("linecap-end"),
+        ticks: get_num(current_layer_map, "tick-spacing")
+            .filter(|spacing| *spacing > 0.0)
+            .map(|spacing| Ticks {
+                spacing,
+                length: get_num(current_layer_map, "tick-length").unwrap_or(DEFAULT_TICK_LENGTH),
+                width: get_num(current_layer_map, "tick-width"),
+                side: get_tick_side("tick-side").unwrap_or(TickSide::Right),
+            }),
 
         casing_color: get_color("casing-color"),
         casing_width: full_casing_width,
         casing_dashes: get_dashes("casing-dashes"),
+        casing_dashes_offset: get_num(current_layer_map, "casing-dashes-offset"),
         casing_line_cap: get_line_cap("casing-linecap"),
 
         icon_image: get_string("icon-image"),
+        icon_width: get_num(current_layer_map, "icon-width"),
+        icon_height: get_num(current_layer_map, "icon-height"),
+        icon_rotation: get_icon_rotation("icon-rotation"),
+        symbol: get_symbol_shape("symbol-shape").map(|shape| {
+            let (fill_color, stroke_color) = (get_color("symbol-fill-color"), get_color("symbol-stroke-color"));
+            Symbol {
+                shape,
+                size: get_num(current_layer_map, "symbol-size").unwrap_or(DEFAULT_SYMBOL_SIZE),
+                // Like in JOSM, a symbol without any colors is red.
+                fill_color: match (fill_color, &stroke_color) {
+                    (None, None) => Some(DEFAULT_SYMBOL_COLOR),
+                    (fill_color, _) => fill_color,
+                },
+                fill_opacity: get_num(current_layer_map, "symbol-fill-opacity").unwrap_or(1.0),
+                stroke_color,
+                stroke_width: get_num(current_layer_map, "symbol-stroke-width").unwrap_or(1.0),
+                stroke_opacity: get_num(current_layer_map, "symbol-stroke-opacity").unwrap_or(1.0),
+            }
+        }),
+        arc: get_num(current_layer_map, "arc-radius")
+            .filter(|radius| *radius > 0.0)
+            .map(|radius| (radius, get_color("arc-color"), get_color("arc-fill-color")))
+            .filter(|(_, color, fill_color)| color.is_some() || fill_color.is_some())
+            .map(|(radius, color, fill_color)| CircleArc {
+                radius,
+                bearings: get_num(current_layer_map, "arc-start").zip(get_num(current_layer_map, "arc-end")),
+                width: get_num(current_layer_map, "arc-width").unwrap_or(1.0),
+                color,
+                opacity: get_num(current_layer_map, "arc-opacity").unwrap_or(1.0),
+                fill_color,
+                fill_opacity: get_num(current_layer_map, "arc-fill-opacity").unwrap_or(1.0),
+            }),
         fill_image: get_string("fill-image"),
         text_style,
+
+        text_priority: get_num(current_layer_map, "text-priority"),
+
+        min_size: get_num(current_layer_map, "min-size"),
+
+        render_pass: get_string("render-pass"),
+        casing_render_pass: get_string("casing-render-pass"),
+    }
+}
+
+// Without a halo color in the stylesheet, the halo is either black or white, whichever stands out more
+// against the text. With `text-halo-auto`, the stylesheet color is also replaced if it's too close to
+// the text color, since such a halo only makes the text look blurry.
+fn pick_halo_color(text_color: &Color, halo_color: Option<Color>, halo_is_auto: bool) -> Color {
+    match halo_color {
+        Some(halo_color) if !halo_is_auto || text_color.contrast_ratio(&halo_color) >= MIN_HALO_CONTRAST => halo_color,
+        _ => {
+            let (black, white) = (Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 255, b: 255 });
+            if text_color.contrast_ratio(&white) >= text_color.contrast_ratio(&black) {
+                white
+            } else {
+                black
+            }
+        }
     }
 }
 
+/// All the properties that `property_map_to_style` (and the canvas rules) use; the other ones are ignored.
+pub const SUPPORTED_PROPERTIES: &[&str] = &[
+    "arc-color",
+    "arc-end",
+    "arc-fill-color",
+    "arc-fill-opacity",
+    "arc-opacity",
+    "arc-radius",
+    "arc-start",
+    "arc-width",
+    "background-color",
+    "casing-color",
+    "casing-dashes",
+    "casing-dashes-offset",
+    "casing-linecap",
+    "casing-render-pass",
+    "casing-width",
+    "color",
+    "dashes",
+    "dashes-offset",
+    "fill-color",
+    "fill-image",
+    "fill-opacity",
+    "fill-position",
+    "font-size",
+    "icon-height",
+    "icon-image",
+    "icon-rotation",
+    "icon-width",
+    "linecap",
+    "linecap-end",
+    "linecap-start",
+    "min-size",
+    "opacity",
+    "render-pass",
+    "shield-casing-color",
+    "shield-casing-width",
+    "shield-color",
+    "shield-offset",
+    "shield-padding",
+    "symbol-fill-color",
+    "symbol-fill-opacity",
+    "symbol-shape",
+    "symbol-size",
+    "symbol-stroke-color",
+    "symbol-stroke-opacity",
+    "symbol-stroke-width",
+    "text",
+    "text-color",
+    "text-halo-auto",
+    "text-halo-color",
+    "text-halo-radius",
+    "text-max-width",
+    "text-orientation",
+    "text-position",
+    "text-priority",
+    "tick-length",
+    "tick-side",
+    "tick-spacing",
+    "tick-width",
+    "width",
+    "width-unit",
+    "z-index",
+];
+
+pub const DEFAULT_TEXT_COLOR: Color = Color { r: 0, g: 0, b: 0 };
+const DEFAULT_AUTO_HALO_RADIUS: f64 = 1.0;
+const MIN_HALO_CONTRAST: f64 = 3.0;
+const DEFAULT_SHIELD_PADDING: f64 = 2.0;
+const DEFAULT_TICK_LENGTH: f64 = 3.0;
+const DEFAULT_SYMBOL_SIZE: f64 = 10.0;
+const DEFAULT_SYMBOL_COLOR: Color = Color { r: 255, g: 0, b: 0 };
+
 fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<Color> {
     let color_prop = match *style_type {
         StyleType::Josm => "fill-color",
@@ -447,14 +912,7 @@ fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<C
     None
 }
 
-fn matches_by_tags<'e, E>(entity: &E, test: &Test) -> bool
-where
-    E: OsmEntity<'e>,
-{
-    let tags = entity.tags();
-
-    let is_true_value = |x| x == "yes" || x == "true" || x == "1";
-
+fn matches_by_tags(tags: &Tags<'_>, test: &Test) -> bool {
     match *test {
         Test::Unary {
             ref tag_name,
@@ -498,7 +956,62 @@ where
     }
 }
 
-fn area_matches<'e, A>(area: &A, selector: &Selector, zoom: u8) -> bool
+fn is_true_value(x: &str) -> bool {
+    x == "yes" || x == "true" || x == "1"
+}
+
+fn evaluate(expr: &Expression, tags: &Tags<'_>) -> Option<f64> {
+    let (function, args) = match *expr {
+        Expression::Number(num) => return Some(num),
+        Expression::String(ref s) => return s.parse().ok(),
+        Expression::Call { ref function, ref args } => (function.as_str(), args),
+    };
+    let arg = |idx: usize| evaluate(&args[idx], tags);
+    // The parser makes sure that the argument of tag(...) and has_tag_key(...) is a string.
+    let tag_value = |args: &[Expression]| match args[0] {
+        Expression::String(ref tag_name) => tags.get_by_key(tag_name),
+        _ => None,
+    };
+    let fold = |f: fn(f64, f64) -> f64| {
+        args.iter()
+            .map(|arg| evaluate(arg, tags))
+            .reduce(|acc, x| Some(f(acc?, x?)))
+            .flatten()
+    };
+    let as_num = |b: bool| if b { 1.0 } else { 0.0 };
+
+    match function {
+        "tag" => tag_value(args).and_then(|x| x.trim().parse().ok()),
+        "has_tag_key" => Some(as_num(tag_value(args).is_some())),
+        "num" => arg(0),
+        "boolean" => Some(as_num(match args[0] {
+            Expression::Call {
+                ref function,
+                args: ref tag_args,
+            } if function == "tag" => tag_value(tag_args).is_some_and(is_true_value),
+            Expression::String(ref s) => is_true_value(s),
+            _ => arg(0).is_some_and(|x| x != 0.0),
+        })),
+        "cond" => {
+            if arg(0).is_some_and(|x| x != 0.0) {
+                arg(1)
+            } else {
+                arg(2)
+            }
+        }
+        "plus" => fold(|a, b| a + b),
+        "minus" => Some(arg(0)? - arg(1)?),
+        "times" => fold(|a, b| a * b),
+        "divided_by" => Some(arg(0)? / arg(1)?),
+        "min" => fold(f64::min),
+        "max" => fold(f64::max),
+        "log" => arg(0).map(f64::ln),
+        "sqrt" => arg(0).map(f64::sqrt),
+        _ => None,
+    }
+}
+
+pub(super) fn area_matches<'e, A>(area: &A, selector: &Selector, zoom: u8) -> bool
 where
     A: StyleableEntity + OsmEntity<'e>,
 {
@@ -516,10 +1029,14 @@ where
 
     let good_object_type = area.matches_object_type(&selector.object_type);
 
-    good_object_type && selector.tests.iter().all(|x| matches_by_tags(area, x))
+    if !good_object_type || !selector.pseudo_classes.iter().all(|x| area.matches_pseudo_class(x)) {
+        return false;
+    }
+    let tags = area.tags();
+    selector.tests.iter().all(|x| matches_by_tags(&tags, x))
 }
 
-fn get_layer_id(selector: &Selector) -> &str {
+pub(super) fn get_layer_id(selector: &Selector) -> &str {
     match selector.layer_id {
         Some(ref id) => id,
         None => BASE_LAYER_NAME,
@@ -536,11 +1053,15 @@ impl<'a> StyleableEntity for Node<'a> {
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
         matches!(*object_type, ObjectType::Node)
     }
+
+    fn matches_pseudo_class(&self, _pseudo_class: &PseudoClass) -> bool {
+        false
+    }
 }
 
 impl<A: OsmArea> StyleableEntity for A {
     fn default_z_index(&self) -> f64 {
-        if self.is_closed() {
+        if self.is_area() {
             1.0
         } else {
             3.0
@@ -550,10 +1071,18 @@ impl<A: OsmArea> StyleableEntity for A {
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
         match *object_type {
             ObjectType::Way => true,
-            ObjectType::Area => self.is_closed(),
+            ObjectType::Area => self.is_area(),
             _ => false,
         }
     }
+
+    fn matches_pseudo_class(&self, pseudo_class: &PseudoClass) -> bool {
+        match *pseudo_class {
+            // The unclosed areas are closed when they're filled.
+            PseudoClass::Closed => self.is_closed() || self.is_area(),
+            PseudoClass::Area => self.is_area(),
+        }
+    }
 }
 
 impl<'a> CacheableEntity for Node<'a> {
@@ -0,0 +1,64 @@
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+
+/// The memory that the caches of the server may take altogether, given in the config as a number of bytes
+/// with an optional `K`, `M` or `G` suffix (e.g. `max-memory = 512M`).
+///
+/// The styles are the only cache that grows with the data, so they get most of the budget. The rest is left
+/// for the icons and the pixel buffers of the rendering threads, which aren't limited but don't grow either.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryBudget {
+    pub max_bytes: usize,
+}
+
+const STYLE_CACHE_SHARE: f64 = 0.75;
+
+impl MemoryBudget {
+    pub fn style_cache_bytes(&self) -> usize {
+        (self.max_bytes as f64 * STYLE_CACHE_SHARE) as usize
+    }
+}
+
+impl FromStr for MemoryBudget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<MemoryBudget> {
+        let s = s.trim();
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((idx, 'K' | 'k')) => (&s[..idx], 1 << 10),
+            Some((idx, 'M' | 'm')) => (&s[..idx], 1 << 20),
+            Some((idx, 'G' | 'g')) => (&s[..idx], 1 << 30),
+            _ => (s, 1),
+        };
+        let number = number
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("Invalid memory size: {}", s))?;
+        match number.checked_mul(multiplier) {
+            Some(max_bytes) if max_bytes > 0 => Ok(MemoryBudget { max_bytes }),
+            _ => bail!("Invalid memory size: {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("1000".parse::<MemoryBudget>().unwrap().max_bytes, 1000);
+        assert_eq!("64K".parse::<MemoryBudget>().unwrap().max_bytes, 64 * 1024);
+        assert_eq!("512M".parse::<MemoryBudget>().unwrap().max_bytes, 512 * 1024 * 1024);
+        assert_eq!("2g".parse::<MemoryBudget>().unwrap().max_bytes, 2 * 1024 * 1024 * 1024);
+        assert_eq!(
+            "4M".parse::<MemoryBudget>().unwrap().style_cache_bytes(),
+            3 * 1024 * 1024
+        );
+
+        assert!("".parse::<MemoryBudget>().is_err());
+        assert!("0".parse::<MemoryBudget>().is_err());
+        assert!("12T".parse::<MemoryBudget>().is_err());
+        assert!("-1M".parse::<MemoryBudget>().is_err());
+    }
+}
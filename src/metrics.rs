@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The upper bounds of the histogram buckets, in seconds. The tiles take from a few milliseconds (the empty ones)
+// to several seconds (the city centers at the low zoom levels).
+const RENDER_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// Getting the entities of a tile is a lookup in the memory-mapped geodata, which is much faster.
+const QUERY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+];
+
+/// The caches that the tiles are looked up in, which are named in the `cache` label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CacheKind {
+    Memory,
+    /// The cache of the configured `type` (e.g. on the disk or in S3).
+    Storage,
+}
+
+impl CacheKind {
+    fn label(self) -> &'static str {
+        match self {
+            CacheKind::Memory => "memory",
+            CacheKind::Storage => "storage",
+        }
+    }
+}
+
+/// What the server has been doing since it started, for Prometheus to scrape from `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    tiles_rendered: AtomicU64,
+    bytes_served: AtomicU64,
+    render_durations: Mutex<BTreeMap<u8, Histogram>>,
+    geodata_query_durations: Mutex<Option<Histogram>>,
+    // By the cache and whether the tile was there.
+    cache_lookups: Mutex<BTreeMap<(CacheKind, bool), u64>>,
+    // By the status code.
+    responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    // One more than the bounds, for the values above all of them.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    // The buckets are cumulative in the text format, and the last one (`+Inf`) is the total count.
+    fn write(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        for (idx, bucket_count) in self.counts.iter().enumerate() {
+            count += bucket_count;
+            let bound = self.bounds.get(idx).map_or_else(|| "+Inf".to_string(), f64::to_string);
+            let _ = writeln!(
+                output,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(output, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(output, "{}_count{} {}", name, labels, count);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Default::default()
+    }
+
+    /// A tile was drawn from the geodata (rather than served from a cache or fetched from upstream).
+    pub fn tile_rendered(&self, zoom: u8, duration: Duration) {
+        self.tiles_rendered.fetch_add(1, Ordering::Relaxed);
+        self.render_durations
+            .lock()
+            .unwrap()
+            .entry(zoom)
+            .or_insert_with(|| Histogram::new(RENDER_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn geodata_queried(&self, duration: Duration) {
+        self.geodata_query_durations
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Histogram::new(QUERY_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn cache_lookup(&self, cache: CacheKind, is_hit: bool) {
+        *self.cache_lookups.lock().unwrap().entry((cache, is_hit)).or_default() += 1;
+    }
+
+    pub fn response_sent(&self, status: u16, body_bytes: usize) {
+        self.bytes_served.fetch_add(body_bytes as u64, Ordering::Relaxed);
+        *self.responses.lock().unwrap().entry(status).or_default() += 1;
+    }
+
+    /// All of the metrics in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        let header = |output: &mut String, name: &str, metric_type: &str, help: &str| {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+        };
+
+        let name = "renderer_tiles_rendered_total";
+        header(&mut output, name, "counter", "The tiles drawn from the geodata.");
+        let _ = writeln!(output, "{} {}", name, self.tiles_rendered.load(Ordering::Relaxed));
+
+        let name = "renderer_render_duration_seconds";
+        header(
+            &mut output,
+            name,
+            "histogram",
+            "How long the tiles took to draw, by zoom level.",
+        );
+        for (zoom, histogram) in self.render_durations.lock().unwrap().iter() {
+            histogram.write(&mut output, name, &format!("zoom=\"{}\"", zoom));
+        }
+
+        let name = "renderer_geodata_query_duration_seconds";
+        header(
+            &mut output,
+            name,
+            "histogram",
+            "How long it took to get the entities of a tile.",
+        );
+        if let Some(histogram) = &*self.geodata_query_durations.lock().unwrap() {
+            histogram.write(&mut output, name, "");
+        }
+
+        let name = "renderer_tile_cache_lookups_total";
+        header(
+            &mut output,
+            name,
+            "counter",
+            "The tiles looked up in the caches, by the result.",
+        );
+        for ((cache, is_hit), count) in self.cache_lookups.lock().unwrap().iter() {
+            let result = if *is_hit { "hit" } else { "miss" };
+            let _ = writeln!(
+                output,
+                "{}{{cache=\"{}\",result=\"{}\"}} {}",
+                name,
+                cache.label(),
+                result,
+                count
+            );
+        }
+
+        let name = "renderer_http_responses_total";
+        header(
+            &mut output,
+            name,
+            "counter",
+            "The responses sent to the clients, by status code.",
+        );
+        for (status, count) in self.responses.lock().unwrap().iter() {
+            let _ = writeln!(output, "{}{{status=\"{}\"}} {}", name, status, count);
+        }
+
+        let name = "renderer_http_response_bytes_total";
+        header(
+            &mut output,
+            name,
+            "counter",
+            "The bytes of the response bodies sent to the clients.",
+        );
+        let _ = writeln!(output, "{} {}", name, self.bytes_served.load(Ordering::Relaxed));
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.tile_rendered(15, Duration::from_millis(20));
+        metrics.tile_rendered(15, Duration::from_millis(300));
+        metrics.tile_rendered(3, Duration::from_secs(20));
+        metrics.geodata_queried(Duration::from_micros(50));
+        metrics.cache_lookup(CacheKind::Memory, false);
+        metrics.cache_lookup(CacheKind::Memory, true);
+        metrics.cache_lookup(CacheKind::Memory, true);
+        metrics.response_sent(200, 1000);
+        metrics.response_sent(404, 10);
+
+        let output = metrics.to_prometheus();
+        let lines = output.lines().collect::<Vec<_>>();
+        for expected in [
+            "# TYPE renderer_tiles_rendered_total counter",
+            "renderer_tiles_rendered_total 3",
+            "# TYPE renderer_render_duration_seconds histogram",
+            "renderer_render_duration_seconds_bucket{zoom=\"15\",le=\"0.01\"} 0",
+            "renderer_render_duration_seconds_bucket{zoom=\"15\",le=\"0.025\"} 1",
+            "renderer_render_duration_seconds_bucket{zoom=\"15\",le=\"0.5\"} 2",
+            "renderer_render_duration_seconds_bucket{zoom=\"15\",le=\"+Inf\"} 2",
+            "renderer_render_duration_seconds_sum{zoom=\"15\"} 0.32",
+            "renderer_render_duration_seconds_count{zoom=\"15\"} 2",
+            "renderer_render_duration_seconds_bucket{zoom=\"3\",le=\"10\"} 0",
+            "renderer_render_duration_seconds_bucket{zoom=\"3\",le=\"+Inf\"} 1",
+            "renderer_geodata_query_duration_seconds_bucket{le=\"0.0001\"} 1",
+            "renderer_geodata_query_duration_seconds_count 1",
+            "renderer_tile_cache_lookups_total{cache=\"memory\",result=\"hit\"} 2",
+            "renderer_tile_cache_lookups_total{cache=\"memory\",result=\"miss\"} 1",
+            "renderer_http_responses_total{status=\"200\"} 1",
+            "renderer_http_responses_total{status=\"404\"} 1",
+            "renderer_http_response_bytes_total 1010",
+        ] {
+            assert!(lines.contains(&expected), "{} is missing from\n{}", expected, output);
+        }
+
+        // The metrics that nothing has happened to yet are still described.
+        let empty = Metrics::new().to_prometheus();
+        assert!(empty.contains("renderer_tiles_rendered_total 0\n"));
+        assert!(empty.contains("# TYPE renderer_render_duration_seconds histogram\n"));
+    }
+}
@@ -22,7 +22,12 @@ impl PerfStatsElement {
     }
 }
 
+// Empty on the threads that help to draw a tile in parallel: only the thread that started the tile measures it.
 pub struct Measurer {
+    measured: Option<MeasuredElement>,
+}
+
+struct MeasuredElement {
     start_time: Instant,
     element: PerfStatsElementRef,
     element_stack: PerfStatsElementStackRef,
@@ -30,8 +35,10 @@ pub struct Measurer {
 
 impl Drop for Measurer {
     fn drop(&mut self) {
-        self.element.borrow_mut().duration += Instant::now() - self.start_time;
-        self.element_stack.borrow_mut().pop();
+        if let Some(measured) = &self.measured {
+            measured.element.borrow_mut().duration += Instant::now() - measured.start_time;
+            measured.element_stack.borrow_mut().pop();
+        }
     }
 }
 
@@ -72,9 +79,11 @@ impl TilePerfStats {
         stack.push(Rc::clone(&new_element));
 
         Measurer {
-            start_time: Instant::now(),
-            element: Rc::clone(&new_element),
-            element_stack: Rc::clone(&self.element_stack),
+            measured: Some(MeasuredElement {
+                start_time: Instant::now(),
+                element: Rc::clone(&new_element),
+                element_stack: Rc::clone(&self.element_stack),
+            }),
         }
     }
 
@@ -203,5 +212,8 @@ pub fn finish_tile(total_stats: &mut PerfStats) {
 }
 
 pub fn measure(name: impl Into<String>) -> Measurer {
-    TLS_PERF_STATS.with(|stats| stats.borrow_mut().as_mut().unwrap().measure(name))
+    TLS_PERF_STATS.with(|stats| match stats.borrow_mut().as_mut() {
+        Some(stats) => stats.measure(name),
+        None => Measurer { measured: None },
+    })
 }
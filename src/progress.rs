@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+/// How the progress of long-running commands is printed to stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for other programs to parse.
+    JsonLines,
+}
+
+/// Reports the progress of a command that consists of one or more stages (e.g. parsing the input
+/// and then saving the result). Each stage has a total amount of work (if known in advance),
+/// which is used to compute the percentage and the ETA, and a number of processed items (elements,
+/// tiles, etc.), which is used to compute the rate.
+pub struct Progress {
+    format: ProgressFormat,
+    stage: Option<Stage>,
+}
+
+struct Stage {
+    name: String,
+    item_name: &'static str,
+    total: Option<u64>,
+    initial_done: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    pub fn new(format: ProgressFormat) -> Progress {
+        Progress { format, stage: None }
+    }
+
+    pub fn message(&self, message: &str) {
+        match self.format {
+            ProgressFormat::Text => println!("{}", message),
+            ProgressFormat::JsonLines => println!("{{\"message\":{}}}", json_string(message)),
+        }
+    }
+
+    /// Starts a new stage. `initial_done` is the amount of work that was done before
+    /// (e.g. by an interrupted run that we're resuming), so that it doesn't affect the rate and the ETA.
+    pub fn start_stage(&mut self, name: &str, item_name: &'static str, total: Option<u64>, initial_done: u64) {
+        let now = Instant::now();
+        self.stage = Some(Stage {
+            name: name.to_string(),
+            item_name,
+            total,
+            initial_done,
+            started: now,
+            last_report: now,
+        });
+        self.message(name);
+    }
+
+    /// Reports that `done` units of work are done and `items` items are processed during the current stage.
+    /// The report is only printed if the previous one was long enough ago.
+    pub fn update(&mut self, done: u64, items: u64) {
+        let should_report = match &self.stage {
+            Some(stage) => stage.last_report.elapsed() >= REPORT_INTERVAL,
+            None => false,
+        };
+        if should_report {
+            self.report(done, items, false);
+        }
+    }
+
+    /// Prints the final report for the current stage.
+    pub fn finish_stage(&mut self, done: u64, items: u64) {
+        self.report(done, items, true);
+        self.stage = None;
+    }
+
+    fn report(&mut self, done: u64, items: u64, finished: bool) {
+        let stage = match self.stage.as_mut() {
+            Some(stage) => stage,
+            None => return,
+        };
+        stage.last_report = Instant::now();
+
+        let elapsed = stage.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { items as f64 / elapsed } else { 0.0 };
+        let percent = stage
+            .total
+            .filter(|total| *total > 0)
+            .map(|total| 100.0 * done.min(total) as f64 / total as f64);
+        let done_now = done.saturating_sub(stage.initial_done);
+        let eta = match stage.total {
+            Some(total) if done_now > 0 && !finished => {
+                Some(elapsed * total.saturating_sub(done) as f64 / done_now as f64)
+            }
+            _ => None,
+        };
+
+        match self.format {
+            ProgressFormat::Text => {
+                let mut line = format!("{}:", stage.name);
+                if let Some(percent) = percent {
+                    line += &format!(" {:.1}% done,", percent);
+                }
+                line += &format!(
+                    " {} {} processed ({:.1} {}/s)",
+                    items, stage.item_name, rate, stage.item_name
+                );
+                if finished {
+                    line += &format!(", took {}", format_duration(elapsed));
+                } else if let Some(eta) = eta {
+                    line += &format!(", ETA {}", format_duration(eta));
+                }
+                println!("{}", line);
+            }
+            ProgressFormat::JsonLines => {
+                let optional = |value: Option<f64>| value.map_or("null".to_string(), |v| format!("{:.1}", v));
+                println!(
+                    "{{\"stage\":{},\"done\":{},\"total\":{},\"percent\":{},\"items\":{},\"item_name\":{},\"rate\":{:.1},\"elapsed_secs\":{:.1},\"eta_secs\":{},\"finished\":{}}}",
+                    json_string(&stage.name),
+                    done,
+                    stage.total.map_or("null".to_string(), |t| t.to_string()),
+                    optional(percent),
+                    items,
+                    json_string(stage.item_name),
+                    rate,
+                    elapsed,
+                    optional(eta),
+                    finished,
+                );
+            }
+        }
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            c if c.is_control() => result += &format!("\\u{:04x}", c as u32),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
@@ -0,0 +1,67 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use tini::Ini;
+
+// A checkpoint only makes sense for the exact same set of tiles it was written for,
+// so we store a description of the seeding job alongside the progress and refuse
+// to resume a different job.
+pub(super) struct Checkpoint {
+    path: PathBuf,
+    job: String,
+}
+
+const SECTION: &str = "checkpoint";
+
+impl Checkpoint {
+    pub(super) fn new(path: PathBuf, job: String) -> Checkpoint {
+        Checkpoint { path, job }
+    }
+
+    /// Returns the number of tiles that were completed by a previous run of the same job,
+    /// or `None` if there's nothing to resume.
+    pub(super) fn load(&self) -> Result<Option<u64>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let path_str = self.path.to_string_lossy();
+        let ini = Ini::from_file(&self.path).context(format!("Failed to read checkpoint from {}", path_str))?;
+
+        let job = ini.get::<String>(SECTION, "job").unwrap_or_default();
+        if job != self.job {
+            bail!(
+                "Checkpoint {} was written for a different job ({}), refusing to resume",
+                path_str,
+                job
+            );
+        }
+
+        match ini.get::<u64>(SECTION, "completed") {
+            Some(completed) => Ok(Some(completed)),
+            None => bail!("Checkpoint {} doesn't record the number of completed tiles", path_str),
+        }
+    }
+
+    pub(super) fn save(&self, completed: u64) -> Result<()> {
+        let ini = Ini::new()
+            .section(SECTION)
+            .item("job", &self.job)
+            .item("completed", completed);
+
+        // Write to a temporary file first so that an interrupted write never destroys the
+        // previous checkpoint.
+        let tmp_path = self.path.with_extension("tmp");
+        ini.to_file(&tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .context(format!("Failed to save checkpoint to {}", self.path.to_string_lossy()))
+    }
+
+    pub(super) fn remove(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .context(format!("Failed to remove checkpoint {}", self.path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
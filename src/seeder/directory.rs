@@ -0,0 +1,176 @@
+use crate::progress::Progress;
+use crate::seeder::{DirectoryLayout, TileOutput, METATILE_SIZE};
+use crate::tile::Tile;
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Stores tiles as files in a directory, either as `{z}/{x}/{y}.png`, ready to be served by any static
+/// web server, or as mod_tile's metatiles.
+pub(super) struct DirectoryOutput {
+    dir: PathBuf,
+    layout: DirectoryLayout,
+    pending_metatile: Option<PendingMetatile>,
+}
+
+// The tiles of the metatile that is currently being rendered.
+struct PendingMetatile {
+    zoom: u8,
+    x: u32,
+    y: u32,
+    tiles: Vec<Vec<u8>>,
+    tile_count: usize,
+}
+
+impl DirectoryOutput {
+    pub(super) fn create(dir: &Path, layout: &DirectoryLayout) -> Result<DirectoryOutput> {
+        fs::create_dir_all(dir).context(format!("Failed to create output directory {}", dir.to_string_lossy()))?;
+        Ok(DirectoryOutput {
+            dir: dir.to_path_buf(),
+            layout: layout.clone(),
+            pending_metatile: None,
+        })
+    }
+
+    fn tile_file_path(&self, tile: &Tile) -> PathBuf {
+        match self.layout {
+            DirectoryLayout::Xyz => self
+                .dir
+                .join(tile.zoom.to_string())
+                .join(tile.x.to_string())
+                .join(format!("{}.png", tile.y)),
+            DirectoryLayout::Metatile => self.metatile_file_path(tile),
+        }
+    }
+
+    // Mirrors xyz_to_meta() from mod_tile: the path consists of the zoom and five bytes,
+    // each containing four bits of x and y of the metatile.
+    fn metatile_file_path(&self, tile: &Tile) -> PathBuf {
+        let (mut x, mut y) = (tile.x & !(METATILE_SIZE - 1), tile.y & !(METATILE_SIZE - 1));
+        let mut hash = [0; 5];
+        for h in hash.iter_mut() {
+            *h = ((x & 0x0f) << 4) | (y & 0x0f);
+            x >>= 4;
+            y >>= 4;
+        }
+
+        let mut path = self.dir.join(tile.zoom.to_string());
+        for h in hash[1..].iter().rev() {
+            path.push(h.to_string());
+        }
+        path.push(format!("{}.meta", hash[0]));
+        path
+    }
+
+    fn add_to_metatile(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        let (meta_x, meta_y) = (tile.x & !(METATILE_SIZE - 1), tile.y & !(METATILE_SIZE - 1));
+        let is_same_metatile = |m: &PendingMetatile| m.zoom == tile.zoom && m.x == meta_x && m.y == meta_y;
+
+        if !self.pending_metatile.as_ref().is_some_and(is_same_metatile) {
+            self.flush_metatile()?;
+            self.pending_metatile = Some(PendingMetatile {
+                zoom: tile.zoom,
+                x: meta_x,
+                y: meta_y,
+                tiles: vec![Vec::new(); (METATILE_SIZE * METATILE_SIZE) as usize],
+                tile_count: 0,
+            });
+        }
+
+        if let Some(metatile) = self.pending_metatile.as_mut() {
+            let mask = METATILE_SIZE - 1;
+            metatile.tiles[((tile.x & mask) * METATILE_SIZE + (tile.y & mask)) as usize] = data.to_vec();
+            metatile.tile_count += 1;
+
+            let side = std::cmp::min(1 << tile.zoom, METATILE_SIZE) as usize;
+            if metatile.tile_count == side * side {
+                self.flush_metatile()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The format is described in mod_tile's metatile.h: a header with the coordinates of the top left tile,
+    // a table of (offset, size) pairs for all 64 tiles and then the tile data itself.
+    fn flush_metatile(&mut self) -> Result<()> {
+        let metatile = match self.pending_metatile.take() {
+            Some(metatile) => metatile,
+            None => return Ok(()),
+        };
+
+        let tile_count = metatile.tiles.len();
+        let mut data = Vec::new();
+        data.extend_from_slice(b"META");
+        data.write_i32::<LittleEndian>(tile_count as i32)?;
+        data.write_i32::<LittleEndian>(metatile.x as i32)?;
+        data.write_i32::<LittleEndian>(metatile.y as i32)?;
+        data.write_i32::<LittleEndian>(i32::from(metatile.zoom))?;
+
+        let mut offset = 20 + 8 * tile_count;
+        for tile in &metatile.tiles {
+            data.write_i32::<LittleEndian>(offset as i32)?;
+            data.write_i32::<LittleEndian>(tile.len() as i32)?;
+            offset += tile.len();
+        }
+        for tile in &metatile.tiles {
+            data.extend_from_slice(tile);
+        }
+
+        let path = self.metatile_file_path(&Tile {
+            zoom: metatile.zoom,
+            x: metatile.x,
+            y: metatile.y,
+        });
+        write_file(&path, &data)
+    }
+}
+
+impl TileOutput for DirectoryOutput {
+    fn checkpoint_path(&self) -> Option<PathBuf> {
+        Some(self.dir.join(CHECKPOINT_FILE_NAME))
+    }
+
+    fn metatile_size(&self) -> u32 {
+        match self.layout {
+            DirectoryLayout::Xyz => 1,
+            DirectoryLayout::Metatile => METATILE_SIZE,
+        }
+    }
+
+    fn is_fresh(&self, tile: &Tile, threshold: &SystemTime) -> bool {
+        match fs::metadata(self.tile_file_path(tile)).and_then(|m| m.modified()) {
+            Ok(modified) => modified > *threshold,
+            Err(_) => false,
+        }
+    }
+
+    fn write_tile(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        match self.layout {
+            DirectoryLayout::Xyz => write_file(&self.tile_file_path(tile), data),
+            DirectoryLayout::Metatile => self.add_to_metatile(tile, data),
+        }
+    }
+
+    fn finish(mut self: Box<Self>, _progress: &Progress) -> Result<()> {
+        self.flush_metatile()
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Never leave a truncated file behind if we get interrupted: it would look fresh
+    // to the next run with --skip-if-newer-than.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, data)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .context(format!("Failed to write {}", path.to_string_lossy()))
+}
+
+const CHECKPOINT_FILE_NAME: &str = "seed_checkpoint.ini";
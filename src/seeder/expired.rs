@@ -0,0 +1,51 @@
+use crate::seeder::tiles_in_metatile;
+use crate::tile::Tile;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Reads an expire list (one `z/x/y` tile per line, as written after applying a diff) and
+/// returns all tiles in the `[min_zoom, max_zoom]` range that have to be re-rendered:
+/// the expired tiles themselves and all of their ancestors.
+///
+/// Just like for a bounding box, the tiles are extended to whole metatiles and sorted
+/// by zoom, then by metatile, then by `x`, then by `y`.
+pub(super) fn read_expired_tiles(path: &Path, min_zoom: u8, max_zoom: u8, metatile_size: u32) -> Result<Vec<Tile>> {
+    let path_str = path.to_string_lossy();
+    let contents = fs::read_to_string(path).context(format!("Failed to read expire list {}", path_str))?;
+
+    let mut tiles = BTreeSet::new();
+    for (line_idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let expired_tile =
+            line.parse::<Tile>()
+                .context(format!("Failed to parse line {} of {}", line_idx + 1, path_str))?;
+
+        let mut tile = Some(expired_tile);
+        while let Some(current) = tile {
+            if current.zoom < min_zoom {
+                break;
+            }
+            tile = current.parent();
+            // Tiles that are deeper than max_zoom still expire their ancestors.
+            if current.zoom <= max_zoom && !tiles.insert(current) {
+                // The ancestors of an already seen tile are already in the set.
+                break;
+            }
+        }
+    }
+
+    let metatiles = tiles
+        .into_iter()
+        .map(|t| (t.zoom, t.x / metatile_size, t.y / metatile_size))
+        .collect::<BTreeSet<_>>();
+
+    Ok(metatiles
+        .into_iter()
+        .flat_map(|(zoom, meta_x, meta_y)| tiles_in_metatile(zoom, meta_x, meta_y, metatile_size))
+        .collect())
+}
@@ -0,0 +1,334 @@
+use crate::coords::GeoBounds;
+use crate::progress::Progress;
+use crate::seeder::TileOutput;
+use crate::tile::{xy_to_coords, Tile, TILE_SIZE};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// See https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md for the description of the format.
+//
+// Unlike a PMTiles archive, the file is a database that can be updated in place: the tiles are added
+// to the ones that are already there, and every completed chunk is committed, so an interrupted run
+// can be resumed. The metadata describes all the tiles in the file, not only the ones seeded last.
+//
+// Many tiles are the same (e.g. the empty land or sea), so the images are stored once each, under the hash of their
+// bytes, and the `map` table points the tiles at them. The readers see the usual `tiles` table, which is a view
+// that joins the two. The files created with a plain `tiles` table are kept as they are.
+pub(super) struct MbtilesOutput {
+    path: PathBuf,
+    connection: Connection,
+    is_deduplicated: bool,
+    tiles_written: u64,
+}
+
+impl MbtilesOutput {
+    pub(super) fn open(path: &Path) -> Result<MbtilesOutput> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let path_str = path.to_string_lossy();
+        let connection = Connection::open(path).context(format!("Failed to open MBTiles file {}", path_str))?;
+        let set_up = || -> Result<bool> {
+            let tiles_type = connection
+                .query_row("SELECT type FROM sqlite_master WHERE name = 'tiles'", [], |row| {
+                    row.get::<_, String>(0)
+                })
+                .optional()?;
+            let is_deduplicated = tiles_type.as_deref() != Some("table");
+            let tile_tables = if is_deduplicated {
+                "CREATE TABLE IF NOT EXISTS map (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_id TEXT);
+                 CREATE UNIQUE INDEX IF NOT EXISTS map_index ON map (zoom_level, tile_column, tile_row);
+                 CREATE TABLE IF NOT EXISTS images (tile_data BLOB, tile_id TEXT);
+                 CREATE UNIQUE INDEX IF NOT EXISTS images_id ON images (tile_id);
+                 CREATE VIEW IF NOT EXISTS tiles AS
+                     SELECT map.zoom_level AS zoom_level, map.tile_column AS tile_column, map.tile_row AS tile_row,
+                         images.tile_data AS tile_data
+                     FROM map JOIN images ON images.tile_id = map.tile_id;"
+            } else {
+                ""
+            };
+            connection.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+                 CREATE UNIQUE INDEX IF NOT EXISTS metadata_name ON metadata (name);
+                 {}
+                 BEGIN;",
+                tile_tables
+            ))?;
+            Ok(is_deduplicated)
+        };
+        let is_deduplicated = set_up().context(format!("Failed to set up MBTiles file {}", path_str))?;
+
+        Ok(MbtilesOutput {
+            path: path.to_path_buf(),
+            connection,
+            is_deduplicated,
+            tiles_written: 0,
+        })
+    }
+
+    fn write_metadata(&self) -> Result<()> {
+        let zoom_range =
+            self.connection
+                .query_row("SELECT MIN(zoom_level), MAX(zoom_level) FROM tiles", [], |row| {
+                    Ok((row.get::<_, Option<u8>>(0)?, row.get::<_, Option<u8>>(1)?))
+                })?;
+        let (min_zoom, max_zoom) = match zoom_range {
+            (Some(min_zoom), Some(max_zoom)) => (min_zoom, max_zoom),
+            _ => return Ok(()),
+        };
+
+        // The seeded area grows towards the lower zoom levels (whole metatiles, ancestors of the expired tiles),
+        // so the bounds are taken from the most detailed zoom level.
+        let (min_x, max_x, min_row, max_row): (u32, u32, u32, u32) = self.connection.query_row(
+            "SELECT MIN(tile_column), MAX(tile_column), MIN(tile_row), MAX(tile_row) FROM tiles WHERE zoom_level = ?1",
+            [max_zoom],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        let bounds = {
+            let tile_size = f64::from(TILE_SIZE);
+            let corner = |x: u32, y: u32| xy_to_coords(f64::from(x) * tile_size, f64::from(y) * tile_size, max_zoom);
+            // The rows go from the bottom to the top.
+            let (max_lat, min_lon) = corner(min_x, tms_row(max_zoom, max_row));
+            let (min_lat, max_lon) = corner(max_x + 1, tms_row(max_zoom, min_row) + 1);
+            GeoBounds {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            }
+        };
+
+        let center = format!(
+            "{},{},{}",
+            (bounds.min_lon + bounds.max_lon) / 2.0,
+            (bounds.min_lat + bounds.max_lat) / 2.0,
+            min_zoom
+        );
+        let metadata = [
+            ("name", "osm-renderer".to_string()),
+            ("format", "png".to_string()),
+            ("type", "baselayer".to_string()),
+            ("minzoom", min_zoom.to_string()),
+            ("maxzoom", max_zoom.to_string()),
+            ("bounds", bounds.to_string()),
+            ("center", center),
+        ];
+
+        let mut statement = self
+            .connection
+            .prepare_cached("INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)")?;
+        for (name, value) in metadata {
+            statement.execute(params![name, value])?;
+        }
+        Ok(())
+    }
+
+    // The tiles that have been drawn again (e.g. with `--expired-list`) might leave their old images unused.
+    fn remove_unused_images(&self) -> Result<()> {
+        if self.is_deduplicated {
+            self.connection
+                .execute("DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)", [])?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.connection.execute_batch("COMMIT; BEGIN;")?;
+        Ok(())
+    }
+}
+
+impl TileOutput for MbtilesOutput {
+    fn checkpoint_path(&self) -> Option<PathBuf> {
+        Some(self.path.with_extension("mbtiles.checkpoint"))
+    }
+
+    fn is_fresh(&self, _tile: &Tile, _threshold: &SystemTime) -> bool {
+        false
+    }
+
+    fn write_tile(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        let row = tms_row(tile.zoom, tile.y);
+        if self.is_deduplicated {
+            let tile_id = Sha256::digest(data)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            self.connection
+                .prepare_cached("INSERT OR IGNORE INTO images (tile_data, tile_id) VALUES (?1, ?2)")?
+                .execute(params![data, tile_id])?;
+            self.connection
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![tile.zoom, tile.x, row, tile_id])?;
+        } else {
+            self.connection
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![tile.zoom, tile.x, row, data])?;
+        }
+        self.tiles_written += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.commit()
+            .context(format!("Failed to commit tiles to {}", self.path.to_string_lossy()))
+    }
+
+    fn finish(self: Box<Self>, progress: &Progress) -> Result<()> {
+        let path_str = self.path.to_string_lossy();
+        self.remove_unused_images()
+            .and_then(|_| self.write_metadata())
+            .and_then(|_| self.connection.execute_batch("COMMIT;").map_err(Into::into))
+            .context(format!("Failed to write the metadata to {}", path_str))?;
+
+        progress.message(&format!("Stored {} tiles in {}", self.tiles_written, path_str));
+        Ok(())
+    }
+}
+
+// MBTiles numbers the rows from the bottom (as TMS does), and the conversion is the same in both directions.
+fn tms_row(zoom: u8, y: u32) -> u32 {
+    ((1u64 << zoom) - 1 - u64::from(y)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::ProgressFormat;
+
+    #[test]
+    fn test_mbtiles_output() {
+        let path = std::env::temp_dir().join(format!("test_mbtiles_output_{}.mbtiles", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let progress = Progress::new(ProgressFormat::Text);
+
+        let tile = Tile {
+            zoom: 15,
+            x: 19805,
+            y: 10244,
+        };
+        let mut output = Box::new(MbtilesOutput::open(&path).unwrap());
+        output.write_tile(&tile, b"first").unwrap();
+        output.flush().unwrap();
+        output.write_tile(&tile, b"second").unwrap();
+        output.finish(&progress).unwrap();
+
+        let mut output = Box::new(MbtilesOutput::open(&path).unwrap());
+        output.write_tile(&tile.parent().unwrap(), b"parent").unwrap();
+        output.finish(&progress).unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let tiles = connection
+            .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles ORDER BY zoom_level")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(u8, u32, u32, Vec<u8>)>>>()
+            .unwrap();
+        assert_eq!(
+            tiles,
+            vec![
+                (14, 9902, 11261, b"parent".to_vec()),
+                (15, 19805, 22523, b"second".to_vec()),
+            ]
+        );
+
+        let metadata = |name: &str| -> String {
+            connection
+                .query_row("SELECT value FROM metadata WHERE name = ?1", [name], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(metadata("format"), "png");
+        assert_eq!(metadata("minzoom"), "14");
+        assert_eq!(metadata("maxzoom"), "15");
+        // The bounds of the only tile at the maximum zoom level.
+        let bounds = metadata("bounds").parse::<GeoBounds>().unwrap();
+        let expected_bounds = [37.584228, 55.745666, 37.595215, 55.751849];
+        let actual_bounds = [bounds.min_lon, bounds.min_lat, bounds.max_lon, bounds.max_lat];
+        assert!((0..4).all(|i| (actual_bounds[i] - expected_bounds[i]).abs() < 1e-6));
+
+        // The image that has been replaced is gone.
+        let image_count: u32 = connection
+            .query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(image_count, 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mbtiles_deduplication() {
+        let path = std::env::temp_dir().join(format!("test_mbtiles_dedup_{}.mbtiles", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let tile = Tile {
+            zoom: 15,
+            x: 19805,
+            y: 10244,
+        };
+        let sibling = Tile { x: 19806, ..tile };
+        let mut output = Box::new(MbtilesOutput::open(&path).unwrap());
+        output.write_tile(&tile, b"sea").unwrap();
+        output.write_tile(&sibling, b"sea").unwrap();
+        output.finish(&Progress::new(ProgressFormat::Text)).unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let count = |table: &str| -> u32 {
+            connection
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count("map"), 2);
+        assert_eq!(count("images"), 1);
+        let tiles = connection
+            .prepare("SELECT tile_column, tile_data FROM tiles ORDER BY tile_column")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(u32, Vec<u8>)>>>()
+            .unwrap();
+        assert_eq!(tiles, vec![(19805, b"sea".to_vec()), (19806, b"sea".to_vec())]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mbtiles_plain_tiles_table() {
+        let path = std::env::temp_dir().join(format!("test_mbtiles_plain_{}.mbtiles", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+            )
+            .unwrap();
+
+        // The files seeded before the deduplication keep their tables.
+        let mut output = Box::new(MbtilesOutput::open(&path).unwrap());
+        output.write_tile(&Tile { zoom: 0, x: 0, y: 0 }, b"world").unwrap();
+        output.finish(&Progress::new(ProgressFormat::Text)).unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let data: Vec<u8> = connection
+            .query_row("SELECT tile_data FROM tiles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(data, b"world");
+        let has_map = connection
+            .query_row("SELECT 1 FROM sqlite_master WHERE name = 'map'", [], |_| Ok(()))
+            .optional()
+            .unwrap();
+        assert_eq!(has_map, None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
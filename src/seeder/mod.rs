@@ -0,0 +1,390 @@
+mod checkpoint;
+mod directory;
+mod expired;
+#[cfg(feature = "mbtiles")]
+mod mbtiles;
+mod pmtiles;
+mod polygon;
+mod scheduler;
+
+use crate::coords::GeoBounds;
+use crate::draw::drawer::{DrawOptions, Drawer, TileRenderedPixels};
+use crate::draw::png_writer::rgb_triples_to_png;
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
+use crate::mapcss::styler::{StyleType, Styler};
+use crate::progress::{Progress, ProgressFormat};
+use crate::seeder::checkpoint::Checkpoint;
+use crate::seeder::directory::DirectoryOutput;
+use crate::seeder::expired::read_expired_tiles;
+#[cfg(feature = "mbtiles")]
+use crate::seeder::mbtiles::MbtilesOutput;
+use crate::seeder::pmtiles::PmtilesOutput;
+pub use crate::seeder::polygon::SeedPolygon;
+use crate::seeder::scheduler::{make_batches, render_batches, RenderContext};
+use crate::tile::{bounds_to_tile_range, Tile};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Which tiles of the zoom range should be seeded.
+pub enum SeedArea {
+    /// All tiles intersecting the bounding box.
+    Bounds(GeoBounds),
+    /// Only the tiles listed in an expire list file, plus their ancestors.
+    ExpiredList(PathBuf),
+    /// All tiles intersecting the polygons (see `SeedPolygon`).
+    Polygon(SeedPolygon),
+}
+
+#[derive(Clone)]
+pub enum DirectoryLayout {
+    /// Every tile is a separate `{z}/{x}/{y}.png` file.
+    Xyz,
+    /// Tiles are bundled into 8x8 `.meta` files in the same layout as mod_tile uses,
+    /// so that the directory can be used as mod_tile's cache.
+    Metatile,
+}
+
+pub struct SeedOptions {
+    pub area: SeedArea,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// A directory for a tree of tiles, a file with the `.pmtiles` extension for a single PMTiles archive,
+    /// or a file with the `.mbtiles` extension for an MBTiles database (with the `mbtiles` feature).
+    pub output: PathBuf,
+    /// How to lay out the tiles when writing to a directory.
+    pub layout: DirectoryLayout,
+    /// Draw the blocks of 8x8 tiles at once (see `Drawer::draw_metatile_to_pixels`), which is faster
+    /// but takes much more memory per thread.
+    pub metatiles: bool,
+    /// Continue from the checkpoint left behind by an interrupted run of the same job.
+    pub resume: bool,
+    /// Don't re-render tiles whose files were modified after this moment.
+    pub skip_if_newer_than: Option<SystemTime>,
+    /// The number of rendering threads.
+    pub threads: usize,
+    pub progress_format: ProgressFormat,
+}
+
+pub fn run_seeder(
+    geodata_file: &str,
+    stylesheet_file: &str,
+    stylesheet_type: &StyleType,
+    font_size_multiplier: Option<f64>,
+    draw_options: DrawOptions,
+    options: &SeedOptions,
+) -> Result<()> {
+    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
+    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
+    let styler = Styler::new(rules, stylesheet_type, font_size_multiplier);
+    let reader = GeodataReader::load(geodata_file).context("Failed to load the geodata file")?;
+    let drawer = Drawer::new(&base_path, draw_options);
+
+    let mut output = create_output(options)?;
+    let metatile_size = if options.metatiles {
+        METATILE_SIZE
+    } else {
+        output.metatile_size()
+    };
+
+    let (tiles, total_tiles): (Box<dyn Iterator<Item = Tile> + '_>, u64) = match &options.area {
+        SeedArea::Bounds(bounds) => (
+            Box::new(tiles_in_bounds(
+                bounds,
+                options.min_zoom,
+                options.max_zoom,
+                metatile_size,
+            )),
+            count_tiles_in_bounds(bounds, options.min_zoom, options.max_zoom, metatile_size),
+        ),
+        SeedArea::ExpiredList(path) => {
+            let expired_tiles = read_expired_tiles(path, options.min_zoom, options.max_zoom, metatile_size)?;
+            let total_tiles = expired_tiles.len() as u64;
+            (Box::new(expired_tiles.into_iter()), total_tiles)
+        }
+        SeedArea::Polygon(polygon) => {
+            let polygon_tiles = tiles_in_polygon(polygon, options.min_zoom, options.max_zoom, metatile_size);
+            let total_tiles = polygon_tiles.len() as u64;
+            (Box::new(polygon_tiles.into_iter()), total_tiles)
+        }
+    };
+
+    let mut progress = Progress::new(options.progress_format);
+    let checkpoint = output
+        .checkpoint_path()
+        .map(|path| Checkpoint::new(path, describe_job(options, total_tiles)));
+    let already_completed = match (options.resume, &checkpoint) {
+        (false, _) => 0,
+        (true, None) => bail!("Resuming is not supported for {}", options.output.to_string_lossy()),
+        (true, Some(checkpoint)) => match checkpoint.load()? {
+            Some(completed) => {
+                progress.message(&format!("Resuming after {} completed tiles", completed));
+                completed
+            }
+            None => {
+                progress.message("No checkpoint found, starting from scratch");
+                0
+            }
+        },
+    };
+
+    let ctx = RenderContext {
+        reader: &reader,
+        drawer: &drawer,
+        styler: &styler,
+        draw_metatiles: options.metatiles,
+    };
+    let mut stats = SeedStats::default();
+    let mut completed = already_completed;
+    progress.start_stage("Seeding", "tiles", Some(total_tiles), already_completed);
+    let mut tiles = tiles.skip(already_completed as usize).peekable();
+
+    // The tiles are processed in chunks, each of which is rendered in parallel in its own order.
+    // Once a chunk is done, all tiles before its end are written, so the checkpoint can still be a single number.
+    loop {
+        let chunk = next_chunk(&mut tiles, metatile_size);
+        if chunk.is_empty() {
+            break;
+        }
+        let chunk_len = chunk.len() as u64;
+
+        let (fresh_tiles, stale_tiles): (Vec<_>, Vec<_>) = chunk.into_iter().partition(|tile| {
+            options
+                .skip_if_newer_than
+                .as_ref()
+                .is_some_and(|threshold| output.is_fresh(tile, threshold))
+        });
+        stats.skipped += fresh_tiles.len() as u64;
+
+        let batches = make_batches(stale_tiles, metatile_size, &reader);
+        let mut completed_in_chunk = fresh_tiles.len() as u64;
+        render_batches(&batches, options.threads, &ctx, |rendered| {
+            completed_in_chunk += rendered.len() as u64;
+            for (tile, tile_png_bytes) in rendered {
+                output.write_tile(&tile, &tile_png_bytes)?;
+                stats.rendered += 1;
+            }
+
+            let done = completed + completed_in_chunk;
+            progress.update(done, done - already_completed);
+            Ok(())
+        })?;
+
+        completed += chunk_len;
+        output.flush()?;
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.save(completed)?;
+        }
+    }
+
+    progress.finish_stage(completed, completed - already_completed);
+
+    output.finish(&progress)?;
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.remove()?;
+    }
+
+    progress.message(&format!(
+        "Seeding finished: {} tiles rendered, {} skipped as fresh",
+        stats.rendered, stats.skipped
+    ));
+
+    Ok(())
+}
+
+trait TileOutput {
+    /// Where to keep the progress of the seeding job, if the output can be resumed at all.
+    fn checkpoint_path(&self) -> Option<PathBuf>;
+
+    /// The output receives the tiles grouped into `metatile_size`x`metatile_size` blocks.
+    fn metatile_size(&self) -> u32 {
+        1
+    }
+
+    /// Returns true if the tile is already present in the output and was written after `threshold`.
+    fn is_fresh(&self, tile: &Tile, threshold: &SystemTime) -> bool;
+
+    fn write_tile(&mut self, tile: &Tile, data: &[u8]) -> Result<()>;
+
+    /// Makes sure that all the tiles written so far survive an interruption, before the checkpoint says so.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, progress: &Progress) -> Result<()>;
+}
+
+fn create_output(options: &SeedOptions) -> Result<Box<dyn TileOutput>> {
+    if options.output.extension().is_some_and(|ext| ext == "pmtiles") {
+        let bounds = match &options.area {
+            SeedArea::Bounds(bounds) => bounds,
+            SeedArea::Polygon(polygon) => polygon.bounds(),
+            SeedArea::ExpiredList(_) => bail!("PMTiles archives can't be updated in place, use a bounding box instead"),
+        };
+        if options.resume || options.skip_if_newer_than.is_some() {
+            bail!(
+                "PMTiles archives are always written from scratch, so they can't be resumed or partially re-rendered"
+            );
+        }
+        return Ok(Box::new(PmtilesOutput::create(
+            &options.output,
+            options.min_zoom,
+            options.max_zoom,
+            bounds,
+        )?));
+    }
+
+    if options.output.extension().is_some_and(|ext| ext == "mbtiles") {
+        if options.skip_if_newer_than.is_some() {
+            bail!("MBTiles files don't record when the tiles were rendered, so --skip-if-newer-than doesn't work with them");
+        }
+        #[cfg(feature = "mbtiles")]
+        return Ok(Box::new(MbtilesOutput::open(&options.output)?));
+        #[cfg(not(feature = "mbtiles"))]
+        bail!("MBTiles files are only supported when built with the mbtiles feature");
+    }
+
+    Ok(Box::new(DirectoryOutput::create(&options.output, &options.layout)?))
+}
+
+// Tiles of a single color (sea, empty land, etc.) make up a large part of any seeding job,
+// so we encode each of them to PNG only once.
+#[derive(Default)]
+struct SolidTileCache {
+    png_by_color: HashMap<(u8, u8, u8), Vec<u8>>,
+}
+
+impl SolidTileCache {
+    fn encode(&mut self, pixels: &TileRenderedPixels) -> Result<Vec<u8>> {
+        let encode = || rgb_triples_to_png(&pixels.triples, pixels.dimension, pixels.dimension);
+
+        let color = match pixels.triples.first() {
+            Some(color) if pixels.triples.iter().all(|c| c == color) => *color,
+            _ => return encode(),
+        };
+
+        if let Some(png) = self.png_by_color.get(&color) {
+            return Ok(png.clone());
+        }
+        let png = encode()?;
+        self.png_by_color.insert(color, png.clone());
+        Ok(png)
+    }
+}
+
+#[derive(Default)]
+struct SeedStats {
+    rendered: u64,
+    skipped: u64,
+}
+
+// Tiles are always enumerated in the same order (zoom by zoom, then metatile by metatile, column by column),
+// which lets a checkpoint describe the progress with a single number. The area is extended to whole metatiles,
+// so that an output can write a metatile as soon as its last tile is rendered.
+fn tiles_in_bounds(
+    bounds: &GeoBounds,
+    min_zoom: u8,
+    max_zoom: u8,
+    metatile_size: u32,
+) -> impl Iterator<Item = Tile> + '_ {
+    (min_zoom..=max_zoom).flat_map(move |zoom| {
+        let range = bounds_to_tile_range(bounds, zoom);
+        (range.min_x / metatile_size..=range.max_x / metatile_size).flat_map(move |meta_x| {
+            (range.min_y / metatile_size..=range.max_y / metatile_size)
+                .flat_map(move |meta_y| tiles_in_metatile(zoom, meta_x, meta_y, metatile_size))
+        })
+    })
+}
+
+fn count_tiles_in_bounds(bounds: &GeoBounds, min_zoom: u8, max_zoom: u8, metatile_size: u32) -> u64 {
+    (min_zoom..=max_zoom)
+        .map(|zoom| {
+            let range = bounds_to_tile_range(bounds, zoom);
+            let zoom_size = 1u64 << zoom;
+            let extend = |min: u32, max: u32| {
+                let size = u64::from(metatile_size);
+                let end = std::cmp::min((u64::from(max) / size + 1) * size, zoom_size);
+                end - u64::from(min) / size * size
+            };
+            extend(range.min_x, range.max_x) * extend(range.min_y, range.max_y)
+        })
+        .sum()
+}
+
+// The same order as in `tiles_in_bounds`, only with the metatiles outside of the polygons left out.
+fn tiles_in_polygon(polygon: &SeedPolygon, min_zoom: u8, max_zoom: u8, metatile_size: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for zoom in min_zoom..=max_zoom {
+        let range = bounds_to_tile_range(polygon.bounds(), zoom);
+        let max_coord = ((1u64 << zoom) - 1) as u32;
+        for meta_x in range.min_x / metatile_size..=range.max_x / metatile_size {
+            for meta_y in range.min_y / metatile_size..=range.max_y / metatile_size {
+                let (min_x, min_y) = (meta_x * metatile_size, meta_y * metatile_size);
+                let max_x = std::cmp::min(min_x + (metatile_size - 1), max_coord);
+                let max_y = std::cmp::min(min_y + (metatile_size - 1), max_coord);
+                if polygon.intersects_tiles(zoom, min_x, min_y, max_x, max_y) {
+                    tiles.extend(tiles_in_metatile(zoom, meta_x, meta_y, metatile_size));
+                }
+            }
+        }
+    }
+    tiles
+}
+
+fn tiles_in_metatile(zoom: u8, meta_x: u32, meta_y: u32, metatile_size: u32) -> impl Iterator<Item = Tile> {
+    let zoom_size = 1u64 << zoom;
+    let tile_range = move |meta_coord: u32| {
+        let start = u64::from(meta_coord) * u64::from(metatile_size);
+        let end = std::cmp::min(start + u64::from(metatile_size), zoom_size);
+        (start as u32)..(end as u32)
+    };
+    tile_range(meta_x).flat_map(move |x| tile_range(meta_y).map(move |y| Tile { zoom, x, y }))
+}
+
+fn describe_job(options: &SeedOptions, total_tiles: u64) -> String {
+    // The metatiles change the order of the tiles.
+    let zoom_range = format!(
+        "z{}-{}{}",
+        options.min_zoom,
+        options.max_zoom,
+        if options.metatiles { " metatiles" } else { "" }
+    );
+    match &options.area {
+        SeedArea::Bounds(bounds) => format!("{} {}", zoom_range, bounds),
+        // The tile count guards against resuming after the expire list was appended to.
+        SeedArea::ExpiredList(path) => format!(
+            "{} expired {} ({} tiles)",
+            zoom_range,
+            path.to_string_lossy(),
+            total_tiles
+        ),
+        SeedArea::Polygon(polygon) => format!("{} polygon {} ({} tiles)", zoom_range, polygon.bounds(), total_tiles),
+    }
+}
+
+// Collects at least CHUNK_SIZE tiles (or whatever is left), never splitting a metatile between chunks.
+fn next_chunk<I: Iterator<Item = Tile>>(tiles: &mut Peekable<I>, metatile_size: u32) -> Vec<Tile> {
+    let mut chunk: Vec<Tile> = Vec::new();
+    while let Some(tile) = tiles.peek() {
+        if chunk.len() >= CHUNK_SIZE {
+            let last = &chunk[chunk.len() - 1];
+            let same_metatile = last.zoom == tile.zoom
+                && last.x / metatile_size == tile.x / metatile_size
+                && last.y / metatile_size == tile.y / metatile_size;
+            if !same_metatile {
+                break;
+            }
+        }
+        chunk.extend(tiles.next());
+    }
+    chunk
+}
+
+const CHUNK_SIZE: usize = 4096;
+
+// The size of the blocks of tiles that mod_tile bundles together, which is also a good size for drawing the tiles
+// at once: a bigger block takes too much memory, and the labels around a smaller one take too much time.
+const METATILE_SIZE: u32 = 8;
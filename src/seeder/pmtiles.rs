@@ -0,0 +1,376 @@
+use crate::coords::GeoBounds;
+use crate::progress::Progress;
+use crate::seeder::TileOutput;
+use crate::tile::Tile;
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use memmap2::MmapOptions;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// See https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md for the description of the format.
+//
+// Tiles arrive in the seeding order, so we append them to a temporary file first and only
+// assemble the archive (header, directories, metadata and the tile data sorted by tile ID)
+// when everything is rendered.
+pub(super) struct PmtilesOutput {
+    path: PathBuf,
+    tile_data_path: PathBuf,
+    tile_data: BufWriter<File>,
+    tile_data_len: u64,
+    entries: Vec<Entry>,
+    header_info: HeaderInfo,
+}
+
+struct HeaderInfo {
+    min_zoom: u8,
+    max_zoom: u8,
+    bounds: GeoBounds,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    // The number of consecutive tile IDs sharing the data. Zero means that the entry points to a leaf directory.
+    run_length: u32,
+}
+
+impl PmtilesOutput {
+    pub(super) fn create(path: &Path, min_zoom: u8, max_zoom: u8, bounds: &GeoBounds) -> Result<PmtilesOutput> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tile_data_path = path.with_extension("pmtiles.tmp");
+        let tile_data = File::create(&tile_data_path).context(format!(
+            "Failed to create temporary tile data file {}",
+            tile_data_path.to_string_lossy()
+        ))?;
+
+        Ok(PmtilesOutput {
+            path: path.to_path_buf(),
+            tile_data_path,
+            tile_data: BufWriter::new(tile_data),
+            tile_data_len: 0,
+            entries: Vec::new(),
+            header_info: HeaderInfo {
+                min_zoom,
+                max_zoom,
+                bounds: bounds.clone(),
+            },
+        })
+    }
+
+    fn write_archive(&mut self, progress: &Progress) -> Result<()> {
+        self.tile_data.flush()?;
+
+        let tile_data_file = File::open(&self.tile_data_path)?;
+        let tile_data = unsafe { MmapOptions::new().map(&tile_data_file)? };
+
+        // Cluster the tile data by tile ID, so that the tiles close to each other on the Hilbert curve
+        // are also close to each other in the archive. Identical tiles are stored only once, and runs
+        // of consecutive identical tiles share a single directory entry.
+        self.entries.sort_by_key(|e| e.tile_id);
+        let mut clustered_entries: Vec<Entry> = Vec::with_capacity(self.entries.len());
+        let mut unique_tiles = Vec::new();
+        let mut offsets_by_content = HashMap::new();
+        let mut clustered_offset = 0;
+        for entry in &self.entries {
+            let start = entry.offset as usize;
+            let content = &tile_data[start..start + entry.length as usize];
+
+            let offset = match offsets_by_content.get(content) {
+                Some(offset) => *offset,
+                None => {
+                    let offset = clustered_offset;
+                    offsets_by_content.insert(content, offset);
+                    unique_tiles.push(content);
+                    clustered_offset += u64::from(entry.length);
+                    offset
+                }
+            };
+
+            if let Some(last) = clustered_entries.last_mut() {
+                if last.offset == offset && last.tile_id + u64::from(last.run_length) == entry.tile_id {
+                    last.run_length += 1;
+                    continue;
+                }
+            }
+
+            clustered_entries.push(Entry {
+                offset,
+                ..entry.clone()
+            });
+        }
+
+        let (root_dir, leaf_dirs) = build_directories(&clustered_entries);
+        let metadata = self.header_info.metadata_json();
+
+        let root_dir_offset = HEADER_SIZE as u64;
+        let metadata_offset = root_dir_offset + root_dir.len() as u64;
+        let leaf_dirs_offset = metadata_offset + metadata.len() as u64;
+        let tile_data_offset = leaf_dirs_offset + leaf_dirs.len() as u64;
+
+        let tmp_path = self.path.with_extension("pmtiles.part");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+        writer.write_all(b"PMTiles")?;
+        writer.write_u8(3)?;
+        for (offset, length) in &[
+            (root_dir_offset, root_dir.len() as u64),
+            (metadata_offset, metadata.len() as u64),
+            (leaf_dirs_offset, leaf_dirs.len() as u64),
+            (tile_data_offset, clustered_offset),
+        ] {
+            writer.write_u64::<LittleEndian>(*offset)?;
+            writer.write_u64::<LittleEndian>(*length)?;
+        }
+        writer.write_u64::<LittleEndian>(self.entries.len() as u64)?; // Addressed tiles.
+        writer.write_u64::<LittleEndian>(clustered_entries.len() as u64)?;
+        writer.write_u64::<LittleEndian>(unique_tiles.len() as u64)?;
+        writer.write_u8(1)?; // Clustered.
+        writer.write_u8(COMPRESSION_NONE)?; // Internal compression.
+        writer.write_u8(COMPRESSION_NONE)?; // Tile compression.
+        writer.write_u8(TILE_TYPE_PNG)?;
+        self.header_info.write(&mut writer)?;
+
+        writer.write_all(&root_dir)?;
+        writer.write_all(&metadata)?;
+        writer.write_all(&leaf_dirs)?;
+        for content in &unique_tiles {
+            writer.write_all(content)?;
+        }
+
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        progress.message(&format!(
+            "Stored {} tiles in {}, {} of them unique",
+            self.entries.len(),
+            self.path.to_string_lossy(),
+            unique_tiles.len()
+        ));
+        Ok(())
+    }
+}
+
+impl TileOutput for PmtilesOutput {
+    // The archive is only assembled at the very end, so there's nothing to resume from.
+    fn checkpoint_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn is_fresh(&self, _tile: &Tile, _threshold: &SystemTime) -> bool {
+        false
+    }
+
+    fn write_tile(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        self.tile_data.write_all(data)?;
+        self.entries.push(Entry {
+            tile_id: tile_id(tile),
+            offset: self.tile_data_len,
+            length: data.len() as u32,
+            run_length: 1,
+        });
+        self.tile_data_len += data.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, progress: &Progress) -> Result<()> {
+        let res = self.write_archive(progress).context(format!(
+            "Failed to write PMTiles archive {}",
+            self.path.to_string_lossy()
+        ));
+        fs::remove_file(&self.tile_data_path)?;
+        res
+    }
+}
+
+impl HeaderInfo {
+    fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        let to_e7 = |c: f64| (c * 10_000_000.0).round() as i32;
+        let b = &self.bounds;
+
+        writer.write_u8(self.min_zoom)?;
+        writer.write_u8(self.max_zoom)?;
+        for c in &[b.min_lon, b.min_lat, b.max_lon, b.max_lat] {
+            writer.write_i32::<LittleEndian>(to_e7(*c))?;
+        }
+        writer.write_u8(self.min_zoom)?;
+        writer.write_i32::<LittleEndian>(to_e7((b.min_lon + b.max_lon) / 2.0))?;
+        writer.write_i32::<LittleEndian>(to_e7((b.min_lat + b.max_lat) / 2.0))?;
+        Ok(())
+    }
+
+    fn metadata_json(&self) -> Vec<u8> {
+        format!(
+            r#"{{"name":"osm-renderer","format":"png","type":"baselayer","minzoom":{},"maxzoom":{},"bounds":"{}"}}"#,
+            self.min_zoom, self.max_zoom, self.bounds
+        )
+        .into_bytes()
+    }
+}
+
+/// Maps a tile to its position on the Hilbert curve of its zoom level, offset by the number of tiles
+/// on all the previous zoom levels.
+fn tile_id(tile: &Tile) -> u64 {
+    let side = 1u64 << tile.zoom;
+    let previous_zooms_tile_count = (side * side - 1) / 3;
+
+    let (mut x, mut y) = (u64::from(tile.x), u64::from(tile.y));
+    let mut d = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+
+    previous_zooms_tile_count + d
+}
+
+// The root directory has to fit into the first 16 KB of the archive along with the header,
+// so large archives move most of the entries to leaf directories.
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let max_root_dir_size = 16384 - HEADER_SIZE;
+
+    let root_dir = serialize_directory(entries);
+    if root_dir.len() <= max_root_dir_size {
+        return (root_dir, Vec::new());
+    }
+
+    let mut leaf_size = std::cmp::max(4096, entries.len() / 3500);
+    loop {
+        let mut root_entries = Vec::new();
+        let mut leaf_dirs = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf_dir = serialize_directory(chunk);
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaf_dirs.len() as u64,
+                length: leaf_dir.len() as u32,
+                run_length: 0,
+            });
+            leaf_dirs.extend(leaf_dir);
+        }
+
+        let root_dir = serialize_directory(&root_entries);
+        if root_dir.len() <= max_root_dir_size {
+            return (root_dir, leaf_dirs);
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut result = Vec::new();
+    write_varint(&mut result, entries.len() as u64);
+
+    let mut last_tile_id = 0;
+    for entry in entries {
+        write_varint(&mut result, entry.tile_id - last_tile_id);
+        last_tile_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut result, u64::from(entry.run_length));
+    }
+    for entry in entries {
+        write_varint(&mut result, u64::from(entry.length));
+    }
+    for (idx, entry) in entries.iter().enumerate() {
+        // Zero means "right after the previous entry", which is the common case for clustered archives.
+        if idx > 0 && entry.offset == entries[idx - 1].offset + u64::from(entries[idx - 1].length) {
+            write_varint(&mut result, 0);
+        } else {
+            write_varint(&mut result, entry.offset + 1);
+        }
+    }
+
+    result
+}
+
+fn write_varint(result: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        result.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    result.push(value as u8);
+}
+
+const HEADER_SIZE: usize = 127;
+const COMPRESSION_NONE: u8 = 1;
+const TILE_TYPE_PNG: u8 = 2;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_id() {
+        let id = |zoom, x, y| tile_id(&Tile { zoom, x, y });
+        assert_eq!(id(0, 0, 0), 0);
+        assert_eq!(id(1, 0, 0), 1);
+        assert_eq!(id(1, 0, 1), 2);
+        assert_eq!(id(1, 1, 1), 3);
+        assert_eq!(id(1, 1, 0), 4);
+        assert_eq!(id(2, 0, 0), 5);
+        assert_eq!(id(3, 0, 0), 21);
+        assert_eq!(id(20, 0, 0), 366503875925);
+    }
+
+    #[test]
+    fn test_serialize_directory() {
+        let entries = vec![
+            Entry {
+                tile_id: 1,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 3,
+                offset: 10,
+                length: 300,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 4,
+                offset: 1000,
+                length: 5,
+                run_length: 2,
+            },
+        ];
+        assert_eq!(
+            serialize_directory(&entries),
+            vec![3, 1, 2, 1, 1, 1, 2, 10, 0xac, 0x02, 5, 1, 0, 0xe9, 0x07]
+        );
+    }
+
+    #[test]
+    fn test_large_directories_are_split_into_leaves() {
+        let entries = (0..100_000)
+            .map(|idx| Entry {
+                tile_id: idx * 2,
+                offset: idx * 100,
+                length: 100,
+                run_length: 1,
+            })
+            .collect::<Vec<_>>();
+        let (root_dir, leaf_dirs) = build_directories(&entries);
+        assert!(root_dir.len() <= 16384 - HEADER_SIZE);
+        assert!(!leaf_dirs.is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::coords::GeoBounds;
+use crate::tile::{coords_to_xy, TILE_SIZE};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+/// An area to seed made of polygons with holes, e.g. the boundary of a city, read from a GeoJSON file.
+/// The tiles that intersect any of the polygons are seeded.
+#[derive(Clone, Debug)]
+pub struct SeedPolygon {
+    bounds: GeoBounds,
+    // Every polygon is its outer ring followed by its holes, with the points in Web Mercator pixels at zoom 0.
+    polygons: Vec<Vec<Vec<(f64, f64)>>>,
+}
+
+impl SeedPolygon {
+    pub fn load(path: &Path) -> Result<SeedPolygon> {
+        let path_str = path.to_string_lossy();
+        let json = fs::read_to_string(path).context(format!("Failed to read {}", path_str))?;
+        SeedPolygon::from_geojson(&json).context(format!("Failed to read the polygons from {}", path_str))
+    }
+
+    /// Takes a `Polygon` or a `MultiPolygon`, as a bare geometry, a `Feature` or a `FeatureCollection`
+    /// (the features with other geometries are skipped).
+    pub fn from_geojson(json: &str) -> Result<SeedPolygon> {
+        let mut chars = json.chars().peekable();
+        let value = read_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next().is_some() {
+            bail!("Unexpected data after the end of the GeoJSON");
+        }
+
+        let mut polygons = Vec::new();
+        collect_polygons(&value, &mut polygons)?;
+        if polygons.is_empty() {
+            bail!("No polygons found");
+        }
+
+        let mut bounds = GeoBounds {
+            min_lon: f64::MAX,
+            min_lat: f64::MAX,
+            max_lon: f64::MIN,
+            max_lat: f64::MIN,
+        };
+        for &(lon, lat) in polygons.iter().flatten().flatten() {
+            bounds.min_lon = bounds.min_lon.min(lon);
+            bounds.min_lat = bounds.min_lat.min(lat);
+            bounds.max_lon = bounds.max_lon.max(lon);
+            bounds.max_lat = bounds.max_lat.max(lat);
+        }
+        let polygons = polygons
+            .iter()
+            .map(|rings| {
+                rings
+                    .iter()
+                    .map(|ring| ring.iter().map(|&(lon, lat)| coords_to_xy(&(lat, lon), 0)).collect())
+                    .collect()
+            })
+            .collect();
+        Ok(SeedPolygon { bounds, polygons })
+    }
+
+    /// The bounding box of all the polygons.
+    pub fn bounds(&self) -> &GeoBounds {
+        &self.bounds
+    }
+
+    /// Whether any of the polygons intersects the block of tiles from `min_x`, `min_y` to `max_x`, `max_y`
+    /// (inclusive) at the zoom level.
+    pub(super) fn intersects_tiles(&self, zoom: u8, min_x: u32, min_y: u32, max_x: u32, max_y: u32) -> bool {
+        let tile_size = f64::from(TILE_SIZE) / f64::from(1u32 << zoom);
+        let rect = (
+            f64::from(min_x) * tile_size,
+            f64::from(min_y) * tile_size,
+            f64::from(max_x + 1) * tile_size,
+            f64::from(max_y + 1) * tile_size,
+        );
+        let corner = (rect.0, rect.1);
+        self.polygons.iter().any(|rings| {
+            // Either an edge crosses the block, or the block is entirely inside the polygon.
+            let crosses_edge = rings.iter().any(|ring| {
+                ring.iter()
+                    .zip(ring.iter().cycle().skip(1))
+                    .any(|(from, to)| segment_intersects_rect(*from, *to, rect))
+            });
+            crosses_edge
+                || (is_point_in_ring(corner, &rings[0])
+                    && !rings[1..].iter().any(|hole| is_point_in_ring(corner, hole)))
+        })
+    }
+}
+
+fn collect_polygons(value: &Json, polygons: &mut Vec<Vec<Vec<(f64, f64)>>>) -> Result<()> {
+    let geometry_type = match value.get("type") {
+        Some(Json::String(geometry_type)) => geometry_type.as_str(),
+        _ => bail!("A GeoJSON object without a type"),
+    };
+    match geometry_type {
+        "FeatureCollection" => match value.get("features") {
+            Some(Json::Array(features)) => features
+                .iter()
+                .try_for_each(|feature| collect_polygons(feature, polygons)),
+            _ => bail!("A feature collection without features"),
+        },
+        "Feature" => match value.get("geometry") {
+            Some(Json::Null) | None => Ok(()),
+            Some(geometry) => collect_polygons(geometry, polygons),
+        },
+        "Polygon" => {
+            polygons.push(read_polygon(value.get("coordinates"))?);
+            Ok(())
+        }
+        "MultiPolygon" => match value.get("coordinates") {
+            Some(Json::Array(coordinates)) => {
+                for polygon in coordinates {
+                    polygons.push(read_polygon(Some(polygon))?);
+                }
+                Ok(())
+            }
+            _ => bail!("Invalid multipolygon coordinates"),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn read_polygon(coordinates: Option<&Json>) -> Result<Vec<Vec<(f64, f64)>>> {
+    let rings = match coordinates {
+        Some(Json::Array(rings)) if !rings.is_empty() => rings,
+        _ => bail!("Invalid polygon coordinates"),
+    };
+    rings
+        .iter()
+        .map(|ring| match ring {
+            Json::Array(positions) => positions
+                .iter()
+                .map(|position| match position {
+                    Json::Array(position) => match position.as_slice() {
+                        [Json::Number(lon), Json::Number(lat), ..] => Ok((*lon, *lat)),
+                        _ => bail!("Invalid position"),
+                    },
+                    _ => bail!("Invalid position"),
+                })
+                .collect(),
+            _ => bail!("Invalid polygon ring"),
+        })
+        .collect()
+}
+
+// Clips the segment to the rectangle (min_x, min_y, max_x, max_y) the same way as Liang-Barsky does.
+fn segment_intersects_rect(from: (f64, f64), to: (f64, f64), rect: (f64, f64, f64, f64)) -> bool {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let (mut t_min, mut t_max) = (0.0f64, 1.0f64);
+    for (p, q) in [
+        (-dx, from.0 - rect.0),
+        (dx, rect.2 - from.0),
+        (-dy, from.1 - rect.1),
+        (dy, rect.3 - from.1),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+        } else if p < 0.0 {
+            t_min = t_min.max(q / p);
+        } else {
+            t_max = t_max.min(q / p);
+        }
+    }
+    t_min <= t_max
+}
+
+fn is_point_in_ring(point: (f64, f64), ring: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.1 > point.1) != (b.1 > point.1) && point.0 < a.0 + (point.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// Only as much of JSON as GeoJSON needs.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+fn read_value(chars: &mut Peekable<Chars<'_>>) -> Result<Json> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let mut members = Vec::new();
+            read_items(chars, '}', |chars| {
+                skip_whitespace(chars);
+                let key = read_string(chars)?;
+                expect_char(chars, ':')?;
+                members.push((key, read_value(chars)?));
+                Ok(())
+            })?;
+            Ok(Json::Object(members))
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            read_items(chars, ']', |chars| {
+                items.push(read_value(chars)?);
+                Ok(())
+            })?;
+            Ok(Json::Array(items))
+        }
+        Some('"') => Ok(Json::String(read_string(chars)?)),
+        Some(ch) if ch.is_ascii_alphabetic() => {
+            let mut word = String::new();
+            while let Some(ch) = chars.next_if(|ch| ch.is_ascii_alphabetic()) {
+                word.push(ch);
+            }
+            match word.as_str() {
+                "null" => Ok(Json::Null),
+                "true" | "false" => Ok(Json::Bool),
+                _ => bail!("Unexpected {}", word),
+            }
+        }
+        _ => {
+            let mut number = String::new();
+            while let Some(ch) = chars.next_if(|ch| ch.is_ascii_digit() || "+-.eE".contains(*ch)) {
+                number.push(ch);
+            }
+            Ok(Json::Number(
+                number.parse().context(format!("Invalid number: {:?}", number))?,
+            ))
+        }
+    }
+}
+
+// The comma-separated items of an object or an array, after the opening bracket.
+fn read_items<F>(chars: &mut Peekable<Chars<'_>>, closing: char, mut read_item: F) -> Result<()>
+where
+    F: FnMut(&mut Peekable<Chars<'_>>) -> Result<()>,
+{
+    skip_whitespace(chars);
+    if chars.next_if_eq(&closing).is_some() {
+        return Ok(());
+    }
+    loop {
+        read_item(chars)?;
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => {}
+            Some(ch) if ch == closing => return Ok(()),
+            _ => bail!("Expected ',' or '{}'", closing),
+        }
+    }
+}
+
+fn read_string(chars: &mut Peekable<Chars<'_>>) -> Result<String> {
+    expect_char(chars, '"')?;
+    let mut result = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(result),
+            Some('\\') => match chars.next() {
+                Some('u') => {
+                    let code = chars.by_ref().take(4).collect::<String>();
+                    let ch = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32);
+                    // The surrogate pairs can only be in the names and the properties, which we don't need.
+                    result.push(ch.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('b') => result.push('\u{8}'),
+                Some('f') => result.push('\u{c}'),
+                Some(ch @ ('"' | '\\' | '/')) => result.push(ch),
+                _ => bail!("Unsupported escape sequence"),
+            },
+            Some(ch) => result.push(ch),
+            None => bail!("Unterminated string"),
+        }
+    }
+}
+
+fn expect_char(chars: &mut Peekable<Chars<'_>>, expected: char) -> Result<()> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(ch) if ch == expected => Ok(()),
+        _ => bail!("Expected '{}'", expected),
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars<'_>>) {
+    while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geojson() {
+        let polygon = SeedPolygon::from_geojson(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "Point \"A\"", "visible": true}, "geometry": {"type": "Point", "coordinates": [1, 2]}},
+                {"type": "Feature", "properties": null, "geometry": {
+                    "type": "MultiPolygon",
+                    "coordinates": [[[[37.5, 55.7], [37.7, 55.7], [37.7, 55.8], [37.5, 55.8], [37.5, 55.7]]]]
+                }}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(polygon.bounds().to_string(), "37.5,55.7,37.7,55.8");
+        assert_eq!(polygon.polygons.len(), 1);
+
+        assert!(SeedPolygon::from_geojson(r#"{"type": "Point", "coordinates": [1, 2]}"#).is_err());
+        assert!(SeedPolygon::from_geojson(r#"{"type": "Polygon", "coordinates": [[[1, 2]]]"#).is_err());
+        assert!(SeedPolygon::from_geojson(r#"{"type": "Polygon", "coordinates": [["1, 2"]]}"#).is_err());
+    }
+
+    #[test]
+    fn test_intersects_tiles() {
+        // A square a bit smaller than the tiles from 1,1 to 2,2 at zoom 3, with a hole in the middle
+        // of the tile 2,2, given in pixels at zoom 0.
+        let square = |min: f64, max: f64| vec![(min, min), (max, min), (max, max), (min, max)];
+        let polygon = SeedPolygon {
+            bounds: GeoBounds {
+                min_lon: 0.0,
+                min_lat: 0.0,
+                max_lon: 0.0,
+                max_lat: 0.0,
+            },
+            polygons: vec![vec![square(33.0, 95.0), square(70.0, 90.0)]],
+        };
+        assert!(polygon.intersects_tiles(3, 1, 1, 1, 1));
+        assert!(polygon.intersects_tiles(3, 0, 0, 7, 7));
+        assert!(!polygon.intersects_tiles(3, 0, 0, 0, 7));
+        assert!(!polygon.intersects_tiles(3, 3, 3, 3, 3));
+        // Inside the hole.
+        assert!(!polygon.intersects_tiles(6, 18, 18, 21, 21));
+        assert!(polygon.intersects_tiles(6, 17, 17, 17, 17));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::draw::drawer::Drawer;
+use crate::draw::tile_pixels::TilePixels;
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::styler::Styler;
+use crate::seeder::{SolidTileCache, METATILE_SIZE};
+use crate::tile::{Tile, TILE_SIZE};
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// A group of tiles that is rendered by a single worker and handed over to the output at once.
+pub(super) struct Batch {
+    tiles: Vec<Tile>,
+    density: usize,
+}
+
+pub(super) struct RenderContext<'a> {
+    pub(super) reader: &'a GeodataReader<'a>,
+    pub(super) drawer: &'a Drawer,
+    pub(super) styler: &'a Styler,
+    // The batches are whole metatiles then (see `make_batches`).
+    pub(super) draw_metatiles: bool,
+}
+
+/// Splits the tiles into batches and orders them so that the densest (and therefore slowest) ones
+/// are rendered first. This way the workers don't end up waiting for a single big city tile
+/// at the very end of the run.
+///
+/// Tiles of the same metatile always go to the same batch. Tiles without any data around them only
+/// need the background to be drawn, so they are grouped together to keep the per-batch overhead low.
+pub(super) fn make_batches(tiles: Vec<Tile>, metatile_size: u32, reader: &GeodataReader<'_>) -> Vec<Batch> {
+    let mut batches: Vec<Batch> = Vec::new();
+    let mut empty_tiles = Vec::new();
+
+    let same_metatile = |a: &Tile, b: &Tile| {
+        a.zoom == b.zoom && a.x / metatile_size == b.x / metatile_size && a.y / metatile_size == b.y / metatile_size
+    };
+
+    for tile in tiles {
+        let density = reader.estimate_tile_density(&tile);
+
+        if metatile_size > 1 {
+            match batches.last_mut() {
+                Some(batch) if same_metatile(&batch.tiles[0], &tile) => {
+                    batch.tiles.push(tile);
+                    batch.density += density;
+                }
+                _ => batches.push(Batch {
+                    tiles: vec![tile],
+                    density,
+                }),
+            }
+        } else if density == 0 {
+            empty_tiles.push(tile);
+        } else {
+            batches.push(Batch {
+                tiles: vec![tile],
+                density,
+            });
+        }
+    }
+
+    let mut empty_tiles = empty_tiles.into_iter().peekable();
+    while empty_tiles.peek().is_some() {
+        batches.push(Batch {
+            tiles: empty_tiles.by_ref().take(EMPTY_TILE_BATCH_SIZE).collect(),
+            density: 0,
+        });
+    }
+
+    batches.sort_by_key(|b| Reverse(b.density));
+    batches
+}
+
+/// Renders the batches on `thread_count` threads and passes each rendered batch to `on_rendered`
+/// on the calling thread, so that the output doesn't have to be thread-safe.
+pub(super) fn render_batches<F>(
+    batches: &[Batch],
+    thread_count: usize,
+    ctx: &RenderContext<'_>,
+    mut on_rendered: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<(Tile, Vec<u8>)>) -> Result<()>,
+{
+    let next_batch = AtomicUsize::new(0);
+    let aborted = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        // The channel is bounded, so that slow output doesn't make us keep all rendered tiles in memory.
+        let (tx, rx) = mpsc::sync_channel(thread_count);
+
+        for _ in 0..thread_count {
+            let tx = tx.clone();
+            let (next_batch, aborted) = (&next_batch, &aborted);
+            scope.spawn(move || {
+                let mut pixels = TilePixels::new(1);
+                let mut solid_tiles = SolidTileCache::default();
+
+                while !aborted.load(Ordering::Relaxed) {
+                    let batch = match batches.get(next_batch.fetch_add(1, Ordering::Relaxed)) {
+                        Some(batch) => batch,
+                        None => break,
+                    };
+                    let rendered = render_batch(batch, ctx, &mut pixels, &mut solid_tiles);
+                    if tx.send(rendered).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let res = rx.iter().try_for_each(|rendered| on_rendered(rendered?));
+        if res.is_err() {
+            aborted.store(true, Ordering::Relaxed);
+            // Unblock the workers waiting for the channel to free up.
+            drop(rx);
+        }
+        res
+    })
+}
+
+fn render_batch(
+    batch: &Batch,
+    ctx: &RenderContext<'_>,
+    pixels: &mut TilePixels,
+    solid_tiles: &mut SolidTileCache,
+) -> Result<Vec<(Tile, Vec<u8>)>> {
+    let mut rendered = Vec::with_capacity(batch.tiles.len());
+    if ctx.draw_metatiles {
+        // Some tiles of the metatile might be missing from the batch (e.g. because they are fresh).
+        let (metatile, size) = batch.tiles[0].metatile(METATILE_SIZE);
+        if pixels.dimension() != size as usize * TILE_SIZE as usize {
+            *pixels = TilePixels::for_metatile(size as usize, 1);
+        }
+        let entities = ctx.reader.get_entities_in_tile_with_neighbors(&metatile, &None);
+        for (tile, rendered_pixels) in ctx
+            .drawer
+            .draw_metatile_to_pixels(&entities, &metatile, pixels, 1, ctx.styler)
+        {
+            if batch.tiles.contains(&tile) {
+                rendered.push((tile, solid_tiles.encode(&rendered_pixels)?));
+            }
+        }
+        return Ok(rendered);
+    }
+    for tile in &batch.tiles {
+        let entities = ctx.reader.get_entities_in_tile_with_neighbors(tile, &None);
+        let rendered_pixels = ctx.drawer.draw_to_pixels(&entities, tile, pixels, 1, ctx.styler);
+        rendered.push((tile.clone(), solid_tiles.encode(&rendered_pixels)?));
+    }
+    Ok(rendered)
+}
+
+const EMPTY_TILE_BATCH_SIZE: usize = 256;
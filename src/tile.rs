@@ -1,17 +1,180 @@
-use crate::coords::Coords;
+use crate::coords::{Coords, GeoBounds};
+use anyhow::{bail, Context, Result};
 
 use std::f64::consts::PI;
+use std::str::FromStr;
 
-pub const MAX_ZOOM: u8 = 23;
+pub const MAX_ZOOM: u8 = 18;
+// The tiles of the further zoom levels are drawn from the geodata of the `MAX_ZOOM` tile that covers them.
+pub const MAX_OVERZOOM: u8 = 22;
 pub const TILE_SIZE: u32 = 256;
+// The tiles can be up to 4 times bigger than `TILE_SIZE` (see `zoom_offset_for_tile_size`).
+const MAX_TILE_SIZE_ZOOM_OFFSET: u8 = 2;
+// In meters, with the radius of the earth that Web Mercator uses.
+const EQUATOR_LENGTH: f64 = 2.0 * PI * 6_378_137.0;
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Tile {
     pub zoom: u8,
     pub x: u32,
     pub y: u32,
 }
 
+impl Tile {
+    /// Return the tile of the previous zoom level that covers this one.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile { zoom: 18, x: 158333, y: 81957 }.parent(), Some(Tile { zoom: 17, x: 79166, y: 40978 }));
+    /// assert_eq!(Tile { zoom: 1, x: 1, y: 0 }.parent(), Some(Tile { zoom: 0, x: 0, y: 0 }));
+    /// assert_eq!(Tile { zoom: 0, x: 0, y: 0 }.parent(), None);
+    /// ```
+    pub fn parent(&self) -> Option<Tile> {
+        if self.zoom == 0 {
+            return None;
+        }
+        Some(Tile {
+            zoom: self.zoom - 1,
+            x: self.x / 2,
+            y: self.y / 2,
+        })
+    }
+
+    /// Return the top left tile among the ones that cover this one `levels` zoom levels further.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile { zoom: 17, x: 79166, y: 40978 }.top_left_descendant(1), Tile { zoom: 18, x: 158332, y: 81956 });
+    /// assert_eq!(Tile { zoom: 0, x: 0, y: 0 }.top_left_descendant(2), Tile { zoom: 2, x: 0, y: 0 });
+    /// assert_eq!(Tile { zoom: 3, x: 1, y: 2 }.top_left_descendant(0), Tile { zoom: 3, x: 1, y: 2 });
+    /// ```
+    pub fn top_left_descendant(&self, levels: u8) -> Tile {
+        Tile {
+            zoom: self.zoom + levels,
+            x: self.x << levels,
+            y: self.y << levels,
+        }
+    }
+
+    /// Return the tile of a lower zoom level that covers the `size`×`size` block of tiles with this one
+    /// (or the whole zoom level, if it has fewer tiles), together with the number of tiles along a side of the block.
+    /// `size` has to be a power of two.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile { zoom: 15, x: 19805, y: 10244 }.metatile(8), (Tile { zoom: 12, x: 2475, y: 1280 }, 8));
+    /// assert_eq!(Tile { zoom: 1, x: 1, y: 0 }.metatile(8), (Tile { zoom: 0, x: 0, y: 0 }, 2));
+    /// assert_eq!(Tile { zoom: 3, x: 1, y: 2 }.metatile(1), (Tile { zoom: 3, x: 1, y: 2 }, 1));
+    /// ```
+    pub fn metatile(&self, size: u32) -> (Tile, u32) {
+        let levels = (size.trailing_zeros() as u8).min(self.zoom);
+        let metatile = Tile {
+            zoom: self.zoom - levels,
+            x: self.x >> levels,
+            y: self.y >> levels,
+        };
+        (metatile, 1 << levels)
+    }
+
+    /// Return the quadkey of the tile, as in the Bing Maps tile system: a digit per zoom level, with the bits of `x`
+    /// and `y` of that level interleaved. The quadkey of the tile of zoom level 0 is empty.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile { zoom: 3, x: 3, y: 5 }.to_quadkey(), "213");
+    /// assert_eq!(Tile { zoom: 15, x: 19805, y: 10244 }.to_quadkey(), "120310101011301");
+    /// assert_eq!(Tile { zoom: 0, x: 0, y: 0 }.to_quadkey(), "");
+    /// ```
+    pub fn to_quadkey(&self) -> String {
+        (1..=self.zoom)
+            .rev()
+            .map(|level| {
+                let mask = 1 << (level - 1);
+                let digit = u8::from(self.x & mask != 0) + 2 * u8::from(self.y & mask != 0);
+                char::from(b'0' + digit)
+            })
+            .collect()
+    }
+
+    /// The inverse of `to_quadkey`.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile::from_quadkey("213").unwrap(), Tile { zoom: 3, x: 3, y: 5 });
+    /// assert_eq!(Tile::from_quadkey("").unwrap(), Tile { zoom: 0, x: 0, y: 0 });
+    /// assert!(Tile::from_quadkey("124").is_err());
+    /// assert!(Tile::from_quadkey(&"0".repeat(33)).is_err());
+    /// ```
+    pub fn from_quadkey(quadkey: &str) -> Result<Tile> {
+        // The coordinates of the further zoom levels don't fit into `u32`.
+        if quadkey.len() >= u32::BITS as usize {
+            bail!("The quadkey {} is too long", quadkey);
+        }
+        let mut tile = Tile { zoom: 0, x: 0, y: 0 };
+        for digit in quadkey.bytes() {
+            if !(b'0'..=b'3').contains(&digit) {
+                bail!("Invalid quadkey: {}", quadkey);
+            }
+            let digit = u32::from(digit - b'0');
+            tile = Tile {
+                zoom: tile.zoom + 1,
+                x: tile.x << 1 | digit & 1,
+                y: tile.y << 1 | digit >> 1,
+            };
+        }
+        Ok(tile)
+    }
+}
+
+/// The tiles that are bigger than `TILE_SIZE` (e.g. the 512×512 tiles that MapLibre uses) cover the same area
+/// as the usual tiles with the same coordinates, but show as much detail as the usual tiles a few zoom levels
+/// further, i.e. a 512×512 tile looks exactly like the 2×2 block of the usual tiles of the next zoom level.
+/// Returns the number of these extra zoom levels, or `None` if the size isn't supported.
+/// # Examples
+/// ```
+/// use renderer::tile::zoom_offset_for_tile_size;
+/// assert_eq!(zoom_offset_for_tile_size(256), Some(0));
+/// assert_eq!(zoom_offset_for_tile_size(512), Some(1));
+/// assert_eq!(zoom_offset_for_tile_size(1024), Some(2));
+/// assert_eq!(zoom_offset_for_tile_size(384), None);
+/// assert_eq!(zoom_offset_for_tile_size(2048), None);
+/// ```
+pub fn zoom_offset_for_tile_size(tile_size: u32) -> Option<u8> {
+    (0..=MAX_TILE_SIZE_ZOOM_OFFSET).find(|offset| TILE_SIZE << offset == tile_size)
+}
+
+/// Parses a tile in the `z/x/y` format used by expire lists.
+/// # Examples
+/// ```
+/// use renderer::tile::Tile;
+/// assert_eq!("15/19805/10244".parse::<Tile>().unwrap(), Tile { zoom: 15, x: 19805, y: 10244 });
+/// assert!("2/4/0".parse::<Tile>().is_err());
+/// assert!("15/19805".parse::<Tile>().is_err());
+/// ```
+impl FromStr for Tile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Tile> {
+        let parts = s.trim().split('/').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            bail!("Expected a tile in the z/x/y format, got {}", s);
+        }
+
+        let zoom = parts[0].parse::<u8>().context(format!("Invalid zoom in {}", s))?;
+        let x = parts[1].parse::<u32>().context(format!("Invalid x in {}", s))?;
+        let y = parts[2].parse::<u32>().context(format!("Invalid y in {}", s))?;
+
+        if zoom > MAX_ZOOM {
+            bail!("Zoom {} in {} is larger than {}", zoom, s, MAX_ZOOM);
+        }
+        if x >= (1 << zoom) || y >= (1 << zoom) {
+            bail!("Tile {} is outside of the zoom level bounds", s);
+        }
+
+        Ok(Tile { zoom, x, y })
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct TileRange {
     pub min_x: u32,
@@ -28,15 +191,33 @@ pub struct TileRange {
 /// assert_eq!(coords_to_max_zoom_tile(&(-35.306536f64, 149.126545f64)), Tile { zoom: 18, x: 239662, y: 158582 });
 /// ```
 pub fn coords_to_max_zoom_tile<C: Coords>(coords: &C) -> Tile {
-    let (x, y) = coords_to_xy(coords, MAX_ZOOM);
-    let tile_index = |t| (t as u32) / TILE_SIZE;
+    coords_to_tile(coords, MAX_ZOOM)
+}
+
+/// Return the tile of a given zoom level that contains a given geopoint. The points on the antimeridian
+/// and beyond the latitudes covered by Web Mercator belong to the outermost tiles.
+/// # Examples
+/// ```
+/// use renderer::tile::{coords_to_tile,Tile};
+/// assert_eq!(coords_to_tile(&(55.747764f64, 37.437745f64), 15), Tile { zoom: 15, x: 19791, y: 10244 });
+/// assert_eq!(coords_to_tile(&(0f64, 180f64), 2), Tile { zoom: 2, x: 3, y: 2 });
+/// assert_eq!(coords_to_tile(&(89f64, -180f64), 2), Tile { zoom: 2, x: 0, y: 0 });
+/// assert_eq!(coords_to_tile(&(-89f64, 0f64), 2), Tile { zoom: 2, x: 2, y: 3 });
+/// ```
+pub fn coords_to_tile<C: Coords>(coords: &C, zoom: u8) -> Tile {
+    let (x, y) = coords_to_xy(coords, zoom);
     Tile {
-        zoom: MAX_ZOOM,
-        x: tile_index(x),
-        y: tile_index(y),
+        zoom,
+        x: pixel_to_tile_index(x, zoom),
+        y: pixel_to_tile_index(y, zoom),
     }
 }
 
+fn pixel_to_tile_index(c: f64, zoom: u8) -> u32 {
+    let max_index = f64::from((1u32 << zoom) - 1);
+    (c / f64::from(TILE_SIZE)).floor().clamp(0.0, max_index) as u32
+}
+
 /// Return the range of all smallest tiles that are covered by a given tile.
 /// # Examples
 /// ```
@@ -59,8 +240,25 @@ pub fn coords_to_max_zoom_tile<C: Coords>(coords: &C) -> Tile {
 ///     min_y: 158582,
 ///     max_y: 158582,
 /// });
+/// assert_eq!(tile_to_max_zoom_tile_range(&Tile { zoom: 20, x: 958651, y: 634331 }), TileRange {
+///     min_x: 239662,
+///     max_x: 239662,
+///     min_y: 158582,
+///     max_y: 158582,
+/// });
 /// ```
 pub fn tile_to_max_zoom_tile_range(tile: &Tile) -> TileRange {
+    // An overzoomed tile is a part of a single max zoom tile.
+    if tile.zoom > MAX_ZOOM {
+        let shrink = |x| x >> (tile.zoom - MAX_ZOOM);
+        let (x, y) = (shrink(tile.x), shrink(tile.y));
+        return TileRange {
+            min_x: x,
+            max_x: x,
+            min_y: y,
+            max_y: y,
+        };
+    }
     let blow_up = |x| x * (1 << (MAX_ZOOM - tile.zoom));
     let (min_x, min_y) = (blow_up(tile.x), blow_up(tile.y));
     let delta = blow_up(1) - 1;
@@ -72,6 +270,41 @@ pub fn tile_to_max_zoom_tile_range(tile: &Tile) -> TileRange {
     }
 }
 
+/// Return the range of all tiles of a given zoom level that intersect with given geographic bounds.
+/// # Examples
+/// ```
+/// use renderer::coords::GeoBounds;
+/// use renderer::tile::{bounds_to_tile_range,TileRange};
+/// let moscow = "37.608505,55.750717,37.619706,55.756187".parse::<GeoBounds>().unwrap();
+/// assert_eq!(bounds_to_tile_range(&moscow, 0), TileRange { min_x: 0, max_x: 0, min_y: 0, max_y: 0 });
+/// assert_eq!(bounds_to_tile_range(&moscow, 18), TileRange {
+///     min_x: 158457,
+///     max_x: 158465,
+///     min_y: 81946,
+///     max_y: 81953,
+/// });
+/// let world = "-180,-85.1,180,85.1".parse::<GeoBounds>().unwrap();
+/// assert_eq!(bounds_to_tile_range(&world, 3), TileRange { min_x: 0, max_x: 7, min_y: 0, max_y: 7 });
+/// ```
+pub fn bounds_to_tile_range(bounds: &GeoBounds, zoom: u8) -> TileRange {
+    let (min_x, min_y) = coords_to_xy(&(bounds.max_lat, bounds.min_lon), zoom);
+    let (max_x, max_y) = coords_to_xy(&(bounds.min_lat, bounds.max_lon), zoom);
+
+    // The bounds that end exactly at a tile edge don't intersect with the tile after the edge.
+    let (min_x, min_y) = (pixel_to_tile_index(min_x, zoom), pixel_to_tile_index(min_y, zoom));
+    let max_tile_index = |c: f64, min_index: u32| {
+        let last_pixel = (c.ceil() - 1.0).max(0.0);
+        pixel_to_tile_index(last_pixel, zoom).max(min_index)
+    };
+
+    TileRange {
+        min_x,
+        max_x: max_tile_index(max_x, min_x),
+        min_y,
+        max_y: max_tile_index(max_y, min_y),
+    }
+}
+
 /// Projects a given geopoint to Web Mercator coordinates for a given zoom level.
 /// # Examples
 /// ```
@@ -93,14 +326,61 @@ pub fn coords_to_xy<C: Coords>(coords: &C, zoom: u8) -> (f64, f64) {
 
     let rescale = |x: f64| {
         let factor = x / (2f64 * PI);
-        let dimension_in_pixels = f64::from(TILE_SIZE * (1 << zoom));
-        factor * dimension_in_pixels
+        factor * dimension_in_pixels(zoom)
     };
 
     (rescale(x), rescale(y))
 }
 
+/// The inverse of `coords_to_xy`: returns the (lat, lon) of a point given in Web Mercator coordinates.
+/// # Examples
+/// ```
+/// use renderer::tile::xy_to_coords;
+/// let (lat, lon) = xy_to_coords(40533333.5, 20981065.5, 18);
+/// assert!((lat - 55.747764).abs() < 1e-5 && (lon - 37.437745).abs() < 1e-5);
+/// assert_eq!(xy_to_coords(128.0, 128.0, 0), (0.0, 0.0));
+/// ```
+pub fn xy_to_coords(x: f64, y: f64, zoom: u8) -> (f64, f64) {
+    let dimension_in_pixels = dimension_in_pixels(zoom);
+    let lon_rad = x / dimension_in_pixels * 2f64 * PI - PI;
+    let lat_rad = 2f64 * (PI - y / dimension_in_pixels * 2f64 * PI).exp().atan() - PI / 2f64;
+    (lat_rad.to_degrees(), lon_rad.to_degrees())
+}
+
 pub fn coords_to_xy_tile_relative<C: Coords>(coords: &C, tile: &Tile) -> (f64, f64) {
     let (x, y) = coords_to_xy(coords, tile.zoom);
-    (x - f64::from(tile.x * TILE_SIZE), y - f64::from(tile.y * TILE_SIZE))
+    let tile_size = f64::from(TILE_SIZE);
+    (x - f64::from(tile.x) * tile_size, y - f64::from(tile.y) * tile_size)
+}
+
+/// How many pixels of the tile (without the scale) a meter on the ground takes in the middle of the tile.
+/// The Web Mercator projection stretches everything by `1 / cos(latitude)`, so it's more the further from the equator.
+/// # Examples
+/// ```
+/// use renderer::tile::{pixels_per_meter, Tile};
+/// let at_equator = pixels_per_meter(&Tile { zoom: 17, x: 65536, y: 65535 });
+/// assert!((at_equator - 1.0 / 1.194).abs() < 1e-3);
+/// let in_moscow = pixels_per_meter(&Tile { zoom: 17, x: 79166, y: 40978 });
+/// assert!((in_moscow / at_equator - 1.0 / 55.75f64.to_radians().cos()).abs() < 1e-3);
+/// ```
+pub fn pixels_per_meter(tile: &Tile) -> f64 {
+    let tile_size = f64::from(TILE_SIZE);
+    let center = |c: u32| (f64::from(c) + 0.5) * tile_size;
+    let (lat, _) = xy_to_coords(center(tile.x), center(tile.y), tile.zoom);
+    dimension_in_pixels(tile.zoom) / (EQUATOR_LENGTH * lat.to_radians().cos())
+}
+
+// The overzoomed tiles (especially the bigger ones, which are drawn a couple of zoom levels further)
+// don't fit into `u32` pixels.
+fn dimension_in_pixels(zoom: u8) -> f64 {
+    f64::from(TILE_SIZE) * f64::from(1u32 << zoom)
+}
+
+/// Same as `coords_to_xy_tile_relative`, but rounded to the pixel grid of the tile drawn at the given scale.
+/// The rounding doesn't depend on the tile, so that a point that is drawn in several neighboring tiles
+/// ends up at the same pixel in all of them, including the tiles where its coordinates are negative.
+pub fn coords_to_pixel_tile_relative<C: Coords>(coords: &C, tile: &Tile, scale: f64) -> (i32, i32) {
+    let (x, y) = coords_to_xy_tile_relative(coords, tile);
+    let to_pixel = |c: f64| (c * scale + 0.5).floor() as i32;
+    (to_pixel(x), to_pixel(y))
 }
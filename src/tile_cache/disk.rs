@@ -0,0 +1,232 @@
+use crate::tile_cache::lru::LruIndex;
+use crate::tile_cache::{KeyLayout, TileCache};
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const TEMP_FILE_EXTENSION: &str = "tmp";
+
+pub struct DiskConfig {
+    pub directory: PathBuf,
+    /// The tiles that were used the longest time ago are deleted to keep the cache within this size.
+    pub max_bytes: u64,
+}
+
+/// The tiles are named after the versions of the style and of the data by default, so that neither a new style
+/// nor a new import has the old tiles served: they are simply never used again and are the first to go.
+pub fn default_layout() -> KeyLayout {
+    KeyLayout::versioned()
+}
+
+/// A tile cache in a local directory, with every tile in a file of its own. The sizes and the order of use of
+/// the files are kept in memory, and the modification time of a file is updated whenever it's used, so that
+/// the order survives a restart.
+pub struct DiskCache {
+    config: DiskConfig,
+    index: Mutex<LruIndex>,
+    next_temp_file_id: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn new(config: DiskConfig) -> Result<DiskCache> {
+        let directory_str = config.directory.to_string_lossy().to_string();
+        fs::create_dir_all(&config.directory)
+            .context(format!("Failed to create the tile cache directory {}", directory_str))?;
+
+        let mut files = Vec::new();
+        find_files(&config.directory, &mut files)
+            .context(format!("Failed to read the tile cache directory {}", directory_str))?;
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let cache = DiskCache {
+            config,
+            index: Mutex::new(LruIndex::default()),
+            next_temp_file_id: AtomicU64::new(0),
+        };
+        {
+            let mut index = cache.index.lock().unwrap();
+            for (path, size, _) in files {
+                // Left behind by a server that stopped while writing a tile.
+                if path.extension().is_some_and(|ext| ext == TEMP_FILE_EXTENSION) {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                if let Some(key) = path_to_key(&cache.config.directory, &path) {
+                    index.insert(&key, size);
+                }
+            }
+            // The limit might have been lowered since the last run.
+            cache.evict(&mut index);
+        }
+        Ok(cache)
+    }
+
+    /// The size of all the tiles in the cache.
+    pub fn used_bytes(&self) -> u64 {
+        self.index.lock().unwrap().total_bytes()
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative_path = Path::new(key);
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("{} can't be used as a path in the tile cache", key);
+        }
+        Ok(self.config.directory.join(relative_path))
+    }
+
+    fn evict(&self, index: &mut LruIndex) {
+        while index.total_bytes() > self.config.max_bytes {
+            let key = match index.pop_least_recently_used() {
+                Some(key) => key,
+                None => break,
+            };
+            if let Ok(path) = self.path(&key) {
+                if let Err(e) = fs::remove_file(&path) {
+                    if e.kind() != ErrorKind::NotFound {
+                        eprintln!("Failed to delete {} from the tile cache: {}", path.to_string_lossy(), e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl TileCache for DiskCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if !self.index.lock().unwrap().touch(key) {
+            return Ok(None);
+        }
+        let path = self.path(key)?;
+        match fs::read(&path) {
+            Ok(data) => {
+                // Only matters for the order after a restart, so a failure isn't worth reporting.
+                let _ = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+                Ok(Some(data))
+            }
+            // Evicted in the meantime (or deleted by hand).
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.index.lock().unwrap().remove(key);
+                Ok(None)
+            }
+            Err(e) => Err(e).context(format!("Failed to read {} from the tile cache", path.to_string_lossy())),
+        }
+    }
+
+    // The tile is written to a temporary file first, so that nobody reads a half-written tile.
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        let temp_path = path.with_extension(format!(
+            "{}.{}",
+            self.next_temp_file_id.fetch_add(1, Ordering::Relaxed),
+            TEMP_FILE_EXTENSION
+        ));
+        let write = || -> Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&temp_path, data)?;
+            fs::rename(&temp_path, &path)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e).context(format!("Failed to put {} into the tile cache", key));
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(key, data.len() as u64);
+        self.evict(&mut index);
+        Ok(())
+    }
+}
+
+fn find_files(directory: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) -> Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            find_files(&entry.path(), files)?;
+        } else {
+            files.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+fn path_to_key(directory: &Path, path: &Path) -> Option<String> {
+    let components = path
+        .strip_prefix(directory)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_cache() {
+        let directory = std::env::temp_dir().join(format!("test_disk_cache_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let open = |max_bytes| {
+            DiskCache::new(DiskConfig {
+                directory: directory.clone(),
+                max_bytes,
+            })
+            .unwrap()
+        };
+
+        let cache = open(25);
+        cache.put("a/15/1/1.png", b"0123456789", "image/png").unwrap();
+        cache.put("a/15/1/2.png", b"0123456789", "image/png").unwrap();
+        assert_eq!(
+            cache.get("a/15/1/1.png").unwrap().as_deref(),
+            Some(b"0123456789".as_slice())
+        );
+        // The tile 1/2 was used the longest time ago.
+        cache.put("b/15/1/1.png", b"0123456789", "image/png").unwrap();
+        assert_eq!(cache.get("a/15/1/2.png").unwrap(), None);
+        assert!(!directory.join("a/15/1/2.png").exists());
+        assert!(cache.get("a/15/1/1.png").unwrap().is_some());
+        assert_eq!(cache.used_bytes(), 20);
+
+        // Replaces the old tile.
+        cache.put("b/15/1/1.png", b"01234", "image/png").unwrap();
+        assert_eq!(cache.used_bytes(), 15);
+        assert!(cache.put("../15/1/1.png", b"", "image/png").is_err());
+
+        // The files are found again after a restart, in the order of their modification times, and the limit
+        // is applied to them.
+        let set_modified = |key: &str, seconds_ago| {
+            let modified = SystemTime::now() - std::time::Duration::from_secs(seconds_ago);
+            File::options()
+                .write(true)
+                .open(directory.join(key))
+                .and_then(|file| file.set_modified(modified))
+                .unwrap();
+        };
+        set_modified("a/15/1/1.png", 10);
+        set_modified("b/15/1/1.png", 20);
+        fs::write(directory.join("a/15/1/3.png.7.tmp"), b"half a tile").unwrap();
+        let cache = open(10);
+        assert_eq!(cache.used_bytes(), 10);
+        assert!(cache.get("a/15/1/1.png").unwrap().is_some());
+        assert!(!directory.join("b/15/1/1.png").exists());
+        assert!(!directory.join("a/15/1/3.png.7.tmp").exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
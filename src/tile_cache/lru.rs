@@ -0,0 +1,54 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// The sizes of the cached tiles and the order in which they were used, for the caches that delete the tiles
+/// that were used the longest time ago to stay within a size.
+#[derive(Default)]
+pub(super) struct LruIndex {
+    // The size of every tile and the moment it was last used, as a number that grows with every use.
+    entries: HashMap<String, (u64, u64)>,
+    keys_by_use: BTreeMap<u64, String>,
+    last_use: u64,
+    total_bytes: u64,
+}
+
+impl LruIndex {
+    pub(super) fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    pub(super) fn touch(&mut self, key: &str) -> bool {
+        self.last_use += 1;
+        match self.entries.get_mut(key) {
+            Some((_, used)) => {
+                let key = self.keys_by_use.remove(used).unwrap_or_default();
+                *used = self.last_use;
+                self.keys_by_use.insert(self.last_use, key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        self.last_use += 1;
+        self.entries.insert(key.to_string(), (size, self.last_use));
+        self.keys_by_use.insert(self.last_use, key.to_string());
+        self.total_bytes += size;
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some((size, used)) = self.entries.remove(key) {
+            self.keys_by_use.remove(&used);
+            self.total_bytes -= size;
+        }
+    }
+
+    pub(super) fn pop_least_recently_used(&mut self) -> Option<String> {
+        let (_, key) = self.keys_by_use.pop_first()?;
+        if let Some((size, _)) = self.entries.remove(&key) {
+            self.total_bytes -= size;
+        }
+        Some(key)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::tile_cache::lru::LruIndex;
+use crate::tile_cache::{CachedTile, KeyLayout};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The encoded tiles that were requested most recently, kept in memory in front of the tile cache (or without
+/// one), so that the tiles everybody looks at are served without drawing them or even reading them from the disk
+/// or the network. The tiles that were used the longest time ago are dropped to keep the cache within its size.
+pub struct MemoryCache {
+    max_bytes: u64,
+    layout: KeyLayout,
+    tiles: Mutex<MemoryTiles>,
+}
+
+#[derive(Default)]
+struct MemoryTiles {
+    index: LruIndex,
+    data: HashMap<String, Arc<Vec<u8>>>,
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: u64) -> MemoryCache {
+        MemoryCache {
+            max_bytes,
+            layout: KeyLayout::versioned(),
+            tiles: Mutex::new(MemoryTiles::default()),
+        }
+    }
+
+    /// The key has the versions of the style and of the geodata in it, so that the tiles drawn before a reload
+    /// aren't served after it (they just aren't used anymore and are the first to go).
+    pub fn key(&self, tile: &CachedTile<'_>) -> String {
+        self.layout.key(tile)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+        let mut tiles = self.tiles.lock().unwrap();
+        if tiles.index.touch(key) {
+            tiles.data.get(key).cloned()
+        } else {
+            None
+        }
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) {
+        let size = (key.len() + data.len()) as u64;
+        // It would push everything else out, only to be dropped right after that.
+        if size > self.max_bytes {
+            return;
+        }
+        let data = Arc::new(data.to_vec());
+        let mut tiles = self.tiles.lock().unwrap();
+        tiles.index.insert(key, size);
+        tiles.data.insert(key.to_string(), data);
+        while tiles.index.total_bytes() > self.max_bytes {
+            match tiles.index.pop_least_recently_used() {
+                Some(key) => tiles.data.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    /// The size of all the tiles in the cache, together with their keys.
+    pub fn used_bytes(&self) -> u64 {
+        self.tiles.lock().unwrap().index.total_bytes()
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_cache() {
+        // Room for two tiles with 5-byte keys.
+        let cache = MemoryCache::new(30);
+        cache.put("1/0/0", b"0123456789");
+        cache.put("1/0/1", b"0123456789");
+        assert_eq!(
+            cache.get("1/0/0").as_deref().map(Vec::as_slice),
+            Some(b"0123456789".as_slice())
+        );
+        assert_eq!(cache.used_bytes(), 30);
+
+        // The tile 1/0/1 was used the longest time ago.
+        cache.put("1/1/0", b"0123456789");
+        assert!(cache.get("1/0/1").is_none());
+        assert!(cache.get("1/0/0").is_some());
+        assert!(cache.get("1/1/0").is_some());
+
+        // Replaces the old tile.
+        cache.put("1/1/0", b"01234");
+        assert_eq!(cache.used_bytes(), 25);
+
+        // Doesn't fit at all, so nothing is dropped for it.
+        cache.put("1/1/1", &[0; 100]);
+        assert!(cache.get("1/1/1").is_none());
+        assert_eq!(cache.used_bytes(), 25);
+    }
+}
@@ -0,0 +1,194 @@
+pub mod disk;
+mod lru;
+pub mod memory;
+pub mod pending;
+pub mod redis;
+pub mod s3;
+
+use crate::draw::drawer::TileLayers;
+use crate::tile::Tile;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A store of the encoded tiles that outlives the server, so that the tiles aren't drawn again after a restart
+/// (and, if the store is shared, aren't drawn by every server separately). The server first looks for a tile
+/// in the cache and puts it there after drawing it.
+pub trait TileCache: Send + Sync {
+    /// The tile, unless it's missing or has expired.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<()>;
+
+    /// Claims the drawing of a missing tile, so that the other servers that share the cache wait for the tile
+    /// to appear there instead of drawing it too. Returns the token to unlock the tile with, or `None` if another
+    /// server is already drawing it. The caches that can't coordinate the servers let everybody draw.
+    fn try_lock(&self, _key: &str) -> Result<Option<String>> {
+        Ok(Some(String::new()))
+    }
+
+    fn unlock(&self, _key: &str, _token: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// How long the other servers wait for a locked tile before drawing it on their own.
+    fn lock_timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A tile as it's requested, which is everything that makes its encoded bytes different from the other tiles.
+pub struct CachedTile<'a> {
+    pub map: Option<&'a str>,
+    pub layers: TileLayers,
+    pub tile: &'a Tile,
+    pub scale: usize,
+    pub extension: &'a str,
+    /// Changes whenever the rules of the style do.
+    pub style_version: &'a str,
+    /// Changes whenever the geodata is imported again.
+    pub data_version: &'a str,
+}
+
+/// How the tiles are named in the cache, e.g. `tiles/{map}{layers}{z}/{x}/{y}{scale}.{ext}`. The placeholders
+/// are the same parts as in the tile URLs: `{map}` is the name of the map followed by a slash (empty for the map
+/// at the root), `{layers}` is `base/`, `labels/` or empty, and `{scale}` is `@2x` and the like (empty for
+/// the usual tiles). `{style}` and `{version}` are the versions of the style and of the geodata, which keep
+/// the outdated tiles from being served. `{z}`, `{x}` and `{y}` are required.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyLayout {
+    template: String,
+}
+
+const PLACEHOLDERS: [&str; 9] = ["map", "layers", "z", "x", "y", "scale", "ext", "style", "version"];
+const REQUIRED_PLACEHOLDERS: [&str; 3] = ["z", "x", "y"];
+
+impl Default for KeyLayout {
+    fn default() -> KeyLayout {
+        KeyLayout {
+            template: "{map}{layers}{z}/{x}/{y}{scale}.{ext}".to_string(),
+        }
+    }
+}
+
+impl KeyLayout {
+    /// Every part of the tile, the versions included.
+    pub fn versioned() -> KeyLayout {
+        KeyLayout {
+            template: "{style}/{version}/{map}{layers}{z}/{x}/{y}{scale}.{ext}".to_string(),
+        }
+    }
+
+    pub fn key(&self, tile: &CachedTile<'_>) -> String {
+        let mut key = String::new();
+        for (text, placeholder) in split_template(&self.template) {
+            key.push_str(text);
+            match placeholder {
+                Some("map") => {
+                    if let Some(map) = tile.map {
+                        key.push_str(map);
+                        key.push('/');
+                    }
+                }
+                Some("layers") => key.push_str(match tile.layers {
+                    TileLayers::All => "",
+                    TileLayers::Base => "base/",
+                    TileLayers::Labels => "labels/",
+                }),
+                Some("z") => key.push_str(&tile.tile.zoom.to_string()),
+                Some("x") => key.push_str(&tile.tile.x.to_string()),
+                Some("y") => key.push_str(&tile.tile.y.to_string()),
+                Some("scale") if tile.scale > 1 => key.push_str(&format!("@{}x", tile.scale)),
+                Some("ext") => key.push_str(tile.extension),
+                Some("style") => key.push_str(tile.style_version),
+                Some("version") => key.push_str(tile.data_version),
+                _ => {}
+            }
+        }
+        key
+    }
+}
+
+impl FromStr for KeyLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<KeyLayout> {
+        let placeholders = split_template(s)
+            .iter()
+            .filter_map(|(_, placeholder)| *placeholder)
+            .collect::<Vec<_>>();
+        if let Some(unknown) = placeholders.iter().find(|p| !PLACEHOLDERS.contains(p)) {
+            bail!("Unknown placeholder in the cache key layout: {{{}}}", unknown);
+        }
+        if let Some(missing) = REQUIRED_PLACEHOLDERS.iter().find(|p| !placeholders.contains(p)) {
+            bail!("The cache key layout {} doesn't have {{{}}}", s, missing);
+        }
+        Ok(KeyLayout {
+            template: s.to_string(),
+        })
+    }
+}
+
+// The text before every placeholder (and after the last one), together with the placeholder names.
+fn split_template(template: &str) -> Vec<(&str, Option<&str>)> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some((text, after_brace)) = rest.split_once('{') {
+        match after_brace.split_once('}') {
+            Some((placeholder, after_placeholder)) => {
+                parts.push((text, Some(placeholder)));
+                rest = after_placeholder;
+            }
+            None => break,
+        }
+    }
+    parts.push((rest, None));
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_layout() {
+        let tile = Tile {
+            zoom: 15,
+            x: 19805,
+            y: 10244,
+        };
+        let cached_tile = |map, layers, scale| CachedTile {
+            map,
+            layers,
+            tile: &tile,
+            scale,
+            extension: "png",
+            style_version: "3f2a9c1e",
+            data_version: "1700000000",
+        };
+
+        let default_layout = KeyLayout::default();
+        assert_eq!(
+            default_layout.key(&cached_tile(None, TileLayers::All, 1)),
+            "15/19805/10244.png"
+        );
+        assert_eq!(
+            default_layout.key(&cached_tile(Some("paris"), TileLayers::Labels, 2)),
+            "paris/labels/15/19805/10244@2x.png"
+        );
+
+        let layout = "tiles/v1/{map}{z}-{x}-{y}".parse::<KeyLayout>().unwrap();
+        assert_eq!(
+            layout.key(&cached_tile(Some("paris"), TileLayers::Base, 1)),
+            "tiles/v1/paris/15-19805-10244"
+        );
+
+        let versioned_layout = "{style}/{version}/{z}/{x}/{y}.{ext}".parse::<KeyLayout>().unwrap();
+        assert_eq!(
+            versioned_layout.key(&cached_tile(None, TileLayers::All, 1)),
+            "3f2a9c1e/1700000000/15/19805/10244.png"
+        );
+
+        assert!("{z}/{x}/{y}.{extension}".parse::<KeyLayout>().is_err());
+        assert!("{z}/{x}.png".parse::<KeyLayout>().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The tiles that are being drawn (or fetched) right now, so that the requests for the same tile that come in
+/// at the same time wait for it instead of drawing it again. The maps load a dozen tiles or more around the view
+/// at once, and the visitors of the same page all ask for the same tiles. The requests wait asynchronously,
+/// so that only the one that draws the tile takes a render thread.
+#[derive(Default)]
+pub struct PendingRenders {
+    renders: Mutex<HashMap<String, watch::Receiver<RenderResult>>>,
+}
+
+// Empty until the render is over, and then the tile, or nothing if it failed.
+type RenderResult = Option<Option<Arc<Vec<u8>>>>;
+
+pub enum Coalesced<'p> {
+    /// Nobody else is drawing the tile, so this request has to, and then give the tile to the others.
+    Leader(RenderLeader<'p>),
+    /// Another request has drawn the tile.
+    Done(Arc<Vec<u8>>),
+}
+
+/// Gives the tile to the requests that wait for it. If it's dropped without the tile (e.g. because the render
+/// has failed or has been cancelled), the next of them draws the tile itself.
+pub struct RenderLeader<'p> {
+    renders: &'p PendingRenders,
+    key: String,
+    result: watch::Sender<RenderResult>,
+}
+
+impl PendingRenders {
+    pub fn new() -> PendingRenders {
+        Default::default()
+    }
+
+    /// Waits for the tile with this key if another request is drawing it, or makes this request the one
+    /// that draws it. The key has to name everything that makes the bytes of the tile different (like the ETag).
+    /// If the client goes away, the request stops waiting along with the dropped future.
+    pub async fn join(&self, key: &str) -> Coalesced<'_> {
+        loop {
+            let mut result = {
+                let mut renders = self.renders.lock().unwrap();
+                match renders.get(key) {
+                    Some(result) => result.clone(),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        renders.insert(key.to_string(), receiver);
+                        return Coalesced::Leader(RenderLeader {
+                            renders: self,
+                            key: key.to_string(),
+                            result: sender,
+                        });
+                    }
+                }
+            };
+
+            let tile_bytes = match result.wait_for(Option::is_some).await {
+                Ok(result) => result.clone().flatten(),
+                Err(_) => None,
+            };
+            if let Some(tile_bytes) = tile_bytes {
+                return Coalesced::Done(tile_bytes);
+            }
+        }
+    }
+}
+
+impl RenderLeader<'_> {
+    pub fn share(&self, tile_bytes: &[u8]) {
+        self.finish(Some(Arc::new(tile_bytes.to_vec())));
+    }
+
+    fn finish(&self, tile_bytes: Option<Arc<Vec<u8>>>) {
+        if self.result.borrow().is_some() {
+            return;
+        }
+        // The requests that come after this are served from the caches, or draw the tile again if there are none.
+        self.renders.renders.lock().unwrap().remove(&self.key);
+        self.result.send_replace(Some(tile_bytes));
+    }
+}
+
+impl Drop for RenderLeader<'_> {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use tokio::runtime;
+    use tokio::task;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    fn is_leader(coalesced: &Coalesced<'_>) -> bool {
+        matches!(coalesced, Coalesced::Leader(_))
+    }
+
+    // Lets the spawned requests get as far as they can.
+    async fn let_others_run() {
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn test_pending_renders() {
+        let renders: &'static PendingRenders = Box::leak(Box::new(PendingRenders::new()));
+        block_on(async {
+            let leader = renders.join("1/0/0.png").await;
+            assert!(is_leader(&leader));
+            assert!(is_leader(&renders.join("1/0/1.png").await));
+            assert_eq!(renders.renders.lock().unwrap().len(), 1);
+
+            let followers = (0..4)
+                .map(|_| {
+                    task::spawn(async {
+                        match renders.join("1/0/0.png").await {
+                            Coalesced::Done(tile_bytes) => tile_bytes.to_vec(),
+                            _ => panic!("Expected the tile drawn by the leader"),
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            let_others_run().await;
+            if let Coalesced::Leader(leader) = &leader {
+                leader.share(b"tile");
+            }
+            for follower in followers {
+                assert_eq!(follower.await.unwrap(), b"tile");
+            }
+            drop(leader);
+            assert!(renders.renders.lock().unwrap().is_empty());
+
+            // A failed render is taken over by one of the requests that wait for it.
+            let leader = renders.join("1/0/0.png").await;
+            let follower = task::spawn(async { is_leader(&renders.join("1/0/0.png").await) });
+            let_others_run().await;
+            assert!(!follower.is_finished());
+            drop(leader);
+            assert!(follower.await.unwrap());
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use crate::tile_cache::TileCache;
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// The longest bulk reply that is read, which is much more than any tile takes.
+const MAX_REPLY_LENGTH: usize = 64 << 20;
+// Deletes the lock only if it still belongs to this server (it might have expired and been taken by another one).
+const UNLOCK_SCRIPT: &str =
+    "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+// Much longer than a tile takes to draw, unless something has gone wrong.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct RedisConfig {
+    /// E.g. `127.0.0.1:6379`.
+    pub address: String,
+    pub password: Option<String>,
+    pub database: Option<u32>,
+    /// Redis deletes the tiles after that long.
+    pub ttl: Option<Duration>,
+    /// How long a server may draw a tile before the others stop waiting for it.
+    pub lock_timeout: Duration,
+}
+
+/// A tile cache in Redis, which is also used to make sure that only one of the servers sharing it draws
+/// a missing tile while the others wait for the result.
+pub struct RedisCache {
+    config: RedisConfig,
+    // The connections that aren't used by any thread at the moment.
+    idle_connections: Mutex<Vec<Connection>>,
+    // Tells the locks of this server from the ones of the other servers.
+    lock_token_prefix: String,
+    next_lock_token: AtomicU64,
+}
+
+impl RedisCache {
+    pub fn new(config: RedisConfig) -> Result<RedisCache> {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let cache = RedisCache {
+            config,
+            idle_connections: Mutex::new(Vec::new()),
+            lock_token_prefix: format!("{}-{}", std::process::id(), started_at.as_nanos()),
+            next_lock_token: AtomicU64::new(0),
+        };
+        // Fail early if Redis isn't reachable.
+        cache.command(&[b"PING"])?;
+        Ok(cache)
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let stream = TcpStream::connect(&self.config.address)
+            .context(format!("Failed to connect to Redis at {}", self.config.address))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        if let Some(password) = &self.config.password {
+            connection.command(&[b"AUTH", password.as_bytes()])?;
+        }
+        if let Some(database) = self.config.database {
+            connection.command(&[b"SELECT", database.to_string().as_bytes()])?;
+        }
+        Ok(connection)
+    }
+
+    // A connection that fails is dropped, so that the next command starts over with a new one.
+    fn command(&self, args: &[&[u8]]) -> Result<Reply> {
+        let idle_connection = self.idle_connections.lock().unwrap().pop();
+        let mut connection = match idle_connection {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let reply = connection.command(args)?;
+        self.idle_connections.lock().unwrap().push(connection);
+        Ok(reply)
+    }
+
+    fn lock_key(key: &str) -> String {
+        format!("{}.lock", key)
+    }
+}
+
+impl TileCache for RedisCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", key.as_bytes()]) {
+            Ok(Reply::Bulk(data)) => Ok(Some(data)),
+            Ok(Reply::Nil) => Ok(None),
+            Ok(reply) => bail!("Unexpected reply to GET: {:?}", reply),
+            Err(e) => Err(e).context(format!("Failed to get {} from the tile cache", key)),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<()> {
+        let ttl = self.config.ttl.map(|ttl| ttl.as_secs().max(1).to_string());
+        let mut args = vec![b"SET".as_slice(), key.as_bytes(), data];
+        if let Some(ttl) = &ttl {
+            args.extend([b"EX".as_slice(), ttl.as_bytes()]);
+        }
+        self.command(&args)
+            .context(format!("Failed to put {} into the tile cache", key))?;
+        Ok(())
+    }
+
+    fn try_lock(&self, key: &str) -> Result<Option<String>> {
+        let token = format!(
+            "{}-{}",
+            self.lock_token_prefix,
+            self.next_lock_token.fetch_add(1, Ordering::Relaxed)
+        );
+        let timeout = self.config.lock_timeout.as_millis().max(1).to_string();
+        let lock_key = RedisCache::lock_key(key);
+        let args: [&[u8]; 6] = [
+            b"SET",
+            lock_key.as_bytes(),
+            token.as_bytes(),
+            b"NX",
+            b"PX",
+            timeout.as_bytes(),
+        ];
+        match self.command(&args).context(format!("Failed to lock {}", key))? {
+            Reply::Status(_) => Ok(Some(token)),
+            _ => Ok(None),
+        }
+    }
+
+    fn unlock(&self, key: &str, token: &str) -> Result<()> {
+        let lock_key = RedisCache::lock_key(key);
+        let args: [&[u8]; 5] = [
+            b"EVAL",
+            UNLOCK_SCRIPT.as_bytes(),
+            b"1",
+            lock_key.as_bytes(),
+            token.as_bytes(),
+        ];
+        self.command(&args).context(format!("Failed to unlock {}", key))?;
+        Ok(())
+    }
+
+    fn lock_timeout(&self) -> Option<Duration> {
+        Some(self.config.lock_timeout)
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn command(&mut self, args: &[&[u8]]) -> Result<Reply> {
+        write_command(&mut self.writer, args)?;
+        read_reply(&mut self.reader)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+}
+
+// The commands are sent as arrays of bulk strings (see https://redis.io/docs/reference/protocol-spec/).
+fn write_command(writer: &mut impl Write, args: &[&[u8]]) -> Result<()> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend(*arg);
+        buf.extend(b"\r\n");
+    }
+    writer.write_all(&buf).context("Failed to send a command to Redis")?;
+    Ok(())
+}
+
+// Only the replies that the commands above get are supported, i.e. no arrays.
+fn read_reply(reader: &mut impl BufRead) -> Result<Reply> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("Failed to read a reply from Redis")?;
+    let line = match line.strip_suffix("\r\n") {
+        Some(line) if !line.is_empty() => line,
+        _ => bail!("Redis has closed the connection"),
+    };
+    let (kind, rest) = line.split_at(1);
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => bail!("Redis has replied with an error: {}", rest),
+        ":" => Ok(Reply::Integer(rest.parse().context("Invalid integer reply")?)),
+        "$" if rest == "-1" => Ok(Reply::Nil),
+        "$" => {
+            let length = rest.parse::<usize>().context("Invalid bulk reply length")?;
+            if length > MAX_REPLY_LENGTH {
+                bail!("The reply from Redis is too long: {} bytes", length);
+            }
+            let mut data = vec![0; length + 2];
+            reader
+                .read_exact(&mut data)
+                .context("Failed to read a reply from Redis")?;
+            if !data.ends_with(b"\r\n") {
+                bail!("Invalid bulk reply");
+            }
+            data.truncate(length);
+            Ok(Reply::Bulk(data))
+        }
+        _ => bail!("Unsupported reply from Redis: {}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_protocol() {
+        let mut buf = Vec::new();
+        write_command(&mut buf, &[b"SET", b"15/19805/10244.png", b"\x89PNG\r\n"]).unwrap();
+        assert_eq!(
+            buf,
+            b"*3\r\n$3\r\nSET\r\n$18\r\n15/19805/10244.png\r\n$6\r\n\x89PNG\r\n\r\n"
+        );
+
+        let mut replies = Cursor::new(b"+OK\r\n$-1\r\n:1\r\n$6\r\n\x89PNG\r\n\r\n-ERR wrong\r\n$3\r\nab".to_vec());
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Status("OK".to_string()));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Nil);
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Integer(1));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(b"\x89PNG\r\n".to_vec()));
+        assert!(read_reply(&mut replies).is_err());
+        assert!(read_reply(&mut replies).is_err());
+        assert!(read_reply(&mut replies).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::coords::GeoBounds;
+use crate::tile::{bounds_to_tile_range, Tile};
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Much more than any tile takes, so that a broken upstream can't use up the memory.
+const MAX_RESPONSE_LENGTH: u64 = 16 << 20;
+
+// The headers of the client request that are sent on to the upstream server, so that the client can revalidate
+// the tiles it already has, and the headers of the upstream response that are sent back for the same reason.
+pub const FORWARDED_REQUEST_HEADERS: [&str; 2] = ["If-None-Match", "If-Modified-Since"];
+const RELAYED_RESPONSE_HEADERS: [&str; 4] = ["ETag", "Last-Modified", "Cache-Control", "Expires"];
+
+/// Another tile server that serves the tiles of a map that the geodata doesn't cover, e.g. a global map
+/// around a detailed city map. The tiles are requested with the same zoom level and coordinates, so the
+/// upstream tiles have to be of the same size.
+pub struct Upstream {
+    url_template: String,
+    local_bounds: Option<GeoBounds>,
+    agent: ureq::Agent,
+}
+
+/// The response of the upstream server as it is, even if it isn't a success.
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+    /// Lines like `ETag: "abc"`.
+    pub headers: Vec<String>,
+}
+
+impl Upstream {
+    /// `url_template` is like `https://tile.openstreetmap.org/{z}/{x}/{y}.png`, with an optional `{scale}`
+    /// that becomes `@2x` and the like for the scaled tiles (and is empty for the usual ones). The tiles that
+    /// intersect `local_bounds` are drawn locally, or the tiles that have anything to draw if there are no bounds.
+    pub fn new(url_template: &str, local_bounds: Option<GeoBounds>) -> Result<Upstream> {
+        if !url_template.starts_with("http://") && !url_template.starts_with("https://") {
+            bail!("The upstream URL {} isn't an HTTP URL", url_template);
+        }
+        if let Some(missing) = ["{z}", "{x}", "{y}"].iter().find(|p| !url_template.contains(*p)) {
+            bail!("The upstream URL {} doesn't have {}", url_template, missing);
+        }
+        Ok(Upstream {
+            url_template: url_template.to_string(),
+            local_bounds,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        })
+    }
+
+    /// Whether the tile is drawn from the geodata rather than requested from the upstream server.
+    pub fn draws_locally(&self, tile: &Tile, has_local_data: impl FnOnce() -> bool) -> bool {
+        match &self.local_bounds {
+            Some(bounds) => {
+                let range = bounds_to_tile_range(bounds, tile.zoom);
+                (range.min_x..=range.max_x).contains(&tile.x) && (range.min_y..=range.max_y).contains(&tile.y)
+            }
+            None => has_local_data(),
+        }
+    }
+
+    /// Whether the upstream server has the tiles of the scale.
+    pub fn has_scale(&self, scale: usize) -> bool {
+        scale == 1 || self.url_template.contains("{scale}")
+    }
+
+    pub fn url(&self, tile: &Tile, scale: usize) -> String {
+        let scale = if scale > 1 {
+            format!("@{}x", scale)
+        } else {
+            String::new()
+        };
+        self.url_template
+            .replace("{z}", &tile.zoom.to_string())
+            .replace("{x}", &tile.x.to_string())
+            .replace("{y}", &tile.y.to_string())
+            .replace("{scale}", &scale)
+    }
+
+    /// `request_headers` are the headers from `FORWARDED_REQUEST_HEADERS` that the client has sent.
+    pub fn fetch(&self, tile: &Tile, scale: usize, request_headers: &[(String, String)]) -> Result<UpstreamResponse> {
+        let url = self.url(tile, scale);
+        let mut request = self.agent.get(&url);
+        for (name, value) in request_headers {
+            request = request.set(name, value);
+        }
+        let response = match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e).context(format!("Failed to get {}", url)),
+        };
+
+        let headers = RELAYED_RESPONSE_HEADERS
+            .iter()
+            .filter_map(|name| response.header(name).map(|value| format!("{}: {}", name, value)))
+            .collect();
+        let status = response.status();
+        let status_text = response.status_text().to_string();
+        let content_type = response.content_type().to_string();
+        let mut data = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_RESPONSE_LENGTH)
+            .read_to_end(&mut data)
+            .context(format!("Failed to read {}", url))?;
+        Ok(UpstreamResponse {
+            status,
+            status_text,
+            content_type,
+            data,
+            headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream() {
+        let tile = Tile {
+            zoom: 15,
+            x: 19805,
+            y: 10244,
+        };
+        let upstream = Upstream::new("https://tile.openstreetmap.org/{z}/{x}/{y}.png", None).unwrap();
+        assert_eq!(
+            upstream.url(&tile, 1),
+            "https://tile.openstreetmap.org/15/19805/10244.png"
+        );
+        assert!(!upstream.has_scale(2));
+        assert!(upstream.draws_locally(&tile, || true));
+        assert!(!upstream.draws_locally(&tile, || false));
+
+        let paris = "2.22,48.81,2.47,48.91".parse::<GeoBounds>().unwrap();
+        let upstream = Upstream::new("https://tiles.example.com/{z}/{x}/{y}{scale}.png", Some(paris)).unwrap();
+        assert_eq!(
+            upstream.url(&tile, 2),
+            "https://tiles.example.com/15/19805/10244@2x.png"
+        );
+        assert!(upstream.has_scale(2));
+        assert!(!upstream.draws_locally(&tile, || true));
+        assert!(upstream.draws_locally(
+            &Tile {
+                zoom: 15,
+                x: 16590,
+                y: 11270
+            },
+            || false
+        ));
+
+        assert!(Upstream::new("tile.openstreetmap.org/{z}/{x}/{y}.png", None).is_err());
+        assert!(Upstream::new("https://tile.openstreetmap.org/{z}/{x}.png", None).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use crate::coords::GeoBounds;
+use crate::tile::{zoom_offset_for_tile_size, MAX_OVERZOOM, TILE_SIZE};
+use anyhow::{bail, Context, Result};
+use std::fmt::Write;
+
+// The only tile matrix set: the usual Web Mercator tiles, which the GIS applications know by this name.
+pub const TILE_MATRIX_SET: &str = "GoogleMapsCompatible";
+// The scale denominator of zoom level 0 of `TILE_MATRIX_SET`, with the standard pixel size of 0.28 mm.
+const ZOOM_0_SCALE_DENOMINATOR: f64 = 559_082_264.028_717_8;
+// The top left corner of the Web Mercator world, in meters.
+const TOP_LEFT_CORNER: f64 = 20_037_508.342_789_2;
+// The formats by their media types and the extensions of the tile paths.
+const FORMATS: &[(&str, &str)] = &[("image/png", "png"), ("image/webp", "webp")];
+// The parameters of the requests that WMTS defines, which aren't passed on to the tile paths.
+const WMTS_PARAMS: &[&str] = &[
+    "service",
+    "request",
+    "version",
+    "layer",
+    "style",
+    "format",
+    "tilematrixset",
+    "tilematrix",
+    "tilerow",
+    "tilecol",
+];
+
+/// A WMTS request in the key-value-pair encoding (e.g. `/wmts?SERVICE=WMTS&REQUEST=GetCapabilities`).
+#[derive(Debug, PartialEq)]
+pub enum WmtsRequest {
+    GetCapabilities,
+    /// The tile is given by the path of the same tile in the XYZ scheme, e.g. `/15/19805/10244.png`. The other
+    /// parameters of the request (e.g. `key`) are kept in the query of the path.
+    GetTile {
+        layer: String,
+        tile_path: String,
+    },
+}
+
+pub fn parse_request(query: &str) -> Result<WmtsRequest> {
+    let params = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect::<Vec<_>>();
+    // The names of the parameters are case-insensitive, unlike their values.
+    let get_param = |name: &str| {
+        params
+            .iter()
+            .find(|(param_name, _)| param_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| percent_decode(value))
+            .transpose()
+    };
+    let get_mandatory_param = |name: &str| get_param(name)?.with_context(|| format!("{} is missing", name));
+
+    if get_mandatory_param("SERVICE")? != "WMTS" {
+        bail!("Only the WMTS service is supported");
+    }
+    match get_mandatory_param("REQUEST")?.as_str() {
+        "GetCapabilities" => Ok(WmtsRequest::GetCapabilities),
+        "GetTile" => {
+            let tile_matrix_set = get_mandatory_param("TILEMATRIXSET")?;
+            if tile_matrix_set != TILE_MATRIX_SET {
+                bail!("Unknown tile matrix set: <{}>", tile_matrix_set);
+            }
+            let get_number = |name: &str| {
+                let value = get_mandatory_param(name)?;
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    bail!("{} isn't a number: <{}>", name, value);
+                }
+                Ok(value)
+            };
+            let (zoom, row, col) = (
+                get_number("TILEMATRIX")?,
+                get_number("TILEROW")?,
+                get_number("TILECOL")?,
+            );
+            let format = get_param("FORMAT")?.unwrap_or_else(|| FORMATS[0].0.to_string());
+            let extension = FORMATS
+                .iter()
+                .find(|(media_type, _)| *media_type == format)
+                .map(|(_, extension)| extension)
+                .with_context(|| format!("Unsupported format: <{}>", format))?;
+            let other_params = params
+                .iter()
+                .filter(|(name, _)| {
+                    !WMTS_PARAMS
+                        .iter()
+                        .any(|wmts_param| name.eq_ignore_ascii_case(wmts_param))
+                })
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>();
+            let mut tile_path = format!("/{}/{}/{}.{}", zoom, col, row, extension);
+            if !other_params.is_empty() {
+                tile_path = format!("{}?{}", tile_path, other_params.join("&"));
+            }
+            Ok(WmtsRequest::GetTile {
+                layer: get_mandatory_param("LAYER")?,
+                tile_path,
+            })
+        }
+        request => bail!("Unknown request: <{}>", request),
+    }
+}
+
+/// The map that the capabilities describe as their only layer.
+pub struct WmtsLayer<'a> {
+    pub identifier: &'a str,
+    pub bounds: GeoBounds,
+    // The URL that the paths of the map are appended to, e.g. `http://localhost:8080/paris`.
+    pub map_url: String,
+    // Added to all of the URLs (e.g. `key=abc`).
+    pub query: Option<String>,
+}
+
+/// The capabilities document, which lists both the key-value-pair and the RESTful encodings of the tile requests,
+/// the latter being the usual XYZ tile paths.
+pub fn capabilities(layer: &WmtsLayer<'_>, tile_size: usize) -> String {
+    let service_url = match &layer.query {
+        Some(query) => format!("{}/wmts?{}&", layer.map_url, query),
+        None => format!("{}/wmts?", layer.map_url),
+    };
+    let tile_query = layer
+        .query
+        .as_ref()
+        .map_or_else(String::new, |query| format!("?{}", query));
+
+    let mut xml = String::new();
+    let _ = writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        xml,
+        r#"<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">"#
+    );
+    let _ = writeln!(xml, "  <ows:ServiceIdentification>");
+    let _ = writeln!(xml, "    <ows:Title>{}</ows:Title>", escape_xml(layer.identifier));
+    let _ = writeln!(xml, "    <ows:ServiceType>OGC WMTS</ows:ServiceType>");
+    let _ = writeln!(xml, "    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>");
+    let _ = writeln!(xml, "  </ows:ServiceIdentification>");
+    let _ = writeln!(xml, "  <ows:OperationsMetadata>");
+    for operation in ["GetCapabilities", "GetTile"] {
+        let _ = writeln!(xml, r#"    <ows:Operation name="{}">"#, operation);
+        let _ = writeln!(
+            xml,
+            r#"      <ows:DCP><ows:HTTP><ows:Get xlink:href="{}"><ows:Constraint name="GetEncoding"><ows:AllowedValues><ows:Value>KVP</ows:Value></ows:AllowedValues></ows:Constraint></ows:Get></ows:HTTP></ows:DCP>"#,
+            escape_xml(&service_url)
+        );
+        let _ = writeln!(xml, "    </ows:Operation>");
+    }
+    let _ = writeln!(xml, "  </ows:OperationsMetadata>");
+
+    let _ = writeln!(xml, "  <Contents>");
+    let _ = writeln!(xml, "    <Layer>");
+    let _ = writeln!(xml, "      <ows:Title>{}</ows:Title>", escape_xml(layer.identifier));
+    let _ = writeln!(xml, "      <ows:WGS84BoundingBox>");
+    let _ = writeln!(
+        xml,
+        "        <ows:LowerCorner>{} {}</ows:LowerCorner>",
+        layer.bounds.min_lon, layer.bounds.min_lat
+    );
+    let _ = writeln!(
+        xml,
+        "        <ows:UpperCorner>{} {}</ows:UpperCorner>",
+        layer.bounds.max_lon, layer.bounds.max_lat
+    );
+    let _ = writeln!(xml, "      </ows:WGS84BoundingBox>");
+    let _ = writeln!(
+        xml,
+        "      <ows:Identifier>{}</ows:Identifier>",
+        escape_xml(layer.identifier)
+    );
+    let _ = writeln!(
+        xml,
+        r#"      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>"#
+    );
+    for (media_type, _) in FORMATS {
+        let _ = writeln!(xml, "      <Format>{}</Format>", media_type);
+    }
+    let _ = writeln!(
+        xml,
+        "      <TileMatrixSetLink><TileMatrixSet>{}</TileMatrixSet></TileMatrixSetLink>",
+        TILE_MATRIX_SET
+    );
+    for (media_type, extension) in FORMATS {
+        let template = format!(
+            "{}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.{}{}",
+            layer.map_url, extension, tile_query
+        );
+        let _ = writeln!(
+            xml,
+            r#"      <ResourceURL format="{}" resourceType="tile" template="{}"/>"#,
+            media_type,
+            escape_xml(&template)
+        );
+    }
+    let _ = writeln!(xml, "    </Layer>");
+
+    // The bigger tiles cover the same area as the usual ones, but with more pixels (see `zoom_offset_for_tile_size`).
+    let zoom_offset = zoom_offset_for_tile_size(tile_size as u32).unwrap_or_default();
+    let _ = writeln!(xml, "    <TileMatrixSet>");
+    let _ = writeln!(xml, "      <ows:Identifier>{}</ows:Identifier>", TILE_MATRIX_SET);
+    let _ = writeln!(
+        xml,
+        "      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>"
+    );
+    if tile_size == TILE_SIZE as usize {
+        let _ = writeln!(
+            xml,
+            "      <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>"
+        );
+    }
+    for zoom in 0..=MAX_OVERZOOM {
+        let scale_denominator = ZOOM_0_SCALE_DENOMINATOR / f64::from(1u32 << (zoom + zoom_offset));
+        let matrix_size = 1u32 << zoom;
+        let _ = writeln!(xml, "      <TileMatrix>");
+        let _ = writeln!(xml, "        <ows:Identifier>{}</ows:Identifier>", zoom);
+        let _ = writeln!(
+            xml,
+            "        <ScaleDenominator>{}</ScaleDenominator>",
+            scale_denominator
+        );
+        let _ = writeln!(
+            xml,
+            "        <TopLeftCorner>{} {}</TopLeftCorner>",
+            -TOP_LEFT_CORNER, TOP_LEFT_CORNER
+        );
+        let _ = writeln!(
+            xml,
+            "        <TileWidth>{0}</TileWidth><TileHeight>{0}</TileHeight>",
+            tile_size
+        );
+        let _ = writeln!(
+            xml,
+            "        <MatrixWidth>{0}</MatrixWidth><MatrixHeight>{0}</MatrixHeight>",
+            matrix_size
+        );
+        let _ = writeln!(xml, "      </TileMatrix>");
+    }
+    let _ = writeln!(xml, "    </TileMatrixSet>");
+    let _ = writeln!(xml, "  </Contents>");
+    let _ = writeln!(xml, "</Capabilities>");
+    xml
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                let hex = bytes.get(idx + 1..idx + 3).context("Truncated percent-encoding")?;
+                let byte = str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .with_context(|| format!("Invalid percent-encoding in <{}>", value))?;
+                decoded.push(byte);
+                idx += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                idx += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).with_context(|| format!("<{}> isn't valid UTF-8", value))
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request("SERVICE=WMTS&REQUEST=GetCapabilities&VERSION=1.0.0").unwrap(),
+            WmtsRequest::GetCapabilities
+        );
+        assert_eq!(
+            parse_request(
+                "service=WMTS&request=GetTile&version=1.0.0&layer=paris&style=default&tilematrixset=GoogleMapsCompatible\
+                 &tilematrix=15&tilerow=10244&tilecol=19805&format=image%2Fwebp&key=abc"
+            )
+            .unwrap(),
+            WmtsRequest::GetTile {
+                layer: "paris".to_string(),
+                tile_path: "/15/19805/10244.webp?key=abc".to_string(),
+            }
+        );
+        let get_tile = |params: &str| {
+            parse_request(&format!(
+                "SERVICE=WMTS&REQUEST=GetTile&LAYER=map&TILEMATRIXSET=GoogleMapsCompatible{}",
+                params
+            ))
+        };
+        assert_eq!(
+            get_tile("&TILEMATRIX=0&TILEROW=0&TILECOL=0").unwrap(),
+            WmtsRequest::GetTile {
+                layer: "map".to_string(),
+                tile_path: "/0/0/0.png".to_string(),
+            }
+        );
+        for params in [
+            "&TILEMATRIX=0&TILEROW=0",
+            "&TILEMATRIX=0/1&TILEROW=0&TILECOL=0",
+            "&TILEMATRIX=-1&TILEROW=0&TILECOL=0",
+            "&TILEMATRIX=0&TILEROW=0&TILECOL=0&FORMAT=image/gif",
+            "&TILEMATRIX=0&TILEROW=0&TILECOL=%zz",
+        ] {
+            assert!(get_tile(params).is_err(), "{}", params);
+        }
+        assert!(parse_request("SERVICE=WMS&REQUEST=GetCapabilities").is_err());
+        assert!(parse_request("SERVICE=WMTS&REQUEST=GetFeatureInfo").is_err());
+        assert!(parse_request("SERVICE=WMTS&REQUEST=GetTile&LAYER=map&TILEMATRIXSET=EPSG:4326").is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let layer = WmtsLayer {
+            identifier: "paris",
+            bounds: GeoBounds {
+                min_lon: 2.25,
+                min_lat: 48.8,
+                max_lon: 2.42,
+                max_lat: 48.9,
+            },
+            map_url: "http://localhost:8080/paris".to_string(),
+            query: Some("key=abc".to_string()),
+        };
+        let xml = capabilities(&layer, 256);
+        for expected in [
+            "<ows:Identifier>paris</ows:Identifier>",
+            "<ows:LowerCorner>2.25 48.8</ows:LowerCorner>",
+            r#"<ows:Get xlink:href="http://localhost:8080/paris/wmts?key=abc&amp;">"#,
+            r#"template="http://localhost:8080/paris/{TileMatrix}/{TileCol}/{TileRow}.png?key=abc""#,
+            "<WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>",
+            "<ScaleDenominator>559082264.0287178</ScaleDenominator>",
+            "<MatrixWidth>4194304</MatrixWidth><MatrixHeight>4194304</MatrixHeight>",
+        ] {
+            assert!(xml.contains(expected), "{} is missing from\n{}", expected, xml);
+        }
+        assert_eq!(xml.matches("<TileMatrix>").count(), usize::from(MAX_OVERZOOM) + 1);
+
+        // The tiles of 512 pixels have twice as many pixels per meter.
+        let xml = capabilities(&layer, 512);
+        assert!(xml.contains("<ScaleDenominator>279541132.0143589</ScaleDenominator>"));
+        assert!(xml.contains("<TileWidth>512</TileWidth>"));
+        assert!(!xml.contains("WellKnownScaleSet"));
+    }
+}
@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+use renderer::draw::drawer::{DrawOptions, Drawer};
+use renderer::geodata::reader::GeodataReader;
+use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::{StyleType, Styler};
+use std::path::{Path, PathBuf};
 
 pub fn get_test_path(relative_path: &[&str]) -> String {
     let mut test_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -9,3 +13,38 @@ pub fn get_test_path(relative_path: &[&str]) -> String {
 
     test_path.to_str().unwrap().to_string()
 }
+
+// Imports `tests/osm/<osm_file>` into `tests/osm/<bin_file>` and loads the result. The tests run in parallel,
+// so every one of them needs a `bin_file` of its own, which nothing else rewrites while it's loaded.
+#[allow(dead_code)]
+pub fn import_fixture(osm_file: &str, bin_file: &str) -> GeodataReader<'static> {
+    let bin_file = get_test_path(&["osm", bin_file]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", osm_file]), &bin_file).unwrap();
+    GeodataReader::load(&bin_file).unwrap()
+}
+
+// The JOSM stylesheet `tests/mapcss/<mapcss_file>`.
+#[allow(dead_code)]
+pub fn load_styler(mapcss_file: &str) -> Styler {
+    Styler::new(
+        parse_file(Path::new(&get_test_path(&["mapcss"])), mapcss_file).unwrap(),
+        &StyleType::Josm,
+        None,
+    )
+}
+
+// Finds the icons next to the test stylesheets.
+#[allow(dead_code)]
+pub fn new_drawer(options: DrawOptions) -> Drawer {
+    Drawer::new(Path::new(&get_test_path(&["mapcss"])), options)
+}
+
+// Everything it takes to draw the tiles of the test data with the default options.
+#[allow(dead_code)]
+pub fn load_fixture(osm_file: &str, bin_file: &str, mapcss_file: &str) -> (GeodataReader<'static>, Styler, Drawer) {
+    (
+        import_fixture(osm_file, bin_file),
+        load_styler(mapcss_file),
+        new_drawer(DrawOptions::default()),
+    )
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::api_keys::{ApiKeys, KeyError, Quota};
+use renderer::geodata::timestamp::parse_timestamp;
+use std::collections::HashMap;
+use std::fs;
+
+#[test]
+fn test_usage_persistence() {
+    let usage_file = get_test_path(&["test_usage_persistence.usage"]);
+    let _ = fs::remove_file(&usage_file);
+    let quotas = || HashMap::from([("key".to_string(), "2/day".parse::<Quota>().unwrap())]);
+    let now = parse_timestamp("2020-01-01T12:00:00Z").unwrap();
+
+    let keys = ApiKeys::new(quotas(), None, Some(usage_file.clone())).unwrap();
+    assert!(keys.use_tile(Some("key"), now).is_ok());
+    keys.save().unwrap();
+    assert_eq!(fs::read_to_string(&usage_file).unwrap(), "key 2020-01-01 1 1\n");
+
+    // The restarted server continues from the saved usage.
+    let keys = ApiKeys::new(quotas(), None, Some(usage_file.clone())).unwrap();
+    assert!(keys.use_tile(Some("key"), now).is_ok());
+    assert!(matches!(
+        keys.use_tile(Some("key"), now),
+        Err(KeyError::QuotaExceeded { period: "day", .. })
+    ));
+    keys.save().unwrap();
+    assert_eq!(fs::read_to_string(&usage_file).unwrap(), "key 2020-01-01 2 2\n");
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use renderer::geodata::geojson::tile_to_geojson;
+use renderer::tile::{coords_to_tile, xy_to_coords, TILE_SIZE};
+
+#[test]
+fn test_tile_to_geojson() {
+    let reader = common::import_fixture("geojson.osm", "geojson.bin");
+
+    let tile = coords_to_tile(&(55.7530, 37.6124), 18);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let geojson = tile_to_geojson(&entities, &tile, None);
+
+    assert!(geojson.starts_with("{\"type\":\"FeatureCollection\",\"features\":["));
+    assert!(geojson.contains(
+        "{\"type\":\"Feature\",\"id\":\"node/1\",\"properties\":{\"amenity\":\"cafe\",\"name\":\"Cafe\"},\
+         \"geometry\":{\"type\":\"Point\",\"coordinates\":[37.6121000,55.7531000]}}"
+    ));
+    // Outside of the tile.
+    assert!(!geojson.contains("node/2"));
+
+    // The street is cut off at the left and the right edges of the tile.
+    let (_, min_lon) = xy_to_coords(f64::from(tile.x * TILE_SIZE), 0.0, tile.zoom);
+    let (_, max_lon) = xy_to_coords(f64::from((tile.x + 1) * TILE_SIZE), 0.0, tile.zoom);
+    assert!(geojson.contains(&format!(
+        "\"id\":\"way/1\",\"properties\":{{\"highway\":\"residential\",\"name\":\"Street\"}},\
+         \"geometry\":{{\"type\":\"LineString\",\"coordinates\":[[{:.7},55.7530000],[{:.7},55.7530000]]}}",
+        min_lon, max_lon
+    )));
+
+    // A single polygon with the outer ring (which is cut off at the top of the tile) and the hole.
+    let relation_pos = geojson.find("\"id\":\"relation/1\"").unwrap();
+    let relation = &geojson[relation_pos..];
+    assert!(relation.contains("\"properties\":{\"landuse\":\"grass\",\"type\":\"multipolygon\"}"));
+    assert!(relation.contains("\"geometry\":{\"type\":\"MultiPolygon\",\"coordinates\":[[["));
+    assert_eq!(relation.matches("]],[[").count(), 1);
+    assert!(!relation.contains("55.7536000"));
+}
+
+#[test]
+fn test_selected_tags() {
+    let reader = common::import_fixture("geojson.osm", "geojson_tags.bin");
+
+    let tile = coords_to_tile(&(55.7530, 37.6124), 18);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let geojson = tile_to_geojson(&entities, &tile, Some(&["name".to_string()]));
+
+    assert!(geojson.contains("\"id\":\"node/1\",\"properties\":{\"name\":\"Cafe\"}"));
+    assert!(geojson.contains("\"id\":\"relation/1\",\"properties\":{}"));
+}
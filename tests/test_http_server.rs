@@ -0,0 +1,448 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::cache_control::{CacheControl, CachePolicy};
+use renderer::draw::drawer::DrawOptions;
+use renderer::http_server::{run_server_on, MapConfig, ServerOptions};
+use renderer::mapcss::styler::StyleType;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+// A tile in the middle of the test data.
+const TILE: &str = "15/19807/10242";
+
+// The tests share a single server on a free port, which runs until the end of the test run.
+fn server_url() -> &'static str {
+    static URL: OnceLock<String> = OnceLock::new();
+    URL.get_or_init(|| {
+        let bin_file = get_test_path(&["osm", "nano_moscow_http.bin"]);
+        renderer::geodata::importer::import(&get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+        let map = MapConfig {
+            name: None,
+            geodata_file: bin_file,
+            stylesheet_file: get_test_path(&["mapcss", "mapnik.mapcss"]),
+            stylesheet_type: StyleType::Josm,
+            font_size_multiplier: None,
+            draw_options: DrawOptions::default(),
+            upstream: None,
+            dark_mode: None,
+            palettes: Vec::new(),
+            settings: Vec::new(),
+            attribution: None,
+        };
+        let mut cache_control = CacheControl::default();
+        cache_control
+            .add("0-14", CachePolicy::MaxAge(Duration::from_secs(3600)))
+            .unwrap();
+        cache_control
+            .add("15-", CachePolicy::Directives("no-cache".to_string()))
+            .unwrap();
+        let options = ServerOptions {
+            cache_control,
+            cors: "https://example.com".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || run_server_on(listener, vec![map], options).unwrap());
+        // The server answers the probes while it loads the maps, and the tiles once it's ready.
+        let ready_url = format!("{}/readyz", url);
+        while ureq::get(&ready_url).call().is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        url
+    })
+}
+
+struct TestResponse {
+    status: u16,
+    // By the lowercase names, with all the values of the repeated headers.
+    headers: HashMap<String, Vec<String>>,
+    body: Vec<u8>,
+}
+
+impl TestResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|values| values[0].as_str())
+    }
+}
+
+fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> TestResponse {
+    let mut request = ureq::request(method, &format!("{}{}", server_url(), path));
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => panic!("{} {} failed: {}", method, path, e),
+    };
+    let headers = response
+        .headers_names()
+        .into_iter()
+        .map(|name| {
+            let values = response.all(&name).into_iter().map(str::to_string).collect();
+            (name, values)
+        })
+        .collect();
+    let status = response.status();
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body).unwrap();
+    TestResponse { status, headers, body }
+}
+
+fn get(path: &str) -> TestResponse {
+    request("GET", path, &[])
+}
+
+// The width of a PNG image, from its header.
+fn png_width(png: &[u8]) -> u32 {
+    assert!(png.starts_with(b"\x89PNG"));
+    u32::from_be_bytes(png[16..20].try_into().unwrap())
+}
+
+#[test]
+fn test_tile_formats() {
+    let png = get(&format!("/{}.png", TILE));
+    assert_eq!(png.status, 200);
+    assert_eq!(png.header("content-type"), Some("image/png"));
+    assert_eq!(png_width(&png.body), 256);
+    assert_eq!(png.header("content-length"), Some(png.body.len().to_string().as_str()));
+
+    let png16 = get(&format!("/{}.png16", TILE));
+    assert_eq!(png16.header("content-type"), Some("image/png"));
+    // The bit depth follows the width and the height.
+    assert_eq!(png16.body[24], 16);
+
+    let webp = get(&format!("/{}.webp", TILE));
+    assert_eq!(webp.header("content-type"), Some("image/webp"));
+    assert!(webp.body.starts_with(b"RIFF") && &webp.body[8..12] == b"WEBP");
+
+    let rgba = get(&format!("/{}.rgba", TILE));
+    assert_eq!(rgba.body.len(), 256 * 256 * 4);
+    assert!(get(&format!("/{}.pam", TILE)).body.starts_with(b"P7"));
+    assert!(get(&format!("/{}.ppm", TILE)).body.starts_with(b"P6"));
+
+    let geojson = get(&format!("/{}.geojson", TILE));
+    assert_eq!(geojson.header("content-type"), Some("application/geo+json"));
+    assert!(geojson.body.starts_with(b"{"));
+
+    assert_eq!(png_width(&get(&format!("/{}@2x.png", TILE)).body), 512);
+    for layer in ["base", "labels"] {
+        let layer_png = get(&format!("/{}/{}.png", layer, TILE));
+        assert_eq!(layer_png.status, 200);
+        assert_eq!(png_width(&layer_png.body), 256);
+    }
+}
+
+#[test]
+fn test_content_negotiation() {
+    let path = format!("/{}", TILE);
+    let webp = request("GET", &path, &[("Accept", "image/avif,image/webp,*/*")]);
+    assert_eq!(webp.header("content-type"), Some("image/webp"));
+    assert!(webp.headers["vary"].iter().any(|vary| vary == "Accept"));
+
+    // The wildcards don't count.
+    let png = request("GET", &path, &[("Accept", "image/*")]);
+    assert_eq!(png.header("content-type"), Some("image/png"));
+    assert!(png.headers["vary"].iter().any(|vary| vary == "Accept"));
+}
+
+#[test]
+fn test_caching_headers() {
+    let low_zoom = get("/14/9903/5121.png");
+    assert_eq!(low_zoom.header("cache-control"), Some("public, max-age=3600"));
+    assert!(low_zoom
+        .header("expires")
+        .is_some_and(|expires| expires.ends_with(" GMT")));
+    let high_zoom = get(&format!("/{}.png", TILE));
+    assert_eq!(high_zoom.header("cache-control"), Some("no-cache"));
+    assert!(high_zoom.header("expires").is_none());
+
+    let path = format!("/{}.png", TILE);
+    let etag = high_zoom.header("etag").unwrap();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+    assert_eq!(get(&path).header("etag"), Some(etag));
+    assert_ne!(get(&format!("/{}.webp", TILE)).header("etag"), Some(etag));
+
+    for if_none_match in [etag.to_string(), format!("W/{}", etag), "*".to_string()] {
+        let not_modified = request("GET", &path, &[("If-None-Match", &if_none_match)]);
+        assert_eq!(not_modified.status, 304);
+        assert!(not_modified.body.is_empty());
+        assert_eq!(not_modified.header("etag"), Some(etag));
+        assert_eq!(not_modified.header("cache-control"), Some("no-cache"));
+    }
+    let modified = request("GET", &path, &[("If-None-Match", "\"something else\"")]);
+    assert_eq!(modified.status, 200);
+}
+
+#[test]
+fn test_cors() {
+    let path = format!("/{}.png", TILE);
+    let allowed = request("GET", &path, &[("Origin", "https://example.com")]);
+    assert_eq!(
+        allowed.header("access-control-allow-origin"),
+        Some("https://example.com")
+    );
+    assert!(allowed.headers["vary"].iter().any(|vary| vary == "Origin"));
+
+    let other = request("GET", &path, &[("Origin", "https://example.org")]);
+    assert_eq!(other.status, 200);
+    assert!(other.header("access-control-allow-origin").is_none());
+
+    let preflight = request(
+        "OPTIONS",
+        &path,
+        &[
+            ("Origin", "https://example.com"),
+            ("Access-Control-Request-Method", "GET"),
+            ("Access-Control-Request-Headers", "authorization"),
+        ],
+    );
+    assert_eq!(preflight.status, 204);
+    assert_eq!(
+        preflight.header("access-control-allow-origin"),
+        Some("https://example.com")
+    );
+    assert_eq!(preflight.header("access-control-allow-methods"), Some("GET, OPTIONS"));
+    assert_eq!(preflight.header("access-control-allow-headers"), Some("authorization"));
+}
+
+#[test]
+fn test_tile_coordinates() {
+    // From the whole world down to the overzoomed tiles beyond the max zoom of the geodata.
+    for path in [
+        "/0/0/0.png",
+        "/10/618/320.png",
+        "/18/158456/81936.png",
+        "/22/2535296/1310976.png",
+    ] {
+        let response = get(path);
+        assert_eq!(response.status, 200, "{}", path);
+        assert_eq!(png_width(&response.body), 256, "{}", path);
+    }
+}
+
+#[test]
+fn test_invalid_requests() {
+    // The paths that can't mean anything.
+    for path in [
+        "/abc/0/0.png",
+        "/1/-1/0.png",
+        "/1/+1/0.png",
+        "/1/0/0.5.png",
+        "/1/0/0.jpg",
+        "/1/0/0@5x.png",
+        "/15/19807/10242.png?date=yesterday",
+        "/15/19807/10242.png?fade=2",
+        "/15/19807/10242.png?level=ground",
+        "/15/19807/10242.png?palette=sepia",
+        "/15/19807/10242.png?palette=",
+        "/static?bbox=nonsense",
+    ] {
+        let response = get(path);
+        assert_eq!(response.status, 400, "{}", path);
+        assert_eq!(response.header("content-type"), Some("text/plain"), "{}", path);
+        assert!(!response.body.is_empty(), "{}", path);
+    }
+    // The paths of the things the server doesn't have.
+    for path in [
+        "/23/0/0.png",
+        "/1/2/0.png",
+        "/1/0/2.png",
+        "/18/262144/0.png",
+        "/99999999999/0/0.png",
+        "/1/0.png",
+        "/1/0/0/0.png",
+        "/paris/1/0/0.png",
+        "/",
+        "/nonsense",
+        "/sprite.png",
+        "/usage",
+        "/15/19807/10242.png?palette=deuteranopia",
+    ] {
+        assert_eq!(get(path).status, 404, "{}", path);
+    }
+    assert_eq!(request("POST", &format!("/{}.png", TILE), &[]).status, 400);
+    assert_eq!(request("DELETE", "/shutdown", &[]).status, 400);
+
+    // The HTTP clients resolve the dot segments before sending the paths, so these go over a socket of their own.
+    for path in [
+        "/../../etc/passwd",
+        "/%2e%2e/%2e%2e/etc/passwd",
+        "/base/..%2f15/1/2.png",
+    ] {
+        let mut stream = TcpStream::connect(server_url().trim_start_matches("http://")).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}: {}", path, response);
+    }
+
+    // Nothing of the above takes the server down.
+    assert_eq!(get(&format!("/{}.png", TILE)).status, 200);
+}
+
+#[test]
+fn test_concurrent_requests() {
+    // The same tile for many clients at once (here all from the same address, as if behind the same proxy),
+    // which is drawn once and given to all of them.
+    let tiles = thread::scope(|s| {
+        let requests = (0..12)
+            .map(|_| s.spawn(|| get("/16/39614/20485.png")))
+            .collect::<Vec<_>>();
+        requests.into_iter().map(|r| r.join().unwrap()).collect::<Vec<_>>()
+    });
+    for tile in &tiles {
+        assert_eq!(tile.status, 200);
+        assert_eq!(tile.body, tiles[0].body);
+        assert_eq!(tile.header("etag"), tiles[0].header("etag"));
+    }
+}
+
+#[test]
+fn test_service_endpoints() {
+    let memory = get("/memory");
+    assert_eq!(memory.status, 200);
+    assert_eq!(memory.header("content-type"), Some("application/json"));
+    assert!(memory.body.starts_with(b"{"));
+
+    for probe in ["/healthz", "/readyz"] {
+        let response = get(probe);
+        assert_eq!(response.status, 200, "{}", probe);
+        assert_eq!(response.body, b"OK\n", "{}", probe);
+    }
+
+    // Without the API keys, the geodata is reloaded for the requests from the same host.
+    let reload = get("/reload");
+    assert_eq!(reload.status, 200);
+    assert_eq!(reload.body, b"OK\n");
+
+    assert_eq!(get("/13/4951/2560.png").status, 200);
+    let metrics = get("/metrics");
+    assert_eq!(metrics.status, 200);
+    assert_eq!(metrics.header("content-type"), Some("text/plain; version=0.0.4"));
+    let metrics = String::from_utf8(metrics.body).unwrap();
+    let value = |name: &str| {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .map(|value| value.parse::<f64>().unwrap())
+    };
+    assert!(value("renderer_tiles_rendered_total").unwrap() >= 1.0);
+    assert!(value("renderer_render_duration_seconds_count{zoom=\"13\"}").unwrap() >= 1.0);
+    assert!(value("renderer_geodata_query_duration_seconds_count").unwrap() >= 1.0);
+    assert!(value("renderer_http_responses_total{status=\"200\"}").unwrap() >= 2.0);
+    assert!(value("renderer_http_response_bytes_total").unwrap() > 0.0);
+}
+
+#[test]
+fn test_tilejson() {
+    let response = get("/tilejson.json");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("application/json"));
+    let tilejson = String::from_utf8(response.body).unwrap();
+    assert!(tilejson.contains(&format!("\"tiles\":[\"{}/{{z}}/{{x}}/{{y}}.png\"]", server_url())));
+    assert!(tilejson.contains("\"minzoom\":0,\"maxzoom\":22"));
+    assert!(tilejson.contains("\"attribution\":\"© OpenStreetMap contributors\""));
+
+    // The bounds cover all of the nodes, rounded out to the tiles that they are in.
+    let bounds = tilejson
+        .split_once("\"bounds\":[")
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .unwrap()
+        .0
+        .split(',')
+        .map(|x| x.parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    let data_bounds = [37.5063561, 55.6935861, 37.6448792, 55.7971341];
+    for (idx, (bound, data_bound)) in bounds.iter().zip(data_bounds).enumerate() {
+        if idx < 2 {
+            assert!(*bound <= data_bound && *bound > data_bound - 0.01, "{}", tilejson);
+        } else {
+            assert!(*bound >= data_bound && *bound < data_bound + 0.01, "{}", tilejson);
+        }
+    }
+
+    // The tile URLs keep the API key and the scheme that a reverse proxy was reached with.
+    let response = request("GET", "/tilejson.json?key=abc", &[("X-Forwarded-Proto", "https")]);
+    let tilejson = String::from_utf8(response.body).unwrap();
+    let host = server_url().strip_prefix("http://").unwrap();
+    assert!(tilejson.contains(&format!("\"https://{}/{{z}}/{{x}}/{{y}}.png?key=abc\"", host)));
+}
+
+#[test]
+fn test_wmts() {
+    let response = get("/wmts?SERVICE=WMTS&REQUEST=GetCapabilities&VERSION=1.0.0");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("application/xml"));
+    let capabilities = String::from_utf8(response.body).unwrap();
+    assert!(capabilities.contains("<ows:Identifier>map</ows:Identifier>"));
+    assert!(capabilities.contains(&format!(
+        "template=\"{}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png\"",
+        server_url()
+    )));
+    assert!(capabilities.contains("<ows:Identifier>22</ows:Identifier>"));
+
+    // GetTile serves the same tile as its XYZ path.
+    let response = get(
+        "/wmts?SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0&LAYER=map&STYLE=default&FORMAT=image%2Fpng\
+         &TILEMATRIXSET=GoogleMapsCompatible&TILEMATRIX=15&TILEROW=10244&TILECOL=19805",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("image/png"));
+    assert_eq!(response.body, get("/15/19805/10244.png").body);
+
+    let get_tile = "/wmts?SERVICE=WMTS&REQUEST=GetTile&TILEMATRIXSET=GoogleMapsCompatible&TILEMATRIX=15\
+                    &TILEROW=10244&TILECOL=19805";
+    assert_eq!(get(&format!("{}&LAYER=unknown", get_tile)).status, 404);
+    assert_eq!(get(&format!("{}&LAYER=map&FORMAT=image/gif", get_tile)).status, 400);
+}
+
+#[test]
+fn test_tms() {
+    // The rows of TMS go up from the bottom: 2^15 - 1 - 10244 = 22523.
+    let response = get("/tms/15/19805/22523.png");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, get("/15/19805/10244.png").body);
+    assert_eq!(get("/tms/15/19805/32768.png").status, 404);
+}
+
+#[test]
+fn test_quadkey() {
+    let response = get("/q/120310101011301.png");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, get("/15/19805/10244.png").body);
+    assert_eq!(get("/q/120310101011304.png").status, 400);
+}
+
+#[test]
+fn test_failed_load() {
+    // The server stops, instead of staying not ready forever.
+    let map = MapConfig {
+        name: None,
+        geodata_file: get_test_path(&["osm", "nonexistent.bin"]),
+        stylesheet_file: get_test_path(&["mapcss", "mapnik.mapcss"]),
+        stylesheet_type: StyleType::Josm,
+        font_size_multiplier: None,
+        draw_options: DrawOptions::default(),
+        upstream: None,
+        dark_mode: None,
+        palettes: Vec::new(),
+        settings: Vec::new(),
+        attribution: None,
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let error = run_server_on(listener, vec![map], ServerOptions::default()).unwrap_err();
+    assert!(format!("{:#}", error).contains("Failed to load the geodata file"));
+}
@@ -0,0 +1,24 @@
+#![cfg(feature = "svg")]
+
+mod common;
+
+use renderer::draw::icon::Icon;
+
+#[test]
+fn test_svg_icon_sizes() {
+    let icon_path = common::get_test_path(&["mapcss", "symbols", "rectangle.svg"]);
+    let size = |width, height, scale| {
+        let icon = Icon::load_svg(&icon_path, width, height, scale).unwrap();
+        (icon.width, icon.height)
+    };
+
+    assert_eq!(size(None, None, 1), (16, 8));
+    assert_eq!(size(None, None, 2), (32, 16));
+    assert_eq!(size(Some(32), None, 1), (32, 16));
+    assert_eq!(size(None, Some(4), 1), (8, 4));
+    assert_eq!(size(Some(10), Some(10), 1), (10, 10));
+
+    let icon = Icon::load_svg(&icon_path, Some(32), None, 1).unwrap();
+    let center = icon.get(16, 8);
+    assert_eq!((center.r, center.g, center.b, center.a), (1.0, 0.0, 0.0, 1.0));
+}
@@ -0,0 +1,542 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::coords::Coords;
+use renderer::geodata::importer::{CoordinatePrecision, ImportOptions};
+use renderer::geodata::indoor::retain_level;
+use renderer::geodata::reader::{GeodataReader, OsmEntity, Tags};
+use renderer::geodata::timestamp::parse_timestamp;
+use renderer::progress::{Progress, ProgressFormat};
+use renderer::tile::coords_to_max_zoom_tile;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_malformed_entities_are_skipped() {
+    let reader = common::import_fixture("malformed.osm", "malformed.bin");
+
+    let tile = coords_to_max_zoom_tile(&(55.7531f64, 37.6131f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+    let mut node_ids = entities.nodes.iter().map(|n| n.global_id()).collect::<Vec<_>>();
+    node_ids.sort_unstable();
+    // Node 2 has no tags, so it's only available as a part of the ways.
+    assert_eq!(node_ids, vec![1, 6]);
+
+    let mut poi_ids = reader
+        .get_pois_in_tile_with_neighbors(&tile, &None)
+        .iter()
+        .map(|n| n.global_id())
+        .collect::<Vec<_>>();
+    poi_ids.sort_unstable();
+    assert_eq!(poi_ids, node_ids);
+
+    let mut way_ids = entities.ways.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+    way_ids.sort_unstable();
+    assert_eq!(way_ids, vec![10, 12]);
+
+    let way = entities.ways.iter().find(|w| w.global_id() == 10).unwrap();
+    assert_eq!(way.node_count(), 2);
+
+    let way = entities.ways.iter().find(|w| w.global_id() == 12).unwrap();
+    let way_node_ids = (0..way.node_count())
+        .map(|i| way.get_node(i).global_id())
+        .collect::<Vec<_>>();
+    assert_eq!(way_node_ids, vec![2, 6]);
+}
+
+#[test]
+fn test_compressed_input() {
+    let plain_bin = get_test_path(&["osm", "malformed_plain.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "malformed.osm"]), &plain_bin).unwrap();
+    let expected = std::fs::read(&plain_bin).unwrap();
+
+    for (compressed, bin) in [
+        ("malformed.osm.gz", "malformed_gz.bin"),
+        ("malformed.osm.bz2", "malformed_bz2.bin"),
+    ] {
+        let bin_file = get_test_path(&["osm", bin]);
+        renderer::geodata::importer::import(&get_test_path(&["osm", compressed]), &bin_file).unwrap();
+        assert_eq!(std::fs::read(&bin_file).unwrap(), expected, "{}", compressed);
+    }
+}
+
+#[test]
+fn test_input_from_stdin() {
+    let plain_bin = get_test_path(&["osm", "malformed_plain_stdin.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "malformed.osm"]), &plain_bin).unwrap();
+    let expected = std::fs::read(&plain_bin).unwrap();
+
+    let bin_file = get_test_path(&["osm", "malformed_stdin.bin"]);
+    let input = std::fs::File::open(get_test_path(&["osm", "malformed.osm.bz2"])).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_importer"))
+        .args(["-", &bin_file])
+        .stdin(input)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read(&bin_file).unwrap(), expected);
+}
+
+#[test]
+fn test_keep_untagged_nodes() {
+    let bin_file = get_test_path(&["osm", "malformed_untagged.bin"]);
+    let options = ImportOptions {
+        keep_untagged_nodes: true,
+        ..Default::default()
+    };
+    let mut progress = Progress::new(ProgressFormat::Text);
+    renderer::geodata::importer::import_with_progress(
+        &get_test_path(&["osm", "malformed.osm"]),
+        &bin_file,
+        &options,
+        &mut progress,
+    )
+    .unwrap();
+    let reader = GeodataReader::load(&bin_file).unwrap();
+
+    let tile = coords_to_max_zoom_tile(&(55.7531f64, 37.6131f64));
+    let mut node_ids = reader
+        .get_pois_in_tile_with_neighbors(&tile, &None)
+        .iter()
+        .map(|n| n.global_id())
+        .collect::<Vec<_>>();
+    node_ids.sort_unstable();
+    assert_eq!(node_ids, vec![1, 2, 6]);
+}
+
+#[test]
+fn test_history_with_timestamps() {
+    let bin_file = get_test_path(&["osm", "history.bin"]);
+    let options = ImportOptions {
+        keep_timestamps: true,
+        ..Default::default()
+    };
+    let mut progress = Progress::new(ProgressFormat::Text);
+    renderer::geodata::importer::import_with_progress(
+        &get_test_path(&["osm", "history.osm"]),
+        &bin_file,
+        &options,
+        &mut progress,
+    )
+    .unwrap();
+    let reader = GeodataReader::load(&bin_file).unwrap();
+
+    let tile = coords_to_max_zoom_tile(&(55.7532f64, 37.6132f64));
+    let describe_at = |date: Option<&str>| {
+        let date = date.map(|d| parse_timestamp(d).unwrap());
+        let entities = reader.get_entities_in_tile_with_neighbors_at(&tile, &None, date);
+        let describe = |id: u64, tags: Tags<'_>, key: &str| format!("{}:{}", id, tags.get_by_key(key).unwrap_or("?"));
+        let mut nodes = entities
+            .nodes
+            .iter()
+            .map(|n| {
+                describe(
+                    n.global_id(),
+                    n.tags(),
+                    if n.global_id() == 1 { "amenity" } else { "shop" },
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut ways = entities
+            .ways
+            .iter()
+            .map(|w| describe(w.global_id(), w.tags(), "highway"))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        ways.sort();
+        (nodes, ways)
+    };
+
+    assert_eq!(
+        describe_at(Some("2014-01-01")),
+        (vec![], vec!["10:footway".to_string()])
+    );
+    assert_eq!(
+        describe_at(Some("2016-06-01")),
+        (
+            vec!["1:cafe".to_string()],
+            vec!["10:footway".to_string(), "11:service".to_string()]
+        )
+    );
+    assert_eq!(
+        describe_at(Some("2020-01-01")),
+        (
+            vec!["1:restaurant".to_string(), "4:bakery".to_string()],
+            vec!["10:path".to_string()]
+        )
+    );
+    assert_eq!(
+        describe_at(None),
+        (vec!["4:bakery".to_string()], vec!["10:path".to_string()])
+    );
+}
+
+#[test]
+fn test_indoor_levels() {
+    let reader = common::import_fixture("indoor.osm", "indoor.bin");
+
+    let tile = coords_to_max_zoom_tile(&(55.7528f64, 37.6125f64));
+    let ids_on_level = |level: Option<f64>| {
+        let mut entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        if let Some(level) = level {
+            retain_level(&mut entities, level);
+        }
+        let mut node_ids = entities.nodes.iter().map(|n| n.global_id()).collect::<Vec<_>>();
+        let mut way_ids = entities.ways.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+        node_ids.sort_unstable();
+        way_ids.sort_unstable();
+        (node_ids, way_ids)
+    };
+
+    // The building and the cafe without a level are on every level, along with the walls that span the levels.
+    assert_eq!(ids_on_level(None), (vec![6, 7, 8], vec![10, 11, 12, 13, 14, 15]));
+    assert_eq!(ids_on_level(Some(0.0)), (vec![6, 8], vec![10, 11, 13, 14, 15]));
+    assert_eq!(ids_on_level(Some(1.0)), (vec![7, 8], vec![10, 12, 13, 14, 15]));
+    assert_eq!(ids_on_level(Some(2.0)), (vec![8], vec![10, 15]));
+    assert_eq!(ids_on_level(Some(-1.0)), (vec![8], vec![10, 14]));
+}
+
+#[test]
+fn test_deleted_entities() {
+    // The objects that are deleted, recreated, and deleted while something still refers to them.
+    let describe_at = |keep_timestamps: bool, dates: &[Option<&str>]| {
+        let bin_file = get_test_path(&[
+            "osm",
+            if keep_timestamps {
+                "deletions_history.bin"
+            } else {
+                "deletions.bin"
+            },
+        ]);
+        let options = ImportOptions {
+            keep_timestamps,
+            ..Default::default()
+        };
+        let mut progress = Progress::new(ProgressFormat::Text);
+        renderer::geodata::importer::import_with_progress(
+            &get_test_path(&["osm", "deletions.osm"]),
+            &bin_file,
+            &options,
+            &mut progress,
+        )
+        .unwrap();
+        let reader = GeodataReader::load(&bin_file).unwrap();
+
+        let tile = coords_to_max_zoom_tile(&(55.7532f64, 37.6132f64));
+        let describe = |date: &Option<&str>| {
+            let date = date.map(|d| parse_timestamp(d).unwrap());
+            let entities = reader.get_entities_in_tile_with_neighbors_at(&tile, &None, date);
+            let mut descriptions = entities
+                .nodes
+                .iter()
+                .map(|n| format!("{}:{:?}", n.global_id(), n.tags().iter().collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            for way in &entities.ways {
+                let node_ids = (0..way.node_count())
+                    .map(|idx| way.get_node(idx).global_id())
+                    .collect::<Vec<_>>();
+                let highway = way.tags().get_by_key("highway").unwrap_or("?");
+                descriptions.push(format!("{}:{}:{:?}", way.global_id(), highway, node_ids));
+            }
+            for multipolygon in &entities.multipolygons {
+                descriptions.push(format!("{}:{}", multipolygon.global_id(), multipolygon.polygon_count()));
+            }
+            descriptions.sort();
+            descriptions
+        };
+        dates.iter().map(describe).collect::<Vec<_>>()
+    };
+
+    // Only the current versions are left, without the references to the deleted nodes and ways.
+    assert_eq!(
+        describe_at(false, &[None]),
+        vec![vec![
+            "1:[(\"shop\", \"bakery\")]",
+            "20:footway:[2, 3]",
+            "21:track:[3, 2]",
+            "22:path:[3]",
+        ]]
+    );
+
+    // Every version at its own dates, with the last known positions of the nodes.
+    assert_eq!(
+        describe_at(
+            true,
+            &[Some("2012-06-01"), Some("2016-06-01"), Some("2020-01-01"), None]
+        ),
+        vec![
+            vec![
+                "1:[(\"amenity\", \"cafe\")]",
+                "20:footway:[2, 3, 5]",
+                "21:service:[2, 5]",
+                "23:?:[6, 7, 8, 6]",
+            ],
+            vec!["20:footway:[2, 3]"],
+            vec![
+                "1:[(\"shop\", \"bakery\")]",
+                "20:footway:[2, 3]",
+                "21:track:[3, 2]",
+                "22:path:[3, 5]",
+                "30:1",
+            ],
+            vec![
+                "1:[(\"shop\", \"bakery\")]",
+                "20:footway:[2, 3]",
+                "21:track:[3, 2]",
+                "22:path:[3, 5]",
+                "30:1",
+            ],
+        ]
+    );
+}
+
+#[test]
+fn test_update() {
+    let base_bin = get_test_path(&["osm", "update_base.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "update_base.osm"]), &base_bin).unwrap();
+
+    let describe = |bin_file: &str| {
+        let reader = GeodataReader::load(bin_file).unwrap();
+        let tile = coords_to_max_zoom_tile(&(55.7531f64, 37.6124f64));
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let tags = |tags: Tags<'_>| {
+            tags.iter()
+                .filter(|(k, _)| *k != "type")
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut nodes = entities
+            .nodes
+            .iter()
+            .map(|n| format!("{}:{}", n.global_id(), tags(n.tags())))
+            .collect::<Vec<_>>();
+        let mut ways = entities
+            .ways
+            .iter()
+            .map(|w| {
+                let lats = (0..w.node_count()).map(|i| w.get_node(i).lat().to_string());
+                format!("{}:{}", w.global_id(), lats.collect::<Vec<_>>().join(","))
+            })
+            .collect::<Vec<_>>();
+        let mut multipolygons = entities
+            .multipolygons
+            .iter()
+            .map(|mp| {
+                let polygon = mp.get_polygon(0);
+                let lats = (0..polygon.node_count()).map(|i| polygon.get_node(i).lat().to_string());
+                format!(
+                    "{}:{}:{}",
+                    mp.global_id(),
+                    tags(mp.tags()),
+                    lats.collect::<Vec<_>>().join(",")
+                )
+            })
+            .collect::<Vec<_>>();
+        nodes.sort();
+        ways.sort();
+        multipolygons.sort();
+        (nodes, ways, multipolygons)
+    };
+
+    let updated_bin = get_test_path(&["osm", "update_updated.bin"]);
+    let mut progress = Progress::new(ProgressFormat::Text);
+    renderer::geodata::importer::update_with_progress(
+        &base_bin,
+        &[get_test_path(&["osm", "update.osc"])],
+        &updated_bin,
+        None,
+        &mut progress,
+    )
+    .unwrap();
+    // The ways and the multipolygons that aren't in the changes follow their moved nodes.
+    let (nodes, ways, multipolygons) = describe(&updated_bin);
+    assert_eq!(nodes, vec!["100:tourism=information", "1:shop=butcher"]);
+    assert_eq!(
+        ways,
+        vec![
+            "101:55.7529,55.7531",
+            "10:55.7532,55.7531",
+            "11:55.7534,55.7533,55.7535,55.7534"
+        ]
+    );
+    let [multipolygon] = &multipolygons[..] else {
+        panic!("{:?}", multipolygons);
+    };
+    assert!(multipolygon.starts_with("20:landuse=meadow:"), "{}", multipolygon);
+    assert!(multipolygon.contains("55.7534"), "{}", multipolygon);
+
+    // The binary replaces the geodata with the updated one, and the changes are applied in order.
+    let copied_bin = get_test_path(&["osm", "update_copied.bin"]);
+    std::fs::copy(&base_bin, &copied_bin).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_importer"))
+        .args([
+            "update",
+            &copied_bin,
+            &get_test_path(&["osm", "update.osc"]),
+            &get_test_path(&["osm", "update2.osc"]),
+        ])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let (nodes, ways, multipolygons) = describe(&copied_bin);
+    assert_eq!(nodes, vec!["100:tourism=viewpoint", "1:shop=butcher"]);
+    assert_eq!(ways.len(), 3);
+    assert!(multipolygons.is_empty());
+
+    // Applying nothing changes nothing.
+    let same_bin = get_test_path(&["osm", "update_same.bin"]);
+    let no_changes: [&str; 0] = [];
+    renderer::geodata::importer::update_with_progress(&base_bin, &no_changes, &same_bin, None, &mut progress).unwrap();
+    assert_eq!(std::fs::read(&same_bin).unwrap(), std::fs::read(&base_bin).unwrap());
+
+    // The history can't be updated.
+    let history_bin = get_test_path(&["osm", "update_history.bin"]);
+    let options = ImportOptions {
+        keep_timestamps: true,
+        ..Default::default()
+    };
+    renderer::geodata::importer::import_with_progress(
+        &get_test_path(&["osm", "history.osm"]),
+        &history_bin,
+        &options,
+        &mut progress,
+    )
+    .unwrap();
+    let history_update = renderer::geodata::importer::update_with_progress(
+        &history_bin,
+        &[get_test_path(&["osm", "update.osc"])],
+        &get_test_path(&["osm", "update_history_updated.bin"]),
+        None,
+        &mut progress,
+    );
+    assert!(history_update.is_err());
+}
+
+#[test]
+fn test_coordinate_precision() {
+    let import = |bin_name: &str, coordinates: CoordinatePrecision| {
+        let bin_file = get_test_path(&["osm", bin_name]);
+        let options = ImportOptions {
+            coordinates,
+            ..Default::default()
+        };
+        let mut progress = Progress::new(ProgressFormat::Text);
+        renderer::geodata::importer::import_with_progress(
+            &get_test_path(&["osm", "nano_moscow.osm"]),
+            &bin_file,
+            &options,
+            &mut progress,
+        )
+        .map(|_| bin_file)
+    };
+    let lossless = import("nano_moscow_lossless.bin", CoordinatePrecision::Lossless).unwrap();
+    let quantized = import("nano_moscow_quantized.bin", CoordinatePrecision::default()).unwrap();
+    let coarse = import("nano_moscow_coarse.bin", CoordinatePrecision::Digits(5)).unwrap();
+    assert!(import("nano_moscow_too_fine.bin", CoordinatePrecision::Digits(8)).is_err());
+
+    let file_size = |bin_file: &str| std::fs::metadata(bin_file).unwrap().len();
+    assert!(file_size(&quantized) < file_size(&lossless));
+
+    // The nodes of all the ways in the tile, in the same order in all the files.
+    let tile = coords_to_max_zoom_tile(&(55.7500f64, 37.6200f64));
+    let get_coords = |bin_file: &str| {
+        let reader = GeodataReader::load(bin_file).unwrap();
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let mut ways = entities.ways;
+        ways.sort_by_key(|w| w.global_id());
+        ways.iter()
+            .flat_map(|w| (0..w.node_count()).map(|idx| w.get_node(idx)).collect::<Vec<_>>())
+            .map(|n| (n.global_id(), n.lat(), n.lon()))
+            .collect::<Vec<_>>()
+    };
+    let lossless_coords = get_coords(&lossless);
+    assert!(!lossless_coords.is_empty());
+    // The input has 7 digits, so nothing is lost by default.
+    assert_eq!(get_coords(&quantized), lossless_coords);
+    let coarse_coords = get_coords(&coarse);
+    assert_eq!(coarse_coords.len(), lossless_coords.len());
+    for ((id, lat, lon), (coarse_id, coarse_lat, coarse_lon)) in lossless_coords.iter().zip(&coarse_coords) {
+        assert_eq!(id, coarse_id);
+        assert!((lat - coarse_lat).abs() <= 0.5e-5 + 1e-12);
+        assert!((lon - coarse_lon).abs() <= 0.5e-5 + 1e-12);
+        assert_eq!(*coarse_lat, (coarse_lat * 1e5).round() / 1e5);
+    }
+}
+
+#[test]
+fn test_shared_rings() {
+    let reader = common::import_fixture("shared_rings.osm", "shared_rings.bin");
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6135f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut way_ids = entities.ways.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+    way_ids.sort_unstable();
+    assert_eq!(way_ids, vec![100, 101, 102, 103]);
+
+    // The forest relation is the same as its way, while the park around the fence and the building with
+    // a courtyard are drawn in addition to their ways.
+    let mut multipolygons = entities.multipolygons;
+    multipolygons.sort_by_key(|m| m.global_id());
+    let multipolygon_ids = multipolygons.iter().map(|m| m.global_id()).collect::<Vec<_>>();
+    assert_eq!(multipolygon_ids, vec![201, 202]);
+    assert_eq!(multipolygons[1].polygon_count(), 2);
+
+    let fence = entities.ways.iter().find(|w| w.global_id() == 101).unwrap();
+    let fence_nodes = (0..fence.node_count())
+        .map(|idx| fence.get_node(idx).global_id())
+        .collect::<Vec<_>>();
+    let park = multipolygons[0].get_polygon(0);
+    let park_nodes = (0..park.node_count())
+        .map(|idx| park.get_node(idx).global_id())
+        .collect::<Vec<_>>();
+    assert_eq!(park_nodes, fence_nodes);
+    assert_eq!(multipolygons[0].tags().get_by_key("leisure"), Some("park"));
+    assert_eq!(fence.tags().get_by_key("barrier"), Some("fence"));
+}
+
+#[test]
+fn test_place_names() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_places.bin");
+
+    // The case doesn't matter.
+    let city = reader.find_places("москва");
+    assert_eq!(city.len(), 1);
+    assert_eq!((city[0].min_lat, city[0].min_lon), (55.7507178, 37.6176606));
+    assert_eq!((city[0].min_lat, city[0].min_lon), (city[0].max_lat, city[0].max_lon));
+
+    // Two multipolygons have this name.
+    let tower = reader.find_places("Сенатская башня");
+    assert_eq!(tower.len(), 2);
+    for bounds in tower {
+        assert!(bounds.min_lat < bounds.max_lat && bounds.min_lon < bounds.max_lon);
+        assert!(bounds.min_lat > 55.75 && bounds.max_lat < 55.76);
+    }
+
+    assert!(reader.find_places("Springfield").is_empty());
+}
+
+#[test]
+fn test_tag_iteration() {
+    let reader = common::import_fixture("geojson.osm", "geojson_tag_iteration.bin");
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6124f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let street = entities.ways.iter().find(|w| w.global_id() == 1).unwrap();
+    let tags = street.tags();
+    assert_eq!(
+        tags.iter().collect::<Vec<_>>(),
+        vec![("highway", "residential"), ("name", "Street")]
+    );
+
+    // Equal strings are stored only once, so the `name` keys of the street and the cafe are at the same offset.
+    let cafe = entities.nodes.iter().find(|n| n.global_id() == 1).unwrap();
+    let name_offset = |tags: Tags<'_>| {
+        tags.iter_with_offsets()
+            .find(|(k, _)| k.str == "name")
+            .map(|(k, _)| k.offset)
+    };
+    assert_eq!(name_offset(street.tags()), name_offset(cafe.tags()));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use renderer::map_matching::MapMatcher;
+
+#[test]
+fn test_match_track() {
+    let reader = common::import_fixture("map_matching.osm", "map_matching.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+
+    let track = [
+        // Closer to the stream than to the street, but only the streets count.
+        (55.75012, 37.6105),
+        // Past the corner of the street.
+        (55.7505, 37.61205),
+        // Too far from everything.
+        (55.7530, 37.6150),
+    ];
+    let matched = MapMatcher::new(&reader, &styler, 16, 20.0).match_track(&track);
+
+    let expected = [
+        (55.7500, 37.6105),
+        (55.7500, 37.6120),
+        (55.7505, 37.6120),
+        (55.7530, 37.6150),
+    ];
+    assert_eq!(matched.len(), expected.len());
+    for (actual, expected) in matched.iter().zip(&expected) {
+        assert!((actual.0 - expected.0).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        assert!((actual.1 - expected.1).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::mapcss::audit::{audit_stylesheet, ColorPair, ContrastThresholds};
+use renderer::mapcss::styler::StyleType;
+
+#[test]
+fn test_audit() {
+    let bin_file = get_test_path(&["osm", "audit.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "audit.osm"]), &bin_file).unwrap();
+    let audit = |thresholds: &ContrastThresholds| {
+        audit_stylesheet(
+            &get_test_path(&["mapcss", "audit.mapcss"]),
+            &StyleType::Josm,
+            None,
+            &bin_file,
+            thresholds,
+        )
+        .unwrap()
+    };
+
+    let issues = audit(&ContrastThresholds::default());
+    assert_eq!(
+        issues.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        vec![
+            "Text on background: contrast 2.32 (#aaaaaa on #ffffff) at z0-18 for 2 object(s): node[place=town]",
+            "Text on halo: contrast 1.66 (#000000 on #333333) at z12-18 for 1 object(s): node|z12-[place=city]",
+            "Casing around line: contrast 1.36 (#dddddd on #ffffff) at z15-18 for 1 object(s): way|z15-[highway]",
+        ]
+    );
+
+    // The white text on the dark blue lake passes a stricter threshold, too.
+    let thresholds = ContrastThresholds { text: 6.0, lines: 1.2 };
+    let pairs = audit(&thresholds)
+        .into_iter()
+        .map(|issue| (issue.pair, issue.selectors))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        vec![
+            (ColorPair::TextOnBackground, "node[place=town]".to_string()),
+            (ColorPair::TextOnHalo, "node|z12-[place=city]".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::mapcss::lint::{lint_stylesheet, LintIssue};
+
+#[test]
+fn test_lint() {
+    let bin_file = get_test_path(&["osm", "places_lint.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "places.osm"]), &bin_file).unwrap();
+    let issues = lint_stylesheet(&get_test_path(&["mapcss", "lint.mapcss"]), &bin_file).unwrap();
+
+    assert_eq!(
+        issues,
+        vec![
+            LintIssue::ShadowedRule {
+                selectors: "node[place=city]".to_string(),
+            },
+            LintIssue::UnusedRule {
+                selectors: "node[amenity=bar]".to_string(),
+            },
+            LintIssue::UnusedSelector {
+                selector: "node[place=hamlet]".to_string(),
+            },
+            LintIssue::UnknownProperty {
+                property: "font-family".to_string(),
+                rule_count: 1,
+                example_selector: "node[place]".to_string(),
+            },
+        ]
+    );
+}
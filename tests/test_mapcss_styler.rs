@@ -3,9 +3,12 @@ mod common;
 use crate::common::get_test_path;
 use renderer::geodata::reader::OsmEntity;
 use renderer::mapcss::color::{from_color_name, Color};
-use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::dark::{parse_exceptions, DarkMode};
+use renderer::mapcss::palette::Palette;
+use renderer::mapcss::parser::{parse_file, PropertyValue};
+use renderer::mapcss::settings::apply_settings;
 use renderer::mapcss::styler::{LineCap, Style, StyleType, Styler};
-use renderer::tile::Tile;
+use renderer::tile::{coords_to_max_zoom_tile, Tile};
 use std::collections::HashMap;
 use std::path::Path;
 
@@ -96,6 +99,173 @@ fn test_styling() {
     }
 }
 
+#[test]
+fn test_eval_by_tags() {
+    let reader = common::import_fixture("places.osm", "places.bin");
+    let styler = common::load_styler("places.mapcss");
+
+    let tile = coords_to_max_zoom_tile(&(55.7532f64, 37.6132f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut styles = styler
+        .style_entities(entities.nodes.iter(), 18, true)
+        .iter()
+        .map(|(node, style)| {
+            let font_size = style.text_style.as_ref().and_then(|t| t.font_size);
+            (
+                node.global_id(),
+                font_size.map(|x| (x * 100.0).round() / 100.0),
+                style.text_priority,
+            )
+        })
+        .collect::<Vec<_>>();
+    styles.sort_by_key(|x| x.0);
+
+    assert_eq!(
+        styles,
+        vec![
+            (1, Some(18.0), Some(12_000_000.0)),
+            (2, Some(15.75), Some(500_000.0)),
+            (3, Some(12.98), Some(50_000.0)),
+            (4, None, None),
+        ]
+    );
+}
+
+#[test]
+fn test_style_cache_limit() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_style_cache.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let tile = Tile {
+        x: 158_458,
+        y: 81_948,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let count_styles = || styler.style_entities(entities.ways.iter(), 18, false).len();
+
+    let unlimited_count = count_styles();
+    let unlimited_usage = styler.cache_usage();
+    assert!(unlimited_usage > 0);
+
+    // The cache is emptied whenever it gets full, which doesn't change the styles.
+    let limit = unlimited_usage / 3;
+    styler.set_cache_limit(Some(limit));
+    for zoom in 15..18 {
+        styler.style_entities(entities.ways.iter(), zoom, false);
+        assert!(styler.cache_usage() <= limit);
+    }
+    assert_eq!(count_styles(), unlimited_count);
+    assert!(styler.cache_usage() <= limit);
+}
+
+#[test]
+fn test_styler_with_empty_cache() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_empty_cache.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let entities = reader.get_entities_in_tile_with_neighbors(
+        &Tile {
+            x: 158_458,
+            y: 81_948,
+            zoom: 18,
+        },
+        &None,
+    );
+    let get_widths = |styler: &Styler| {
+        styler
+            .style_entities(entities.ways.iter(), 18, false)
+            .iter()
+            .map(|(way, style)| (way.global_id(), style.width))
+            .collect::<Vec<_>>()
+    };
+
+    let widths = get_widths(&styler);
+    let limit = styler.cache_usage() * 2;
+    styler.set_cache_limit(Some(limit));
+
+    // The new styler matches the same rules, starting from scratch but with the same cache limit.
+    let new_styler = styler.with_empty_cache();
+    assert_eq!(new_styler.cache_usage(), 0);
+    assert_eq!(get_widths(&new_styler), widths);
+    for zoom in 12..18 {
+        new_styler.style_entities(entities.ways.iter(), zoom, false);
+        assert!(new_styler.cache_usage() <= limit);
+    }
+    assert!(styler.cache_usage() > 0);
+}
+
+#[test]
+fn test_dark_mode() {
+    let mut rules = parse_file(Path::new(&get_test_path(&["mapcss"])), "mapnik.mapcss").unwrap();
+    let dark_mode = DarkMode {
+        exceptions: parse_exceptions("natural=water").unwrap(),
+    };
+    dark_mode.apply(&mut rules);
+
+    let fill_color = |selector: &str| {
+        let rule = rules
+            .iter()
+            .find(|rule| rule.selectors.iter().any(|s| s.to_string() == selector))
+            .unwrap();
+        match &rule.properties.iter().find(|p| p.name == "fill-color").unwrap().value {
+            PropertyValue::Color(color) => color.clone(),
+            value => panic!("Unexpected fill color: {}", value),
+        }
+    };
+    // The lightness is inverted, but the hue stays the same.
+    assert_eq!(fill_color("area|z10-[leisure=park]"), Color { r: 2, g: 73, b: 2 });
+    // The water rule is an exception, and so are the other selectors of the same rule.
+    assert_eq!(fill_color("area|z6-[natural=water]"), Color { r: 181, g: 208, b: 208 });
+    assert_eq!(
+        fill_color("area|z6-[landuse=reservoir]"),
+        Color { r: 181, g: 208, b: 208 }
+    );
+    assert_eq!(fill_color("area|z7-[landuse=basin]"), Color { r: 47, g: 74, b: 74 });
+
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    assert_eq!(styler.canvas_fill_color, Some(Color { r: 23, g: 20, b: 14 }));
+}
+
+#[test]
+fn test_palette() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_palette.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let entities = reader.get_entities_in_tile_with_neighbors(
+        &Tile {
+            x: 158_458,
+            y: 81_948,
+            zoom: 18,
+        },
+        &None,
+    );
+    let get_colors = |styler: &Styler| {
+        styler
+            .style_entities(entities.ways.iter(), 18, false)
+            .iter()
+            .map(|(_, style)| {
+                (
+                    style.color.clone(),
+                    style.fill_color.clone(),
+                    style.casing_color.clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let palette = Palette::Deuteranopia;
+    let palette_styler = styler.with_palette(palette);
+    let remap = |color: &Option<Color>| color.as_ref().map(|color| palette.remap(color));
+    let expected_colors = get_colors(&styler)
+        .iter()
+        .map(|(color, fill_color, casing_color)| (remap(color), remap(fill_color), remap(casing_color)))
+        .collect::<Vec<_>>();
+    assert_eq!(get_colors(&palette_styler), expected_colors);
+    assert_ne!(get_colors(&palette_styler), get_colors(&styler));
+    assert_eq!(palette_styler.canvas_fill_color, remap(&styler.canvas_fill_color));
+
+    // The styler without the palette isn't affected by the one with it.
+    assert_eq!(get_colors(&styler.with_empty_cache()), get_colors(&styler));
+}
+
 fn compare_with_josm_style(our_style: &Style, way_is_closed: bool, josm_style_str: &str) {
     let josm_style = from_josm_style(way_is_closed, josm_style_str);
     assert_styles_eq(our_style, &josm_style);
@@ -153,6 +323,7 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
         fill_opacity: parse_num("fill-opacity"),
 
         width: parse_num("width"),
+        width_in_meters: false,
         dashes: props.get("dashes").map(|x| {
             x.trim_start_matches('[')
                 .trim_end_matches(']')
@@ -160,6 +331,7 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
                 .map(|x| x.parse().unwrap())
                 .collect::<Vec<_>>()
         }),
+        dashes_offset: None,
         line_cap: Some(
             props
                 .get("linecap")
@@ -169,14 +341,110 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
                 })
                 .unwrap_or(LineCap::Butt),
         ),
+        line_cap_start: None,
+        line_cap_end: None,
+        ticks: None,
 
         casing_color: None,
         casing_width: None,
         casing_dashes: None,
+        casing_dashes_offset: None,
         casing_line_cap: None,
 
         icon_image: None,
+        icon_width: None,
+        icon_height: None,
+        icon_rotation: None,
+        symbol: None,
+        arc: None,
         fill_image: None,
         text_style: None,
+        text_priority: None,
+
+        min_size: None,
+
+        render_pass: None,
+        casing_render_pass: None,
     }
 }
+
+#[test]
+fn test_area_tag() {
+    let reader = common::import_fixture("areas.osm", "areas.bin");
+    let styler = common::load_styler("areas.mapcss");
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut styles = styler
+        .style_entities(entities.ways.iter(), 18, false)
+        .iter()
+        .map(|(way, style)| {
+            (
+                way.global_id(),
+                style.fill_color.is_some(),
+                style.casing_color.is_some(),
+                style.fill_opacity,
+                style.z_index,
+            )
+        })
+        .collect::<Vec<_>>();
+    styles.sort_by_key(|x| x.0);
+
+    assert_eq!(
+        styles,
+        vec![
+            // A closed way is an area...
+            (1, true, true, Some(0.5), 1.0),
+            // ...unless it's tagged with area=no, but it's still closed.
+            (2, false, true, None, 3.0),
+            // An unclosed way tagged with area=yes is an area that is closed when it's filled.
+            (3, true, true, Some(0.5), 1.0),
+            (4, false, false, None, 3.0),
+        ]
+    );
+}
+
+#[test]
+fn test_settings() {
+    let reader = common::import_fixture("pistes.osm", "pistes.bin");
+    let parse = || parse_file(Path::new(&get_test_path(&["mapcss"])), "pistes.mapcss").unwrap();
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let get_styles = |styler: &Styler| {
+        let mut styles = styler
+            .style_entities(entities.ways.iter(), 18, false)
+            .iter()
+            .map(|(way, style)| (way.global_id(), style.color.clone(), style.width))
+            .collect::<Vec<_>>();
+        styles.sort_by_key(|x| x.0);
+        let station_icons = styler
+            .style_entities(entities.nodes.iter(), 18, false)
+            .iter()
+            .filter(|(_, style)| style.icon_image.is_some())
+            .count();
+        (styles, station_icons)
+    };
+    let lift_color = Some(Color { r: 32, g: 32, b: 32 });
+
+    // Only the settings with `default: true` are on unless they're turned on.
+    let styler = Styler::new(parse(), &StyleType::Josm, None);
+    assert_eq!(get_styles(&styler), (vec![(3, lift_color.clone(), None)], 0));
+
+    let mut rules = parse();
+    apply_settings(&mut rules, &["pistes".to_string()]).unwrap();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    assert_eq!(
+        get_styles(&styler),
+        (
+            vec![
+                (1, Some(Color { r: 30, g: 90, b: 230 }), Some(3.0)),
+                (2, Some(Color { r: 0, g: 0, b: 0 }), Some(3.0)),
+                (3, lift_color, Some(2.0)),
+            ],
+            1
+        )
+    );
+
+    assert!(apply_settings(&mut parse(), &["hillshading".to_string()]).is_err());
+}
@@ -1,14 +1,31 @@
 mod common;
 
+use renderer::coords::GeoBounds;
+use renderer::draw::cancellation::{CancellationToken, Cancelled};
+use renderer::draw::drawer::{DrawOptions, Drawer, ImageFormat, TileLayers, TileRequest};
+use renderer::draw::filters::Filter;
 use renderer::draw::png_writer::rgb_triples_to_png;
+use renderer::draw::render_order::RenderOrder;
+use renderer::draw::sprite::Sprite;
 use renderer::draw::tile_pixels::{RgbTriples, TilePixels};
+use renderer::draw::view::{render_static_map, View, ViewRenderer};
+use renderer::geodata::baked::BakeOptions;
+use renderer::geodata::importer::ImportOptions;
+use renderer::geodata::landcover::landcover_class;
 use renderer::mapcss::parser::parse_file;
-use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::mapcss::styler::{get_style_version, StyleType, Styler};
 use renderer::perf_stats;
+use renderer::progress::{Progress, ProgressFormat};
+use renderer::tile::{
+    coords_to_max_zoom_tile, coords_to_tile, coords_to_xy_tile_relative, pixels_per_meter, xy_to_coords, MAX_OVERZOOM,
+    MAX_ZOOM,
+};
 use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 const RED_PIXEL: (u8, u8, u8) = (255, 0, 0);
 
@@ -76,7 +93,7 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
         &StyleType::Josm,
         None,
     );
-    let drawer = renderer::draw::drawer::Drawer::new(Path::new(&base_path));
+    let drawer = renderer::draw::drawer::Drawer::new(Path::new(&base_path), Default::default());
 
     let mut rendered_tiles: BTreeMap<u8, BTreeMap<u32, BTreeMap<u32, RgbTriples>>> = BTreeMap::new();
 
@@ -92,9 +109,9 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
             perf_stats::finish_tile(&mut perf_stats);
             rendered_tiles
                 .entry(tile_to_draw.zoom)
-                .or_insert_with(Default::default)
+                .or_default()
                 .entry(tile_to_draw.y)
-                .or_insert_with(Default::default)
+                .or_default()
                 .insert(tile_to_draw.x, rendered.triples);
         }
     }
@@ -107,7 +124,7 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
             for sub_y in 0..tile_dimension {
                 for rendered in x_rendered.values() {
                     if sub_y == 0 {
-                        rgb.extend(std::iter::repeat(RED_PIXEL).take(tile_dimension));
+                        rgb.extend(std::iter::repeat_n(RED_PIXEL, tile_dimension));
                     } else {
                         rgb.extend(&rendered[sub_y * tile_dimension..(sub_y + 1) * tile_dimension - 1]);
                         rgb.push(RED_PIXEL);
@@ -174,3 +191,1043 @@ fn test_zoom_18() {
 fn test_zoom_18_2x() {
     test_rendering_zoom(18, 158_457, 158_465, 81_946, 81_953, 2)
 }
+
+#[test]
+fn test_label_layer() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_layers.bin", "mapnik.mapcss");
+
+    let tile = renderer::tile::Tile {
+        zoom: 17,
+        x: 79_230,
+        y: 40_974,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |layers| {
+        let png_bytes = drawer
+            .draw_tile(
+                &entities,
+                &tile,
+                &mut pixels,
+                &styler,
+                &TileRequest {
+                    scale: 1,
+                    layers,
+                    format: ImageFormat::Png,
+                    cancellation: &CancellationToken::new(),
+                },
+            )
+            .unwrap();
+        let mut reader = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+        let mut raw_pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut raw_pixels).unwrap();
+        (raw_pixels[..info.buffer_size()].to_vec(), info.color_type)
+    };
+
+    let (all, all_color_type) = draw(TileLayers::All);
+    let (base, base_color_type) = draw(TileLayers::Base);
+    let (labels, labels_color_type) = draw(TileLayers::Labels);
+    assert_eq!(all_color_type, png::ColorType::Rgb);
+    assert_eq!(base_color_type, png::ColorType::Rgb);
+    assert_eq!(labels_color_type, png::ColorType::Rgba);
+
+    let labels = labels.chunks(4).collect::<Vec<_>>();
+    assert!(labels.iter().any(|p| p[3] == 0));
+    assert!(labels.iter().any(|p| p[3] == u8::MAX));
+
+    // Drawing the labels on top of the base layer should give the same tile as rendering it at once.
+    let composited = base.chunks(3).zip(&labels).flat_map(|(b, l)| {
+        let alpha = f64::from(l[3]) / f64::from(u8::MAX);
+        (0..3).map(move |i| (f64::from(l[i]) * alpha + f64::from(b[i]) * (1.0 - alpha)).round() as i32)
+    });
+    for (expected, actual) in all.iter().zip(composited) {
+        assert!((i32::from(*expected) - actual).abs() <= 2);
+    }
+}
+
+#[test]
+fn test_raw_formats() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_raw.bin", "mapnik.mapcss");
+
+    let tile = renderer::tile::Tile {
+        zoom: 17,
+        x: 79_230,
+        y: 40_974,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |layers, format| {
+        drawer
+            .draw_tile(
+                &entities,
+                &tile,
+                &mut pixels,
+                &styler,
+                &TileRequest {
+                    scale: 1,
+                    layers,
+                    format,
+                    cancellation: &CancellationToken::new(),
+                },
+            )
+            .unwrap()
+    };
+    let decode_png = |png_bytes: Vec<u8>| {
+        let mut reader = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+        let mut raw_pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut raw_pixels).unwrap();
+        raw_pixels[..info.buffer_size()].to_vec()
+    };
+
+    // The uncompressed formats contain exactly the same pixels as the PNG tiles.
+    let all = decode_png(draw(TileLayers::All, ImageFormat::Png));
+    let ppm = draw(TileLayers::All, ImageFormat::Ppm);
+    let ppm_header = b"P6\n256 256\n255\n";
+    assert_eq!(&ppm[..ppm_header.len()], ppm_header);
+    assert_eq!(ppm[ppm_header.len()..], all[..]);
+
+    let rgba = draw(TileLayers::All, ImageFormat::Rgba);
+    assert_eq!(rgba.len(), 256 * 256 * 4);
+    assert!(rgba.chunks(4).all(|p| p[3] == u8::MAX));
+    let rgb = rgba.chunks(4).flat_map(|p| p[..3].to_vec()).collect::<Vec<_>>();
+    assert_eq!(rgb, all);
+
+    let labels = decode_png(draw(TileLayers::Labels, ImageFormat::Png));
+    let pam = draw(TileLayers::Labels, ImageFormat::Pam);
+    let pam_header = b"P7\nWIDTH 256\nHEIGHT 256\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n";
+    assert_eq!(&pam[..pam_header.len()], pam_header);
+    assert_eq!(pam[pam_header.len()..], labels[..]);
+
+    // The 16-bit PNG tiles round down to the 8-bit ones, but keep the precision that those lose.
+    let to_8_bit = |deep: &[u8]| {
+        deep.chunks(2)
+            .map(|c| (u16::from_be_bytes([c[0], c[1]]) / 257) as u8)
+            .collect::<Vec<_>>()
+    };
+    let deep_all = decode_png(draw(TileLayers::All, ImageFormat::Png16));
+    assert_eq!(deep_all.len(), 256 * 256 * 3 * 2);
+    assert_eq!(to_8_bit(&deep_all), all);
+    assert!(deep_all.chunks(2).any(|c| u16::from_be_bytes([c[0], c[1]]) % 257 != 0));
+    let deep_labels = decode_png(draw(TileLayers::Labels, ImageFormat::Png16));
+    assert_eq!(to_8_bit(&deep_labels), labels);
+
+    // The WebP tiles are lossless and smaller than the PNG ones.
+    let decode_webp = |webp_bytes: &[u8]| {
+        let mut decoder = image_webp::WebPDecoder::new(Cursor::new(webp_bytes)).unwrap();
+        let mut raw_pixels = vec![0; decoder.output_buffer_size().unwrap()];
+        decoder.read_image(&mut raw_pixels).unwrap();
+        raw_pixels
+    };
+    let webp_all = draw(TileLayers::All, ImageFormat::WebP);
+    assert_eq!(decode_webp(&webp_all), all);
+    assert!(webp_all.len() < draw(TileLayers::All, ImageFormat::Png).len());
+    assert_eq!(decode_webp(&draw(TileLayers::Labels, ImageFormat::WebP)), labels);
+}
+
+#[test]
+fn test_cross_faded_tile() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_fade.bin", "mapnik.mapcss");
+
+    // The styles of the main roads change between zoom levels 14 and 15.
+    let tile = renderer::tile::Tile {
+        zoom: 14,
+        x: 9_903,
+        y: 5_121,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |fade| {
+        drawer
+            .draw_cross_faded_tile(
+                &entities,
+                &tile,
+                &mut pixels,
+                &styler,
+                fade,
+                &TileRequest {
+                    scale: 1,
+                    layers: TileLayers::All,
+                    format: ImageFormat::Rgba,
+                    cancellation: &CancellationToken::new(),
+                },
+            )
+            .unwrap()
+    };
+    let current = draw(0.0);
+    let next = draw(1.0);
+    let halfway = draw(0.5);
+    assert_ne!(current, next);
+
+    let plain = drawer
+        .draw_tile(
+            &entities,
+            &tile,
+            &mut pixels,
+            &styler,
+            &TileRequest {
+                scale: 1,
+                layers: TileLayers::All,
+                format: ImageFormat::Rgba,
+                cancellation: &CancellationToken::new(),
+            },
+        )
+        .unwrap();
+    assert_eq!(current, plain);
+
+    for ((&c, &n), &h) in current.iter().zip(&next).zip(&halfway) {
+        assert!(h >= c.min(n) && h <= c.max(n));
+        assert!((f64::from(h) - (f64::from(c) + f64::from(n)) / 2.0).abs() <= 0.5);
+    }
+}
+
+#[test]
+fn test_view() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_view.bin", "mapnik.mapcss");
+
+    let mut pixels = TilePixels::new(1);
+    let mut draw_tile = |x, y| {
+        let tile = renderer::tile::Tile { zoom: 17, x, y };
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler).triples
+    };
+    let top_left = draw_tile(79_230, 40_974);
+    let bottom_right = draw_tile(79_231, 40_975);
+
+    // A view that is centered on the corner of the tiles and has the same zoom level consists of their quarters.
+    let (lat, lon) = xy_to_coords(79_231.0 * 256.0, 40_975.0 * 256.0, 17);
+    let mut view_renderer = ViewRenderer::new(&reader, &styler, &drawer);
+    let view = View {
+        lat,
+        lon,
+        zoom: 17.0,
+        width: 256,
+        height: 256,
+    };
+    let rendered = view_renderer.render(&view);
+    assert_eq!(rendered.len(), 256 * 256);
+    for y in 0..128 {
+        for x in 0..128 {
+            assert_eq!(rendered[y * 256 + x], top_left[(y + 128) * 256 + x + 128]);
+            assert_eq!(rendered[(y + 128) * 256 + x + 128], bottom_right[y * 256 + x]);
+        }
+    }
+
+    // Half a zoom level out, the same center shows more of the map.
+    let zoomed_out = view_renderer.render(&View { zoom: 16.5, ..view });
+    assert_eq!(zoomed_out.len(), 256 * 256);
+    assert_ne!(zoomed_out, rendered);
+
+    // Both tiles fit into a static map of the same size one zoom level out, and with margins into a wider one.
+    let (max_lat, min_lon) = xy_to_coords(79_230.0 * 256.0, 40_974.0 * 256.0, 17);
+    let (min_lat, max_lon) = xy_to_coords(79_232.0 * 256.0, 40_976.0 * 256.0, 17);
+    let bounds = GeoBounds {
+        min_lon,
+        min_lat,
+        max_lon,
+        max_lat,
+    };
+    for (width, height) in [(256, 256), (512, 256)] {
+        let fitted = View::fitting(&bounds, width, height);
+        assert!((fitted.zoom - 16.0).abs() < 1e-6);
+        assert!((fitted.lat - lat).abs() < 1e-9 && (fitted.lon - lon).abs() < 1e-9);
+    }
+    let png = render_static_map(&reader, &styler, &drawer, &bounds, 512, 256).unwrap();
+    let png_reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    assert_eq!((png_reader.info().width, png_reader.info().height), (512, 256));
+}
+
+#[test]
+fn test_sprite() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_sprite.bin");
+    let base_path = common::get_test_path(&["mapcss"]);
+    let styler = common::load_styler("mapnik.mapcss");
+
+    // Round-trip the sprite through its serialized form, like the renderer would load it from the disk.
+    let packed = Sprite::pack(Path::new(&base_path)).unwrap();
+    let sprite = Sprite::from_bytes(packed.png().to_vec(), &packed.to_json()).unwrap();
+    let options = DrawOptions {
+        sprite: Some(Arc::new(sprite)),
+        ..Default::default()
+    };
+
+    let tile = renderer::tile::Tile {
+        zoom: 18,
+        x: 158_461,
+        y: 81_949,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |drawer: Drawer| drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler).triples;
+
+    let from_files = draw(common::new_drawer(DrawOptions::default()));
+    let from_sprite = draw(common::new_drawer(options));
+    assert!(from_files == from_sprite);
+}
+
+#[test]
+fn test_shield() {
+    let (reader, styler, drawer) = common::load_fixture("junctions.osm", "junctions.bin", "junctions.mapcss");
+
+    let junction = (55.7530f64, 37.6124f64);
+    let tile = coords_to_max_zoom_tile(&junction);
+    let (node_x, node_y) = coords_to_xy_tile_relative(&junction, &tile);
+    let (node_x, node_y) = (node_x.round() as usize, node_y.round() as usize);
+    assert!((50..200).contains(&node_x) && (50..200).contains(&node_y));
+
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    let get_pixel = |x: usize, y: usize| drawn.triples[y * drawn.dimension + x];
+    let (yellow, blue) = ((255, 255, 0), (0, 0, 255));
+
+    // The cafe label takes the space to the right of the junction, so the shield goes to its left,
+    // connected to the node with a leader line.
+    let row = (0..drawn.dimension).map(|x| get_pixel(x, node_y)).collect::<Vec<_>>();
+    assert!(row[..node_x].contains(&yellow));
+    assert!(!row[node_x..].contains(&yellow));
+    assert_eq!(get_pixel(node_x - 3, node_y), blue);
+}
+
+#[test]
+fn test_text_priority() {
+    let (reader, styler, drawer) = common::load_fixture("priorities.osm", "priorities.bin", "priorities.mapcss");
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6120f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    // The colors of the labels around the nodes at the given latitude.
+    let colors_at = |lat: f64| {
+        let (_, y) = coords_to_xy_tile_relative(&(lat, 37.6120), &tile);
+        let rows = (y as usize - 15)..(y as usize + 15);
+        let pixels = rows
+            .flat_map(|y| drawn.triples[y * drawn.dimension..(y + 1) * drawn.dimension].iter())
+            .collect::<Vec<_>>();
+        let has_red = pixels.iter().any(|&&(r, g, b)| r > 200 && g < 100 && b < 100);
+        let has_blue = pixels.iter().any(|&&(r, g, b)| b > 200 && r < 100 && g < 100);
+        (has_red, has_blue)
+    };
+
+    // The two labels of every pair overlap. Of the labels with the same priority, the first one is placed,
+    // while a higher priority wins even for a label that would have been placed later.
+    assert_eq!(colors_at(55.7528), (true, false));
+    assert_eq!(colors_at(55.7532), (false, true));
+}
+
+#[test]
+fn test_render_order() {
+    let reader = common::import_fixture("passes.osm", "passes.bin");
+    let styler = common::load_styler("passes.mapcss");
+
+    // A point where the water and the building overlap.
+    let overlap = (55.7530f64, 37.6124f64);
+    let tile = coords_to_max_zoom_tile(&overlap);
+    let (x, y) = coords_to_xy_tile_relative(&overlap, &tile);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |render_order: Option<&str>| {
+        let options = DrawOptions {
+            render_order: render_order.map(|order| Arc::new(RenderOrder::parse(order).unwrap())),
+            ..Default::default()
+        };
+        let drawer = common::new_drawer(options);
+        let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        drawn.triples[y as usize * drawn.dimension + x as usize]
+    };
+
+    assert_eq!(draw(None), (0, 0, 255));
+    assert_eq!(draw(Some("water\nbuildings")), RED_PIXEL);
+    assert_eq!(draw(Some("buildings\nwater")), (0, 0, 255));
+}
+
+#[test]
+fn test_shared_boundaries() {
+    let reader = common::import_fixture("boundaries.osm", "boundaries.bin");
+    let styler = common::load_styler("boundaries.mapcss");
+
+    let max_zoom_tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let tile = renderer::tile::Tile {
+        zoom: MAX_ZOOM - 1,
+        x: max_zoom_tile.x / 2,
+        y: max_zoom_tile.y / 2,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let drawer = common::new_drawer(DrawOptions::default());
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    let pixel_at = |coords: (f64, f64)| {
+        let (x, y) = coords_to_xy_tile_relative(&coords, &tile);
+        drawn.triples[y as usize * drawn.dimension + x as usize]
+    };
+
+    // The border of the two regions belongs to both of their boundaries, but looks the same as the outer
+    // border of one of them.
+    let shared_border = pixel_at((55.7530, 37.6126));
+    assert_ne!(shared_border, (255, 255, 255));
+    assert_eq!(shared_border, pixel_at((55.7530, 37.6122)));
+    assert_eq!(shared_border, pixel_at((55.7530, 37.6130)));
+}
+
+#[test]
+fn test_ticks() {
+    let (reader, styler, drawer) = common::load_fixture("barriers.osm", "barriers.bin", "barriers.mapcss");
+
+    let tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    let row = |lat: f64, offset: i32| {
+        let y = coords_to_xy_tile_relative(&(lat, 37.6126f64), &tile).1.round() as i32 + offset;
+        drawn.triples[y as usize * drawn.dimension..(y as usize + 1) * drawn.dimension].to_vec()
+    };
+    // The runs of the color along a row, i.e. the ticks that it crosses.
+    let count_ticks = |row: &[(u8, u8, u8)], color| {
+        row.chunk_by(|p1, p2| (*p1 == color) == (*p2 == color))
+            .filter(|run| run[0] == color)
+            .count()
+    };
+    let blue = (0, 0, 255);
+
+    // Both ways go to the east, so the right side is below them. The ticks are 8 pixels apart.
+    let expected_ticks = drawn.dimension / 8;
+    let wall_ticks = count_ticks(&row(55.7531, 3), RED_PIXEL);
+    assert!(wall_ticks.abs_diff(expected_ticks) <= 1, "{} ticks", wall_ticks);
+    assert_eq!(count_ticks(&row(55.7531, -3), RED_PIXEL), 0);
+    let fence_ticks = count_ticks(&row(55.7528, -3), blue);
+    assert!(fence_ticks.abs_diff(expected_ticks) <= 1, "{} ticks", fence_ticks);
+    assert_eq!(count_ticks(&row(55.7528, 3), blue), 0);
+}
+
+#[test]
+fn test_width_in_meters() {
+    let (reader, styler, drawer) = common::load_fixture("runways.osm", "runways.bin", "runways.mapcss");
+    let blue = (0, 0, 255);
+
+    for zoom in [16, 17] {
+        let tile = coords_to_tile(&(55.7527f64, 37.6126f64), zoom);
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let mut pixels = TilePixels::new(1);
+        let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        let pixel = |x: usize, y: usize| drawn.triples[y * drawn.dimension + x];
+        let ppm = pixels_per_meter(&tile);
+
+        // The runways are as wide as the width tag says, or 45 meters without it, whatever the zoom level.
+        for (lat, width) in [(55.7530, 60.0), (55.7524, 45.0)] {
+            let y = coords_to_xy_tile_relative(&(lat, 37.6126f64), &tile).1.round() as usize;
+            let column = (0..drawn.dimension).map(|y| pixel(10, y)).collect::<Vec<_>>();
+            let run = column[..y].iter().rev().take_while(|p| **p != (255, 255, 255)).count()
+                + column[y..].iter().take_while(|p| **p != (255, 255, 255)).count();
+            assert!((run as f64 - width * ppm).abs() <= 2.0, "zoom {}: {} pixels", zoom, run);
+
+            // The dashes of the centerline are 30 meters long with the gaps of 20 meters.
+            let row = (0..drawn.dimension).map(|x| pixel(x, y)).collect::<Vec<_>>();
+            let dashes = row
+                .chunk_by(|p1, p2| (*p1 == blue) == (*p2 == blue))
+                .filter(|run| run[0] == blue)
+                .count();
+            let expected_dashes = drawn.dimension as f64 / (50.0 * ppm);
+            assert!(
+                (dashes as f64 - expected_dashes).abs() <= 1.0,
+                "zoom {}: {} dashes",
+                zoom,
+                dashes
+            );
+        }
+    }
+}
+
+#[test]
+fn test_arcs() {
+    let (reader, styler, drawer) = common::load_fixture("seamarks.osm", "seamarks.bin", "seamarks.mapcss");
+
+    let tile = coords_to_tile(&(55.7530f64, 37.6126f64), 18);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    // The pixel in the direction of the bearing (clockwise from the north), the given distance from the node.
+    let pixel_at = |coords: (f64, f64), bearing: f64, distance: f64| {
+        let (x, y) = coords_to_xy_tile_relative(&coords, &tile);
+        let (x, y) = (
+            (x + distance * bearing.to_radians().sin()).round() as usize,
+            (y - distance * bearing.to_radians().cos()).round() as usize,
+        );
+        drawn.triples[y * drawn.dimension + x]
+    };
+    let white = (255, 255, 255);
+
+    // The sectors of the light are given as seen from the sea, so they're drawn on the opposite side of it.
+    let light = (55.7530, 37.6126);
+    assert_eq!(pixel_at(light, 105.0, 20.0), RED_PIXEL);
+    assert_eq!(pixel_at(light, 135.0, 20.0), (255, 255, 0));
+    assert_eq!(pixel_at(light, 165.0, 20.0), (0, 255, 0));
+    for bearing in [0.0, 60.0, 200.0, 285.0, 345.0] {
+        assert_eq!(pixel_at(light, bearing, 20.0), white, "bearing {}", bearing);
+    }
+    assert_eq!(pixel_at(light, 135.0, 10.0), white);
+    assert_eq!(pixel_at(light, 135.0, 30.0), white);
+
+    // The sector under the arc is filled.
+    assert_eq!(pixel_at(light, 45.0, 4.0), (0, 255, 255));
+    assert_eq!(pixel_at(light, 135.0, 4.0), white);
+    assert_eq!(pixel_at(light, 45.0, 12.0), white);
+
+    // Without the bearings, it's the whole circle.
+    let buoy = (55.7528, 37.6120);
+    for bearing in [0.0, 90.0, 180.0, 270.0] {
+        assert_eq!(pixel_at(buoy, bearing, 6.0), (0, 0, 255), "bearing {}", bearing);
+    }
+    assert_eq!(pixel_at(buoy, 0.0, 0.0), white);
+}
+
+#[test]
+fn test_symbols() {
+    let (reader, styler, drawer) = common::load_fixture("dots.osm", "dots.bin", "dots.mapcss");
+
+    let tile = coords_to_tile(&(55.7530f64, 37.6126f64), 18);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+    let pixel_near = |coords: (f64, f64), dx: f64, dy: f64| {
+        let (x, y) = coords_to_xy_tile_relative(&coords, &tile);
+        drawn.triples[(y + dy).floor() as usize * drawn.dimension + (x + dx).floor() as usize]
+    };
+    let white = (255, 255, 255);
+
+    // The dots are drawn instead of the icons, 9 pixels across.
+    let bench = (55.7530, 37.6126);
+    assert_eq!(pixel_near(bench, 0.0, 0.0), (0, 0, 255));
+    assert_eq!(pixel_near(bench, 3.0, 0.0), (0, 0, 255));
+    assert_eq!(pixel_near(bench, 3.5, 3.5), white);
+    assert_eq!(pixel_near(bench, 7.0, 0.0), white);
+
+    // The stroke of the square goes around its fill.
+    let basket = (55.7530, 37.6120);
+    assert_eq!(pixel_near(basket, 0.0, 0.0), (0, 255, 0));
+    assert_eq!(pixel_near(basket, 5.0, 5.0), (0, 0, 0));
+    assert_eq!(pixel_near(basket, 8.0, 0.0), white);
+
+    // Without any colors, the dot is red, and the text goes below it.
+    let fountain = (55.7527, 37.6126);
+    assert_eq!(pixel_near(fountain, 0.0, 0.0), RED_PIXEL);
+    let below = (6..20)
+        .map(|dy| pixel_near(fountain, 0.0, f64::from(dy)))
+        .collect::<Vec<_>>();
+    assert!(below.iter().any(|p| *p != white && *p != RED_PIXEL));
+}
+
+#[test]
+fn test_multipolygon_holes() {
+    let reader = common::import_fixture("holes.osm", "holes.bin");
+    let styler = common::load_styler("holes.mapcss");
+
+    let tile = coords_to_tile(&(55.7530f64, 37.6126f64), 17);
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    for options in [
+        DrawOptions::default(),
+        DrawOptions {
+            tile_threads: 4,
+            parallel_layers: true,
+            ..Default::default()
+        },
+    ] {
+        let drawer = common::new_drawer(options);
+        let mut pixels = TilePixels::new(1);
+        let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        let pixel_at = |coords: (f64, f64)| {
+            let (x, y) = coords_to_xy_tile_relative(&coords, &tile);
+            drawn.triples[y as usize * drawn.dimension + x as usize]
+        };
+
+        // A lake made of two outer ways, with an island.
+        assert_eq!(pixel_at((55.7530, 37.6122)), (0, 0, 255));
+        assert_eq!(pixel_at((55.7526, 37.6125)), (255, 255, 255));
+        // A building with a courtyard.
+        assert_eq!(pixel_at((55.7523, 37.6134)), RED_PIXEL);
+        assert_eq!(pixel_at((55.7526, 37.61375)), (255, 255, 255));
+    }
+
+    // The SVG tiles keep the holes, too.
+    let drawer = common::new_drawer(DrawOptions::default());
+    let svg = drawer.draw_svg_tile(&entities, &tile, 256, 1, &styler);
+    assert_eq!(svg.matches("fill-rule=\"evenodd\"").count(), 2);
+    assert_eq!(svg.matches('Z').count(), 4);
+}
+
+#[test]
+fn test_area_priorities() {
+    let reader = common::import_fixture("landuse.osm", "landuse.bin");
+    let styler = common::load_styler("landuse.mapcss");
+
+    let max_zoom_tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let tile = renderer::tile::Tile {
+        zoom: MAX_ZOOM - 1,
+        x: max_zoom_tile.x / 2,
+        y: max_zoom_tile.y / 2,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let overlap_color = |options: DrawOptions| {
+        let drawer = common::new_drawer(options);
+        let mut pixels = TilePixels::new(1);
+        let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        let (x, y) = coords_to_xy_tile_relative(&(55.7530f64, 37.6126f64), &tile);
+        drawn.triples[y as usize * drawn.dimension + x as usize]
+    };
+
+    // The residential area has the larger ID, so it's drawn over the water by default.
+    assert_eq!(overlap_color(DrawOptions::default()), RED_PIXEL);
+    let options = DrawOptions {
+        area_priorities: "landuse=residential, natural".parse().unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(overlap_color(options), (0, 0, 255));
+}
+
+#[test]
+fn test_landcover() {
+    let import = |bin_name: &str, landcover| {
+        let bin_file = common::get_test_path(&["osm", bin_name]);
+        let options = ImportOptions {
+            landcover,
+            ..Default::default()
+        };
+        let mut progress = Progress::new(ProgressFormat::Text);
+        renderer::geodata::importer::import_with_progress(
+            &common::get_test_path(&["osm", "landcover.osm"]),
+            &bin_file,
+            &options,
+            &mut progress,
+        )
+        .unwrap();
+        renderer::geodata::reader::GeodataReader::load(&bin_file).unwrap()
+    };
+    let styler = common::load_styler("landuse.mapcss");
+
+    let center = (55.7500f64, 37.6200f64);
+    let max_zoom_tile = coords_to_max_zoom_tile(&center);
+    let tile = renderer::tile::Tile {
+        zoom: 10,
+        x: max_zoom_tile.x >> (MAX_ZOOM - 10),
+        y: max_zoom_tile.y >> (MAX_ZOOM - 10),
+    };
+    let center_color = |reader: &renderer::geodata::reader::GeodataReader<'_>| {
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let drawer = common::new_drawer(DrawOptions::default());
+        let mut pixels = TilePixels::new(1);
+        let drawn = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        let (x, y) = coords_to_xy_tile_relative(&center, &tile);
+        drawn.triples[y as usize * drawn.dimension + x as usize]
+    };
+
+    let plain = import("landcover_plain.bin", false);
+    assert!(plain
+        .get_entities_in_tile_with_neighbors(&tile, &None)
+        .landcover
+        .is_none());
+    assert_eq!(center_color(&plain), RED_PIXEL);
+
+    // The residential area is only a few pixels large, so it's drawn from the grid of the tile.
+    let aggregated = import("landcover_grids.bin", true);
+    let entities = aggregated.get_entities_in_tile_with_neighbors(&tile, &None);
+    let grid = entities.landcover.unwrap().grid(&tile).unwrap();
+    let residential = landcover_class(|key| (key == "landuse").then_some("residential"));
+    assert!(grid.iter().filter(|class| **class == residential).count() > 1);
+    assert_eq!(center_color(&aggregated), RED_PIXEL);
+}
+
+#[test]
+fn test_baked_tiles() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_baked.bin"]);
+    let base_path = common::get_test_path(&["mapcss"]);
+    let stylesheet_file = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let options = ImportOptions {
+        bake: Some(BakeOptions {
+            stylesheet_file,
+            stylesheet_type: StyleType::Josm,
+            font_size_multiplier: None,
+            draw_options: DrawOptions::default(),
+            max_zoom: 4,
+        }),
+        ..Default::default()
+    };
+    let mut progress = Progress::new(ProgressFormat::Text);
+    renderer::geodata::importer::import_with_progress(
+        &common::get_test_path(&["osm", "nano_moscow.osm"]),
+        &bin_file,
+        &options,
+        &mut progress,
+    )
+    .unwrap();
+    let reader = renderer::geodata::reader::GeodataReader::load(&bin_file).unwrap();
+    let rules = parse_file(Path::new(&base_path), "mapnik.mapcss").unwrap();
+    let style_version = get_style_version(&rules, &StyleType::Josm, None);
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let drawer = common::new_drawer(DrawOptions::default());
+
+    let max_zoom_tile = coords_to_max_zoom_tile(&(55.7530f64, 37.6126f64));
+    let tile_at = |zoom| renderer::tile::Tile {
+        zoom,
+        x: max_zoom_tile.x >> (MAX_ZOOM - zoom),
+        y: max_zoom_tile.y >> (MAX_ZOOM - zoom),
+    };
+    for zoom in 0..=4 {
+        let tile = tile_at(zoom);
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let drawn = drawer
+            .draw_tile(
+                &entities,
+                &tile,
+                &mut TilePixels::new(1),
+                &styler,
+                &TileRequest {
+                    scale: 1,
+                    layers: TileLayers::All,
+                    format: ImageFormat::Png,
+                    cancellation: &CancellationToken::new(),
+                },
+            )
+            .unwrap();
+        assert!(reader.baked_tile(&tile, &style_version) == Some(&drawn[..]));
+        assert!(reader.baked_tile(&tile, "another style").is_none());
+    }
+    assert!(reader.baked_tile(&tile_at(5), &style_version).is_none());
+    let empty_tile = renderer::tile::Tile { zoom: 4, x: 0, y: 0 };
+    assert!(reader.baked_tile(&empty_tile, &style_version).is_none());
+}
+
+#[test]
+fn test_tile_threads() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_threads.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+
+    // Dense enough to be styled and drawn by several threads.
+    let tile = renderer::tile::Tile {
+        zoom: 14,
+        x: 9_903,
+        y: 5_121,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    for scale in [1, 2] {
+        let mut pixels = TilePixels::new(scale);
+        let mut draw = |tile_threads| {
+            let options = DrawOptions {
+                tile_threads,
+                ..Default::default()
+            };
+            let drawer = common::new_drawer(options);
+            drawer
+                .draw_to_pixels(&entities, &tile, &mut pixels, scale, &styler)
+                .triples
+        };
+        let single_threaded = draw(1);
+        assert!(single_threaded == draw(3));
+        assert!(single_threaded == draw(8));
+    }
+}
+
+#[test]
+fn test_parallel_layers() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_parallel_layers.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+
+    let tile = renderer::tile::Tile {
+        zoom: 14,
+        x: 9_903,
+        y: 5_121,
+    };
+    // Dense enough to be drawn by several threads.
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    for scale in [1, 2] {
+        let mut pixels = TilePixels::new(scale);
+        let mut draw = |tile_threads, parallel_layers| {
+            let options = DrawOptions {
+                tile_threads,
+                parallel_layers,
+                ..Default::default()
+            };
+            let drawer = common::new_drawer(options);
+            drawer
+                .draw_to_pixels(&entities, &tile, &mut pixels, scale, &styler)
+                .triples
+        };
+        // The layers are blended in a different order, which can only make a difference in the rounding.
+        let single_threaded = draw(1, false);
+        for tile_threads in [3, 8] {
+            let layered = draw(tile_threads, true);
+            assert_eq!(layered.len(), single_threaded.len());
+            for (single, layered) in single_threaded.iter().zip(&layered) {
+                let difference = |a: u8, b: u8| a.abs_diff(b);
+                assert!(
+                    difference(single.0, layered.0) <= 1
+                        && difference(single.1, layered.1) <= 1
+                        && difference(single.2, layered.2) <= 1,
+                    "{:?} != {:?}",
+                    single,
+                    layered
+                );
+            }
+            // Without the layers, only the bands are drawn at the same time.
+            assert!(single_threaded == draw(tile_threads, false));
+        }
+    }
+}
+
+#[test]
+fn test_cancellation() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_cancellation.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let options = DrawOptions {
+        tile_threads: 4,
+        ..Default::default()
+    };
+    let drawer = common::new_drawer(options);
+
+    let tile = renderer::tile::Tile {
+        zoom: 14,
+        x: 9_903,
+        y: 5_121,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |cancellation: &CancellationToken, layers| {
+        drawer.draw_tile(
+            &entities,
+            &tile,
+            &mut pixels,
+            &styler,
+            &TileRequest {
+                scale: 1,
+                layers,
+                format: ImageFormat::Png,
+                cancellation,
+            },
+        )
+    };
+
+    let cancellation = CancellationToken::new();
+    let expected = draw(&cancellation, TileLayers::All).unwrap();
+    cancellation.clone().cancel();
+    assert!(cancellation.is_cancelled());
+    for layers in [TileLayers::All, TileLayers::Base, TileLayers::Labels] {
+        assert!(draw(&cancellation, layers).unwrap_err().is::<Cancelled>());
+    }
+
+    // A cancelled render doesn't leave anything behind in the pixels.
+    assert_eq!(draw(&CancellationToken::new(), TileLayers::All).unwrap(), expected);
+}
+
+#[test]
+fn test_render_budget() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_budget.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let get_drawer = |render_budget| {
+        let options = DrawOptions {
+            render_budget,
+            ..Default::default()
+        };
+        common::new_drawer(options)
+    };
+
+    let tile = renderer::tile::Tile {
+        zoom: 15,
+        x: 19_807,
+        y: 10_242,
+    };
+    let neighbor = renderer::tile::Tile {
+        x: tile.x - 1,
+        ..tile.clone()
+    };
+    let mut pixels = TilePixels::new(1);
+    let mut draw = |drawer: &Drawer, tile| {
+        let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+        drawer.draw_to_pixels(&entities, tile, &mut pixels, 1, &styler).triples
+    };
+
+    let plain_drawer = get_drawer(None);
+    let expected_tile = draw(&plain_drawer, &tile);
+    let expected_neighbor = draw(&plain_drawer, &neighbor);
+
+    // Every tile takes longer than that, so the first one is drawn in full, and its neighbor (in the same block
+    // of tiles) already isn't.
+    let hurried_drawer = get_drawer(Some(Duration::ZERO));
+    assert!(draw(&hurried_drawer, &tile) == expected_tile);
+    assert!(draw(&hurried_drawer, &neighbor) != expected_neighbor);
+
+    let patient_drawer = get_drawer(Some(Duration::from_secs(3600)));
+    for _ in 0..3 {
+        assert!(draw(&patient_drawer, &tile) == expected_tile);
+    }
+}
+
+#[test]
+fn test_svg_tile() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_svg.bin", "mapnik.mapcss");
+
+    let tile = renderer::tile::Tile {
+        zoom: 17,
+        x: 79_230,
+        y: 40_974,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let svg = drawer.draw_svg_tile(&entities, &tile, 256, 1, &styler);
+    assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="256" height="256""#));
+    assert!(svg.ends_with("</svg>\n"));
+
+    let lines = svg.lines().collect::<Vec<_>>();
+    assert!(lines[1].starts_with("<rect"));
+    let first_stroke = lines.iter().position(|line| line.contains("stroke=")).unwrap();
+    let last_fill = lines.iter().rposition(|line| line.contains("fill-rule=")).unwrap();
+    assert!(first_stroke > last_fill);
+    assert!(lines.iter().any(|line| line.contains("stroke-dasharray")));
+
+    let svg_2x = drawer.draw_svg_tile(&entities, &tile, 256, 2, &styler);
+    assert!(svg_2x.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="512" height="512""#));
+    assert_eq!(svg_2x.lines().count(), lines.len());
+}
+
+#[test]
+fn test_tile_size() {
+    let (reader, styler, drawer) =
+        common::load_fixture("nano_moscow.osm", "nano_moscow_tile_size.bin", "mapnik.mapcss");
+    let draw = |tile: &renderer::tile::Tile, pixels: &mut TilePixels| {
+        let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+        drawer
+            .draw_tile(
+                &entities,
+                tile,
+                pixels,
+                &styler,
+                &TileRequest {
+                    scale: 1,
+                    layers: TileLayers::Base,
+                    format: ImageFormat::Rgba,
+                    cancellation: &CancellationToken::new(),
+                },
+            )
+            .unwrap()
+    };
+
+    // A 512×512 tile looks exactly like the 2×2 block of the usual tiles of the next zoom level,
+    // including the overzoomed ones beyond the max zoom.
+    let mut pixels = TilePixels::new(1);
+    for tile in [
+        renderer::tile::Tile {
+            zoom: 16,
+            x: 39_615,
+            y: 20_487,
+        },
+        renderer::tile::Tile {
+            zoom: MAX_ZOOM,
+            x: 158_461,
+            y: 81_949,
+        },
+    ] {
+        let big_tile = draw(&tile, &mut TilePixels::with_tile_size(512, 1));
+        assert_eq!(big_tile.len(), 512 * 512 * 4);
+        assert!(big_tile.chunks(4).any(|pixel| pixel != &big_tile[..4]));
+
+        let first_child = tile.top_left_descendant(1);
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let child = renderer::tile::Tile {
+                zoom: first_child.zoom,
+                x: first_child.x + dx,
+                y: first_child.y + dy,
+            };
+            let child_tile = draw(&child, &mut pixels);
+            for row in 0..256 {
+                let big_tile_offset = (((dy * 256 + row) * 512 + dx * 256) * 4) as usize;
+                assert_eq!(
+                    child_tile[(row * 256 * 4) as usize..((row + 1) * 256 * 4) as usize],
+                    big_tile[big_tile_offset..big_tile_offset + 256 * 4],
+                    "row {} of the tile at ({}, {}) below {:?}",
+                    row,
+                    dx,
+                    dy,
+                    tile
+                );
+            }
+        }
+    }
+
+    // The biggest tiles of the last overzoomed level are drawn even further.
+    let deepest_tile = renderer::tile::Tile {
+        zoom: MAX_OVERZOOM,
+        x: 158_461 << (MAX_OVERZOOM - MAX_ZOOM),
+        y: 81_949 << (MAX_OVERZOOM - MAX_ZOOM),
+    };
+    let deepest_tile = draw(&deepest_tile, &mut TilePixels::with_tile_size(1024, 1));
+    assert_eq!(deepest_tile.len(), 1024 * 1024 * 4);
+}
+
+#[test]
+fn test_metatile() {
+    let (reader, styler, drawer) = common::load_fixture("nano_moscow.osm", "nano_moscow_metatile.bin", "mapnik.mapcss");
+
+    // A metatile is drawn just like a big tile, labels included, and then cut into the usual tiles.
+    let (metatile, size) = renderer::tile::Tile {
+        zoom: 16,
+        x: 39_614,
+        y: 20_487,
+    }
+    .metatile(4);
+    assert_eq!(size, 4);
+    let entities = reader.get_entities_in_tile_with_neighbors(&metatile, &None);
+    let big_tile = drawer.draw_to_pixels(
+        &entities,
+        &metatile,
+        &mut TilePixels::with_tile_size(1024, 1),
+        1,
+        &styler,
+    );
+    let tiles = drawer.draw_metatile_to_pixels(&entities, &metatile, &mut TilePixels::for_metatile(4, 1), 1, &styler);
+    assert_eq!(tiles.len(), 16);
+    assert!(big_tile.triples.iter().any(|pixel| pixel != &big_tile.triples[0]));
+    for (tile, pixels) in &tiles {
+        assert_eq!(tile.zoom, 16);
+        assert_eq!(pixels.dimension, 256);
+        let (dx, dy) = ((tile.x - 39_612) as usize, (tile.y - 20_484) as usize);
+        for row in 0..256 {
+            let big_tile_offset = (dy * 256 + row) * 1024 + dx * 256;
+            assert_eq!(
+                pixels.triples[row * 256..(row + 1) * 256],
+                big_tile.triples[big_tile_offset..big_tile_offset + 256],
+                "row {} of {:?}",
+                row,
+                tile
+            );
+        }
+    }
+}
+
+#[test]
+fn test_filters() {
+    let reader = common::import_fixture("nano_moscow.osm", "nano_moscow_filters.bin");
+    let styler = common::load_styler("mapnik.mapcss");
+    let tile = renderer::tile::Tile {
+        zoom: 16,
+        x: 39_615,
+        y: 20_487,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let draw = |filters: Vec<Filter>| {
+        let drawer = common::new_drawer(DrawOptions {
+            filters,
+            ..Default::default()
+        });
+        drawer
+            .draw_to_pixels(&entities, &tile, &mut TilePixels::new(1), 1, &styler)
+            .triples
+    };
+
+    // The filters work on the finished tile, so inverting it gives the opposite of every pixel.
+    let plain = draw(Vec::new());
+    let inverted = draw(vec![Filter::Invert(1.0)]);
+    assert!(plain.iter().any(|pixel| pixel != &plain[0]));
+    for (p, i) in plain.iter().zip(&inverted) {
+        for (c, inverted_c) in [(p.0, i.0), (p.1, i.1), (p.2, i.2)] {
+            assert!((i32::from(c) + i32::from(inverted_c) - 255).abs() <= 1);
+        }
+    }
+
+    assert_eq!(draw(vec![Filter::Contrast(1.0), Filter::Sharpen(0.0)]), plain);
+    assert_ne!(draw(vec![Filter::Sharpen(1.0)]), plain);
+}
@@ -0,0 +1,130 @@
+use proptest::prelude::*;
+use renderer::coords::GeoBounds;
+use renderer::tile::*;
+
+// The latitudes that Web Mercator covers.
+const MAX_LAT: f64 = 85.051_128_779_806_59;
+
+fn zooms() -> impl Strategy<Value = u8> {
+    0..=MAX_ZOOM
+}
+
+fn coords() -> impl Strategy<Value = (f64, f64)> {
+    (-MAX_LAT..=MAX_LAT, -180.0..=180.0f64)
+}
+
+fn dimension(zoom: u8) -> f64 {
+    f64::from(TILE_SIZE * (1 << zoom))
+}
+
+proptest! {
+    #[test]
+    fn test_coords_round_trip((lat, lon) in coords(), zoom in zooms()) {
+        let (x, y) = coords_to_xy(&(lat, lon), zoom);
+        let (lat_back, lon_back) = xy_to_coords(x, y, zoom);
+        prop_assert!((lat - lat_back).abs() < 1e-9);
+        prop_assert!((lon - lon_back).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_xy_round_trip(x_share in 0.0..=1.0f64, y_share in 0.0..=1.0f64, zoom in zooms()) {
+        let (x, y) = (x_share * dimension(zoom), y_share * dimension(zoom));
+        let (x_back, y_back) = coords_to_xy(&xy_to_coords(x, y, zoom), zoom);
+        // Within a thousandth of a pixel.
+        prop_assert!((x - x_back).abs() < 1e-3);
+        prop_assert!((y - y_back).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_coords_to_tile(coords in coords(), zoom in zooms()) {
+        let tile = coords_to_tile(&coords, zoom);
+        prop_assert!(tile.x < 1 << zoom && tile.y < 1 << zoom);
+
+        // The point is inside the tile (or on its far edge for the last tile).
+        let (x, y) = coords_to_xy_tile_relative(&coords, &tile);
+        let size = f64::from(TILE_SIZE);
+        prop_assert!((0.0..size).contains(&x) || (x == size && tile.x == (1 << zoom) - 1));
+        prop_assert!((0.0..size).contains(&y) || (y == size && tile.y == (1 << zoom) - 1));
+
+        // The tile is an ancestor of the max zoom tile of the point.
+        let mut ancestor = coords_to_max_zoom_tile(&coords);
+        while ancestor.zoom > zoom {
+            ancestor = ancestor.parent().unwrap();
+        }
+        prop_assert_eq!(&ancestor, &tile);
+
+        let range = tile_to_max_zoom_tile_range(&tile);
+        let max_zoom_tile = coords_to_max_zoom_tile(&coords);
+        prop_assert!((range.min_x..=range.max_x).contains(&max_zoom_tile.x));
+        prop_assert!((range.min_y..=range.max_y).contains(&max_zoom_tile.y));
+    }
+
+    #[test]
+    fn test_bounds_to_tile_range(a in coords(), b in coords(), point_share in (0.0..=1.0f64, 0.0..=1.0f64), zoom in zooms()) {
+        let bounds = GeoBounds {
+            min_lon: a.1.min(b.1),
+            min_lat: a.0.min(b.0),
+            max_lon: a.1.max(b.1),
+            max_lat: a.0.max(b.0),
+        };
+        let range = bounds_to_tile_range(&bounds, zoom);
+        prop_assert!(range.min_x <= range.max_x && range.max_x < 1 << zoom);
+        prop_assert!(range.min_y <= range.max_y && range.max_y < 1 << zoom);
+
+        // Every point of the bounds (apart from the ones on the tile edges at the far sides) is in the range.
+        let lat = bounds.min_lat + point_share.0 * (bounds.max_lat - bounds.min_lat);
+        let lon = bounds.min_lon + point_share.1 * (bounds.max_lon - bounds.min_lon);
+        let tile = coords_to_tile(&(lat, lon), zoom);
+        let (x, y) = coords_to_xy_tile_relative(&(lat, lon), &tile);
+        prop_assert!((range.min_x..=range.max_x).contains(&tile.x) || (x == 0.0 && tile.x == range.max_x + 1));
+        prop_assert!((range.min_y..=range.max_y).contains(&tile.y) || (y == 0.0 && tile.y == range.max_y + 1));
+    }
+
+    #[test]
+    fn test_pixels_match_across_tiles(coords in coords(), zoom in 1..=MAX_ZOOM, scale in 1..=2u32) {
+        // The same point drawn in a tile and in its neighbors ends up at the same pixel of the map.
+        let tile = coords_to_tile(&coords, zoom);
+        let (x, y) = coords_to_pixel_tile_relative(&coords, &tile, f64::from(scale));
+        let scaled_tile_size = (TILE_SIZE * scale) as i32;
+        for (dx, dy) in [(-1i32, 0i32), (1, 0), (0, -1), (0, 1)] {
+            let (neighbor_x, neighbor_y) = (tile.x as i32 + dx, tile.y as i32 + dy);
+            if neighbor_x < 0 || neighbor_y < 0 || neighbor_x >= 1 << zoom || neighbor_y >= 1 << zoom {
+                continue;
+            }
+            let neighbor = Tile { zoom, x: neighbor_x as u32, y: neighbor_y as u32 };
+            let (nx, ny) = coords_to_pixel_tile_relative(&coords, &neighbor, f64::from(scale));
+            prop_assert_eq!((nx + dx * scaled_tile_size, ny + dy * scaled_tile_size), (x, y));
+        }
+    }
+}
+
+#[test]
+fn test_tile_boundaries() {
+    for zoom in 0..=MAX_ZOOM {
+        let last = (1u32 << zoom) - 1;
+        assert_eq!(coords_to_tile(&(0.0, 180.0), zoom).x, last);
+        assert_eq!(coords_to_tile(&(-MAX_LAT, 0.0), zoom).y, last);
+        assert_eq!(coords_to_tile(&(90.0, -180.0), zoom), Tile { zoom, x: 0, y: 0 });
+    }
+
+    // Bounds that end at a tile edge don't spill into the next tile.
+    let west = "-180,0,0,80".parse::<GeoBounds>().unwrap();
+    assert_eq!(
+        bounds_to_tile_range(&west, 1),
+        TileRange {
+            min_x: 0,
+            max_x: 0,
+            min_y: 0,
+            max_y: 0
+        }
+    );
+
+    // This point is at 255.5 in one tile and at -0.5 in the next one, so it must be rounded the same way.
+    let point = (10.0, -45.08789062499999);
+    let left = Tile { zoom: 3, x: 2, y: 3 };
+    let right = Tile { x: 3, ..left.clone() };
+    assert_eq!(coords_to_xy_tile_relative(&point, &left).0, 255.5);
+    let (left_x, _) = coords_to_pixel_tile_relative(&point, &left, 1.0);
+    let (right_x, _) = coords_to_pixel_tile_relative(&point, &right, 1.0);
+    assert_eq!(left_x - 256, right_x);
+}